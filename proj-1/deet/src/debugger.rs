@@ -1,9 +1,16 @@
 use crate::debugger_command::DebuggerCommand;
-use crate::inferior::{Inferior, Status};
+use crate::inferior::{FollowForkMode, Inferior, SignalHandling, Status, SyscallCatches, SyscallStop};
+use nix::sys::signal::Signal;
 use crate::dwarf_data::{DwarfData, Error as DwarfError};
+use crate::syscalls;
 use rustyline::error::ReadlineError;
 use rustyline::Editor;
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::str::FromStr;
+
+/// How many lines of source `list` shows
+const LIST_LINES: usize = 10;
 
 pub struct Debugger {
     target: String,
@@ -11,7 +18,16 @@ pub struct Debugger {
     readline: Editor<()>,
     inferior: Option<Inferior>,
     debug_data: DwarfData,
-    breakpoints: HashMap<usize, u8>
+    /// The program `debug_data` was read from, which is no longer the target once it execs
+    debug_data_path: String,
+    breakpoints: HashMap<usize, u8>,
+    /// Which of `breakpoints` are deleted once hit
+    temporary_breakpoints: HashSet<usize>,
+    follow_fork_mode: FollowForkMode,
+    /// Arguments `run` starts the target with when given none
+    args: Vec<String>,
+    signal_handling: HashMap<Signal, SignalHandling>,
+    syscall_catches: SyscallCatches,
 }
 
 impl Debugger {
@@ -42,7 +58,13 @@ impl Debugger {
             readline,
             inferior: None,
             debug_data,
-            breakpoints
+            debug_data_path: target.to_string(),
+            breakpoints,
+            temporary_breakpoints: HashSet::new(),
+            follow_fork_mode: FollowForkMode::Parent,
+            args: Vec::new(),
+            signal_handling: HashMap::new(),
+            syscall_catches: SyscallCatches::default(),
         }
     }
 
@@ -54,8 +76,17 @@ impl Debugger {
                         self.inferior.as_mut().unwrap().kill();
                         self.inferior = None;
                     }
-                    if let Some(inferior) = Inferior::new(&self.target, &args, &self.breakpoints) {
+                    if self.debug_data_path != self.target {
+                        let target = self.target.clone();
+                        self.load_debug_data(&target);
+                    }
+                    if !args.is_empty() {
+                        self.args = args;
+                    }
+                    if let Some(mut inferior) = Inferior::new(&self.target, &self.args, &self.breakpoints, self.follow_fork_mode, &self.signal_handling) {
                         // Create the inferior
+                        inferior.temporary_breakpoints = self.temporary_breakpoints.clone();
+                        inferior.syscall_catches = self.syscall_catches.clone();
                         self.inferior = Some(inferior);
                         
                         let status = self.inferior.as_mut().unwrap().continue_run();
@@ -64,12 +95,12 @@ impl Debugger {
                         println!("Error starting subprocess");
                     }
                 }
-                DebuggerCommand::Continue => {
-                    if self.inferior.is_some() {
-                        let status = self.inferior.as_mut().unwrap().continue_run();
-                        self.check_status(status);
-                    } else {
+                DebuggerCommand::Continue(count) => {
+                    if self.inferior.is_none() {
                         println!("Error no inferior running");
+                    } else if let Some(count) = parse_count(count, "Usage: c|cont|continue [count]") {
+                        let status = self.inferior.as_mut().unwrap().continue_times(count);
+                        self.check_status(status);
                     }
                 }
                 DebuggerCommand::Quit => {
@@ -81,35 +112,49 @@ impl Debugger {
                 }
                 DebuggerCommand::Backtrace => {
                     if self.inferior.is_some() {
-                        self.inferior.as_mut().unwrap().print_backtrace(&self.debug_data).unwrap();
+                        self.inferior.as_mut().unwrap().print_backtrace(&self.debug_data);
                     } else {
                         println!("Error no inferior running")
                     }
                 }
-                DebuggerCommand::Breakpoint(location) => {
-                    let bp_addr;
-                    if location.starts_with("*") {
-                        if let Some(address) = self.parse_address(&location[1..]) {
-                            bp_addr = address;
-                        } else {
-                            println!("Invalid address");
-                            continue;
-                        }
-                    } else if let Some(line_number) = usize::from_str_radix(&location, 10).ok() {
-                        if let Some(address) = self.debug_data.get_addr_for_line(None, line_number) {
-                            bp_addr = address;
-                        } else {
-                            println!("Invalid line number");
-                            continue;
-                        }
-                    } else if let Some(address) = self.debug_data.get_addr_for_function(None, &location) {
-                        bp_addr = address;
-                    } else {
-                        println!("Usage: b|break|breakpoint *address|line|func");
+                DebuggerCommand::Frame(number) => {
+                    if self.inferior.is_none() {
+                        println!("Error no inferior running");
                         continue;
                     }
-                    
-                    
+                    let (selected, _) = self.inferior.as_ref().unwrap().selected_frame();
+                    match number.map(|number| usize::from_str_radix(&number, 10)) {
+                        Some(Ok(number)) => self.select_frame(number),
+                        Some(Err(_)) => println!("Usage: f|frame [number]"),
+                        None => self.select_frame(selected),
+                    }
+                }
+                DebuggerCommand::Up(_) | DebuggerCommand::Down(_) if self.inferior.is_none() => {
+                    println!("Error no inferior running");
+                }
+                DebuggerCommand::Up(count) => {
+                    let (selected, frame_count) = self.inferior.as_ref().unwrap().selected_frame();
+                    match count.map_or(Ok(1), |count| count.parse::<usize>()) {
+                        Ok(_) if selected + 1 >= frame_count => println!("Initial frame selected; you cannot go up."),
+                        Ok(count) => self.select_frame((selected + count).min(frame_count - 1)),
+                        Err(_) => println!("Usage: up [count]"),
+                    }
+                }
+                DebuggerCommand::Down(count) => {
+                    let (selected, _) = self.inferior.as_ref().unwrap().selected_frame();
+                    match count.map_or(Ok(1), |count| count.parse::<usize>()) {
+                        Ok(_) if selected == 0 => println!("Bottom (innermost) frame selected; you cannot go down."),
+                        Ok(count) => self.select_frame(selected.saturating_sub(count)),
+                        Err(_) => println!("Usage: down [count]"),
+                    }
+                }
+                DebuggerCommand::List(target) => self.print_listing(target),
+                DebuggerCommand::Breakpoint(location) => {
+                    let bp_addr = match self.parse_location(&location, "Usage: b|break|breakpoint *address|line|func") {
+                        Some(address) => address,
+                        None => continue,
+                    };
+
                     if self.inferior.is_some() {
                         println!("Set breakpoint {} at {:#x}", self.inferior.as_mut().unwrap().breakpoints.len(), bp_addr);
                         self.inferior.as_mut().unwrap().set_breakpoint(bp_addr);
@@ -118,19 +163,68 @@ impl Debugger {
                         self.breakpoints.insert(bp_addr, 0);
                     }
                 }
-                DebuggerCommand::Step => {
+                DebuggerCommand::TemporaryBreakpoint(location) => {
+                    let bp_addr = match self.parse_location(&location, "Usage: tb|tbreak *address|line|func") {
+                        Some(address) => address,
+                        None => continue,
+                    };
+
                     if self.inferior.is_some() {
-                        self.inferior.as_mut().unwrap().step_in(&self.debug_data);
+                        println!("Temporary breakpoint {} at {:#x}", self.inferior.as_mut().unwrap().breakpoints.len(), bp_addr);
+                        self.inferior.as_mut().unwrap().set_temporary_breakpoint(bp_addr);
                     } else {
+                        println!("Temporary breakpoint {} at {:#x}", self.breakpoints.len(), bp_addr);
+                        self.breakpoints.insert(bp_addr, 0);
+                        self.temporary_breakpoints.insert(bp_addr);
+                    }
+                }
+                DebuggerCommand::Until(line) => {
+                    if self.inferior.is_none() {
                         println!("Error no inferior running");
+                        continue;
                     }
+                    let line_number = match usize::from_str_radix(&line, 10) {
+                        Ok(line_number) => line_number,
+                        Err(_) => {
+                            println!("Usage: u|until line");
+                            continue;
+                        }
+                    };
+                    let inferior = self.inferior.as_mut().unwrap();
+                    let func = match inferior.current_function(&self.debug_data) {
+                        Some(func) => func,
+                        None => {
+                            println!("Error not stopped in a function with debug info");
+                            continue;
+                        }
+                    };
+                    let status = match self.debug_data.get_addr_for_line(None, line_number) {
+                        Some(addr) if addr >= func.address && addr < func.address + func.text_length => {
+                            inferior.run_until(addr)
+                        }
+                        _ => {
+                            println!("Error line {} isn't in function {}", line_number, func.name);
+                            continue;
+                        }
+                    };
+                    self.check_status(status);
                 }
-                DebuggerCommand::Next => {
-                    if self.inferior.is_some() {
-                        let status = self.inferior.as_mut().unwrap().step_over(&self.debug_data);
-                        self.check_status(status);
-                    } else {
+                DebuggerCommand::Step(count) => {
+                    if self.inferior.is_none() {
                         println!("Error no inferior running");
+                    } else if let Some(count) = parse_count(count, "Usage: s|step [count]") {
+                        match self.inferior.as_mut().unwrap().step_in(&self.debug_data, count) {
+                            Some(status) => self.check_status(Ok(status)),
+                            None => self.inferior.as_mut().unwrap().unwind(&self.debug_data),
+                        }
+                    }
+                }
+                DebuggerCommand::Next(count) => {
+                    if self.inferior.is_none() {
+                        println!("Error no inferior running");
+                    } else if let Some(count) = parse_count(count, "Usage: n|next [count]") {
+                        let status = self.inferior.as_mut().unwrap().step_over(&self.debug_data, count);
+                        self.check_status(status);
                     }
                 }
                 DebuggerCommand::Finish => {
@@ -148,7 +242,95 @@ impl Debugger {
                         println!("Error no inferior running");
                     }
                 }
+                DebuggerCommand::Disassemble(name) => {
+                    if self.inferior.is_some() {
+                        self.inferior.as_ref().unwrap().print_disassembly(&self.debug_data, name);
+                    } else {
+                        println!("Error no inferior running");
+                    }
+                }
+                DebuggerCommand::Set(setting, values) if setting == "args" => self.args = values,
+                DebuggerCommand::Set(setting, values) => {
+                    let value = values.first().map(|value| value.as_str());
+                    let mode = match (setting.as_str(), value) {
+                        ("follow-fork-mode", Some("parent")) => FollowForkMode::Parent,
+                        ("follow-fork-mode", Some("child")) => FollowForkMode::Child,
+                        _ => {
+                            println!("Usage: set follow-fork-mode parent|child");
+                            println!("       set args [arguments]");
+                            continue;
+                        }
+                    };
+                    self.follow_fork_mode = mode;
+                    if let Some(inferior) = self.inferior.as_mut() {
+                        inferior.follow_fork_mode = mode;
+                    }
+                }
+                DebuggerCommand::Handle(args) => self.handle_signal(&args),
+                DebuggerCommand::Catch(args) => self.catch(&args),
+                DebuggerCommand::InfoLocals => self.print_locals(false),
+                DebuggerCommand::InfoArgs => self.print_locals(true),
+                DebuggerCommand::InfoThreads => {
+                    if self.inferior.is_some() {
+                        self.inferior.as_ref().unwrap().print_threads(&self.debug_data);
+                    } else {
+                        println!("Error no inferior running");
+                    }
+                }
+                DebuggerCommand::Thread(number) => {
+                    if self.inferior.is_none() {
+                        println!("Error no inferior running");
+                        continue;
+                    }
+                    let inferior = self.inferior.as_mut().unwrap();
+                    let number = match number {
+                        Some(number) => number,
+                        None => {
+                            let (number, tid, _) = inferior.current_thread();
+                            println!("[Current thread is {} (LWP {})]", number, tid);
+                            continue;
+                        }
+                    };
+                    match usize::from_str_radix(&number, 10).ok().and_then(|number| inferior.select_thread(number)) {
+                        Some(rip) => {
+                            inferior.unwind(&self.debug_data);
+                            let (number, tid, _) = inferior.current_thread();
+                            println!("[Switching to thread {} (LWP {})]", number, tid);
+                            match self.debug_data.get_line_from_addr(rip) {
+                                Some(line) => {
+                                    println!("Stopped at {}", line);
+                                    inferior.print_source(&line);
+                                },
+                                None => println!("Stopped at {:#x}", rip),
+                            }
+                        }
+                        None => println!("Invalid thread number"),
+                    }
+                }
+            }
+        }
+    }
+
+    /// Finds the address of a breakpoint location: `*address`, a line number or a function name.
+    /// Explains what's wrong with it (printing `usage` if it's none of those) if it can't.
+    fn parse_location(&self, location: &str, usage: &str) -> Option<usize> {
+        if location.starts_with("*") {
+            let address = self.parse_address(&location[1..]);
+            if address.is_none() {
+                println!("Invalid address");
+            }
+            address
+        } else if let Some(line_number) = usize::from_str_radix(&location, 10).ok() {
+            let address = self.debug_data.get_addr_for_line(None, line_number);
+            if address.is_none() {
+                println!("Invalid line number");
             }
+            address
+        } else if let Some(address) = self.debug_data.get_addr_for_function(None, &location) {
+            Some(address)
+        } else {
+            println!("{}", usage);
+            None
         }
     }
 
@@ -161,19 +343,205 @@ impl Debugger {
         usize::from_str_radix(addr_without_0x, 16).ok()
     }
 
+    /// Prints ten numbered lines of source around a line number or the start of a function, or
+    /// around where the selected frame is (main if nothing's running) if given neither. The line
+    /// the selected frame is at is marked.
+    fn print_listing(&self, target: Option<String>) {
+        let current = self
+            .inferior
+            .as_ref()
+            .and_then(|inferior| inferior.frame_line(&self.debug_data));
+        let default = current.clone().or_else(|| {
+            let main = self.debug_data.get_function_by_name("main")?;
+            self.debug_data.get_line_from_addr(main.address)
+        });
+
+        let (file, center) = match target {
+            Some(target) => match usize::from_str_radix(&target, 10) {
+                Ok(number) => match &default {
+                    Some(line) => (line.file.clone(), number),
+                    None => {
+                        println!("Error no source file to list");
+                        return;
+                    }
+                },
+                Err(_) => {
+                    let func = self.debug_data.get_function_by_name(&target);
+                    let location = func.and_then(|func| {
+                        Some((self.debug_data.get_line_from_addr(func.address)?.file, func.line_number))
+                    });
+                    match location {
+                        Some(location) => location,
+                        None => {
+                            println!("Error no function {}", target);
+                            return;
+                        }
+                    }
+                }
+            },
+            None => match &default {
+                Some(line) => (line.file.clone(), line.number),
+                None => {
+                    println!("Error no source file to list");
+                    return;
+                }
+            },
+        };
+
+        let source = match fs::read_to_string(&file) {
+            Ok(source) => source,
+            Err(err) => {
+                println!("Error reading {} with {}", file, err);
+                return;
+            }
+        };
+        let line_count = source.lines().count();
+        if center > line_count {
+            println!("Line number {} out of range; {} has {} lines.", center, file, line_count);
+            return;
+        }
+        let first = center.saturating_sub(LIST_LINES / 2).max(1);
+        for (index, content) in source.lines().enumerate().skip(first - 1).take(LIST_LINES) {
+            let number = index + 1;
+            let is_current = current.as_ref().map_or(false, |line| line.file == file && line.number == number);
+            println!("{} {}\t{}", if is_current { "=>" } else { "  " }, number, content);
+        }
+    }
+
+    fn print_locals(&self, parameters: bool) {
+        if self.inferior.is_some() {
+            self.inferior.as_ref().unwrap().print_locals(&self.debug_data, parameters);
+        } else {
+            println!("Error no inferior running");
+        }
+    }
+
+    /// Selects the numbered stack frame, printing where it is
+    fn select_frame(&mut self, number: usize) {
+        let inferior = self.inferior.as_mut().unwrap();
+        if inferior.select_frame(number) {
+            inferior.print_frame(&self.debug_data);
+        } else {
+            println!("No frame {}", number);
+        }
+    }
+
+    /// Changes whether a signal stops the inferior and whether it's passed on to it, printing how
+    /// it's handled now: `handle SIGUSR1 nostop pass`
+    fn handle_signal(&mut self, args: &[String]) {
+        let usage = "Usage: handle SIGNAL [stop|nostop] [pass|nopass]";
+        let name = match args.first() {
+            Some(name) => name.to_uppercase(),
+            None => {
+                println!("{}", usage);
+                return;
+            }
+        };
+        let name = if name.starts_with("SIG") { name } else { format!("SIG{}", name) };
+        let signal = match Signal::from_str(&name) {
+            Ok(Signal::SIGTRAP) | Ok(Signal::SIGSTOP) | Ok(Signal::SIGKILL) => {
+                println!("{} is used by the debugger, so can't be handled differently", name);
+                return;
+            }
+            Ok(signal) => signal,
+            Err(_) => {
+                println!("Unknown signal {}", name);
+                return;
+            }
+        };
+        let mut handling = self
+            .signal_handling
+            .get(&signal)
+            .copied()
+            .unwrap_or_else(|| SignalHandling::default_for(signal));
+        for action in &args[1..] {
+            match action.as_str() {
+                "stop" => handling.stop = true,
+                "nostop" => handling.stop = false,
+                "pass" => handling.pass = true,
+                "nopass" => handling.pass = false,
+                _ => {
+                    println!("{}", usage);
+                    return;
+                }
+            }
+        }
+        self.signal_handling.insert(signal, handling);
+        if let Some(inferior) = self.inferior.as_mut() {
+            inferior.signal_handling.insert(signal, handling);
+        }
+
+        let yes_no = |flag: bool| if flag { "Yes" } else { "No" };
+        println!("{:<16}{:<8}{}", "Signal", "Stop", "Pass");
+        println!("{:<16}{:<8}{}", signal.as_ref(), yes_no(handling.stop), yes_no(handling.pass));
+    }
+
+    /// Stops the inferior at the syscalls named (by name or number) from now on, or at every
+    /// syscall if none are
+    fn catch(&mut self, args: &[String]) {
+        if args.first().map(|arg| arg.as_str()) != Some("syscall") {
+            println!("Usage: catch syscall [name|number]...");
+            return;
+        }
+        let mut numbers = Vec::new();
+        for arg in &args[1..] {
+            let number = match u64::from_str_radix(arg, 10) {
+                Ok(number) => number,
+                Err(_) => match syscalls::number(arg) {
+                    Some(number) => number,
+                    None => {
+                        println!("Unknown syscall {}", arg);
+                        return;
+                    }
+                },
+            };
+            numbers.push(number);
+        }
+        if numbers.is_empty() {
+            self.syscall_catches.all = true;
+            println!("Catching any syscall");
+        }
+        for number in numbers {
+            self.syscall_catches.numbers.insert(number);
+            println!("Catching syscall {}", describe_syscall(number));
+        }
+        if let Some(inferior) = self.inferior.as_mut() {
+            inferior.syscall_catches = self.syscall_catches.clone();
+        }
+    }
+
+    /// Reads the debugging symbols of the program at `path`, keeping the ones we have if it has
+    /// none we can read
+    fn load_debug_data(&mut self, path: &str) {
+        match DwarfData::from_file(path) {
+            Ok(debug_data) => self.debug_data = debug_data,
+            Err(err) => println!("Could not read debugging symbols from {}: {:?}", path, err),
+        }
+        self.debug_data_path = path.to_string();
+    }
+
     fn check_status(&mut self, status: Result<Status, nix::Error>) {
         match status.unwrap() {
             Status::Stopped(signal, rip) => {
-                println!("Child stopped (signal {})", signal);
-                match self.debug_data.get_line_from_addr(rip) {
-                    Some(line) => {
-                        println!("Stopped at {}", line);
-                        self.inferior.as_mut().unwrap().print_source(&line);
-                    },
-                    None => {
-                        println!("Stopped at {:#x}", rip)
-                    },
+                // A temporary breakpoint set before running is gone for later runs too, once hit
+                if self.temporary_breakpoints.contains(&rip)
+                    && !self.inferior.as_ref().unwrap().temporary_breakpoints.contains(&rip)
+                {
+                    self.temporary_breakpoints.remove(&rip);
+                    self.breakpoints.remove(&rip);
                 }
+
+                self.print_stop(&format!("stopped (signal {})", signal), rip);
+            },
+            Status::Syscall(SyscallStop::Call(number, args), rip) => {
+                let args: Vec<String> = args.iter().map(|arg| format!("{:#x}", arg)).collect();
+                let name = syscalls::name(number).unwrap_or("syscall");
+                let event = format!("called syscall {}: {}({})", describe_syscall(number), name, args.join(", "));
+                self.print_stop(&event, rip);
+            },
+            Status::Syscall(SyscallStop::Return(number, value), rip) => {
+                let event = format!("returned from syscall {} with {}", describe_syscall(number), value);
+                self.print_stop(&event, rip);
             },
             Status::Exited(exit_code) => {
                 println!("Child exited (status {})", exit_code);
@@ -183,6 +551,33 @@ impl Debugger {
                 println!("Child exited (signal {})", signal);
                 self.inferior = None;
             },
+            Status::Exec(path) => {
+                println!("process {} is executing new program: {}", self.inferior.as_ref().unwrap().pid(), path);
+                self.load_debug_data(&path);
+                // Frames from before the exec are gone, so bt, up and down need the new program's
+                self.inferior.as_mut().unwrap().unwind(&self.debug_data);
+            },
+        }
+    }
+
+    /// Tells the user where the inferior stopped, and what it stopped for (`event`, as in "Child
+    /// stopped (signal SIGINT)")
+    fn print_stop(&mut self, event: &str, rip: usize) {
+        self.inferior.as_mut().unwrap().unwind(&self.debug_data);
+        let (number, tid, thread_count) = self.inferior.as_ref().unwrap().current_thread();
+        if thread_count > 1 {
+            println!("Thread {} (LWP {}) {}", number, tid, event);
+        } else {
+            println!("Child {}", event);
+        }
+        match self.debug_data.get_line_from_addr(rip) {
+            Some(line) => {
+                println!("Stopped at {}", line);
+                self.inferior.as_mut().unwrap().print_source(&line);
+            },
+            None => {
+                println!("Stopped at {:#x}", rip)
+            },
         }
     }
 
@@ -227,3 +622,23 @@ impl Debugger {
         }
     }
 }
+
+/// Parses the number of times to repeat a command, which is once if not given. Prints `usage`
+/// and returns None if it isn't a positive number.
+fn parse_count(count: Option<String>, usage: &str) -> Option<usize> {
+    match count.map_or(Ok(1), |count| count.parse::<usize>()) {
+        Ok(count) if count > 0 => Some(count),
+        _ => {
+            println!("{}", usage);
+            None
+        }
+    }
+}
+
+/// Names a syscall along with its number, e.g. "write (1)"
+fn describe_syscall(number: u64) -> String {
+    match syscalls::name(number) {
+        Some(name) => format!("{} ({})", name, number),
+        None => number.to_string(),
+    }
+}
@@ -1,13 +1,27 @@
 pub enum DebuggerCommand {
     Quit,
     Run(Vec<String>),
-    Continue,
+    Continue(Option<String>),
     Backtrace,
+    Frame(Option<String>),
+    Up(Option<String>),
+    Down(Option<String>),
+    List(Option<String>),
     Breakpoint(String),
-    Step,
-    Next,
+    TemporaryBreakpoint(String),
+    Until(String),
+    Step(Option<String>),
+    Next(Option<String>),
     Finish,
-    Print(String)
+    Print(String),
+    Disassemble(Option<String>),
+    InfoThreads,
+    InfoLocals,
+    InfoArgs,
+    Thread(Option<String>),
+    Set(String, Vec<String>),
+    Handle(Vec<String>),
+    Catch(Vec<String>),
 }
 
 impl DebuggerCommand {
@@ -20,13 +34,33 @@ impl DebuggerCommand {
                     args.iter().map(|s| s.to_string()).collect(),
                 ))
             },
-            "c" | "cont" | "continue" => Some(DebuggerCommand::Continue),
+            "c" | "cont" | "continue" => Some(DebuggerCommand::Continue(tokens.get(1).map(|s| s.to_string()))),
             "bt" | "back" | "backtrace" => Some(DebuggerCommand::Backtrace),
+            "f" | "frame" => Some(DebuggerCommand::Frame(tokens.get(1).map(|s| s.to_string()))),
+            "up" => Some(DebuggerCommand::Up(tokens.get(1).map(|s| s.to_string()))),
+            "down" => Some(DebuggerCommand::Down(tokens.get(1).map(|s| s.to_string()))),
+            "l" | "list" => Some(DebuggerCommand::List(tokens.get(1).map(|s| s.to_string()))),
             "b" | "break" => Some(DebuggerCommand::Breakpoint(tokens.get(1).unwrap_or(&"").to_string())),
-            "s" | "step" => Some(DebuggerCommand::Step),
-            "n" | "next" => Some(DebuggerCommand::Next),
+            "tb" | "tbreak" => Some(DebuggerCommand::TemporaryBreakpoint(tokens.get(1).unwrap_or(&"").to_string())),
+            "u" | "until" => Some(DebuggerCommand::Until(tokens.get(1).unwrap_or(&"").to_string())),
+            "s" | "step" => Some(DebuggerCommand::Step(tokens.get(1).map(|s| s.to_string()))),
+            "n" | "next" => Some(DebuggerCommand::Next(tokens.get(1).map(|s| s.to_string()))),
             "fin" | "finish" => Some(DebuggerCommand::Finish),
-            "p" | "print" => Some(DebuggerCommand::Print(tokens.get(1).unwrap_or(&"").to_string())),
+            "p" | "print" => Some(DebuggerCommand::Print(tokens[1..].join(" "))),
+            "disas" | "disassemble" => Some(DebuggerCommand::Disassemble(tokens.get(1).map(|s| s.to_string()))),
+            "info" => match tokens.get(1) {
+                Some(&"threads") => Some(DebuggerCommand::InfoThreads),
+                Some(&"locals") => Some(DebuggerCommand::InfoLocals),
+                Some(&"args") => Some(DebuggerCommand::InfoArgs),
+                _ => None,
+            },
+            "thread" => Some(DebuggerCommand::Thread(tokens.get(1).map(|s| s.to_string()))),
+            "handle" => Some(DebuggerCommand::Handle(tokens[1..].iter().map(|s| s.to_string()).collect())),
+            "catch" => Some(DebuggerCommand::Catch(tokens[1..].iter().map(|s| s.to_string()).collect())),
+            "set" => Some(DebuggerCommand::Set(
+                tokens.get(1).unwrap_or(&"").to_string(),
+                tokens.iter().skip(2).map(|s| s.to_string()).collect(),
+            )),
             // Default case:
             _ => None,
         }
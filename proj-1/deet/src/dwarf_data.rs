@@ -12,6 +12,7 @@ pub enum Error {
 
 pub struct DwarfData {
     files: Vec<File>,
+    types: gimli_wrapper::Types,
     addr2line: Context<addr2line::gimli::EndianRcSlice<addr2line::gimli::RunTimeEndian>>,
 }
 
@@ -38,8 +39,10 @@ impl DwarfData {
         } else {
             gimli::RunTimeEndian::Big
         };
+        let (files, types) = gimli_wrapper::load_file(&object, endian)?;
         Ok(DwarfData {
-            files: gimli_wrapper::load_file(&object, endian)?,
+            files,
+            types,
             addr2line: Context::new(&object).or_else(|e| Err(gimli_wrapper::Error::from(e)))?,
         })
     }
@@ -111,6 +114,20 @@ impl DwarfData {
         None
     }
 
+    pub fn get_function_by_name(&self, func_name: &str) -> Option<Function> {
+        for file in &self.files {
+            if let Some(func) = file.functions.iter().find(|func| func.name == func_name) {
+                return Some(func.clone());
+            }
+        }
+        None
+    }
+
+    /// Builds the type described at `offset` in .debug_info, e.g. the one a pointer points to
+    pub fn get_type(&self, offset: usize) -> Option<Type> {
+        self.types.resolve(offset)
+    }
+
     pub fn get_global_variables(&self) -> Vec<&Variable> {
         let mut variables = Vec::new();
         for file in &self.files {
@@ -171,17 +188,46 @@ impl DwarfData {
 pub struct Type {
     pub name: String,
     pub size: usize,
+    pub kind: TypeKind,
 }
 
 impl Type {
     pub fn new(name: String, size: usize) -> Self {
         Type {
             name,
-            size
+            size,
+            kind: TypeKind::Base,
         }
     }
 }
 
+#[derive(Debug, Clone)]
+pub enum TypeKind {
+    /// A number, character or bool, which is told apart by its name
+    Base,
+    /// Points to a value of the type at the given offset in .debug_info (see
+    /// `DwarfData::get_type`), or to void
+    Pointer(Option<usize>),
+    /// Holds the given number of elements of the given type
+    Array(Box<Type>, usize),
+    /// A struct or union, made of these members
+    Struct(Vec<Member>),
+}
+
+#[derive(Debug, Clone)]
+pub struct Member {
+    pub name: String,
+    /// Where the member starts, in bytes from the start of the struct
+    pub offset: usize,
+    pub entity_type: Type,
+}
+
+impl Default for TypeKind {
+    fn default() -> Self {
+        TypeKind::Base
+    }
+}
+
 impl fmt::Display for Type {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         write!(f, "{}", self.name)
@@ -216,6 +262,7 @@ pub struct Variable {
     pub entity_type: Type,
     pub location: Location,
     pub line_number: usize, // Line number in source file
+    pub is_parameter: bool,
 }
 
 #[derive(Debug, Default, Clone)]
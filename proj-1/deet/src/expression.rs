@@ -0,0 +1,308 @@
+//! Parses the expressions `print` accepts: variables and number literals, combined with
+//! arithmetic (`+ - * / %`), dereferencing (`*ptr`), taking addresses (`&x`), indexing
+//! (`arr[3]`), struct members (`p.x`, `node->next`) and parentheses. Evaluating them is up to the
+//! inferior, which has the memory they read from.
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Expr {
+    Int(i64),
+    Float(f64),
+    Variable(String),
+    Negate(Box<Expr>),
+    Deref(Box<Expr>),
+    AddressOf(Box<Expr>),
+    Index(Box<Expr>, Box<Expr>),
+    /// A struct member; `a->b` is parsed as `(*a).b`
+    Member(Box<Expr>, String),
+    Binary(BinaryOp, Box<Expr>, Box<Expr>),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum BinaryOp {
+    Add,
+    Sub,
+    Mul,
+    Div,
+    Rem,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+    Int(i64),
+    Float(f64),
+    Ident(String),
+    Punct(char),
+    Arrow,
+}
+
+pub fn parse(input: &str) -> Result<Expr, String> {
+    let tokens = tokenize(input)?;
+    if tokens.is_empty() {
+        return Err("nothing to print".to_string());
+    }
+    let mut parser = Parser { tokens, pos: 0 };
+    let expr = parser.expr()?;
+    match parser.peek() {
+        None => Ok(expr),
+        Some(token) => Err(format!("unexpected {:?} in expression", token)),
+    }
+}
+
+fn tokenize(input: &str) -> Result<Vec<Token>, String> {
+    let chars: Vec<char> = input.chars().collect();
+    let mut tokens = Vec::new();
+    let mut i = 0;
+    while i < chars.len() {
+        let c = chars[i];
+        if c.is_whitespace() {
+            i += 1;
+        } else if c.is_ascii_digit() {
+            let start = i;
+            while i < chars.len() && (chars[i].is_ascii_alphanumeric() || chars[i] == '.') {
+                i += 1;
+            }
+            let literal: String = chars[start..i].iter().collect();
+            tokens.push(parse_number(&literal)?);
+        } else if c.is_alphabetic() || c == '_' {
+            let start = i;
+            while i < chars.len() && (chars[i].is_alphanumeric() || chars[i] == '_') {
+                i += 1;
+            }
+            tokens.push(Token::Ident(chars[start..i].iter().collect()));
+        } else if c == '-' && chars.get(i + 1) == Some(&'>') {
+            tokens.push(Token::Arrow);
+            i += 2;
+        } else if "+-*/%&[]().".contains(c) {
+            tokens.push(Token::Punct(c));
+            i += 1;
+        } else {
+            return Err(format!("unexpected character '{}' in expression", c));
+        }
+    }
+    Ok(tokens)
+}
+
+fn parse_number(literal: &str) -> Result<Token, String> {
+    let lower = literal.to_lowercase();
+    let parsed = if let Some(hex) = lower.strip_prefix("0x") {
+        i64::from_str_radix(hex, 16).ok().map(Token::Int)
+    } else if lower.contains('.') {
+        lower.parse::<f64>().ok().map(Token::Float)
+    } else {
+        lower.parse::<i64>().ok().map(Token::Int)
+    };
+    parsed.ok_or(format!("invalid number {}", literal))
+}
+
+/// A recursive descent parser, with one function per level of precedence
+struct Parser {
+    tokens: Vec<Token>,
+    pos: usize,
+}
+
+impl Parser {
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.pos)
+    }
+
+    fn next(&mut self) -> Option<Token> {
+        let token = self.tokens.get(self.pos).cloned();
+        self.pos += 1;
+        token
+    }
+
+    fn eat(&mut self, punct: char) -> bool {
+        if self.peek() == Some(&Token::Punct(punct)) {
+            self.pos += 1;
+            true
+        } else {
+            false
+        }
+    }
+
+    fn expect(&mut self, punct: char) -> Result<(), String> {
+        if self.eat(punct) {
+            Ok(())
+        } else {
+            Err(format!("expected '{}' in expression", punct))
+        }
+    }
+
+    /// expr := term (('+' | '-') term)*
+    fn expr(&mut self) -> Result<Expr, String> {
+        let mut lhs = self.term()?;
+        loop {
+            let op = if self.eat('+') {
+                BinaryOp::Add
+            } else if self.eat('-') {
+                BinaryOp::Sub
+            } else {
+                return Ok(lhs);
+            };
+            let rhs = self.term()?;
+            lhs = Expr::Binary(op, Box::new(lhs), Box::new(rhs));
+        }
+    }
+
+    /// term := unary (('*' | '/' | '%') unary)*
+    fn term(&mut self) -> Result<Expr, String> {
+        let mut lhs = self.unary()?;
+        loop {
+            let op = if self.eat('*') {
+                BinaryOp::Mul
+            } else if self.eat('/') {
+                BinaryOp::Div
+            } else if self.eat('%') {
+                BinaryOp::Rem
+            } else {
+                return Ok(lhs);
+            };
+            let rhs = self.unary()?;
+            lhs = Expr::Binary(op, Box::new(lhs), Box::new(rhs));
+        }
+    }
+
+    /// unary := ('-' | '*' | '&') unary | postfix
+    fn unary(&mut self) -> Result<Expr, String> {
+        if self.eat('-') {
+            Ok(Expr::Negate(Box::new(self.unary()?)))
+        } else if self.eat('*') {
+            Ok(Expr::Deref(Box::new(self.unary()?)))
+        } else if self.eat('&') {
+            Ok(Expr::AddressOf(Box::new(self.unary()?)))
+        } else {
+            self.postfix()
+        }
+    }
+
+    /// postfix := primary ('[' expr ']' | '.' identifier | '->' identifier)*
+    fn postfix(&mut self) -> Result<Expr, String> {
+        let mut expr = self.primary()?;
+        loop {
+            if self.eat('[') {
+                let index = self.expr()?;
+                self.expect(']')?;
+                expr = Expr::Index(Box::new(expr), Box::new(index));
+            } else if self.eat('.') {
+                expr = Expr::Member(Box::new(expr), self.member_name()?);
+            } else if self.peek() == Some(&Token::Arrow) {
+                self.pos += 1;
+                let deref = Expr::Deref(Box::new(expr));
+                expr = Expr::Member(Box::new(deref), self.member_name()?);
+            } else {
+                return Ok(expr);
+            }
+        }
+    }
+
+    fn member_name(&mut self) -> Result<String, String> {
+        match self.next() {
+            Some(Token::Ident(name)) => Ok(name),
+            _ => Err("expected a member name in expression".to_string()),
+        }
+    }
+
+    /// primary := number | variable | '(' expr ')'
+    fn primary(&mut self) -> Result<Expr, String> {
+        match self.next() {
+            Some(Token::Int(value)) => Ok(Expr::Int(value)),
+            Some(Token::Float(value)) => Ok(Expr::Float(value)),
+            Some(Token::Ident(name)) => Ok(Expr::Variable(name)),
+            Some(Token::Punct('(')) => {
+                let expr = self.expr()?;
+                self.expect(')')?;
+                Ok(expr)
+            }
+            Some(token) => Err(format!("unexpected {:?} in expression", token)),
+            None => Err("expression ends too soon".to_string()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn var(name: &str) -> Box<Expr> {
+        Box::new(Expr::Variable(name.to_string()))
+    }
+
+    fn binary(op: BinaryOp, lhs: Box<Expr>, rhs: Box<Expr>) -> Box<Expr> {
+        Box::new(Expr::Binary(op, lhs, rhs))
+    }
+
+    #[test]
+    fn test_precedence() {
+        assert_eq!(
+            parse("x + y*2"),
+            Ok(*binary(
+                BinaryOp::Add,
+                var("x"),
+                binary(BinaryOp::Mul, var("y"), Box::new(Expr::Int(2)))
+            ))
+        );
+        // Operators of the same precedence group to the left
+        assert_eq!(
+            parse("a - b - c"),
+            Ok(*binary(BinaryOp::Sub, binary(BinaryOp::Sub, var("a"), var("b")), var("c")))
+        );
+        assert_eq!(
+            parse("(x + y) % 0x10"),
+            Ok(*binary(
+                BinaryOp::Rem,
+                binary(BinaryOp::Add, var("x"), var("y")),
+                Box::new(Expr::Int(16))
+            ))
+        );
+    }
+
+    #[test]
+    fn test_unary() {
+        assert_eq!(parse("*p"), Ok(Expr::Deref(var("p"))));
+        assert_eq!(parse("&x"), Ok(Expr::AddressOf(var("x"))));
+        assert_eq!(parse("**pp"), Ok(Expr::Deref(Box::new(Expr::Deref(var("pp"))))));
+        assert_eq!(parse("-1.5"), Ok(Expr::Negate(Box::new(Expr::Float(1.5)))));
+        // A unary operator binds tighter than a binary one, and a postfix one tighter still
+        assert_eq!(
+            parse("*p * 2"),
+            Ok(*binary(BinaryOp::Mul, Box::new(Expr::Deref(var("p"))), Box::new(Expr::Int(2))))
+        );
+        assert_eq!(
+            parse("&arr[1]"),
+            Ok(Expr::AddressOf(Box::new(Expr::Index(var("arr"), Box::new(Expr::Int(1))))))
+        );
+    }
+
+    #[test]
+    fn test_postfix() {
+        assert_eq!(parse("arr[3]"), Ok(Expr::Index(var("arr"), Box::new(Expr::Int(3)))));
+        assert_eq!(
+            parse("p->x"),
+            Ok(Expr::Member(Box::new(Expr::Deref(var("p"))), "x".to_string()))
+        );
+        assert_eq!(
+            parse("grid[i][j + 1].value"),
+            Ok(Expr::Member(
+                Box::new(Expr::Index(
+                    Box::new(Expr::Index(var("grid"), var("i"))),
+                    binary(BinaryOp::Add, var("j"), Box::new(Expr::Int(1)))
+                )),
+                "value".to_string()
+            ))
+        );
+    }
+
+    #[test]
+    fn test_errors() {
+        assert!(parse("").is_err());
+        assert!(parse("(x + 1").is_err());
+        assert!(parse("x + 1)").is_err());
+        assert!(parse("arr[2").is_err());
+        assert!(parse("x y").is_err());
+        assert!(parse("x +").is_err());
+        assert!(parse("p->").is_err());
+        assert!(parse("p.3").is_err());
+        assert!(parse("x $ y").is_err());
+        assert!(parse("12ab").is_err());
+    }
+}
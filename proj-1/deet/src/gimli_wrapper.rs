@@ -10,13 +10,43 @@ use gimli::{UnitOffset, UnitSectionOffset};
 use object::Object;
 use std::borrow;
 //use std::io::{BufWriter, Write};
-use crate::dwarf_data::{File, Function, Line, Location, Type, Variable};
+use crate::dwarf_data::{File, Function, Line, Location, Member, Type, TypeKind, Variable};
 use std::collections::HashMap;
 use std::convert::TryInto;
 use std::fmt::Write;
+use std::mem::size_of;
 use std::{io, path};
 
-pub fn load_file(object: &object::File, endian: gimli::RunTimeEndian) -> Result<Vec<File>, Error> {
+/// A type as the DWARF describes it, referring to the types it's made from by their offsets
+enum RawType {
+    /// Name and size in bytes
+    Base(String, usize),
+    /// Type pointed to (None for void), and size of the pointer in bytes
+    Pointer(Option<usize>, usize),
+    /// Element type, and the number of elements along each dimension
+    Array(usize, Vec<usize>),
+    /// A typedef (with its name) or const/volatile qualifier of another type (None for void)
+    Alias(Option<String>, Option<usize>),
+    /// A struct or union: its name (e.g. "struct point"), size in bytes, and each member's name,
+    /// offset and type
+    Struct(String, usize, Vec<(String, usize, usize)>),
+}
+
+/// Every type described in the DWARF, keyed by its offset in .debug_info. What a pointer points
+/// to is looked up here when the pointer is followed, rather than built along with the pointer.
+#[derive(Default)]
+pub struct Types(HashMap<usize, RawType>);
+
+impl Types {
+    pub fn resolve(&self, offset: usize) -> Option<Type> {
+        resolve_type(offset, &self.0)
+    }
+}
+
+pub fn load_file(
+    object: &object::File,
+    endian: gimli::RunTimeEndian,
+) -> Result<(Vec<File>, Types), Error> {
     // Load a section and return as `Cow<[u8]>`.
     let load_section = |id: gimli::SectionId| -> Result<borrow::Cow<[u8]>, gimli::Error> {
         Ok(object
@@ -39,22 +69,22 @@ pub fn load_file(object: &object::File, endian: gimli::RunTimeEndian) -> Result<
     // Create `EndianSlice`s for all of the sections.
     let dwarf = dwarf_cow.borrow(&borrow_section);
 
-    // Define a mapping from type offsets to type structs
-    let mut offset_to_type: HashMap<usize, Type> = HashMap::new();
-
     let mut compilation_units: Vec<File> = Vec::new();
+    let mut types = Types::default();
 
     // Iterate over the compilation units.
     let mut iter = dwarf.units();
     while let Some(header) = iter.next()? {
         let unit = dwarf.unit(header)?;
 
+        // Collect the unit's types first, since variables can refer to types described after them
+        load_types(&unit, &dwarf, &mut types.0)?;
+
         // Iterate over the Debugging Information Entries (DIEs) in the unit.
         let mut depth = 0;
         let mut entries = unit.entries();
         while let Some((delta_depth, entry)) = entries.next_dfs()? {
             depth += delta_depth;
-            // Update the offset_to_type mapping for types
             // Update the variable list for formal params/variables
             match entry.tag() {
                 gimli::DW_TAG_compile_unit => {
@@ -74,33 +104,6 @@ pub fn load_file(object: &object::File, endian: gimli::RunTimeEndian) -> Result<
                         lines: Vec::new(),
                     });
                 }
-                gimli::DW_TAG_base_type => {
-                    let name = if let Ok(Some(attr)) = entry.attr(gimli::DW_AT_name) {
-                        if let Ok(DebugValue::Str(name)) = get_attr_value(&attr, &unit, &dwarf) {
-                            name
-                        } else {
-                            "<unknown>".to_string()
-                        }
-                    } else {
-                        "<unknown>".to_string()
-                    };
-                    let byte_size = if let Ok(Some(attr)) = entry.attr(gimli::DW_AT_byte_size) {
-                        if let Ok(DebugValue::Uint(byte_size)) =
-                            get_attr_value(&attr, &unit, &dwarf)
-                        {
-                            byte_size
-                        } else {
-                            // TODO: report error?
-                            0
-                        }
-                    } else {
-                        // TODO: report error?
-                        0
-                    };
-                    let type_offset = entry.offset().0;
-                    offset_to_type
-                        .insert(type_offset, Type::new(name, byte_size.try_into().unwrap()));
-                }
                 gimli::DW_TAG_subprogram => {
                     let mut func: Function = Default::default();
                     let mut attrs = entry.attrs();
@@ -151,9 +154,7 @@ pub fn load_file(object: &object::File, endian: gimli::RunTimeEndian) -> Result<
                             }
                             gimli::DW_AT_type => {
                                 if let Ok(DebugValue::Size(offset)) = val {
-                                    if let Some(dtype) = offset_to_type.get(&offset).clone() {
-                                        entity_type = Some(dtype.clone());
-                                    }
+                                    entity_type = resolve_type(offset, &types.0);
                                 }
                             }
                             gimli::DW_AT_location => {
@@ -175,6 +176,7 @@ pub fn load_file(object: &object::File, endian: gimli::RunTimeEndian) -> Result<
                             entity_type: entity_type.unwrap(),
                             location: location.unwrap(),
                             line_number: line_number.try_into().unwrap(),
+                            is_parameter: entry.tag() == gimli::DW_TAG_formal_parameter,
                         };
                         if depth == 1 {
                             compilation_units
@@ -240,7 +242,196 @@ pub fn load_file(object: &object::File, endian: gimli::RunTimeEndian) -> Result<
             }
         }
     }
-    Ok(compilation_units)
+    Ok((compilation_units, types))
+}
+
+/// Reads the base, pointer, array and struct types described in a unit (along with typedefs and
+/// qualifiers) into `raw_types`, keyed by their offsets in .debug_info
+fn load_types<R: Reader>(
+    unit: &gimli::Unit<R>,
+    dwarf: &gimli::Dwarf<R>,
+    raw_types: &mut HashMap<usize, RawType>,
+) -> Result<(), Error> {
+    let mut last_array = None;
+    // Structs being described, with their depths, innermost last. Structs can be declared inside
+    // structs, so the members that follow one may belong to the struct around it.
+    let mut structs: Vec<(isize, usize)> = Vec::new();
+    let mut depth = 0;
+    let mut entries = unit.entries();
+    while let Some((delta_depth, entry)) = entries.next_dfs()? {
+        depth += delta_depth;
+        while structs.last().map_or(false, |(struct_depth, _)| *struct_depth >= depth) {
+            structs.pop();
+        }
+        let offset = section_offset(entry.offset(), unit);
+        let name = match attr_value(entry, gimli::DW_AT_name, unit, dwarf) {
+            Some(DebugValue::Str(name)) => Some(name),
+            _ => None,
+        };
+        let byte_size = match attr_value(entry, gimli::DW_AT_byte_size, unit, dwarf) {
+            Some(DebugValue::Uint(byte_size)) => Some(byte_size as usize),
+            _ => None,
+        };
+        let target = match attr_value(entry, gimli::DW_AT_type, unit, dwarf) {
+            Some(DebugValue::Size(target)) => Some(target),
+            _ => None,
+        };
+        match entry.tag() {
+            gimli::DW_TAG_base_type => {
+                let name = name.unwrap_or_else(|| "<unknown>".to_string());
+                raw_types.insert(offset, RawType::Base(name, byte_size.unwrap_or(0)));
+            }
+            gimli::DW_TAG_pointer_type => {
+                let byte_size = byte_size.unwrap_or(size_of::<usize>());
+                raw_types.insert(offset, RawType::Pointer(target, byte_size));
+            }
+            gimli::DW_TAG_array_type => {
+                if let Some(target) = target {
+                    raw_types.insert(offset, RawType::Array(target, Vec::new()));
+                    last_array = Some(offset);
+                }
+            }
+            gimli::DW_TAG_subrange_type => {
+                // Each dimension of an array is a subrange under it. Arrays whose length isn't
+                // known (e.g. `int arr[]` parameters) are treated as empty
+                let count = match entry.attr(gimli::DW_AT_count)? {
+                    Some(count) => count.udata_value().unwrap_or(0) as usize,
+                    None => match entry.attr(gimli::DW_AT_upper_bound)? {
+                        Some(upper_bound) => upper_bound.udata_value().map_or(0, |n| n as usize + 1),
+                        None => 0,
+                    },
+                };
+                if let Some(RawType::Array(_, counts)) =
+                    last_array.and_then(|array| raw_types.get_mut(&array))
+                {
+                    counts.push(count);
+                }
+            }
+            gimli::DW_TAG_typedef => {
+                raw_types.insert(offset, RawType::Alias(name, target));
+            }
+            gimli::DW_TAG_const_type | gimli::DW_TAG_volatile_type => {
+                raw_types.insert(offset, RawType::Alias(None, target));
+            }
+            gimli::DW_TAG_structure_type | gimli::DW_TAG_union_type => {
+                let keyword = if entry.tag() == gimli::DW_TAG_union_type { "union" } else { "struct" };
+                let name = format!("{} {}", keyword, name.unwrap_or_else(|| "{...}".to_string()));
+                raw_types.insert(offset, RawType::Struct(name, byte_size.unwrap_or(0), Vec::new()));
+                structs.push((depth, offset));
+            }
+            gimli::DW_TAG_member => {
+                // Union members don't have a location, since they all start at the beginning
+                let member_offset = match entry.attr(gimli::DW_AT_data_member_location)? {
+                    Some(location) => location.udata_value().unwrap_or(0) as usize,
+                    None => 0,
+                };
+                let parent = structs.last().map(|(_, parent)| *parent);
+                if let (Some(name), Some(target), Some(RawType::Struct(_, _, members))) =
+                    (name, target, parent.and_then(|parent| raw_types.get_mut(&parent)))
+                {
+                    members.push((name, member_offset, target));
+                }
+            }
+            _ => {}
+        }
+    }
+    Ok(())
+}
+
+/// Builds the type at `offset` out of the types it's made from. Pointers only get the offset of
+/// what they point to, so types that point to themselves don't go on forever.
+fn resolve_type(offset: usize, raw_types: &HashMap<usize, RawType>) -> Option<Type> {
+    match raw_types.get(&offset)? {
+        RawType::Base(name, size) => Some(Type::new(name.clone(), *size)),
+        RawType::Pointer(target, size) => {
+            // Pointers to types we can't describe (e.g. functions) are treated as void *
+            let target = target.filter(|target| type_name(*target, raw_types).is_some());
+            Some(Type {
+                name: type_name(offset, raw_types)?,
+                size: *size,
+                kind: TypeKind::Pointer(target),
+            })
+        }
+        RawType::Array(element, counts) => {
+            let element = resolve_type(*element, raw_types)?;
+            // int a[2][3] is an array of 2 arrays of 3 ints
+            let mut array = element.clone();
+            let mut dimensions = String::new();
+            for count in counts.iter().rev() {
+                dimensions = format!("[{}]{}", count, dimensions);
+                array = Type {
+                    name: format!("{} {}", element.name, dimensions),
+                    size: array.size * count,
+                    kind: TypeKind::Array(Box::new(array), *count),
+                };
+            }
+            Some(array)
+        }
+        RawType::Struct(name, size, members) => {
+            let members = members
+                .iter()
+                .filter_map(|(member_name, offset, member_type)| {
+                    Some(Member {
+                        name: member_name.clone(),
+                        offset: *offset,
+                        entity_type: resolve_type(*member_type, raw_types)?,
+                    })
+                })
+                .collect();
+            Some(Type {
+                name: name.clone(),
+                size: *size,
+                kind: TypeKind::Struct(members),
+            })
+        }
+        RawType::Alias(name, target) => {
+            let mut aliased = match target {
+                Some(target) => resolve_type(*target, raw_types)?,
+                None => Type::new("void".to_string(), 0),
+            };
+            if let Some(name) = name {
+                aliased.name = name.clone();
+            }
+            Some(aliased)
+        }
+    }
+}
+
+/// Works out the name `resolve_type` would give the type at `offset`, without building the type
+fn type_name(offset: usize, raw_types: &HashMap<usize, RawType>) -> Option<String> {
+    match raw_types.get(&offset)? {
+        RawType::Base(name, _) | RawType::Struct(name, _, _) => Some(name.clone()),
+        RawType::Pointer(target, _) => {
+            let target_name = target.and_then(|target| type_name(target, raw_types));
+            Some(format!("{} *", target_name.as_deref().unwrap_or("void")))
+        }
+        RawType::Array(element, counts) => {
+            let dimensions: String = counts.iter().map(|count| format!("[{}]", count)).collect();
+            Some(format!("{} {}", type_name(*element, raw_types)?, dimensions))
+        }
+        RawType::Alias(Some(name), _) => Some(name.clone()),
+        RawType::Alias(None, Some(target)) => type_name(*target, raw_types),
+        RawType::Alias(None, None) => Some("void".to_string()),
+    }
+}
+
+/// Returns the offset in .debug_info of an entry in a unit
+fn section_offset<R: Reader>(offset: UnitOffset, unit: &gimli::Unit<R>) -> usize {
+    match offset.to_unit_section_offset(unit) {
+        UnitSectionOffset::DebugInfoOffset(goff) => goff.0,
+        UnitSectionOffset::DebugTypesOffset(goff) => goff.0,
+    }
+}
+
+/// Returns the value of one of an entry's attributes, if it has it
+fn attr_value<R: Reader>(
+    entry: &gimli::DebuggingInformationEntry<R>,
+    name: gimli::DwAt,
+    unit: &gimli::Unit<R>,
+    dwarf: &gimli::Dwarf<R>,
+) -> Option<DebugValue> {
+    let attr = entry.attr(name).ok()??;
+    get_attr_value(&attr, unit, dwarf).ok()
 }
 
 #[derive(Debug, Clone)]
@@ -3,13 +3,17 @@ use nix::sys::signal;
 use nix::sys::wait::{waitpid, WaitPidFlag, WaitStatus};
 use nix::unistd::Pid;
 use std::convert::TryInto;
-use std::fs;
+use std::collections::HashSet;
+use std::fs::{self, File, OpenOptions};
+use std::path::Path;
 use std::mem::size_of;
-use std::ops::BitAnd;
 use std::os::unix::prelude::CommandExt;
-use std::process::{Child, Command};
+use std::process::Command;
 use std::collections::HashMap;
-use crate::dwarf_data::{DwarfData, Line, Location, Variable};
+use std::sync::atomic::{AtomicBool, AtomicI32, Ordering};
+use iced_x86::{Decoder, DecoderOptions, Formatter, Instruction, IntelFormatter};
+use crate::dwarf_data::{DwarfData, Function, Line, Location, Type, TypeKind, Variable};
+use crate::expression::{self, BinaryOp, Expr};
 
 pub enum Status {
     /// Indicates inferior stopped. Contains the signal that stopped the process, as well as the
@@ -22,6 +26,70 @@ pub enum Status {
     /// Indicates the inferior exited due to a signal. Contains the signal that killed the
     /// process.
     Signaled(signal::Signal),
+
+    /// Indicates the inferior stopped at a syscall caught with `catch syscall`. Contains the
+    /// syscall, and the instruction pointer (just past the syscall instruction).
+    Syscall(SyscallStop, usize),
+
+    /// Indicates the inferior exec'd a new program, and stopped so that breakpoints can be set in
+    /// it (the old ones are gone with the old program). Contains the path of the new program.
+    Exec(String),
+}
+
+/// The two places a caught syscall stops the inferior
+pub enum SyscallStop {
+    /// On the way in. Contains the syscall number and its six possible arguments.
+    Call(u64, [u64; 6]),
+    /// On the way out. Contains the syscall number and what it returned.
+    Return(u64, i64),
+}
+
+/// Which syscalls `catch syscall` stops at: all of them, or those listed
+#[derive(Debug, Clone, Default)]
+pub struct SyscallCatches {
+    pub all: bool,
+    pub numbers: HashSet<u64>,
+}
+
+impl SyscallCatches {
+    fn any(&self) -> bool {
+        self.all || !self.numbers.is_empty()
+    }
+
+    fn catches(&self, number: u64) -> bool {
+        self.all || self.numbers.contains(&number)
+    }
+}
+
+/// What to do when the inferior gets a signal: whether to stop and tell the user, and whether to
+/// deliver it to the inferior when it resumes (or swallow it)
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct SignalHandling {
+    pub stop: bool,
+    pub pass: bool,
+}
+
+impl SignalHandling {
+    /// How a signal is handled unless the user says otherwise. Signals programs get in the normal
+    /// course of things pass straight through, and SIGINT (usually meant for the debugger) is
+    /// swallowed; everything else stops and is delivered.
+    pub fn default_for(signal: signal::Signal) -> SignalHandling {
+        use signal::Signal::*;
+        match signal {
+            SIGALRM | SIGURG | SIGCHLD | SIGWINCH | SIGIO | SIGVTALRM | SIGPROF => {
+                SignalHandling { stop: false, pass: true }
+            }
+            SIGINT | SIGTRAP => SignalHandling { stop: true, pass: false },
+            _ => SignalHandling { stop: true, pass: true },
+        }
+    }
+}
+
+/// Which process to keep debugging when the inferior forks
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum FollowForkMode {
+    Parent,
+    Child,
 }
 
 /// This function calls ptrace with PTRACE_TRACEME to enable debugging on a process. You should use
@@ -33,30 +101,330 @@ fn child_traceme() -> Result<(), std::io::Error> {
     )))
 }
 
+/// Takes the shell-style redirections (`< in`, `> out`, `>> out`, `2> err`, `2>> err`, with or
+/// without a space before the file) out of `args`, pointing the command's standard streams at
+/// the files they name. Returns the arguments that are left.
+fn redirect(cmd: &mut Command, args: &[String]) -> Result<Vec<String>, String> {
+    let mut remaining = Vec::new();
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        let operator = ["2>>", "2>", ">>", ">", "<"].iter().find(|op| arg.starts_with(*op));
+        let operator = match operator {
+            Some(operator) => *operator,
+            None => {
+                remaining.push(arg.clone());
+                continue;
+            }
+        };
+        let path = if arg.len() > operator.len() {
+            arg[operator.len()..].to_string()
+        } else {
+            match args.next() {
+                Some(path) => path.clone(),
+                None => return Err(format!("no file to redirect {} to", operator)),
+            }
+        };
+        let file = if operator == "<" {
+            File::open(&path)
+        } else {
+            OpenOptions::new()
+                .write(true)
+                .create(true)
+                .append(operator.ends_with(">>"))
+                .truncate(!operator.ends_with(">>"))
+                .open(&path)
+        };
+        let file = file.map_err(|err| format!("could not open {}: {}", path, err))?;
+        match operator {
+            "<" => cmd.stdin(file),
+            ">" | ">>" => cmd.stdout(file),
+            _ => cmd.stderr(file),
+        };
+    }
+    Ok(remaining)
+}
+
+/// The inferior process while it's running, which ctrl+c stops, or 0 when nothing is running
+static RUNNING: AtomicI32 = AtomicI32::new(0);
+/// Set once ctrl+c has sent the running inferior a SIGSTOP, until we hear of it
+static INTERRUPTED: AtomicBool = AtomicBool::new(false);
+
+/// The debugger's SIGINT handler. The terminal sends ctrl+c to the inferior as well, but it can
+/// have blocked SIGINT or moved to a process group of its own, so the inferior is also sent a
+/// SIGSTOP, which can't be missed, and which `wait` takes as the interrupt.
+pub extern "C" fn interrupt(_signal: libc::c_int) {
+    let pid = RUNNING.load(Ordering::SeqCst);
+    if pid != 0 {
+        INTERRUPTED.store(true, Ordering::SeqCst);
+        unsafe {
+            libc::syscall(libc::SYS_tgkill, pid, pid, libc::SIGSTOP);
+        }
+    }
+}
+
+/// Returns whether `tid` has a SIGINT waiting to be delivered to it (and not blocked)
+fn sigint_pending(pid: Pid, tid: Pid) -> bool {
+    let status = fs::read_to_string(format!("/proc/{}/task/{}/status", pid, tid)).unwrap_or_default();
+    let mask = |field: &str| {
+        status
+            .lines()
+            .find(|line| line.starts_with(field))
+            .and_then(|line| u64::from_str_radix(line[field.len()..].trim(), 16).ok())
+            .unwrap_or(0)
+    };
+    let pending = (mask("SigPnd:") | mask("ShdPnd:")) & !mask("SigBlk:");
+    pending & (1 << (libc::SIGINT - 1)) != 0
+}
+
 fn align_addr_to_word(addr: usize) -> usize {
     addr & (-(size_of::<usize>() as isize) as usize)
 }
 
+/// Names the function and line `addr` is in, or just gives the address if it has no debug info
+fn describe_location(debug_data: &DwarfData, addr: usize) -> String {
+    match (debug_data.get_function_from_addr(addr), debug_data.get_line_from_addr(addr)) {
+        (Some(func), Some(line)) => format!("{} ({})", func, line),
+        _ => format!("{:#x} in ??", addr),
+    }
+}
+
+/// Writes a byte into the memory of a traced process, returning the byte that was there
+fn write_byte_in(pid: Pid, addr: usize, val: u8) -> Result<u8, nix::Error> {
+    let aligned_addr = align_addr_to_word(addr);
+    let byte_offset = addr - aligned_addr;
+    let word = ptrace::read(pid, aligned_addr as ptrace::AddressType)? as u64;
+    let orig_byte = (word >> 8 * byte_offset) & 0xff;
+    let masked_word = word & !(0xff << 8 * byte_offset);
+    let updated_word = masked_word | ((val as u64) << 8 * byte_offset);
+    ptrace::write(
+        pid,
+        aligned_addr as ptrace::AddressType,
+        updated_word as *mut std::ffi::c_void
+    )?;
+
+    Ok(orig_byte as u8)
+}
+
+/// How many elements of an array `print` shows before giving up with "..."
+const MAX_ARRAY_ELEMENTS: usize = 100;
+
+/// How many characters of a C string `print` shows before giving up with "..."
+const MAX_STRING_LENGTH: usize = 200;
+
+/// How deep a stack we unwind, in case the frame pointers lead us round in circles
+const MAX_FRAMES: usize = 256;
+
+/// The result of evaluating (part of) an expression passed to `print`
+enum Value {
+    Int(i64),
+    Float(f64),
+    /// An address, and the type of what's there (None for void)
+    Pointer(usize, Option<Type>),
+    /// Something in the inferior's memory: where it is, and its type
+    Object(usize, Type),
+}
+
+fn is_char(entity_type: &Type) -> bool {
+    entity_type.size == 1 && entity_type.name.contains("char")
+}
+
+/// Writes out the bytes of a string in quotes, escaping anything that isn't printable
+fn quote_string(bytes: &[u8], truncated: bool) -> String {
+    let escaped: String = bytes
+        .iter()
+        .flat_map(|byte| std::ascii::escape_default(*byte))
+        .map(char::from)
+        .collect();
+    format!("\"{}\"{}", escaped, if truncated { "..." } else { "" })
+}
+
+/// Reads a little-endian integer of up to 8 bytes, sign extending it if it's signed
+fn to_int(bytes: &[u8], signed: bool) -> i64 {
+    let mut word = [0u8; 8];
+    word[..bytes.len()].copy_from_slice(bytes);
+    let value = u64::from_le_bytes(word);
+    let unused_bits = 64 - 8 * bytes.len() as u32;
+    if signed && unused_bits > 0 && unused_bits < 64 {
+        ((value << unused_bits) as i64) >> unused_bits
+    } else {
+        value as i64
+    }
+}
+
+/// Makes a number out of a base type's bytes, telling the kind of number apart by its name
+fn decode_base(entity_type: &Type, bytes: &[u8]) -> Result<Value, String> {
+    let name = entity_type.name.as_str();
+    if name.contains("float") || name.contains("double") {
+        return match bytes.len() {
+            4 => Ok(Value::Float(f32::from_le_bytes(bytes.try_into().unwrap()) as f64)),
+            8 => Ok(Value::Float(f64::from_le_bytes(bytes.try_into().unwrap()))),
+            _ => Err(format!("type \"{}\" not support yet.", entity_type)),
+        };
+    }
+    if bytes.len() > 8 {
+        return Err(format!("type \"{}\" not support yet.", entity_type));
+    }
+    let signed = !name.contains("unsigned") && name != "_Bool";
+    Ok(Value::Int(to_int(bytes, signed)))
+}
+
+/// Returns the address of the `index`th element of `size` bytes from `addr`
+fn offset_addr(addr: usize, index: i64, size: usize) -> usize {
+    (addr as i64).wrapping_add(index.wrapping_mul(size as i64)) as usize
+}
+
+fn as_float(value: Value) -> f64 {
+    match value {
+        Value::Int(value) => value as f64,
+        Value::Float(value) => value,
+        _ => unreachable!(),
+    }
+}
+
+fn apply_binary(op: BinaryOp, lhs: Value, rhs: Value) -> Result<Value, String> {
+    match (lhs, rhs) {
+        (Value::Int(lhs), Value::Int(rhs)) => match op {
+            BinaryOp::Add => Ok(Value::Int(lhs.wrapping_add(rhs))),
+            BinaryOp::Sub => Ok(Value::Int(lhs.wrapping_sub(rhs))),
+            BinaryOp::Mul => Ok(Value::Int(lhs.wrapping_mul(rhs))),
+            BinaryOp::Div | BinaryOp::Rem if rhs == 0 => Err("division by zero".to_string()),
+            BinaryOp::Div => Ok(Value::Int(lhs.wrapping_div(rhs))),
+            BinaryOp::Rem => Ok(Value::Int(lhs.wrapping_rem(rhs))),
+        },
+        (Value::Pointer(addr, target), Value::Int(index)) => {
+            let size = target.as_ref().map_or(1, |target| target.size);
+            match op {
+                BinaryOp::Add => Ok(Value::Pointer(offset_addr(addr, index, size), target)),
+                BinaryOp::Sub => Ok(Value::Pointer(offset_addr(addr, -index, size), target)),
+                _ => Err("pointers can only be added to or subtracted from".to_string()),
+            }
+        }
+        (Value::Int(index), Value::Pointer(addr, target)) if op == BinaryOp::Add => {
+            let size = target.as_ref().map_or(1, |target| target.size);
+            Ok(Value::Pointer(offset_addr(addr, index, size), target))
+        }
+        (Value::Pointer(lhs, target), Value::Pointer(rhs, _)) if op == BinaryOp::Sub => {
+            let size = target.as_ref().map_or(1, |target| target.size.max(1));
+            Ok(Value::Int((lhs as i64 - rhs as i64) / size as i64))
+        }
+        (Value::Pointer(..), _) | (_, Value::Pointer(..)) => {
+            Err("invalid operation on a pointer".to_string())
+        }
+        (lhs, rhs) => {
+            let (lhs, rhs) = (as_float(lhs), as_float(rhs));
+            match op {
+                BinaryOp::Add => Ok(Value::Float(lhs + rhs)),
+                BinaryOp::Sub => Ok(Value::Float(lhs - rhs)),
+                BinaryOp::Mul => Ok(Value::Float(lhs * rhs)),
+                BinaryOp::Div => Ok(Value::Float(lhs / rhs)),
+                BinaryOp::Rem => Ok(Value::Float(lhs % rhs)),
+            }
+        }
+    }
+}
+
+/// A stack frame: the instruction it's at, and its frame pointer
+#[derive(Debug, Clone, Copy)]
+pub struct Frame {
+    pub rip: usize,
+    pub rbp: usize,
+}
+
+/// A thread of the inferior, numbered in the order we saw it start
+struct Thread {
+    number: usize,
+    tid: Pid,
+    /// Whether the thread may be running, so has to be stopped before we can look at it
+    running: bool,
+    /// Whether a SIGSTOP we sent (or the one new threads start with) has yet to arrive. It's
+    /// swallowed when it does, rather than reported as a stop.
+    sigstop_pending: bool,
+    /// A signal the thread got while we were stopping it, to be delivered when it resumes
+    pending_signal: Option<signal::Signal>,
+    /// Whether the thread's last syscall stop was the way into a syscall, so the next one is
+    /// the way out. Only kept up to date while syscalls are traced.
+    in_syscall: bool,
+}
+
 pub struct Inferior {
-    child: Child,
+    /// The process being debugged. This is the child we started, unless we followed a fork.
+    pid: Pid,
     pub breakpoints: HashMap<usize, u8>,
-    tmp_bp_key: usize
+    /// Breakpoints (also in `breakpoints`) to delete once hit
+    pub temporary_breakpoints: HashSet<usize>,
+    pub follow_fork_mode: FollowForkMode,
+    /// How signals are handled where the user changed it from the default
+    pub signal_handling: HashMap<signal::Signal, SignalHandling>,
+    pub syscall_catches: SyscallCatches,
+    tmp_bp_key: usize,
+    threads: Vec<Thread>,
+    /// The thread that last stopped, or that the user switched to. Registers are read from and
+    /// single steps taken in this thread.
+    current: Pid,
+    next_thread_number: usize,
+    /// The current thread's stack as of when it last stopped, innermost first. Empty while it runs,
+    /// or if it hasn't been unwound.
+    frames: Vec<Frame>,
+    /// The frame that printing variables and listing source happen in
+    selected_frame: usize,
+    /// Children that stopped before we heard about the fork that made them
+    forked: HashSet<Pid>,
+    /// When following the child of a vfork: the parent's threads, which share the child's memory
+    /// (breakpoints and all) until it execs or exits, so can't be let go of before then
+    vfork_parent: Option<Vec<Thread>>,
 }
 
 impl Inferior {
     /// Attempts to start a new inferior process. Returns Some(Inferior) if successful, or None if
-    /// an error is encountered.
-    pub fn new(target: &str, args: &Vec<String>, breakpoints: &HashMap<usize, u8>) -> Option<Inferior> {
+    /// an error is encountered. `args` may redirect the inferior's input and output, as a shell
+    /// would.
+    pub fn new(
+        target: &str,
+        args: &Vec<String>,
+        breakpoints: &HashMap<usize, u8>,
+        follow_fork_mode: FollowForkMode,
+        signal_handling: &HashMap<signal::Signal, SignalHandling>,
+    ) -> Option<Inferior> {
         let mut cmd = Command::new(target);
-        cmd.args(args);
+        match redirect(&mut cmd, args) {
+            Ok(args) => cmd.args(args),
+            Err(err) => {
+                println!("Error {}", err);
+                return None;
+            }
+        };
         unsafe {
             cmd.pre_exec(child_traceme);
         }
+        let child = cmd.spawn().ok()?;
+        let pid = Pid::from_raw(child.id() as i32);
+        // The child stops with SIGTRAP once it has exec'd the target. From then on, have every
+        // thread and process it creates traced too, and hear about it exec'ing.
+        waitpid(pid, None).ok()?;
+        let options = ptrace::Options::PTRACE_O_TRACECLONE
+            | ptrace::Options::PTRACE_O_TRACEFORK
+            | ptrace::Options::PTRACE_O_TRACEVFORK
+            | ptrace::Options::PTRACE_O_TRACEVFORKDONE
+            | ptrace::Options::PTRACE_O_TRACEEXEC
+            | ptrace::Options::PTRACE_O_TRACESYSGOOD;
+        ptrace::setoptions(pid, options).ok()?;
         let mut inferior = Inferior {
-            child: cmd.spawn().ok()?,
+            pid,
             breakpoints: HashMap::new(),
-            tmp_bp_key: 0
+            temporary_breakpoints: HashSet::new(),
+            follow_fork_mode,
+            signal_handling: signal_handling.clone(),
+            syscall_catches: SyscallCatches::default(),
+            tmp_bp_key: 0,
+            threads: Vec::new(),
+            current: pid,
+            next_thread_number: 1,
+            frames: Vec::new(),
+            selected_frame: 0,
+            forked: HashSet::new(),
+            vfork_parent: None,
         };
+        inferior.add_thread(pid, false);
 
         for addr in breakpoints.keys() {
             match inferior.write_byte(*addr, 0xcc) {
@@ -72,29 +440,470 @@ impl Inferior {
 
     /// Returns the pid of this inferior.
     pub fn pid(&self) -> Pid {
-        nix::unistd::Pid::from_raw(self.child.id() as i32)
+        self.pid
     }
 
-    /// Calls waitpid on this inferior and returns a Status to indicate the state of the process
-    /// after the waitpid call.
-    pub fn wait(&self, options: Option<WaitPidFlag>) -> Result<Status, nix::Error> {
-        Ok(match waitpid(self.pid(), options)? {
-            WaitStatus::Exited(_pid, exit_code) => Status::Exited(exit_code),
-            WaitStatus::Signaled(_pid, signal, _core_dumped) => Status::Signaled(signal),
-            WaitStatus::Stopped(_pid, signal) => {
-                let regs = ptrace::getregs(self.pid())?;
-                Status::Stopped(signal, regs.rip as usize)
+    /// Returns the id of the current thread
+    fn tid(&self) -> Pid {
+        self.current
+    }
+
+    /// Returns the number and id of the current thread, and how many threads there are
+    pub fn current_thread(&self) -> (usize, Pid, usize) {
+        let number = self.thread(self.current).map_or(0, |thread| thread.number);
+        (number, self.current, self.threads.len())
+    }
+
+    fn thread(&self, tid: Pid) -> Option<&Thread> {
+        self.threads.iter().find(|thread| thread.tid == tid)
+    }
+
+    fn thread_mut(&mut self, tid: Pid) -> Option<&mut Thread> {
+        self.threads.iter_mut().find(|thread| thread.tid == tid)
+    }
+
+    /// Starts tracking a thread. New threads start out stopping themselves with SIGSTOP, and we
+    /// can't touch them until that stop arrives.
+    fn add_thread(&mut self, tid: Pid, new: bool) {
+        let number = self.next_thread_number;
+        self.next_thread_number += 1;
+        if new {
+            println!("[New thread {} (LWP {})]", number, tid);
+        }
+        self.threads.push(Thread {
+            number,
+            tid,
+            running: new,
+            sigstop_pending: new,
+            pending_signal: None,
+            in_syscall: false,
+        });
+    }
+
+    fn remove_thread(&mut self, tid: Pid) {
+        if let Some(index) = self.threads.iter().position(|thread| thread.tid == tid) {
+            let thread = self.threads.remove(index);
+            if tid != self.pid() {
+                println!("[Thread {} (LWP {}) exited]", thread.number, tid);
+            }
+        }
+    }
+
+    /// Waits for some thread to stop (or the whole inferior to exit), looking after threads and
+    /// processes coming and going along the way, then stops every other thread so the user sees
+    /// the inferior standing still. Returns a Status to indicate the state of the process.
+    fn wait(&mut self) -> Result<Status, nix::Error> {
+        INTERRUPTED.store(false, Ordering::SeqCst);
+        let status = self.wait_running();
+        RUNNING.store(0, Ordering::SeqCst);
+        status
+    }
+
+    fn wait_running(&mut self) -> Result<Status, nix::Error> {
+        loop {
+            // Following a fork can leave us with a different process to interrupt
+            RUNNING.store(self.pid().as_raw(), Ordering::SeqCst);
+            let status = waitpid(Pid::from_raw(-1), Some(WaitPidFlag::__WALL))?;
+            match status {
+                WaitStatus::Exited(tid, exit_code) if tid == self.pid() => {
+                    self.release_vfork_parent()?;
+                    return Ok(Status::Exited(exit_code))
+                }
+                WaitStatus::Signaled(tid, signal, _core_dumped) if tid == self.pid() => {
+                    self.release_vfork_parent()?;
+                    return Ok(Status::Signaled(signal))
+                }
+                WaitStatus::Exited(tid, _) | WaitStatus::Signaled(tid, _, _) => {
+                    self.remove_thread(tid);
+                }
+                WaitStatus::PtraceEvent(tid, _, event) if event == ptrace::Event::PTRACE_EVENT_CLONE as i32 => {
+                    let new_tid = Pid::from_raw(ptrace::getevent(tid)? as i32);
+                    if self.thread(new_tid).is_none() {
+                        self.add_thread(new_tid, true);
+                    }
+                    self.resume(tid, None)?;
+                }
+                WaitStatus::PtraceEvent(tid, _, event)
+                    if event == ptrace::Event::PTRACE_EVENT_FORK as i32
+                        || event == ptrace::Event::PTRACE_EVENT_VFORK as i32 =>
+                {
+                    let vfork = event == ptrace::Event::PTRACE_EVENT_VFORK as i32;
+                    let followed = self.follow_fork(tid, vfork)?;
+                    self.resume(followed, None)?;
+                    self.thread_mut(followed).unwrap().running = true;
+                }
+                WaitStatus::PtraceEvent(tid, _, event) if event == ptrace::Event::PTRACE_EVENT_VFORK_DONE as i32 => {
+                    // The child we let go of after a vfork is done with our memory, so the
+                    // breakpoints we took out of it can go back in
+                    self.write_breakpoints(tid, true)?;
+                    self.resume(tid, None)?;
+                }
+                WaitStatus::PtraceEvent(_, _, event) if event == ptrace::Event::PTRACE_EVENT_EXEC as i32 => {
+                    return self.exec_stop();
+                }
+                // A new process can stop before we hear about the fork that made it
+                WaitStatus::Stopped(tid, signal::Signal::SIGSTOP)
+                    if self.thread(tid).is_none() && !self.in_process(tid) =>
+                {
+                    self.forked.insert(tid);
+                }
+                WaitStatus::Stopped(tid, signal::Signal::SIGSTOP)
+                    if self.thread(tid).map_or(true, |thread| thread.sigstop_pending) =>
+                {
+                    // A new thread can stop before we hear from the thread that created it
+                    if self.thread(tid).is_none() {
+                        self.add_thread(tid, true);
+                    }
+                    self.thread_mut(tid).unwrap().sigstop_pending = false;
+                    self.resume(tid, None)?;
+                }
+                // The SIGSTOP ctrl+c sent. If the SIGINT the terminal sent is yet to come, that's
+                // what we stop for instead, so the user doesn't see two stops.
+                WaitStatus::Stopped(tid, signal::Signal::SIGSTOP)
+                    if tid == self.pid() && INTERRUPTED.load(Ordering::SeqCst) =>
+                {
+                    INTERRUPTED.store(false, Ordering::SeqCst);
+                    if sigint_pending(self.pid(), tid) {
+                        self.resume(tid, None)?;
+                    } else {
+                        return self.stopped(tid, signal::Signal::SIGINT);
+                    }
+                }
+                // Our breakpoints and single steps are SIGTRAPs, so those always stop
+                WaitStatus::Stopped(tid, signal)
+                    if signal != signal::Signal::SIGTRAP && !self.handling(signal).stop =>
+                {
+                    if signal == signal::Signal::SIGINT {
+                        self.take_interrupt();
+                    }
+                    let pass = self.handling(signal).pass;
+                    self.resume(tid, if pass { Some(signal) } else { None })?;
+                }
+                WaitStatus::PtraceSyscall(tid) => {
+                    let entering = self.toggle_in_syscall(tid);
+                    let regs = ptrace::getregs(tid)?;
+                    let number = regs.orig_rax;
+                    if !self.syscall_catches.catches(number) {
+                        self.resume(tid, None)?;
+                        continue;
+                    }
+                    let stop = if entering {
+                        SyscallStop::Call(number, [regs.rdi, regs.rsi, regs.rdx, regs.r10, regs.r8, regs.r9])
+                    } else {
+                        SyscallStop::Return(number, regs.rax as i64)
+                    };
+                    self.current = tid;
+                    if let Some(thread) = self.thread_mut(tid) {
+                        thread.running = false;
+                    }
+                    if self.stop_all()? {
+                        return self.exec_stop();
+                    }
+                    return Ok(Status::Syscall(stop, regs.rip as usize));
+                }
+                WaitStatus::Stopped(tid, signal) => {
+                    if signal == signal::Signal::SIGINT {
+                        self.take_interrupt();
+                    }
+                    return self.stopped(tid, signal);
+                }
+                other => panic!("waitpid returned unexpected status: {:?}", other),
             }
-            other => panic!("waitpid returned unexpected status: {:?}", other),
-        })
+        }
+    }
+
+    /// Stops the rest of the inferior now that `tid` has stopped with `signal`
+    fn stopped(&mut self, tid: Pid, signal: signal::Signal) -> Result<Status, nix::Error> {
+        self.current = tid;
+        let pass = signal != signal::Signal::SIGTRAP && self.handling(signal).pass;
+        if let Some(thread) = self.thread_mut(tid) {
+            thread.running = false;
+            if pass {
+                thread.pending_signal = Some(signal);
+            }
+        }
+        if self.stop_all()? {
+            return self.exec_stop();
+        }
+        let rip = self.get_rip()?;
+        if signal == signal::Signal::SIGTRAP && self.temporary_breakpoints.remove(&(rip - 1)) {
+            // A temporary breakpoint has done its job: take it out, and back up over it so the
+            // instruction it replaced runs next
+            self.remove_breakpoint(rip - 1);
+            let mut regs = ptrace::getregs(tid)?;
+            regs.rip = (rip - 1) as u64;
+            ptrace::setregs(tid, regs)?;
+            return Ok(Status::Stopped(signal, rip - 1));
+        }
+        Ok(Status::Stopped(signal, rip))
+    }
+
+    /// Called when the inferior gets a SIGINT, which is as good as an interrupt: if ctrl+c sent
+    /// it, the SIGSTOP sent along with it is swallowed once it turns up
+    fn take_interrupt(&mut self) {
+        if INTERRUPTED.swap(false, Ordering::SeqCst) {
+            let pid = self.pid();
+            if let Some(thread) = self.thread_mut(pid) {
+                thread.sigstop_pending = true;
+            }
+        }
+    }
+
+    fn handling(&self, signal: signal::Signal) -> SignalHandling {
+        self.signal_handling
+            .get(&signal)
+            .copied()
+            .unwrap_or_else(|| SignalHandling::default_for(signal))
+    }
+
+    /// Returns whether `tid` is a thread of the process being debugged
+    fn in_process(&self, tid: Pid) -> bool {
+        Path::new(&format!("/proc/{}/task/{}", self.pid(), tid)).exists()
+    }
+
+    /// Deals with `parent` having forked (or vforked): detaches from the child if following the
+    /// parent, or from the parent if following the child. Returns the (stopped) thread we follow.
+    fn follow_fork(&mut self, parent: Pid, vfork: bool) -> Result<Pid, nix::Error> {
+        let child = Pid::from_raw(ptrace::getevent(parent)? as i32);
+        // The child starts out stopped, though we may have seen that already
+        if !self.forked.remove(&child) {
+            waitpid(child, Some(WaitPidFlag::__WALL))?;
+        }
+        match self.follow_fork_mode {
+            FollowForkMode::Parent => {
+                // The child has a copy of our breakpoints, which would kill it once untraced. A
+                // vfork child shares our memory, so they're put back when it's done with it.
+                self.write_breakpoints(child, false)?;
+                ptrace::detach(child, None)?;
+                println!("[Detaching after {} from child process {}]", if vfork { "vfork" } else { "fork" }, child);
+                Ok(parent)
+            }
+            FollowForkMode::Child => {
+                if let Some(thread) = self.thread_mut(parent) {
+                    thread.running = false;
+                }
+                if self.stop_all()? {
+                    // Another of its threads exec'd, and is all that's left of it. The new
+                    // program has none of our breakpoints to take out.
+                    self.threads.clear();
+                    ptrace::detach(self.pid(), None)?;
+                }
+                let parent_threads: Vec<Thread> = self.threads.drain(..).collect();
+                println!("[Attaching after process {} fork to child process {}]", self.pid(), child);
+                if vfork {
+                    self.vfork_parent = Some(parent_threads);
+                } else {
+                    self.detach_process(parent_threads)?;
+                }
+                self.pid = child;
+                self.current = child;
+                self.next_thread_number = 1;
+                self.add_thread(child, false);
+                Ok(child)
+            }
+        }
+    }
+
+    /// Takes the breakpoints out of a process we're done with, and lets its (stopped) threads go
+    fn detach_process(&self, threads: Vec<Thread>) -> Result<(), nix::Error> {
+        let leader = match threads.first() {
+            Some(thread) => thread.tid,
+            None => return Ok(()),
+        };
+        self.write_breakpoints(leader, false)?;
+        let mut sigstop_pending = false;
+        for thread in threads {
+            sigstop_pending |= thread.sigstop_pending;
+            ptrace::detach(thread.tid, thread.pending_signal)?;
+        }
+        // A SIGSTOP we sent and never collected would leave it stopped for good
+        if sigstop_pending {
+            signal::kill(leader, signal::Signal::SIGCONT)?;
+        }
+        Ok(())
+    }
+
+    /// Lets go of the parent of the vfork we followed, now the child no longer shares its memory
+    fn release_vfork_parent(&mut self) -> Result<(), nix::Error> {
+        match self.vfork_parent.take() {
+            Some(threads) => self.detach_process(threads),
+            None => Ok(()),
+        }
+    }
+
+    /// Starts afresh after the inferior exec'd: only the thread that exec'd is left (with the
+    /// process's pid), and the breakpoints went with the old program
+    fn exec_stop(&mut self) -> Result<Status, nix::Error> {
+        // The exec is reported from inside the execve, by the thread that made it
+        let in_syscall = ptrace::getevent(self.pid())
+            .ok()
+            .and_then(|former| self.thread(Pid::from_raw(former as i32)))
+            .map_or(false, |thread| thread.in_syscall);
+        self.release_vfork_parent()?;
+        self.breakpoints.clear();
+        self.temporary_breakpoints.clear();
+        self.threads.clear();
+        self.next_thread_number = 1;
+        self.add_thread(self.pid(), false);
+        self.threads[0].in_syscall = in_syscall;
+        self.current = self.pid();
+        let path = fs::read_link(format!("/proc/{}/exe", self.pid()))
+            .map(|path| path.to_string_lossy().into_owned())
+            .unwrap_or_else(|_| "?".to_string());
+        Ok(Status::Exec(path))
+    }
+
+    /// Stops every thread that may still be running. Returns true if one exec'd instead, which
+    /// took the others (and whatever stop we were after) with it; that's for `exec_stop` to report.
+    fn stop_all(&mut self) -> Result<bool, nix::Error> {
+        // Threads created while we're at it are added to the end, and get stopped too
+        let mut index = 0;
+        while index < self.threads.len() {
+            let (tid, running, sigstop_pending) = {
+                let thread = &self.threads[index];
+                (thread.tid, thread.running, thread.sigstop_pending)
+            };
+            if !running {
+                index += 1;
+                continue;
+            }
+            if !sigstop_pending {
+                unsafe {
+                    libc::syscall(libc::SYS_tgkill, self.pid().as_raw(), tid.as_raw(), libc::SIGSTOP);
+                }
+                self.threads[index].sigstop_pending = true;
+            }
+            match waitpid(tid, Some(WaitPidFlag::__WALL))? {
+                WaitStatus::Stopped(_, signal::Signal::SIGSTOP) => {
+                    self.threads[index].sigstop_pending = false;
+                }
+                WaitStatus::Stopped(_, signal) => {
+                    // It stopped for something else before our SIGSTOP got to it. If it hit a
+                    // breakpoint, back it up so it hits it again once resumed; any other signal
+                    // is passed on then, if it's to be passed at all.
+                    let mut regs = ptrace::getregs(tid)?;
+                    let rip = regs.rip as usize - 1;
+                    if signal == signal::Signal::SIGTRAP && self.breakpoints.contains_key(&rip) {
+                        regs.rip = rip as u64;
+                        ptrace::setregs(tid, regs)?;
+                    } else if signal != signal::Signal::SIGTRAP && self.handling(signal).pass {
+                        self.threads[index].pending_signal = Some(signal);
+                    }
+                }
+                WaitStatus::PtraceEvent(_, _, event) if event == ptrace::Event::PTRACE_EVENT_CLONE as i32 => {
+                    let new_tid = Pid::from_raw(ptrace::getevent(tid)? as i32);
+                    if self.thread(new_tid).is_none() {
+                        self.add_thread(new_tid, true);
+                    }
+                }
+                WaitStatus::PtraceEvent(_, _, event)
+                    if event == ptrace::Event::PTRACE_EVENT_FORK as i32
+                        || event == ptrace::Event::PTRACE_EVENT_VFORK as i32 =>
+                {
+                    // Too late to switch to the child without losing the stop we're reporting,
+                    // so it's let go whatever the follow-fork mode
+                    let child = Pid::from_raw(ptrace::getevent(tid)? as i32);
+                    if !self.forked.remove(&child) {
+                        waitpid(child, Some(WaitPidFlag::__WALL))?;
+                    }
+                    self.write_breakpoints(child, false)?;
+                    ptrace::detach(child, None)?;
+                    println!("[Detaching after fork from child process {}]", child);
+                }
+                // A vfork child it was waiting on is done with our memory. Our SIGSTOP is still
+                // to come.
+                WaitStatus::PtraceEvent(_, _, event) if event == ptrace::Event::PTRACE_EVENT_VFORK_DONE as i32 => {
+                    self.write_breakpoints(tid, true)?;
+                    self.resume(tid, None)?;
+                    continue;
+                }
+                WaitStatus::PtraceEvent(_, _, event) if event == ptrace::Event::PTRACE_EVENT_EXEC as i32 => {
+                    return Ok(true);
+                }
+                // It got to a syscall first; the SIGSTOP is swallowed once it's resumed
+                WaitStatus::PtraceSyscall(_) => {
+                    self.toggle_in_syscall(tid);
+                }
+                WaitStatus::Exited(..) | WaitStatus::Signaled(..) => {
+                    self.remove_thread(tid);
+                    continue;
+                }
+                other => panic!("waitpid returned unexpected status: {:?}", other),
+            }
+            self.threads[index].running = false;
+            index += 1;
+        }
+        Ok(false)
     }
 
     pub fn continue_run(&mut self) -> Result<Status, nix::Error> {
-        self.step_over_breakpoint();
-        // resume normal execution
-        ptrace::cont(self.pid(), None)?;
+        // step every thread sitting on a breakpoint past it, before anything else runs over the
+        // instruction we put back while doing so
+        let tids: Vec<Pid> = self.threads.iter().filter(|thread| !thread.running).map(|thread| thread.tid).collect();
+        for tid in tids {
+            if let Some(status) = self.step_over_breakpoint(tid) {
+                return Ok(status);
+            }
+        }
+        self.frames.clear();
+        // resume normal execution, leaving alone new threads that haven't stopped yet
+        let catch_syscalls = self.syscall_catches.any();
+        for thread in self.threads.iter_mut().filter(|thread| !thread.running) {
+            let signal = thread.pending_signal.take();
+            if catch_syscalls {
+                ptrace::syscall(thread.tid, signal)?;
+            } else {
+                // The way out of a syscall it's in goes unreported
+                thread.in_syscall = false;
+                ptrace::cont(thread.tid, signal)?;
+            }
+            thread.running = true;
+        }
         // wait for inferior to stop or terminate
-        self.wait(None)
+        self.wait()
+    }
+
+    /// Continues `count` times, so the first `count - 1` breakpoints hit are passed over. Stops
+    /// early for anything else, such as a signal or the inferior terminating.
+    pub fn continue_times(&mut self, count: usize) -> Result<Status, nix::Error> {
+        let mut status = self.continue_run()?;
+        for _ in 1..count {
+            match status {
+                Status::Stopped(signal::Signal::SIGTRAP, rip) if self.at_breakpoint(rip) => {}
+                _ => break,
+            }
+            status = self.continue_run()?;
+        }
+        Ok(status)
+    }
+
+    /// Returns whether a SIGTRAP at `rip` was one of the user's breakpoints, rather than one we
+    /// set to stop somewhere (or a temporary breakpoint, which is gone once hit)
+    fn at_breakpoint(&self, rip: usize) -> bool {
+        self.breakpoints.contains_key(&(rip - 1)) && rip - 1 != self.tmp_bp_key
+    }
+
+    /// Lets a stopped thread carry on, stopping at syscalls too if any are caught
+    fn resume(&mut self, tid: Pid, signal: Option<signal::Signal>) -> Result<(), nix::Error> {
+        if self.syscall_catches.any() {
+            ptrace::syscall(tid, signal)
+        } else {
+            if let Some(thread) = self.thread_mut(tid) {
+                thread.in_syscall = false;
+            }
+            ptrace::cont(tid, signal)
+        }
+    }
+
+    /// Notes that `tid` made a syscall stop, returning whether it's on the way into the syscall
+    fn toggle_in_syscall(&mut self, tid: Pid) -> bool {
+        match self.thread_mut(tid) {
+            Some(thread) => {
+                thread.in_syscall = !thread.in_syscall;
+                thread.in_syscall
+            }
+            None => true,
+        }
     }
 
     pub fn set_breakpoint(&mut self, addr: usize) {
@@ -120,63 +929,154 @@ impl Inferior {
         }
     }
 
+    /// Steps `tid` past the breakpoint it's stopped at, if any. Returns the status if the
+    /// inferior terminated in doing so.
     #[allow(mutable_borrow_reservation_conflict)]
-    fn step_over_breakpoint(&mut self) {
-        let mut regs = ptrace::getregs(self.pid()).unwrap();
-        let rip = self.get_rip().unwrap() - 1;
+    fn step_over_breakpoint(&mut self, tid: Pid) -> Option<Status> {
+        let mut regs = ptrace::getregs(tid).unwrap();
+        let rip = regs.rip as usize - 1;
         // if stopped at a breakpoint
         if let Some(orig_byte) = self.breakpoints.get(&(rip)) {
             // restore the first byte of the instruction we replaced
             self.write_byte(rip, *orig_byte).unwrap();
             // rewind the instruction pointer
             regs.rip = rip as u64;
-            ptrace::setregs(self.pid(), regs).unwrap();
+            ptrace::setregs(tid, regs).unwrap();
             // go to next instruction
-            ptrace::step(self.pid(), None).unwrap();
-            // wait for inferior to stop due to SIGTRAP
-            self.wait(None).unwrap();
+            if let Some(status) = self.step_thread(tid).unwrap() {
+                return Some(status);
+            }
             // restore 0xcc in the breakpoint location
             self.write_byte(rip, 0xcc).unwrap();
         }
-        if rip == self.tmp_bp_key {
+        if rip == self.tmp_bp_key && tid == self.tid() {
             self.breakpoints.remove(&rip);
         }
+        None
     }
 
-    fn single_step_instruction(&mut self) {
-        let rip = self.get_rip().unwrap() - 1;
-        if self.breakpoints.contains_key(&rip) {
-            self.step_over_breakpoint()
+    /// Runs one instruction of the current thread: the one a breakpoint replaced, if it has
+    /// just hit that breakpoint
+    fn single_step_instruction(&mut self, hit_breakpoint: bool) -> Option<Status> {
+        if hit_breakpoint {
+            self.step_over_breakpoint(self.tid())
         } else {
-            ptrace::step(self.pid(), None).unwrap();
-            self.wait(None).unwrap();
+            self.step_thread(self.tid()).unwrap()
+        }
+    }
+
+    /// Runs one instruction of a thread, leaving the others stopped. Returns the status if the
+    /// inferior terminated instead.
+    fn step_thread(&mut self, tid: Pid) -> Result<Option<Status>, nix::Error> {
+        self.frames.clear();
+        // Single steps make no syscall stops, so a syscall it's in finishes unreported
+        if let Some(thread) = self.thread_mut(tid) {
+            thread.in_syscall = false;
+        }
+        loop {
+            ptrace::step(tid, None)?;
+            match waitpid(tid, Some(WaitPidFlag::__WALL))? {
+                // A SIGSTOP we sent earlier got in before the instruction ran
+                WaitStatus::Stopped(_, signal::Signal::SIGSTOP)
+                    if self.thread(tid).map_or(false, |thread| thread.sigstop_pending) =>
+                {
+                    self.thread_mut(tid).unwrap().sigstop_pending = false;
+                }
+                // A signal got in before the instruction ran. It's delivered when we next
+                // continue (if it's passed at all), and the step tried again.
+                WaitStatus::Stopped(_, signal) if signal != signal::Signal::SIGTRAP => {
+                    if self.handling(signal).pass {
+                        if let Some(thread) = self.thread_mut(tid) {
+                            thread.pending_signal = Some(signal);
+                        }
+                    }
+                }
+                // The instruction created a thread, which we'll wait for when we next stop
+                WaitStatus::PtraceEvent(_, _, event) if event == ptrace::Event::PTRACE_EVENT_CLONE as i32 => {
+                    let new_tid = Pid::from_raw(ptrace::getevent(tid)? as i32);
+                    if self.thread(new_tid).is_none() {
+                        self.add_thread(new_tid, true);
+                    }
+                }
+                // The instruction forked. Following the child, the step ends there, just past the
+                // fork; otherwise the parent's step carries on.
+                WaitStatus::PtraceEvent(_, _, event)
+                    if event == ptrace::Event::PTRACE_EVENT_FORK as i32
+                        || event == ptrace::Event::PTRACE_EVENT_VFORK as i32 =>
+                {
+                    let vfork = event == ptrace::Event::PTRACE_EVENT_VFORK as i32;
+                    if self.follow_fork(tid, vfork)? != tid {
+                        return Ok(None);
+                    }
+                }
+                WaitStatus::PtraceEvent(_, _, event) if event == ptrace::Event::PTRACE_EVENT_VFORK_DONE as i32 => {
+                    self.write_breakpoints(tid, true)?;
+                }
+                WaitStatus::PtraceEvent(_, _, event) if event == ptrace::Event::PTRACE_EVENT_EXEC as i32 => {
+                    return self.exec_stop().map(Some);
+                }
+                WaitStatus::Exited(_, exit_code) if tid == self.pid() => {
+                    self.release_vfork_parent()?;
+                    return Ok(Some(Status::Exited(exit_code)));
+                }
+                WaitStatus::Signaled(_, signal, _) if tid == self.pid() => {
+                    self.release_vfork_parent()?;
+                    return Ok(Some(Status::Signaled(signal)));
+                }
+                _ => return Ok(None),
+            }
         }
     }
 
     fn get_rip(&self) -> Result<usize, nix::Error> {
-        let regs = ptrace::getregs(self.pid())?;
+        let regs = ptrace::getregs(self.tid())?;
         Ok(regs.rip as usize)
     }
 
-    pub fn step_in(&mut self, debug_data: &DwarfData) {
-        let line = debug_data.get_line_from_addr(self.get_rip().unwrap()).unwrap();
+    /// Steps `count` lines, into any functions called, and shows the line stopped at. Stops
+    /// early at a breakpoint, and returns the status if the inferior terminated first.
+    pub fn step_in(&mut self, debug_data: &DwarfData, count: usize) -> Option<Status> {
+        let mut rip = self.get_rip().unwrap();
+        // Having stopped just past a breakpoint, it was hit. Later on, that only means the
+        // instruction there has run, unless we stepped onto the breakpoint itself.
+        let mut hit_breakpoint = self.breakpoints.contains_key(&(rip - 1));
+        for _ in 0..count {
+            let line = match debug_data.get_line_from_addr(rip) {
+                Some(line) => line,
+                None => {
+                    println!("Error no line information for {:#x}", rip);
+                    return None;
+                }
+            };
 
-        while debug_data.get_line_from_addr(self.get_rip().unwrap()).unwrap_or(Line {
-            file: "".to_string(),
-            number: line.number,
-            address: 0
-        }).number == line.number {
-            self.single_step_instruction();
+            while debug_data.get_line_from_addr(rip).unwrap_or(Line {
+                file: "".to_string(),
+                number: line.number,
+                address: 0
+            }).number == line.number {
+                if let Some(status) = self.single_step_instruction(hit_breakpoint) {
+                    return Some(status);
+                }
+                let next_rip = self.get_rip().unwrap();
+                hit_breakpoint = next_rip == rip + 1 && self.breakpoints.contains_key(&rip);
+                rip = next_rip;
+            }
+            // Stop early at a breakpoint, as `next` does
+            if hit_breakpoint {
+                break;
+            }
         }
 
-        let line_entry = debug_data.get_line_from_addr(self.get_rip().unwrap()).unwrap();
-        self.print_source(&line_entry);
+        if let Some(line_entry) = debug_data.get_line_from_addr(self.get_rip().unwrap()) {
+            self.print_source(&line_entry);
+        }
+        None
     }
 
     pub fn step_out(&mut self) -> Result<Status, nix::Error> {
-        let regs = ptrace::getregs(self.pid()).unwrap();
+        let regs = ptrace::getregs(self.tid()).unwrap();
         let rbp = regs.rbp;
-        let return_address = ptrace::read(self.pid(), (rbp + 8) as ptrace::AddressType).unwrap() as usize;
+        let return_address = ptrace::read(self.tid(), (rbp + 8) as ptrace::AddressType).unwrap() as usize;
 
         let mut should_remove_breakpoint = false;
         if !self.breakpoints.contains_key(&return_address) {
@@ -193,8 +1093,27 @@ impl Inferior {
         Ok(status)
     }
 
-    pub fn step_over(&mut self, debug_data: &DwarfData) -> Result<Status, nix::Error> {
-        let func = debug_data.get_function(self.get_rip().unwrap()).unwrap();
+    /// Steps `count` lines, over any functions called. Stops early at a breakpoint, or for
+    /// anything else that stops the inferior.
+    pub fn step_over(&mut self, debug_data: &DwarfData, count: usize) -> Result<Status, nix::Error> {
+        let mut status = self.next_line(debug_data)?;
+        for _ in 1..count {
+            match status {
+                Status::Stopped(signal::Signal::SIGTRAP, rip) if !self.at_breakpoint(rip) => {}
+                _ => break,
+            }
+            status = self.next_line(debug_data)?;
+        }
+        Ok(status)
+    }
+
+    fn next_line(&mut self, debug_data: &DwarfData) -> Result<Status, nix::Error> {
+        // Having returned somewhere without debug info (like out of main), there are no more
+        // lines to stop at
+        let func = match debug_data.get_function(self.get_rip()?) {
+            Some(func) => func,
+            None => return self.continue_run(),
+        };
         let func_entry = func.address;
         let func_end = func.address + func.text_length;
 
@@ -202,64 +1121,184 @@ impl Inferior {
         let mut line_number = line.number;
         let mut load_address = line.address;
         let start_line = debug_data.get_line_from_addr(self.get_rip().unwrap()).unwrap();
-        let mut to_delete = Vec::new();
+        let mut stops = Vec::new();
 
         while load_address < func_end {
-            if load_address != start_line.address && !self.breakpoints.contains_key(&load_address) {
-                self.set_breakpoint(load_address);
-                to_delete.push(load_address);
+            if load_address != start_line.address {
+                stops.push(load_address);
             }
             line_number += 1;
-            load_address = debug_data.get_addr_for_line(None, line_number).unwrap();
+            // The function may end on the last line of the file
+            load_address = match debug_data.get_addr_for_line(None, line_number) {
+                Some(addr) => addr,
+                None => break,
+            };
         }
+        stops.push(self.return_address()?);
 
-        let regs = ptrace::getregs(self.pid())?;
-        let rbp = regs.rbp;
-        let return_address = ptrace::read(self.pid(), (rbp + 8) as ptrace::AddressType).unwrap() as usize;
-        if !self.breakpoints.contains_key(&return_address) {
-            self.set_breakpoint(return_address);
-            to_delete.push(return_address);
+        self.continue_with_scratch_breakpoints(stops)
+    }
+
+    /// Returns the function we're stopped in, if it has debug info
+    pub fn current_function(&self, debug_data: &DwarfData) -> Option<Function> {
+        debug_data.get_function(self.get_rip().ok()?)
+    }
+
+    /// Runs until `addr` is reached or the current function returns, whichever comes first
+    pub fn run_until(&mut self, addr: usize) -> Result<Status, nix::Error> {
+        let return_address = self.return_address()?;
+        self.continue_with_scratch_breakpoints(vec![addr, return_address])
+    }
+
+    fn return_address(&self) -> Result<usize, nix::Error> {
+        let regs = ptrace::getregs(self.tid())?;
+        Ok(ptrace::read(self.tid(), (regs.rbp + 8) as ptrace::AddressType)? as usize)
+    }
+
+    /// Continues with breakpoints at each of `addrs` (where there isn't one already), then takes
+    /// them out again once stopped. The one we stopped at comes out when we next step past it.
+    fn continue_with_scratch_breakpoints(&mut self, addrs: Vec<usize>) -> Result<Status, nix::Error> {
+        let mut to_delete = Vec::new();
+        for addr in addrs {
+            if !self.breakpoints.contains_key(&addr) {
+                self.set_breakpoint(addr);
+                to_delete.push(addr);
+            }
         }
 
         let status = self.continue_run()?;
 
-        let current_line = debug_data.get_line_from_addr(self.get_rip().unwrap()).unwrap();
-        for addr in to_delete {
-            if addr == current_line.address - 1 {
-                self.tmp_bp_key = addr;
-            } else {
-                self.remove_breakpoint(addr);
+        if let Status::Stopped(..) | Status::Syscall(..) = status {
+            let rip = self.get_rip()?;
+            for addr in to_delete {
+                if addr == rip - 1 {
+                    self.tmp_bp_key = addr;
+                } else {
+                    self.remove_breakpoint(addr);
+                }
             }
         }
 
         Ok(status)
     }
 
+    /// Sets a breakpoint that's deleted the first time it's hit
+    pub fn set_temporary_breakpoint(&mut self, addr: usize) {
+        self.set_breakpoint(addr);
+        self.temporary_breakpoints.insert(addr);
+    }
+
     pub fn kill(&mut self) {
-        self.child.kill().unwrap();
-        self.wait(None).unwrap();
+        signal::kill(self.pid(), signal::Signal::SIGKILL).unwrap();
+        // reap every thread, or the process itself is never reported gone
+        loop {
+            match waitpid(Pid::from_raw(-1), Some(WaitPidFlag::__WALL)) {
+                Ok(WaitStatus::Exited(tid, _)) | Ok(WaitStatus::Signaled(tid, _, _)) if tid == self.pid() => break,
+                Ok(_) => continue,
+                Err(_) => break,
+            }
+        }
+        let _ = self.release_vfork_parent();
         println!("Killing running inferior (pid {})", self.pid());
     }
 
-    pub fn print_backtrace(&self, debug_data: &DwarfData) -> Result<(), nix::Error> {
-        let regs = ptrace::getregs(self.pid())?;
-        let mut rip = regs.rip as usize;
-        let mut rbp = regs.rbp as usize;
+    /// Walks the current thread's stack from where it's stopped out to main (or the first
+    /// function without debug info), and selects the innermost frame
+    pub fn unwind(&mut self, debug_data: &DwarfData) {
+        self.frames.clear();
+        self.selected_frame = 0;
+        let regs = match ptrace::getregs(self.tid()) {
+            Ok(regs) => regs,
+            Err(_) => return,
+        };
+        let mut frame = Frame { rip: regs.rip as usize, rbp: regs.rbp as usize };
 
-        loop {
-            let line = debug_data.get_line_from_addr(rip).unwrap();
-            let func = debug_data.get_function_from_addr(rip).unwrap();
-            println!("{} ({})", func, line);
+        while self.frames.len() < MAX_FRAMES {
+            self.frames.push(frame);
+            // threads other than the main one start in the C library, which has no debug info
+            match debug_data.get_function_from_addr(frame.rip) {
+                Some(func) if func != "main" => {}
+                _ => break,
+            }
 
-            if func == "main" {
-                break;
+            let rip = ptrace::read(self.tid(), (frame.rbp + 8) as ptrace::AddressType);
+            let rbp = ptrace::read(self.tid(), frame.rbp as ptrace::AddressType);
+            match (rip, rbp) {
+                (Ok(rip), Ok(rbp)) => frame = Frame { rip: rip as usize, rbp: rbp as usize },
+                _ => break,
             }
+        }
+    }
+
+    /// Returns the selected frame's number, and how many frames there are
+    pub fn selected_frame(&self) -> (usize, usize) {
+        (self.selected_frame, self.frames.len())
+    }
 
-            rip = ptrace::read(self.pid(), (rbp + 8) as ptrace::AddressType)? as usize;
-            rbp = ptrace::read(self.pid(), rbp as ptrace::AddressType)? as usize;
+    /// Makes the numbered frame (0 being the innermost) the one variables are printed from.
+    /// Returns whether there is such a frame.
+    pub fn select_frame(&mut self, number: usize) -> bool {
+        if number < self.frames.len() {
+            self.selected_frame = number;
+            true
+        } else {
+            false
         }
+    }
 
-        Ok(())
+    /// The selected frame, or the innermost one if the stack hasn't been unwound
+    fn frame(&self) -> Result<Frame, nix::Error> {
+        match self.frames.get(self.selected_frame) {
+            Some(frame) => Ok(*frame),
+            None => {
+                let regs = ptrace::getregs(self.tid())?;
+                Ok(Frame { rip: regs.rip as usize, rbp: regs.rbp as usize })
+            }
+        }
+    }
+
+    pub fn print_backtrace(&self, debug_data: &DwarfData) {
+        for (number, frame) in self.frames.iter().enumerate() {
+            println!("#{}  {}", number, describe_location(debug_data, frame.rip));
+        }
+    }
+
+    /// Prints the selected frame, and the line of source it's at
+    pub fn print_frame(&self, debug_data: &DwarfData) {
+        let frame = match self.frames.get(self.selected_frame) {
+            Some(frame) => frame,
+            None => return,
+        };
+        println!("#{}  {}", self.selected_frame, describe_location(debug_data, frame.rip));
+        if let Some(line) = debug_data.get_line_from_addr(frame.rip) {
+            self.print_source(&line);
+        }
+    }
+
+    /// Returns the line of source the selected frame is at
+    pub fn frame_line(&self, debug_data: &DwarfData) -> Option<Line> {
+        debug_data.get_line_from_addr(self.frame().ok()?.rip)
+    }
+
+    /// Lists the inferior's threads with where each is stopped, marking the current one
+    pub fn print_threads(&self, debug_data: &DwarfData) {
+        for thread in &self.threads {
+            let marker = if thread.tid == self.current { "*" } else { " " };
+            let frame = match ptrace::getregs(thread.tid) {
+                Ok(regs) => describe_location(debug_data, regs.rip as usize),
+                Err(err) => format!("<cannot read registers: {}>", err),
+            };
+            println!("{} {:<4} LWP {:<8} {}", marker, thread.number, thread.tid.as_raw(), frame);
+        }
+    }
+
+    /// Makes the numbered thread the current one, so that printing, stepping and backtraces
+    /// happen in it. Returns where it's stopped.
+    pub fn select_thread(&mut self, number: usize) -> Option<usize> {
+        let tid = self.threads.iter().find(|thread| thread.number == number)?.tid;
+        self.current = tid;
+        self.frames.clear();
+        self.get_rip().ok()
     }
 
     pub fn print_source(&self, line: &Line) {
@@ -271,78 +1310,355 @@ impl Inferior {
         }
     }
 
-    fn get_variable_value(&self, var: &Variable, name: &String) {
-        let addr;
+    /// Evaluates an expression (e.g. `x + y*2`, `arr[3]` or `*ptr`) and prints the result
+    pub fn print_variable(&self, debug_data: &DwarfData, expression: String) {
+        let result = expression::parse(&expression)
+            .and_then(|expr| self.evaluate(debug_data, &expr))
+            .and_then(|value| self.format_result(debug_data, value));
+        match result {
+            Ok((type_name, text)) => println!("{} :{} = {}", expression, type_name, text),
+            Err(err) => println!("Error {}", err),
+        }
+    }
+
+    /// Prints every local variable (or every parameter) of the selected frame's function, the way
+    /// `print` would print each
+    pub fn print_locals(&self, debug_data: &DwarfData, parameters: bool) {
+        let func = match self.frame().ok().and_then(|frame| debug_data.get_function(frame.rip)) {
+            Some(func) => func,
+            None => {
+                println!("No symbol table info available.");
+                return;
+            }
+        };
+        let vars: Vec<&Variable> = func.variables.iter().filter(|var| var.is_parameter == parameters).collect();
+        if vars.is_empty() {
+            println!("{}", if parameters { "No arguments." } else { "No locals." });
+            return;
+        }
+        for var in vars {
+            let result = self
+                .variable_address(var)
+                .map_err(|err| err.to_string())
+                .map(|addr| Value::Object(addr, var.entity_type.clone()))
+                .and_then(|value| self.format_result(debug_data, value));
+            match result {
+                Ok((type_name, text)) => println!("{} :{} = {}", var.name, type_name, text),
+                Err(err) => println!("{} :{} = <{}>", var.name, var.entity_type.name, err),
+            }
+        }
+    }
+
+    /// Looks a variable up in the selected frame's function, then in the globals, returning its
+    /// address and type
+    fn find_variable(&self, debug_data: &DwarfData, name: &str) -> Option<(usize, Type)> {
+        let func = debug_data.get_function(self.frame().ok()?.rip);
+        let local = func.as_ref().and_then(|func| func.variables.iter().find(|var| var.name == name));
+        let var = match local {
+            Some(var) => var,
+            None => debug_data.get_global_variables().into_iter().find(|var| var.name == name)?,
+        };
+        Some((self.variable_address(var).ok()?, var.entity_type.clone()))
+    }
+
+    fn variable_address(&self, var: &Variable) -> Result<usize, nix::Error> {
         match var.location {
-            Location::Address(address) => {
-                addr = address;
-            },
+            Location::Address(address) => Ok(address),
             Location::FramePointerOffset(offset) => {
-                let regs = ptrace::getregs(self.pid()).unwrap();
-                let rbp = regs.rbp;
-                addr = ((rbp as isize) + offset + 16) as usize;
+                let frame = self.frame()?;
+                Ok(((frame.rbp as isize) + offset + 16) as usize)
+            }
+        }
+    }
+
+    fn evaluate(&self, debug_data: &DwarfData, expr: &Expr) -> Result<Value, String> {
+        match expr {
+            Expr::Int(value) => Ok(Value::Int(*value)),
+            Expr::Float(value) => Ok(Value::Float(*value)),
+            Expr::Variable(name) => match self.find_variable(debug_data, name) {
+                Some((addr, entity_type)) => Ok(Value::Object(addr, entity_type)),
+                None => Err(format!("no such variable {}", name)),
+            },
+            Expr::Negate(operand) => match self.evaluate_loaded(debug_data, operand)? {
+                Value::Int(value) => Ok(Value::Int(value.wrapping_neg())),
+                Value::Float(value) => Ok(Value::Float(-value)),
+                _ => Err("can't negate a pointer".to_string()),
+            },
+            Expr::Deref(operand) => match self.evaluate_loaded(debug_data, operand)? {
+                Value::Pointer(addr, Some(target)) => Ok(Value::Object(addr, target)),
+                Value::Pointer(_, None) => Err("can't dereference a void pointer".to_string()),
+                _ => Err("can't dereference something that isn't a pointer".to_string()),
             },
+            Expr::AddressOf(operand) => match self.evaluate(debug_data, operand)? {
+                Value::Object(addr, entity_type) => Ok(Value::Pointer(addr, Some(entity_type))),
+                _ => Err("can't take the address of something not in memory".to_string()),
+            },
+            Expr::Index(array, index) => {
+                let array = self.evaluate_loaded(debug_data, array)?;
+                let index = self.evaluate_loaded(debug_data, index)?;
+                match (array, index) {
+                    (Value::Pointer(addr, Some(element)), Value::Int(index)) => {
+                        let addr = offset_addr(addr, index, element.size);
+                        Ok(Value::Object(addr, element))
+                    }
+                    (Value::Pointer(_, None), _) => Err("can't index a void pointer".to_string()),
+                    (Value::Pointer(..), _) => Err("array index isn't an integer".to_string()),
+                    _ => Err("can't index something that isn't an array or pointer".to_string()),
+                }
+            }
+            Expr::Member(operand, name) => match self.evaluate(debug_data, operand)? {
+                Value::Object(addr, Type { kind: TypeKind::Struct(members), name: struct_name, .. }) => {
+                    match members.into_iter().find(|member| &member.name == name) {
+                        Some(member) => Ok(Value::Object(addr + member.offset, member.entity_type)),
+                        None => Err(format!("{} has no member named {}", struct_name, name)),
+                    }
+                }
+                _ => Err(format!("can't get member {} of something that isn't a struct", name)),
+            },
+            Expr::Binary(op, lhs, rhs) => {
+                let lhs = self.evaluate_loaded(debug_data, lhs)?;
+                let rhs = self.evaluate_loaded(debug_data, rhs)?;
+                apply_binary(*op, lhs, rhs)
+            }
         }
-        let raw_data = ptrace::read(self.pid(), addr as ptrace::AddressType).unwrap();
-        match var.entity_type.name.as_str() {
-            "int" => {
-                let data = raw_data.bitand(0xFFFFFFFF) as i32;
-                println!("{} :{} = {}", name, var.entity_type, data);
+    }
+
+    /// Evaluates an operand that's going to be computed with, reading it out of memory
+    fn evaluate_loaded(&self, debug_data: &DwarfData, expr: &Expr) -> Result<Value, String> {
+        self.load(debug_data, self.evaluate(debug_data, expr)?)
+    }
+
+    /// Reads what's in memory into a value that can be computed with. Arrays become pointers to
+    /// their first element, the way they do in C.
+    fn load(&self, debug_data: &DwarfData, value: Value) -> Result<Value, String> {
+        let (addr, entity_type) = match value {
+            Value::Object(addr, entity_type) => (addr, entity_type),
+            other => return Ok(other),
+        };
+        match entity_type.kind {
+            TypeKind::Array(element, _) => Ok(Value::Pointer(addr, Some(*element))),
+            TypeKind::Pointer(target) => {
+                let bytes = self.read_bytes(addr, entity_type.size)?;
+                let target = target.and_then(|target| debug_data.get_type(target));
+                Ok(Value::Pointer(to_int(&bytes, false) as usize, target))
             }
-            "long int" => {
-                println!("{} :{} = {}", name, var.entity_type, raw_data);
+            TypeKind::Base => {
+                let bytes = self.read_bytes(addr, entity_type.size)?;
+                decode_base(&entity_type, &bytes)
             }
-            "float" => {
-                let mut data_32_bytes = raw_data.bitand(0xFFFFFFFF).to_be_bytes().to_vec();
-                data_32_bytes.retain(|&x| x != 0);
-                let data = f32::from_be_bytes(data_32_bytes.try_into().unwrap());
-                println!("{} :{} = {}", name, var.entity_type, data);
+            TypeKind::Struct(_) => Err(format!("can't compute with a {}", entity_type.name)),
+        }
+    }
+
+    fn read_bytes(&self, addr: usize, len: usize) -> Result<Vec<u8>, String> {
+        self.read_memory(addr, len)
+            .map_err(|_| format!("cannot access memory at address {:#x}", addr))
+    }
+
+    /// Returns the name of the result's type, and the result written out
+    fn format_result(
+        &self,
+        debug_data: &DwarfData,
+        value: Value,
+    ) -> Result<(String, String), String> {
+        match value {
+            Value::Object(addr, entity_type) => {
+                let text = self.format_object(debug_data, addr, &entity_type, true)?;
+                Ok((entity_type.name, text))
             }
-            "double" => {
-                let data_bytes = raw_data.to_be_bytes().to_vec();
-                let data = f64::from_be_bytes(data_bytes.try_into().unwrap());
-                println!("{} :{} = {}", name, var.entity_type, data);
+            Value::Int(value) => Ok(("long int".to_string(), value.to_string())),
+            Value::Float(value) => Ok(("double".to_string(), value.to_string())),
+            Value::Pointer(addr, target) => {
+                let type_name = match &target {
+                    Some(target) => format!("{} *", target.name),
+                    None => "void *".to_string(),
+                };
+                Ok((type_name, self.format_pointer(debug_data, addr, target.as_ref(), true)))
             }
-            _ => {
-                println!("Error type: \"{}\" not support yet.", var.entity_type);
+        }
+    }
+
+    /// Writes out what's at `addr`, going into arrays and structs member by member. Pointers are
+    /// followed only at the top level (`top`), so that printing a linked list doesn't walk it all.
+    fn format_object(
+        &self,
+        debug_data: &DwarfData,
+        addr: usize,
+        entity_type: &Type,
+        top: bool,
+    ) -> Result<String, String> {
+        match &entity_type.kind {
+            TypeKind::Array(element, count) if is_char(element) => {
+                let bytes = self.read_bytes(addr, *count)?;
+                let end = bytes.iter().position(|byte| *byte == 0).unwrap_or(bytes.len());
+                Ok(quote_string(&bytes[..end], false))
+            }
+            TypeKind::Array(element, count) => {
+                let mut items: Vec<String> = (0..*count.min(&MAX_ARRAY_ELEMENTS))
+                    .map(|i| self.format_nested(debug_data, addr + i * element.size, element))
+                    .collect();
+                if *count > MAX_ARRAY_ELEMENTS {
+                    items.push("...".to_string());
+                }
+                Ok(format!("{{{}}}", items.join(", ")))
             }
+            TypeKind::Struct(members) => {
+                let items: Vec<String> = members
+                    .iter()
+                    .map(|member| {
+                        let member_addr = addr + member.offset;
+                        let member_type = &member.entity_type;
+                        let value = self.format_nested(debug_data, member_addr, member_type);
+                        format!("{} = {}", member.name, value)
+                    })
+                    .collect();
+                Ok(format!("{{{}}}", items.join(", ")))
+            }
+            _ => match self.load(debug_data, Value::Object(addr, entity_type.clone()))? {
+                Value::Int(value) if is_char(entity_type) => {
+                    let escaped: String = std::ascii::escape_default(value as u8).map(char::from).collect();
+                    Ok(format!("{} '{}'", value, escaped))
+                }
+                Value::Int(value) => Ok(value.to_string()),
+                Value::Float(value) => Ok(value.to_string()),
+                Value::Pointer(addr, target) => {
+                    Ok(self.format_pointer(debug_data, addr, target.as_ref(), top))
+                }
+                Value::Object(..) => unreachable!(),
+            },
+        }
+    }
+
+    /// Writes out an element or member, with memory that can't be read noted in its place
+    fn format_nested(&self, debug_data: &DwarfData, addr: usize, entity_type: &Type) -> String {
+        self.format_object(debug_data, addr, entity_type, false)
+            .unwrap_or_else(|err| format!("<{}>", err))
+    }
+
+    /// Writes out a pointer's address. `char *`s are shown with the string they point to, and
+    /// other pointers with what they point to if `follow` is set.
+    fn format_pointer(
+        &self,
+        debug_data: &DwarfData,
+        addr: usize,
+        target: Option<&Type>,
+        follow: bool,
+    ) -> String {
+        let target = match target {
+            Some(target) if addr != 0 => target,
+            _ => return format!("{:#x}", addr),
+        };
+        if is_char(target) {
+            let string = match self.read_c_string(addr) {
+                Ok((bytes, truncated)) => quote_string(&bytes, truncated),
+                Err(err) => format!("<{}>", err),
+            };
+            format!("{:#x} {}", addr, string)
+        } else if follow {
+            format!("{:#x} -> {}", addr, self.format_nested(debug_data, addr, target))
+        } else {
+            format!("{:#x}", addr)
         }
     }
 
-    pub fn print_variable(&self, debug_data: &DwarfData, name: String) {
+    /// Reads the NUL-terminated string at `addr`, up to MAX_STRING_LENGTH bytes of it. Also
+    /// returns whether it was cut short.
+    fn read_c_string(&self, addr: usize) -> Result<(Vec<u8>, bool), String> {
+        let mut bytes = Vec::new();
+        // Read a word at a time, so we never read past the page the string ends in
+        let mut chunk_addr = addr;
+        while bytes.len() < MAX_STRING_LENGTH {
+            let chunk_len = align_addr_to_word(chunk_addr) + size_of::<usize>() - chunk_addr;
+            let chunk = self.read_bytes(chunk_addr, chunk_len)?;
+            if let Some(end) = chunk.iter().position(|byte| *byte == 0) {
+                bytes.extend_from_slice(&chunk[..end]);
+                return Ok((bytes, false));
+            }
+            bytes.extend_from_slice(&chunk);
+            chunk_addr += chunk_len;
+        }
+        bytes.truncate(MAX_STRING_LENGTH);
+        Ok((bytes, true))
+    }
+
+    /// Prints the assembly for the named function, or the one we're stopped in if no name is given,
+    /// with each source line it was compiled from and the current instruction marked
+    pub fn print_disassembly(&self, debug_data: &DwarfData, name: Option<String>) {
         let rip = self.get_rip().unwrap();
-        let func = debug_data.get_function(rip).unwrap();
-        let mut have_var = false;
-        for var in &func.variables {
-            if var.name == name {
-                have_var = true;
-                self.get_variable_value(var, &name);
+        let func = match name {
+            Some(name) => debug_data.get_function_by_name(&name),
+            None => debug_data.get_function(rip),
+        };
+        match func {
+            Some(func) => {
+                if let Err(err) = self.disassemble(debug_data, &func, rip) {
+                    println!("Error reading function {} with {}", func.name, err);
+                }
             }
+            None => println!("Error no such function"),
         }
-        for var in debug_data.get_global_variables() {
-            if var.name == name {
-                have_var = true;
-                self.get_variable_value(var, &name);
+    }
+
+    fn disassemble(&self, debug_data: &DwarfData, func: &Function, rip: usize) -> Result<(), nix::Error> {
+        let code = self.read_memory(func.address, func.text_length)?;
+        // When stopped at a breakpoint, rip is just past the 0xcc we wrote over the instruction
+        let current = if self.breakpoints.contains_key(&(rip - 1)) { rip - 1 } else { rip };
+
+        let mut decoder = Decoder::with_ip(64, &code, func.address as u64, DecoderOptions::NONE);
+        let mut formatter = IntelFormatter::new();
+        let mut instruction = Instruction::default();
+        let mut text = String::new();
+        let mut last_line = None;
+        println!("Dump of assembler code for function {}:", func.name);
+        while decoder.can_decode() {
+            decoder.decode_out(&mut instruction);
+            let addr = instruction.ip() as usize;
+            if let Some(line) = debug_data.get_line_from_addr(addr) {
+                if last_line != Some(line.number) {
+                    println!("{}", line);
+                    last_line = Some(line.number);
+                }
             }
+            text.clear();
+            formatter.format(&instruction, &mut text);
+            let marker = if addr == current { "=>" } else { "  " };
+            println!("{} {:#x} <+{}>:\t{}", marker, addr, addr - func.address, text);
+        }
+        println!("End of assembler dump.");
+
+        Ok(())
+    }
+
+    /// Reads `len` bytes of the inferior's memory starting at `addr`, with the original bytes put
+    /// back wherever we set a breakpoint
+    fn read_memory(&self, addr: usize, len: usize) -> Result<Vec<u8>, nix::Error> {
+        let start = align_addr_to_word(addr);
+        let mut bytes = Vec::new();
+        let mut word_addr = start;
+        while word_addr < addr + len {
+            let word = ptrace::read(self.tid(), word_addr as ptrace::AddressType)? as u64;
+            bytes.extend_from_slice(&word.to_le_bytes());
+            word_addr += size_of::<usize>();
         }
-        if !have_var {
-            println!("Error no such variable");
+        let mut bytes = bytes[addr - start..addr - start + len].to_vec();
+        for (bp_addr, orig_byte) in &self.breakpoints {
+            if *bp_addr >= addr && *bp_addr < addr + len {
+                bytes[bp_addr - addr] = *orig_byte;
+            }
         }
+
+        Ok(bytes)
     }
 
     pub fn write_byte(&mut self, addr: usize, val: u8) -> Result<u8, nix::Error> {
-        let aligned_addr = align_addr_to_word(addr);
-        let byte_offset = addr - aligned_addr;
-        let word = ptrace::read(self.pid(), aligned_addr as ptrace::AddressType)? as u64;
-        let orig_byte = (word >> 8 * byte_offset) & 0xff;
-        let masked_word = word & !(0xff << 8 * byte_offset);
-        let updated_word = masked_word | ((val as u64) << 8 * byte_offset);
-        ptrace::write(
-            self.pid(),
-            aligned_addr as ptrace::AddressType,
-            updated_word as *mut std::ffi::c_void
-        )?;
-
-        Ok(orig_byte as u8)
+        write_byte_in(self.tid(), addr, val)
+    }
+
+    /// Puts every breakpoint into (or takes them all out of) the memory of a process
+    fn write_breakpoints(&self, pid: Pid, insert: bool) -> Result<(), nix::Error> {
+        for (addr, orig_byte) in &self.breakpoints {
+            write_byte_in(pid, *addr, if insert { 0xcc } else { *orig_byte })?;
+        }
+        Ok(())
     }
 }
@@ -2,7 +2,9 @@ mod debugger;
 mod debugger_command;
 mod inferior;
 mod dwarf_data;
+mod expression;
 mod gimli_wrapper;
+mod syscalls;
 
 use crate::debugger::Debugger;
 use nix::sys::signal::{signal, SigHandler, Signal};
@@ -16,9 +18,9 @@ fn main() {
     }
     let target = &args[1];
 
-    // Disable handling of ctrl+c in this process (so that ctrl+c only gets delivered to child
-    // processes)
-    unsafe { signal(Signal::SIGINT, SigHandler::SigIgn) }.expect("Error disabling SIGINT handling");
+    // Have ctrl+c interrupt the inferior while it runs, and bring back the prompt
+    unsafe { signal(Signal::SIGINT, SigHandler::Handler(inferior::interrupt)) }
+        .expect("Error setting up SIGINT handling");
 
     Debugger::new(target).run();
 }
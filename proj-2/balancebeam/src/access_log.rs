@@ -0,0 +1,245 @@
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::net::IpAddr;
+use std::sync::Mutex;
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use flate2::{write::GzEncoder, Compression};
+use serde::Serialize;
+
+/// One line of the access log
+#[derive(Serialize)]
+struct Entry<'a> {
+    /// When the request came in, in RFC 3339 format
+    timestamp: String,
+    client_ip: IpAddr,
+    method: &'a str,
+    path: &'a str,
+    /// Upstream the request was sent to, if it got as far as being sent to one
+    upstream: Option<&'a str>,
+    status: u16,
+    /// Size of the response body sent to the client
+    bytes: u64,
+    /// Bytes read from the client for the request, headers included
+    bytes_received: u64,
+    /// Bytes written to the client for the response, headers included
+    bytes_sent: u64,
+    /// Time from receiving the request's headers to sending the last of the response, in
+    /// milliseconds
+    latency_ms: f64,
+}
+
+/// How much a request moved over the client's connection
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Sizes {
+    /// Bytes of response body sent to the client
+    pub body: u64,
+    /// Bytes read from the client, headers included
+    pub received: u64,
+    /// Bytes written to the client, headers included
+    pub sent: u64,
+}
+
+/// When a request came in, for working out how long it took to answer
+pub struct Timing {
+    received_at: SystemTime,
+    started: Instant,
+}
+
+impl Timing {
+    pub fn start() -> Timing {
+        Timing { received_at: SystemTime::now(), started: Instant::now() }
+    }
+
+    /// How long it's been since the request came in
+    pub fn elapsed(&self) -> Duration {
+        self.started.elapsed()
+    }
+}
+
+/// When the access log is rolled over to a new file, and what happens to the old ones. The file
+/// being written is always at the configured path; rotated files are named after it with .1
+/// (the newest), .2 and so on after it, plus .gz if they're compressed.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Rotation {
+    /// Roll over before the file grows past this many bytes (0 = no size limit)
+    pub max_size: u64,
+    /// Roll over when the day changes (in UTC)
+    pub daily: bool,
+    /// Gzip rotated files, in the background so requests aren't held up
+    pub compress: bool,
+    /// Number of rotated files to keep, deleting the oldest ones past it (0 = keep them all)
+    pub keep: usize,
+}
+
+/// Returns the number of days since the Unix epoch, in UTC
+fn day_of(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH).map(|since| since.as_secs() / 86400).unwrap_or(0)
+}
+
+/// The access log file, rolled over as the `Rotation` says
+struct RotatingFile {
+    path: String,
+    rotation: Rotation,
+    file: File,
+    /// Size of the file being written
+    size: u64,
+    /// Day the file being written was started on
+    day: u64,
+    /// Compresses the file rotated last, if that's still going on
+    compressing: Option<JoinHandle<()>>,
+}
+
+impl RotatingFile {
+    fn open(path: &str, rotation: Rotation) -> io::Result<RotatingFile> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        let metadata = file.metadata()?;
+        // A file left over from an earlier run belongs to the day it was last written on
+        let day = day_of(metadata.modified().unwrap_or_else(|_| SystemTime::now()));
+        Ok(RotatingFile {
+            path: path.to_string(),
+            rotation,
+            file,
+            size: metadata.len(),
+            day,
+            compressing: None,
+        })
+    }
+
+    /// Returns the name of the nth newest rotated file
+    fn rotated_path(&self, n: usize) -> String {
+        let suffix = if self.rotation.compress { ".gz" } else { "" };
+        format!("{}.{}{}", self.path, n, suffix)
+    }
+
+    fn write_line(&mut self, line: &str) -> io::Result<()> {
+        let len = line.len() as u64 + 1;
+        let too_big = self.rotation.max_size > 0
+            && self.size > 0
+            && self.size + len > self.rotation.max_size;
+        let today = day_of(SystemTime::now());
+        let new_day = self.rotation.daily && today != self.day;
+        if too_big || new_day {
+            // The file can still be written to if this fails, so carry on with it and try again
+            // once it's due for rotating again
+            if let Err(error) = self.rotate() {
+                log::warn!("Failed to rotate access log {}: {}", self.path, error);
+            }
+            self.size = 0;
+            self.day = today;
+        }
+        self.file.write_all(format!("{}\n", line).as_bytes())?;
+        self.size += len;
+        Ok(())
+    }
+
+    /// Moves each rotated file along by one, dropping the oldest, then moves the current file to
+    /// .1 and starts a new one
+    fn rotate(&mut self) -> io::Result<()> {
+        if let Some(compressing) = self.compressing.take() {
+            let _ = compressing.join();
+        }
+        let oldest = match self.rotation.keep {
+            0 => (1..).take_while(|&n| fs::metadata(self.rotated_path(n)).is_ok()).count() + 1,
+            keep => keep,
+        };
+        match fs::remove_file(self.rotated_path(oldest)) {
+            Err(error) if error.kind() != io::ErrorKind::NotFound => return Err(error),
+            _ => {}
+        }
+        for n in (1..oldest).rev() {
+            match fs::rename(self.rotated_path(n), self.rotated_path(n + 1)) {
+                Err(error) if error.kind() != io::ErrorKind::NotFound => return Err(error),
+                _ => {}
+            }
+        }
+        let rotated = format!("{}.1", self.path);
+        fs::rename(&self.path, &rotated)?;
+        self.file = OpenOptions::new().create(true).append(true).open(&self.path)?;
+        if self.rotation.compress {
+            let compressed = self.rotated_path(1);
+            self.compressing = Some(std::thread::spawn(move || {
+                if let Err(error) = compress(&rotated, &compressed) {
+                    log::warn!("Failed to compress rotated access log {}: {}", rotated, error);
+                }
+            }));
+        }
+        log::info!("Rotated access log {}", self.path);
+        Ok(())
+    }
+}
+
+/// Gzips the file at `from` into `to`, removing the original once that's done
+fn compress(from: &str, to: &str) -> io::Result<()> {
+    let mut input = File::open(from)?;
+    let mut encoder = GzEncoder::new(File::create(to)?, Compression::default());
+    io::copy(&mut input, &mut encoder)?;
+    encoder.finish()?;
+    fs::remove_file(from)
+}
+
+/// Where the access log goes
+enum Output {
+    Stdout,
+    File(RotatingFile),
+}
+
+/// Writes a JSON line for each request that is answered, for feeding into log processing tools.
+/// This is separate from the debug logging, which is meant for people to read.
+pub struct AccessLog {
+    output: Mutex<Output>,
+}
+
+impl AccessLog {
+    /// Opens the access log at the given path, appending to it if it already exists, and rolling
+    /// it over as `rotation` says. A path of "-" writes to stdout instead, which is never rotated.
+    pub fn open(path: &str, rotation: &Rotation) -> std::io::Result<AccessLog> {
+        let output = if path == "-" {
+            Output::Stdout
+        } else {
+            Output::File(RotatingFile::open(path, rotation.clone())?)
+        };
+        Ok(AccessLog { output: Mutex::new(output) })
+    }
+
+    /// Logs a request that was answered with the given status
+    pub fn log(
+        &self,
+        client_ip: IpAddr,
+        request: &http::Request<Vec<u8>>,
+        upstream: Option<&str>,
+        status: http::StatusCode,
+        sizes: Sizes,
+        timing: &Timing,
+    ) {
+        let entry = Entry {
+            timestamp: humantime::format_rfc3339_millis(timing.received_at).to_string(),
+            client_ip,
+            method: request.method().as_str(),
+            path: request.uri().path(),
+            upstream,
+            status: status.as_u16(),
+            bytes: sizes.body,
+            bytes_received: sizes.received,
+            bytes_sent: sizes.sent,
+            latency_ms: timing.elapsed().as_secs_f64() * 1000.0,
+        };
+        let line = match serde_json::to_string(&entry) {
+            Ok(line) => line,
+            Err(error) => {
+                log::warn!("Failed to format access log entry: {}", error);
+                return;
+            }
+        };
+        let result = match &mut *self.output.lock().unwrap() {
+            Output::Stdout => {
+                let mut stdout = io::stdout();
+                writeln!(stdout, "{}", line).and_then(|_| stdout.flush())
+            }
+            Output::File(file) => file.write_line(&line),
+        };
+        if let Err(error) = result {
+            log::warn!("Failed to write to access log: {}", error);
+        }
+    }
+}
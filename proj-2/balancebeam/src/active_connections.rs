@@ -0,0 +1,87 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use tokio::sync::futures::Notified;
+use tokio::sync::Notify;
+
+/// Number of connections currently open to each address
+type Counts = Arc<Mutex<HashMap<String, usize>>>;
+
+/// Keeps track of how many connections are open to each upstream, for load balancers that send
+/// requests to the least busy upstreams. Client connections are tracked the same way, by client
+/// address, so that we know when they're all closed when shutting down.
+pub struct ActiveConnections {
+    counts: Counts,
+    /// Notified whenever a connection is closed
+    closed: Arc<Notify>,
+}
+
+/// Held for as long as a connection to an upstream is open. Dropping it takes the connection off
+/// the upstream's count.
+pub struct ActiveConnection {
+    counts: Counts,
+    closed: Arc<Notify>,
+    address: String,
+}
+
+impl Drop for ActiveConnection {
+    fn drop(&mut self) {
+        let mut counts = self.counts.lock().unwrap();
+        if let Some(count) = counts.get_mut(&self.address) {
+            *count -= 1;
+            if *count == 0 {
+                counts.remove(&self.address);
+            }
+        }
+        self.closed.notify_waiters();
+    }
+}
+
+impl ActiveConnections {
+    pub fn new() -> ActiveConnections {
+        ActiveConnections {
+            counts: Arc::new(Mutex::new(HashMap::new())),
+            closed: Arc::new(Notify::new()),
+        }
+    }
+
+    /// Counts a new connection to the upstream, until the returned handle is dropped
+    pub fn track(&self, address: &str) -> ActiveConnection {
+        *self.counts.lock().unwrap().entry(address.to_string()).or_insert(0) += 1;
+        self.handle(address)
+    }
+
+    /// Counts a new connection to the upstream like `track`, unless there are already `max` open
+    pub fn try_track(&self, address: &str, max: usize) -> Option<ActiveConnection> {
+        let mut counts = self.counts.lock().unwrap();
+        if counts.get(address).copied().unwrap_or(0) >= max {
+            return None;
+        }
+        *counts.entry(address.to_string()).or_insert(0) += 1;
+        drop(counts);
+        Some(self.handle(address))
+    }
+
+    fn handle(&self, address: &str) -> ActiveConnection {
+        ActiveConnection {
+            counts: self.counts.clone(),
+            closed: self.closed.clone(),
+            address: address.to_string(),
+        }
+    }
+
+    /// Completes the next time any connection is closed. Connections closed after this is called
+    /// count, even if it isn't awaited until later.
+    pub fn closed(&self) -> Notified<'_> {
+        self.closed.notified()
+    }
+
+    /// Returns the number of connections currently open to the upstream
+    pub fn get(&self, address: &str) -> usize {
+        self.counts.lock().unwrap().get(address).copied().unwrap_or(0)
+    }
+
+    /// Returns the number of connections currently open to any address
+    pub fn total(&self) -> usize {
+        self.counts.lock().unwrap().values().sum()
+    }
+}
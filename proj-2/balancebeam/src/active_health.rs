@@ -0,0 +1,165 @@
+use std::convert::TryFrom;
+use std::str::FromStr;
+use std::sync::Arc;
+use std::sync::atomic::Ordering;
+use futures_util::future::join_all;
+use serde::Deserialize;
+use tokio::time::{sleep, timeout, Duration};
+use crate::config::Config;
+use crate::upstream::{self, UpstreamAddr};
+use crate::state::ProxyState;
+use crate::{request, response, ProxyMode};
+
+/// Range of HTTP status codes that an active health check accepts as healthy, written as a single
+/// code ("200") or an inclusive range ("200-399")
+#[derive(Clone, Copy, Debug, PartialEq, Deserialize)]
+#[serde(try_from = "String")]
+pub struct StatusRange {
+    min: u16,
+    max: u16,
+}
+
+impl StatusRange {
+    pub fn contains(&self, status: http::StatusCode) -> bool {
+        (self.min..=self.max).contains(&status.as_u16())
+    }
+}
+
+impl FromStr for StatusRange {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<StatusRange, String> {
+        let parse_code = |code: &str| {
+            code.trim()
+                .parse::<u16>()
+                .ok()
+                .filter(|code| (100..=599).contains(code))
+                .ok_or_else(|| format!("invalid HTTP status code {:?}", code))
+        };
+        let (min, max) = match s.split_once('-') {
+            Some((min, max)) => (parse_code(min)?, parse_code(max)?),
+            None => (parse_code(s)?, parse_code(s)?),
+        };
+        if min > max {
+            return Err(format!("invalid status range {:?}", s));
+        }
+        Ok(StatusRange { min, max })
+    }
+}
+
+impl TryFrom<String> for StatusRange {
+    type Error = String;
+
+    fn try_from(s: String) -> Result<StatusRange, String> {
+        s.parse()
+    }
+}
+
+/// What an active health check sends to each upstream, and what it expects back
+#[derive(Clone)]
+pub struct HealthCheck {
+    pub method: http::Method,
+    pub path: String,
+    pub expected_status: StatusRange,
+    /// If set, the response body must contain this string
+    pub expected_body: Option<String>,
+    /// How long the whole check (connecting, sending the request and reading the response) may take
+    pub timeout: Duration,
+}
+
+impl HealthCheck {
+    pub fn from_config(config: &Config) -> HealthCheck {
+        HealthCheck {
+            method: config.active_health_check_method.clone(),
+            path: config.active_health_check_path.clone(),
+            expected_status: config.active_health_check_status,
+            expected_body: config.active_health_check_body.clone(),
+            timeout: Duration::from_secs(config.active_health_check_timeout as u64),
+        }
+    }
+
+    fn accepts(&self, response: &http::Response<Vec<u8>>) -> bool {
+        if !self.expected_status.contains(response.status()) {
+            return false;
+        }
+        match &self.expected_body {
+            Some(expected) => String::from_utf8_lossy(response.body()).contains(expected.as_str()),
+            None => true,
+        }
+    }
+}
+
+/// Sends a health check request to the upstream with the given address. Returns None if the
+/// upstream couldn't be reached or gave a bad response.
+async fn check_server(state: &ProxyState, addr: &str, check: &HealthCheck) -> Option<bool> {
+    let mut stream = upstream::connect(addr, &state.upstream_tls).await.ok()?;
+    if state.mode == ProxyMode::Tcp {
+        // There's no telling what protocol the upstream speaks, so all we can check is that it
+        // accepts connections
+        return Some(true);
+    }
+    let request = http::Request::builder()
+        .method(check.method.clone())
+        .uri(check.path.as_str())
+        .header("Host", UpstreamAddr::parse(addr).authority)
+        .body(Vec::new())
+        .unwrap();
+    request::write_to_stream(&request, &mut stream).await.ok()?;
+    let res = response::read_from_stream(&mut stream, &check.method).await.ok()?;
+    if check.accepts(&res) {
+        Some(true)
+    } else {
+        log::debug!(
+            "Health check of {} got unexpected response {}",
+            addr,
+            response::format_response_line(&res)
+        );
+        None
+    }
+}
+
+/// Checks the upstream, giving up on it after the check's timeout. Returns true if it is healthy.
+async fn probe(state: &ProxyState, addr: &str, check: &HealthCheck) -> bool {
+    match timeout(check.timeout, check_server(state, addr, check)).await {
+        Ok(result) => result.is_some(),
+        Err(_) => {
+            log::debug!("Health check of {} timed out", addr);
+            false
+        }
+    }
+}
+
+pub async fn active_health_check(state: Arc<ProxyState>) {
+    loop {
+        let interval = state.active_health_check_interval.load(Ordering::SeqCst) as u64;
+        sleep(Duration::from_secs(interval)).await;
+        let check = state.active_health_check.read().await.clone();
+        // Check all the upstreams at once, so a slow one doesn't hold up checking the others, and
+        // without holding any locks, so requests aren't held up either
+        let addresses = state.upstream_addresses.read().await.clone();
+        let results = join_all(addresses.iter().map(|addr| probe(&state, addr, &check))).await;
+
+        let mut changed = Vec::new();
+        {
+            let current_addresses = state.upstream_addresses.read().await;
+            let upstream_status = state.upstream_status.load();
+            if *current_addresses != addresses {
+                // The upstreams changed while we were checking them, so the results may not line
+                // up with them anymore. The next round will check the new ones.
+                continue;
+            }
+            for (idx, passed) in results.into_iter().enumerate() {
+                let flipped = match passed {
+                    true => upstream_status.set_up(idx),
+                    false => upstream_status.set_down(idx),
+                };
+                if flipped {
+                    changed.push((idx, passed));
+                }
+            }
+        }
+        for (idx, healthy) in changed {
+            state.report_health_change(&addresses[idx], healthy).await;
+        }
+    }
+}
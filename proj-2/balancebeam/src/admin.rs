@@ -0,0 +1,267 @@
+use std::net::{IpAddr, SocketAddr};
+use std::sync::Arc;
+use std::sync::atomic::Ordering;
+use serde::Serialize;
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::time::Duration;
+use crate::blue_green::Color;
+use crate::listener::Listener;
+use crate::state::{AdminState, ProxyState};
+use crate::{buffer_pool, request, response};
+
+/// What the admin API reports about each upstream
+#[derive(Serialize)]
+struct UpstreamInfo {
+    address: String,
+    healthy: bool,
+    state: AdminState,
+    circuit_open: bool,
+    /// Requests being served by the upstream right now
+    in_flight: usize,
+    /// Whether the upstream is being drained and has no requests left in flight, so it can be
+    /// taken down
+    drained: bool,
+}
+
+fn make_response(status: http::StatusCode, content_type: &str, body: Vec<u8>) -> http::Response<Vec<u8>> {
+    http::Response::builder()
+        .status(status)
+        .header("Content-Type", content_type)
+        .header("Content-Length", body.len().to_string())
+        .version(http::Version::HTTP_11)
+        .body(body)
+        .unwrap()
+}
+
+fn text_response(status: http::StatusCode, message: &str) -> http::Response<Vec<u8>> {
+    make_response(status, "text/plain", format!("{}\n", message).into_bytes())
+}
+
+fn maintenance_status(state: &ProxyState) -> http::Response<Vec<u8>> {
+    let enabled = state.maintenance.load(Ordering::SeqCst);
+    let body = serde_json::to_vec_pretty(&serde_json::json!({ "enabled": enabled })).unwrap();
+    make_response(http::StatusCode::OK, "application/json", body)
+}
+
+fn set_maintenance(state: &ProxyState, enabled: bool) -> http::Response<Vec<u8>> {
+    state.maintenance.store(enabled, Ordering::SeqCst);
+    log::info!("Admin API turned maintenance mode {}", if enabled { "on" } else { "off" });
+    text_response(http::StatusCode::OK, "OK")
+}
+
+async fn deployment_status(state: &ProxyState) -> http::Response<Vec<u8>> {
+    if state.routes.read().await.color_pool(Color::Blue).is_none() {
+        return text_response(http::StatusCode::NOT_FOUND, "No blue/green pools configured");
+    }
+    let drain_timeout = state.blue_green_drain_timeout.load(Ordering::SeqCst) as u64;
+    let report = state.deployment.report(Duration::from_secs(drain_timeout));
+    let body = serde_json::to_vec_pretty(&report).unwrap();
+    make_response(http::StatusCode::OK, "application/json", body)
+}
+
+async fn switch_deployment(state: &ProxyState, color: Color) -> http::Response<Vec<u8>> {
+    if state.routes.read().await.color_pool(color).is_none() {
+        return text_response(http::StatusCode::NOT_FOUND, "No blue/green pools configured");
+    }
+    if state.deployment.switch_to(color) {
+        log::info!("Admin API switched requests over to the {:?} pool", color);
+        text_response(http::StatusCode::OK, "OK")
+    } else {
+        text_response(http::StatusCode::OK, "Already live")
+    }
+}
+
+fn buffer_stats() -> http::Response<Vec<u8>> {
+    let body = serde_json::to_vec_pretty(&buffer_pool::stats()).unwrap();
+    make_response(http::StatusCode::OK, "application/json", body)
+}
+
+async fn list_bans(state: &ProxyState) -> http::Response<Vec<u8>> {
+    let body = serde_json::to_vec_pretty(&state.ban_list.list()).unwrap();
+    make_response(http::StatusCode::OK, "application/json", body)
+}
+
+async fn unban(state: &ProxyState, ip: &str) -> http::Response<Vec<u8>> {
+    let ip: IpAddr = match ip.parse() {
+        Ok(ip) => ip,
+        Err(_) => return text_response(http::StatusCode::BAD_REQUEST, "Invalid IP address"),
+    };
+    if state.ban_list.unban(ip) {
+        log::info!("Admin API unbanned {}", ip);
+        text_response(http::StatusCode::OK, "OK")
+    } else {
+        text_response(http::StatusCode::NOT_FOUND, "Not banned")
+    }
+}
+
+fn bandwidth_usage(state: &ProxyState) -> http::Response<Vec<u8>> {
+    let body = serde_json::to_vec_pretty(&state.bandwidth.report()).unwrap();
+    make_response(http::StatusCode::OK, "application/json", body)
+}
+
+fn upstream_stats(state: &ProxyState) -> http::Response<Vec<u8>> {
+    let body = serde_json::to_vec_pretty(&state.upstream_stats.report()).unwrap();
+    make_response(http::StatusCode::OK, "application/json", body)
+}
+
+fn canary_report(state: &ProxyState) -> http::Response<Vec<u8>> {
+    let body = serde_json::to_vec_pretty(&state.canary_stats.report()).unwrap();
+    make_response(http::StatusCode::OK, "application/json", body)
+}
+
+async fn list_upstreams(state: &ProxyState) -> http::Response<Vec<u8>> {
+    let upstream_addresses = state.upstream_addresses.read().await;
+    let upstream_status = state.upstream_status.load();
+    let upstreams: Vec<UpstreamInfo> = upstream_addresses
+        .iter()
+        .enumerate()
+        .map(|(idx, address)| {
+            let admin_state = upstream_status.admin_state(idx).unwrap_or(AdminState::Enabled);
+            let in_flight = state.upstream_requests.get(address);
+            UpstreamInfo {
+                address: address.clone(),
+                healthy: upstream_status.is_healthy(idx),
+                state: admin_state,
+                circuit_open: upstream_status.is_circuit_open(idx),
+                in_flight,
+                drained: admin_state == AdminState::Draining && in_flight == 0,
+            }
+        })
+        .collect();
+    let body = serde_json::to_vec_pretty(&upstreams).unwrap();
+    make_response(http::StatusCode::OK, "application/json", body)
+}
+
+/// Handles a single admin API request:
+///
+/// * `GET /upstreams` lists the upstreams with their health, admin state, whether their circuit
+///   breaker is open, how many requests they're serving, and whether they have been drained
+/// * `GET /maintenance` reports whether maintenance mode is on
+/// * `POST /maintenance/on` answers all requests (except from `--maintenance-allow-cidr` clients)
+///   with 503 and the maintenance page, and `POST /maintenance/off` goes back to proxying them
+/// * `GET /bans` lists the clients banned for going over the rate limit too often, with the
+///   seconds left on each ban, and `POST /bans/unban` lifts the ban on the IP given in the body
+/// * `GET /buffers` reports how many times connections' I/O buffers had to allocate more room,
+///   and how many times they reused memory freed up by pieces they had passed on instead
+/// * `GET /stats` reports each upstream's request rate, error rate and 50th, 95th and 99th
+///   percentile response times (in milliseconds) over the last minute
+/// * `GET /canary` reports how many requests the canary and stable upstreams have served, and how
+///   many of them failed
+/// * `GET /deployment` reports which of the blue and green pools is live, and which one (if any)
+///   connections pinned to it are still draining from
+/// * `POST /deployment/green` sends requests that don't match any route to the green pool instead
+///   of the blue one, letting connections pinned to the blue pool finish up there for a while,
+///   and `POST /deployment/blue` switches back
+/// * `POST /upstreams` adds the upstream whose address is given in the request body
+/// * `POST /upstreams/drain` stops sending new requests to the upstream given in the body, while
+///   letting the ones in flight finish. It is listed as drained once they have, and can then be
+///   taken down.
+/// * `POST /upstreams/down` takes the upstream given in the body out of rotation, even if its
+///   health checks pass
+/// * `POST /upstreams/up` puts the upstream given in the body back into rotation
+///
+/// Drain, down and up can be given an upstream's host name to act on every address it resolved to.
+async fn handle_request(
+    request: &http::Request<Vec<u8>>,
+    state: &ProxyState,
+) -> http::Response<Vec<u8>> {
+    let address = String::from_utf8_lossy(request.body()).trim().to_string();
+    let admin_state = match (request.method(), request.uri().path()) {
+        (&http::Method::GET, "/upstreams") => return list_upstreams(state).await,
+        (&http::Method::GET, "/stats") => return upstream_stats(state),
+        (&http::Method::GET, "/canary") => return canary_report(state),
+        (&http::Method::GET, "/buffers") => return buffer_stats(),
+        (&http::Method::GET, "/bans") => return list_bans(state).await,
+        (&http::Method::GET, "/bandwidth") => return bandwidth_usage(state),
+        (&http::Method::POST, "/bans/unban") => return unban(state, &address).await,
+        (&http::Method::GET, "/maintenance") => return maintenance_status(state),
+        (&http::Method::POST, "/maintenance/on") => return set_maintenance(state, true),
+        (&http::Method::POST, "/maintenance/off") => return set_maintenance(state, false),
+        (&http::Method::GET, "/deployment") => return deployment_status(state).await,
+        (&http::Method::POST, "/deployment/blue") => {
+            return switch_deployment(state, Color::Blue).await
+        }
+        (&http::Method::POST, "/deployment/green") => {
+            return switch_deployment(state, Color::Green).await
+        }
+        (&http::Method::POST, "/upstreams") => {
+            if address.is_empty() {
+                return text_response(http::StatusCode::BAD_REQUEST, "Missing upstream address");
+            }
+            return if state.add_upstream(address.clone()).await {
+                log::info!("Admin API added upstream {}", address);
+                text_response(http::StatusCode::CREATED, "Added")
+            } else {
+                text_response(http::StatusCode::CONFLICT, "Upstream already exists")
+            };
+        }
+        (&http::Method::POST, "/upstreams/drain") => AdminState::Draining,
+        (&http::Method::POST, "/upstreams/down") => AdminState::Disabled,
+        (&http::Method::POST, "/upstreams/up") => AdminState::Enabled,
+        (_, "/upstreams")
+        | (_, "/stats")
+        | (_, "/canary")
+        | (_, "/buffers")
+        | (_, "/bans")
+        | (_, "/bandwidth")
+        | (_, "/bans/unban")
+        | (_, "/maintenance")
+        | (_, "/maintenance/on")
+        | (_, "/maintenance/off")
+        | (_, "/deployment")
+        | (_, "/deployment/blue")
+        | (_, "/deployment/green")
+        | (_, "/upstreams/drain")
+        | (_, "/upstreams/down")
+        | (_, "/upstreams/up") => {
+            return text_response(http::StatusCode::METHOD_NOT_ALLOWED, "Method not allowed");
+        }
+        _ => return text_response(http::StatusCode::NOT_FOUND, "Not found"),
+    };
+    if state.set_admin_state(&address, admin_state).await {
+        log::info!("Admin API set upstream {} to {:?}", address, admin_state);
+        text_response(http::StatusCode::OK, "OK")
+    } else {
+        text_response(http::StatusCode::NOT_FOUND, "No such upstream")
+    }
+}
+
+async fn handle_connection<S: AsyncRead + AsyncWrite + Unpin>(
+    mut stream: S,
+    client_addr: SocketAddr,
+    state: Arc<ProxyState>,
+) {
+    loop {
+        let response = match request::read_from_stream(&mut stream).await {
+            Ok(request) => {
+                log::info!(
+                    "Admin API request from {}: {}",
+                    client_addr.ip(),
+                    request::format_request_line(&request)
+                );
+                handle_request(&request, &state).await
+            }
+            Err(request::Error::IncompleteRequest(0)) | Err(request::Error::ConnectionError(_)) => {
+                return;
+            }
+            Err(error) => {
+                log::debug!("Error parsing admin API request: {:?}", error);
+                response::make_http_error(http::StatusCode::BAD_REQUEST)
+            }
+        };
+        if let Err(error) = response::write_to_stream(&response, &mut stream).await {
+            log::warn!("Failed to send admin API response: {}", error);
+            return;
+        }
+    }
+}
+
+/// Serves the admin API on the given listener
+pub async fn serve(listener: Listener, state: Arc<ProxyState>) {
+    while let Ok((stream, client_addr)) = listener.accept().await {
+        let state = state.clone();
+        tokio::spawn(async move {
+            handle_connection(stream, client_addr, state).await;
+        });
+    }
+}
@@ -0,0 +1,265 @@
+use std::collections::{HashMap, HashSet};
+use std::sync::{Arc, Mutex};
+use base64::Engine;
+use md5::{Digest, Md5};
+use sha1::Sha1;
+
+/// Most credentials remembered as checked; the cache starts over once it's full
+const MAX_CACHED: usize = 1024;
+
+/// Whether a request may go through, and if not, why
+#[derive(Debug, PartialEq)]
+pub enum Outcome {
+    Allowed,
+    /// The request had no credentials, or had ones of a kind we don't accept
+    Missing,
+    /// The request's credentials were wrong
+    Rejected,
+    /// The request had a bearer token, and it was wrong
+    InvalidToken,
+}
+
+/// Checks requests' credentials: a user name and password (HTTP Basic authentication) against an
+/// htpasswd-style file, or a bearer token against the one that's been set up. Requests are let
+/// through when neither is set up.
+pub struct Authenticator {
+    /// Password hash of each user, as found in the htpasswd file
+    users: Option<HashMap<String, String>>,
+    bearer_token: Option<String>,
+    /// Name of the protected area, which browsers show when asking for a password
+    realm: String,
+    /// SHA-1 digests of Authorization headers whose credentials were found to be right. Clients
+    /// using Basic authentication send their password with every request, and checking a bcrypt
+    /// hash takes long enough that doing it every time would slow everything down.
+    verified: Arc<Mutex<HashSet<[u8; 20]>>>,
+}
+
+impl Authenticator {
+    /// Sets up authentication with the users in the given htpasswd file and the given bearer
+    /// token, either of which may be left out. Lines of the file that can't be made sense of are
+    /// skipped with a warning.
+    pub fn new(htpasswd: Option<&str>, bearer_token: Option<String>, realm: &str) -> Authenticator {
+        let users = htpasswd.map(|path| match std::fs::read_to_string(path) {
+            Ok(contents) => parse_htpasswd(path, &contents),
+            Err(err) => {
+                // Nobody can log in, rather than everybody
+                log::warn!("Could not read htpasswd file {}: {}", path, err);
+                HashMap::new()
+            }
+        });
+        Authenticator {
+            users,
+            bearer_token,
+            realm: realm.to_string(),
+            verified: Arc::new(Mutex::new(HashSet::new())),
+        }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.users.is_some() || self.bearer_token.is_some()
+    }
+
+    /// Checks the credentials in the request's Authorization header
+    pub async fn check(&self, request: &http::Request<Vec<u8>>) -> Outcome {
+        let header = match request.headers().get(http::header::AUTHORIZATION) {
+            Some(header) => header.as_bytes(),
+            None => return Outcome::Missing,
+        };
+        let (scheme, credentials) = match std::str::from_utf8(header).ok().and_then(|header| {
+            header.trim().split_once(' ')
+        }) {
+            Some(parts) => parts,
+            None => return Outcome::Missing,
+        };
+        let credentials = credentials.trim();
+        if scheme.eq_ignore_ascii_case("Bearer") {
+            return match &self.bearer_token {
+                Some(token) if constant_time_eq(token.as_bytes(), credentials.as_bytes()) => {
+                    Outcome::Allowed
+                }
+                Some(_) => Outcome::InvalidToken,
+                None => Outcome::Missing,
+            };
+        }
+        if !scheme.eq_ignore_ascii_case("Basic") {
+            return Outcome::Missing;
+        }
+        let users = match &self.users {
+            Some(users) => users,
+            None => return Outcome::Missing,
+        };
+
+        let digest: [u8; 20] = Sha1::digest(header).into();
+        if self.verified.lock().unwrap().contains(&digest) {
+            return Outcome::Allowed;
+        }
+        let decoded = base64::engine::general_purpose::STANDARD
+            .decode(credentials)
+            .ok()
+            .and_then(|decoded| String::from_utf8(decoded).ok());
+        let credentials = decoded.as_deref().and_then(|decoded| decoded.split_once(':'));
+        let (user, password) = match credentials {
+            Some((user, password)) => (user.to_string(), password.to_string()),
+            None => return Outcome::Rejected,
+        };
+        let hash = match users.get(&user) {
+            Some(hash) => hash.clone(),
+            None => return Outcome::Rejected,
+        };
+        // Checking a bcrypt hash is slow on purpose, so it's kept off the threads serving requests
+        let matches = tokio::task::spawn_blocking(move || verify_password(&password, &hash))
+            .await
+            .unwrap_or(false);
+        if !matches {
+            log::info!("Wrong password for user {:?}", user);
+            return Outcome::Rejected;
+        }
+        let mut verified = self.verified.lock().unwrap();
+        if verified.len() >= MAX_CACHED {
+            verified.clear();
+        }
+        verified.insert(digest);
+        Outcome::Allowed
+    }
+
+    /// Values for the WWW-Authenticate headers of a 401 response, one for each kind of
+    /// credentials we accept
+    pub fn challenges(&self, outcome: &Outcome) -> Vec<String> {
+        let mut challenges = Vec::new();
+        if self.users.is_some() {
+            challenges.push(format!("Basic realm=\"{}\", charset=\"UTF-8\"", self.realm));
+        }
+        if self.bearer_token.is_some() {
+            let error = match outcome {
+                Outcome::InvalidToken => ", error=\"invalid_token\"",
+                _ => "",
+            };
+            challenges.push(format!("Bearer realm=\"{}\"{}", self.realm, error));
+        }
+        challenges
+    }
+}
+
+/// Reads "user:hash" lines, skipping blank lines and comments. Users whose password is stored in
+/// a way we don't understand (including as the password itself) are skipped with a warning, since
+/// they could never log in.
+fn parse_htpasswd(path: &str, contents: &str) -> HashMap<String, String> {
+    let mut users = HashMap::new();
+    for (number, line) in contents.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        match line.split_once(':') {
+            Some((user, hash)) if !user.is_empty() && !hash.is_empty() => {
+                if !is_known_hash(hash) {
+                    log::warn!(
+                        "Skipping user {:?} on line {} of {}: unsupported password hash",
+                        user,
+                        number + 1,
+                        path
+                    );
+                    continue;
+                }
+                users.insert(user.to_string(), hash.to_string());
+            }
+            _ => log::warn!("Skipping invalid line {} of {}", number + 1, path),
+        }
+    }
+    users
+}
+
+/// Returns true if the hash is of a kind verify_password can check
+fn is_known_hash(hash: &str) -> bool {
+    hash.starts_with("$2") || hash.starts_with("$apr1$") || hash.starts_with("{SHA}")
+}
+
+/// Checks a password against a hash from an htpasswd file, which may be bcrypt ("$2y$..."), Apache
+/// MD5 ("$apr1$...") or SHA-1 ("{SHA}..."). Any other kind of hash never matches.
+fn verify_password(password: &str, hash: &str) -> bool {
+    if hash.starts_with("$2") {
+        bcrypt::verify(password, hash).unwrap_or(false)
+    } else if let Some(rest) = hash.strip_prefix("$apr1$") {
+        let salt = rest.split('$').next().unwrap_or_default();
+        constant_time_eq(apr1_md5(password, salt).as_bytes(), hash.as_bytes())
+    } else if let Some(digest) = hash.strip_prefix("{SHA}") {
+        let expected = base64::engine::general_purpose::STANDARD.encode(Sha1::digest(password));
+        constant_time_eq(expected.as_bytes(), digest.as_bytes())
+    } else {
+        false
+    }
+}
+
+/// Hashes the password the way Apache's htpasswd does by default ("$apr1$salt$hash")
+fn apr1_md5(password: &str, salt: &str) -> String {
+    const MAGIC: &str = "$apr1$";
+    let password = password.as_bytes();
+    let salt = &salt.as_bytes()[..salt.len().min(8)];
+
+    let alternate =
+        Md5::new().chain_update(password).chain_update(salt).chain_update(password).finalize();
+    let mut context = Md5::new();
+    context.update(password);
+    context.update(MAGIC);
+    context.update(salt);
+    for chunk in (0..password.len()).step_by(16) {
+        context.update(&alternate[..(password.len() - chunk).min(16)]);
+    }
+    let mut length = password.len();
+    while length > 0 {
+        if length & 1 == 1 {
+            context.update([0]);
+        } else {
+            context.update(&password[..1]);
+        }
+        length >>= 1;
+    }
+    let mut result = context.finalize();
+
+    // Make it slow to compute, to make guessing harder
+    for round in 0..1000 {
+        let mut context = Md5::new();
+        if round & 1 == 1 {
+            context.update(password);
+        } else {
+            context.update(result);
+        }
+        if round % 3 != 0 {
+            context.update(salt);
+        }
+        if round % 7 != 0 {
+            context.update(password);
+        }
+        if round & 1 == 1 {
+            context.update(result);
+        } else {
+            context.update(password);
+        }
+        result = context.finalize();
+    }
+
+    const ALPHABET: &[u8] = b"./0123456789ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz";
+    let mut encoded = String::from(MAGIC);
+    encoded.push_str(std::str::from_utf8(salt).unwrap_or_default());
+    encoded.push('$');
+    let mut encode = |value: u32, chars: usize| {
+        for i in 0..chars {
+            encoded.push(ALPHABET[((value >> (6 * i)) & 0x3f) as usize] as char);
+        }
+    };
+    let byte = |i: usize| result[i] as u32;
+    for &(a, b, c) in &[(0, 6, 12), (1, 7, 13), (2, 8, 14), (3, 9, 15), (4, 10, 5)] {
+        encode((byte(a) << 16) | (byte(b) << 8) | byte(c), 4);
+    }
+    encode(byte(11), 2);
+    encoded
+}
+
+/// Compares two byte strings without returning early at the first difference, so the time taken
+/// doesn't tell an attacker how much of a guess was right
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    if a.len() != b.len() {
+        return false;
+    }
+    a.iter().zip(b).fold(0, |difference, (x, y)| difference | (x ^ y)) == 0
+}
@@ -0,0 +1,116 @@
+use std::collections::VecDeque;
+use std::net::IpAddr;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use arc_swap::ArcSwap;
+use dashmap::DashMap;
+use serde::Serialize;
+
+/// How far back rate limit violations are counted when deciding whether to ban a client
+const WINDOW: Duration = Duration::from_secs(60 * 60);
+
+/// When clients get banned, and for how long
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Settings {
+    /// Number of times a client may go over the rate limit in an hour before it is banned
+    /// (0 = never ban)
+    pub threshold: usize,
+    pub duration: Duration,
+}
+
+/// A banned client, as reported by the admin API
+#[derive(Serialize)]
+pub struct Ban {
+    pub ip: IpAddr,
+    /// Seconds until the ban is lifted
+    pub remaining: u64,
+}
+
+/// Keeps clients that keep going over the rate limit from connecting at all for a while, so they
+/// stop costing us a rate limit check (and a 429) on every request. It is checked on every
+/// connection, so it's sharded rather than locked as a whole.
+pub struct BanList {
+    settings: ArcSwap<Settings>,
+    /// Times each client went over the rate limit within the window
+    violations: DashMap<IpAddr, VecDeque<Instant>>,
+    /// Banned clients, with when their ban ends
+    bans: DashMap<IpAddr, Instant>,
+}
+
+impl BanList {
+    pub fn new(settings: Settings) -> BanList {
+        BanList {
+            settings: ArcSwap::from_pointee(settings),
+            violations: DashMap::new(),
+            bans: DashMap::new(),
+        }
+    }
+
+    /// Switches to new settings. Bans already handed out keep their original end.
+    pub fn set_settings(&self, settings: Settings) {
+        self.settings.store(Arc::new(settings));
+        if settings.threshold == 0 {
+            self.violations.clear();
+        }
+    }
+
+    pub fn is_banned(&self, ip: IpAddr) -> bool {
+        self.bans.get(&ip).is_some_and(|until| Instant::now() < *until)
+    }
+
+    /// Records the client going over the rate limit. Returns true if that got it banned.
+    pub fn record_violation(&self, ip: IpAddr) -> bool {
+        let settings = **self.settings.load();
+        if settings.threshold == 0 {
+            return false;
+        }
+        let now = Instant::now();
+        let mut violations = self.violations.entry(ip).or_default();
+        violations.push_back(now);
+        while let Some(&oldest) = violations.front() {
+            if now.duration_since(oldest) <= WINDOW {
+                break;
+            }
+            violations.pop_front();
+        }
+        if violations.len() > settings.threshold {
+            // The violations are forgotten once the client is banned. The entry has to be let go
+            // of before it's removed, since it holds its shard locked.
+            drop(violations);
+            self.violations.remove(&ip);
+            self.bans.insert(ip, now + settings.duration);
+            true
+        } else {
+            false
+        }
+    }
+
+    /// Lifts the client's ban. Returns false if it wasn't banned.
+    pub fn unban(&self, ip: IpAddr) -> bool {
+        self.violations.remove(&ip);
+        self.bans.remove(&ip).is_some_and(|(_, until)| Instant::now() < until)
+    }
+
+    /// Returns the clients that are banned right now
+    pub fn list(&self) -> Vec<Ban> {
+        let now = Instant::now();
+        let mut bans: Vec<Ban> = self
+            .bans
+            .iter()
+            .filter(|ban| now < *ban.value())
+            .map(|ban| Ban { ip: *ban.key(), remaining: ban.value().duration_since(now).as_secs() })
+            .collect();
+        bans.sort_by_key(|ban| ban.ip);
+        bans
+    }
+
+    /// Forgets bans that are over and violations that are out of the window, so that memory use
+    /// is bounded by the number of recently misbehaving clients
+    pub fn prune(&self) {
+        let now = Instant::now();
+        self.bans.retain(|_, until| now < *until);
+        self.violations.retain(|_, violations| {
+            violations.back().is_some_and(|&latest| now.duration_since(latest) <= WINDOW)
+        });
+    }
+}
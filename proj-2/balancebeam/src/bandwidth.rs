@@ -0,0 +1,57 @@
+use std::net::IpAddr;
+use dashmap::DashMap;
+use serde::Serialize;
+use crate::access_log::Sizes;
+
+/// What the admin API reports about one client's traffic since the counts were last cleared
+#[derive(Serialize, Debug)]
+pub struct ClientUsage {
+    ip: IpAddr,
+    bytes_received: u64,
+    bytes_sent: u64,
+}
+
+/// Counts the bytes each client IP has sent us and been sent, so heavy clients can be found and
+/// held to a quota of bytes per minute alongside the request rate limit. The counts are cleared
+/// along with the rate limiter's, and are sharded, so clients never wait on each other.
+#[derive(Default)]
+pub struct BandwidthLimiter {
+    /// Bytes received from and sent to each client
+    usage: DashMap<IpAddr, (u64, u64)>,
+}
+
+impl BandwidthLimiter {
+    /// Adds a request's bytes to the client's counts
+    pub fn record(&self, ip: IpAddr, sizes: Sizes) {
+        let mut usage = self.usage.entry(ip).or_default();
+        usage.0 += sizes.received;
+        usage.1 += sizes.sent;
+    }
+
+    /// Returns whether the client may send another request under a quota of `max_bytes`
+    /// (0 = no quota). How big a request is only becomes known once it's done, so the one that
+    /// takes the client over is let through in full, and the ones after it are turned away.
+    pub fn allows(&self, ip: IpAddr, max_bytes: u64) -> bool {
+        max_bytes == 0
+            || self.usage.get(&ip).is_none_or(|usage| usage.0 + usage.1 < max_bytes)
+    }
+
+    pub fn refresh(&self) {
+        self.usage.clear()
+    }
+
+    /// Returns every client's counts, heaviest first
+    pub fn report(&self) -> Vec<ClientUsage> {
+        let mut report: Vec<ClientUsage> = self
+            .usage
+            .iter()
+            .map(|usage| ClientUsage {
+                ip: *usage.key(),
+                bytes_received: usage.0,
+                bytes_sent: usage.1,
+            })
+            .collect();
+        report.sort_by_key(|usage| std::cmp::Reverse(usage.bytes_received + usage.bytes_sent));
+        report
+    }
+}
@@ -0,0 +1,385 @@
+use clap::Clap;
+use std::collections::BTreeMap;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+use std::net::{Ipv4Addr, SocketAddr};
+use tokio::net::{TcpSocket, TcpStream};
+use tokio::time::{timeout, Duration, Instant};
+
+/// Options for `balancebeam bench`, which sends a proxy (or anything else speaking HTTP/1.1) a
+/// steady stream of requests and reports how fast they were answered, so that changes to load
+/// balancing or connection handling can be measured the same way each time
+#[derive(Clap, Debug)]
+pub struct BenchOptions {
+    #[clap(about = "URL to send requests to, e.g. http://127.0.0.1:1100/")]
+    url: String,
+    #[clap(
+        short,
+        long,
+        about = "Number of connections sending requests at once",
+        default_value = "10"
+    )]
+    concurrency: usize,
+    #[clap(short = 'n', long, about = "Number of requests to send", default_value = "1000")]
+    requests: usize,
+    #[clap(
+        short,
+        long,
+        about = "Keep sending requests for this many seconds, instead of a set number of them (0 = use --requests)",
+        default_value = "0"
+    )]
+    duration: u64,
+    #[clap(
+        short = 'H',
+        long,
+        multiple_occurrences = true,
+        about = "Header to send with each request, as <name>: <value>. May be given more than once"
+    )]
+    header: Vec<String>,
+    #[clap(long, about = "Open a new connection for each request, instead of keeping them open")]
+    new_connections: bool,
+    #[clap(
+        long,
+        about = "Spread connections over this many loopback addresses (127.0.0.1, 127.0.0.2, ...), so that the proxy sees that many clients (0 = let the OS pick)",
+        default_value = "0"
+    )]
+    source_addresses: u32,
+    #[clap(
+        long,
+        about = "Count a request as failed if it isn't answered within this many seconds",
+        default_value = "10"
+    )]
+    timeout: u64,
+    #[clap(
+        long,
+        about = "Send each request as a POST with a body this many bytes long (0 = send GETs)",
+        default_value = "0"
+    )]
+    body_size: usize,
+    #[clap(
+        long,
+        about = "Address of balancebeam's admin API, to report how many buffer allocations the requests cost"
+    )]
+    admin: Option<String>,
+}
+
+/// What one connection's worth of requests came to
+#[derive(Default)]
+struct Results {
+    /// How long each answered request took
+    latencies: Vec<Duration>,
+    /// Number of responses with each status code
+    statuses: BTreeMap<u16, usize>,
+    /// Requests that failed without a response (connection errors, timeouts, bad responses)
+    errors: usize,
+}
+
+impl Results {
+    fn merge(&mut self, other: Results) {
+        self.latencies.extend(other.latencies);
+        for (status, count) in other.statuses {
+            *self.statuses.entry(status).or_insert(0) += count;
+        }
+        self.errors += other.errors;
+    }
+}
+
+/// Where requests are sent, and what's sent
+struct Target {
+    /// host:port to connect to
+    address: String,
+    /// The request, which is the same every time
+    request: Vec<u8>,
+}
+
+impl Target {
+    fn parse(options: &BenchOptions) -> Result<Target, String> {
+        let rest = options
+            .url
+            .strip_prefix("http://")
+            .ok_or_else(|| format!("Only http:// URLs can be benchmarked, not {}", options.url))?;
+        let (authority, path) = match rest.find('/') {
+            Some(idx) => (&rest[..idx], &rest[idx..]),
+            None => (rest, "/"),
+        };
+        let address = if authority.contains(':') && !authority.ends_with(']') {
+            authority.to_string()
+        } else {
+            format!("{}:80", authority)
+        };
+        let method = if options.body_size > 0 { "POST" } else { "GET" };
+        let mut request = format!("{} {} HTTP/1.1\r\nHost: {}\r\n", method, path, authority);
+        for header in &options.header {
+            if !header.contains(':') {
+                return Err(format!("Invalid header {:?}, expected <name>: <value>", header));
+            }
+            request += &format!("{}\r\n", header);
+        }
+        if options.new_connections {
+            request += "Connection: close\r\n";
+        }
+        if options.body_size > 0 {
+            request += &format!("Content-Length: {}\r\n", options.body_size);
+        }
+        request += "\r\n";
+        let mut request = request.into_bytes();
+        request.resize(request.len() + options.body_size, b'x');
+        Ok(Target { address, request })
+    }
+}
+
+/// Connects to the target, from the given local address if there is one
+async fn connect(address: &str, source: Option<Ipv4Addr>) -> std::io::Result<TcpStream> {
+    let source = match source {
+        Some(source) => source,
+        None => return TcpStream::connect(address).await,
+    };
+    let target = tokio::net::lookup_host(address)
+        .await?
+        .find(SocketAddr::is_ipv4)
+        .ok_or_else(|| std::io::Error::other(format!("{} has no IPv4 address", address)))?;
+    let socket = TcpSocket::new_v4()?;
+    socket.bind(SocketAddr::new(source.into(), 0))?;
+    socket.connect(target).await
+}
+
+/// Reads a response off the connection, discarding its body. Returns its status, and whether the
+/// connection can be used for another request.
+async fn read_response(conn: &mut BufReader<TcpStream>) -> Result<(u16, bool), String> {
+    let mut head = Vec::new();
+    loop {
+        let len = head.len();
+        if conn.read_until(b'\n', &mut head).await.map_err(|err| err.to_string())? == 0 {
+            return Err("Connection closed before the response was complete".to_string());
+        }
+        if head[len..] == b"\r\n"[..] || head[len..] == b"\n"[..] {
+            break;
+        }
+    }
+    let mut headers = [httparse::EMPTY_HEADER; 64];
+    let mut response = httparse::Response::new(&mut headers);
+    response.parse(&head).map_err(|err| err.to_string())?;
+    let status = response.code.unwrap_or(0);
+    let header = |name: &str| {
+        response
+            .headers
+            .iter()
+            .find(|header| header.name.eq_ignore_ascii_case(name))
+            .map(|header| String::from_utf8_lossy(header.value).to_ascii_lowercase())
+    };
+    let keep_alive = header("connection").as_deref() != Some("close");
+    let mut sink = tokio::io::sink();
+    if header("transfer-encoding").is_some_and(|encoding| encoding.contains("chunked")) {
+        loop {
+            let mut line = String::new();
+            conn.read_line(&mut line).await.map_err(|err| err.to_string())?;
+            let size = line.trim().split(';').next().unwrap_or("");
+            let size = usize::from_str_radix(size, 16)
+                .map_err(|_| format!("Invalid chunk size {:?}", line.trim()))?;
+            if size == 0 {
+                break;
+            }
+            // The chunk, followed by its CRLF
+            let mut chunk = (&mut *conn).take(size as u64 + 2);
+            tokio::io::copy(&mut chunk, &mut sink).await.map_err(|err| err.to_string())?;
+        }
+        // Skip any trailers, up to the blank line that ends the body
+        loop {
+            let mut line = String::new();
+            if conn.read_line(&mut line).await.map_err(|err| err.to_string())? == 0
+                || line.trim().is_empty()
+            {
+                break;
+            }
+        }
+        Ok((status, keep_alive))
+    } else if let Some(length) = header("content-length") {
+        let length = length.trim().parse::<u64>().map_err(|err| err.to_string())?;
+        let mut body = (&mut *conn).take(length);
+        tokio::io::copy(&mut body, &mut sink).await.map_err(|err| err.to_string())?;
+        Ok((status, keep_alive))
+    } else {
+        // The body runs until the connection closes
+        tokio::io::copy(conn, &mut sink).await.map_err(|err| err.to_string())?;
+        Ok((status, false))
+    }
+}
+
+/// Sends requests over one connection (reopening it when needed) until there are none left to send
+async fn worker(
+    target: Arc<Target>,
+    source: Option<Ipv4Addr>,
+    remaining: Arc<AtomicUsize>,
+    deadline: Option<Instant>,
+    request_timeout: Duration,
+) -> Results {
+    let mut results = Results::default();
+    let mut conn: Option<BufReader<TcpStream>> = None;
+    loop {
+        match deadline {
+            Some(deadline) if Instant::now() >= deadline => break,
+            Some(_) => {}
+            None => {
+                let claimed = remaining
+                    .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |left| left.checked_sub(1));
+                if claimed.is_err() {
+                    break;
+                }
+            }
+        }
+        let started = Instant::now();
+        let attempt = async {
+            let mut stream = match conn.take() {
+                Some(stream) => stream,
+                None => BufReader::new(
+                    connect(&target.address, source).await.map_err(|err| err.to_string())?,
+                ),
+            };
+            stream.get_mut().write_all(&target.request).await.map_err(|err| err.to_string())?;
+            let (status, keep_alive) = read_response(&mut stream).await?;
+            Ok::<_, String>((status, keep_alive.then_some(stream)))
+        };
+        match timeout(request_timeout, attempt).await {
+            Ok(Ok((status, stream))) => {
+                results.latencies.push(started.elapsed());
+                *results.statuses.entry(status).or_insert(0) += 1;
+                conn = stream;
+            }
+            Ok(Err(err)) => {
+                log::debug!("Request failed: {}", err);
+                results.errors += 1;
+            }
+            Err(_) => {
+                log::debug!("Request timed out");
+                results.errors += 1;
+            }
+        }
+    }
+    results
+}
+
+/// Asks balancebeam's admin API how many times its buffers have had to allocate memory, and how
+/// many times they reused it instead
+async fn buffer_stats(admin: &str) -> Result<(u64, u64), String> {
+    let mut conn = BufReader::new(TcpStream::connect(admin).await.map_err(|err| err.to_string())?);
+    let request = format!("GET /buffers HTTP/1.1\r\nHost: {}\r\n\r\n", admin);
+    conn.get_mut().write_all(request.as_bytes()).await.map_err(|err| err.to_string())?;
+    // The admin API keeps the connection open, so the body is read by its Content-Length
+    let mut content_length = None;
+    loop {
+        let mut line = String::new();
+        if conn.read_line(&mut line).await.map_err(|err| err.to_string())? == 0 {
+            return Err("Admin API closed the connection before answering".to_string());
+        }
+        let line = line.trim();
+        if line.is_empty() {
+            break;
+        }
+        if let Some((name, value)) = line.split_once(':') {
+            if name.eq_ignore_ascii_case("content-length") {
+                content_length = value.trim().parse::<usize>().ok();
+            }
+        }
+    }
+    let mut body = vec![0; content_length.ok_or("Admin API didn't send a Content-Length")?];
+    conn.read_exact(&mut body).await.map_err(|err| err.to_string())?;
+    let stats: serde_json::Value = serde_json::from_slice(&body).map_err(|err| err.to_string())?;
+    match (stats["allocated"].as_u64(), stats["reused"].as_u64()) {
+        (Some(allocated), Some(reused)) => Ok((allocated, reused)),
+        _ => Err(format!("Admin API sent unexpected buffer stats: {}", stats)),
+    }
+}
+
+/// Returns the latency that the given percentage of requests were answered within
+fn percentile(sorted: &[Duration], percent: f64) -> Duration {
+    if sorted.is_empty() {
+        return Duration::from_secs(0);
+    }
+    let rank = ((percent / 100.0) * sorted.len() as f64).ceil() as usize;
+    sorted[rank.clamp(1, sorted.len()) - 1]
+}
+
+fn format_latency(latency: Duration) -> String {
+    format!("{:.2}ms", latency.as_secs_f64() * 1000.0)
+}
+
+/// Runs the benchmark, printing a report once it's done
+pub async fn run(options: &BenchOptions) -> Result<(), String> {
+    let target = Arc::new(Target::parse(options)?);
+    if options.concurrency == 0 {
+        return Err("--concurrency must be at least 1".to_string());
+    }
+    let remaining = Arc::new(AtomicUsize::new(options.requests));
+    let deadline =
+        (options.duration > 0).then(|| Instant::now() + Duration::from_secs(options.duration));
+    let request_timeout = Duration::from_secs(options.timeout);
+    let stats_before = match &options.admin {
+        Some(admin) => Some(buffer_stats(admin).await?),
+        None => None,
+    };
+
+    let started = Instant::now();
+    let workers: Vec<_> = (0..options.concurrency)
+        .map(|idx| {
+            let source = (options.source_addresses > 0).then(|| {
+                let offset = idx as u32 % options.source_addresses;
+                Ipv4Addr::from(u32::from(Ipv4Addr::LOCALHOST) + offset)
+            });
+            let remaining = remaining.clone();
+            tokio::spawn(worker(target.clone(), source, remaining, deadline, request_timeout))
+        })
+        .collect();
+    let mut results = Results::default();
+    for worker in workers {
+        results.merge(worker.await.map_err(|err| err.to_string())?);
+    }
+    let elapsed = started.elapsed();
+
+    let answered = results.latencies.len();
+    println!(
+        "Sent {} requests to {} over {} connections in {:.2}s",
+        answered + results.errors,
+        options.url,
+        options.concurrency,
+        elapsed.as_secs_f64()
+    );
+    println!("Requests/sec: {:.1}", answered as f64 / elapsed.as_secs_f64());
+    let statuses: Vec<String> = results
+        .statuses
+        .iter()
+        .map(|(status, count)| format!("{} x {}", status, count))
+        .collect();
+    if statuses.is_empty() {
+        println!("Responses: none");
+    } else {
+        println!("Responses: {}", statuses.join(", "));
+    }
+    println!("Errors: {}", results.errors);
+    let mut latencies = results.latencies;
+    latencies.sort();
+    if !latencies.is_empty() {
+        let total: Duration = latencies.iter().sum();
+        println!(
+            "Latency: min {}, mean {}, p50 {}, p90 {}, p99 {}, max {}",
+            format_latency(latencies[0]),
+            format_latency(total / latencies.len() as u32),
+            format_latency(percentile(&latencies, 50.0)),
+            format_latency(percentile(&latencies, 90.0)),
+            format_latency(percentile(&latencies, 99.0)),
+            format_latency(latencies[latencies.len() - 1])
+        );
+    }
+    if let (Some(admin), Some((allocated_before, reused_before))) = (&options.admin, stats_before)
+    {
+        let (allocated, reused) = buffer_stats(admin).await?;
+        let allocated = allocated - allocated_before;
+        println!(
+            "Buffer allocations: {} ({:.2} per request), reused: {}",
+            allocated,
+            allocated as f64 / answered.max(1) as f64,
+            reused - reused_before
+        );
+    }
+    Ok(())
+}
@@ -0,0 +1,78 @@
+use std::sync::Mutex;
+use serde::Serialize;
+use tokio::time::{Duration, Instant};
+
+/// One of the two pools in a blue/green deployment
+#[derive(Clone, Copy, Debug, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Color {
+    Blue,
+    Green,
+}
+
+struct Switch {
+    live: Color,
+    /// When the live pool last changed, and which one it changed from
+    switched_from: Option<(Color, Instant)>,
+}
+
+/// Which of the `--blue-pool` and `--green-pool` pools takes requests that don't match any route.
+/// Blue is live to begin with. After a switch, client connections already pinned to an upstream
+/// in the old pool keep using it for up to `--blue-green-drain-timeout` seconds, so their sessions
+/// aren't cut off; every other request goes to the new pool straight away.
+pub struct Deployment {
+    switch: Mutex<Switch>,
+}
+
+/// What the admin API reports about the deployment
+#[derive(Serialize)]
+pub struct DeploymentReport {
+    live: Color,
+    /// The pool switched away from, while connections pinned to it may still use it
+    draining: Option<Color>,
+    /// Seconds until connections pinned to the draining pool are moved off it
+    drain_seconds_left: u64,
+}
+
+impl Deployment {
+    pub fn new() -> Deployment {
+        Deployment { switch: Mutex::new(Switch { live: Color::Blue, switched_from: None }) }
+    }
+
+    pub fn live(&self) -> Color {
+        self.switch.lock().unwrap().live
+    }
+
+    /// Makes the pool live. Returns false if it already was.
+    pub fn switch_to(&self, color: Color) -> bool {
+        let mut switch = self.switch.lock().unwrap();
+        if switch.live == color {
+            return false;
+        }
+        switch.switched_from = Some((switch.live, Instant::now()));
+        switch.live = color;
+        true
+    }
+
+    /// Returns the pool switched away from, if connections pinned to it may still use it
+    pub fn draining(&self, drain_timeout: Duration) -> Option<Color> {
+        let switch = self.switch.lock().unwrap();
+        switch
+            .switched_from
+            .filter(|(_, switched_at)| switched_at.elapsed() < drain_timeout)
+            .map(|(color, _)| color)
+    }
+
+    pub fn report(&self, drain_timeout: Duration) -> DeploymentReport {
+        let switch = self.switch.lock().unwrap();
+        let left = switch
+            .switched_from
+            .map(|(color, at)| (color, drain_timeout.saturating_sub(at.elapsed())))
+            .filter(|(_, left)| !left.is_zero());
+        DeploymentReport {
+            live: switch.live,
+            draining: left.map(|(color, _)| color),
+            drain_seconds_left: left.map(|(_, left)| left.as_secs()).unwrap_or(0),
+        }
+    }
+}
@@ -0,0 +1,271 @@
+use std::future::Future;
+use std::time::Duration;
+use bytes::{BufMut, Bytes, BytesMut};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::sync::mpsc;
+use crate::buffer_pool;
+use crate::chunked;
+
+/// Largest piece of a body that is read, and passed on, at a time. Bodies are streamed through
+/// the proxy in pieces this size, so memory use doesn't depend on how big they are.
+pub const PIECE_SIZE: usize = 8192;
+
+#[derive(Debug)]
+pub enum Error {
+    /// The peer hung up before sending as many bytes as the Content-Length header promised
+    ContentLengthMismatch,
+    /// The body uses Transfer-Encoding: chunked, but isn't validly chunked
+    InvalidChunkedBody,
+    /// The body is bigger than the caller's limit
+    BodyTooLarge,
+    /// Nothing was read from the stream for longer than the read timeout
+    TimedOut,
+    /// Encountered an I/O error when reading from the stream
+    Io(std::io::Error),
+}
+
+impl From<std::io::Error> for Error {
+    fn from(err: std::io::Error) -> Error {
+        Error::Io(err)
+    }
+}
+
+impl From<chunked::Error> for Error {
+    fn from(err: chunked::Error) -> Error {
+        match err {
+            chunked::Error::InvalidChunkedBody => Error::InvalidChunkedBody,
+            chunked::Error::Io(err) => Error::Io(err),
+        }
+    }
+}
+
+/// Copying a body from one stream to another failed
+#[derive(Debug)]
+pub enum CopyError {
+    /// The body couldn't be read from the sender
+    Read(Error),
+    /// The body couldn't be written to the receiver
+    Write(std::io::Error),
+}
+
+/// How the end of a message body is found
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Framing {
+    /// The message has no body
+    Empty,
+    /// The body is as many bytes long as the Content-Length header says
+    Length(usize),
+    /// The body is sent as a series of chunks (Transfer-Encoding: chunked)
+    Chunked,
+    /// The body lasts until the sender closes the connection (only for responses)
+    UntilClose,
+}
+
+/// Runs a read off the stream, giving up on it after the timeout (None = never)
+async fn with_read_timeout<T, E: Into<Error>>(
+    timeout: Option<Duration>,
+    read: impl Future<Output = Result<T, E>>,
+) -> Result<T, Error> {
+    let result = match timeout {
+        Some(timeout) => tokio::time::timeout(timeout, read).await.map_err(|_| Error::TimedOut)?,
+        None => read.await,
+    };
+    result.map_err(Into::into)
+}
+
+enum State {
+    /// Bytes are passed through as they are; `remaining` is None if they last until the sender
+    /// hangs up
+    Raw { buffer: BytesMut, remaining: Option<usize> },
+    Chunked(chunked::Decoder),
+}
+
+/// Reads a message body off a stream a piece at a time. The message's headers have already been
+/// read from the stream, and some of the body may have been read along with them.
+pub struct BodyReader {
+    framing: Framing,
+    state: State,
+    /// Longest time to wait for the sender to send more of the body (None = forever)
+    read_timeout: Option<Duration>,
+    /// Where to send a copy of each piece as it is read, followed by None once the body is over
+    tee: Option<mpsc::Sender<Option<Bytes>>>,
+}
+
+impl BodyReader {
+    /// Creates a reader for a body with the given framing. `already_read` holds the bytes that
+    /// were read off the stream after the headers, and the rest of the body is read into it.
+    pub fn new(framing: Framing, already_read: BytesMut) -> BodyReader {
+        let state = match framing {
+            Framing::Empty => State::Raw { buffer: already_read, remaining: Some(0) },
+            Framing::Length(len) => State::Raw { buffer: already_read, remaining: Some(len) },
+            Framing::UntilClose => State::Raw { buffer: already_read, remaining: None },
+            Framing::Chunked => State::Chunked(chunked::Decoder::new(already_read)),
+        };
+        BodyReader { framing, state, read_timeout: None, tee: None }
+    }
+
+    pub fn framing(&self) -> Framing {
+        self.framing
+    }
+
+    /// Gives up on the sender if it goes the given number of seconds without sending any of the
+    /// body (0 = never)
+    pub fn set_read_timeout(&mut self, seconds: usize) {
+        self.read_timeout = match seconds {
+            0 => None,
+            seconds => Some(Duration::from_secs(seconds as u64)),
+        };
+    }
+
+    /// Sends a copy of each piece of the body read from now on to the channel, followed by None
+    /// once the body is over. If the receiver doesn't keep up, it is cut off rather than holding
+    /// up the body.
+    pub fn set_tee(&mut self, tee: mpsc::Sender<Option<Bytes>>) {
+        self.tee = Some(tee);
+    }
+
+    /// Checks the part of the body that was read along with the headers, without waiting for any
+    /// more of it, so that a body that's broken from the start (e.g. a malformed first chunk) can
+    /// be caught before anything is passed on. The rest is checked as it is read.
+    pub fn check_buffered(&self) -> Result<(), Error> {
+        match &self.state {
+            State::Chunked(decoder) => decoder.check_buffered().map_err(Into::into),
+            State::Raw { .. } => Ok(()),
+        }
+    }
+
+    /// Returns the next piece of the body, or None once the body is over. Pieces share memory
+    /// with the buffer they were read into, which gets it back once they have been dropped.
+    pub async fn next<S: AsyncRead + Unpin>(
+        &mut self,
+        stream: &mut S,
+    ) -> Result<Option<Bytes>, Error> {
+        let piece = self.read_from_stream(stream).await?;
+        if let Some(tee) = &self.tee {
+            if tee.try_send(piece.clone()).is_err() {
+                self.tee = None;
+            }
+        }
+        if piece.is_none() {
+            // Dropping the sender lets the receiver know nothing more is coming
+            self.tee = None;
+        }
+        Ok(piece)
+    }
+
+    async fn read_from_stream<S: AsyncRead + Unpin>(
+        &mut self,
+        stream: &mut S,
+    ) -> Result<Option<Bytes>, Error> {
+        let read_timeout = self.read_timeout;
+        match &mut self.state {
+            State::Chunked(decoder) => {
+                with_read_timeout(read_timeout, decoder.next(stream, PIECE_SIZE)).await
+            }
+            State::Raw { buffer, remaining } => {
+                if *remaining == Some(0) {
+                    return Ok(None);
+                }
+                let max_len = remaining.map_or(PIECE_SIZE, |remaining| remaining.min(PIECE_SIZE));
+                // Whatever was read along with the headers is handed out before reading any more
+                if buffer.is_empty() {
+                    buffer_pool::reserve(buffer, max_len);
+                    let mut room = (&mut *buffer).limit(max_len);
+                    let bytes_read =
+                        with_read_timeout(read_timeout, stream.read_buf(&mut room)).await?;
+                    if bytes_read == 0 {
+                        return match remaining {
+                            // The sender hung up before sending all of the body
+                            Some(_) => Err(Error::ContentLengthMismatch),
+                            // The body ends when the sender hangs up
+                            None => {
+                                *remaining = Some(0);
+                                Ok(None)
+                            }
+                        };
+                    }
+                }
+                let len = max_len.min(buffer.len());
+                if let Some(remaining) = remaining {
+                    *remaining -= len;
+                }
+                Ok(Some(buffer.split_to(len).freeze()))
+            }
+        }
+    }
+
+    /// Returns the bytes that were read off the stream past the end of the body, in the buffer
+    /// that was read into, so that its memory can go on being used for the connection. This
+    /// should only be called once the body has been read to the end.
+    pub fn into_leftover(self) -> BytesMut {
+        match self.state {
+            State::Raw { buffer, .. } => buffer,
+            State::Chunked(decoder) => decoder.into_leftover(),
+        }
+    }
+
+    /// Reads the rest of the body into memory, failing if it is more than `max_size` bytes long
+    pub async fn read_to_end<S: AsyncRead + Unpin>(
+        mut self,
+        stream: &mut S,
+        max_size: usize,
+    ) -> Result<Vec<u8>, Error> {
+        let mut body = match self.framing {
+            Framing::Length(len) if len <= max_size => Vec::with_capacity(len),
+            _ => Vec::new(),
+        };
+        while let Some(piece) = self.next(stream).await? {
+            if piece.len() > max_size - body.len() {
+                return Err(Error::BodyTooLarge);
+            }
+            body.extend_from_slice(&piece);
+        }
+        let leftover = self.into_leftover();
+        if !leftover.is_empty() {
+            log::debug!("Discarding {} bytes sent after the end of a body", leftover.len());
+        }
+        Ok(body)
+    }
+}
+
+/// Passes the rest of a body on from one stream to the other a piece at a time, keeping the
+/// framing it was sent with. Returns the number of body bytes copied.
+pub async fn copy<R, W>(body: &mut BodyReader, from: &mut R, to: &mut W) -> Result<u64, CopyError>
+where
+    R: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    let chunked = body.framing() == Framing::Chunked;
+    copy_framed(body, from, to, chunked).await
+}
+
+/// Like copy, but sends the body chunked or as plain bytes as asked, whatever framing it was sent
+/// with. A chunked body sent as plain bytes can only be ended by closing the connection.
+pub async fn copy_framed<R, W>(
+    body: &mut BodyReader,
+    from: &mut R,
+    to: &mut W,
+    chunked: bool,
+) -> Result<u64, CopyError>
+where
+    R: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    let mut copied = 0;
+    while let Some(piece) = body.next(from).await.map_err(CopyError::Read)? {
+        let written = if chunked {
+            chunked::write_chunk(to, &piece).await
+        } else {
+            to.write_all(&piece).await
+        };
+        written.map_err(CopyError::Write)?;
+        // Pass each piece on right away, rather than leaving it in a buffering stream (e.g. TLS)
+        to.flush().await.map_err(CopyError::Write)?;
+        copied += piece.len() as u64;
+    }
+    if chunked {
+        chunked::write_end(to).await.map_err(CopyError::Write)?;
+    }
+    to.flush().await.map_err(CopyError::Write)?;
+    Ok(copied)
+}
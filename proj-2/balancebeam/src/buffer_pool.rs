@@ -0,0 +1,169 @@
+use std::sync::atomic::{AtomicUsize, Ordering};
+use bytes::{Bytes, BytesMut};
+use serde::Serialize;
+
+/// Smallest allocation made for a buffer, so that a connection's buffer has room for a set of
+/// headers and a piece of body without growing
+const MIN_CAPACITY: usize = 16 * 1024;
+
+/// How well buffers are being reused, as reported by the admin API
+#[derive(Clone, Copy, Debug, Serialize)]
+pub struct Stats {
+    /// Times a buffer needed more room and had to allocate it
+    pub allocated: usize,
+    /// Times a buffer needed more room and got it back from pieces that had been passed on
+    pub reused: usize,
+}
+
+static ALLOCATED: AtomicUsize = AtomicUsize::new(0);
+static REUSED: AtomicUsize = AtomicUsize::new(0);
+
+/// Makes room in the buffer for at least `additional` more bytes. Each connection reads into a
+/// buffer of its own, and the headers and body pieces read into it are split off rather than
+/// copied out, so once those have been passed on and dropped, the buffer takes its memory back
+/// instead of allocating more.
+pub fn reserve(buffer: &mut BytesMut, additional: usize) {
+    if buffer.capacity() - buffer.len() >= additional {
+        return;
+    }
+    if buffer.try_reclaim(additional) {
+        REUSED.fetch_add(1, Ordering::Relaxed);
+    } else {
+        ALLOCATED.fetch_add(1, Ordering::Relaxed);
+        buffer.reserve(additional.max(MIN_CAPACITY));
+    }
+}
+
+/// A message's head, copied out of the buffer it was read into in one go, so that the URI and
+/// header values can share the copy rather than each being copied out on its own. The head isn't
+/// split off the buffer itself, which would keep the buffer from getting its memory back for as
+/// long as the message is around.
+pub struct Head<'a> {
+    buffer: &'a [u8],
+    copy: Bytes,
+}
+
+impl<'a> Head<'a> {
+    /// Copies the first `len` bytes of the buffer
+    pub fn new(buffer: &'a [u8], len: usize) -> Head<'a> {
+        Head { buffer, copy: Bytes::copy_from_slice(&buffer[..len]) }
+    }
+
+    /// Returns the copy of `part`, which has to be a slice of the head in the buffer
+    pub fn part(&self, part: &[u8]) -> Bytes {
+        let start = part.as_ptr() as usize - self.buffer.as_ptr() as usize;
+        self.copy.slice(start..start + part.len())
+    }
+}
+
+pub fn stats() -> Stats {
+    Stats {
+        allocated: ALLOCATED.load(Ordering::Relaxed),
+        reused: REUSED.load(Ordering::Relaxed),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::alloc::{GlobalAlloc, Layout, System};
+    use std::cell::Cell;
+    use bytes::BytesMut;
+
+    /// Counts the allocations made on each thread, so that tests running alongside each other
+    /// don't count each other's
+    struct CountingAllocator;
+
+    thread_local! {
+        static ALLOCATIONS: Cell<usize> = const { Cell::new(0) };
+    }
+
+    unsafe impl GlobalAlloc for CountingAllocator {
+        unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+            let _ = ALLOCATIONS.try_with(|count| count.set(count.get() + 1));
+            System.alloc(layout)
+        }
+
+        unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+            System.dealloc(ptr, layout)
+        }
+
+        unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+            let _ = ALLOCATIONS.try_with(|count| count.set(count.get() + 1));
+            System.realloc(ptr, layout, new_size)
+        }
+    }
+
+    #[global_allocator]
+    static ALLOCATOR: CountingAllocator = CountingAllocator;
+
+    fn allocations() -> usize {
+        ALLOCATIONS.with(Cell::get)
+    }
+
+    /// Headers with well-known names, which don't take an allocation of their own
+    const HEADERS: [(&str, &str); 16] = [
+        ("Accept", "text/html"),
+        ("Accept-Encoding", "gzip, deflate"),
+        ("Accept-Language", "en-US,en;q=0.5"),
+        ("Cache-Control", "no-cache"),
+        ("Connection", "keep-alive"),
+        ("Cookie", "session=0123456789abcdef"),
+        ("Date", "Sat, 17 Oct 2026 00:00:00 GMT"),
+        ("Origin", "http://example.com"),
+        ("Pragma", "no-cache"),
+        ("Referer", "http://example.com/"),
+        ("Server", "test"),
+        ("User-Agent", "Mozilla/5.0"),
+        ("Via", "1.1 proxy"),
+        ("Vary", "Accept-Encoding"),
+        ("Etag", "\"abc\""),
+        ("Content-Type", "text/plain"),
+    ];
+
+    /// Writes out a message's start line followed by the first `count` of HEADERS and a body
+    fn message(start_line: &str, count: usize) -> Vec<u8> {
+        let mut message = format!("{}\r\nContent-Length: 5\r\n", start_line);
+        for (name, value) in &HEADERS[..count] {
+            message.push_str(&format!("{}: {}\r\n", name, value));
+        }
+        message.push_str("\r\nhello");
+        message.into_bytes()
+    }
+
+    async fn request_allocations(headers: usize) -> usize {
+        let message = message("GET /some/path?query=1 HTTP/1.1", headers);
+        let before = allocations();
+        let request = crate::request::read_head(&mut &message[..], BytesMut::new()).await;
+        let used = allocations() - before;
+        assert_eq!(request.unwrap().0.headers().len(), headers + 1);
+        used
+    }
+
+    async fn response_allocations(headers: usize) -> usize {
+        let message = message("HTTP/1.1 200 OK", headers);
+        let before = allocations();
+        let response =
+            crate::response::read_from_stream(&mut &message[..], &http::Method::GET).await;
+        let used = allocations() - before;
+        assert_eq!(response.unwrap().body(), b"hello");
+        used
+    }
+
+    /// Reading a request's head should cost the same few allocations however many headers it has
+    #[tokio::test]
+    async fn test_request_head_allocations() {
+        let few = request_allocations(1).await;
+        let many = request_allocations(HEADERS.len()).await;
+        assert_eq!(few, many, "Each header costs an allocation of its own");
+        assert!(many <= 5, "{} allocations to read a request head", many);
+    }
+
+    /// Reading a response should cost the same few allocations however many headers it has
+    #[tokio::test]
+    async fn test_response_allocations() {
+        let few = response_allocations(1).await;
+        let many = response_allocations(HEADERS.len()).await;
+        assert_eq!(few, many, "Each header costs an allocation of its own");
+        assert!(many <= 7, "{} allocations to read a response", many);
+    }
+}
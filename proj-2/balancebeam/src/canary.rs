@@ -0,0 +1,72 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+use serde::Serialize;
+
+/// Parses a `<host>=<percent>` command-line argument, such as `10.0.0.5:8080=5%`
+pub fn parse_canary(arg: &str) -> Result<(String, usize), String> {
+    let (host, percent) = match arg.rsplit_once('=') {
+        Some((host, percent)) if !host.is_empty() => (host, percent),
+        _ => return Err(format!("expected <host>=<percent>, got {:?}", arg)),
+    };
+    match percent.trim_end_matches('%').parse::<usize>() {
+        Ok(percent) if percent <= 100 => Ok((host.to_string(), percent)),
+        _ => Err(format!("expected a percentage from 0 to 100, got {:?}", percent)),
+    }
+}
+
+/// Requests and failures counted for one group of upstreams
+#[derive(Default)]
+struct Counts {
+    requests: AtomicU64,
+    errors: AtomicU64,
+}
+
+/// What the admin API reports about a group of upstreams
+#[derive(Serialize)]
+pub struct GroupReport {
+    requests: u64,
+    errors: u64,
+    error_rate: f64,
+}
+
+impl Counts {
+    fn report(&self) -> GroupReport {
+        let requests = self.requests.load(Ordering::SeqCst);
+        let errors = self.errors.load(Ordering::SeqCst);
+        let error_rate = if requests == 0 { 0.0 } else { errors as f64 / requests as f64 };
+        GroupReport { requests, errors, error_rate }
+    }
+}
+
+/// What the admin API reports about canary traffic
+#[derive(Serialize)]
+pub struct Report {
+    stable: GroupReport,
+    canary: GroupReport,
+}
+
+/// Counts requests answered by the stable upstreams and by the canaries, and how many of each
+/// failed, so the canaries' error rate can be compared to the rest
+#[derive(Default)]
+pub struct CanaryStats {
+    stable: Counts,
+    canary: Counts,
+}
+
+impl CanaryStats {
+    pub fn new() -> CanaryStats {
+        CanaryStats::default()
+    }
+
+    /// Records a request that was sent to a canary or a stable upstream, and whether it failed
+    pub fn record(&self, canary: bool, failed: bool) {
+        let counts = if canary { &self.canary } else { &self.stable };
+        counts.requests.fetch_add(1, Ordering::SeqCst);
+        if failed {
+            counts.errors.fetch_add(1, Ordering::SeqCst);
+        }
+    }
+
+    pub fn report(&self) -> Report {
+        Report { stable: self.stable.report(), canary: self.canary.report() }
+    }
+}
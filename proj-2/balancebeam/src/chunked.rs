@@ -0,0 +1,186 @@
+use bytes::{Buf, Bytes, BytesMut};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use crate::buffer_pool;
+
+/// Longest chunk-size line (including chunk extensions) or trailer line we're willing to buffer
+const MAX_LINE_SIZE: usize = 4096;
+/// Number of bytes to read off the stream at a time
+const READ_SIZE: usize = 8192;
+
+#[derive(Debug)]
+pub enum Error {
+    /// The body isn't valid chunked encoding, or the peer hung up partway through it
+    InvalidChunkedBody,
+    /// Encountered an I/O error when reading from the stream
+    Io(std::io::Error),
+}
+
+/// Returns true if the message body uses chunked encoding, i.e. "chunked" is the last coding in
+/// the Transfer-Encoding header.
+pub fn is_chunked(headers: &http::HeaderMap) -> bool {
+    headers
+        .get_all(http::header::TRANSFER_ENCODING)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .map(|coding| coding.trim())
+        .rfind(|coding| !coding.is_empty())
+        .is_some_and(|coding| coding.eq_ignore_ascii_case("chunked"))
+}
+
+/// Where the decoder is in the body
+enum State {
+    /// Expecting a chunk-size line
+    Size,
+    /// In the middle of a chunk, with the given number of bytes left in it
+    Data(usize),
+    /// Expecting the CRLF that ends a chunk
+    DataEnd,
+    /// Past the last chunk; the body is over once we've read past the trailer
+    Done,
+}
+
+/// Decodes a chunked body a piece at a time as it is read off the stream, so that only a bounded
+/// number of bytes is ever held in memory.
+pub struct Decoder {
+    /// Raw bytes read off the stream that haven't been decoded yet
+    buffer: BytesMut,
+    state: State,
+}
+
+impl Decoder {
+    /// Creates a decoder for a body whose first bytes (read off the stream along with the headers)
+    /// are `already_read`
+    pub fn new(already_read: BytesMut) -> Decoder {
+        Decoder { buffer: already_read, state: State::Size }
+    }
+
+    /// Returns the bytes that were read off the stream after the end of the body
+    pub fn into_leftover(self) -> BytesMut {
+        self.buffer
+    }
+
+    /// Reads more bytes from the stream onto the end of the buffer
+    async fn fill<S: AsyncRead + Unpin>(&mut self, stream: &mut S) -> Result<(), Error> {
+        buffer_pool::reserve(&mut self.buffer, READ_SIZE);
+        let bytes_read = stream.read_buf(&mut self.buffer).await.map_err(Error::Io)?;
+        if bytes_read == 0 {
+            // The peer hung up before sending the terminating chunk
+            return Err(Error::InvalidChunkedBody);
+        }
+        Ok(())
+    }
+
+    /// Checks the chunk-size line at the start of the buffer, if it has arrived yet, without
+    /// reading anything more from the stream
+    pub fn check_buffered(&self) -> Result<(), Error> {
+        if let State::Size = self.state {
+            let unread = &self.buffer[..];
+            match unread.windows(2).position(|window| window == b"\r\n") {
+                Some(idx) => {
+                    parse_chunk_size(&unread[..idx])?;
+                }
+                None if unread.len() > MAX_LINE_SIZE => return Err(Error::InvalidChunkedBody),
+                None => {}
+            }
+        }
+        Ok(())
+    }
+
+    /// Returns the next CRLF-terminated line, without the CRLF
+    async fn read_line<S: AsyncRead + Unpin>(&mut self, stream: &mut S) -> Result<Bytes, Error> {
+        loop {
+            if let Some(idx) = self.buffer.windows(2).position(|window| window == b"\r\n") {
+                let line = self.buffer.split_to(idx).freeze();
+                self.buffer.advance(2);
+                return Ok(line);
+            }
+            if self.buffer.len() > MAX_LINE_SIZE {
+                return Err(Error::InvalidChunkedBody);
+            }
+            self.fill(stream).await?;
+        }
+    }
+
+    /// Returns the next piece of the decoded body, at most `max_len` bytes long, or None once the
+    /// body is over. Trailer fields are read and discarded. Pieces are split off the buffer the
+    /// chunks were read into, rather than copied out of it.
+    pub async fn next<S: AsyncRead + Unpin>(
+        &mut self,
+        stream: &mut S,
+        max_len: usize,
+    ) -> Result<Option<Bytes>, Error> {
+        loop {
+            match self.state {
+                State::Size => {
+                    let size = parse_chunk_size(&self.read_line(stream).await?)?;
+                    if size == 0 {
+                        // Skip over the trailer, which ends with an empty line
+                        while !self.read_line(stream).await?.is_empty() {}
+                        self.state = State::Done;
+                    } else {
+                        self.state = State::Data(size);
+                    }
+                }
+                State::Data(remaining) => {
+                    if self.buffer.is_empty() {
+                        self.fill(stream).await?;
+                    }
+                    let len = remaining.min(max_len).min(self.buffer.len());
+                    let piece = self.buffer.split_to(len).freeze();
+                    self.state = if len == remaining {
+                        State::DataEnd
+                    } else {
+                        State::Data(remaining - len)
+                    };
+                    return Ok(Some(piece));
+                }
+                State::DataEnd => {
+                    if !self.read_line(stream).await?.is_empty() {
+                        return Err(Error::InvalidChunkedBody);
+                    }
+                    self.state = State::Size;
+                }
+                State::Done => return Ok(None),
+            }
+        }
+    }
+}
+
+fn parse_chunk_size(line: &[u8]) -> Result<usize, Error> {
+    // Ignore any chunk extensions (";name=value") after the size
+    let size = line.split(|&byte| byte == b';').next().unwrap_or(&[]);
+    let size = std::str::from_utf8(size).map_err(|_| Error::InvalidChunkedBody)?.trim();
+    if size.is_empty() || !size.chars().all(|c| c.is_ascii_hexdigit()) {
+        return Err(Error::InvalidChunkedBody);
+    }
+    usize::from_str_radix(size, 16).map_err(|_| Error::InvalidChunkedBody)
+}
+
+/// Writes one chunk of a chunked body to the stream. An empty chunk would end the body, so
+/// nothing is written for one.
+pub async fn write_chunk<S: AsyncWrite + Unpin>(
+    stream: &mut S,
+    chunk: &[u8],
+) -> Result<(), std::io::Error> {
+    if !chunk.is_empty() {
+        stream.write_all(format!("{:x}\r\n", chunk.len()).as_bytes()).await?;
+        stream.write_all(chunk).await?;
+        stream.write_all(b"\r\n").await?;
+    }
+    Ok(())
+}
+
+/// Writes the terminating chunk that ends a chunked body
+pub async fn write_end<S: AsyncWrite + Unpin>(stream: &mut S) -> Result<(), std::io::Error> {
+    stream.write_all(b"0\r\n\r\n").await
+}
+
+/// Writes the body to the stream using chunked encoding
+pub async fn write_body<S: AsyncWrite + Unpin>(
+    stream: &mut S,
+    body: &[u8],
+) -> Result<(), std::io::Error> {
+    write_chunk(stream, body).await?;
+    write_end(stream).await
+}
@@ -0,0 +1,108 @@
+use std::collections::VecDeque;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use arc_swap::ArcSwap;
+use dashmap::DashMap;
+
+/// When circuits should trip, and how they recover
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Settings {
+    /// Percentage of failed requests at which a circuit opens (0 = never)
+    pub error_rate: usize,
+    /// Number of most recent requests the error rate is measured over
+    pub window: usize,
+    /// How long an open circuit keeps requests away from its upstream
+    pub cooldown: Duration,
+    /// Number of requests in a row that have to succeed for a half-open circuit to close again
+    pub trial_requests: usize,
+}
+
+enum Circuit {
+    /// Requests are flowing as usual. Holds whether each of the most recent requests failed.
+    Closed(VecDeque<bool>),
+    /// The upstream is failing too often, so no requests are sent to it until the given time
+    Open(Instant),
+    /// The cooldown is over and trial requests are being let through. Holds how many have
+    /// succeeded so far.
+    HalfOpen(usize),
+}
+
+/// Keeps a circuit for each upstream, which opens when too many requests to it fail, so that the
+/// upstream gets some time to recover before it is sent more traffic. Every request's outcome is
+/// recorded, so the circuits are sharded rather than locked as a whole.
+pub struct CircuitBreakers {
+    settings: ArcSwap<Settings>,
+    circuits: DashMap<String, Circuit>,
+}
+
+impl CircuitBreakers {
+    pub fn new(settings: Settings) -> CircuitBreakers {
+        CircuitBreakers { settings: ArcSwap::from_pointee(settings), circuits: DashMap::new() }
+    }
+
+    /// Changes the settings. Open circuits stay open until their cooldown is over.
+    pub fn set_settings(&self, settings: Settings) {
+        if settings.error_rate == 0 {
+            self.circuits.clear();
+        }
+        self.settings.store(Arc::new(settings));
+    }
+
+    /// Records the outcome of a request to the upstream. Returns true if this opened its circuit.
+    pub fn record(&self, address: &str, success: bool) -> bool {
+        let settings = **self.settings.load();
+        if settings.error_rate == 0 {
+            return false;
+        }
+        let mut circuit = match self.circuits.get_mut(address) {
+            Some(circuit) => circuit,
+            None => self
+                .circuits
+                .entry(address.to_string())
+                .or_insert_with(|| Circuit::Closed(VecDeque::new())),
+        };
+        let trip = match &mut *circuit {
+            Circuit::Closed(outcomes) => {
+                outcomes.push_back(!success);
+                while outcomes.len() > settings.window {
+                    outcomes.pop_front();
+                }
+                let failures = outcomes.iter().filter(|&&failed| failed).count();
+                outcomes.len() >= settings.window
+                    && failures * 100 >= settings.error_rate * outcomes.len()
+            }
+            // Requests that were already in flight when the circuit opened don't count
+            Circuit::Open(_) => false,
+            Circuit::HalfOpen(successes) => {
+                if success {
+                    *successes += 1;
+                    if *successes >= settings.trial_requests {
+                        *circuit = Circuit::Closed(VecDeque::new());
+                    }
+                    false
+                } else {
+                    true
+                }
+            }
+        };
+        if trip {
+            *circuit = Circuit::Open(Instant::now() + settings.cooldown);
+        }
+        trip
+    }
+
+    /// Moves the circuits whose cooldown is over to half-open, returning their addresses
+    pub fn half_open_expired(&self) -> Vec<String> {
+        let now = Instant::now();
+        let mut expired = Vec::new();
+        for mut circuit in self.circuits.iter_mut() {
+            if let Circuit::Open(until) = *circuit.value() {
+                if until <= now {
+                    *circuit.value_mut() = Circuit::HalfOpen(0);
+                    expired.push(circuit.key().clone());
+                }
+            }
+        }
+        expired
+    }
+}
@@ -0,0 +1,364 @@
+use async_compression::tokio::write::{GzipDecoder, GzipEncoder, ZlibDecoder, ZlibEncoder};
+use tokio::io::{AsyncRead, AsyncWrite, AsyncWriteExt};
+use crate::body::{self, BodyReader, CopyError, Framing};
+use crate::chunked;
+
+/// Content types that are compressed when no --compression-type is given
+const DEFAULT_TYPES: [&str; 5] = [
+    "text/*",
+    "application/json",
+    "application/javascript",
+    "application/xml",
+    "image/svg+xml",
+];
+
+/// Which responses get compressed
+#[derive(Clone, Debug)]
+pub struct Settings {
+    /// Smallest body worth compressing, in bytes (0 = compression is off)
+    pub min_size: usize,
+    /// Content types to compress, e.g. "application/json" or "text/*" (empty = the defaults)
+    pub types: Vec<String>,
+}
+
+/// Content codings we can compress and decompress bodies with
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Coding {
+    Gzip,
+    /// The zlib format, which HTTP calls "deflate"
+    Deflate,
+}
+
+impl Coding {
+    fn name(&self) -> &'static str {
+        match self {
+            Coding::Gzip => "gzip",
+            Coding::Deflate => "deflate",
+        }
+    }
+
+    fn from_name(name: &str) -> Option<Coding> {
+        match name.trim().to_ascii_lowercase().as_str() {
+            "gzip" | "x-gzip" => Some(Coding::Gzip),
+            "deflate" => Some(Coding::Deflate),
+            _ => None,
+        }
+    }
+}
+
+/// What to do to a response body on its way to the client
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Transform {
+    Compress(Coding),
+    /// The upstream compressed the body with a coding the client doesn't accept
+    Decompress(Coding),
+}
+
+/// The Accept-Encoding header a client sent, kept aside while the request goes to the upstream
+/// with the canonical one instead
+#[derive(Clone, Debug)]
+struct ClientAcceptEncoding(Vec<http::HeaderValue>);
+
+/// Asks the upstream for the uncompressed representation of the resource, whatever the client
+/// accepts, so that the cache keeps one copy of it rather than one per encoding. The client's own
+/// Accept-Encoding is still what the response is compressed to suit.
+pub fn normalize_accept_encoding(request: &mut http::Request<Vec<u8>>) {
+    if request.extensions().get::<ClientAcceptEncoding>().is_some() {
+        return;
+    }
+    let headers = request.headers_mut();
+    let client_values = headers.get_all(http::header::ACCEPT_ENCODING).iter().cloned().collect();
+    headers.insert(http::header::ACCEPT_ENCODING, http::HeaderValue::from_static("identity"));
+    request.extensions_mut().insert(ClientAcceptEncoding(client_values));
+}
+
+/// Returns the quality value the client's Accept-Encoding header gives the coding, or None if
+/// the request doesn't have the header. Codings that aren't listed get the "*" entry's value.
+fn accepted_quality(request: &http::Request<Vec<u8>>, coding: Coding) -> Option<f32> {
+    let values: Vec<&http::HeaderValue> = match request.extensions().get() {
+        Some(ClientAcceptEncoding(values)) => values.iter().collect(),
+        None => request.headers().get_all(http::header::ACCEPT_ENCODING).iter().collect(),
+    };
+    if values.is_empty() {
+        return None;
+    }
+    let mut wildcard = 0.0;
+    let entries = values.iter().filter_map(|value| value.to_str().ok());
+    for entry in entries.flat_map(|value| value.split(',')) {
+        let mut params = entry.split(';');
+        let name = params.next().unwrap_or("").trim();
+        let quality = params
+            .filter_map(|param| param.trim().strip_prefix("q="))
+            .find_map(|quality| quality.trim().parse::<f32>().ok())
+            .unwrap_or(1.0);
+        if name == "*" {
+            wildcard = quality;
+        } else if Coding::from_name(name) == Some(coding) {
+            return Some(quality);
+        }
+    }
+    Some(wildcard)
+}
+
+fn matches_type(content_type: &str, pattern: &str) -> bool {
+    match pattern.strip_suffix("/*") {
+        Some(main_type) => content_type
+            .split('/')
+            .next()
+            .is_some_and(|content_main_type| content_main_type.eq_ignore_ascii_case(main_type)),
+        None => content_type.eq_ignore_ascii_case(pattern),
+    }
+}
+
+/// Returns true if the response's Content-Type is one of the types to compress
+fn is_compressible_type(settings: &Settings, response: &http::Response<Vec<u8>>) -> bool {
+    let content_type = match response.headers().get(http::header::CONTENT_TYPE) {
+        Some(value) => value.to_str().unwrap_or(""),
+        None => return false,
+    };
+    let content_type = content_type.split(';').next().unwrap_or("").trim();
+    if settings.types.is_empty() {
+        DEFAULT_TYPES.iter().any(|pattern| matches_type(content_type, pattern))
+    } else {
+        settings.types.iter().any(|pattern| matches_type(content_type, pattern))
+    }
+}
+
+/// Decides whether the response body should be compressed or decompressed before it is sent to
+/// the client
+pub fn choose(
+    settings: &Settings,
+    request: &http::Request<Vec<u8>>,
+    response: &http::Response<Vec<u8>>,
+    framing: Framing,
+) -> Option<Transform> {
+    if settings.min_size == 0 || framing == Framing::Empty {
+        return None;
+    }
+    let headers = response.headers();
+    // Caches and clients may ask for the body to be passed on exactly as it is, and ranges of a
+    // body would no longer line up with it once it was re-encoded
+    let no_transform = headers
+        .get_all(http::header::CACHE_CONTROL)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .any(|value| value.to_ascii_lowercase().contains("no-transform"));
+    if no_transform
+        || response.status() == http::StatusCode::PARTIAL_CONTENT
+        || headers.contains_key(http::header::CONTENT_RANGE)
+    {
+        return None;
+    }
+
+    let encodings: Vec<&str> = headers
+        .get_all(http::header::CONTENT_ENCODING)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .map(|coding| coding.trim())
+        .filter(|coding| !coding.is_empty() && !coding.eq_ignore_ascii_case("identity"))
+        .collect();
+    if !encodings.is_empty() {
+        // Undo the upstream's compression if the client can't, as long as it's a single coding
+        // we know
+        let coding = match encodings.as_slice() {
+            [coding] => Coding::from_name(coding)?,
+            _ => return None,
+        };
+        return match accepted_quality(request, coding) {
+            Some(quality) if quality <= 0.0 => Some(Transform::Decompress(coding)),
+            _ => None,
+        };
+    }
+
+    if let Framing::Length(len) = framing {
+        if len < settings.min_size {
+            return None;
+        }
+    }
+    if !is_compressible_type(settings, response) {
+        return None;
+    }
+    // Prefer gzip when the client likes both equally
+    let gzip = accepted_quality(request, Coding::Gzip).unwrap_or(0.0);
+    let deflate = accepted_quality(request, Coding::Deflate).unwrap_or(0.0);
+    if gzip > 0.0 && gzip >= deflate {
+        Some(Transform::Compress(Coding::Gzip))
+    } else if deflate > 0.0 {
+        Some(Transform::Compress(Coding::Deflate))
+    } else {
+        None
+    }
+}
+
+/// Updates the response's headers for a body that will be transformed. The new body's length
+/// isn't known up front, so it is sent chunked.
+pub fn update_headers(response: &mut http::Response<Vec<u8>>, transform: Transform) {
+    let headers = response.headers_mut();
+    headers.remove(http::header::CONTENT_LENGTH);
+    headers.insert(http::header::TRANSFER_ENCODING, http::HeaderValue::from_static("chunked"));
+    match transform {
+        Transform::Compress(coding) => {
+            headers.insert(
+                http::header::CONTENT_ENCODING,
+                http::HeaderValue::from_static(coding.name()),
+            );
+            if !varies_by_encoding(headers) {
+                let vary = http::HeaderValue::from_static("Accept-Encoding");
+                headers.append(http::header::VARY, vary);
+            }
+        }
+        Transform::Decompress(_) => {
+            headers.remove(http::header::CONTENT_ENCODING);
+        }
+    }
+    // The body's bytes change, so a strong validator no longer matches them
+    if let Some(etag) = headers.get(http::header::ETAG).and_then(|etag| etag.to_str().ok()) {
+        if !etag.starts_with("W/") {
+            if let Ok(weak) = http::HeaderValue::from_str(&format!("W/{}", etag)) {
+                headers.insert(http::header::ETAG, weak);
+            }
+        }
+    }
+}
+
+/// Returns true if the headers' Vary already lists Accept-Encoding
+fn varies_by_encoding(headers: &http::HeaderMap) -> bool {
+    headers
+        .get_all(http::header::VARY)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .any(|name| name.trim().eq_ignore_ascii_case("accept-encoding"))
+}
+
+/// Compresses or decompresses a response whose whole body is in memory (one from the cache) to
+/// suit the client. Since the new body is at hand, it's sent with its length rather than chunked.
+pub async fn transform_in_memory(
+    settings: &Settings,
+    request: &http::Request<Vec<u8>>,
+    response: &mut http::Response<Vec<u8>>,
+) {
+    let framing = Framing::Length(response.body().len());
+    let transform = match choose(settings, request, response, framing) {
+        Some(transform) => transform,
+        None => return,
+    };
+    let mut transcoder = Transcoder::new(transform);
+    let transcoded = async {
+        let mut body = transcoder.write(response.body()).await?;
+        body.extend(transcoder.finish().await?);
+        Ok::<_, std::io::Error>(body)
+    };
+    match transcoded.await {
+        Ok(body) => {
+            update_headers(response, transform);
+            let headers = response.headers_mut();
+            headers.remove(http::header::TRANSFER_ENCODING);
+            headers.insert(http::header::CONTENT_LENGTH, http::HeaderValue::from(body.len()));
+            *response.body_mut() = body;
+        }
+        Err(error) => log::warn!("Failed to {:?} cached response body: {}", transform, error),
+    }
+}
+
+/// Compresses or decompresses into an in-memory buffer, which is emptied as pieces are passed on
+enum Transcoder {
+    GzipEncoder(GzipEncoder<Vec<u8>>),
+    DeflateEncoder(ZlibEncoder<Vec<u8>>),
+    GzipDecoder(GzipDecoder<Vec<u8>>),
+    DeflateDecoder(ZlibDecoder<Vec<u8>>),
+}
+
+impl Transcoder {
+    fn new(transform: Transform) -> Transcoder {
+        match transform {
+            Transform::Compress(Coding::Gzip) => Transcoder::GzipEncoder(GzipEncoder::new(Vec::new())),
+            Transform::Compress(Coding::Deflate) => {
+                Transcoder::DeflateEncoder(ZlibEncoder::new(Vec::new()))
+            }
+            Transform::Decompress(Coding::Gzip) => {
+                Transcoder::GzipDecoder(GzipDecoder::new(Vec::new()))
+            }
+            Transform::Decompress(Coding::Deflate) => {
+                Transcoder::DeflateDecoder(ZlibDecoder::new(Vec::new()))
+            }
+        }
+    }
+
+    fn writer(&mut self) -> &mut (dyn AsyncWrite + Unpin + Send) {
+        match self {
+            Transcoder::GzipEncoder(encoder) => encoder,
+            Transcoder::DeflateEncoder(encoder) => encoder,
+            Transcoder::GzipDecoder(decoder) => decoder,
+            Transcoder::DeflateDecoder(decoder) => decoder,
+        }
+    }
+
+    /// Takes the output produced so far
+    fn take_output(&mut self) -> Vec<u8> {
+        std::mem::take(match self {
+            Transcoder::GzipEncoder(encoder) => encoder.get_mut(),
+            Transcoder::DeflateEncoder(encoder) => encoder.get_mut(),
+            Transcoder::GzipDecoder(decoder) => decoder.get_mut(),
+            Transcoder::DeflateDecoder(decoder) => decoder.get_mut(),
+        })
+    }
+
+    /// Feeds in a piece of the body, returning all the output it can so far
+    async fn write(&mut self, piece: &[u8]) -> Result<Vec<u8>, std::io::Error> {
+        let writer = self.writer();
+        writer.write_all(piece).await?;
+        // Flush, so that each piece reaches the client as soon as it arrives, like an untouched
+        // body would
+        writer.flush().await?;
+        Ok(self.take_output())
+    }
+
+    /// Ends the body, returning the rest of the output
+    async fn finish(&mut self) -> Result<Vec<u8>, std::io::Error> {
+        self.writer().shutdown().await?;
+        Ok(self.take_output())
+    }
+}
+
+/// Passes the rest of a body on from one stream to the other a piece at a time, compressing or
+/// decompressing it, and sending it chunked (or, for clients that don't understand chunks, as
+/// plain bytes ended by closing the connection). Returns the number of body bytes sent.
+pub async fn copy<R, W>(
+    transform: Transform,
+    body: &mut BodyReader,
+    from: &mut R,
+    to: &mut W,
+    chunked: bool,
+) -> Result<u64, CopyError>
+where
+    R: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    // A body that doesn't decompress is the upstream's fault, just like one that's cut short
+    let transcode_error = |err| CopyError::Read(body::Error::Io(err));
+    let mut transcoder = Transcoder::new(transform);
+    let mut sent = 0;
+    while let Some(piece) = body.next(from).await.map_err(CopyError::Read)? {
+        let output = transcoder.write(&piece).await.map_err(transcode_error)?;
+        let written = if chunked {
+            chunked::write_chunk(to, &output).await
+        } else {
+            to.write_all(&output).await
+        };
+        written.map_err(CopyError::Write)?;
+        to.flush().await.map_err(CopyError::Write)?;
+        sent += output.len() as u64;
+    }
+    let output = transcoder.finish().await.map_err(transcode_error)?;
+    let written = if chunked {
+        chunked::write_body(to, &output).await
+    } else {
+        to.write_all(&output).await
+    };
+    written.map_err(CopyError::Write)?;
+    to.flush().await.map_err(CopyError::Write)?;
+    sent += output.len() as u64;
+    Ok(sent)
+}
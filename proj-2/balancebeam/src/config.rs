@@ -0,0 +1,1009 @@
+use std::{fmt, fs};
+use std::collections::BTreeMap;
+use std::time::Duration;
+use serde::Deserialize;
+use crate::active_health::StatusRange;
+use crate::auth::Authenticator;
+use crate::jwt::{self, JwtValidator};
+use crate::ban_list;
+use crate::circuit_breaker;
+use crate::compression;
+use crate::cors;
+use crate::error_pages::{self, ErrorPage};
+use crate::header_rules::{HeaderRule, HeaderRules};
+use crate::health_hooks;
+use crate::load_balance::ArgLoadBalance;
+use crate::proxy_headers::ProxyHeader;
+use crate::access_control::{AccessList, Cidr};
+use crate::redirect::{RedirectRule, Redirects};
+use crate::upstreams_file;
+use crate::routing::RoutePolicy;
+use crate::rate_limiter::{self, ArgRateLimiter, RateLimitBy};
+
+/// Settings that can be changed while balancebeam is running, by editing the config file and
+/// sending the process a SIGHUP. These start out with the values given on the command line, and
+/// anything set in the config file takes precedence.
+#[derive(Clone, Debug)]
+pub struct Config {
+    /// Upstreams for requests that don't match any of the routes
+    pub upstreams: Vec<String>,
+    /// File listing more upstreams like the ones above, which is watched for changes
+    pub upstreams_file: Option<String>,
+    /// Upstreams read from the upstreams file when the config was last loaded (or the file last
+    /// changed)
+    pub file_upstreams: Vec<String>,
+    /// Upstreams found through service discovery (Consul or etcd) when it was last polled
+    pub discovered_upstreams: Vec<String>,
+    /// Named groups of upstreams that routes can send requests to
+    pub pools: BTreeMap<String, Vec<String>>,
+    /// Path prefixes, and the pool that requests under each one are sent to
+    pub routes: BTreeMap<String, String>,
+    /// Settings that routes (given by their prefix) override for the requests under them
+    pub route_policies: BTreeMap<String, RoutePolicy>,
+    /// Client address ranges, and the pool that requests from each one are sent to (whatever
+    /// their path)
+    pub client_routes: Vec<(Cidr, String)>,
+    /// Pools taking turns serving requests that don't match any route, switched between through
+    /// the admin API. Blue serves them to begin with.
+    pub blue_pool: Option<String>,
+    pub green_pool: Option<String>,
+    /// How long (in seconds) client connections pinned to an upstream in the pool switched away
+    /// from may keep using it
+    pub blue_green_drain_timeout: usize,
+    /// Canary upstreams, with the percentage of requests each one gets
+    pub canaries: BTreeMap<String, usize>,
+    /// Shadow backend that gets a copy of every request, whose responses are thrown away
+    pub mirror: Option<String>,
+    /// Upstreams (or host names) being drained for maintenance: they get no new requests, while
+    /// the ones already in flight finish
+    pub drain: Vec<String>,
+    /// Path prefixes whose GET responses are cached for as long as their Cache-Control allows
+    pub cache_paths: Vec<String>,
+    /// File to answer requests with while in maintenance mode (defaults to a plain 503 message)
+    pub maintenance_page: Option<String>,
+    /// Clients in these ranges are let through while in maintenance mode
+    pub maintenance_allow_cidrs: Vec<Cidr>,
+    /// Let clients pick the upstream for a request with the X-Balancebeam-Upstream header, for
+    /// debugging a single backend through the proxy
+    pub upstream_override: bool,
+    /// Clients in these ranges may use the X-Balancebeam-Upstream header (empty = only clients
+    /// on this host)
+    pub upstream_override_cidrs: Vec<Cidr>,
+    /// Proxies in front of balancebeam whose X-Forwarded-For is believed, so that rate limits and
+    /// logs see the client behind them instead of the proxy
+    pub trusted_proxies: Vec<Cidr>,
+    /// Redirect clients that connected over plain HTTP to HTTPS
+    pub force_https: bool,
+    /// Redirect requests for any other host name to this one
+    pub canonical_host: Option<String>,
+    /// Path prefixes whose requests are redirected elsewhere
+    pub redirects: Vec<RedirectRule>,
+    /// Pages to answer with instead of the plain message, for errors we answer requests with
+    pub error_pages: BTreeMap<u16, ErrorPage>,
+    /// Changes made to the headers of each request before it's forwarded
+    pub request_headers: Vec<HeaderRule>,
+    /// Changes made to the headers of each response before it's sent to the client
+    pub response_headers: Vec<HeaderRule>,
+    pub active_health_check_interval: usize,
+    pub active_health_check_path: String,
+    pub active_health_check_method: http::Method,
+    pub active_health_check_status: StatusRange,
+    pub active_health_check_body: Option<String>,
+    pub active_health_check_timeout: usize,
+    pub passive_health_check_failures: usize,
+    pub passive_health_check_window: usize,
+    pub dns_refresh_interval: usize,
+    pub slow_start_window: usize,
+    /// URL to POST a JSON event to whenever an upstream goes down or comes back up
+    pub health_webhook: Option<String>,
+    /// Shell command to run whenever an upstream goes down or comes back up
+    pub health_command: Option<String>,
+    /// Seconds between summaries of each upstream's stats in the log (0 = don't log them)
+    pub stats_log_interval: usize,
+    pub max_retries: usize,
+    pub client_read_timeout: usize,
+    /// Seconds a client connection may sit idle between requests before it is closed
+    /// (0 = never)
+    pub client_idle_timeout: usize,
+    /// Seconds a client may go without reading any of a response before it is disconnected
+    /// (0 = never)
+    pub client_write_timeout: usize,
+    /// Bytes per second a client has to send requests and read responses at, counting only the
+    /// time spent waiting on it, before it is disconnected (0 = no minimum)
+    pub client_min_rate: usize,
+    /// Requests a client may send on one connection before it is closed (0 = no limit)
+    pub max_requests_per_connection: usize,
+    pub upstream_connect_timeout: usize,
+    /// Seconds to wait for open connections to finish when shutting down (0 = forever)
+    pub shutdown_timeout: usize,
+    /// Milliseconds to wait before trying another upstream after failing to connect to one,
+    /// doubling with each attempt (0 = don't wait)
+    pub upstream_connect_backoff: usize,
+    pub upstream_response_timeout: usize,
+    pub max_upstream_rps: usize,
+    pub upstream_queue_timeout: usize,
+    /// Most requests each upstream may be serving at once (0 = unlimited)
+    pub max_upstream_concurrency: usize,
+    /// Most requests that may wait for an upstream to have room for them
+    pub request_queue_size: usize,
+    /// Seconds a request may wait for an upstream to have room for it
+    pub request_queue_timeout: usize,
+    pub circuit_breaker_error_rate: usize,
+    pub circuit_breaker_window: usize,
+    pub circuit_breaker_cooldown: usize,
+    pub circuit_breaker_trial_requests: usize,
+    pub max_requests_per_minute: usize,
+    /// Most requests each client IP may have in progress at once (0 = unlimited)
+    pub max_concurrent_per_ip: usize,
+    /// Most bytes each client IP may send and be sent in a minute, headers included
+    /// (0 = unlimited)
+    pub max_bytes_per_minute: u64,
+    pub rate_limiter: ArgRateLimiter,
+    pub rate_limit_burst: usize,
+    /// Redis server for the Redis rate limiter, e.g. "redis://10.0.0.5:6379"
+    #[cfg(feature = "redis")]
+    pub rate_limit_redis_url: Option<String>,
+    pub rate_limit_by: RateLimitBy,
+    /// Number of times a client may go over the rate limit in an hour before it is banned
+    /// (0 = never ban)
+    pub ban_threshold: usize,
+    /// How long (in seconds) banned clients are turned away for
+    pub ban_duration: usize,
+    pub load_balancer: ArgLoadBalance,
+    pub hash_header: Option<String>,
+    pub sticky_cookie: Option<String>,
+    pub disabled_proxy_headers: Vec<ProxyHeader>,
+    /// Only clients in these ranges may connect (empty = anyone)
+    pub allow_cidrs: Vec<Cidr>,
+    /// Clients in these ranges may not connect, even if they're in an allowed range
+    pub deny_cidrs: Vec<Cidr>,
+    /// htpasswd-style file of the users allowed through with HTTP Basic authentication
+    pub auth_htpasswd: Option<String>,
+    /// Bearer token that lets requests through
+    pub auth_bearer_token: Option<String>,
+    /// Name of the protected area, sent to clients asked for credentials
+    pub auth_realm: String,
+    /// Shared secret that HS256-signed JSON Web Tokens are checked with
+    pub jwt_hs256_secret: Option<String>,
+    /// PEM file with the public key that RS256-signed JSON Web Tokens are checked with
+    pub jwt_rs256_public_key: Option<String>,
+    /// Issuer that tokens must name, if any
+    pub jwt_issuer: Option<String>,
+    /// Audience that tokens must name, if any
+    pub jwt_audience: Option<String>,
+    /// Claim whose value names the pool to send a request to, ahead of the routes
+    pub jwt_route_claim: Option<String>,
+    /// Claim that requests are counted by when rate limiting by JWT
+    pub jwt_rate_limit_claim: String,
+    /// Origins allowed to make cross-origin requests, or "*" for any (empty = CORS is off)
+    pub cors_allow_origins: Vec<String>,
+    /// Methods allowed in cross-origin requests (empty = the usual ones)
+    pub cors_allow_methods: Vec<String>,
+    /// Request headers allowed in cross-origin requests (empty = any the browser asks for)
+    pub cors_allow_headers: Vec<String>,
+    /// Response headers that scripts making cross-origin requests may read
+    pub cors_expose_headers: Vec<String>,
+    /// Whether cross-origin requests may carry credentials
+    pub cors_allow_credentials: bool,
+    /// Seconds browsers may cache preflight answers for (0 = don't say)
+    pub cors_max_age: usize,
+    /// Smallest response body to compress, in bytes (0 = don't compress)
+    pub compression_min_size: usize,
+    /// Content types to compress (empty = text, JSON, JavaScript, XML and SVG)
+    pub compression_types: Vec<String>,
+}
+
+/// Contents of a config file. Keys use the same names as the command-line options, and every key
+/// is optional:
+///
+/// ```toml
+/// upstreams = ["10.0.0.1:8080", "10.0.0.2:8080"]
+/// active-health-check-path = "/healthz"
+/// active-health-check-status = "200-299"
+/// max-requests-per-minute = 120
+/// blue-pool = "v1"
+/// green-pool = "v2"
+///
+/// [pools]
+/// api = ["10.0.1.1:8080", "10.0.1.2:8080"]
+/// v1 = ["10.0.2.1:8080"]
+/// v2 = ["10.0.3.1:8080"]
+///
+/// [routes]
+/// "/api" = "api"
+/// "/reports" = { pool = "api", upstream-response-timeout = 300, max-retries = 0 }
+///
+/// [client-routes]
+/// "10.0.0.0/8" = "staging"
+///
+/// [[redirects]]
+/// from = "/blog"
+/// to = "https://blog.example.com"
+/// status = 308
+///
+/// [[response-headers]]
+/// action = "remove"
+/// name = "Server"
+///
+/// [error-pages.502]
+/// file = "/etc/balancebeam/502.html"
+///
+/// [error-pages.429]
+/// body = "Slow down! (request {{request_id}})"
+/// ```
+#[derive(Deserialize, Default, Debug)]
+#[serde(default, rename_all = "kebab-case", deny_unknown_fields)]
+struct ConfigFile {
+    upstreams: Option<Vec<String>>,
+    upstreams_file: Option<String>,
+    pools: Option<BTreeMap<String, Vec<String>>>,
+    routes: Option<BTreeMap<String, RouteEntry>>,
+    client_routes: Option<BTreeMap<String, String>>,
+    blue_pool: Option<String>,
+    green_pool: Option<String>,
+    blue_green_drain_timeout: Option<usize>,
+    canaries: Option<BTreeMap<String, usize>>,
+    mirror: Option<String>,
+    drain: Option<Vec<String>>,
+    cache_paths: Option<Vec<String>>,
+    maintenance_page: Option<String>,
+    maintenance_allow_cidrs: Option<Vec<Cidr>>,
+    upstream_override: Option<bool>,
+    upstream_override_cidrs: Option<Vec<Cidr>>,
+    trusted_proxies: Option<Vec<Cidr>>,
+    force_https: Option<bool>,
+    canonical_host: Option<String>,
+    redirects: Option<Vec<RedirectRule>>,
+    error_pages: Option<BTreeMap<String, ErrorPage>>,
+    request_headers: Option<Vec<HeaderRule>>,
+    response_headers: Option<Vec<HeaderRule>>,
+    active_health_check_interval: Option<usize>,
+    active_health_check_path: Option<String>,
+    active_health_check_method: Option<String>,
+    active_health_check_status: Option<StatusRange>,
+    active_health_check_body: Option<String>,
+    active_health_check_timeout: Option<usize>,
+    passive_health_check_failures: Option<usize>,
+    passive_health_check_window: Option<usize>,
+    dns_refresh_interval: Option<usize>,
+    slow_start_window: Option<usize>,
+    health_webhook: Option<String>,
+    health_command: Option<String>,
+    stats_log_interval: Option<usize>,
+    max_retries: Option<usize>,
+    client_read_timeout: Option<usize>,
+    client_idle_timeout: Option<usize>,
+    client_write_timeout: Option<usize>,
+    client_min_rate: Option<usize>,
+    max_requests_per_connection: Option<usize>,
+    upstream_connect_timeout: Option<usize>,
+    shutdown_timeout: Option<usize>,
+    upstream_connect_backoff: Option<usize>,
+    upstream_response_timeout: Option<usize>,
+    max_upstream_rps: Option<usize>,
+    upstream_queue_timeout: Option<usize>,
+    max_upstream_concurrency: Option<usize>,
+    request_queue_size: Option<usize>,
+    request_queue_timeout: Option<usize>,
+    circuit_breaker_error_rate: Option<usize>,
+    circuit_breaker_window: Option<usize>,
+    circuit_breaker_cooldown: Option<usize>,
+    circuit_breaker_trial_requests: Option<usize>,
+    max_requests_per_minute: Option<usize>,
+    max_concurrent_per_ip: Option<usize>,
+    max_bytes_per_minute: Option<u64>,
+    rate_limiter: Option<ArgRateLimiter>,
+    rate_limit_burst: Option<usize>,
+    #[cfg(feature = "redis")]
+    rate_limit_redis_url: Option<String>,
+    rate_limit_by: Option<RateLimitBy>,
+    ban_threshold: Option<usize>,
+    ban_duration: Option<usize>,
+    load_balancer: Option<ArgLoadBalance>,
+    hash_header: Option<String>,
+    sticky_cookie: Option<String>,
+    disabled_proxy_headers: Option<Vec<ProxyHeader>>,
+    allow_cidrs: Option<Vec<Cidr>>,
+    deny_cidrs: Option<Vec<Cidr>>,
+    auth_htpasswd: Option<String>,
+    auth_bearer_token: Option<String>,
+    auth_realm: Option<String>,
+    jwt_hs256_secret: Option<String>,
+    jwt_rs256_public_key: Option<String>,
+    jwt_issuer: Option<String>,
+    jwt_audience: Option<String>,
+    jwt_route_claim: Option<String>,
+    jwt_rate_limit_claim: Option<String>,
+    cors_allow_origins: Option<Vec<String>>,
+    cors_allow_methods: Option<Vec<String>>,
+    cors_allow_headers: Option<Vec<String>>,
+    cors_expose_headers: Option<Vec<String>>,
+    cors_allow_credentials: Option<bool>,
+    cors_max_age: Option<usize>,
+    compression_min_size: Option<usize>,
+    compression_types: Option<Vec<String>>,
+}
+
+/// A route in a config file: either just the pool it sends requests to, or a table that also
+/// overrides some settings for the requests under it
+#[derive(Deserialize, Debug)]
+#[serde(untagged)]
+enum RouteEntry {
+    Pool(String),
+    WithPolicy(RouteTable),
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+struct RouteTable {
+    pool: String,
+    upstream_response_timeout: Option<usize>,
+    max_retries: Option<usize>,
+    max_requests_per_minute: Option<usize>,
+}
+
+#[derive(Debug)]
+pub enum Error {
+    /// The config file couldn't be read
+    Unreadable(std::io::Error),
+    /// The config file isn't valid TOML, or contains unknown keys
+    Malformed(toml::de::Error),
+    /// The upstreams file couldn't be read
+    UpstreamsFileUnreadable(String, std::io::Error),
+    /// The config file has a setting whose value isn't valid, e.g. an unparseable HTTP method
+    InvalidValue(&'static str, String),
+    /// The resulting config doesn't have any upstream servers to forward requests to
+    NoUpstreams,
+    /// A route (given by its prefix, or its client address range) sends requests to a pool that
+    /// doesn't exist
+    UnknownPool(String, String),
+    /// The canaries' percentages add up to more than 100
+    CanaryShareTooLarge(usize),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Unreadable(err) => write!(f, "could not read config file: {}", err),
+            Error::Malformed(err) => write!(f, "invalid config file: {}", err),
+            Error::UpstreamsFileUnreadable(path, err) => {
+                write!(f, "could not read upstreams file {}: {}", path, err)
+            }
+            Error::InvalidValue(key, value) => write!(f, "invalid value {:?} for {}", value, key),
+            Error::NoUpstreams => write!(f, "at least one upstream server must be specified"),
+            Error::UnknownPool(prefix, pool) => {
+                write!(f, "route {} refers to unknown pool {:?}", prefix, pool)
+            }
+            Error::CanaryShareTooLarge(percent) => {
+                write!(f, "canaries would get {}% of requests, more than 100%", percent)
+            }
+        }
+    }
+}
+
+impl Config {
+    /// Returns the settings balancebeam uses by default for the given upstreams. These are the
+    /// same as the command line's defaults.
+    pub fn new(upstreams: Vec<String>) -> Config {
+        Config {
+            upstreams,
+            upstreams_file: None,
+            file_upstreams: Vec::new(),
+            discovered_upstreams: Vec::new(),
+            pools: BTreeMap::new(),
+            routes: BTreeMap::new(),
+            route_policies: BTreeMap::new(),
+            client_routes: Vec::new(),
+            blue_pool: None,
+            green_pool: None,
+            blue_green_drain_timeout: 30,
+            canaries: BTreeMap::new(),
+            mirror: None,
+            drain: Vec::new(),
+            cache_paths: Vec::new(),
+            maintenance_page: None,
+            maintenance_allow_cidrs: Vec::new(),
+            upstream_override: false,
+            upstream_override_cidrs: Vec::new(),
+            trusted_proxies: Vec::new(),
+            force_https: false,
+            canonical_host: None,
+            redirects: Vec::new(),
+            error_pages: BTreeMap::new(),
+            request_headers: Vec::new(),
+            response_headers: Vec::new(),
+            active_health_check_interval: 10,
+            active_health_check_path: "/".to_string(),
+            active_health_check_method: http::Method::GET,
+            active_health_check_status: "200".parse().unwrap(),
+            active_health_check_body: None,
+            active_health_check_timeout: 5,
+            passive_health_check_failures: 3,
+            passive_health_check_window: 10,
+            dns_refresh_interval: 0,
+            slow_start_window: 0,
+            health_webhook: None,
+            health_command: None,
+            stats_log_interval: 60,
+            max_retries: 0,
+            client_read_timeout: 60,
+            client_idle_timeout: 60,
+            client_write_timeout: 60,
+            client_min_rate: 0,
+            max_requests_per_connection: 0,
+            upstream_connect_timeout: 10,
+            shutdown_timeout: 30,
+            upstream_connect_backoff: 0,
+            upstream_response_timeout: 60,
+            max_upstream_rps: 0,
+            upstream_queue_timeout: 0,
+            max_upstream_concurrency: 0,
+            request_queue_size: 100,
+            request_queue_timeout: 5,
+            circuit_breaker_error_rate: 0,
+            circuit_breaker_window: 20,
+            circuit_breaker_cooldown: 30,
+            circuit_breaker_trial_requests: 3,
+            max_requests_per_minute: 0,
+            max_concurrent_per_ip: 0,
+            max_bytes_per_minute: 0,
+            rate_limiter: ArgRateLimiter::Counter,
+            rate_limit_burst: 0,
+            #[cfg(feature = "redis")]
+            rate_limit_redis_url: None,
+            rate_limit_by: RateLimitBy::Ip,
+            ban_threshold: 0,
+            ban_duration: 3600,
+            load_balancer: ArgLoadBalance::RoundRobin,
+            hash_header: None,
+            sticky_cookie: None,
+            disabled_proxy_headers: Vec::new(),
+            allow_cidrs: Vec::new(),
+            deny_cidrs: Vec::new(),
+            auth_htpasswd: None,
+            auth_bearer_token: None,
+            auth_realm: "balancebeam".to_string(),
+            jwt_hs256_secret: None,
+            jwt_rs256_public_key: None,
+            jwt_issuer: None,
+            jwt_audience: None,
+            jwt_route_claim: None,
+            jwt_rate_limit_claim: "sub".to_string(),
+            cors_allow_origins: Vec::new(),
+            cors_allow_methods: Vec::new(),
+            cors_allow_headers: Vec::new(),
+            cors_expose_headers: Vec::new(),
+            cors_allow_credentials: false,
+            cors_max_age: 0,
+            compression_min_size: 0,
+            compression_types: Vec::new(),
+        }
+    }
+
+    /// Returns a copy of this config with the settings from the given config file applied on top
+    pub fn with_file(&self, path: &str) -> Result<Config, Error> {
+        let contents = fs::read_to_string(path).map_err(Error::Unreadable)?;
+        let file: ConfigFile = toml::from_str(&contents).map_err(Error::Malformed)?;
+
+        let mut config = self.clone();
+        if let Some(upstreams) = file.upstreams {
+            config.upstreams = upstreams;
+        }
+        if file.upstreams_file.is_some() {
+            config.upstreams_file = file.upstreams_file;
+        }
+        if let Some(pools) = file.pools {
+            config.pools = pools;
+        }
+        if let Some(routes) = file.routes {
+            config.routes.clear();
+            config.route_policies.clear();
+            for (prefix, route) in routes {
+                let pool = match route {
+                    RouteEntry::Pool(pool) => pool,
+                    RouteEntry::WithPolicy(table) => {
+                        let policy = RoutePolicy {
+                            upstream_response_timeout: table.upstream_response_timeout,
+                            max_retries: table.max_retries,
+                            max_requests_per_minute: table.max_requests_per_minute,
+                        };
+                        config.route_policies.insert(prefix.clone(), policy);
+                        table.pool
+                    }
+                };
+                config.routes.insert(prefix, pool);
+            }
+        }
+        if let Some(routes) = file.client_routes {
+            config.client_routes.clear();
+            for (range, pool) in routes {
+                let cidr = range.parse().map_err(|_| Error::InvalidValue("client-routes", range))?;
+                config.client_routes.push((cidr, pool));
+            }
+        }
+        if file.blue_pool.is_some() {
+            config.blue_pool = file.blue_pool;
+        }
+        if file.green_pool.is_some() {
+            config.green_pool = file.green_pool;
+        }
+        if let Some(timeout) = file.blue_green_drain_timeout {
+            config.blue_green_drain_timeout = timeout;
+        }
+        if let Some(canaries) = file.canaries {
+            config.canaries = canaries;
+        }
+        if file.mirror.is_some() {
+            config.mirror = file.mirror;
+        }
+        if let Some(drain) = file.drain {
+            config.drain = drain;
+        }
+        if let Some(cache_paths) = file.cache_paths {
+            config.cache_paths = cache_paths;
+        }
+        if file.maintenance_page.is_some() {
+            config.maintenance_page = file.maintenance_page;
+        }
+        if let Some(cidrs) = file.maintenance_allow_cidrs {
+            config.maintenance_allow_cidrs = cidrs;
+        }
+        if let Some(upstream_override) = file.upstream_override {
+            config.upstream_override = upstream_override;
+        }
+        if let Some(cidrs) = file.upstream_override_cidrs {
+            config.upstream_override_cidrs = cidrs;
+        }
+        if let Some(cidrs) = file.trusted_proxies {
+            config.trusted_proxies = cidrs;
+        }
+        if let Some(force_https) = file.force_https {
+            config.force_https = force_https;
+        }
+        if file.canonical_host.is_some() {
+            config.canonical_host = file.canonical_host;
+        }
+        if let Some(redirects) = file.redirects {
+            config.redirects = redirects;
+        }
+        if let Some(rules) = file.request_headers {
+            config.request_headers = rules;
+        }
+        if let Some(rules) = file.response_headers {
+            config.response_headers = rules;
+        }
+        if let Some(pages) = file.error_pages {
+            config.error_pages.clear();
+            for (status, page) in pages {
+                let status = error_pages::parse_status(&status)
+                    .ok_or(Error::InvalidValue("error-pages", status))?;
+                config.error_pages.insert(status, page);
+            }
+        }
+        if let Some(interval) = file.active_health_check_interval {
+            config.active_health_check_interval = interval;
+        }
+        if let Some(path) = file.active_health_check_path {
+            config.active_health_check_path = path;
+        }
+        if let Some(method) = file.active_health_check_method {
+            config.active_health_check_method = method
+                .parse()
+                .map_err(|_| Error::InvalidValue("active-health-check-method", method))?;
+        }
+        if let Some(status) = file.active_health_check_status {
+            config.active_health_check_status = status;
+        }
+        if file.active_health_check_body.is_some() {
+            config.active_health_check_body = file.active_health_check_body;
+        }
+        if let Some(timeout) = file.active_health_check_timeout {
+            config.active_health_check_timeout = timeout;
+        }
+        if let Some(failures) = file.passive_health_check_failures {
+            config.passive_health_check_failures = failures;
+        }
+        if let Some(window) = file.passive_health_check_window {
+            config.passive_health_check_window = window;
+        }
+        if let Some(interval) = file.dns_refresh_interval {
+            config.dns_refresh_interval = interval;
+        }
+        if let Some(window) = file.slow_start_window {
+            config.slow_start_window = window;
+        }
+        if file.health_webhook.is_some() {
+            config.health_webhook = file.health_webhook;
+        }
+        if file.health_command.is_some() {
+            config.health_command = file.health_command;
+        }
+        if let Some(interval) = file.stats_log_interval {
+            config.stats_log_interval = interval;
+        }
+        if let Some(max_retries) = file.max_retries {
+            config.max_retries = max_retries;
+        }
+        if let Some(timeout) = file.client_read_timeout {
+            config.client_read_timeout = timeout;
+        }
+        if let Some(timeout) = file.client_idle_timeout {
+            config.client_idle_timeout = timeout;
+        }
+        if let Some(timeout) = file.client_write_timeout {
+            config.client_write_timeout = timeout;
+        }
+        if let Some(rate) = file.client_min_rate {
+            config.client_min_rate = rate;
+        }
+        if let Some(max_requests) = file.max_requests_per_connection {
+            config.max_requests_per_connection = max_requests;
+        }
+        if let Some(timeout) = file.upstream_connect_timeout {
+            config.upstream_connect_timeout = timeout;
+        }
+        if let Some(timeout) = file.shutdown_timeout {
+            config.shutdown_timeout = timeout;
+        }
+        if let Some(backoff) = file.upstream_connect_backoff {
+            config.upstream_connect_backoff = backoff;
+        }
+        if let Some(timeout) = file.upstream_response_timeout {
+            config.upstream_response_timeout = timeout;
+        }
+        if let Some(max_rps) = file.max_upstream_rps {
+            config.max_upstream_rps = max_rps;
+        }
+        if let Some(timeout) = file.upstream_queue_timeout {
+            config.upstream_queue_timeout = timeout;
+        }
+        if let Some(max) = file.max_upstream_concurrency {
+            config.max_upstream_concurrency = max;
+        }
+        if let Some(size) = file.request_queue_size {
+            config.request_queue_size = size;
+        }
+        if let Some(timeout) = file.request_queue_timeout {
+            config.request_queue_timeout = timeout;
+        }
+        if let Some(error_rate) = file.circuit_breaker_error_rate {
+            config.circuit_breaker_error_rate = error_rate;
+        }
+        if let Some(window) = file.circuit_breaker_window {
+            config.circuit_breaker_window = window;
+        }
+        if let Some(cooldown) = file.circuit_breaker_cooldown {
+            config.circuit_breaker_cooldown = cooldown;
+        }
+        if let Some(trial_requests) = file.circuit_breaker_trial_requests {
+            config.circuit_breaker_trial_requests = trial_requests;
+        }
+        if let Some(max_requests_per_minute) = file.max_requests_per_minute {
+            config.max_requests_per_minute = max_requests_per_minute;
+        }
+        if let Some(max) = file.max_concurrent_per_ip {
+            config.max_concurrent_per_ip = max;
+        }
+        if let Some(max) = file.max_bytes_per_minute {
+            config.max_bytes_per_minute = max;
+        }
+        if let Some(rate_limiter) = file.rate_limiter {
+            config.rate_limiter = rate_limiter;
+        }
+        if let Some(burst) = file.rate_limit_burst {
+            config.rate_limit_burst = burst;
+        }
+        #[cfg(feature = "redis")]
+        if file.rate_limit_redis_url.is_some() {
+            config.rate_limit_redis_url = file.rate_limit_redis_url;
+        }
+        if let Some(rate_limit_by) = file.rate_limit_by {
+            config.rate_limit_by = rate_limit_by;
+        }
+        if let Some(threshold) = file.ban_threshold {
+            config.ban_threshold = threshold;
+        }
+        if let Some(duration) = file.ban_duration {
+            config.ban_duration = duration;
+        }
+        if let Some(load_balancer) = file.load_balancer {
+            config.load_balancer = load_balancer;
+        }
+        if file.hash_header.is_some() {
+            config.hash_header = file.hash_header;
+        }
+        if file.sticky_cookie.is_some() {
+            config.sticky_cookie = file.sticky_cookie;
+        }
+        if let Some(headers) = file.disabled_proxy_headers {
+            config.disabled_proxy_headers = headers;
+        }
+        if let Some(cidrs) = file.allow_cidrs {
+            config.allow_cidrs = cidrs;
+        }
+        if let Some(cidrs) = file.deny_cidrs {
+            config.deny_cidrs = cidrs;
+        }
+        if file.auth_htpasswd.is_some() {
+            config.auth_htpasswd = file.auth_htpasswd;
+        }
+        if file.auth_bearer_token.is_some() {
+            config.auth_bearer_token = file.auth_bearer_token;
+        }
+        if let Some(realm) = file.auth_realm {
+            config.auth_realm = realm;
+        }
+        if file.jwt_hs256_secret.is_some() {
+            config.jwt_hs256_secret = file.jwt_hs256_secret;
+        }
+        if file.jwt_rs256_public_key.is_some() {
+            config.jwt_rs256_public_key = file.jwt_rs256_public_key;
+        }
+        if file.jwt_issuer.is_some() {
+            config.jwt_issuer = file.jwt_issuer;
+        }
+        if file.jwt_audience.is_some() {
+            config.jwt_audience = file.jwt_audience;
+        }
+        if file.jwt_route_claim.is_some() {
+            config.jwt_route_claim = file.jwt_route_claim;
+        }
+        if let Some(claim) = file.jwt_rate_limit_claim {
+            config.jwt_rate_limit_claim = claim;
+        }
+        if let Some(origins) = file.cors_allow_origins {
+            config.cors_allow_origins = origins;
+        }
+        if let Some(methods) = file.cors_allow_methods {
+            config.cors_allow_methods = methods;
+        }
+        if let Some(headers) = file.cors_allow_headers {
+            config.cors_allow_headers = headers;
+        }
+        if let Some(headers) = file.cors_expose_headers {
+            config.cors_expose_headers = headers;
+        }
+        if let Some(allow_credentials) = file.cors_allow_credentials {
+            config.cors_allow_credentials = allow_credentials;
+        }
+        if let Some(max_age) = file.cors_max_age {
+            config.cors_max_age = max_age;
+        }
+        if let Some(min_size) = file.compression_min_size {
+            config.compression_min_size = min_size;
+        }
+        if let Some(types) = file.compression_types {
+            config.compression_types = types;
+        }
+        config.with_upstreams_file()
+    }
+
+    pub fn validate(&self) -> Result<(), Error> {
+        if self.all_upstreams().is_empty() {
+            return Err(Error::NoUpstreams);
+        }
+        for (prefix, pool) in &self.routes {
+            if !self.pools.contains_key(pool) {
+                return Err(Error::UnknownPool(prefix.clone(), pool.clone()));
+            }
+        }
+        for (range, pool) in &self.client_routes {
+            if !self.pools.contains_key(pool) {
+                return Err(Error::UnknownPool(range.to_string(), pool.clone()));
+            }
+        }
+        // Blue/green pools come as a pair
+        match (&self.blue_pool, &self.green_pool) {
+            (Some(blue), Some(green)) => {
+                for (key, pool) in [("blue-pool", blue), ("green-pool", green)] {
+                    if !self.pools.contains_key(pool) {
+                        return Err(Error::InvalidValue(key, pool.clone()));
+                    }
+                }
+            }
+            (Some(blue), None) => return Err(Error::InvalidValue("blue-pool", blue.clone())),
+            (None, Some(green)) => return Err(Error::InvalidValue("green-pool", green.clone())),
+            (None, None) => {}
+        }
+        if let Some(path) = &self.maintenance_page {
+            if fs::metadata(path).is_err() {
+                return Err(Error::InvalidValue("maintenance-page", path.clone()));
+            }
+        }
+        if let Some(url) = &self.health_webhook {
+            if !health_hooks::is_valid_webhook(url) {
+                return Err(Error::InvalidValue("health-webhook", url.clone()));
+            }
+        }
+        if let Some(path) = &self.auth_htpasswd {
+            if fs::metadata(path).is_err() {
+                return Err(Error::InvalidValue("auth-htpasswd", path.clone()));
+            }
+        }
+        if self.auth_bearer_token.as_deref() == Some("") {
+            return Err(Error::InvalidValue("auth-bearer-token", String::new()));
+        }
+        // The realm is sent inside a quoted string
+        if self.auth_realm.contains(|c: char| c == '"' || c == '\\' || c.is_control()) {
+            return Err(Error::InvalidValue("auth-realm", self.auth_realm.clone()));
+        }
+        if self.jwt_hs256_secret.as_deref() == Some("") {
+            return Err(Error::InvalidValue("jwt-hs256-secret", String::new()));
+        }
+        if let Err(err) = JwtValidator::new(&self.jwt_settings()) {
+            let path = self.jwt_rs256_public_key.as_deref().unwrap_or_default();
+            let value = format!("{} ({})", path, err);
+            return Err(Error::InvalidValue("jwt-rs256-public-key", value));
+        }
+        for origin in &self.cors_allow_origins {
+            if !cors::is_valid_origin(origin) {
+                return Err(Error::InvalidValue("cors-allow-origins", origin.clone()));
+            }
+        }
+        for method in &self.cors_allow_methods {
+            if http::Method::from_bytes(method.as_bytes()).is_err() {
+                return Err(Error::InvalidValue("cors-allow-methods", method.clone()));
+            }
+        }
+        for (key, names) in [
+            ("cors-allow-headers", &self.cors_allow_headers),
+            ("cors-expose-headers", &self.cors_expose_headers),
+        ] {
+            for name in names {
+                if http::header::HeaderName::from_bytes(name.as_bytes()).is_err() {
+                    return Err(Error::InvalidValue(key, name.clone()));
+                }
+            }
+        }
+        for rule in &self.redirects {
+            if !rule.is_valid() {
+                let rule = format!("{} -> {}", rule.from, rule.to);
+                return Err(Error::InvalidValue("redirects", rule));
+            }
+        }
+        for (key, rules) in [
+            ("request-headers", &self.request_headers),
+            ("response-headers", &self.response_headers),
+        ] {
+            if let Some(rule) = rules.iter().find(|rule| !rule.is_valid()) {
+                return Err(Error::InvalidValue(key, format!("{:?} {}", rule.action, rule.name)));
+            }
+        }
+        for (status, page) in &self.error_pages {
+            let source_ok = match (&page.file, &page.body) {
+                (Some(path), None) => fs::metadata(path).is_ok(),
+                (None, Some(_)) => true,
+                _ => false,
+            };
+            let type_ok = page
+                .content_type
+                .iter()
+                .all(|content_type| http::HeaderValue::from_str(content_type).is_ok());
+            if !source_ok || !type_ok {
+                return Err(Error::InvalidValue("error-pages", status.to_string()));
+            }
+        }
+        #[cfg(feature = "redis")]
+        if self.rate_limiter == ArgRateLimiter::Redis {
+            match &self.rate_limit_redis_url {
+                Some(url) if redis::Client::open(url.as_str()).is_ok() => {}
+                Some(url) => return Err(Error::InvalidValue("rate-limit-redis-url", url.clone())),
+                None => return Err(Error::InvalidValue("rate-limit-redis-url", String::new())),
+            }
+        }
+        let canary_share: usize = self.canaries.values().sum();
+        if canary_share > 100 {
+            return Err(Error::CanaryShareTooLarge(canary_share));
+        }
+        Ok(())
+    }
+
+    /// Reads the upstreams file, if there is one, and checks the resulting config
+    pub fn with_upstreams_file(mut self) -> Result<Config, Error> {
+        if let Some(path) = &self.upstreams_file {
+            self.file_upstreams = upstreams_file::read(path)
+                .map_err(|err| Error::UpstreamsFileUnreadable(path.clone(), err))?;
+        }
+        self.validate()?;
+        Ok(self)
+    }
+
+    /// Returns the upstreams for requests that don't match any of the routes: the ones given in
+    /// the config, followed by the ones in the upstreams file and the ones found through service
+    /// discovery
+    pub fn default_upstreams(&self) -> Vec<String> {
+        let mut upstreams = self.upstreams.clone();
+        for upstream in self.file_upstreams.iter().chain(&self.discovered_upstreams) {
+            if !upstreams.contains(upstream) {
+                upstreams.push(upstream.clone());
+            }
+        }
+        upstreams
+    }
+
+    /// Returns every upstream in the config, whether it's a default upstream, a canary or in a
+    /// pool, with duplicates removed
+    pub fn all_upstreams(&self) -> Vec<String> {
+        let mut all_upstreams: Vec<String> = Vec::new();
+        let default_upstreams = self.default_upstreams();
+        let upstreams = default_upstreams.iter().chain(self.canaries.keys());
+        for addr in upstreams.chain(self.pools.values().flatten()) {
+            if !all_upstreams.contains(addr) {
+                all_upstreams.push(addr.clone());
+            }
+        }
+        all_upstreams
+    }
+
+    pub fn access_list(&self) -> AccessList {
+        AccessList::new(self.allow_cidrs.clone(), self.deny_cidrs.clone())
+    }
+
+    pub fn compression_settings(&self) -> compression::Settings {
+        compression::Settings {
+            min_size: self.compression_min_size,
+            types: self.compression_types.clone(),
+        }
+    }
+
+    pub fn authenticator(&self) -> Authenticator {
+        let htpasswd = self.auth_htpasswd.as_deref();
+        Authenticator::new(htpasswd, self.auth_bearer_token.clone(), &self.auth_realm)
+    }
+
+    pub fn jwt_settings(&self) -> jwt::Settings {
+        jwt::Settings {
+            hs256_secret: self.jwt_hs256_secret.clone(),
+            rs256_public_key: self.jwt_rs256_public_key.clone(),
+            issuer: self.jwt_issuer.clone(),
+            audience: self.jwt_audience.clone(),
+            route_claim: self.jwt_route_claim.clone(),
+            rate_limit_claim: self.jwt_rate_limit_claim.clone(),
+            realm: self.auth_realm.clone(),
+        }
+    }
+
+    pub fn cors_settings(&self) -> cors::Settings {
+        cors::Settings {
+            allow_origins: self.cors_allow_origins.clone(),
+            allow_methods: self.cors_allow_methods.clone(),
+            allow_headers: self.cors_allow_headers.clone(),
+            expose_headers: self.cors_expose_headers.clone(),
+            allow_credentials: self.cors_allow_credentials,
+            max_age: self.cors_max_age,
+        }
+    }
+
+    pub fn header_rules(&self) -> HeaderRules {
+        HeaderRules::new(&self.request_headers, &self.response_headers)
+    }
+
+    pub fn redirects(&self) -> Redirects {
+        Redirects::new(self.force_https, self.canonical_host.clone(), &self.redirects)
+    }
+
+    pub fn rate_limiter_settings(&self) -> rate_limiter::Settings {
+        rate_limiter::Settings {
+            strategy: self.rate_limiter,
+            burst: self.rate_limit_burst,
+            #[cfg(feature = "redis")]
+            redis_url: self.rate_limit_redis_url.clone(),
+        }
+    }
+
+    pub fn ban_settings(&self) -> ban_list::Settings {
+        ban_list::Settings {
+            threshold: self.ban_threshold,
+            duration: Duration::from_secs(self.ban_duration as u64),
+        }
+    }
+
+    pub fn circuit_breaker_settings(&self) -> circuit_breaker::Settings {
+        circuit_breaker::Settings {
+            error_rate: self.circuit_breaker_error_rate,
+            window: self.circuit_breaker_window.max(1),
+            cooldown: Duration::from_secs(self.circuit_breaker_cooldown as u64),
+            trial_requests: self.circuit_breaker_trial_requests,
+        }
+    }
+}
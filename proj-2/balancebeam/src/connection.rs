@@ -0,0 +1,1298 @@
+use bytes::{Bytes, BytesMut};
+use crate::access_log::Timing;
+use crate::active_connections::ActiveConnection;
+use crate::body::{BodyReader, CopyError, Framing};
+use crate::connection_limit::ConnectionPermit;
+use crate::jwt::Claims;
+use crate::listener::Listener;
+use crate::load_balance::{sticky, RequestContext};
+use crate::middleware::{Action, ForcedUpstream};
+use crate::proxy_headers::Frontend;
+use crate::response_cache::Lookup;
+use crate::state::{AdminState, ProxyState};
+use crate::tcp_proxy::ProxyMode;
+use crate::upstream::UpstreamStream;
+use crate::{body, chunked, compression, http2, middleware, proxy_headers, request, request_queue};
+use crate::{response, response_cache, slow_client, tcp_proxy, tls, upstream, with_timeout};
+use std::net::{IpAddr, SocketAddr};
+use std::sync::atomic::Ordering;
+use std::{fmt, future::Future, io::ErrorKind, sync::Arc};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::time::{sleep, Duration, Instant};
+use tracing::Instrument;
+
+/// Accepts client connections on one of the sockets we listen on, and serves each of them in a
+/// task of its own, until accepting fails. With `tls_auto_detect`, clients that don't start a TLS
+/// handshake are served in plaintext rather than failing it.
+pub(crate) async fn accept_connections(
+    listener: Listener,
+    frontend: Frontend,
+    tls_acceptor: Option<tokio_rustls::TlsAcceptor>,
+    tls_auto_detect: bool,
+    shared_state: Arc<ProxyState>,
+) {
+    loop {
+        let (stream, client_addr) = match listener.accept().await {
+            Ok(accepted) => accepted,
+            Err(err) => {
+                log::error!("Stopped accepting connections: {}", err);
+                return;
+            }
+        };
+        let mut rejection = None;
+        if !shared_state
+            .access_list
+            .read()
+            .await
+            .permits(client_addr.ip())
+        {
+            log::info!(
+                "Turning away {}, which isn't allowed to connect",
+                client_addr
+            );
+            rejection = Some(http::StatusCode::FORBIDDEN);
+        } else if shared_state.ban_list.is_banned(client_addr.ip()) {
+            log::info!("Turning away {}, which is banned", client_addr);
+            rejection = Some(http::StatusCode::FORBIDDEN);
+        }
+        // The permit is held until the connection is closed
+        let mut permit: Option<ConnectionPermit> = None;
+        if rejection.is_none() {
+            permit = shared_state.connection_limits.try_acquire(client_addr.ip());
+            if permit.is_none() {
+                log::info!("Too many connections, turning away {}", client_addr);
+                rejection = Some(http::StatusCode::SERVICE_UNAVAILABLE);
+            }
+        }
+        let shared_state_ref = shared_state.clone();
+        let tls_acceptor = tls_acceptor.clone();
+        tokio::spawn(async move {
+            let _connection = shared_state_ref
+                .client_connections
+                .track(&client_addr.to_string());
+            // Checking the rate limit may mean waiting on the limiter (e.g. on Redis),
+            // so it happens here rather than holding up accepting other connections
+            let mut permit = permit;
+            if rejection.is_none() && !shared_state_ref.register_connection(client_addr.ip()).await
+            {
+                permit = None;
+                rejection = Some(http::StatusCode::TOO_MANY_REQUESTS);
+            }
+            let _permit = permit;
+            let state = shared_state_ref;
+            match tls_acceptor {
+                Some(acceptor) if tls_auto_detect => {
+                    serve_any_client(acceptor, stream, client_addr, frontend, rejection, state)
+                        .await
+                }
+                Some(acceptor) => {
+                    serve_tls_client(acceptor, stream, client_addr, frontend, rejection, state)
+                        .await
+                }
+                None => {
+                    serve_client(stream, client_addr, None, frontend, false, rejection, state).await
+                }
+            }
+        });
+    }
+}
+
+/// Serves a client over TLS if it starts a TLS handshake, and in plaintext otherwise
+async fn serve_any_client<S: AsyncRead + AsyncWrite + Unpin>(
+    acceptor: tokio_rustls::TlsAcceptor,
+    mut stream: S,
+    client_addr: SocketAddr,
+    frontend: Frontend,
+    rejection: Option<http::StatusCode>,
+    state: Arc<ProxyState>,
+) {
+    let timeout = state.client_read_timeout.load(Ordering::SeqCst);
+    match tls::sniff(&mut stream, timeout).await {
+        Ok((true, start)) => {
+            let stream = http2::Rewound::new(start, stream);
+            serve_tls_client(acceptor, stream, client_addr, frontend, rejection, state).await
+        }
+        Ok((false, start)) => {
+            let stream = http2::Rewound::new(start, stream);
+            let frontend = Frontend {
+                https: false,
+                ..frontend
+            };
+            serve_client(stream, client_addr, None, frontend, false, rejection, state).await
+        }
+        Err(err) => log::info!("Error reading from {}: {}", client_addr, err),
+    }
+}
+
+/// Does the TLS handshake with a client, and serves it if that succeeds
+async fn serve_tls_client<S: AsyncRead + AsyncWrite + Unpin>(
+    acceptor: tokio_rustls::TlsAcceptor,
+    stream: S,
+    client_addr: SocketAddr,
+    frontend: Frontend,
+    rejection: Option<http::StatusCode>,
+    state: Arc<ProxyState>,
+) {
+    match acceptor.accept(stream).await {
+        Ok(stream) => {
+            let cert_subject = tls::client_subject(stream.get_ref().1);
+            let http2 = tls::negotiated_http2(stream.get_ref().1);
+            serve_client(
+                stream,
+                client_addr,
+                cert_subject,
+                frontend,
+                http2,
+                rejection,
+                state,
+            )
+            .await
+        }
+        Err(err) => log::info!("TLS handshake with {} failed: {}", client_addr, err),
+    }
+}
+
+/// An open connection to the upstream that a client's requests are being forwarded to
+pub(crate) struct UpstreamConnection {
+    pub(crate) stream: UpstreamStream,
+    /// Index of the upstream in `upstream_addresses` at the time it was picked
+    idx: usize,
+    /// Address of the upstream, as given in the config
+    pub(crate) address: String,
+    /// IP address and port we're actually connected to
+    pub(crate) ip: String,
+    /// What the last response on this connection was read into, kept so that the next one can
+    /// reuse its memory
+    buffer: BytesMut,
+    /// Counts this connection towards the upstream's open connections while it's open
+    _active: ActiveConnection,
+}
+
+/// Why no connection to an upstream could be made for a request
+#[derive(Debug)]
+pub(crate) struct ConnectError {
+    /// Upstreams we tried to connect to, in order
+    tried: Vec<String>,
+    /// Whether any of the attempts timed out
+    pub(crate) timed_out: bool,
+}
+
+impl fmt::Display for ConnectError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.tried.is_empty() {
+            write!(f, "no upstream is available")
+        } else {
+            write!(f, "could not connect to any of {}", self.tried.join(", "))
+        }
+    }
+}
+
+/// Longest we wait between connection attempts when backing off
+const MAX_CONNECT_BACKOFF: Duration = Duration::from_secs(5);
+
+/// Opens a connection to an upstream picked by the load balancer
+pub(crate) async fn connect_to_upstream(
+    state: &Arc<ProxyState>,
+    context: &RequestContext<'_>,
+) -> Result<UpstreamConnection, ConnectError> {
+    let connect = |addr: String| {
+        let connector = state.upstream_tls.clone();
+        async move {
+            let stream = upstream::connect(&addr, &connector).await?;
+            let ip = stream.peer()?;
+            Ok((stream, ip))
+        }
+    };
+    let (idx, address, (stream, ip)) = connect_with(state, context, connect).await?;
+    let _active = state.upstream_connections.track(&address);
+    Ok(UpstreamConnection {
+        stream,
+        idx,
+        address,
+        ip,
+        buffer: BytesMut::new(),
+        _active,
+    })
+}
+
+/// Connects to an upstream picked by the load balancer using `connect`, returning the upstream's
+/// index and address along with the connection. If connecting fails, the upstream is marked down
+/// and another one is tried, up to as many attempts as there were live upstreams in the request's
+/// pool to begin with, so that upstreams flapping up and down can't keep us trying forever.
+pub(crate) async fn connect_with<T, F, Fut>(
+    state: &Arc<ProxyState>,
+    context: &RequestContext<'_>,
+    connect: F,
+) -> Result<(usize, String, T), ConnectError>
+where
+    F: Fn(String) -> Fut,
+    Fut: Future<Output = std::io::Result<T>>,
+{
+    let connect_timeout = state.upstream_connect_timeout.load(Ordering::SeqCst);
+    let backoff = state.upstream_connect_backoff.load(Ordering::SeqCst) as u64;
+    let max_attempts = {
+        let upstream_status = state.upstream_status.load();
+        context
+            .pool
+            .iter()
+            .filter(|&&idx| upstream_status.is_alive(idx) && !context.excluded.contains(&idx))
+            .count()
+    };
+    let mut error = ConnectError {
+        tried: Vec::new(),
+        timed_out: false,
+    };
+    // Upstreams that failed this time around aren't picked again
+    let mut excluded = context.excluded.to_vec();
+    for attempt in 0..max_attempts {
+        if attempt > 0 && backoff > 0 {
+            // Wait twice as long before each attempt as before the last one
+            let delay = Duration::from_millis(backoff << (attempt - 1).min(16));
+            sleep(delay.min(MAX_CONNECT_BACKOFF)).await;
+        }
+        let attempt_context = RequestContext {
+            excluded: &excluded,
+            ..*context
+        };
+        let selected = {
+            let load_balancer = state.load_balancer.read().await;
+            let select = load_balancer.select_backend(state, &attempt_context);
+            select
+                .instrument(tracing::info_span!("select_upstream"))
+                .await
+        };
+        let idx = match selected {
+            Some(idx) => idx,
+            None => break,
+        };
+        let addr = match state.upstream_addresses.read().await.get(idx) {
+            Some(addr) => addr.clone(),
+            // The upstream list changed under us; pick again
+            None => {
+                excluded.push(idx);
+                continue;
+            }
+        };
+        let result = with_timeout(connect_timeout, connect(addr.clone()))
+            .await
+            .unwrap_or_else(|| {
+                error.timed_out = true;
+                Err(std::io::Error::new(
+                    ErrorKind::TimedOut,
+                    "connection timed out",
+                ))
+            });
+        match result {
+            Ok(connection) => return Ok((idx, addr, connection)),
+            Err(err) => {
+                log::error!("Failed to connect to upstream {}: {}", addr, err);
+                if state.upstream_status.load().set_down(idx) {
+                    state.report_health_change(&addr, false).await;
+                }
+                excluded.push(idx);
+                error.tried.push(addr);
+            }
+        }
+    }
+    Err(error)
+}
+
+/// Why a request couldn't be forwarded to an upstream
+enum ForwardError {
+    /// Talking to the upstream failed, and the client should be sent the given status. The
+    /// connection to the upstream shouldn't be used again.
+    Upstream(http::StatusCode),
+    /// The client didn't send a complete, valid request body
+    Client(request::Error),
+    /// Every upstream that could take the request was too busy for it to wait for
+    Overloaded,
+}
+
+/// Sends a request to the upstream, streaming its body from the client as it arrives, and reads
+/// back the head of the upstream's response. The response body is left on the upstream connection
+/// to be read with the returned BodyReader.
+async fn forward_request<S: AsyncRead + Unpin>(
+    state: &ProxyState,
+    upstream: &mut UpstreamConnection,
+    request: &http::Request<Vec<u8>>,
+    request_body: &mut BodyReader,
+    client_conn: &mut S,
+    response_timeout: usize,
+) -> Result<(http::Response<Vec<u8>>, BodyReader), ForwardError> {
+    let sent = async {
+        // Don't bother the upstream with a request whose body is garbage from the start. This
+        // only looks at what arrived with the head: waiting for more would hold up clients that
+        // send `Expect: 100-continue`, and the rest of the body is checked as it's copied.
+        request_body.check_buffered().map_err(CopyError::Read)?;
+        request::write_head(request, &mut upstream.stream)
+            .await
+            .map_err(CopyError::Write)?;
+        body::copy(request_body, client_conn, &mut upstream.stream).await
+    };
+    let result = match sent.instrument(tracing::info_span!("send_request")).await {
+        Ok(_) => {
+            log::debug!("Forwarded request to server");
+            let sent_at = Instant::now();
+            let buffer = std::mem::take(&mut upstream.buffer);
+            let response = response::read_head(&mut upstream.stream, request.method(), buffer);
+            let response = response.instrument(tracing::info_span!("wait_for_response"));
+            match with_timeout(response_timeout, response).await {
+                Some(Ok((response, mut response_body))) => {
+                    state
+                        .upstream_latencies
+                        .record(&upstream.address, sent_at.elapsed());
+                    response_body.set_read_timeout(response_timeout);
+                    Ok((response, response_body))
+                }
+                Some(Err(error)) => {
+                    log::error!("Error reading response from server: {:?}", error);
+                    Err(http::StatusCode::BAD_GATEWAY)
+                }
+                None => {
+                    log::error!(
+                        "Timed out waiting for a response from upstream {}",
+                        upstream.ip
+                    );
+                    Err(http::StatusCode::GATEWAY_TIMEOUT)
+                }
+            }
+        }
+        Err(CopyError::Read(error)) => {
+            log::info!("Error reading request body from client: {:?}", error);
+            return Err(ForwardError::Client(error.into()));
+        }
+        Err(CopyError::Write(error)) => {
+            log::error!(
+                "Failed to send request to upstream {}: {}",
+                upstream.ip,
+                error
+            );
+            Err(http::StatusCode::BAD_GATEWAY)
+        }
+    };
+    match &result {
+        Ok((response, _)) => {
+            state.record_upstream_success(&upstream.address).await;
+            let success = !response.status().is_server_error();
+            state
+                .record_circuit_outcome(&upstream.address, success)
+                .await;
+        }
+        Err(_) => {
+            state.record_upstream_failure(&upstream.address).await;
+            state.record_circuit_outcome(&upstream.address, false).await;
+        }
+    }
+    result.map_err(ForwardError::Upstream)
+}
+
+/// Serves a client connection (plain or TLS), or answers it with an HTTP error if it was rejected.
+/// In TCP mode, rejected connections are just closed, since the client may not speak HTTP.
+/// `http2` is true if the client agreed through ALPN to speak HTTP/2.
+async fn serve_client<S: AsyncRead + AsyncWrite + Unpin>(
+    mut client_conn: S,
+    client_addr: SocketAddr,
+    cert_subject: Option<String>,
+    frontend: Frontend,
+    http2: bool,
+    rejection: Option<http::StatusCode>,
+    state: Arc<ProxyState>,
+) {
+    match (rejection, state.mode) {
+        (Some(_), ProxyMode::Tcp) => {}
+        (Some(status), ProxyMode::Http) => {
+            reject_connection(client_conn, client_addr, status, &state).await
+        }
+        (None, ProxyMode::Tcp) => tcp_proxy::serve(client_conn, client_addr, state).await,
+        (None, ProxyMode::Http) if http2 => {
+            http2::serve(client_conn, client_addr, cert_subject, frontend, state).await
+        }
+        (None, ProxyMode::Http) if state.http2.is_some() => {
+            // Without TLS, HTTP/2 clients can only be told apart by how they start talking
+            let timeout = state.client_read_timeout.load(Ordering::SeqCst);
+            match http2::sniff(&mut client_conn, timeout).await {
+                Ok((true, start)) => {
+                    let client_conn = http2::Rewound::new(start, client_conn);
+                    http2::serve(client_conn, client_addr, cert_subject, frontend, state).await
+                }
+                Ok((false, start)) => {
+                    let client_conn = http2::Rewound::new(start, client_conn);
+                    handle_connection(client_conn, client_addr, cert_subject, frontend, state).await
+                }
+                Err(err) => log::info!("Error reading request from {}: {}", client_addr.ip(), err),
+            }
+        }
+        (None, ProxyMode::Http) => {
+            handle_connection(client_conn, client_addr, cert_subject, frontend, state).await
+        }
+    }
+}
+
+/// Most of a rejected client's request that is read before answering it
+const REJECTED_READ_LIMIT: u64 = 8192;
+/// Longest time to wait for a rejected client's request when client reads have no timeout
+const REJECTED_READ_TIMEOUT: usize = 10;
+
+/// Answers a connection we don't want to serve with an HTTP error. The request's head is read
+/// first, so that the error page can suit it and closing the socket doesn't reset the connection
+/// before the client sees the response, but only briefly and only so much of it: a client we
+/// don't want mustn't be able to hold the connection open. Its body isn't read at all.
+async fn reject_connection<S: AsyncRead + AsyncWrite + Unpin>(
+    mut client_conn: S,
+    client_addr: SocketAddr,
+    status: http::StatusCode,
+    state: &ProxyState,
+) {
+    let timeout = match state.client_read_timeout.load(Ordering::SeqCst) {
+        0 => REJECTED_READ_TIMEOUT,
+        timeout => timeout,
+    };
+    let mut limited = (&mut client_conn).take(REJECTED_READ_LIMIT);
+    let head = request::read_head(&mut limited, BytesMut::new());
+    let request = match with_timeout(timeout, head).await {
+        Some(Ok((request, _))) => Some(request),
+        _ => None,
+    };
+    let mut response = state.error_response(status, request.as_ref(), None).await;
+    response::set_keep_alive(&mut response, false);
+    send_response(&mut client_conn, &client_addr.ip().to_string(), &response).await;
+}
+
+async fn send_response<S: AsyncWrite + Unpin>(
+    client_conn: &mut S,
+    client_ip: &str,
+    response: &http::Response<Vec<u8>>,
+) {
+    log::info!(
+        "{} <- {}",
+        client_ip,
+        response::format_response_line(response)
+    );
+    if let Err(error) = response::write_to_stream(response, client_conn).await {
+        log::warn!("Failed to send response to client: {}", error);
+    }
+}
+
+/// A request being answered, with what's needed to trace and log it
+struct Exchange<'a> {
+    request: &'a http::Request<Vec<u8>>,
+    /// Address the connection comes from
+    peer_ip: &'a str,
+    /// Address the request is logged under, which behind a trusted proxy is the proxy's client
+    client_ip: IpAddr,
+    span: &'a tracing::Span,
+    timing: &'a Timing,
+}
+
+/// Answers a request with a response that didn't come from an upstream just now, once the header
+/// rules have had their say, and logs it
+async fn send_local_response<S: AsyncWrite + Unpin>(
+    state: &ProxyState,
+    client_conn: &mut slow_client::Guarded<S>,
+    exchange: &Exchange<'_>,
+    upstream: Option<&UpstreamConnection>,
+    mut response: http::Response<Vec<u8>>,
+    keep_alive: bool,
+) {
+    exchange
+        .span
+        .record("http.status_code", response.status().as_u16());
+    state
+        .header_rules
+        .read()
+        .await
+        .apply_to_response(&mut response);
+    response::set_keep_alive(&mut response, keep_alive);
+    send_response(client_conn, exchange.peer_ip, &response).await;
+    let bytes = client_conn.sizes(response.body().len() as u64);
+    state.log_access(
+        exchange.client_ip,
+        exchange.request,
+        upstream,
+        response.status(),
+        bytes,
+        exchange.timing,
+    );
+}
+
+/// Answers a request with a response from the cache, passed through the response middlewares and
+/// compressed to suit the client
+async fn send_cached_response<S: AsyncWrite + Unpin>(
+    client_conn: &mut slow_client::Guarded<S>,
+    context: &middleware::Context<'_>,
+    exchange: &Exchange<'_>,
+    mut response: http::Response<Vec<u8>>,
+    keep_alive: bool,
+) {
+    let state = context.state;
+    middleware::run_response(&state.middlewares, context, exchange.request, &mut response).await;
+    let compression = state.compression.read().await.clone();
+    compression::transform_in_memory(&compression, exchange.request, &mut response).await;
+    send_local_response(state, client_conn, exchange, None, response, keep_alive).await;
+}
+
+/// Answers a request with an error page, after which the connection is closed
+async fn respond_with_error<S: AsyncWrite + Unpin>(
+    state: &ProxyState,
+    client_conn: &mut slow_client::Guarded<S>,
+    exchange: &Exchange<'_>,
+    upstream: Option<&UpstreamConnection>,
+    status: http::StatusCode,
+) {
+    let address = upstream.map(|upstream| upstream.address.as_str());
+    let response = state
+        .error_response(status, Some(exchange.request), address)
+        .await;
+    send_local_response(state, client_conn, exchange, upstream, response, false).await;
+}
+
+/// What came of forwarding a request: the head of the upstream's response and a reader for its
+/// body, or why there isn't one
+type Forwarded = Result<(http::Response<Vec<u8>>, BodyReader), ForwardError>;
+
+/// Whether forwarding a request failed on the upstream's account
+fn upstream_failed(forwarded: &Forwarded) -> bool {
+    match forwarded {
+        Ok((response, _)) => response.status().is_server_error(),
+        Err(ForwardError::Upstream(_)) => true,
+        Err(ForwardError::Client(_)) | Err(ForwardError::Overloaded) => false,
+    }
+}
+
+/// Where and how a request may be forwarded, as decided by its route
+struct Forwarding {
+    /// Upstreams that may serve the request
+    pool: Vec<usize>,
+    /// Upstreams of the pool that was just switched away from, which connections already pinned
+    /// to one of them may keep using for a while
+    draining_pool: Vec<usize>,
+    /// How many other upstreams the request may be retried on if one fails
+    max_retries: usize,
+    response_timeout: usize,
+}
+
+/// Works out where a request may be forwarded. The request's route decides which upstreams may
+/// serve it, and may override some settings for it. An upstream picked with the
+/// X-Balancebeam-Upstream header takes precedence over everything, followed by a pool named by the
+/// request's token, then the pool for the client's address range, and then the route's.
+async fn route_request(
+    state: &ProxyState,
+    request: &http::Request<Vec<u8>>,
+    framing: Framing,
+    client_addr: IpAddr,
+) -> Forwarding {
+    let live = state.deployment.live();
+    let forced = request
+        .extensions()
+        .get::<ForcedUpstream>()
+        .map(|forced| vec![forced.0]);
+    let (pool, policy, draining_pool) = {
+        let routes = state.routes.read().await;
+        let path = request.uri().path();
+        let claimed_pool = request
+            .extensions()
+            .get::<Claims>()
+            .and_then(|claims| claims.route.as_deref())
+            .and_then(|name| routes.pool_named(name));
+        let pool = forced
+            .as_deref()
+            .or(claimed_pool)
+            .or_else(|| routes.pool_for_client(client_addr))
+            .unwrap_or_else(|| routes.pool_for(path, live));
+        // Right after a switch between the blue and green pools, connections pinned to the old
+        // one may keep using it for requests that would otherwise go to the new one
+        let drain_timeout = state.blue_green_drain_timeout.load(Ordering::SeqCst);
+        let drain_timeout = Duration::from_secs(drain_timeout as u64);
+        let draining_pool = match state.deployment.draining(drain_timeout) {
+            Some(old) if routes.color_pool(live) == Some(pool) => routes.color_pool(old),
+            _ => None,
+        };
+        (
+            pool.to_vec(),
+            routes.policy_for(path),
+            draining_pool.unwrap_or(&[]).to_vec(),
+        )
+    };
+    let response_timeout = policy
+        .upstream_response_timeout
+        .unwrap_or_else(|| state.upstream_response_timeout.load(Ordering::SeqCst));
+    // Requests that fail or get a 5xx may be retried on other upstreams. Only idempotent requests
+    // are, since the upstream that failed may already have acted on the request. Neither are
+    // requests with a body, which is passed on as it arrives and can't be resent.
+    let max_retries = if request.method().is_idempotent() && framing == Framing::Empty {
+        policy
+            .max_retries
+            .unwrap_or_else(|| state.max_retries.load(Ordering::SeqCst))
+    } else {
+        0
+    };
+    Forwarding {
+        pool: state.split_canary_traffic(pool).await,
+        draining_pool,
+        max_retries,
+        response_timeout,
+    }
+}
+
+/// Forwards a request to an upstream, connecting to one picked by the load balancer if the client
+/// isn't connected to one it can use. Requests that fail or get a 5xx are retried on other
+/// upstreams, as many times as `forwarding` allows. Returns what came of the last attempt, along
+/// with what counts the request as in flight to the upstream that answered it.
+async fn forward_with_retries<S: AsyncRead + AsyncWrite + Unpin>(
+    state: &Arc<ProxyState>,
+    client_conn: &mut slow_client::Guarded<S>,
+    exchange: &Exchange<'_>,
+    request_body: &mut BodyReader,
+    upstream: &mut Option<UpstreamConnection>,
+    forwarding: &Forwarding,
+) -> (Forwarded, Option<ActiveConnection>) {
+    let request = exchange.request;
+    let client_ip = exchange.peer_ip;
+    let pool = &forwarding.pool;
+    let mut failed_upstreams = Vec::new();
+    let mut in_flight = None;
+    loop {
+        // The upstream may already be serving as many requests as it may at once, in which case
+        // the request goes elsewhere
+        if let Some(connection) = upstream.as_ref() {
+            in_flight = request_queue::claim(state, &connection.address);
+            if in_flight.is_none() {
+                *upstream = None;
+            }
+        }
+        // Open a connection to a destination server chosen by the load balancer. If every
+        // upstream that could take the request is too busy, it waits for one to have room.
+        if upstream.is_none() {
+            let queued = request_queue::wait_for_room(state, pool, &failed_upstreams);
+            if let Err(rejected) = queued.await {
+                log::warn!(
+                    "No upstream has room for a request from {}: {:?}",
+                    client_ip,
+                    rejected
+                );
+                return (Err(ForwardError::Overloaded), None);
+            }
+            let saturated = request_queue::saturated(state, pool).await;
+            let excluded: Vec<usize> =
+                failed_upstreams.iter().chain(&saturated).copied().collect();
+            let context = RequestContext {
+                client_ip: exchange.client_ip,
+                request,
+                pool,
+                excluded: &excluded,
+            };
+            let connect = connect_to_upstream(state, &context);
+            match connect
+                .instrument(tracing::info_span!(parent: exchange.span, "connect"))
+                .await
+            {
+                Ok(connection) => {
+                    exchange
+                        .span
+                        .record("upstream", connection.address.as_str());
+                    *upstream = Some(connection);
+                    // Claim a slot on the upstream before using it
+                    continue;
+                }
+                Err(error) => {
+                    log::error!(
+                        "Failed to connect to an upstream for {}: {}",
+                        client_ip,
+                        error
+                    );
+                    let status = if error.timed_out {
+                        http::StatusCode::GATEWAY_TIMEOUT
+                    } else {
+                        http::StatusCode::BAD_GATEWAY
+                    };
+                    return (Err(ForwardError::Upstream(status)), in_flight);
+                }
+            }
+        }
+        let upstream_conn = upstream.as_mut().unwrap();
+
+        log::info!(
+            "{} -> {}: {}",
+            client_ip,
+            upstream_conn.ip,
+            request::format_request_line(request)
+        );
+        let attempt_started = Instant::now();
+        let forwarded = if state.wait_for_upstream_slot(&upstream_conn.address).await {
+            let address = &upstream_conn.address;
+            let forward_span = tracing::info_span!(parent: exchange.span, "forward", upstream = %address);
+            forward_request(
+                state,
+                upstream_conn,
+                request,
+                request_body,
+                client_conn,
+                forwarding.response_timeout,
+            )
+            .instrument(forward_span)
+            .await
+        } else {
+            log::warn!(
+                "Upstream {} is over its request rate limit",
+                upstream_conn.ip
+            );
+            Err(ForwardError::Upstream(
+                http::StatusCode::SERVICE_UNAVAILABLE,
+            ))
+        };
+        let failed = upstream_failed(&forwarded);
+        // Requests the client botched say nothing about the upstream
+        if !matches!(forwarded, Err(ForwardError::Client(_))) {
+            let latency = attempt_started.elapsed();
+            state
+                .upstream_stats
+                .record(&upstream_conn.address, latency, failed);
+        }
+        if !failed || failed_upstreams.len() >= forwarding.max_retries {
+            return (forwarded, in_flight);
+        }
+        log::warn!(
+            "Request to upstream {} failed, retrying on another upstream",
+            upstream_conn.ip
+        );
+        failed_upstreams.push(upstream_conn.idx);
+        *upstream = None;
+        in_flight = None;
+    }
+}
+
+/// Relays a connection whose upstream agreed to switch protocols (e.g. to WebSocket) to the client.
+/// The connection no longer carries HTTP from here on, so bytes are just shuttled in both
+/// directions until one side hangs up, and then the whole exchange is logged as one long request.
+async fn relay_upgraded<S: AsyncRead + AsyncWrite + Unpin>(
+    state: &ProxyState,
+    client_conn: &mut slow_client::Guarded<S>,
+    exchange: &Exchange<'_>,
+    connection: &mut UpstreamConnection,
+    response: &http::Response<Vec<u8>>,
+    request_body: BodyReader,
+    response_body: BodyReader,
+) {
+    log::info!(
+        "{} <- {}",
+        exchange.peer_ip,
+        response::format_response_line(response)
+    );
+    // Bytes either side sent right after the headers already belong to the new protocol. Either
+    // side may go quiet for as long as it likes from here on.
+    client_conn.lift_limits();
+    let upstream_conn = &mut connection.stream;
+    let from_client = request_body.into_leftover();
+    let from_upstream = response_body.into_leftover();
+    let relayed = async {
+        response::write_head(response, &mut *client_conn).await?;
+        client_conn.write_all(&from_upstream).await?;
+        client_conn.flush().await?;
+        upstream_conn.write_all(&from_client).await?;
+        upstream_conn.flush().await
+    };
+    if let Err(error) = relayed.await {
+        log::warn!("Failed to switch protocols: {}", error);
+        return;
+    }
+    log::debug!("Upgraded connection, relaying raw bytes");
+    let to_client = match tokio::io::copy_bidirectional(client_conn, upstream_conn).await {
+        Ok((to_upstream, to_client)) => {
+            log::debug!(
+                "Upgraded connection closed after relaying {} bytes up and {} bytes down",
+                to_upstream,
+                to_client
+            );
+            to_client
+        }
+        Err(error) => {
+            log::info!("Error relaying upgraded connection: {}", error);
+            0
+        }
+    };
+    let bytes = client_conn.sizes(to_client);
+    state.log_access(
+        exchange.client_ip,
+        exchange.request,
+        Some(connection),
+        response.status(),
+        bytes,
+        exchange.timing,
+    );
+}
+
+async fn handle_connection<S: AsyncRead + AsyncWrite + Unpin>(
+    client_conn: S,
+    client_addr: SocketAddr,
+    cert_subject: Option<String>,
+    frontend: Frontend,
+    state: Arc<ProxyState>,
+) {
+    let peer_addr = client_addr.ip();
+    let client_ip = peer_addr.to_string();
+    log::info!("Connection received from {}", client_ip);
+    // Clients that are too slow to send requests or read responses are cut off, so they can't
+    // tie up the connection (or the upstream connection behind it) indefinitely
+    let mut client_conn = slow_client::Guarded::new(client_conn, state.slow_client_limits());
+
+    // The upstream connection is opened once the first request arrives, so that the load
+    // balancer can take the request into account when picking a destination server
+    let mut upstream: Option<UpstreamConnection> = None;
+    // Bytes the client sent past the end of the last request, which are the start of the next one
+    let mut leftover = BytesMut::new();
+    let mut requests_served = 0;
+
+    // The client may now send us one or more requests. Keep trying to read requests until the
+    // client hangs up or we get an error.
+    loop {
+        client_conn.reset(state.slow_client_limits());
+        // Read a request's headers from the client. Its body is streamed to the upstream later.
+        // Once a request has been served, the connection is just being kept open for the next
+        // one, and is closed if the client leaves it idle for too long
+        let client_read_timeout = state.client_read_timeout.load(Ordering::SeqCst);
+        let head_timeout = if requests_served == 0 {
+            client_read_timeout
+        } else {
+            state.client_idle_timeout.load(Ordering::SeqCst)
+        };
+        let request = request::read_head(&mut client_conn, std::mem::take(&mut leftover));
+        let request = if requests_served == 0 {
+            with_timeout(head_timeout, request).await
+        } else {
+            // An idle connection is closed right away if we start shutting down
+            let shutdown = state.shutdown.notified();
+            if state.shutting_down.load(Ordering::SeqCst) {
+                return;
+            }
+            tokio::select! {
+                request = with_timeout(head_timeout, request) => request,
+                _ = shutdown => {
+                    log::debug!("Closing idle connection from {} to shut down", client_ip);
+                    return;
+                }
+            }
+        };
+        let timing = Timing::start();
+        let (mut request, mut request_body) = match request {
+            None if requests_served == 0 => {
+                log::info!("Timed out waiting for a request from {}", client_ip);
+                return;
+            }
+            None => {
+                log::info!("Closing idle connection from {}", client_ip);
+                return;
+            }
+            Some(Ok(request)) => request,
+            // Handle case where client closed connection and is no longer sending requests
+            Some(Err(request::Error::IncompleteRequest(0))) => {
+                log::debug!("Client finished sending requests. Shutting down connection");
+                return;
+            }
+            // Handle I/O error in reading from the client
+            Some(Err(request::Error::ConnectionError(io_err))) => {
+                log::info!("Error reading request from client stream: {}", io_err);
+                return;
+            }
+            Some(Err(error)) => {
+                log::debug!("Error parsing request: {:?}", error);
+                let status = match error {
+                    request::Error::IncompleteRequest(_)
+                    | request::Error::MalformedRequest(_)
+                    | request::Error::InvalidContentLength
+                    | request::Error::ConflictingContentLength
+                    | request::Error::AmbiguousFraming
+                    | request::Error::UnsupportedTransferEncoding
+                    | request::Error::InvalidHeader
+                    | request::Error::ContentLengthMismatch
+                    | request::Error::InvalidChunkedBody => http::StatusCode::BAD_REQUEST,
+                    request::Error::RequestBodyTooLarge => http::StatusCode::PAYLOAD_TOO_LARGE,
+                    request::Error::ConnectionError(_) => http::StatusCode::SERVICE_UNAVAILABLE,
+                };
+                // When a request's head can't be trusted, there's no telling where the next one on
+                // the connection starts, so it's closed rather than risk reading a smuggled one
+                let framing_error = matches!(
+                    error,
+                    request::Error::MalformedRequest(_)
+                        | request::Error::InvalidHeader
+                        | request::Error::InvalidContentLength
+                        | request::Error::ConflictingContentLength
+                        | request::Error::AmbiguousFraming
+                        | request::Error::UnsupportedTransferEncoding
+                );
+                let mut response = state.error_response(status, None, None).await;
+                state
+                    .header_rules
+                    .read()
+                    .await
+                    .apply_to_response(&mut response);
+                if framing_error {
+                    response::set_keep_alive(&mut response, false);
+                }
+                send_response(&mut client_conn, &client_ip, &response).await;
+                if framing_error {
+                    return;
+                }
+                continue;
+            }
+        };
+
+        request_body.set_read_timeout(client_read_timeout);
+        requests_served += 1;
+        // Traces what happens to the request: picking an upstream, forwarding the request to it,
+        // and sending the response back
+        let span = tracing::info_span!(
+            "request",
+            http.method = %request.method(),
+            http.target = %request.uri(),
+            client.address = %client_ip,
+            upstream = tracing::field::Empty,
+            http.status_code = tracing::field::Empty,
+        );
+        // Whether the connection stays open for another request once this one is answered
+        let max_requests = state.max_requests_per_connection.load(Ordering::SeqCst);
+        let mut keep_alive = request::wants_keep_alive(&request)
+            && (max_requests == 0 || requests_served < max_requests)
+            && !state.shutting_down.load(Ordering::SeqCst);
+
+        // Done before the middlewares, so custom ones can decide what to do based on who the
+        // client is
+        proxy_headers::set_client_cert_subject(&mut request, cert_subject.as_deref());
+
+        // Pass the request through the middlewares (rate limiting, proxy headers and any custom
+        // ones), any of which may answer it without it going to an upstream
+        // Behind a trusted proxy, the request is rate limited and logged under the client the
+        // proxy says sent it
+        let client_addr =
+            proxy_headers::client_ip(&request, peer_addr, &state.trusted_proxies.read().await);
+        let context = middleware::Context {
+            client_ip: client_addr,
+            peer_ip: peer_addr,
+            frontend,
+            state: &state,
+        };
+        let action = middleware::run_request(&state.middlewares, &context, &mut request).await;
+        if let Action::Respond(response) = action {
+            // Read past the body, so the next request on the connection can be found
+            let mut sink = tokio::io::sink();
+            if body::copy(&mut request_body, &mut client_conn, &mut sink)
+                .await
+                .is_err()
+            {
+                return;
+            }
+            leftover = request_body.into_leftover();
+            let exchange = Exchange {
+                request: &request,
+                peer_ip: &client_ip,
+                client_ip: client_addr,
+                span: &span,
+                timing: &timing,
+            };
+            send_local_response(&state, &mut client_conn, &exchange, None, response, keep_alive)
+                .await;
+            if !keep_alive {
+                return;
+            }
+            continue;
+        }
+        // A client that sent `Expect: 100-continue` holds the body back until it's told to go
+        // ahead. The upstream's own 100 Continue is skipped along with its other interim
+        // responses, so the go-ahead comes from here.
+        if request::expects_continue(&request) && request_body.framing() != Framing::Empty {
+            let go_ahead = b"HTTP/1.1 100 Continue\r\n\r\n";
+            if client_conn.write_all(go_ahead).await.is_err() || client_conn.flush().await.is_err()
+            {
+                return;
+            }
+        }
+        // The header rules come after the middlewares, so they have the last word on what the
+        // upstream sees (and, below, on what the client sees)
+        state
+            .header_rules
+            .read()
+            .await
+            .apply_to_request(&mut request);
+
+        // Send a copy of the request to the shadow backend, if there is one. Its body is copied
+        // as it is read. Upgrades aren't copied, since the connection stops carrying HTTP after.
+        if !request::is_upgrade(&request) {
+            if let Some(address) = state.mirror_address.read().await.as_deref() {
+                state
+                    .mirror
+                    .send(address, &state.upstream_tls, &request, &mut request_body);
+            }
+        }
+
+        let forwarding = route_request(&state, &request, request_body.framing(), client_addr).await;
+        // Answer from the cache if it has a response for the request, fetching a fresh copy in
+        // the background if the one it has has gone stale. Requests for a particular upstream
+        // have to reach it, so they're never answered from the cache.
+        let cache_key = if request.extensions().get::<ForcedUpstream>().is_some() {
+            None
+        } else {
+            response_cache::key(&request, &state.cache_paths.read().await)
+        };
+        if cache_key.is_some() {
+            // The cache keeps one uncompressed copy of the response, compressed to suit each
+            // client as it's served
+            compression::normalize_accept_encoding(&mut request);
+        }
+        let exchange = Exchange {
+            request: &request,
+            peer_ip: &client_ip,
+            client_ip: client_addr,
+            span: &span,
+            timing: &timing,
+        };
+        if let Some(key) = &cache_key {
+            let cached = match state.response_cache.lookup(key) {
+                Lookup::Fresh(response) => Some(response),
+                Lookup::Stale(response) => {
+                    let pool = forwarding.pool.clone();
+                    response_cache::revalidate(&state, key.clone(), &request, pool, client_addr);
+                    Some(response)
+                }
+                Lookup::Miss => None,
+            };
+            if let Some(response) = cached {
+                // Read past the body, so the next request on the connection can be found
+                let mut sink = tokio::io::sink();
+                if body::copy(&mut request_body, &mut client_conn, &mut sink)
+                    .await
+                    .is_err()
+                {
+                    return;
+                }
+                leftover = request_body.into_leftover();
+                send_cached_response(&mut client_conn, &context, &exchange, response, keep_alive)
+                    .await;
+                if !keep_alive {
+                    return;
+                }
+                continue;
+            }
+        }
+        // The client's connection stays with one upstream for as long as its requests can go
+        // there; a request for a different route needs an upstream from that route's pool, and
+        // one that has been drained or forced down takes no new requests
+        if let Some(connection) = &upstream {
+            let enabled = state.upstream_status.load().admin_state(connection.idx)
+                == Some(AdminState::Enabled);
+            let in_pool = forwarding.pool.contains(&connection.idx)
+                || forwarding.draining_pool.contains(&connection.idx);
+            if !enabled || !in_pool {
+                upstream = None;
+            }
+        }
+        // Counts the request as in flight to the upstream until it's answered
+        let (response, _in_flight) = forward_with_retries(
+            &state,
+            &mut client_conn,
+            &exchange,
+            &mut request_body,
+            &mut upstream,
+            &forwarding,
+        )
+        .await;
+        if let Some(upstream) = &upstream {
+            state
+                .record_canary_outcome(upstream.idx, upstream_failed(&response))
+                .await;
+        }
+        // A stale response is better than an error, for as long as it says it may be used that
+        // way. Upstreams all being too busy counts as them failing here.
+        let overloaded = matches!(response, Err(ForwardError::Overloaded));
+        let stale = cache_key
+            .as_deref()
+            .filter(|_| upstream_failed(&response) || overloaded)
+            .and_then(|key| state.response_cache.lookup_on_error(key));
+        if let Some(response) = stale {
+            log::warn!(
+                "Upstreams failed, answering {} with a stale cached response",
+                client_ip
+            );
+            send_cached_response(&mut client_conn, &context, &exchange, response, false).await;
+            return;
+        }
+        let (mut response, mut response_body) = match response {
+            Ok(response) => response,
+            Err(ForwardError::Upstream(status)) => {
+                let upstream = upstream.as_ref();
+                respond_with_error(&state, &mut client_conn, &exchange, upstream, status).await;
+                return;
+            }
+            // Every upstream was too busy for the request
+            Err(ForwardError::Overloaded) => {
+                let status = http::StatusCode::SERVICE_UNAVAILABLE;
+                let mut response = state.error_response(status, Some(&request), None).await;
+                let retry_after = http::HeaderValue::from(request_queue::retry_after(&state));
+                response
+                    .headers_mut()
+                    .insert(http::header::RETRY_AFTER, retry_after);
+                send_local_response(&state, &mut client_conn, &exchange, None, response, false)
+                    .await;
+                return;
+            }
+            // The client hung up or stalled partway through sending the body
+            Err(ForwardError::Client(request::Error::ConnectionError(_))) => return,
+            Err(ForwardError::Client(_)) => {
+                let status = http::StatusCode::BAD_REQUEST;
+                let upstream = upstream.as_ref();
+                respond_with_error(&state, &mut client_conn, &exchange, upstream, status).await;
+                return;
+            }
+        };
+        let connection = upstream.as_mut().unwrap();
+        // Keep the response for later requests if it may be cached. Its body is read in full
+        // first, and then passed on from memory.
+        let freshness = response_cache::cacheable(&response, response_body.framing());
+        if let (Some(key), Some(freshness)) = (&cache_key, freshness) {
+            let max_size = response_cache::MAX_BODY_SIZE;
+            match response_body
+                .read_to_end(&mut connection.stream, max_size)
+                .await
+            {
+                Ok(body) => {
+                    state.response_cache.store(key, &response, &body, freshness);
+                    let body = BytesMut::from(Bytes::from(body));
+                    response_body = BodyReader::new(Framing::Length(body.len()), body);
+                }
+                Err(error) => {
+                    let ip = &connection.ip;
+                    log::warn!("Failed to read response to cache from {}: {:?}", ip, error);
+                    return;
+                }
+            }
+        }
+        span.record("http.status_code", response.status().as_u16());
+        middleware::run_response(&state.middlewares, &context, &request, &mut response).await;
+
+        // Pin the client to this upstream, unless it's already pinned to it
+        if let Some(cookie) = state.sticky_cookie.read().await.as_deref() {
+            let value = sticky::cookie_value(&connection.address);
+            if request::get_cookie(&request, cookie) != Some(value.as_str()) {
+                response::add_cookie(&mut response, cookie, &value);
+            }
+        }
+        state
+            .header_rules
+            .read()
+            .await
+            .apply_to_response(&mut response);
+
+        // If the upstream agreed to switch protocols (e.g. to WebSocket), the connection no longer
+        // carries HTTP from here on
+        if response.status() == http::StatusCode::SWITCHING_PROTOCOLS
+            && request::is_upgrade(&request)
+        {
+            relay_upgraded(
+                &state,
+                &mut client_conn,
+                &exchange,
+                connection,
+                &response,
+                request_body,
+                response_body,
+            )
+            .await;
+            return;
+        }
+        let upstream_conn = &mut connection.stream;
+
+        // Compress the body if the client accepts it, or undo the upstream's compression if the
+        // client can't
+        let compression = state.compression.read().await.clone();
+        let transform =
+            compression::choose(&compression, &request, &response, response_body.framing());
+        if let Some(transform) = transform {
+            compression::update_headers(&mut response, transform);
+        }
+        // A body that ends when the upstream hangs up can only end the same way for the client
+        if response_body.framing() == Framing::UntilClose {
+            keep_alive = false;
+        }
+        // HTTP/1.0 clients don't understand chunked bodies, so those are sent to them as plain
+        // bytes, ended by closing the connection
+        let dechunk =
+            request.version() == http::Version::HTTP_10 && chunked::is_chunked(response.headers());
+        if dechunk {
+            response
+                .headers_mut()
+                .remove(http::header::TRANSFER_ENCODING);
+            keep_alive = false;
+        }
+        let upstream_closing = !response::is_keep_alive(&response);
+        response::set_keep_alive(&mut response, keep_alive);
+
+        // Forward the response to the client, passing the body on as it arrives from the upstream
+        log::info!(
+            "{} <- {}",
+            client_ip,
+            response::format_response_line(&response)
+        );
+        let sent = async {
+            response::write_head(&response, &mut client_conn)
+                .await
+                .map_err(CopyError::Write)?;
+            match transform {
+                Some(transform) => {
+                    compression::copy(
+                        transform,
+                        &mut response_body,
+                        upstream_conn,
+                        &mut client_conn,
+                        !dechunk,
+                    )
+                    .await
+                }
+                None if dechunk => {
+                    body::copy_framed(&mut response_body, upstream_conn, &mut client_conn, false)
+                        .await
+                }
+                None => body::copy(&mut response_body, upstream_conn, &mut client_conn).await,
+            }
+        };
+        match sent
+            .instrument(tracing::info_span!(parent: &span, "send_response"))
+            .await
+        {
+            Ok(bytes) => {
+                log::debug!("Forwarded response to client");
+                let status = response.status();
+                let bytes = client_conn.sizes(bytes);
+                state.log_access(
+                    client_addr,
+                    &request,
+                    Some(connection),
+                    status,
+                    bytes,
+                    &timing,
+                );
+                connection.buffer = response_body.into_leftover();
+            }
+            Err(CopyError::Read(error)) => {
+                // The client already has the headers, so all we can do is hang up
+                log::error!(
+                    "Error reading response body from upstream {}: {:?}",
+                    connection.ip,
+                    error
+                );
+                state.record_upstream_failure(&connection.address).await;
+                return;
+            }
+            Err(CopyError::Write(error)) => {
+                log::warn!("Failed to send response to client: {}", error);
+                return;
+            }
+        }
+        if !keep_alive {
+            return;
+        }
+        if upstream_closing {
+            // The upstream won't take another request on this connection, so the next one needs
+            // a new connection
+            upstream = None;
+        }
+        leftover = request_body.into_leftover();
+    }
+}
@@ -0,0 +1,70 @@
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::{Arc, Mutex};
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+
+/// Number of connections currently open from each IP address
+type PerIpCounts = Arc<Mutex<HashMap<IpAddr, usize>>>;
+
+/// Caps the number of client connections that are open at once, both in total and from any one IP
+/// address, so that clients holding connections open can't exhaust the proxy's resources.
+pub struct ConnectionLimits {
+    /// Permits for the connections that may be open in total, or None if unlimited
+    total: Option<Arc<Semaphore>>,
+    /// Maximum number of connections from one IP address (0 = unlimited)
+    max_per_ip: usize,
+    per_ip: PerIpCounts,
+}
+
+/// Held for as long as a client connection is open. Dropping it frees up the connection's slot.
+pub struct ConnectionPermit {
+    _total: Option<OwnedSemaphorePermit>,
+    per_ip: Option<(PerIpCounts, IpAddr)>,
+}
+
+impl Drop for ConnectionPermit {
+    fn drop(&mut self) {
+        if let Some((per_ip, ip)) = &self.per_ip {
+            let mut per_ip = per_ip.lock().unwrap();
+            if let Some(count) = per_ip.get_mut(ip) {
+                *count -= 1;
+                if *count == 0 {
+                    per_ip.remove(ip);
+                }
+            }
+        }
+    }
+}
+
+impl ConnectionLimits {
+    /// Creates limits allowing `max_total` connections in total and `max_per_ip` from each IP
+    /// address. 0 means unlimited.
+    pub fn new(max_total: usize, max_per_ip: usize) -> ConnectionLimits {
+        ConnectionLimits {
+            total: if max_total > 0 { Some(Arc::new(Semaphore::new(max_total))) } else { None },
+            max_per_ip,
+            per_ip: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+
+    /// Takes a slot for a new connection from the given IP address. Returns None if there is no
+    /// room for it.
+    pub fn try_acquire(&self, ip: IpAddr) -> Option<ConnectionPermit> {
+        let total = match &self.total {
+            Some(semaphore) => Some(semaphore.clone().try_acquire_owned().ok()?),
+            None => None,
+        };
+        let per_ip = if self.max_per_ip > 0 {
+            let mut per_ip = self.per_ip.lock().unwrap();
+            let count = per_ip.entry(ip).or_insert(0);
+            if *count >= self.max_per_ip {
+                return None;
+            }
+            *count += 1;
+            Some((self.per_ip.clone(), ip))
+        } else {
+            None
+        };
+        Some(ConnectionPermit { _total: total, per_ip })
+    }
+}
@@ -0,0 +1,154 @@
+use http::header::{self, HeaderValue};
+
+/// Methods allowed in cross-origin requests when none are given
+const DEFAULT_METHODS: &str = "GET, HEAD, POST, PUT, PATCH, DELETE";
+
+/// How cross-origin requests from browsers are handled. CORS is off (and requests and responses
+/// are passed through untouched) unless some origins are allowed.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Settings {
+    /// Origins that may make requests, e.g. "https://app.example.com", or "*" for any
+    pub allow_origins: Vec<String>,
+    /// Methods they may use (empty = the usual ones)
+    pub allow_methods: Vec<String>,
+    /// Request headers they may send (empty = whichever ones the browser asks for)
+    pub allow_headers: Vec<String>,
+    /// Response headers, beyond the basic ones, that scripts may read
+    pub expose_headers: Vec<String>,
+    /// Whether requests may carry cookies and other credentials
+    pub allow_credentials: bool,
+    /// How long (in seconds) browsers may cache the answer to a preflight request (0 = don't say)
+    pub max_age: usize,
+}
+
+impl Settings {
+    pub fn is_enabled(&self) -> bool {
+        !self.allow_origins.is_empty()
+    }
+
+    /// Returns the value for Access-Control-Allow-Origin if the origin may make requests. When
+    /// credentials are allowed, browsers don't accept "*", so the origin is named instead.
+    fn allowed_origin(&self, origin: &str) -> Option<String> {
+        if self.allow_origins.iter().any(|allowed| allowed == "*") {
+            if self.allow_credentials {
+                Some(origin.to_string())
+            } else {
+                Some("*".to_string())
+            }
+        } else if self.allow_origins.iter().any(|allowed| allowed.eq_ignore_ascii_case(origin)) {
+            Some(origin.to_string())
+        } else {
+            None
+        }
+    }
+
+    /// Answers a preflight request, which a browser sends to ask whether a cross-origin request
+    /// may be made, without passing it on to an upstream. Returns None if the request isn't a
+    /// preflight request. Origins that aren't allowed get an answer without any of the
+    /// Access-Control-Allow-* headers, which the browser takes as a refusal.
+    pub fn preflight_response(
+        &self,
+        request: &http::Request<Vec<u8>>,
+    ) -> Option<http::Response<Vec<u8>>> {
+        let headers = request.headers();
+        if request.method() != http::Method::OPTIONS
+            || !headers.contains_key(header::ACCESS_CONTROL_REQUEST_METHOD)
+        {
+            return None;
+        }
+        let origin = headers.get(header::ORIGIN).and_then(|origin| origin.to_str().ok())?;
+
+        let mut response = http::Response::builder()
+            .status(http::StatusCode::NO_CONTENT)
+            .version(http::Version::HTTP_11)
+            .header(header::VARY, "Origin, Access-Control-Request-Method")
+            .body(Vec::new())
+            .unwrap();
+        let allowed_origin = match self.allowed_origin(origin) {
+            Some(allowed_origin) => allowed_origin,
+            None => {
+                log::info!("Refusing cross-origin request from {}", origin);
+                return Some(response);
+            }
+        };
+        let response_headers = response.headers_mut();
+        insert(response_headers, header::ACCESS_CONTROL_ALLOW_ORIGIN, &allowed_origin);
+        let methods = if self.allow_methods.is_empty() {
+            DEFAULT_METHODS.to_string()
+        } else {
+            self.allow_methods.join(", ")
+        };
+        insert(response_headers, header::ACCESS_CONTROL_ALLOW_METHODS, &methods);
+        if self.allow_headers.is_empty() {
+            // Allow whatever the browser asked for
+            if let Some(requested) = headers.get(header::ACCESS_CONTROL_REQUEST_HEADERS) {
+                response_headers.insert(header::ACCESS_CONTROL_ALLOW_HEADERS, requested.clone());
+            }
+        } else {
+            let allowed = self.allow_headers.join(", ");
+            insert(response_headers, header::ACCESS_CONTROL_ALLOW_HEADERS, &allowed);
+        }
+        if self.allow_credentials {
+            insert(response_headers, header::ACCESS_CONTROL_ALLOW_CREDENTIALS, "true");
+        }
+        if self.max_age > 0 {
+            insert(response_headers, header::ACCESS_CONTROL_MAX_AGE, &self.max_age.to_string());
+        }
+        Some(response)
+    }
+
+    /// Adds the headers that let the browser hand the response to the script that made the
+    /// request, if it came from an allowed origin. Any the upstream sent itself are replaced, so
+    /// only the origins we allow can read responses.
+    pub fn add_response_headers(
+        &self,
+        request: &http::Request<Vec<u8>>,
+        response: &mut http::Response<Vec<u8>>,
+    ) {
+        let headers = response.headers_mut();
+        for name in &[
+            header::ACCESS_CONTROL_ALLOW_ORIGIN,
+            header::ACCESS_CONTROL_ALLOW_CREDENTIALS,
+            header::ACCESS_CONTROL_EXPOSE_HEADERS,
+        ] {
+            headers.remove(name);
+        }
+        // The response depends on the Origin header unless every origin gets the same answer
+        if self.allow_origins.iter().all(|allowed| allowed != "*") || self.allow_credentials {
+            headers.append(header::VARY, HeaderValue::from_static("Origin"));
+        }
+        let origin = request.headers().get(header::ORIGIN).and_then(|origin| origin.to_str().ok());
+        let allowed_origin = match origin.and_then(|origin| self.allowed_origin(origin)) {
+            Some(allowed_origin) => allowed_origin,
+            None => return,
+        };
+        insert(headers, header::ACCESS_CONTROL_ALLOW_ORIGIN, &allowed_origin);
+        if self.allow_credentials {
+            insert(headers, header::ACCESS_CONTROL_ALLOW_CREDENTIALS, "true");
+        }
+        if !self.expose_headers.is_empty() {
+            let exposed = self.expose_headers.join(", ");
+            insert(headers, header::ACCESS_CONTROL_EXPOSE_HEADERS, &exposed);
+        }
+    }
+}
+
+/// Sets a header, skipping values that can't be sent (the config is checked for those up front)
+fn insert(headers: &mut http::HeaderMap, name: header::HeaderName, value: &str) {
+    if let Ok(value) = HeaderValue::from_str(value) {
+        headers.insert(name, value);
+    }
+}
+
+/// Returns true if the value may be given as an allowed origin: "*", or a scheme and host with no
+/// path, e.g. "https://app.example.com:8443"
+pub fn is_valid_origin(origin: &str) -> bool {
+    if origin == "*" {
+        return true;
+    }
+    let host = match origin.strip_prefix("http://").or_else(|| origin.strip_prefix("https://")) {
+        Some(host) => host,
+        None => return false,
+    };
+    !host.is_empty() && !host.contains('/') && HeaderValue::from_str(origin).is_ok()
+}
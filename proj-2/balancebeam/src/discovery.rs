@@ -0,0 +1,659 @@
+use std::collections::HashMap;
+use std::path::Path;
+use std::sync::Arc;
+use std::time::Duration;
+use base64::Engine;
+use bytes::BytesMut;
+use tokio::time::{sleep, timeout};
+use crate::upstream::{self, UpstreamAddr};
+use crate::state::ProxyState;
+use crate::{request, response};
+
+/// How long a lookup (connecting, sending the request and reading the response) may take
+const LOOKUP_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// How long a Kubernetes watch is asked to last before the API server ends it and it is started
+/// again. Watches that go quiet for longer than this (give or take LOOKUP_TIMEOUT) are given up on.
+const WATCH_TIMEOUT: Duration = Duration::from_secs(300);
+
+/// Largest watch event that is read. EndpointSlices hold at most 1000 endpoints, which is well
+/// under this.
+const MAX_EVENT_SIZE: usize = 4 * 1024 * 1024;
+
+/// Where Kubernetes mounts the service account's credentials in each pod
+const SERVICE_ACCOUNT_TOKEN: &str = "/var/run/secrets/kubernetes.io/serviceaccount/token";
+const SERVICE_ACCOUNT_CA_CERT: &str = "/var/run/secrets/kubernetes.io/serviceaccount/ca.crt";
+
+/// Where service instances are looked up
+#[derive(clap::ArgEnum, Debug, Clone, Copy, PartialEq)]
+pub enum DiscoveryBackend {
+    /// Consul's health API, which lists the instances of a service whose checks pass
+    Consul,
+    /// etcd's key-value store, where each key under a prefix holds an instance's address
+    Etcd,
+    /// The Kubernetes API, which lists the ready pods behind a service in its EndpointSlices (or,
+    /// on clusters too old to have those, its Endpoints)
+    K8s,
+}
+
+/// How to find upstreams through service discovery
+#[derive(Clone, Debug)]
+pub struct Settings {
+    pub backend: DiscoveryBackend,
+    /// Address of the Consul agent, etcd server or Kubernetes API server, in the same form as an
+    /// upstream (e.g. "127.0.0.1:8500" or "https://consul.internal:8501")
+    pub address: String,
+    /// Name of the Consul service, the etcd key prefix its instances are registered under, or
+    /// the Kubernetes service as "namespace/service", optionally followed by ":port" to pick one
+    /// of its ports by name or number
+    pub service: String,
+    /// How often to look the instances up again. Kubernetes is watched for changes instead, and
+    /// only listed again this long after a watch fails.
+    pub interval: Duration,
+    /// File holding a bearer token to authenticate with, read again for each lookup since
+    /// Kubernetes rotates it
+    pub token_path: Option<String>,
+    /// PEM file with the CA certificate the server's certificate is signed with, if it isn't one
+    /// of the usual public CAs
+    pub ca_cert_path: Option<String>,
+}
+
+impl Settings {
+    /// Returns the settings for looking up the given service, or an error if it isn't named the
+    /// way the backend expects. When running in a Kubernetes pod, the API server and the pod's
+    /// service account are found the way the official clients find them.
+    pub fn new(
+        backend: DiscoveryBackend,
+        address: Option<&str>,
+        service: &str,
+    ) -> Result<Settings, String> {
+        let mut settings = Settings {
+            backend,
+            address: address.map(String::from).unwrap_or_else(|| default_address(backend)),
+            service: service.to_string(),
+            interval: Duration::from_secs(10),
+            token_path: None,
+            ca_cert_path: None,
+        };
+        if backend == DiscoveryBackend::K8s {
+            K8sService::parse(service)?;
+            if Path::new(SERVICE_ACCOUNT_TOKEN).exists() {
+                settings.token_path = Some(SERVICE_ACCOUNT_TOKEN.to_string());
+            }
+            if Path::new(SERVICE_ACCOUNT_CA_CERT).exists() {
+                settings.ca_cert_path = Some(SERVICE_ACCOUNT_CA_CERT.to_string());
+            }
+        }
+        Ok(settings)
+    }
+}
+
+fn default_address(backend: DiscoveryBackend) -> String {
+    match backend {
+        DiscoveryBackend::Consul => "127.0.0.1:8500".to_string(),
+        DiscoveryBackend::Etcd => "127.0.0.1:2379".to_string(),
+        // Kubernetes tells each pod where the API server is
+        DiscoveryBackend::K8s => match (
+            std::env::var("KUBERNETES_SERVICE_HOST"),
+            std::env::var("KUBERNETES_SERVICE_PORT"),
+        ) {
+            (Ok(host), Ok(port)) if host.contains(':') => format!("https://[{}]:{}", host, port),
+            (Ok(host), Ok(port)) => format!("https://{}:{}", host, port),
+            _ => "https://kubernetes.default.svc".to_string(),
+        },
+    }
+}
+
+/// Parses a --discover argument, which is formatted like "consul:web", "etcd:/services/web/" or
+/// "k8s:namespace/service[:port]"
+pub fn parse_discover(arg: &str) -> Result<(DiscoveryBackend, String), String> {
+    let (backend, service) = arg
+        .split_once(':')
+        .ok_or_else(|| format!("expected <consul|etcd|k8s>:<service>, got {:?}", arg))?;
+    let backend = match backend {
+        "consul" => DiscoveryBackend::Consul,
+        "etcd" => DiscoveryBackend::Etcd,
+        "k8s" => DiscoveryBackend::K8s,
+        _ => {
+            let expected = "expected consul, etcd or k8s";
+            return Err(format!("unknown discovery backend {:?}: {}", backend, expected));
+        }
+    };
+    if service.is_empty() {
+        return Err(format!("no service given in {:?}", arg));
+    }
+    if backend == DiscoveryBackend::K8s {
+        K8sService::parse(service)?;
+    }
+    Ok((backend, service.to_string()))
+}
+
+/// A Kubernetes service, and which of its ports to send requests to
+struct K8sService<'a> {
+    namespace: &'a str,
+    name: &'a str,
+    /// Name or number of the port (None = the first one listed)
+    port: Option<&'a str>,
+}
+
+impl<'a> K8sService<'a> {
+    fn parse(service: &'a str) -> Result<K8sService<'a>, String> {
+        let (namespace, rest) = service
+            .split_once('/')
+            .ok_or_else(|| format!("expected <namespace>/<service>[:<port>], got {:?}", service))?;
+        let (name, port) = match rest.split_once(':') {
+            Some((name, port)) => (name, Some(port)),
+            None => (rest, None),
+        };
+        // Namespace and service names are DNS labels, so they can go into a URL as they are
+        let is_label = |label: &str| {
+            !label.is_empty()
+                && label.chars().all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '-')
+        };
+        if !is_label(namespace) || !is_label(name) || port == Some("") {
+            return Err(format!("invalid Kubernetes service {:?}", service));
+        }
+        Ok(K8sService { namespace, name, port })
+    }
+
+    /// Returns the port with the wanted name or number out of a list of ports in the Kubernetes
+    /// API's format, or the first one if no port was asked for
+    fn pick_port(&self, ports: &[serde_json::Value]) -> Option<u64> {
+        let port = match self.port {
+            Some(wanted) => ports.iter().find(|port| {
+                port["name"].as_str() == Some(wanted)
+                    || port["port"].as_u64().map(|number| number.to_string()).as_deref()
+                        == Some(wanted)
+            })?,
+            None => ports.first()?,
+        };
+        port["port"].as_u64()
+    }
+}
+
+/// Formats an instance's host and port as an upstream address, bracketing IPv6 addresses
+fn instance_address(host: &str, port: u64) -> String {
+    if host.contains(':') && !host.starts_with('[') {
+        format!("[{}]:{}", host, port)
+    } else {
+        format!("{}:{}", host, port)
+    }
+}
+
+/// Reads the instances out of a response from Consul's /v1/health/service endpoint. An instance
+/// registered without an address of its own is at its node's address.
+fn parse_consul(body: &[u8]) -> Result<Vec<String>, String> {
+    let entries: Vec<serde_json::Value> =
+        serde_json::from_slice(body).map_err(|err| format!("invalid response: {}", err))?;
+    let mut instances = Vec::new();
+    for entry in &entries {
+        let service = &entry["Service"];
+        let host = match service["Address"].as_str() {
+            Some(address) if !address.is_empty() => address,
+            _ => entry["Node"]["Address"].as_str().unwrap_or_default(),
+        };
+        match service["Port"].as_u64() {
+            Some(port) if !host.is_empty() => instances.push(instance_address(host, port)),
+            _ => log::warn!("Skipping Consul service instance without an address: {}", service),
+        }
+    }
+    Ok(instances)
+}
+
+/// Reads the instances out of a response from etcd's /v3/kv/range endpoint, whose keys and
+/// values are base64-encoded
+fn parse_etcd(body: &[u8]) -> Result<Vec<String>, String> {
+    let response: serde_json::Value =
+        serde_json::from_slice(body).map_err(|err| format!("invalid response: {}", err))?;
+    let kvs = match response["kvs"].as_array() {
+        Some(kvs) => kvs,
+        // etcd leaves the list out altogether when no keys match
+        None => return Ok(Vec::new()),
+    };
+    let mut instances = Vec::new();
+    for kv in kvs {
+        let value = kv["value"]
+            .as_str()
+            .and_then(|value| base64::engine::general_purpose::STANDARD.decode(value).ok())
+            .and_then(|value| String::from_utf8(value).ok());
+        match value.as_deref().map(str::trim) {
+            Some(address) if !address.is_empty() => instances.push(address.to_string()),
+            _ => log::warn!("Skipping etcd key without an address: {}", kv["key"]),
+        }
+    }
+    Ok(instances)
+}
+
+/// Reads the ready pods out of one of a service's EndpointSlices. Endpoints that don't say
+/// whether they're ready count as ready, as the API documents.
+fn slice_instances(slice: &serde_json::Value, service: &K8sService) -> Vec<String> {
+    let ports = slice["ports"].as_array().map(Vec::as_slice).unwrap_or_default();
+    let port = match service.pick_port(ports) {
+        Some(port) => port,
+        None => return Vec::new(),
+    };
+    let mut instances = Vec::new();
+    for endpoint in slice["endpoints"].as_array().map(Vec::as_slice).unwrap_or_default() {
+        if endpoint["conditions"]["ready"].as_bool() == Some(false) {
+            continue;
+        }
+        // The addresses of one endpoint are all the same pod, so one will do
+        if let Some(address) = endpoint["addresses"][0].as_str() {
+            instances.push(instance_address(address, port));
+        }
+    }
+    instances
+}
+
+/// Reads the ready pods out of a service's Endpoints, which list pods that aren't ready
+/// separately
+fn endpoints_instances(endpoints: &serde_json::Value, service: &K8sService) -> Vec<String> {
+    let mut instances = Vec::new();
+    for subset in endpoints["subsets"].as_array().map(Vec::as_slice).unwrap_or_default() {
+        let ports = subset["ports"].as_array().map(Vec::as_slice).unwrap_or_default();
+        let port = match service.pick_port(ports) {
+            Some(port) => port,
+            None => continue,
+        };
+        for address in subset["addresses"].as_array().map(Vec::as_slice).unwrap_or_default() {
+            if let Some(ip) = address["ip"].as_str() {
+                instances.push(instance_address(ip, port));
+            }
+        }
+    }
+    instances
+}
+
+/// Which of the Kubernetes APIs a service's pods are listed in
+#[derive(Clone, Copy, Debug)]
+enum K8sApi {
+    EndpointSlices,
+    /// The Endpoints API, for clusters too old to have EndpointSlices
+    Endpoints,
+}
+
+impl K8sApi {
+    /// Returns the path that lists the service's EndpointSlices, or its Endpoints
+    fn list_path(self, service: &K8sService) -> String {
+        match self {
+            K8sApi::EndpointSlices => format!(
+                "/apis/discovery.k8s.io/v1/namespaces/{}/endpointslices\
+                 ?labelSelector=kubernetes.io%2Fservice-name%3D{}",
+                service.namespace, service.name
+            ),
+            K8sApi::Endpoints => format!(
+                "/api/v1/namespaces/{}/endpoints?fieldSelector=metadata.name%3D{}",
+                service.namespace, service.name
+            ),
+        }
+    }
+
+    /// Returns the ready pods in one EndpointSlice or Endpoints object
+    fn instances(self, object: &serde_json::Value, service: &K8sService) -> Vec<String> {
+        match self {
+            K8sApi::EndpointSlices => slice_instances(object, service),
+            K8sApi::Endpoints => endpoints_instances(object, service),
+        }
+    }
+}
+
+/// A watch on the Kubernetes API stopped
+#[derive(Debug)]
+enum WatchError {
+    /// The resourceVersion it started from is too old (410 Gone), so the service has to be
+    /// listed again
+    Expired,
+    Failed(String),
+}
+
+/// What's known of a Kubernetes service's pods: the ready ones in each of its EndpointSlices (or
+/// its Endpoints) by the object's name, and the resourceVersion to watch for changes from
+struct K8sListing {
+    api: K8sApi,
+    objects: HashMap<String, Vec<String>>,
+    resource_version: String,
+}
+
+impl K8sListing {
+    /// Reads a list of EndpointSlices or Endpoints
+    fn parse(api: K8sApi, body: &[u8], service: &K8sService) -> Result<K8sListing, String> {
+        let list: serde_json::Value =
+            serde_json::from_slice(body).map_err(|err| format!("invalid response: {}", err))?;
+        let objects = list["items"]
+            .as_array()
+            .map(Vec::as_slice)
+            .unwrap_or_default()
+            .iter()
+            .map(|object| {
+                let name = object["metadata"]["name"].as_str().unwrap_or_default().to_string();
+                (name, api.instances(object, service))
+            })
+            .collect();
+        let resource_version =
+            list["metadata"]["resourceVersion"].as_str().unwrap_or_default().to_string();
+        Ok(K8sListing { api, objects, resource_version })
+    }
+
+    /// Returns the ready pods across all the objects, in order
+    fn instances(&self) -> Vec<String> {
+        let mut instances: Vec<String> = self.objects.values().flatten().cloned().collect();
+        instances.sort();
+        instances.dedup();
+        instances
+    }
+
+    /// Applies one event from a watch: an object being added, changed or deleted, or a bookmark
+    /// that only moves the resourceVersion along
+    fn apply_event(&mut self, event: &[u8], service: &K8sService) -> Result<(), WatchError> {
+        let event: serde_json::Value = serde_json::from_slice(event)
+            .map_err(|err| WatchError::Failed(format!("invalid watch event: {}", err)))?;
+        let object = &event["object"];
+        let name = object["metadata"]["name"].as_str().unwrap_or_default().to_string();
+        match event["type"].as_str() {
+            Some("ADDED") | Some("MODIFIED") => {
+                self.objects.insert(name, self.api.instances(object, service));
+            }
+            Some("DELETED") => {
+                self.objects.remove(&name);
+            }
+            Some("BOOKMARK") => {}
+            Some("ERROR") if object["code"].as_u64() == Some(410) => {
+                return Err(WatchError::Expired);
+            }
+            _ => return Err(WatchError::Failed(format!("watch failed: {}", object))),
+        }
+        if let Some(version) = object["metadata"]["resourceVersion"].as_str() {
+            self.resource_version = version.to_string();
+        }
+        Ok(())
+    }
+}
+
+/// Returns the end of the range of etcd keys starting with the prefix: the prefix with its last
+/// byte incremented, as etcd's own clients do
+fn prefix_range_end(prefix: &[u8]) -> Vec<u8> {
+    let mut end = prefix.to_vec();
+    while let Some(last) = end.pop() {
+        if last < 0xff {
+            end.push(last + 1);
+            return end;
+        }
+    }
+    // Every key is after the prefix
+    vec![0]
+}
+
+/// Builds a GET request for the given path
+fn get_request(settings: &Settings, path: String) -> http::Request<Vec<u8>> {
+    let mut builder = http::Request::builder()
+        .method(http::Method::GET)
+        .uri(path)
+        .header("Host", UpstreamAddr::parse(&settings.address).authority)
+        .header("Accept", "application/json");
+    if let Some(token) = read_token(settings) {
+        builder = builder.header("Authorization", format!("Bearer {}", token));
+    }
+    builder.body(Vec::new()).unwrap()
+}
+
+/// Builds the request that asks etcd for the keys under the prefix
+fn etcd_request(settings: &Settings) -> http::Request<Vec<u8>> {
+    let encode = |key: &[u8]| base64::engine::general_purpose::STANDARD.encode(key);
+    let prefix = settings.service.as_bytes();
+    let body = serde_json::json!({
+        "key": encode(prefix),
+        "range_end": encode(&prefix_range_end(prefix)),
+    })
+    .to_string()
+    .into_bytes();
+    http::Request::builder()
+        .method(http::Method::POST)
+        .uri("/v3/kv/range")
+        .header("Host", UpstreamAddr::parse(&settings.address).authority)
+        .header("Content-Type", "application/json")
+        .header("Content-Length", body.len().to_string())
+        .body(body)
+        .unwrap()
+}
+
+/// Reads the bearer token to send, if there is one
+fn read_token(settings: &Settings) -> Option<String> {
+    let path = settings.token_path.as_ref()?;
+    match std::fs::read_to_string(path) {
+        Ok(token) => Some(token.trim().to_string()),
+        Err(err) => {
+            log::warn!("Could not read token from {}: {}", path, err);
+            None
+        }
+    }
+}
+
+/// Sends a request to the discovery server and returns its response
+async fn send(
+    settings: &Settings,
+    connector: &tokio_rustls::TlsConnector,
+    request: &http::Request<Vec<u8>>,
+) -> Result<http::Response<Vec<u8>>, String> {
+    let exchange = async {
+        let mut stream = upstream::connect(&settings.address, connector).await?;
+        request::write_to_stream(request, &mut stream).await?;
+        response::read_from_stream(&mut stream, request.method())
+            .await
+            .map_err(|err| std::io::Error::other(format!("{:?}", err)))
+    };
+    match timeout(LOOKUP_TIMEOUT, exchange).await {
+        Ok(Ok(response)) => Ok(response),
+        Ok(Err(err)) => Err(format!("request to {} failed: {}", settings.address, err)),
+        Err(_) => Err(format!("request to {} timed out", settings.address)),
+    }
+}
+
+/// Returns the response's body, or an error if it doesn't have a successful status
+fn success_body(
+    settings: &Settings,
+    response: &http::Response<Vec<u8>>,
+) -> Result<Vec<u8>, String> {
+    if response.status().is_success() {
+        Ok(response.body().clone())
+    } else {
+        Err(format!("{} answered with {}", settings.address, response.status()))
+    }
+}
+
+/// Lists the service's EndpointSlices, or its Endpoints on clusters that predate them
+async fn list_k8s(
+    settings: &Settings,
+    connector: &tokio_rustls::TlsConnector,
+    service: &K8sService<'_>,
+) -> Result<K8sListing, String> {
+    let path = K8sApi::EndpointSlices.list_path(service);
+    let response = send(settings, connector, &get_request(settings, path)).await?;
+    if response.status() != http::StatusCode::NOT_FOUND {
+        let body = success_body(settings, &response)?;
+        return K8sListing::parse(K8sApi::EndpointSlices, &body, service);
+    }
+    let path = K8sApi::Endpoints.list_path(service);
+    let response = send(settings, connector, &get_request(settings, path)).await?;
+    K8sListing::parse(K8sApi::Endpoints, &success_body(settings, &response)?, service)
+}
+
+/// Watches the objects in the listing for changes, applying each one to the listing and switching
+/// over to the pods that are ready after it. Returns once the API server ends the watch, as it
+/// does after WATCH_TIMEOUT.
+async fn watch_k8s(
+    state: &ProxyState,
+    settings: &Settings,
+    connector: &tokio_rustls::TlsConnector,
+    service: &K8sService<'_>,
+    listing: &mut K8sListing,
+) -> Result<(), WatchError> {
+    let path = format!(
+        "{}&watch=true&allowWatchBookmarks=true&timeoutSeconds={}&resourceVersion={}",
+        listing.api.list_path(service),
+        WATCH_TIMEOUT.as_secs(),
+        listing.resource_version
+    );
+    let request = get_request(settings, path);
+    let failed = |err: String| {
+        WatchError::Failed(format!("watch on {} failed: {}", settings.address, err))
+    };
+    let start = async {
+        let mut stream = upstream::connect(&settings.address, connector).await?;
+        request::write_to_stream(&request, &mut stream).await?;
+        let (response, body) = response::read_head(&mut stream, request.method(), BytesMut::new())
+            .await
+            .map_err(|err| std::io::Error::other(format!("{:?}", err)))?;
+        Ok::<_, std::io::Error>((stream, response, body))
+    };
+    let (mut stream, response, mut body) = match timeout(LOOKUP_TIMEOUT, start).await {
+        Ok(Ok(started)) => started,
+        Ok(Err(err)) => return Err(failed(err.to_string())),
+        Err(_) => return Err(failed("timed out".to_string())),
+    };
+    if response.status() == http::StatusCode::GONE {
+        return Err(WatchError::Expired);
+    }
+    if !response.status().is_success() {
+        return Err(failed(format!("answered with {}", response.status())));
+    }
+
+    // Events come as JSON objects, one per line
+    body.set_read_timeout((WATCH_TIMEOUT + LOOKUP_TIMEOUT).as_secs() as usize);
+    let mut events = Vec::new();
+    loop {
+        let piece = match body.next(&mut stream).await {
+            Ok(Some(piece)) => piece,
+            Ok(None) => break,
+            Err(err) => return Err(failed(format!("{:?}", err))),
+        };
+        events.extend_from_slice(&piece);
+        while let Some(end) = events.iter().position(|byte| *byte == b'\n') {
+            let event: Vec<u8> = events.drain(..=end).collect();
+            listing.apply_event(&event, service)?;
+            update_instances(state, settings, listing.instances()).await;
+        }
+        if events.len() > MAX_EVENT_SIZE {
+            return Err(failed("event too large".to_string()));
+        }
+    }
+    if !events.iter().all(u8::is_ascii_whitespace) {
+        // Most likely not a watch at all, e.g. a proxy in front of the API server that doesn't
+        // support them and answered with the list
+        return Err(failed("ended partway through an event".to_string()));
+    }
+    Ok(())
+}
+
+/// Keeps the upstreams in sync with a Kubernetes service's ready pods: lists them, then watches
+/// for changes from there. A watch that ends is started again from where it left off, and one
+/// that has fallen too far behind lists the pods again. If listing or watching fails, the pods
+/// found last are kept and the pods are listed again after the interval.
+async fn sync_k8s(state: &ProxyState, settings: &Settings, connector: &tokio_rustls::TlsConnector) {
+    let service = match K8sService::parse(&settings.service) {
+        Ok(service) => service,
+        Err(err) => {
+            log::error!("Not watching Kubernetes: {}", err);
+            return;
+        }
+    };
+    let mut listing = None;
+    loop {
+        let mut current = match listing.take() {
+            Some(current) => current,
+            None => match list_k8s(settings, connector, &service).await {
+                Ok(current) => {
+                    update_instances(state, settings, current.instances()).await;
+                    current
+                }
+                Err(err) => {
+                    log::warn!("Keeping the current instances of {}: {}", settings.service, err);
+                    sleep(settings.interval).await;
+                    continue;
+                }
+            },
+        };
+        match watch_k8s(state, settings, connector, &service, &mut current).await {
+            Ok(()) => listing = Some(current),
+            Err(WatchError::Expired) => {
+                log::info!("Watch on {} fell behind, listing its pods again", settings.service);
+            }
+            Err(WatchError::Failed(err)) => {
+                log::warn!("Keeping the current instances of {}: {}", settings.service, err);
+                sleep(settings.interval).await;
+            }
+        }
+    }
+}
+
+/// Asks Consul, etcd or Kubernetes for the service's instances, returning their addresses in
+/// order
+pub async fn lookup(
+    settings: &Settings,
+    connector: &tokio_rustls::TlsConnector,
+) -> Result<Vec<String>, String> {
+    let mut instances = match settings.backend {
+        DiscoveryBackend::Consul => {
+            let path = format!("/v1/health/service/{}?passing=true", settings.service);
+            let response = send(settings, connector, &get_request(settings, path)).await?;
+            parse_consul(&success_body(settings, &response)?)?
+        }
+        DiscoveryBackend::Etcd => {
+            let response = send(settings, connector, &etcd_request(settings)).await?;
+            parse_etcd(&success_body(settings, &response)?)?
+        }
+        DiscoveryBackend::K8s => {
+            let service = K8sService::parse(&settings.service)?;
+            list_k8s(settings, connector, &service).await?.instances()
+        }
+    };
+    instances.sort();
+    instances.dedup();
+    Ok(instances)
+}
+
+/// Switches over to the given instances of the service, if they've changed and can be used
+async fn update_instances(state: &ProxyState, settings: &Settings, instances: Vec<String>) {
+    let mut current = state.config.write().await;
+    if current.discovered_upstreams == instances {
+        return;
+    }
+    let mut config = current.clone();
+    config.discovered_upstreams = instances;
+    if let Err(err) = config.validate() {
+        log::error!(
+            "Keeping the current instances of {}, the new ones are unusable: {}",
+            settings.service,
+            err
+        );
+        return;
+    }
+    log::info!(
+        "Instances of {} changed: {}",
+        settings.service,
+        config.discovered_upstreams.join(", ")
+    );
+    state.switch_config(&mut current, config).await;
+}
+
+/// Looks the service's instances up every interval (or, for Kubernetes, watches them), and
+/// switches over to them when they change. If a lookup fails, the instances found last time are
+/// kept, so that Consul or etcd being unreachable doesn't take every upstream away.
+pub async fn poll(
+    state: Arc<ProxyState>,
+    settings: Settings,
+    connector: tokio_rustls::TlsConnector,
+) {
+    if settings.backend == DiscoveryBackend::K8s {
+        sync_k8s(&state, &settings, &connector).await;
+        return;
+    }
+    loop {
+        sleep(settings.interval).await;
+        match lookup(&settings, &connector).await {
+            Ok(instances) => update_instances(&state, &settings, instances).await,
+            Err(err) => {
+                log::warn!("Keeping the current instances of {}: {}", settings.service, err);
+            }
+        }
+    }
+}
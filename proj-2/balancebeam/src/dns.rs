@@ -0,0 +1,79 @@
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use tokio::time::{sleep, Duration};
+use crate::upstream::UpstreamAddr;
+use crate::state::ProxyState;
+
+/// Addresses that each `--upstream` entry naming a host resolved to
+pub type ResolvedHosts = HashMap<String, Vec<SocketAddr>>;
+
+/// Turns the `--upstream` entries into the list of backends to send requests to, with one backend
+/// for each address a host name resolved to. Entries that haven't been resolved are used as they
+/// are.
+pub fn expand(upstreams: &[String], resolved: &ResolvedHosts) -> Vec<String> {
+    let mut backends = Vec::new();
+    for upstream in upstreams {
+        match resolved.get(upstream) {
+            Some(addrs) if !addrs.is_empty() => {
+                backends.extend(addrs.iter().map(|addr| format!("{}@{}", upstream, addr)))
+            }
+            _ => backends.push(upstream.clone()),
+        }
+    }
+    backends
+}
+
+/// Looks up the host names of the given entries. If a lookup fails, the entry keeps the addresses
+/// it resolved to last time.
+async fn resolve(upstreams: &[String], previous: &ResolvedHosts) -> ResolvedHosts {
+    let mut resolved = ResolvedHosts::new();
+    for upstream in upstreams {
+        let addr = UpstreamAddr::parse(upstream);
+        if addr.is_resolved() {
+            continue;
+        }
+        let lookup = tokio::net::lookup_host(addr.authority.as_str()).await;
+        match lookup {
+            Ok(addrs) => {
+                let mut addrs: Vec<SocketAddr> = addrs.collect();
+                addrs.sort();
+                addrs.dedup();
+                resolved.insert(upstream.clone(), addrs);
+            }
+            Err(err) => {
+                log::warn!("Failed to resolve upstream {}: {}", upstream, err);
+                if let Some(addrs) = previous.get(upstream) {
+                    resolved.insert(upstream.clone(), addrs.clone());
+                }
+            }
+        }
+    }
+    resolved
+}
+
+/// Resolves the upstreams' host names, and switches over to the new backends if any of the
+/// addresses changed
+pub async fn refresh(state: &ProxyState) {
+    let upstreams = state.config.read().await.all_upstreams();
+    let previous = state.resolved_hosts.read().await.clone();
+    let resolved = resolve(&upstreams, &previous).await;
+    if resolved != previous {
+        log::info!("Upstream DNS records changed, updating backends");
+        *state.resolved_hosts.write().await = resolved;
+        state.reapply_config().await;
+    }
+}
+
+/// Re-resolves the upstreams' host names every `--dns-refresh-interval` seconds
+pub async fn refresh_periodically(state: Arc<ProxyState>) {
+    loop {
+        let interval = state.dns_refresh_interval.load(Ordering::SeqCst) as u64;
+        // When re-resolving is turned off, check back in a while in case it gets turned on
+        sleep(Duration::from_secs(if interval > 0 { interval } else { 10 })).await;
+        if state.dns_refresh_interval.load(Ordering::SeqCst) > 0 {
+            refresh(&state).await;
+        }
+    }
+}
@@ -0,0 +1,120 @@
+use std::collections::{BTreeMap, HashMap};
+use serde::Deserialize;
+
+/// An operator-supplied page for one status code, read from a file or given inline in the config
+/// file. The page may contain `{{status}}`, `{{reason}}`, `{{request_id}}` and `{{upstream}}`,
+/// which are filled in for each response.
+#[derive(Clone, Debug, Default, PartialEq, Deserialize)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+pub struct ErrorPage {
+    pub file: Option<String>,
+    pub body: Option<String>,
+    /// Defaults to HTML for files ending in .html or .htm, and plain text otherwise
+    pub content_type: Option<String>,
+}
+
+/// Parses an --error-page argument, which is formatted like "502=/path/to/page.html"
+pub fn parse_error_page(arg: &str) -> Result<(u16, String), String> {
+    let (status, path) = match arg.split_once('=') {
+        Some((status, path)) if !path.is_empty() => (status, path),
+        _ => return Err(format!("expected <status>=<file>, got {:?}", arg)),
+    };
+    match parse_status(status) {
+        Some(status) => Ok((status, path.to_string())),
+        None => Err(format!("{:?} isn't an HTTP error status", status)),
+    }
+}
+
+/// Parses an HTTP error (4xx or 5xx) status code
+pub fn parse_status(status: &str) -> Option<u16> {
+    status.parse().ok().filter(|status| (400..600).contains(status))
+}
+
+/// Guesses a page's content type from its file name
+pub fn content_type_for(path: &str) -> &'static str {
+    if path.ends_with(".html") || path.ends_with(".htm") {
+        "text/html; charset=utf-8"
+    } else {
+        "text/plain; charset=utf-8"
+    }
+}
+
+/// Values filled into a page for one response
+pub struct Vars<'a> {
+    pub request_id: &'a str,
+    /// Upstream the request was being sent to, if one had been picked
+    pub upstream: Option<&'a str>,
+}
+
+struct Template {
+    content_type: String,
+    body: String,
+}
+
+/// The error pages in use, loaded and ready to fill in
+#[derive(Default)]
+pub struct ErrorPages {
+    templates: HashMap<u16, Template>,
+}
+
+impl ErrorPages {
+    /// Reads the pages' files. A page whose file can't be read is left out, so that status gets
+    /// the plain error message.
+    pub fn load(pages: &BTreeMap<u16, ErrorPage>) -> ErrorPages {
+        let mut templates = HashMap::new();
+        for (status, page) in pages {
+            let (body, default_type) = match (&page.body, &page.file) {
+                (Some(body), _) => (body.clone(), "text/plain; charset=utf-8"),
+                (None, Some(path)) => match std::fs::read_to_string(path) {
+                    Ok(body) => (body, content_type_for(path)),
+                    Err(err) => {
+                        log::warn!("Could not read error page {}: {}", path, err);
+                        continue;
+                    }
+                },
+                (None, None) => continue,
+            };
+            let content_type = page.content_type.as_deref().unwrap_or(default_type).to_string();
+            templates.insert(*status, Template { content_type, body });
+        }
+        ErrorPages { templates }
+    }
+
+    /// Makes an error response with the page for its status, or the plain error message if
+    /// there's no page for it
+    pub fn render(&self, status: http::StatusCode, vars: &Vars) -> http::Response<Vec<u8>> {
+        let template = match self.templates.get(&status.as_u16()) {
+            Some(template) => template,
+            None => return crate::response::make_http_error(status),
+        };
+        let body = template
+            .body
+            .replace("{{status}}", status.as_str())
+            .replace("{{reason}}", status.canonical_reason().unwrap_or(""))
+            .replace("{{request_id}}", vars.request_id)
+            .replace("{{upstream}}", vars.upstream.unwrap_or("-"))
+            .into_bytes();
+        let mut builder = http::Response::builder()
+            .status(status)
+            .header("Content-Type", template.content_type.as_str())
+            .header("Content-Length", body.len().to_string())
+            .version(http::Version::HTTP_11);
+        if let Ok(request_id) = http::HeaderValue::from_str(vars.request_id) {
+            builder = builder.header("X-Request-Id", request_id);
+        }
+        match builder.body(body) {
+            Ok(response) => response,
+            // The content type from the config isn't a valid header value
+            Err(_) => crate::response::make_http_error(status),
+        }
+    }
+}
+
+/// Returns the ID the client (or a proxy in front of us) gave the request, or makes one up
+pub fn request_id(request: Option<&http::Request<Vec<u8>>>) -> String {
+    request
+        .and_then(|request| request.headers().get("x-request-id"))
+        .and_then(|value| value.to_str().ok())
+        .map(String::from)
+        .unwrap_or_else(|| format!("{:016x}", rand::random::<u64>()))
+}
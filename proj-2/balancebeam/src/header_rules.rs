@@ -0,0 +1,140 @@
+use http::header::{HeaderMap, HeaderName, HeaderValue};
+use serde::Deserialize;
+
+/// Headers that say where a message's body ends. Changing them would have us read or write the
+/// body with the wrong framing, so rules may not touch them.
+const FRAMING_HEADERS: [HeaderName; 2] =
+    [http::header::CONTENT_LENGTH, http::header::TRANSFER_ENCODING];
+
+/// What a rule does to its header
+#[derive(Clone, Copy, Debug, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum HeaderAction {
+    /// Add a value, keeping any the message already has
+    Add,
+    /// Replace any values the message has with this one
+    Set,
+    /// Drop the header altogether
+    Remove,
+}
+
+/// Changes a header of each request before it's forwarded, or of each response before it's sent
+/// to the client. In a config file:
+///
+/// ```toml
+/// [[response-headers]]
+/// action = "set"
+/// name = "Strict-Transport-Security"
+/// value = "max-age=31536000"
+/// ```
+#[derive(Clone, Debug, PartialEq, Deserialize)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+pub struct HeaderRule {
+    pub action: HeaderAction,
+    pub name: String,
+    /// The value to add or set (not used for remove)
+    #[serde(default)]
+    pub value: Option<String>,
+}
+
+impl HeaderRule {
+    pub fn is_valid(&self) -> bool {
+        let name = match HeaderName::from_bytes(self.name.as_bytes()) {
+            Ok(name) => name,
+            Err(_) => return false,
+        };
+        if FRAMING_HEADERS.contains(&name) {
+            return false;
+        }
+        match (self.action, &self.value) {
+            (HeaderAction::Remove, None) => true,
+            (HeaderAction::Add, Some(value)) | (HeaderAction::Set, Some(value)) => {
+                HeaderValue::from_str(value).is_ok()
+            }
+            _ => false,
+        }
+    }
+}
+
+/// Parses a --request-header or --response-header argument, which is formatted like
+/// "set:Name=value", "add:Name=value" or "remove:Name"
+pub fn parse_header_rule(arg: &str) -> Result<HeaderRule, String> {
+    let (action, rest) = arg
+        .split_once(':')
+        .ok_or_else(|| format!("expected <add|set|remove>:<name>[=<value>], got {:?}", arg))?;
+    let action = match action {
+        "add" => HeaderAction::Add,
+        "set" => HeaderAction::Set,
+        "remove" => HeaderAction::Remove,
+        _ => return Err(format!("unknown header action {:?}: expected add, set or remove", action)),
+    };
+    let (name, value) = match rest.split_once('=') {
+        Some((name, value)) => (name, Some(value.to_string())),
+        None => (rest, None),
+    };
+    let rule = HeaderRule { action, name: name.to_string(), value };
+    if rule.is_valid() {
+        Ok(rule)
+    } else {
+        Err(format!("invalid header rule {:?}", arg))
+    }
+}
+
+/// A rule with its header name and value parsed, ready to apply
+#[derive(Clone, Debug)]
+struct Rule {
+    action: HeaderAction,
+    name: HeaderName,
+    value: Option<HeaderValue>,
+}
+
+/// Header rules for requests and for responses, applied in the order they were given
+#[derive(Clone, Debug, Default)]
+pub struct HeaderRules {
+    request: Vec<Rule>,
+    response: Vec<Rule>,
+}
+
+impl HeaderRules {
+    /// Prepares the rules for use. Invalid rules (which the config has been checked for) are
+    /// skipped.
+    pub fn new(request: &[HeaderRule], response: &[HeaderRule]) -> HeaderRules {
+        HeaderRules { request: compile(request), response: compile(response) }
+    }
+
+    pub fn apply_to_request(&self, request: &mut http::Request<Vec<u8>>) {
+        apply(&self.request, request.headers_mut());
+    }
+
+    pub fn apply_to_response(&self, response: &mut http::Response<Vec<u8>>) {
+        apply(&self.response, response.headers_mut());
+    }
+}
+
+fn compile(rules: &[HeaderRule]) -> Vec<Rule> {
+    rules
+        .iter()
+        .filter(|rule| rule.is_valid())
+        .map(|rule| Rule {
+            action: rule.action,
+            name: HeaderName::from_bytes(rule.name.as_bytes()).unwrap(),
+            value: rule.value.as_deref().and_then(|value| HeaderValue::from_str(value).ok()),
+        })
+        .collect()
+}
+
+fn apply(rules: &[Rule], headers: &mut HeaderMap) {
+    for rule in rules {
+        match (rule.action, &rule.value) {
+            (HeaderAction::Add, Some(value)) => {
+                headers.append(&rule.name, value.clone());
+            }
+            (HeaderAction::Set, Some(value)) => {
+                headers.insert(&rule.name, value.clone());
+            }
+            _ => {
+                headers.remove(&rule.name);
+            }
+        }
+    }
+}
@@ -0,0 +1,124 @@
+use std::process::Stdio;
+use std::time::SystemTime;
+use serde::Serialize;
+use tokio::process::Command;
+use tokio::time::{timeout, Duration};
+use crate::config::Config;
+use crate::upstream::{self, UpstreamAddr};
+use crate::{request, response};
+
+/// How long a webhook or command may take before it's given up on
+const HOOK_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// An upstream going down or coming back up, as sent to the webhook
+#[derive(Serialize)]
+struct Event<'a> {
+    upstream: &'a str,
+    /// "up" or "down"
+    state: &'static str,
+    /// When it happened, in RFC 3339 format
+    timestamp: String,
+}
+
+/// Where to report upstreams going down and coming back up, so that someone can be paged when a
+/// backend fails
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct HealthHooks {
+    /// http:// or https:// URL that a JSON `Event` is POSTed to
+    webhook: Option<String>,
+    /// Shell command that is run with the event in BALANCEBEAM_UPSTREAM, BALANCEBEAM_STATE and
+    /// BALANCEBEAM_TIMESTAMP
+    command: Option<String>,
+}
+
+impl HealthHooks {
+    pub fn from_config(config: &Config) -> HealthHooks {
+        HealthHooks {
+            webhook: config.health_webhook.clone(),
+            command: config.health_command.clone(),
+        }
+    }
+
+    /// Reports the upstream's new state to the webhook and command, if there are any. They're run
+    /// in the background, so a slow or broken hook never holds up requests or health checks.
+    pub fn notify(&self, connector: &tokio_rustls::TlsConnector, address: &str, healthy: bool) {
+        let state = if healthy { "up" } else { "down" };
+        let timestamp = humantime::format_rfc3339_millis(SystemTime::now()).to_string();
+        if let Some(url) = self.webhook.clone() {
+            let event = Event { upstream: address, state, timestamp: timestamp.clone() };
+            let body = serde_json::to_vec(&event).unwrap();
+            let connector = connector.clone();
+            tokio::spawn(async move {
+                match timeout(HOOK_TIMEOUT, send_webhook(&url, body, &connector)).await {
+                    Ok(Ok(())) => {}
+                    Ok(Err(err)) => log::warn!("Health webhook {} failed: {}", url, err),
+                    Err(_) => log::warn!("Health webhook {} timed out", url),
+                }
+            });
+        }
+        if let Some(command) = self.command.clone() {
+            let address = address.to_string();
+            tokio::spawn(async move {
+                let child = Command::new("sh")
+                    .arg("-c")
+                    .arg(&command)
+                    .env("BALANCEBEAM_UPSTREAM", address)
+                    .env("BALANCEBEAM_STATE", state)
+                    .env("BALANCEBEAM_TIMESTAMP", timestamp)
+                    .stdin(Stdio::null())
+                    .kill_on_drop(true)
+                    .status();
+                match timeout(HOOK_TIMEOUT, child).await {
+                    Ok(Ok(status)) if status.success() => {}
+                    Ok(Ok(status)) => log::warn!("Health command {:?} {}", command, status),
+                    Ok(Err(err)) => {
+                        log::warn!("Could not run health command {:?}: {}", command, err)
+                    }
+                    Err(_) => log::warn!("Health command {:?} timed out", command),
+                }
+            });
+        }
+    }
+}
+
+/// Splits a URL into the backend to connect to and the path to request
+fn split_url(url: &str) -> (&str, &str) {
+    let after_scheme = url.find("://").map_or(0, |idx| idx + 3);
+    match url[after_scheme..].find('/') {
+        Some(idx) => url.split_at(after_scheme + idx),
+        None => (url, "/"),
+    }
+}
+
+/// Returns true if the URL is one the webhook can be sent to
+pub fn is_valid_webhook(url: &str) -> bool {
+    (url.starts_with("http://") || url.starts_with("https://"))
+        && split_url(url).1.parse::<http::Uri>().is_ok()
+}
+
+async fn send_webhook(
+    url: &str,
+    body: Vec<u8>,
+    connector: &tokio_rustls::TlsConnector,
+) -> Result<(), String> {
+    let (backend, path) = split_url(url);
+    let request = http::Request::builder()
+        .method(http::Method::POST)
+        .uri(path)
+        .header("Host", UpstreamAddr::parse(backend).authority)
+        .header("Content-Type", "application/json")
+        .header("Content-Length", body.len().to_string())
+        .header("Connection", "close")
+        .body(body)
+        .map_err(|err| err.to_string())?;
+    let mut stream = upstream::connect(backend, connector).await.map_err(|err| err.to_string())?;
+    request::write_to_stream(&request, &mut stream).await.map_err(|err| err.to_string())?;
+    let response = response::read_from_stream(&mut stream, request.method())
+        .await
+        .map_err(|err| format!("{:?}", err))?;
+    if response.status().is_success() {
+        Ok(())
+    } else {
+        Err(format!("answered with {}", response.status()))
+    }
+}
@@ -0,0 +1,603 @@
+use std::collections::HashMap;
+use std::io::{self, ErrorKind};
+use std::net::{IpAddr, SocketAddr};
+use std::pin::Pin;
+use std::sync::atomic::Ordering;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+use bytes::Bytes;
+use futures_util::future::poll_fn;
+use h2::client::SendRequest;
+use h2::server::SendResponse;
+use h2::{RecvStream, SendStream};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, ReadBuf};
+use tokio::time::Instant;
+use tokio_rustls::TlsConnector;
+use tracing::Instrument;
+use crate::access_log::{Sizes, Timing};
+use crate::jwt::Claims;
+use crate::load_balance::RequestContext;
+use crate::middleware::{self, Action, ForcedUpstream};
+use crate::proxy_headers::{self, Frontend};
+use crate::upstream::{self, UpstreamAddr};
+use crate::connection::connect_with;
+use crate::state::ProxyState;
+use crate::{request_queue, with_timeout};
+
+/// What a client opens an HTTP/2 connection with. Clients that know we speak HTTP/2 (like gRPC
+/// clients talking to us without TLS) start with it right away.
+const PREFACE: &[u8] = b"PRI * HTTP/2.0\r\n\r\nSM\r\n\r\n";
+
+/// Headers that only make sense for a single HTTP/1.1 connection, which HTTP/2 doesn't allow
+const CONNECTION_HEADERS: &[&str] =
+    &["connection", "keep-alive", "proxy-connection", "transfer-encoding", "upgrade"];
+
+/// HTTP/2 connections to the upstreams, shared by all the requests going to each of them. Every
+/// request picks its own upstream, so the calls a gRPC client makes over its one connection to
+/// us are spread over the upstreams just like separate connections would be.
+pub struct Upstreams {
+    /// TLS client settings for connecting to https:// upstreams, asking them for HTTP/2
+    tls: TlsConnector,
+    /// Open connection to each upstream, by backend address
+    connections: Mutex<HashMap<String, SendRequest<Bytes>>>,
+}
+
+impl Upstreams {
+    pub fn new(tls: TlsConnector) -> Upstreams {
+        Upstreams { tls, connections: Mutex::new(HashMap::new()) }
+    }
+
+    /// Returns a connection to the backend that is ready to take another request, opening one if
+    /// there is none, or the one there was has closed
+    async fn connect(&self, backend: &str) -> io::Result<SendRequest<Bytes>> {
+        let open = self.connections.lock().unwrap().get(backend).cloned();
+        if let Some(connection) = open {
+            match connection.ready().await {
+                Ok(connection) => return Ok(connection),
+                Err(_) => {
+                    self.connections.lock().unwrap().remove(backend);
+                }
+            }
+        }
+        let stream = upstream::connect(backend, &self.tls).await?;
+        let (connection, driver) = h2::client::handshake(stream).await.map_err(into_io_error)?;
+        let address = backend.to_string();
+        tokio::spawn(async move {
+            if let Err(err) = driver.await {
+                log::info!("HTTP/2 connection to upstream {} failed: {}", address, err);
+            }
+        });
+        self.connections.lock().unwrap().insert(backend.to_string(), connection.clone());
+        connection.ready().await.map_err(into_io_error)
+    }
+}
+
+fn into_io_error(err: h2::Error) -> io::Error {
+    if err.is_io() {
+        err.into_io().unwrap()
+    } else {
+        io::Error::other(err)
+    }
+}
+
+/// Reads the start of a connection to see whether the client opens it the way HTTP/2 connections
+/// are opened. Returns whether it does, along with the bytes read, which are the start of the
+/// first request either way. Fails if reading takes longer than `timeout` seconds.
+pub async fn sniff<S: AsyncRead + Unpin>(
+    client_conn: &mut S,
+    timeout: usize,
+) -> io::Result<(bool, Vec<u8>)> {
+    let read = async {
+        let mut buffer = vec![0; PREFACE.len()];
+        let mut len = 0;
+        // An HTTP/1 request gives itself away within the first few bytes
+        while len < PREFACE.len() && buffer[..len] == PREFACE[..len] {
+            match client_conn.read(&mut buffer[len..]).await? {
+                0 => break,
+                n => len += n,
+            }
+        }
+        buffer.truncate(len);
+        Ok::<_, io::Error>((buffer == PREFACE, buffer))
+    };
+    with_timeout(timeout, read)
+        .await
+        .unwrap_or_else(|| Err(io::Error::new(ErrorKind::TimedOut, "timed out")))
+}
+
+/// A client connection with the bytes read off it to sniff out its protocol put back in front
+pub struct Rewound<S> {
+    start: Vec<u8>,
+    read: usize,
+    inner: S,
+}
+
+impl<S> Rewound<S> {
+    pub fn new(start: Vec<u8>, inner: S) -> Rewound<S> {
+        Rewound { start, read: 0, inner }
+    }
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for Rewound<S> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        if this.read < this.start.len() {
+            let len = buf.remaining().min(this.start.len() - this.read);
+            buf.put_slice(&this.start[this.read..this.read + len]);
+            this.read += len;
+            return Poll::Ready(Ok(()));
+        }
+        Pin::new(&mut this.inner).poll_read(cx, buf)
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for Rewound<S> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.get_mut().inner).poll_write(cx, buf)
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_shutdown(cx)
+    }
+}
+
+/// Where the requests on a client connection come from
+#[derive(Clone)]
+struct Client {
+    ip: IpAddr,
+    cert_subject: Option<String>,
+    frontend: Frontend,
+}
+
+/// Serves an HTTP/2 client connection, forwarding each request on it to an upstream picked for
+/// that request alone. Requests are forwarded over HTTP/2, with their bodies and trailers streamed
+/// through in both directions, so gRPC calls (streaming ones included) work end to end.
+pub async fn serve<S: AsyncRead + AsyncWrite + Unpin>(
+    client_conn: S,
+    client_addr: SocketAddr,
+    cert_subject: Option<String>,
+    frontend: Frontend,
+    state: Arc<ProxyState>,
+) {
+    let client = Client { ip: client_addr.ip(), cert_subject, frontend };
+    log::info!("HTTP/2 connection received from {}", client.ip);
+    let mut connection = match h2::server::handshake(client_conn).await {
+        Ok(connection) => connection,
+        Err(err) => {
+            log::info!("HTTP/2 handshake with {} failed: {}", client.ip, err);
+            return;
+        }
+    };
+    // When we start shutting down, the client is told not to send any more requests, and the
+    // connection is closed once the ones it already sent are answered
+    let shutdown = state.shutdown.notified();
+    tokio::pin!(shutdown);
+    let mut closing = state.shutting_down.load(Ordering::SeqCst);
+    if closing {
+        connection.graceful_shutdown();
+    }
+    loop {
+        let accepted = tokio::select! {
+            accepted = connection.accept() => accepted,
+            _ = &mut shutdown, if !closing => {
+                connection.graceful_shutdown();
+                closing = true;
+                continue;
+            }
+        };
+        match accepted {
+            Some(Ok((request, respond))) => {
+                let span = tracing::info_span!(
+                    "request",
+                    http.method = %request.method(),
+                    http.target = %request.uri(),
+                    client.address = %client.ip,
+                    upstream = tracing::field::Empty,
+                    http.status_code = tracing::field::Empty,
+                );
+                let stream = forward_stream(request, respond, client.clone(), state.clone());
+                tokio::spawn(stream.instrument(span));
+            }
+            Some(Err(err)) => {
+                log::info!("Error reading requests from {}: {}", client.ip, err);
+                return;
+            }
+            None => {
+                log::debug!("Client {} closed its HTTP/2 connection", client.ip);
+                return;
+            }
+        }
+    }
+}
+
+/// Returns true if the request is a gRPC call
+fn is_grpc<B>(request: &http::Request<B>) -> bool {
+    request
+        .headers()
+        .get(http::header::CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .is_some_and(|value| value.starts_with("application/grpc"))
+}
+
+/// Returns the gRPC status code a client should see for an HTTP error, as gRPC maps them
+fn grpc_status(status: http::StatusCode) -> u16 {
+    match status.as_u16() {
+        400 => 13, // INTERNAL
+        401 => 16, // UNAUTHENTICATED
+        403 => 7,  // PERMISSION_DENIED
+        404 => 12, // UNIMPLEMENTED
+        429 | 502 | 503 | 504 => 14, // UNAVAILABLE
+        _ => 2,    // UNKNOWN
+    }
+}
+
+fn strip_connection_headers(headers: &mut http::HeaderMap) {
+    for name in CONNECTION_HEADERS {
+        headers.remove(*name);
+    }
+    // TE is allowed, but only to say that trailers are welcome
+    if headers.get(http::header::TE).is_some_and(|value| value != "trailers") {
+        headers.remove(http::header::TE);
+    }
+}
+
+/// What a request moved over the client's connection, as far as can be told. The streams on an
+/// HTTP/2 connection are framed and flow controlled together, so only response bodies are counted.
+fn sizes(body: u64) -> Sizes {
+    Sizes { body, received: 0, sent: body }
+}
+
+/// Answers a request with a response of our own (an error, or one a middleware made), returning
+/// the number of body bytes sent. gRPC clients get the error as a gRPC status, in a response that
+/// is all headers, since the HTTP status means little to them.
+fn respond_locally(
+    respond: &mut SendResponse<Bytes>,
+    response: http::Response<Vec<u8>>,
+    grpc: bool,
+) -> u64 {
+    let (mut parts, body) = response.into_parts();
+    strip_connection_headers(&mut parts.headers);
+    parts.version = http::Version::HTTP_2;
+    let body = if grpc {
+        let status = parts.status;
+        parts.headers.remove(http::header::CONTENT_LENGTH);
+        parts.headers.insert(
+            http::header::CONTENT_TYPE,
+            http::HeaderValue::from_static("application/grpc"),
+        );
+        parts.headers.insert("grpc-status", grpc_status(status).into());
+        if let Some(reason) = status.canonical_reason() {
+            parts.headers.insert("grpc-message", http::HeaderValue::from_static(reason));
+        }
+        parts.status = http::StatusCode::OK;
+        Vec::new()
+    } else {
+        body
+    };
+    let head = http::Response::from_parts(parts, ());
+    let bytes = body.len() as u64;
+    let sent = respond.send_response(head, body.is_empty()).and_then(|mut stream| {
+        match body.is_empty() {
+            true => Ok(()),
+            false => stream.send_data(Bytes::from(body), true),
+        }
+    });
+    match sent {
+        Ok(()) => bytes,
+        Err(err) => {
+            log::warn!("Failed to send response to client: {}", err);
+            0
+        }
+    }
+}
+
+/// Passes a body on from one stream to another as it arrives, followed by its trailers (where
+/// a gRPC response carries its status), without sending faster than the receiving side's flow
+/// control allows. Returns the number of body bytes passed on. If either side fails, the
+/// receiving side is reset, so that it doesn't take a partial body for a whole one.
+async fn pipe(mut from: RecvStream, mut to: SendStream<Bytes>) -> Result<u64, h2::Error> {
+    let result = copy_body(&mut from, &mut to).await;
+    if result.is_err() {
+        to.send_reset(h2::Reason::CANCEL);
+    }
+    result
+}
+
+async fn copy_body(from: &mut RecvStream, to: &mut SendStream<Bytes>) -> Result<u64, h2::Error> {
+    let mut bytes = 0;
+    while let Some(data) = from.data().await {
+        let mut data = data?;
+        let len = data.len();
+        while !data.is_empty() {
+            to.reserve_capacity(data.len());
+            let capacity = match poll_fn(|cx| to.poll_capacity(cx)).await {
+                Some(capacity) => capacity?,
+                // The receiving side went away
+                None => return Err(h2::Reason::CANCEL.into()),
+            };
+            if capacity > 0 {
+                to.send_data(data.split_to(capacity.min(data.len())), false)?;
+            }
+        }
+        from.flow_control().release_capacity(len)?;
+        bytes += len as u64;
+    }
+    match from.trailers().await? {
+        Some(trailers) => to.send_trailers(trailers)?,
+        None => to.send_data(Bytes::new(), true)?,
+    }
+    Ok(bytes)
+}
+
+/// Answers a request with an error, and logs it
+async fn respond_with_error(
+    state: &ProxyState,
+    respond: &mut SendResponse<Bytes>,
+    client: &Client,
+    request: &http::Request<Vec<u8>>,
+    upstream: Option<&str>,
+    status: http::StatusCode,
+    timing: &Timing,
+) {
+    tracing::Span::current().record("http.status_code", status.as_u16());
+    let mut response = state.error_response(status, Some(request), upstream).await;
+    state.header_rules.read().await.apply_to_response(&mut response);
+    log::info!("{} <- {}", client.ip, status);
+    let bytes = respond_locally(respond, response, is_grpc(request));
+    state.log_request(client.ip, request, upstream, status, sizes(bytes), timing);
+}
+
+/// Forwards a request (one stream on the client's connection) to an upstream picked for it, and
+/// the upstream's response back. Requests aren't retried, since their bodies are passed on as
+/// they arrive.
+async fn forward_stream(
+    request: http::Request<RecvStream>,
+    mut respond: SendResponse<Bytes>,
+    mut client: Client,
+    state: Arc<ProxyState>,
+) {
+    let timing = Timing::start();
+    let (parts, request_body) = request.into_parts();
+    let mut request = http::Request::from_parts(parts, Vec::new());
+    // Everything else looks for the host in the Host header, as HTTP/1.1 clients send it
+    if let Some(authority) = request.uri().authority() {
+        if let Ok(host) = http::HeaderValue::from_str(authority.as_str()) {
+            request.headers_mut().entry(http::header::HOST).or_insert(host);
+        }
+    }
+    proxy_headers::set_client_cert_subject(&mut request, client.cert_subject.as_deref());
+    // Behind a trusted proxy, the client is whoever the proxy says sent the request
+    let peer_ip = client.ip;
+    client.ip = proxy_headers::client_ip(&request, peer_ip, &state.trusted_proxies.read().await);
+
+    // Requests go through the middlewares just like HTTP/1 ones
+    let middleware_context = middleware::Context {
+        client_ip: client.ip,
+        peer_ip,
+        frontend: client.frontend,
+        state: &state,
+    };
+    let action =
+        middleware::run_request(&state.middlewares, &middleware_context, &mut request).await;
+    if let Action::Respond(mut response) = action {
+        let status = response.status();
+        tracing::Span::current().record("http.status_code", status.as_u16());
+        state.header_rules.read().await.apply_to_response(&mut response);
+        log::info!("{} <- {}", client.ip, status);
+        let bytes = respond_locally(&mut respond, response, is_grpc(&request));
+        state.log_request(client.ip, &request, None, status, sizes(bytes), &timing);
+        return;
+    }
+    state.header_rules.read().await.apply_to_request(&mut request);
+
+    let (pool, policy) = {
+        let routes = state.routes.read().await;
+        let path = request.uri().path();
+        let claimed_pool = request
+            .extensions()
+            .get::<Claims>()
+            .and_then(|claims| claims.route.as_deref())
+            .and_then(|name| routes.pool_named(name));
+        let forced = request.extensions().get::<ForcedUpstream>().map(|forced| vec![forced.0]);
+        let pool = forced
+            .as_deref()
+            .or(claimed_pool)
+            .or_else(|| routes.pool_for_client(client.ip))
+            .unwrap_or_else(|| routes.pool_for(path, state.deployment.live()));
+        (pool.to_vec(), routes.policy_for(path))
+    };
+    let response_timeout = policy
+        .upstream_response_timeout
+        .unwrap_or_else(|| state.upstream_response_timeout.load(Ordering::SeqCst));
+    let pool = state.split_canary_traffic(pool).await;
+    let upstreams = state.http2.as_ref().expect("HTTP/2 client connection without HTTP/2 set up");
+    // Connect to an upstream with room for the request, waiting for one to have room if they're
+    // all too busy. One picked may fill up before the request claims a slot on it, in which case
+    // it waits again.
+    let (idx, address, connection, _in_flight) = loop {
+        if let Err(rejected) = request_queue::wait_for_room(&state, &pool, &[]).await {
+            log::warn!("No upstream has room for a request from {}: {:?}", client.ip, rejected);
+            let status = http::StatusCode::SERVICE_UNAVAILABLE;
+            tracing::Span::current().record("http.status_code", status.as_u16());
+            let mut response = state.error_response(status, Some(&request), None).await;
+            let retry_after = http::HeaderValue::from(request_queue::retry_after(&state));
+            response.headers_mut().insert(http::header::RETRY_AFTER, retry_after);
+            state.header_rules.read().await.apply_to_response(&mut response);
+            log::info!("{} <- {}", client.ip, status);
+            let bytes = respond_locally(&mut respond, response, is_grpc(&request));
+            state.log_request(client.ip, &request, None, status, sizes(bytes), &timing);
+            return;
+        }
+        let saturated = request_queue::saturated(&state, &pool).await;
+        let context = RequestContext {
+            client_ip: client.ip,
+            request: &request,
+            pool: &pool,
+            excluded: &saturated,
+        };
+        let connect = |address: String| async move { upstreams.connect(&address).await };
+        let (idx, address, connection) = match connect_with(&state, &context, connect).await {
+            Ok(connected) => connected,
+            Err(error) => {
+                log::error!("Failed to connect to an upstream for {}: {}", client.ip, error);
+                let status = if error.timed_out {
+                    http::StatusCode::GATEWAY_TIMEOUT
+                } else {
+                    http::StatusCode::BAD_GATEWAY
+                };
+                respond_with_error(&state, &mut respond, &client, &request, None, status, &timing)
+                    .await;
+                return;
+            }
+        };
+        if let Some(in_flight) = request_queue::claim(&state, &address) {
+            break (idx, address, connection, in_flight);
+        }
+    };
+    tracing::Span::current().record("upstream", address.as_str());
+    log::info!(
+        "{} -> {}: {} {} {:?}",
+        client.ip,
+        address,
+        request.method(),
+        request.uri(),
+        request.version()
+    );
+    if !state.wait_for_upstream_slot(&address).await {
+        log::warn!("Upstream {} is over its request rate limit", address);
+        let status = http::StatusCode::SERVICE_UNAVAILABLE;
+        let upstream = Some(address.as_str());
+        respond_with_error(&state, &mut respond, &client, &request, upstream, status, &timing)
+            .await;
+        return;
+    }
+    // Counts the request towards the upstream's load while it's in progress
+    let _active = state.upstream_connections.track(&address);
+
+    let attempt_started = Instant::now();
+    let response =
+        send_request(&state, connection, &address, &request, request_body, response_timeout)
+            .await;
+    let failed = match &response {
+        Ok(response) => response.status().is_server_error(),
+        Err(_) => true,
+    };
+    if failed {
+        state.record_upstream_failure(&address).await;
+    } else {
+        state.record_upstream_success(&address).await;
+    }
+    state.record_circuit_outcome(&address, !failed).await;
+    state.record_canary_outcome(idx, failed).await;
+    state.upstream_stats.record(&address, attempt_started.elapsed(), failed);
+
+    let response = match response {
+        Ok(response) => response,
+        Err(status) => {
+            let upstream = Some(address.as_str());
+            respond_with_error(&state, &mut respond, &client, &request, upstream, status, &timing)
+                .await;
+            return;
+        }
+    };
+    let (parts, response_body) = response.into_parts();
+    let mut response = http::Response::from_parts(parts, Vec::new());
+    let status = response.status();
+    tracing::Span::current().record("http.status_code", status.as_u16());
+    middleware::run_response(&state.middlewares, &middleware_context, &request, &mut response)
+        .await;
+    state.header_rules.read().await.apply_to_response(&mut response);
+    strip_connection_headers(response.headers_mut());
+    let (parts, _) = response.into_parts();
+    let head = http::Response::from_parts(parts, ());
+
+    // Pass the response on, body and trailers included
+    log::info!("{} <- {}", client.ip, status);
+    let end_of_stream = response_body.is_end_stream();
+    let sent = match respond.send_response(head, end_of_stream) {
+        Ok(_) if end_of_stream => Ok(0),
+        Ok(stream) => pipe(response_body, stream).await,
+        Err(err) => Err(err),
+    };
+    match sent {
+        Ok(bytes) => {
+            log::debug!("Forwarded response to client");
+            state.log_request(client.ip, &request, Some(&address), status, sizes(bytes), &timing);
+        }
+        Err(err) => log::info!("Failed to pass response from {} on to client: {}", address, err),
+    }
+}
+
+/// Sends a request to the upstream over HTTP/2, passing its body on as it arrives from the client
+/// in the background, and waits for the head of the upstream's response. Returns the status the
+/// client should be sent if that fails.
+async fn send_request(
+    state: &ProxyState,
+    mut connection: SendRequest<Bytes>,
+    address: &str,
+    request: &http::Request<Vec<u8>>,
+    request_body: RecvStream,
+    response_timeout: usize,
+) -> Result<http::Response<RecvStream>, http::StatusCode> {
+    // HTTP/2 requests carry the scheme and authority in the URI rather than a Host header
+    let authority = match request.uri().authority() {
+        Some(authority) => authority.to_string(),
+        None => UpstreamAddr::parse(address).authority,
+    };
+    let path = request.uri().path_and_query().map_or("/", |path| path.as_str());
+    let uri = format!("{}://{}{}", UpstreamAddr::parse(address).scheme(), authority, path);
+    let mut upstream_request = http::Request::builder()
+        .method(request.method())
+        .uri(uri)
+        .version(http::Version::HTTP_2)
+        .body(())
+        .map_err(|err| {
+            log::warn!("Could not make a request for upstream {}: {}", address, err);
+            http::StatusCode::BAD_REQUEST
+        })?;
+    *upstream_request.headers_mut() = request.headers().clone();
+    upstream_request.headers_mut().remove(http::header::HOST);
+    strip_connection_headers(upstream_request.headers_mut());
+
+    let end_of_stream = request_body.is_end_stream();
+    let (response, stream) =
+        connection.send_request(upstream_request, end_of_stream).map_err(|err| {
+            log::error!("Failed to send request to upstream {}: {}", address, err);
+            http::StatusCode::BAD_GATEWAY
+        })?;
+    if !end_of_stream {
+        let address = address.to_string();
+        tokio::spawn(async move {
+            if let Err(err) = pipe(request_body, stream).await {
+                log::info!("Failed to pass request body on to upstream {}: {}", address, err);
+            }
+        });
+    }
+    let sent_at = Instant::now();
+    match with_timeout(response_timeout, response).await {
+        Some(Ok(response)) => {
+            state.upstream_latencies.record(address, sent_at.elapsed());
+            Ok(response)
+        }
+        Some(Err(err)) => {
+            log::error!("Error reading response from upstream {}: {}", address, err);
+            Err(http::StatusCode::BAD_GATEWAY)
+        }
+        None => {
+            log::error!("Timed out waiting for a response from upstream {}", address);
+            Err(http::StatusCode::GATEWAY_TIMEOUT)
+        }
+    }
+}
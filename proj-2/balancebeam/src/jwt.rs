@@ -0,0 +1,113 @@
+use jsonwebtoken::{Algorithm, DecodingKey, Validation};
+use serde_json::{Map, Value};
+
+/// What balancebeam takes from a request's token once it's been validated. It is kept in the
+/// request's extensions, for routing and rate limiting to use.
+#[derive(Clone, Debug)]
+pub struct Claims {
+    /// Value of the claim that names the pool to send the request to
+    pub route: Option<String>,
+    /// Value of the claim that requests are rate limited by
+    pub rate_limit_key: Option<String>,
+}
+
+/// Settings for validating JSON Web Tokens
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Settings {
+    /// Shared secret for HS256-signed tokens
+    pub hs256_secret: Option<String>,
+    /// PEM file with the public key for RS256-signed tokens
+    pub rs256_public_key: Option<String>,
+    /// Issuer ("iss") tokens must have, if any
+    pub issuer: Option<String>,
+    /// Audience ("aud") tokens must have, if any
+    pub audience: Option<String>,
+    /// Claim whose value names the pool to send each request to
+    pub route_claim: Option<String>,
+    /// Claim that requests are rate limited by, when rate limiting by JWT
+    pub rate_limit_claim: String,
+    /// Realm to name when asking clients for a token
+    pub realm: String,
+}
+
+/// Returns the bearer token in the request's Authorization header, if it has one
+pub fn bearer_token(request: &http::Request<Vec<u8>>) -> Option<&str> {
+    request
+        .headers()
+        .get(http::header::AUTHORIZATION)
+        .and_then(|header| header.to_str().ok())
+        .and_then(|header| header.trim().split_once(' '))
+        .filter(|(scheme, _)| scheme.eq_ignore_ascii_case("Bearer"))
+        .map(|(_, token)| token.trim())
+}
+
+/// Checks the bearer tokens of requests: their signature, with the keys that have been set up,
+/// and their expiry time, issuer and audience
+pub struct JwtValidator {
+    keys: Vec<(Algorithm, DecodingKey)>,
+    issuer: Option<String>,
+    audience: Option<String>,
+    route_claim: Option<String>,
+    rate_limit_claim: String,
+    /// Name of the protected area, sent to clients asked for a token
+    pub realm: String,
+}
+
+impl JwtValidator {
+    /// Sets up validation with the keys in the settings. Returns None if there are none, i.e.
+    /// tokens aren't checked.
+    pub fn new(settings: &Settings) -> Result<Option<JwtValidator>, String> {
+        let mut keys = Vec::new();
+        if let Some(secret) = &settings.hs256_secret {
+            keys.push((Algorithm::HS256, DecodingKey::from_secret(secret.as_bytes())));
+        }
+        if let Some(path) = &settings.rs256_public_key {
+            let pem = std::fs::read(path).map_err(|err| err.to_string())?;
+            let key = DecodingKey::from_rsa_pem(&pem).map_err(|err| err.to_string())?;
+            keys.push((Algorithm::RS256, key));
+        }
+        if keys.is_empty() {
+            return Ok(None);
+        }
+        Ok(Some(JwtValidator {
+            keys,
+            issuer: settings.issuer.clone(),
+            audience: settings.audience.clone(),
+            route_claim: settings.route_claim.clone(),
+            rate_limit_claim: settings.rate_limit_claim.clone(),
+            realm: settings.realm.clone(),
+        }))
+    }
+
+    /// Validates a token, returning the claims the request's handling depends on, or why the
+    /// token isn't valid
+    pub fn validate(&self, token: &str) -> Result<Claims, String> {
+        let header = jsonwebtoken::decode_header(token).map_err(|err| err.to_string())?;
+        // The token says how it was signed, but only the algorithms we have keys for are accepted
+        let key = self
+            .keys
+            .iter()
+            .find(|(algorithm, _)| *algorithm == header.alg)
+            .map(|(_, key)| key)
+            .ok_or_else(|| format!("tokens signed with {:?} aren't accepted", header.alg))?;
+        let mut validation = Validation::new(header.alg);
+        match &self.audience {
+            Some(audience) => validation.set_audience(&[audience]),
+            None => validation.validate_aud = false,
+        }
+        if let Some(issuer) = &self.issuer {
+            validation.set_issuer(&[issuer]);
+        }
+        let data = jsonwebtoken::decode::<Map<String, Value>>(token, key, &validation)
+            .map_err(|err| err.to_string())?;
+        let claim = |name: &str| match data.claims.get(name) {
+            Some(Value::String(value)) => Some(value.clone()),
+            Some(Value::Number(value)) => Some(value.to_string()),
+            _ => None,
+        };
+        Ok(Claims {
+            route: self.route_claim.as_deref().and_then(claim),
+            rate_limit_key: claim(&self.rate_limit_claim),
+        })
+    }
+}
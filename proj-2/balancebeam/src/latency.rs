@@ -0,0 +1,37 @@
+use std::time::Duration;
+use dashmap::DashMap;
+
+/// How much a new measurement counts towards the average, compared to all the earlier ones
+const WEIGHT: f64 = 0.3;
+
+/// Keeps an exponentially weighted moving average of how long each upstream takes to respond, so
+/// that recent measurements count the most. Every response is recorded, so the averages are
+/// sharded rather than locked as a whole.
+pub struct Latencies {
+    /// Average time to the response headers, in seconds, for each upstream address
+    averages: DashMap<String, f64>,
+}
+
+impl Latencies {
+    pub fn new() -> Latencies {
+        Latencies { averages: DashMap::new() }
+    }
+
+    /// Records how long the upstream took to respond to a request
+    pub fn record(&self, address: &str, latency: Duration) {
+        let latency = latency.as_secs_f64();
+        if let Some(mut average) = self.averages.get_mut(address) {
+            *average = WEIGHT * latency + (1.0 - WEIGHT) * *average;
+            return;
+        }
+        self.averages
+            .entry(address.to_string())
+            .and_modify(|average| *average = WEIGHT * latency + (1.0 - WEIGHT) * *average)
+            .or_insert(latency);
+    }
+
+    /// Returns the upstream's average response time, or None if it hasn't responded yet
+    pub fn get(&self, address: &str) -> Option<Duration> {
+        self.averages.get(address).map(|average| Duration::from_secs_f64(*average))
+    }
+}
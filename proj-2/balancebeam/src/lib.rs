@@ -0,0 +1,582 @@
+//! balancebeam is an HTTP load balancer. Besides running it from the command line, other programs
+//! can embed it: set up a `Proxy` with the upstreams and settings to use, and `run()` it.
+
+mod request;
+mod response;
+mod rate_limiter;
+mod load_balance;
+mod config;
+mod body;
+mod buffer_pool;
+mod canary;
+mod chunked;
+mod compression;
+mod cors;
+mod error_pages;
+mod header_rules;
+mod tls;
+mod upstream;
+mod admin;
+mod passive_health;
+mod active_health;
+mod health_hooks;
+mod circuit_breaker;
+mod routing;
+mod blue_green;
+mod proxy_headers;
+mod connection_limit;
+mod access_control;
+mod auth;
+mod jwt;
+mod access_log;
+mod ban_list;
+mod upstream_limit;
+mod dns;
+mod upstreams_file;
+mod tcp_proxy;
+mod http2;
+mod latency;
+mod upstream_stats;
+mod bandwidth;
+mod mirror;
+mod redirect;
+mod active_connections;
+mod request_queue;
+mod response_cache;
+mod slow_client;
+mod listener;
+mod statsd;
+#[cfg(feature = "otel")]
+mod telemetry;
+#[cfg(feature = "discovery")]
+mod discovery;
+mod upgrade;
+mod state;
+mod connection;
+mod tasks;
+pub mod middleware;
+
+use std::{fmt, future::Future, sync::Arc};
+use std::os::unix::io::AsRawFd;
+use tokio::{sync::RwLock, time::Duration};
+use tokio::signal::unix::{signal, SignalKind};
+use crate::access_log::{AccessLog, Rotation};
+use crate::connection::accept_connections;
+use crate::connection_limit::ConnectionLimits;
+use crate::jwt::JwtValidator;
+use crate::proxy_headers::Frontend;
+use crate::state::ProxyState;
+use crate::tasks::{half_open_circuits, limiter_refresh, reload_on_sighup, report_drained};
+
+pub use crate::access_control::Cidr;
+pub use crate::access_log::Rotation as AccessLogRotation;
+pub use crate::active_health::StatusRange;
+pub use crate::canary::parse_canary;
+pub use crate::config::{Config, Error as ConfigError};
+pub use crate::error_pages::{parse_error_page, ErrorPage};
+pub use crate::load_balance::ArgLoadBalance;
+pub use crate::middleware::Middleware;
+pub use crate::proxy_headers::ProxyHeader;
+pub use crate::rate_limiter::{ArgRateLimiter, RateLimitBy};
+pub use crate::header_rules::{parse_header_rule, HeaderRule};
+pub use crate::redirect::{parse_redirect, RedirectRule};
+pub use crate::statsd::Settings as StatsdSettings;
+pub use crate::routing::{parse_key_value, RoutePolicy};
+pub use crate::tcp_proxy::ProxyMode;
+pub use crate::tls::Error as TlsError;
+#[cfg(feature = "otel")]
+pub use crate::telemetry::Telemetry;
+#[cfg(feature = "discovery")]
+pub use crate::discovery::{parse_discover, DiscoveryBackend, Settings as DiscoverySettings};
+
+/// Why the proxy couldn't be started
+#[derive(Debug)]
+pub enum Error {
+    /// The settings (including the config file, if any) aren't valid
+    Config(config::Error),
+    /// The certificate or key for serving HTTPS, or the CA certificates for connecting to
+    /// upstreams, couldn't be loaded
+    Tls(tls::Error),
+    /// Couldn't listen on the given address
+    Bind(String, std::io::Error),
+    /// The access log couldn't be opened
+    AccessLog(std::io::Error),
+    /// Couldn't listen for the signals that control upgrades and shutting down
+    Signal(std::io::Error),
+    /// The statsd server's address couldn't be resolved, or no socket could be opened to it
+    Statsd(std::io::Error),
+    /// The key for checking bearer tokens couldn't be loaded
+    Jwt(String),
+    /// A request filtering script couldn't be read or doesn't parse
+    #[cfg(feature = "scripting")]
+    Script(String, Box<rhai::EvalAltResult>),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Config(err) => write!(f, "could not load configuration: {}", err),
+            Error::Tls(err) => write!(f, "could not set up TLS: {}", err),
+            Error::Bind(addr, err) => write!(f, "could not bind to {}: {}", addr, err),
+            Error::AccessLog(err) => write!(f, "could not open access log: {}", err),
+            Error::Signal(err) => write!(f, "could not listen for signals: {}", err),
+            Error::Statsd(err) => write!(f, "could not set up statsd: {}", err),
+            Error::Jwt(err) => write!(f, "could not load JWT key: {}", err),
+            #[cfg(feature = "scripting")]
+            Error::Script(path, err) => write!(f, "could not load script {}: {}", path, err),
+        }
+    }
+}
+
+/// Where the proxy listens for requests if no address is given
+pub const DEFAULT_BIND: &str = "0.0.0.0:1100";
+
+/// A load balancer that can be set up and run from another program. The settings that can be
+/// changed while it's running live in its `Config`; the rest are set with the methods below.
+///
+/// ```no_run
+/// # async fn run() -> Result<(), balancebeam::Error> {
+/// balancebeam::Proxy::new(vec!["127.0.0.1:8080".into(), "127.0.0.1:8081".into()])
+///     .bind("127.0.0.1:1100")
+///     .load_balancer(balancebeam::ArgLoadBalance::P2c)
+///     .run()
+///     .await
+/// # }
+/// ```
+pub struct Proxy {
+    config: Config,
+    config_path: Option<String>,
+    binds: Vec<String>,
+    tls: Option<(String, String)>,
+    tls_client_ca: Option<String>,
+    tls_auto_detect: bool,
+    upstream_ca_cert: Option<String>,
+    upstream_client_cert: Option<(String, String)>,
+    admin_bind: Option<String>,
+    max_connections: usize,
+    max_connections_per_ip: usize,
+    access_log: Option<String>,
+    access_log_rotation: Rotation,
+    middlewares: Vec<Arc<dyn Middleware>>,
+    mode: ProxyMode,
+    http2: bool,
+    statsd: Option<statsd::Settings>,
+    #[cfg(feature = "scripting")]
+    scripts: Vec<String>,
+    #[cfg(feature = "discovery")]
+    discovery: Option<discovery::Settings>,
+}
+
+impl Proxy {
+    /// Creates a proxy for the given upstreams, with the same defaults as the command line
+    pub fn new(upstreams: Vec<String>) -> Proxy {
+        Proxy::with_config(Config::new(upstreams))
+    }
+
+    /// Creates a proxy with the given settings
+    pub fn with_config(config: Config) -> Proxy {
+        Proxy {
+            config,
+            config_path: None,
+            binds: Vec::new(),
+            tls: None,
+            tls_client_ca: None,
+            tls_auto_detect: false,
+            upstream_ca_cert: None,
+            upstream_client_cert: None,
+            admin_bind: None,
+            max_connections: 0,
+            max_connections_per_ip: 0,
+            access_log: None,
+            access_log_rotation: Rotation::default(),
+            middlewares: Vec::new(),
+            mode: ProxyMode::Http,
+            http2: false,
+            statsd: None,
+            #[cfg(feature = "scripting")]
+            scripts: Vec::new(),
+            #[cfg(feature = "discovery")]
+            discovery: None,
+        }
+    }
+
+    /// Settings that can also be changed while the proxy is running
+    pub fn config_mut(&mut self) -> &mut Config {
+        &mut self.config
+    }
+
+    /// Applies a config file on top of the settings, and reloads it on SIGHUP
+    pub fn config_file(mut self, path: &str) -> Proxy {
+        self.config_path = Some(path.to_string());
+        self
+    }
+
+    /// IP/port to listen for requests on, or "unix:/path/to.sock" for a Unix domain socket. Call
+    /// this more than once to listen on several addresses; if it isn't called, the proxy listens
+    /// on `DEFAULT_BIND`.
+    pub fn bind(mut self, address: &str) -> Proxy {
+        self.binds.push(address.to_string());
+        self
+    }
+
+    pub fn load_balancer(mut self, load_balancer: ArgLoadBalance) -> Proxy {
+        self.config.load_balancer = load_balancer;
+        self
+    }
+
+    /// Limits each client to this many requests per minute (0 = unlimited)
+    pub fn rate_limit(mut self, limiter: ArgRateLimiter, max_requests_per_minute: usize) -> Proxy {
+        self.config.rate_limiter = limiter;
+        self.config.max_requests_per_minute = max_requests_per_minute;
+        self
+    }
+
+    /// Limits how many client connections may be open at once, in total and from each IP
+    /// address (0 = unlimited)
+    pub fn max_connections(mut self, total: usize, per_ip: usize) -> Proxy {
+        self.max_connections = total;
+        self.max_connections_per_ip = per_ip;
+        self
+    }
+
+    /// Serves HTTPS with the given PEM-encoded certificate chain and private key
+    pub fn tls(mut self, cert_path: &str, key_path: &str) -> Proxy {
+        self.tls = Some((cert_path.to_string(), key_path.to_string()));
+        self
+    }
+
+    /// Requires clients to present a certificate signed by one of the CA certificates in this PEM
+    /// file. Only takes effect along with `tls`. Requests are forwarded with the subject of the
+    /// client's certificate in the X-Client-Cert-Subject header.
+    pub fn tls_client_ca(mut self, path: &str) -> Proxy {
+        self.tls_client_ca = Some(path.to_string());
+        self
+    }
+
+    /// Serves plaintext clients as well as TLS ones on the same listeners, telling them apart by
+    /// whether they open the connection with a TLS handshake, so that clients can move over to
+    /// TLS a few at a time. Only takes effect along with `tls`.
+    pub fn tls_auto_detect(mut self) -> Proxy {
+        self.tls_auto_detect = true;
+        self
+    }
+
+    /// Trusts the CA certificates in this PEM file when connecting to https:// upstreams
+    pub fn upstream_ca_cert(mut self, path: &str) -> Proxy {
+        self.upstream_ca_cert = Some(path.to_string());
+        self
+    }
+
+    /// Presents the given PEM-encoded certificate chain and private key to https:// upstreams that
+    /// ask for a client certificate
+    pub fn upstream_client_cert(mut self, cert_path: &str, key_path: &str) -> Proxy {
+        self.upstream_client_cert = Some((cert_path.to_string(), key_path.to_string()));
+        self
+    }
+
+    /// Serves the admin API on this IP/port
+    pub fn admin_bind(mut self, address: &str) -> Proxy {
+        self.admin_bind = Some(address.to_string());
+        self
+    }
+
+    /// Writes a JSON line to this file for each request, or to stdout if the path is "-"
+    pub fn access_log(mut self, path: &str) -> Proxy {
+        self.access_log = Some(path.to_string());
+        self
+    }
+
+    /// Rolls the access log file over by size or by day, instead of letting it grow forever
+    pub fn access_log_rotation(mut self, rotation: AccessLogRotation) -> Proxy {
+        self.access_log_rotation = rotation;
+        self
+    }
+
+    /// Proxies raw TCP connections instead of HTTP requests, for protocols like Redis or Postgres.
+    /// Load balancing, health checks and connection limits still apply, but everything that works
+    /// on requests (routes, rate limits per request, middlewares, etc.) doesn't.
+    pub fn mode(mut self, mode: ProxyMode) -> Proxy {
+        self.mode = mode;
+        self
+    }
+
+    /// Accepts HTTP/2 connections as well as HTTP/1 ones, so that gRPC clients can be served:
+    /// offered through ALPN when serving HTTPS, and recognized by how they start otherwise. Each
+    /// request on an HTTP/2 connection is sent to an upstream of its own over HTTP/2, so the
+    /// upstreams have to speak it too.
+    pub fn http2(mut self) -> Proxy {
+        self.http2 = true;
+        self
+    }
+
+    /// Pushes request counts and latencies, and upstreams going down and coming back up, to a
+    /// statsd server (or Datadog agent) over UDP
+    pub fn statsd(mut self, settings: StatsdSettings) -> Proxy {
+        self.statsd = Some(settings);
+        self
+    }
+
+    /// Adds a middleware to the end of the chain each request and response passes through. The
+    /// built-in rate limiting and proxy headers always run first.
+    pub fn middleware(mut self, middleware: Arc<dyn Middleware>) -> Proxy {
+        self.middlewares.push(middleware);
+        self
+    }
+
+    /// Runs each request past the Rhai script at this path, which can let it through, turn it
+    /// away or rewrite it (see `middleware::Script` for how). Scripts run in the order they were
+    /// added, after the built-in middlewares and before any added with `middleware`.
+    #[cfg(feature = "scripting")]
+    pub fn script(mut self, path: &str) -> Proxy {
+        self.scripts.push(path.to_string());
+        self
+    }
+
+    /// Adds the healthy instances of a service registered in Consul or etcd to the upstreams, and
+    /// keeps them up to date as instances come and go
+    #[cfg(feature = "discovery")]
+    pub fn discovery(mut self, settings: DiscoverySettings) -> Proxy {
+        self.discovery = Some(settings);
+        self
+    }
+
+    /// Starts the proxy, and serves requests until it can't accept connections anymore
+    pub async fn run(self) -> Result<(), Error> {
+        let upstream_client_cert = self
+            .upstream_client_cert
+            .as_ref()
+            .map(|(cert_path, key_path)| (cert_path.as_str(), key_path.as_str()));
+        let upstream_tls =
+            tls::make_connector(self.upstream_ca_cert.as_deref(), upstream_client_cert)
+                .map_err(Error::Tls)?;
+        let http2_upstreams = if self.http2 {
+            let connector =
+                tls::make_http2_connector(self.upstream_ca_cert.as_deref(), upstream_client_cert)
+                    .map_err(Error::Tls)?;
+            Some(http2::Upstreams::new(connector))
+        } else {
+            None
+        };
+
+        let base_config = self.config;
+        #[allow(unused_mut)]
+        let mut initial_config = base_config.clone();
+        #[cfg(feature = "discovery")]
+        let discovery = match self.discovery {
+            Some(settings) => {
+                let connector = match &settings.ca_cert_path {
+                    Some(path) => tls::make_connector(Some(path), None).map_err(Error::Tls)?,
+                    None => upstream_tls.clone(),
+                };
+                // Start out with the instances there are now, rather than waiting for the first
+                // poll
+                match discovery::lookup(&settings, &connector).await {
+                    Ok(instances) => initial_config.discovered_upstreams = instances,
+                    Err(err) => log::warn!("Could not look up {}: {}", settings.service, err),
+                }
+                Some((settings, connector))
+            }
+            None => None,
+        };
+        let config = match &self.config_path {
+            Some(path) => initial_config.with_file(path),
+            None => initial_config.with_upstreams_file(),
+        };
+        let config = config.map_err(Error::Config)?;
+
+        let tls_acceptor = match &self.tls {
+            Some((cert_path, key_path)) => {
+                let client_ca = self.tls_client_ca.as_deref();
+                let http2 = self.http2 && self.mode == ProxyMode::Http;
+                let acceptor = tls::make_acceptor(cert_path, key_path, client_ca, http2);
+                Some(acceptor.map_err(Error::Tls)?)
+            }
+            None => None,
+        };
+
+        // Start listening for connections, on the sockets of the process we're taking over from if
+        // this is an upgrade
+        let mut inherited = upgrade::Inherited::from_env();
+        let binds = if self.binds.is_empty() { vec![DEFAULT_BIND.to_string()] } else { self.binds };
+        let mut listeners = Vec::new();
+        let mut listener_fds = Vec::new();
+        for bind in &binds {
+            let listener = inherited
+                .bind(bind, listener::is_v6_only(bind, &binds))
+                .await
+                .map_err(|err| Error::Bind(bind.clone(), err))?;
+            listener_fds.push((bind.clone(), listener.as_raw_fd()));
+            let protocol = match (self.mode, tls_acceptor.is_some(), self.tls_auto_detect) {
+                (ProxyMode::Http, false, _) => "HTTP requests",
+                (ProxyMode::Http, true, false) => "HTTPS requests",
+                (ProxyMode::Http, true, true) => "HTTP and HTTPS requests",
+                (ProxyMode::Tcp, false, _) => "TCP connections",
+                (ProxyMode::Tcp, true, false) => "TLS connections",
+                (ProxyMode::Tcp, true, true) => "TCP and TLS connections",
+            };
+            log::info!("Listening for {} on {}", protocol, bind);
+            listeners.push(listener);
+        }
+
+        // Handle incoming connections
+        let connection_limits =
+            ConnectionLimits::new(self.max_connections, self.max_connections_per_ip);
+        let rotation = &self.access_log_rotation;
+        let access_log = self
+            .access_log
+            .as_deref()
+            .map(|path| AccessLog::open(path, rotation))
+            .transpose()
+            .map_err(Error::AccessLog)?;
+        let statsd = self
+            .statsd
+            .as_ref()
+            .map(statsd::Statsd::connect)
+            .transpose()
+            .map_err(Error::Statsd)?;
+        let jwt_validator = JwtValidator::new(&config.jwt_settings()).map_err(Error::Jwt)?;
+        #[cfg(feature = "scripting")]
+        let middlewares = {
+            let mut middlewares: Vec<Arc<dyn Middleware>> = Vec::new();
+            for path in &self.scripts {
+                let script = middleware::Script::load(path)
+                    .map_err(|err| Error::Script(path.clone(), err))?;
+                middlewares.push(Arc::new(script));
+            }
+            middlewares.extend(self.middlewares);
+            middlewares
+        };
+        #[cfg(not(feature = "scripting"))]
+        let middlewares = self.middlewares;
+        let mut state = ProxyState::new(
+            &config,
+            base_config,
+            self.config_path.clone(),
+            upstream_tls,
+            connection_limits,
+            access_log,
+            middlewares,
+        );
+        state.mode = self.mode;
+        state.http2 = http2_upstreams;
+        state.statsd = statsd;
+        state.jwt_validator = RwLock::new(jwt_validator);
+        let shared_state = Arc::new(state);
+        if config.dns_refresh_interval > 0 {
+            dns::refresh(&shared_state).await;
+        }
+        shared_state.update_drained(&[], &config.drain).await;
+
+        if let Some(admin_bind) = &self.admin_bind {
+            let admin_listener = inherited
+                .bind(admin_bind, false)
+                .await
+                .map_err(|err| Error::Bind(admin_bind.clone(), err))?;
+            listener_fds.push((admin_bind.clone(), admin_listener.as_raw_fd()));
+            log::info!("Serving admin API on {}", admin_bind);
+            let shared_state_ref = shared_state.clone();
+            tokio::spawn(async move {
+                admin::serve(admin_listener, shared_state_ref).await;
+            });
+        }
+
+        let shared_state_ref = shared_state.clone();
+        tokio::spawn(async move {
+            active_health::active_health_check(shared_state_ref).await;
+        });
+
+        let shared_state_ref = shared_state.clone();
+        tokio::spawn(async move {
+            limiter_refresh(shared_state_ref, 60).await;
+        });
+
+        let shared_state_ref = shared_state.clone();
+        tokio::spawn(async move {
+            reload_on_sighup(shared_state_ref).await;
+        });
+
+        let shared_state_ref = shared_state.clone();
+        tokio::spawn(async move {
+            half_open_circuits(shared_state_ref, 1).await;
+        });
+
+        let shared_state_ref = shared_state.clone();
+        tokio::spawn(async move {
+            report_drained(shared_state_ref).await;
+        });
+
+        let shared_state_ref = shared_state.clone();
+        tokio::spawn(async move {
+            dns::refresh_periodically(shared_state_ref).await;
+        });
+
+        let shared_state_ref = shared_state.clone();
+        tokio::spawn(async move {
+            upstream_stats::log_periodically(shared_state_ref).await;
+        });
+
+        let shared_state_ref = shared_state.clone();
+        tokio::spawn(async move {
+            upstreams_file::watch(shared_state_ref).await;
+        });
+
+        #[cfg(feature = "discovery")]
+        if let Some((settings, connector)) = discovery {
+            let shared_state_ref = shared_state.clone();
+            tokio::spawn(async move {
+                discovery::poll(shared_state_ref, settings, connector).await;
+            });
+        }
+
+        // Each listener gets a task of its own to accept connections on it. SIGUSR2 starts a new
+        // process to take over from us, and SIGQUIT (which that process sends once it's running)
+        // makes us stop accepting connections and finish up
+        let mut accept_loops = tokio::task::JoinSet::new();
+        for listener in listeners {
+            let frontend = Frontend { port: listener.port(), https: tls_acceptor.is_some() };
+            accept_loops.spawn(accept_connections(
+                listener,
+                frontend,
+                tls_acceptor.clone(),
+                self.tls_auto_detect,
+                shared_state.clone(),
+            ));
+        }
+        let mut upgrades = signal(SignalKind::user_defined2()).map_err(Error::Signal)?;
+        let mut quits = signal(SignalKind::quit()).map_err(Error::Signal)?;
+        inherited.done();
+        loop {
+            tokio::select! {
+                finished = accept_loops.join_next() => {
+                    if finished.is_none() {
+                        // None of the listeners can accept connections anymore
+                        break;
+                    }
+                }
+                _ = upgrades.recv() => {
+                    match upgrade::spawn_successor(&listener_fds) {
+                        Ok(pid) => log::info!("Started process {} to take over", pid),
+                        Err(err) => log::error!("Could not start a process to take over: {}", err),
+                    }
+                }
+                _ = quits.recv() => {
+                    log::info!("Received SIGQUIT, no longer accepting connections");
+                    break;
+                }
+            }
+        }
+        // Closes the listeners
+        accept_loops.shutdown().await;
+        shared_state.finish_connections().await;
+        Ok(())
+    }
+}
+
+/// Runs the future, giving up on it after the given number of seconds (0 = never). Returns None
+/// if it took too long.
+async fn with_timeout<F: Future>(seconds: usize, future: F) -> Option<F::Output> {
+    if seconds == 0 {
+        return Some(future.await);
+    }
+    tokio::time::timeout(Duration::from_secs(seconds as u64), future).await.ok()
+}
@@ -0,0 +1,185 @@
+use std::io;
+use std::net::{Ipv4Addr, SocketAddr};
+use std::os::unix::io::{AsRawFd, FromRawFd, RawFd};
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::net::{TcpListener, TcpSocket, TcpStream, UnixListener, UnixStream};
+
+/// Clients connecting over a Unix domain socket have no address of their own, so they're treated
+/// as connecting from localhost (which is where they are) for access lists, rate limits and logs
+const UNIX_CLIENT_ADDR: SocketAddr = SocketAddr::new(std::net::IpAddr::V4(Ipv4Addr::LOCALHOST), 0);
+
+/// Returns the socket path if the address names a Unix domain socket, e.g. "unix:/run/app.sock"
+pub fn unix_path(address: &str) -> Option<&str> {
+    address.strip_prefix("unix:")
+}
+
+/// Returns true if the address is an IPv6 one that should only take IPv6 connections, because
+/// another of the addresses we listen on is an IPv4 one with the same port. Otherwise, an IPv6
+/// address such as [::]:80 takes IPv4 connections to its port, too.
+pub fn is_v6_only(address: &str, binds: &[String]) -> bool {
+    let port = match address.parse() {
+        Ok(SocketAddr::V6(addr)) => addr.port(),
+        _ => return false,
+    };
+    binds.iter().any(|bind| matches!(bind.parse(), Ok(SocketAddr::V4(addr)) if addr.port() == port))
+}
+
+/// Binds a TCP listener to an IPv6 address, choosing whether it also accepts IPv4 connections
+/// rather than leaving that to the system's default
+fn bind_v6(addr: SocketAddr, v6_only: bool) -> io::Result<TcpListener> {
+    let socket = TcpSocket::new_v6()?;
+    let value: libc::c_int = v6_only.into();
+    // Safety: the option value is a c_int that lives across the call, as setsockopt expects
+    let result = unsafe {
+        libc::setsockopt(
+            socket.as_raw_fd(),
+            libc::IPPROTO_IPV6,
+            libc::IPV6_V6ONLY,
+            &value as *const libc::c_int as *const libc::c_void,
+            std::mem::size_of::<libc::c_int>() as libc::socklen_t,
+        )
+    };
+    if result != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    socket.set_reuseaddr(true)?;
+    socket.bind(addr)?;
+    socket.listen(1024)
+}
+
+/// A socket that accepts client connections: either a TCP one bound to an IP and port, or a Unix
+/// domain socket bound to a path
+pub enum Listener {
+    Tcp(TcpListener),
+    Unix(UnixListener),
+}
+
+impl Listener {
+    /// Binds to the address, which is either "ip:port" or "unix:/path". A socket file left behind
+    /// by a process that's no longer running is replaced. See `is_v6_only` for `v6_only`.
+    pub async fn bind(address: &str, v6_only: bool) -> io::Result<Listener> {
+        let path = match unix_path(address) {
+            Some(path) => path,
+            None => {
+                let listener = match address.parse() {
+                    Ok(addr @ SocketAddr::V6(_)) => bind_v6(addr, v6_only)?,
+                    _ => TcpListener::bind(address).await?,
+                };
+                return Ok(Listener::Tcp(listener));
+            }
+        };
+        match UnixListener::bind(path) {
+            Ok(listener) => Ok(Listener::Unix(listener)),
+            Err(err) if err.kind() == io::ErrorKind::AddrInUse => {
+                // Only take the path over if nothing is accepting connections on it
+                if std::os::unix::net::UnixStream::connect(path).is_ok() {
+                    return Err(err);
+                }
+                log::info!("Removing stale socket {}", path);
+                std::fs::remove_file(path)?;
+                Ok(Listener::Unix(UnixListener::bind(path)?))
+            }
+            Err(err) => Err(err),
+        }
+    }
+
+    /// Wraps a listening socket that was bound to the address by another process
+    ///
+    /// # Safety
+    ///
+    /// The fd must be an open listening socket of the kind the address calls for, owned by nobody
+    /// else.
+    pub unsafe fn from_raw_fd(address: &str, fd: RawFd) -> io::Result<Listener> {
+        if unix_path(address).is_some() {
+            let listener = std::os::unix::net::UnixListener::from_raw_fd(fd);
+            listener.set_nonblocking(true)?;
+            Ok(Listener::Unix(UnixListener::from_std(listener)?))
+        } else {
+            let listener = std::net::TcpListener::from_raw_fd(fd);
+            listener.set_nonblocking(true)?;
+            Ok(Listener::Tcp(TcpListener::from_std(listener)?))
+        }
+    }
+
+    pub async fn accept(&self) -> io::Result<(ClientStream, SocketAddr)> {
+        match self {
+            Listener::Tcp(listener) => {
+                let (stream, client_addr) = listener.accept().await?;
+                // IPv4 clients of a dual-stack listener show up as IPv4-mapped IPv6 addresses,
+                // which would get around access lists and rate limits for their IPv4 address
+                let client_addr =
+                    SocketAddr::new(client_addr.ip().to_canonical(), client_addr.port());
+                Ok((ClientStream::Tcp(stream), client_addr))
+            }
+            Listener::Unix(listener) => {
+                let (stream, _) = listener.accept().await?;
+                Ok((ClientStream::Unix(stream), UNIX_CLIENT_ADDR))
+            }
+        }
+    }
+
+    /// The TCP port we're listening on, or 0 for a Unix domain socket
+    pub fn port(&self) -> u16 {
+        match self {
+            Listener::Tcp(listener) => listener.local_addr().map(|addr| addr.port()).unwrap_or(0),
+            Listener::Unix(_) => 0,
+        }
+    }
+}
+
+impl AsRawFd for Listener {
+    fn as_raw_fd(&self) -> RawFd {
+        match self {
+            Listener::Tcp(listener) => listener.as_raw_fd(),
+            Listener::Unix(listener) => listener.as_raw_fd(),
+        }
+    }
+}
+
+/// A connection accepted by a `Listener`
+pub enum ClientStream {
+    Tcp(TcpStream),
+    Unix(UnixStream),
+}
+
+impl AsyncRead for ClientStream {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            ClientStream::Tcp(stream) => Pin::new(stream).poll_read(cx, buf),
+            ClientStream::Unix(stream) => Pin::new(stream).poll_read(cx, buf),
+        }
+    }
+}
+
+impl AsyncWrite for ClientStream {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        match self.get_mut() {
+            ClientStream::Tcp(stream) => Pin::new(stream).poll_write(cx, buf),
+            ClientStream::Unix(stream) => Pin::new(stream).poll_write(cx, buf),
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            ClientStream::Tcp(stream) => Pin::new(stream).poll_flush(cx),
+            ClientStream::Unix(stream) => Pin::new(stream).poll_flush(cx),
+        }
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            ClientStream::Tcp(stream) => Pin::new(stream).poll_shutdown(cx),
+            ClientStream::Unix(stream) => Pin::new(stream).poll_shutdown(cx),
+        }
+    }
+}
@@ -0,0 +1,69 @@
+use std::collections::BTreeMap;
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
+use std::sync::Arc;
+use async_trait::async_trait;
+use crate::state::ProxyState;
+use super::{LoadBalanceStrategy, RequestContext};
+
+/// Number of points each upstream gets on the hash ring. More points spread clients more evenly
+/// across upstreams.
+const VIRTUAL_NODES: usize = 100;
+
+fn hash<T: Hash + ?Sized>(value: &T) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    value.hash(&mut hasher);
+    hasher.finish()
+}
+
+/// Maps each client onto a hash ring of upstreams so the same client always lands on the same
+/// backend. When an upstream goes down, only the clients that were mapped to it move elsewhere.
+pub struct ConsistentHash {
+    /// Points on the ring, mapping a hash to the index of the upstream that owns it
+    ring: BTreeMap<u64, usize>,
+    /// Request header to hash on. Falls back to the client IP if unset or missing
+    header: Option<String>,
+}
+
+impl ConsistentHash {
+    pub fn new(upstream_addresses: &[String], header: Option<String>) -> ConsistentHash {
+        let mut ring = BTreeMap::new();
+        for (idx, addr) in upstream_addresses.iter().enumerate() {
+            for vnode in 0..VIRTUAL_NODES {
+                ring.insert(hash(&format!("{}#{}", addr, vnode)), idx);
+            }
+        }
+        ConsistentHash { ring, header }
+    }
+
+    fn client_key(&self, context: &RequestContext<'_>) -> u64 {
+        if let Some(name) = &self.header {
+            if let Some(value) = context.request.headers().get(name.as_str()) {
+                return hash(value.as_bytes());
+            }
+        }
+        hash(&context.client_ip)
+    }
+}
+
+#[async_trait]
+impl LoadBalanceStrategy for ConsistentHash {
+    async fn select_backend<'l>(
+        &'l self,
+        state: &'l Arc<ProxyState>,
+        context: &'l RequestContext<'l>,
+    ) -> Option<usize> {
+        let upstream_status = state.upstream_status.load();
+        if upstream_status.all_dead() {
+            return None;
+        }
+
+        // Walk clockwise from the client's position until we find an upstream that is alive
+        let key = self.client_key(context);
+        self.ring
+            .range(key..)
+            .chain(self.ring.range(..key))
+            .map(|(_, &idx)| idx)
+            .find(|&idx| context.is_eligible(&upstream_status, idx))
+    }
+}
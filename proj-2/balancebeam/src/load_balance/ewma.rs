@@ -0,0 +1,39 @@
+use std::sync::Arc;
+use std::time::Duration;
+use async_trait::async_trait;
+use crate::state::ProxyState;
+use super::{LoadBalanceStrategy, RequestContext};
+
+/// Sends each request to the upstream that has been responding the fastest lately. Upstreams that
+/// haven't been measured yet are tried first, so that every upstream gets a measurement.
+pub struct Ewma {}
+
+impl Ewma {
+    pub fn new() -> Ewma {
+        Ewma {}
+    }
+}
+
+#[async_trait]
+impl LoadBalanceStrategy for Ewma {
+    async fn select_backend<'l>(
+        &'l self,
+        state: &'l Arc<ProxyState>,
+        context: &'l RequestContext<'l>,
+    ) -> Option<usize> {
+        let upstream_status = state.upstream_status.load();
+        if upstream_status.all_dead() {
+            return None;
+        }
+        let upstream_addresses = state.upstream_addresses.read().await;
+        let latencies = &state.upstream_latencies;
+        (0..upstream_status.len())
+            .filter(|&idx| context.is_eligible(&upstream_status, idx))
+            .min_by_key(|&idx| {
+                upstream_addresses
+                    .get(idx)
+                    .and_then(|addr| latencies.get(addr))
+                    .unwrap_or(Duration::from_secs(0))
+            })
+    }
+}
@@ -1,31 +1,64 @@
+use std::net::IpAddr;
 use std::sync::Arc;
 use async_trait::async_trait;
-use crate::ProxyState;
-use self::{random::Random, round_robin::RoundRobin};
+use crate::state::{ProxyState, UpstreamsStatus};
 
+pub mod consistent_hash;
+pub mod ewma;
+pub mod p2c;
 pub mod random;
 pub mod round_robin;
+pub mod sticky;
 
-#[derive(clap::ArgEnum, Debug)]
+#[derive(clap::ArgEnum, Debug, Clone, Copy, PartialEq, serde::Deserialize)]
+#[serde(rename_all = "kebab-case")]
 pub enum ArgLoadBalance {
     Random,
-    RoundRobin
+    RoundRobin,
+    ConsistentHash,
+    Ewma,
+    P2c
 }
 
-#[async_trait]
-pub trait LoadBalanceStrategy: Send + Sync {
-    async fn select_backend<'l>(&'l self, state: &'l Arc<ProxyState>) -> Option<usize>;
+/// Information about the request being routed, for strategies that pick an upstream based on
+/// who is asking rather than only on the state of the upstreams
+pub struct RequestContext<'a> {
+    /// IP address of the client that sent the request
+    pub client_ip: IpAddr,
+    /// The request that is about to be forwarded
+    pub request: &'a http::Request<Vec<u8>>,
+    /// Upstreams that may serve this request, i.e. the pool its route sends it to
+    pub pool: &'a [usize],
+    /// Upstreams this request already failed on, which shouldn't be picked again when retrying it
+    pub excluded: &'a [usize],
 }
 
-impl From<ArgLoadBalance> for Box<dyn LoadBalanceStrategy> {
-    fn from(other: ArgLoadBalance) -> Self {
-        match other {
-            ArgLoadBalance::Random => {
-                Box::new(Random::new())
-            }
-            ArgLoadBalance::RoundRobin => {
-                Box::new(RoundRobin::new())
-            }
-        }
+impl RequestContext<'_> {
+    /// Returns true if the upstream may be picked for this request. An upstream that is still
+    /// warming up after recovering is only eligible for its share of requests, picked at random,
+    /// as long as some other upstream that isn't warming up could take the rest.
+    pub fn is_eligible(&self, upstream_status: &UpstreamsStatus, idx: usize) -> bool {
+        self.is_candidate(upstream_status, idx)
+            && upstream_status.admit_while_warming_up(idx, || {
+                self.pool.iter().any(|&other| {
+                    other != idx
+                        && self.is_candidate(upstream_status, other)
+                        && !upstream_status.is_warming_up(other)
+                })
+            })
+    }
+
+    /// Returns true if the upstream can take connections and this request may go to it
+    fn is_candidate(&self, upstream_status: &UpstreamsStatus, idx: usize) -> bool {
+        upstream_status.is_alive(idx) && self.pool.contains(&idx) && !self.excluded.contains(&idx)
     }
 }
+
+#[async_trait]
+pub trait LoadBalanceStrategy: Send + Sync {
+    async fn select_backend<'l>(
+        &'l self,
+        state: &'l Arc<ProxyState>,
+        context: &'l RequestContext<'l>,
+    ) -> Option<usize>;
+}
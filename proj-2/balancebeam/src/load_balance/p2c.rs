@@ -0,0 +1,51 @@
+use std::sync::Arc;
+use rand::SeedableRng;
+use async_trait::async_trait;
+use crate::state::ProxyState;
+use super::{LoadBalanceStrategy, RequestContext};
+
+/// Power of two choices: picks two upstreams at random and sends the request to whichever of them
+/// has fewer connections open. This spreads load nearly as well as always picking the least busy
+/// upstream, without having to look at all of them.
+pub struct P2c {}
+
+impl P2c {
+    pub fn new() -> P2c {
+        P2c {}
+    }
+}
+
+#[async_trait]
+impl LoadBalanceStrategy for P2c {
+    async fn select_backend<'l>(
+        &'l self,
+        state: &'l Arc<ProxyState>,
+        context: &'l RequestContext<'l>,
+    ) -> Option<usize> {
+        let mut rng = rand::rngs::StdRng::from_entropy();
+        let upstream_status = state.upstream_status.load();
+        if upstream_status.all_dead() {
+            return None;
+        }
+
+        let candidates: Vec<usize> = (0..upstream_status.len())
+            .filter(|&idx| context.is_eligible(&upstream_status, idx))
+            .collect();
+        if candidates.len() < 2 {
+            return candidates.first().copied();
+        }
+        let upstream_addresses = state.upstream_addresses.read().await;
+        let active_connections = |idx: usize| {
+            upstream_addresses
+                .get(idx)
+                .map_or(0, |addr| state.upstream_connections.get(addr))
+        };
+        let picks = rand::seq::index::sample(&mut rng, candidates.len(), 2);
+        let (first, second) = (candidates[picks.index(0)], candidates[picks.index(1)]);
+        if active_connections(second) < active_connections(first) {
+            Some(second)
+        } else {
+            Some(first)
+        }
+    }
+}
@@ -1,8 +1,8 @@
 use std::sync::Arc;
 use rand::{Rng, SeedableRng};
 use async_trait::async_trait;
-use crate::ProxyState;
-use super::LoadBalanceStrategy;
+use crate::state::ProxyState;
+use super::{LoadBalanceStrategy, RequestContext};
 
 pub struct Random {}
 
@@ -14,19 +14,23 @@ impl Random {
 
 #[async_trait]
 impl LoadBalanceStrategy for Random {
-    async fn select_backend<'l>(&'l self, state: &'l Arc<ProxyState>) -> Option<usize> {
+    async fn select_backend<'l>(
+        &'l self,
+        state: &'l Arc<ProxyState>,
+        context: &'l RequestContext<'l>,
+    ) -> Option<usize> {
         let mut rng = rand::rngs::StdRng::from_entropy();
-        let upstream_status = state.upstream_status.read().await;
+        let upstream_status = state.upstream_status.load();
         if upstream_status.all_dead() {
             return None;
         }
 
-        let mut idx;
-        loop {
-            idx = rng.gen_range(0..state.upstream_addresses.len());
-            if upstream_status.is_alive(idx) {
-                return Some(idx)
-            }
+        let candidates: Vec<usize> = (0..upstream_status.len())
+            .filter(|&idx| context.is_eligible(&upstream_status, idx))
+            .collect();
+        if candidates.is_empty() {
+            return None;
         }
+        Some(candidates[rng.gen_range(0..candidates.len())])
     }
 }
\ No newline at end of file
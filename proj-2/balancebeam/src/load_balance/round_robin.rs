@@ -1,7 +1,7 @@
 use std::sync::{Arc, Mutex};
 use async_trait::async_trait;
-use crate::ProxyState;
-use super::LoadBalanceStrategy;
+use crate::state::ProxyState;
+use super::{LoadBalanceStrategy, RequestContext};
 
 pub struct RoundRobin {
     rrc: Arc<Mutex<u32>>
@@ -17,21 +17,26 @@ impl RoundRobin {
 
 #[async_trait]
 impl LoadBalanceStrategy for RoundRobin {
-    async fn select_backend<'l>(&'l self, state: &'l Arc<ProxyState>) -> Option<usize> {
-        let upstream_status = state.upstream_status.read().await;
+    async fn select_backend<'l>(
+        &'l self,
+        state: &'l Arc<ProxyState>,
+        context: &'l RequestContext<'l>,
+    ) -> Option<usize> {
+        let upstream_status = state.upstream_status.load();
         if upstream_status.all_dead() {
             return None;
         }
 
         let mut rrc_handle = self.rrc.lock().unwrap();
 
-        loop {
-            *rrc_handle = (*rrc_handle + 1) % state.upstream_addresses.len() as u32;
+        // Go around at most once, in case every live upstream has been excluded
+        for _ in 0..upstream_status.len() {
+            *rrc_handle = (*rrc_handle + 1) % upstream_status.len() as u32;
             let idx = *rrc_handle as usize;
-            if upstream_status.is_alive(idx) {
+            if context.is_eligible(&upstream_status, idx) {
                 return Some(idx);
             }
         }
-        
+        None
     }
 }
\ No newline at end of file
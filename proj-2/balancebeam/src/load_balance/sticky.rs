@@ -0,0 +1,54 @@
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
+use std::sync::Arc;
+use async_trait::async_trait;
+use crate::request;
+use crate::state::ProxyState;
+use super::{LoadBalanceStrategy, RequestContext};
+
+/// Returns the session cookie value that identifies the upstream with the given address. This is
+/// a hash rather than the address itself, so clients don't learn how the backends are laid out.
+pub fn cookie_value(upstream_address: &str) -> String {
+    let mut hasher = DefaultHasher::new();
+    upstream_address.hash(&mut hasher);
+    format!("{:016x}", hasher.finish())
+}
+
+/// Sends requests carrying a session cookie back to the upstream that the cookie names, and
+/// leaves everything else (including requests whose upstream is down) to another strategy.
+pub struct Sticky {
+    /// Name of the session cookie
+    cookie: String,
+    /// Cookie value for each upstream, one by one match upstream_addresses
+    values: Vec<String>,
+    inner: Box<dyn LoadBalanceStrategy>,
+}
+
+impl Sticky {
+    pub fn new(
+        cookie: String,
+        upstream_addresses: &[String],
+        inner: Box<dyn LoadBalanceStrategy>,
+    ) -> Sticky {
+        let values = upstream_addresses.iter().map(|addr| cookie_value(addr)).collect();
+        Sticky { cookie, values, inner }
+    }
+}
+
+#[async_trait]
+impl LoadBalanceStrategy for Sticky {
+    async fn select_backend<'l>(
+        &'l self,
+        state: &'l Arc<ProxyState>,
+        context: &'l RequestContext<'l>,
+    ) -> Option<usize> {
+        let sticky_idx = request::get_cookie(context.request, &self.cookie)
+            .and_then(|value| self.values.iter().position(|v| v == value));
+        if let Some(idx) = sticky_idx {
+            if context.is_eligible(&state.upstream_status.load(), idx) {
+                return Some(idx);
+            }
+        }
+        self.inner.select_backend(state, context).await
+    }
+}
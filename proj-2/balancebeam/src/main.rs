@@ -1,29 +1,175 @@
-mod request;
-mod response;
-mod rate_limiter;
-mod load_balance;
-
-use std::{io::ErrorKind, sync::Arc};
+use balancebeam::{ArgLoadBalance, ArgRateLimiter, Cidr, Config, ConfigError, ErrorPage, Proxy};
+use balancebeam::{HeaderRule, ProxyHeader, ProxyMode, RateLimitBy, RedirectRule, StatusRange};
+use balancebeam::{AccessLogRotation, StatsdSettings};
 use clap::Clap;
-use tokio::{net::{TcpListener, TcpStream}, sync::{Mutex, RwLock}, time::{sleep, Duration}};
-use crate::rate_limiter::counter::Counter;
-use crate::rate_limiter::{RateLimiterStrategy, ArgRateLimiter};
-use crate::load_balance::{LoadBalanceStrategy, ArgLoadBalance};
+use std::collections::BTreeMap;
+#[cfg(feature = "discovery")]
+use balancebeam::{parse_discover, DiscoveryBackend, DiscoverySettings};
+#[cfg(feature = "discovery")]
+use std::time::Duration;
+
+mod bench;
 
 /// Contains information parsed from the command-line invocation of balancebeam. The Clap macros
 /// provide a fancy way to automatically construct a command-line argument parser.
 #[derive(Clap, Debug)]
 #[clap(about = "Fun with load balancing")]
 struct CmdOptions {
+    #[clap(subcommand)]
+    command: Option<Command>,
+    #[clap(
+        short,
+        long,
+        multiple_occurrences = true,
+        about = "IP/port to bind to (e.g. 0.0.0.0:1100 or [::]:1100), or unix:<path> to listen on a Unix domain socket. May be given more than once",
+        default_value = balancebeam::DEFAULT_BIND
+    )]
+    bind: Vec<String>,
+    #[clap(
+        arg_enum,
+        long,
+        about = "Proxy HTTP requests, or raw TCP connections for other protocols (e.g. Redis or Postgres), which are load balanced and health checked by connecting but otherwise passed through untouched",
+        default_value = "http"
+    )]
+    mode: ProxyMode,
+    #[clap(
+        long,
+        about = "Also accept HTTP/2 connections (e.g. from gRPC clients), forwarding each request on them to an upstream of its own over HTTP/2, trailers included. Upstreams must speak HTTP/2"
+    )]
+    http2: bool,
     #[clap(
         short,
         long,
-        about = "IP/port to bind to",
-        default_value = "0.0.0.0:1100"
+        multiple_occurrences = true,
+        about = "Upstream host to forward requests to. Prefix with https:// to connect over TLS, or give unix:<path> to connect over a Unix domain socket"
     )]
-    bind: String,
-    #[clap(short, long, multiple_occurrences = true, about = "Upstream host to forward requests to")]
     upstream: Vec<String>,
+    #[clap(
+        long,
+        about = "File listing more upstream hosts, one per line. Changes to it are picked up while running, to add and remove upstreams without a restart"
+    )]
+    upstreams_file: Option<String>,
+    #[clap(
+        long,
+        multiple_occurrences = true,
+        parse(try_from_str = balancebeam::parse_key_value),
+        about = "Named pool of upstreams that routes can send requests to, as <name>=<host>[,<host>...]"
+    )]
+    pool: Vec<(String, String)>,
+    #[clap(
+        long,
+        multiple_occurrences = true,
+        parse(try_from_str = balancebeam::parse_key_value),
+        about = "Send requests whose path starts with a prefix to a pool, as <prefix>=<pool>. Other requests go to the --upstream hosts"
+    )]
+    route: Vec<(String, String)>,
+    #[clap(
+        long,
+        multiple_occurrences = true,
+        parse(try_from_str = balancebeam::parse_canary),
+        about = "Send a percentage of requests to a canary upstream, as <host>=<percent>% (e.g. 10.0.0.5:8080=5%). The rest go to the other upstreams"
+    )]
+    canary: Vec<(String, usize)>,
+    #[clap(
+        long,
+        about = "Pool (see --pool) that takes requests not matching any route, until the admin API switches them over to --green-pool. Can also be set in the config file"
+    )]
+    blue_pool: Option<String>,
+    #[clap(
+        long,
+        about = "Pool that takes requests not matching any route once the admin API switches over to it from --blue-pool"
+    )]
+    green_pool: Option<String>,
+    #[clap(
+        long,
+        about = "After switching between the blue and green pools, let client connections already pinned to an upstream in the old pool keep using it for this many seconds",
+        default_value = "30"
+    )]
+    blue_green_drain_timeout: usize,
+    #[clap(
+        long,
+        about = "Send a copy of each request to this shadow backend, throwing away its responses"
+    )]
+    mirror: Option<String>,
+    #[clap(
+        long,
+        multiple_occurrences = true,
+        about = "Drain an upstream (or every address a host name resolves to) for maintenance: it gets no new requests, and the admin API reports when the ones in flight are done. Can also be listed in the config file, and changed with SIGHUP"
+    )]
+    drain: Vec<String>,
+    #[clap(
+        long,
+        multiple_occurrences = true,
+        about = "Cache responses to GET requests whose path starts with this prefix (e.g. /static or /health) for as long as their Cache-Control header allows, including stale-while-revalidate and stale-if-error. May be given more than once"
+    )]
+    cache_path: Vec<String>,
+    #[clap(
+        long,
+        about = "File to answer requests with while maintenance mode is turned on through the admin API"
+    )]
+    maintenance_page: Option<String>,
+    #[clap(
+        long,
+        multiple_occurrences = true,
+        about = "Let clients in this IP range through while in maintenance mode"
+    )]
+    maintenance_allow_cidr: Vec<Cidr>,
+    #[clap(
+        long,
+        about = "Send requests with an X-Balancebeam-Upstream header to the upstream it names, by address or by its index in the admin API's list, for debugging a single backend through the proxy"
+    )]
+    upstream_override: bool,
+    #[clap(
+        long,
+        multiple_occurrences = true,
+        about = "Let clients in this IP range use the X-Balancebeam-Upstream header (by default, only clients on this host may)"
+    )]
+    upstream_override_cidr: Vec<Cidr>,
+    #[clap(
+        long,
+        multiple_occurrences = true,
+        use_delimiter = true,
+        about = "IP ranges of proxies in front of balancebeam, as <cidr>,... Requests from them are rate limited and logged under the rightmost address in X-Forwarded-For that isn't one of them"
+    )]
+    trusted_proxies: Vec<Cidr>,
+    #[clap(
+        long,
+        about = "Redirect clients that connect over plain HTTP to the same URL over HTTPS"
+    )]
+    force_https: bool,
+    #[clap(
+        long,
+        about = "Redirect requests for any other host name to this one"
+    )]
+    canonical_host: Option<String>,
+    #[clap(
+        long,
+        multiple_occurrences = true,
+        parse(try_from_str = balancebeam::parse_redirect),
+        about = "Redirect requests whose path starts with a prefix, as <prefix>=<path or URL>. The rest of the path is kept"
+    )]
+    redirect: Vec<RedirectRule>,
+    #[clap(
+        long,
+        multiple_occurrences = true,
+        parse(try_from_str = balancebeam::parse_error_page),
+        about = "Answer with a page instead of the plain message for errors we generate, as <status>=<file>. The page may contain {{request_id}} and {{upstream}}"
+    )]
+    error_page: Vec<(u16, String)>,
+    #[clap(
+        long,
+        multiple_occurrences = true,
+        parse(try_from_str = balancebeam::parse_header_rule),
+        about = "Change a header of each request before forwarding it, as add:<name>=<value>, set:<name>=<value> or remove:<name>"
+    )]
+    request_header: Vec<HeaderRule>,
+    #[clap(
+        long,
+        multiple_occurrences = true,
+        parse(try_from_str = balancebeam::parse_header_rule),
+        about = "Change a header of each response before sending it to the client, as add:<name>=<value>, set:<name>=<value> or remove:<name>"
+    )]
+    response_header: Vec<HeaderRule>,
     #[clap(
         long,
         about = "Perform active health checks on this interval (in seconds)",
@@ -36,12 +182,201 @@ struct CmdOptions {
     default_value = "/"
     )]
     active_health_check_path: String,
+    #[clap(
+        long,
+        about = "HTTP method to use for active health checks",
+        default_value = "GET"
+    )]
+    active_health_check_method: http::Method,
+    #[clap(
+        long,
+        about = "Status code (e.g. 200) or range (e.g. 200-399) that active health checks expect",
+        default_value = "200"
+    )]
+    active_health_check_status: StatusRange,
+    #[clap(
+        long,
+        about = "Text that the response body must contain for an active health check to pass"
+    )]
+    active_health_check_body: Option<String>,
+    #[clap(
+        long,
+        about = "Fail an active health check if the upstream takes longer than this (in seconds) to respond",
+        default_value = "5"
+    )]
+    active_health_check_timeout: usize,
+    #[clap(
+        long,
+        about = "Mark an upstream down after this many requests to it fail in a row (0 = never)",
+        default_value = "3"
+    )]
+    passive_health_check_failures: usize,
+    #[clap(
+        long,
+        about = "Only count request failures that happened within this many seconds of each other",
+        default_value = "10"
+    )]
+    passive_health_check_window: usize,
+    #[clap(
+        long,
+        about = "Look up upstream host names at startup and again every this many seconds, sending requests to every address they resolve to (0 = look them up on each connection instead)",
+        default_value = "0"
+    )]
+    dns_refresh_interval: usize,
+    #[clap(
+        long,
+        about = "When an upstream recovers, ramp its share of requests up gradually over this many seconds (0 = give it a full share right away)",
+        default_value = "0"
+    )]
+    slow_start_window: usize,
+    #[clap(
+        long,
+        about = "POST a JSON event (upstream, state and timestamp) to this http:// or https:// URL whenever an upstream goes down or comes back up"
+    )]
+    health_webhook: Option<String>,
+    #[clap(
+        long,
+        about = "Run this shell command whenever an upstream goes down or comes back up, with the upstream, its new state (up or down) and the time in BALANCEBEAM_UPSTREAM, BALANCEBEAM_STATE and BALANCEBEAM_TIMESTAMP"
+    )]
+    health_command: Option<String>,
+    #[clap(
+        long,
+        about = "Log each upstream's request rate, error rate and response time percentiles over the last minute every this many seconds (0 = don't log them)",
+        default_value = "60"
+    )]
+    stats_log_interval: usize,
+    #[clap(
+        long,
+        about = "Retry failed idempotent requests on up to this many other upstreams before giving up",
+        default_value = "0"
+    )]
+    max_retries: usize,
+    #[clap(
+        long,
+        about = "Close client connections that take longer than this (in seconds) to send a request (0 = never)",
+        default_value = "60"
+    )]
+    client_read_timeout: usize,
+    #[clap(
+        long,
+        about = "Close client connections that sit idle for this long (in seconds) between requests (0 = never)",
+        default_value = "60"
+    )]
+    client_idle_timeout: usize,
+    #[clap(
+        long,
+        about = "Close client connections that go this long (in seconds) without reading any of a response (0 = never)",
+        default_value = "60"
+    )]
+    client_write_timeout: usize,
+    #[clap(
+        long,
+        about = "Close client connections that send requests or read responses slower than this many bytes per second, counting only the time spent waiting on the client, after a short grace period (0 = no minimum)",
+        default_value = "0"
+    )]
+    client_min_rate: usize,
+    #[clap(
+        long,
+        about = "Close client connections after this many requests (0 = no limit)",
+        default_value = "0"
+    )]
+    max_requests_per_connection: usize,
+    #[clap(
+        long,
+        about = "Give up connecting to an upstream after this many seconds (0 = never)",
+        default_value = "10"
+    )]
+    upstream_connect_timeout: usize,
+    #[clap(
+        long,
+        about = "Wait this many milliseconds before trying another upstream after failing to connect to one, doubling with each attempt (0 = don't wait)",
+        default_value = "0"
+    )]
+    upstream_connect_backoff: usize,
+    #[clap(
+        long,
+        about = "When shutting down (on SIGQUIT, e.g. once a process started with SIGUSR2 has taken over), wait this many seconds for open connections to finish (0 = forever)",
+        default_value = "30"
+    )]
+    shutdown_timeout: usize,
+    #[clap(
+        long,
+        about = "Answer with 504 Gateway Timeout if an upstream takes longer than this (in seconds) to respond (0 = never)",
+        default_value = "60"
+    )]
+    upstream_response_timeout: usize,
+    #[clap(
+        long,
+        about = "Send each upstream at most this many requests per second (0 = unlimited)",
+        default_value = "0"
+    )]
+    max_upstream_rps: usize,
+    #[clap(
+        long,
+        about = "Let requests over --max-upstream-rps wait up to this many seconds for their turn, instead of answering them with 503 right away",
+        default_value = "0"
+    )]
+    upstream_queue_timeout: usize,
+    #[clap(
+        long,
+        about = "Send each upstream at most this many requests at once (0 = unlimited)",
+        default_value = "0"
+    )]
+    max_upstream_concurrency: usize,
+    #[clap(
+        long,
+        about = "Let at most this many requests wait for an upstream to have room under --max-upstream-concurrency; any more are answered with 503",
+        default_value = "100"
+    )]
+    request_queue_size: usize,
+    #[clap(
+        long,
+        about = "Answer requests that have waited this many seconds for an upstream to have room with 503",
+        default_value = "5"
+    )]
+    request_queue_timeout: usize,
+    #[clap(
+        long,
+        about = "Stop sending requests to an upstream when this percentage of its requests fail (0 = never)",
+        default_value = "0"
+    )]
+    circuit_breaker_error_rate: usize,
+    #[clap(
+        long,
+        about = "Number of most recent requests to an upstream that its error rate is measured over",
+        default_value = "20"
+    )]
+    circuit_breaker_window: usize,
+    #[clap(
+        long,
+        about = "Seconds to wait before letting trial requests through to an upstream whose circuit opened",
+        default_value = "30"
+    )]
+    circuit_breaker_cooldown: usize,
+    #[clap(
+        long,
+        about = "Number of trial requests that must succeed before an upstream's circuit closes again",
+        default_value = "3"
+    )]
+    circuit_breaker_trial_requests: usize,
     #[clap(
         long,
         about = "Maximum number of requests to accept per IP per minute (0 = unlimited)",
         default_value = "0"
     )]
     max_requests_per_minute: usize,
+    #[clap(
+        long,
+        about = "Maximum number of requests each IP may have in progress at once; any more are answered with 429 (0 = unlimited)",
+        default_value = "0"
+    )]
+    max_concurrent_per_ip: usize,
+    #[clap(
+        long,
+        about = "Maximum number of bytes each IP may send and be sent per minute, headers included; once a client goes over, its requests are answered with 429 until the minute is up (0 = unlimited)",
+        default_value = "0"
+    )]
+    max_bytes_per_minute: u64,
     #[clap(
         arg_enum,
         long,
@@ -49,6 +384,37 @@ struct CmdOptions {
         default_value = "counter",
     )]
     rate_limiter: ArgRateLimiter,
+    #[clap(
+        long,
+        about = "Burst size for the token-bucket rate limiter (0 = same as max-requests-per-minute)",
+        default_value = "0"
+    )]
+    rate_limit_burst: usize,
+    #[cfg(feature = "redis")]
+    #[clap(
+        long,
+        about = "Redis server to keep rate limit counts in for the redis rate limiter, e.g. redis://10.0.0.5:6379"
+    )]
+    rate_limit_redis_url: Option<String>,
+    #[clap(
+        arg_enum,
+        long,
+        about = "What to rate limit requests by: client IP, API key (the X-Api-Key or Authorization header, falling back to the client IP), or a claim of the request's JSON Web Token (see --jwt-rate-limit-claim)",
+        default_value = "ip",
+    )]
+    rate_limit_by: RateLimitBy,
+    #[clap(
+        long,
+        about = "Ban clients that go over the rate limit more than this many times in an hour (0 = never ban)",
+        default_value = "0"
+    )]
+    ban_threshold: usize,
+    #[clap(
+        long,
+        about = "How long (in seconds) banned clients are turned away for",
+        default_value = "3600"
+    )]
+    ban_duration: usize,
     #[clap(
         arg_enum,
         long,
@@ -56,64 +422,385 @@ struct CmdOptions {
         default_value = "round-robin",
     )]
     load_balancer: ArgLoadBalance,
+    #[clap(
+        long,
+        about = "Request header to hash on for the consistent-hash load balancer (defaults to the client IP)"
+    )]
+    hash_header: Option<String>,
+    #[clap(
+        long,
+        about = "Set a session cookie with this name naming the upstream, and keep sending requests that carry it to the same upstream"
+    )]
+    sticky_cookie: Option<String>,
+    #[clap(
+        arg_enum,
+        long,
+        multiple_occurrences = true,
+        about = "Don't add this header to forwarded requests (and, for Via, responses)"
+    )]
+    disable_proxy_header: Vec<ProxyHeader>,
+    #[clap(
+        long,
+        multiple_occurrences = true,
+        about = "Only let clients in this IP range (e.g. 10.0.0.0/8) use the proxy. Others are answered with 403"
+    )]
+    allow_cidr: Vec<Cidr>,
+    #[clap(
+        long,
+        multiple_occurrences = true,
+        about = "Answer clients in this IP range with 403, even if they're in an --allow-cidr range"
+    )]
+    deny_cidr: Vec<Cidr>,
+    #[clap(
+        long,
+        about = "htpasswd file of users to let through with HTTP Basic authentication (bcrypt, apr1, SHA or plain passwords). Other requests are answered with 401"
+    )]
+    auth_htpasswd: Option<String>,
+    #[clap(
+        long,
+        about = "Let through requests with this bearer token in their Authorization header. Other requests are answered with 401"
+    )]
+    auth_bearer_token: Option<String>,
+    #[clap(
+        long,
+        about = "Realm to name when asking clients for credentials",
+        default_value = "balancebeam"
+    )]
+    auth_realm: String,
+    #[clap(
+        long,
+        about = "Require requests to have a JSON Web Token signed with this HS256 secret (or the --jwt-rs256-public-key). Others are answered with 401"
+    )]
+    jwt_hs256_secret: Option<String>,
+    #[clap(
+        long,
+        about = "PEM file with the public key to check RS256-signed JSON Web Tokens with"
+    )]
+    jwt_rs256_public_key: Option<String>,
+    #[clap(long, about = "Only accept JSON Web Tokens from this issuer (iss)")]
+    jwt_issuer: Option<String>,
+    #[clap(long, about = "Only accept JSON Web Tokens for this audience (aud)")]
+    jwt_audience: Option<String>,
+    #[clap(
+        long,
+        about = "Send each request to the pool named by this claim of its JSON Web Token (e.g. tenant), if there is such a pool"
+    )]
+    jwt_route_claim: Option<String>,
+    #[clap(
+        long,
+        about = "Claim of the JSON Web Token to count requests by with --rate-limit-by jwt",
+        default_value = "sub"
+    )]
+    jwt_rate_limit_claim: String,
+    #[clap(
+        long,
+        multiple_occurrences = true,
+        about = "Let scripts from this origin (e.g. https://app.example.com, or * for any) make cross-origin requests. Preflight requests are answered without contacting an upstream"
+    )]
+    cors_allow_origin: Vec<String>,
+    #[clap(
+        long,
+        multiple_occurrences = true,
+        about = "Method to allow in cross-origin requests (defaults to GET, HEAD, POST, PUT, PATCH and DELETE)"
+    )]
+    cors_allow_method: Vec<String>,
+    #[clap(
+        long,
+        multiple_occurrences = true,
+        about = "Request header to allow in cross-origin requests (defaults to any the browser asks for)"
+    )]
+    cors_allow_header: Vec<String>,
+    #[clap(
+        long,
+        multiple_occurrences = true,
+        about = "Response header that scripts making cross-origin requests may read"
+    )]
+    cors_expose_header: Vec<String>,
+    #[clap(long, about = "Let cross-origin requests carry cookies and other credentials")]
+    cors_allow_credentials: bool,
+    #[clap(
+        long,
+        about = "Seconds browsers may cache the answer to a preflight request for (0 = don't say)",
+        default_value = "0"
+    )]
+    cors_max_age: usize,
+    #[clap(
+        long,
+        about = "Compress response bodies of at least this many bytes for clients that accept gzip or deflate (0 = don't compress)",
+        default_value = "0"
+    )]
+    compression_min_size: usize,
+    #[clap(
+        long,
+        multiple_occurrences = true,
+        about = "Content type to compress, e.g. application/json or text/* (defaults to text, JSON, JavaScript, XML and SVG)"
+    )]
+    compression_type: Vec<String>,
+    #[clap(
+        long,
+        about = "Config file to read settings from. Reloaded when balancebeam receives SIGHUP"
+    )]
+    config: Option<String>,
+    #[clap(
+        long,
+        requires = "tls-key",
+        about = "PEM file with the certificate chain to serve HTTPS with (requires --tls-key)"
+    )]
+    tls_cert: Option<String>,
+    #[clap(
+        long,
+        requires = "tls-cert",
+        about = "PEM file with the private key for --tls-cert"
+    )]
+    tls_key: Option<String>,
+    #[clap(
+        long,
+        requires = "tls-cert",
+        about = "PEM file with CA certificates that clients' certificates must be signed by. Clients without one are turned away, and the subject of each client's certificate is forwarded in the X-Client-Cert-Subject header"
+    )]
+    tls_client_ca: Option<String>,
+    #[clap(
+        long,
+        requires = "tls-cert",
+        about = "Serve plaintext HTTP (or TCP) as well as TLS on the same ports, telling clients apart by whether they start a TLS handshake. Eases moving clients over to TLS gradually"
+    )]
+    tls_auto_detect: bool,
+    #[clap(
+        long,
+        about = "PEM file with extra CA certificates to trust when connecting to https:// upstreams"
+    )]
+    upstream_ca_cert: Option<String>,
+    #[clap(
+        long,
+        requires = "upstream-client-key",
+        about = "PEM file with a certificate chain to present to https:// upstreams that ask for a client certificate (requires --upstream-client-key)"
+    )]
+    upstream_client_cert: Option<String>,
+    #[clap(
+        long,
+        requires = "upstream-client-cert",
+        about = "PEM file with the private key for --upstream-client-cert"
+    )]
+    upstream_client_key: Option<String>,
+    #[clap(
+        long,
+        about = "IP/port to serve the admin API on, for managing upstreams at runtime (disabled by default)"
+    )]
+    admin_bind: Option<String>,
+    #[clap(
+        long,
+        about = "Maximum number of client connections to have open at once (0 = unlimited)",
+        default_value = "0"
+    )]
+    max_connections: usize,
+    #[clap(
+        long,
+        about = "Maximum number of connections to have open at once from each client IP (0 = unlimited)",
+        default_value = "0"
+    )]
+    max_connections_per_ip: usize,
+    #[clap(
+        long,
+        about = "File to write a JSON line to for each request, or - for stdout (disabled by default)"
+    )]
+    access_log: Option<String>,
+    #[clap(
+        long,
+        about = "Roll the access log over to a new file before it grows past this many bytes (0 = no size limit)",
+        default_value = "0"
+    )]
+    access_log_max_size: u64,
+    #[clap(long, about = "Roll the access log over to a new file each day (at midnight UTC)")]
+    access_log_daily: bool,
+    #[clap(long, about = "Gzip rotated access log files")]
+    access_log_compress: bool,
+    #[clap(
+        long,
+        about = "Number of rotated access log files to keep, deleting older ones (0 = keep them all)",
+        default_value = "7"
+    )]
+    access_log_keep: usize,
+    #[clap(
+        long,
+        about = "statsd server (or Datadog agent) to push request and upstream health metrics to over UDP, as host:port (disabled by default)"
+    )]
+    statsd_addr: Option<String>,
+    #[clap(
+        long,
+        about = "Prefix for the names of metrics pushed to statsd",
+        default_value = "balancebeam"
+    )]
+    statsd_prefix: String,
+    #[clap(
+        long,
+        about = "Tag metrics pushed to statsd DogStatsD-style (for Datadog), instead of putting upstreams and status classes in their names"
+    )]
+    statsd_tags: bool,
+    #[cfg(feature = "otel")]
+    #[clap(
+        long,
+        about = "OTLP/HTTP endpoint to export request traces to, e.g. http://localhost:4318/v1/traces"
+    )]
+    otlp_endpoint: Option<String>,
+    #[cfg(feature = "otel")]
+    #[clap(long, about = "Service name to report traces under", default_value = "balancebeam")]
+    otel_service_name: String,
+    #[cfg(feature = "discovery")]
+    #[clap(
+        long,
+        about = "Find upstreams through service discovery, as <backend>:<service>: consul:<service name>, etcd:<key prefix> or k8s:<namespace>/<service>[:<port>]. Instances are added and removed as they come and go",
+        parse(try_from_str = parse_discover),
+        conflicts_with_all = &["discovery", "discovery-service"]
+    )]
+    discover: Option<(DiscoveryBackend, String)>,
+    #[cfg(feature = "discovery")]
+    #[clap(
+        arg_enum,
+        long,
+        about = "Find upstreams through service discovery, adding and removing them as instances come and go (the same as --discover, with the service given separately)",
+        requires = "discovery-service"
+    )]
+    discovery: Option<DiscoveryBackend>,
+    #[cfg(feature = "discovery")]
+    #[clap(
+        long,
+        about = "Address of the Consul agent, etcd server or Kubernetes API server (default: 127.0.0.1:8500 for Consul, 127.0.0.1:2379 for etcd, and the cluster's API server for Kubernetes)"
+    )]
+    discovery_address: Option<String>,
+    #[cfg(feature = "discovery")]
+    #[clap(
+        long,
+        about = "Consul service whose healthy instances are upstreams, etcd key prefix under which each key's value is an upstream's address, or Kubernetes service as <namespace>/<service>[:<port>]"
+    )]
+    discovery_service: Option<String>,
+    #[cfg(feature = "discovery")]
+    #[clap(
+        long,
+        about = "Look the service's instances up again every this many seconds. Kubernetes services are watched for changes instead, and only listed again this long after a watch fails",
+        default_value = "10"
+    )]
+    discovery_interval: u64,
+    #[cfg(feature = "scripting")]
+    #[clap(
+        long,
+        multiple_occurrences = true,
+        about = "Rhai script to run each request past, defining fn on_request(request) to let it through, turn it away or rewrite it. Can be given more than once; scripts run in order"
+    )]
+    script: Vec<String>,
 }
 
-/// Contains information about the state of balancebeam (e.g. what servers we are currently proxying
-/// to, what servers have failed, rate limiting counts, etc.)
-///
-/// You should add fields to this struct in later milestones.
-pub struct ProxyState {
-    /// How frequently we check whether upstream servers are alive (Milestone 4)
-    active_health_check_interval: usize,
-    /// Where we should send requests when doing active health checks (Milestone 4)
-    active_health_check_path: String,
-    /// Maximum number of requests an individual IP can make in a minute (Milestone 5)
-    #[allow(dead_code)]
-    max_requests_per_minute: usize,
-    /// Addresses of servers that we are proxying to
-    upstream_addresses: Vec<String>,
-    /// Status of upstream servers
-    upstream_status: RwLock<UpstreamsStatus>,
-    /// Strategy of limiter to use
-    limiter: Mutex<Box<dyn RateLimiterStrategy>>,
-    /// Strategy of load balancer to use
-    load_balancer: Box<dyn LoadBalanceStrategy>
-}
-
-struct UpstreamsStatus {
-    /// Alive upstream counts
-    counts: usize,
-    /// Upstream status, one by one match upstream_addresses
-    status: Vec<bool>
+/// Things balancebeam can do other than run the proxy
+#[derive(Clap, Debug)]
+enum Command {
+    #[clap(about = "Send requests to a running proxy as fast as it answers them, and report throughput and latency percentiles")]
+    Bench(bench::BenchOptions),
 }
 
-impl UpstreamsStatus {
-    fn new(counts: usize) -> UpstreamsStatus {
-        UpstreamsStatus { 
-            counts, 
-            status: vec![true; counts]
-        }
-    }
-
-    fn is_alive(&self, idx: usize) -> bool {
-        self.status[idx]
-    }
-
-    fn all_dead(&self) -> bool {
-        self.counts == 0
-    }
-
-    fn set_up(&mut self, idx: usize) {
-        if !self.is_alive(idx) { 
-            self.counts += 1;
-            self.status[idx] = true;
-        }
-    }
-
-    fn set_down(&mut self, idx: usize) {
-        if self.is_alive(idx) {
-            self.counts -= 1;
-            self.status[idx] = false;
+impl CmdOptions {
+    /// Settings given on the command line, before any config file is applied
+    fn to_config(&self) -> Config {
+        Config {
+            upstreams: self.upstream.clone(),
+            upstreams_file: self.upstreams_file.clone(),
+            file_upstreams: Vec::new(),
+            discovered_upstreams: Vec::new(),
+            pools: self
+                .pool
+                .iter()
+                .map(|(name, hosts)| (name.clone(), hosts.split(',').map(String::from).collect()))
+                .collect(),
+            routes: self.route.iter().cloned().collect(),
+            route_policies: BTreeMap::new(),
+            client_routes: Vec::new(),
+            blue_pool: self.blue_pool.clone(),
+            green_pool: self.green_pool.clone(),
+            blue_green_drain_timeout: self.blue_green_drain_timeout,
+            canaries: self.canary.iter().cloned().collect(),
+            mirror: self.mirror.clone(),
+            drain: self.drain.clone(),
+            cache_paths: self.cache_path.clone(),
+            maintenance_page: self.maintenance_page.clone(),
+            maintenance_allow_cidrs: self.maintenance_allow_cidr.clone(),
+            upstream_override: self.upstream_override,
+            upstream_override_cidrs: self.upstream_override_cidr.clone(),
+            trusted_proxies: self.trusted_proxies.clone(),
+            force_https: self.force_https,
+            canonical_host: self.canonical_host.clone(),
+            redirects: self.redirect.clone(),
+            error_pages: self
+                .error_page
+                .iter()
+                .map(|(status, path)| {
+                    (*status, ErrorPage { file: Some(path.clone()), ..ErrorPage::default() })
+                })
+                .collect(),
+            request_headers: self.request_header.clone(),
+            response_headers: self.response_header.clone(),
+            active_health_check_interval: self.active_health_check_interval,
+            active_health_check_path: self.active_health_check_path.clone(),
+            active_health_check_method: self.active_health_check_method.clone(),
+            active_health_check_status: self.active_health_check_status,
+            active_health_check_body: self.active_health_check_body.clone(),
+            active_health_check_timeout: self.active_health_check_timeout,
+            passive_health_check_failures: self.passive_health_check_failures,
+            passive_health_check_window: self.passive_health_check_window,
+            dns_refresh_interval: self.dns_refresh_interval,
+            slow_start_window: self.slow_start_window,
+            health_webhook: self.health_webhook.clone(),
+            health_command: self.health_command.clone(),
+            stats_log_interval: self.stats_log_interval,
+            max_retries: self.max_retries,
+            client_read_timeout: self.client_read_timeout,
+            client_idle_timeout: self.client_idle_timeout,
+            client_write_timeout: self.client_write_timeout,
+            client_min_rate: self.client_min_rate,
+            max_requests_per_connection: self.max_requests_per_connection,
+            upstream_connect_timeout: self.upstream_connect_timeout,
+            upstream_connect_backoff: self.upstream_connect_backoff,
+            shutdown_timeout: self.shutdown_timeout,
+            upstream_response_timeout: self.upstream_response_timeout,
+            max_upstream_rps: self.max_upstream_rps,
+            upstream_queue_timeout: self.upstream_queue_timeout,
+            max_upstream_concurrency: self.max_upstream_concurrency,
+            request_queue_size: self.request_queue_size,
+            request_queue_timeout: self.request_queue_timeout,
+            circuit_breaker_error_rate: self.circuit_breaker_error_rate,
+            circuit_breaker_window: self.circuit_breaker_window,
+            circuit_breaker_cooldown: self.circuit_breaker_cooldown,
+            circuit_breaker_trial_requests: self.circuit_breaker_trial_requests,
+            max_requests_per_minute: self.max_requests_per_minute,
+            max_concurrent_per_ip: self.max_concurrent_per_ip,
+            max_bytes_per_minute: self.max_bytes_per_minute,
+            rate_limiter: self.rate_limiter,
+            rate_limit_burst: self.rate_limit_burst,
+            #[cfg(feature = "redis")]
+            rate_limit_redis_url: self.rate_limit_redis_url.clone(),
+            rate_limit_by: self.rate_limit_by,
+            ban_threshold: self.ban_threshold,
+            ban_duration: self.ban_duration,
+            load_balancer: self.load_balancer,
+            hash_header: self.hash_header.clone(),
+            sticky_cookie: self.sticky_cookie.clone(),
+            disabled_proxy_headers: self.disable_proxy_header.clone(),
+            allow_cidrs: self.allow_cidr.clone(),
+            deny_cidrs: self.deny_cidr.clone(),
+            auth_htpasswd: self.auth_htpasswd.clone(),
+            auth_bearer_token: self.auth_bearer_token.clone(),
+            auth_realm: self.auth_realm.clone(),
+            jwt_hs256_secret: self.jwt_hs256_secret.clone(),
+            jwt_rs256_public_key: self.jwt_rs256_public_key.clone(),
+            jwt_issuer: self.jwt_issuer.clone(),
+            jwt_audience: self.jwt_audience.clone(),
+            jwt_route_claim: self.jwt_route_claim.clone(),
+            jwt_rate_limit_claim: self.jwt_rate_limit_claim.clone(),
+            cors_allow_origins: self.cors_allow_origin.clone(),
+            cors_allow_methods: self.cors_allow_method.clone(),
+            cors_allow_headers: self.cors_allow_header.clone(),
+            cors_expose_headers: self.cors_expose_header.clone(),
+            cors_allow_credentials: self.cors_allow_credentials,
+            cors_max_age: self.cors_max_age,
+            compression_min_size: self.compression_min_size,
+            compression_types: self.compression_type.clone(),
         }
     }
 }
@@ -130,225 +817,101 @@ async fn main() {
 
     // Parse the command line arguments passed to this program
     let options = CmdOptions::parse();
-    if options.upstream.len() < 1 {
-        log::error!("At least one upstream server must be specified using the --upstream option.");
-        std::process::exit(1);
-    }
-
-    // Start listening for connections
-    let listener = match TcpListener::bind(&options.bind).await {
-        Ok(listener) => listener,
-        Err(err) => {
-            log::error!("Could not bind to {}: {}", options.bind, err);
+    if let Some(Command::Bench(bench_options)) = &options.command {
+        if let Err(err) = bench::run(bench_options).await {
+            log::error!("{}", err);
             std::process::exit(1);
         }
-    };
-    log::info!("Listening for requests on {}", options.bind);
-
-    let upstreams_counts = options.upstream.len();
-    // Handle incoming connections
-    let state = ProxyState {
-        upstream_addresses: options.upstream,
-        upstream_status: RwLock::new(UpstreamsStatus::new(upstreams_counts)),
-        active_health_check_interval: options.active_health_check_interval,
-        active_health_check_path: options.active_health_check_path,
-        max_requests_per_minute: options.max_requests_per_minute,
-        limiter: Mutex::new(set_up_rate_limiter(options.rate_limiter, options.max_requests_per_minute)),
-        load_balancer: options.load_balancer.into(),
-    };
-    
-    let shared_state = Arc::new(state);
-    
-    let shared_state_ref = shared_state.clone();
-    tokio::spawn(async move {
-        active_health_check(shared_state_ref).await;
-    });
-
-    if shared_state.max_requests_per_minute > 0 {
-        let shared_state_ref = shared_state.clone();
-        tokio::spawn(async move {
-            limiter_refresh(shared_state_ref, 60).await;
-        });
+        return;
     }
-
-    loop {
-        match listener.accept().await {
-            Ok((mut stream, _)) => {
-                if shared_state.max_requests_per_minute > 0 {
-                    let mut limiter = shared_state.limiter.lock().await;
-                    let addr = stream.peer_addr().unwrap().ip();
-                    if !limiter.register_request(addr) {
-                        let response = response::make_http_error(http::StatusCode::TOO_MANY_REQUESTS);
-                        response::write_to_stream(&response, &mut stream).await.unwrap();
-                        continue;
-                    }
-                }
-                let shared_state_ref = shared_state.clone();
-                tokio::spawn(async move {
-                    handle_connection(stream, shared_state_ref).await
-                });
-            },
-            Err(_) => { break; },
-        }
+    let mut proxy = Proxy::with_config(options.to_config())
+        .max_connections(options.max_connections, options.max_connections_per_ip)
+        .mode(options.mode);
+    for bind in &options.bind {
+        proxy = proxy.bind(bind);
     }
-}
-
-fn set_up_rate_limiter(limiter: ArgRateLimiter, max_requests_per_minute: usize) -> Box<dyn RateLimiterStrategy> {
-    match limiter {
-        ArgRateLimiter::Counter => {
-            Box::new(Counter::new(max_requests_per_minute))
-        }
+    if let Some(path) = &options.config {
+        proxy = proxy.config_file(path);
     }
-}
-
-async fn limiter_refresh(state: Arc<ProxyState>, interval: u64) {
-    sleep(Duration::from_secs(interval)).await;
-    let mut limiter = state.limiter.lock().await;
-    limiter.refresh()
-}
-
-async fn check_server(state: &Arc<ProxyState>, idx: usize, path: &String) -> Option<bool> {
-    let addr = &state.upstream_addresses[idx];
-    if let Ok(mut stream) = TcpStream::connect(addr).await {
-        let request = http::Request::builder()
-                .method(http::Method::GET)
-                .uri(path)
-                .header("Host", addr)
-                .body(Vec::new())
-                .unwrap();
-        let _ = request::write_to_stream(&request, &mut stream).await.ok()?;
-        let res = response::read_from_stream(&mut stream, &http::Method::GET).await.ok()?;
-        if res .status().as_u16() != 200 {
-            None
-        } else {
-            Some(true)
-        }
-    } else {
-        None
+    if let (Some(cert_path), Some(key_path)) = (&options.tls_cert, &options.tls_key) {
+        proxy = proxy.tls(cert_path, key_path);
     }
-}
-
-async fn active_health_check(state: Arc<ProxyState>) {
-    let interval = state.active_health_check_interval as u64;
-    let path = &state.active_health_check_path;
-    loop {
-        sleep(Duration::from_secs(interval)).await;
-        let mut upstream_status = state.upstream_status.write().await;
-        for idx in 0..upstream_status.status.len() {
-            if check_server(&state, idx, path).await.is_some() {
-                upstream_status.set_up(idx);
-            } else {
-                upstream_status.set_down(idx);
-            }
-        }
+    if let Some(path) = &options.tls_client_ca {
+        proxy = proxy.tls_client_ca(path);
     }
-}
-
-async fn connect_to_upstream(state: Arc<ProxyState>) -> Result<TcpStream, std::io::Error> {
-    loop {
-        if let Some(idx) = state.load_balancer.select_backend(&state).await {
-            let addr = &state.upstream_addresses[idx];
-            match TcpStream::connect(addr).await {
-                Ok(stream) => return Ok(stream),
-                Err(err) => {
-                    log::error!("Failed to connect to upstream {}: {}", addr, err);
-                    let mut upstream_status = state.upstream_status.write().await;
-                    upstream_status.set_down(idx);
-                }
-            }
-        } else {
-            return Err(std::io::Error::new(ErrorKind::Other, "All the upstream servers are down!"));
-        }
+    if options.tls_auto_detect {
+        proxy = proxy.tls_auto_detect();
     }
-}
-
-async fn send_response(client_conn: &mut TcpStream, response: &http::Response<Vec<u8>>) {
-    let client_ip = client_conn.peer_addr().unwrap().ip().to_string();
-    log::info!("{} <- {}", client_ip, response::format_response_line(&response));
-    if let Err(error) = response::write_to_stream(&response, client_conn).await {
-        log::warn!("Failed to send response to client: {}", error);
-        return;
+    if let Some(path) = &options.upstream_ca_cert {
+        proxy = proxy.upstream_ca_cert(path);
+    }
+    if let (Some(cert_path), Some(key_path)) =
+        (&options.upstream_client_cert, &options.upstream_client_key)
+    {
+        proxy = proxy.upstream_client_cert(cert_path, key_path);
+    }
+    if options.http2 {
+        proxy = proxy.http2();
+    }
+    if let Some(admin_bind) = &options.admin_bind {
+        proxy = proxy.admin_bind(admin_bind);
+    }
+    if let Some(path) = &options.access_log {
+        proxy = proxy.access_log(path).access_log_rotation(AccessLogRotation {
+            max_size: options.access_log_max_size,
+            daily: options.access_log_daily,
+            compress: options.access_log_compress,
+            keep: options.access_log_keep,
+        });
+    }
+    if let Some(address) = &options.statsd_addr {
+        proxy = proxy.statsd(StatsdSettings {
+            address: address.clone(),
+            prefix: options.statsd_prefix.clone(),
+            tags: options.statsd_tags,
+        });
+    }
+    #[cfg(feature = "discovery")]
+    let discover = options.discover.clone().or_else(|| {
+        options.discovery.zip(options.discovery_service.clone())
+    });
+    #[cfg(feature = "discovery")]
+    if let Some((backend, service)) = discover {
+        let address = options.discovery_address.as_deref();
+        let mut settings = DiscoverySettings::new(backend, address, &service).unwrap_or_else(|err| {
+            log::error!("{}", err);
+            std::process::exit(1);
+        });
+        settings.interval = Duration::from_secs(options.discovery_interval.max(1));
+        proxy = proxy.discovery(settings);
+    }
+    #[cfg(feature = "scripting")]
+    for path in &options.script {
+        proxy = proxy.script(path);
     }
-}
 
-async fn handle_connection(mut client_conn: TcpStream, state: Arc<ProxyState>) {
-    let client_ip = client_conn.peer_addr().unwrap().ip().to_string();
-    log::info!("Connection received from {}", client_ip);
+    #[cfg(feature = "otel")]
+    let telemetry = options.otlp_endpoint.as_ref().map(|endpoint| {
+        balancebeam::Telemetry::install(endpoint, &options.otel_service_name).unwrap_or_else(|err| {
+            log::error!("Could not set up trace exporting: {}", err);
+            std::process::exit(1);
+        })
+    });
 
-    // Open a connection to a random destination server
-    let mut upstream_conn = match connect_to_upstream(state).await {
-        Ok(stream) => stream,
-        Err(_error) => {
-            let response = response::make_http_error(http::StatusCode::BAD_GATEWAY);
-            send_response(&mut client_conn, &response).await;
-            return;
+    let result = proxy.run().await;
+    #[cfg(feature = "otel")]
+    if let Some(telemetry) = telemetry {
+        telemetry.shutdown();
+    }
+    match result {
+        Ok(()) => {}
+        Err(balancebeam::Error::Config(ConfigError::NoUpstreams)) => {
+            log::error!("At least one upstream server must be specified using the --upstream option or the config file.");
+            std::process::exit(1);
         }
-    };
-    let upstream_ip = client_conn.peer_addr().unwrap().ip().to_string();
-
-    // The client may now send us one or more requests. Keep trying to read requests until the
-    // client hangs up or we get an error.
-    loop {
-        // Read a request from the client
-        let mut request = match request::read_from_stream(&mut client_conn).await {
-            Ok(request) => request,
-            // Handle case where client closed connection and is no longer sending requests
-            Err(request::Error::IncompleteRequest(0)) => {
-                log::debug!("Client finished sending requests. Shutting down connection");
-                return;
-            }
-            // Handle I/O error in reading from the client
-            Err(request::Error::ConnectionError(io_err)) => {
-                log::info!("Error reading request from client stream: {}", io_err);
-                return;
-            }
-            Err(error) => {
-                log::debug!("Error parsing request: {:?}", error);
-                let response = response::make_http_error(match error {
-                    request::Error::IncompleteRequest(_)
-                    | request::Error::MalformedRequest(_)
-                    | request::Error::InvalidContentLength
-                    | request::Error::ContentLengthMismatch => http::StatusCode::BAD_REQUEST,
-                    request::Error::RequestBodyTooLarge => http::StatusCode::PAYLOAD_TOO_LARGE,
-                    request::Error::ConnectionError(_) => http::StatusCode::SERVICE_UNAVAILABLE,
-                });
-                send_response(&mut client_conn, &response).await;
-                continue;
-            }
-        };
-        log::info!(
-            "{} -> {}: {}",
-            client_ip,
-            upstream_ip,
-            request::format_request_line(&request)
-        );
-
-        // Add X-Forwarded-For header so that the upstream server knows the client's IP address.
-        // (We're the ones connecting directly to the upstream server, so without this header, the
-        // upstream server will only know our IP, not the client's.)
-        request::extend_header_value(&mut request, "x-forwarded-for", &client_ip);
-
-        // Forward the request to the server
-        if let Err(error) = request::write_to_stream(&request, &mut upstream_conn).await {
-            log::error!("Failed to send request to upstream {}: {}", upstream_ip, error);
-            let response = response::make_http_error(http::StatusCode::BAD_GATEWAY);
-            send_response(&mut client_conn, &response).await;
-            return;
+        Err(err) => {
+            log::error!("{}", err);
+            std::process::exit(1);
         }
-        log::debug!("Forwarded request to server");
-
-        // Read the server's response
-        let response = match response::read_from_stream(&mut upstream_conn, request.method()).await {
-            Ok(response) => response,
-            Err(error) => {
-                log::error!("Error reading response from server: {:?}", error);
-                let response = response::make_http_error(http::StatusCode::BAD_GATEWAY);
-                send_response(&mut client_conn, &response).await;
-                return;
-            }
-        };
-        // Forward the response to the client
-        send_response(&mut client_conn, &response).await;
-        log::debug!("Forwarded response to client");
     }
 }
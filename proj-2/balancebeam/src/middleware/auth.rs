@@ -0,0 +1,32 @@
+use async_trait::async_trait;
+use crate::auth::Outcome;
+use super::{Action, Context, Middleware};
+
+/// Answers requests without valid credentials with 401, when authentication is set up
+pub struct Authenticate {}
+
+#[async_trait]
+impl Middleware for Authenticate {
+    async fn on_request(
+        &self,
+        context: &Context<'_>,
+        request: &mut http::Request<Vec<u8>>,
+    ) -> Action {
+        let authenticator = context.state.authenticator.read().await;
+        if !authenticator.is_enabled() {
+            return Action::Continue;
+        }
+        let outcome = authenticator.check(request).await;
+        if outcome == Outcome::Allowed {
+            return Action::Continue;
+        }
+        let status = http::StatusCode::UNAUTHORIZED;
+        let mut response = context.state.error_response(status, Some(request), None).await;
+        for challenge in authenticator.challenges(&outcome) {
+            if let Ok(value) = http::HeaderValue::from_str(&challenge) {
+                response.headers_mut().append(http::header::WWW_AUTHENTICATE, value);
+            }
+        }
+        Action::Respond(response)
+    }
+}
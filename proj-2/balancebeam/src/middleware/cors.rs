@@ -0,0 +1,36 @@
+use async_trait::async_trait;
+use super::{Action, Context, Middleware};
+
+/// Answers CORS preflight requests, and adds the Access-Control-Allow-* headers to responses,
+/// when some origins are allowed to make cross-origin requests
+pub struct Cors {}
+
+#[async_trait]
+impl Middleware for Cors {
+    async fn on_request(
+        &self,
+        context: &Context<'_>,
+        request: &mut http::Request<Vec<u8>>,
+    ) -> Action {
+        let cors = context.state.cors.read().await;
+        if !cors.is_enabled() {
+            return Action::Continue;
+        }
+        match cors.preflight_response(request) {
+            Some(response) => Action::Respond(response),
+            None => Action::Continue,
+        }
+    }
+
+    async fn on_response(
+        &self,
+        context: &Context<'_>,
+        request: &http::Request<Vec<u8>>,
+        response: &mut http::Response<Vec<u8>>,
+    ) {
+        let cors = context.state.cors.read().await;
+        if cors.is_enabled() {
+            cors.add_response_headers(request, response);
+        }
+    }
+}
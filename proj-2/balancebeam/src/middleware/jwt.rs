@@ -0,0 +1,39 @@
+use async_trait::async_trait;
+use crate::jwt;
+use super::{Action, Context, Middleware};
+
+/// Answers requests without a valid JSON Web Token with 401, when JWT validation is set up. The
+/// claims of valid tokens are kept with the request, for routing and rate limiting by.
+pub struct JwtAuth {}
+
+#[async_trait]
+impl Middleware for JwtAuth {
+    async fn on_request(
+        &self,
+        context: &Context<'_>,
+        request: &mut http::Request<Vec<u8>>,
+    ) -> Action {
+        let validator = context.state.jwt_validator.read().await;
+        let validator = match validator.as_ref() {
+            Some(validator) => validator,
+            None => return Action::Continue,
+        };
+        let challenge = match jwt::bearer_token(request).map(|token| validator.validate(token)) {
+            Some(Ok(claims)) => {
+                request.extensions_mut().insert(claims);
+                return Action::Continue;
+            }
+            Some(Err(reason)) => {
+                log::info!("Rejecting invalid token from {}: {}", context.client_ip, reason);
+                format!("Bearer realm=\"{}\", error=\"invalid_token\"", validator.realm)
+            }
+            None => format!("Bearer realm=\"{}\"", validator.realm),
+        };
+        let status = http::StatusCode::UNAUTHORIZED;
+        let mut response = context.state.error_response(status, Some(request), None).await;
+        if let Ok(value) = http::HeaderValue::from_str(&challenge) {
+            response.headers_mut().insert(http::header::WWW_AUTHENTICATE, value);
+        }
+        Action::Respond(response)
+    }
+}
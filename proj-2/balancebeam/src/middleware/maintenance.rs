@@ -0,0 +1,52 @@
+use std::sync::atomic::Ordering;
+use async_trait::async_trait;
+use crate::error_pages;
+use super::{Action, Context, Middleware};
+
+/// While maintenance mode is on, answers requests with 503 and the maintenance page, except those
+/// from the IP ranges allowed through
+pub struct Maintenance {}
+
+#[async_trait]
+impl Middleware for Maintenance {
+    async fn on_request(
+        &self,
+        context: &Context<'_>,
+        request: &mut http::Request<Vec<u8>>,
+    ) -> Action {
+        let state = context.state;
+        if !state.maintenance.load(Ordering::SeqCst) {
+            return Action::Continue;
+        }
+        let allowed = state.maintenance_allow_cidrs.read().await;
+        if allowed.iter().any(|cidr| cidr.contains(context.client_ip)) {
+            return Action::Continue;
+        }
+        let status = http::StatusCode::SERVICE_UNAVAILABLE;
+        let page = state.maintenance_page.read().await;
+        let response = match page.as_ref() {
+            Some((content_type, body)) => http::Response::builder()
+                .status(status)
+                .header("Content-Type", *content_type)
+                .header("Content-Length", body.len().to_string())
+                .version(http::Version::HTTP_11)
+                .body(body.clone())
+                .unwrap(),
+            None => state.error_response(status, Some(request), None).await,
+        };
+        Action::Respond(response)
+    }
+}
+
+/// Reads the maintenance page, returning its content type and contents. Falls back to the usual
+/// 503 response if there's no page or it can't be read.
+pub fn load_page(path: Option<&str>) -> Option<(&'static str, Vec<u8>)> {
+    let path = path?;
+    match std::fs::read(path) {
+        Ok(body) => Some((error_pages::content_type_for(path), body)),
+        Err(err) => {
+            log::warn!("Could not read maintenance page {}: {}", path, err);
+            None
+        }
+    }
+}
@@ -0,0 +1,98 @@
+use std::net::IpAddr;
+use std::sync::Arc;
+use async_trait::async_trait;
+use crate::proxy_headers::Frontend;
+use crate::state::ProxyState;
+
+mod auth;
+mod cors;
+mod jwt;
+mod maintenance;
+mod proxy_headers;
+mod rate_limit;
+mod redirect;
+#[cfg(feature = "scripting")]
+mod script;
+mod upstream_override;
+
+pub(crate) use auth::Authenticate;
+pub(crate) use cors::Cors;
+pub(crate) use jwt::JwtAuth;
+pub(crate) use maintenance::{load_page as load_maintenance_page, Maintenance};
+pub(crate) use proxy_headers::ProxyHeaders;
+pub(crate) use rate_limit::{ApiKeyRateLimit, BandwidthLimit, ConcurrentRequestLimit};
+pub(crate) use rate_limit::RouteRateLimit;
+pub(crate) use redirect::Redirect;
+#[cfg(feature = "scripting")]
+pub use script::Script;
+pub(crate) use upstream_override::{ForcedUpstream, UpstreamOverride};
+
+/// What to do with a request once a middleware has looked at it
+pub enum Action {
+    /// Pass the request on to the next middleware, and then to an upstream
+    Continue,
+    /// Answer the request with this response instead of forwarding it
+    Respond(http::Response<Vec<u8>>),
+}
+
+/// Where a request came from
+pub struct Context<'a> {
+    /// IP address of the client that sent the request. When it came through a trusted proxy, this
+    /// is the address the proxy says it came from.
+    pub client_ip: IpAddr,
+    /// IP address the request arrived from, which X-Forwarded-For is extended with
+    pub(crate) peer_ip: IpAddr,
+    /// The listener the client connected to
+    pub(crate) frontend: Frontend,
+    pub(crate) state: &'a ProxyState,
+}
+
+/// Hooks that run on every request and response passing through the proxy. Middlewares run in
+/// the order they were added on the way in, and in reverse order on the way out.
+#[async_trait]
+pub trait Middleware: Send + Sync {
+    /// Called with each request's headers before it is forwarded. The body hasn't been read yet.
+    async fn on_request(
+        &self,
+        _context: &Context<'_>,
+        _request: &mut http::Request<Vec<u8>>,
+    ) -> Action {
+        Action::Continue
+    }
+
+    /// Called with the headers of each response from an upstream before it is passed on to the
+    /// client. The body hasn't been read yet.
+    async fn on_response(
+        &self,
+        _context: &Context<'_>,
+        _request: &http::Request<Vec<u8>>,
+        _response: &mut http::Response<Vec<u8>>,
+    ) {
+    }
+}
+
+/// Runs the request through the middlewares, stopping at the first one that answers it
+pub(crate) async fn run_request(
+    middlewares: &[Arc<dyn Middleware>],
+    context: &Context<'_>,
+    request: &mut http::Request<Vec<u8>>,
+) -> Action {
+    for middleware in middlewares {
+        if let Action::Respond(response) = middleware.on_request(context, request).await {
+            return Action::Respond(response);
+        }
+    }
+    Action::Continue
+}
+
+/// Runs the response through the middlewares, last one first
+pub(crate) async fn run_response(
+    middlewares: &[Arc<dyn Middleware>],
+    context: &Context<'_>,
+    request: &http::Request<Vec<u8>>,
+    response: &mut http::Response<Vec<u8>>,
+) {
+    for middleware in middlewares.iter().rev() {
+        middleware.on_response(context, request, response).await;
+    }
+}
@@ -0,0 +1,34 @@
+use async_trait::async_trait;
+use crate::proxy_headers;
+use super::{Action, Context, Middleware};
+
+/// Adds the X-Forwarded-* and Via headers, except those turned off with --disable-proxy-header
+pub struct ProxyHeaders {}
+
+#[async_trait]
+impl Middleware for ProxyHeaders {
+    async fn on_request(
+        &self,
+        context: &Context<'_>,
+        request: &mut http::Request<Vec<u8>>,
+    ) -> Action {
+        let disabled = context.state.disabled_proxy_headers.read().await;
+        proxy_headers::add_request_headers(
+            request,
+            &context.peer_ip.to_string(),
+            context.frontend,
+            &disabled,
+        );
+        Action::Continue
+    }
+
+    async fn on_response(
+        &self,
+        context: &Context<'_>,
+        _request: &http::Request<Vec<u8>>,
+        response: &mut http::Response<Vec<u8>>,
+    ) {
+        let disabled = context.state.disabled_proxy_headers.read().await;
+        proxy_headers::add_response_headers(response, &disabled);
+    }
+}
@@ -0,0 +1,140 @@
+use std::sync::atomic::Ordering;
+use async_trait::async_trait;
+use crate::active_connections::ActiveConnection;
+use crate::rate_limiter::{RateLimitBy, RateLimitKey};
+use super::{Action, Context, Middleware};
+
+/// Counts each request against its API key's (or token's) rate limit, answering requests over the
+/// limit with 429. Limiting by client IP happens when connections are accepted instead, except for
+/// connections from trusted proxies, whose requests are counted here under the client each was
+/// forwarded for.
+pub struct ApiKeyRateLimit {}
+
+#[async_trait]
+impl Middleware for ApiKeyRateLimit {
+    async fn on_request(
+        &self,
+        context: &Context<'_>,
+        request: &mut http::Request<Vec<u8>>,
+    ) -> Action {
+        let state = context.state;
+        let limit_by = *state.rate_limit_by.read().await;
+        if state.max_requests_per_minute.load(Ordering::SeqCst) == 0
+            || (limit_by == RateLimitBy::Ip && !state.is_trusted_proxy(context.peer_ip).await)
+        {
+            return Action::Continue;
+        }
+        let key = RateLimitKey::by(limit_by, request, context.client_ip);
+        let limiter = state.limiter.load_full();
+        if limiter.register_request(key).await {
+            Action::Continue
+        } else {
+            log::info!("Rate limiting request from {}", context.client_ip);
+            state.record_rate_limit_violation(context.client_ip).await;
+            let status = http::StatusCode::TOO_MANY_REQUESTS;
+            Action::Respond(state.error_response(status, Some(request), None).await)
+        }
+    }
+}
+
+/// Counts each request under a route with its own rate limit against that route's limit,
+/// answering requests over it with 429
+pub struct RouteRateLimit {}
+
+#[async_trait]
+impl Middleware for RouteRateLimit {
+    async fn on_request(
+        &self,
+        context: &Context<'_>,
+        request: &mut http::Request<Vec<u8>>,
+    ) -> Action {
+        let state = context.state;
+        let route = state.routes.read().await.route_for(request.uri().path()).and_then(|route| {
+            let limit = route.policy.max_requests_per_minute?;
+            Some((route.prefix.clone(), limit))
+        });
+        let (prefix, limit) = match route {
+            Some((prefix, limit)) if limit > 0 => (prefix, limit),
+            _ => return Action::Continue,
+        };
+        let key = RateLimitKey::by(*state.rate_limit_by.read().await, request, context.client_ip);
+        let settings = state.config.read().await.rate_limiter_settings();
+        let scope = format!("route:{}", prefix);
+        let limiter = state
+            .route_limiters
+            .entry(prefix)
+            .or_insert_with(|| crate::state::set_up_rate_limiter(&settings, limit, &scope).into())
+            .clone();
+        if limiter.register_request(key).await {
+            Action::Continue
+        } else {
+            log::info!("Rate limiting request from {} under its route", context.client_ip);
+            state.record_rate_limit_violation(context.client_ip).await;
+            let status = http::StatusCode::TOO_MANY_REQUESTS;
+            Action::Respond(state.error_response(status, Some(request), None).await)
+        }
+    }
+}
+
+/// Kept in a request's extensions while it's in progress, counting it against its client's
+/// concurrent requests
+struct InProgress {
+    _counted: ActiveConnection,
+}
+
+/// Caps the number of requests each client IP may have in progress at once, answering any over the
+/// limit with 429. Unlike the per-minute rate limit, this catches clients that tie the proxy up
+/// with a few requests they take their time over.
+pub struct ConcurrentRequestLimit {}
+
+#[async_trait]
+impl Middleware for ConcurrentRequestLimit {
+    async fn on_request(
+        &self,
+        context: &Context<'_>,
+        request: &mut http::Request<Vec<u8>>,
+    ) -> Action {
+        let state = context.state;
+        let max = state.max_concurrent_per_ip.load(Ordering::SeqCst);
+        if max == 0 {
+            return Action::Continue;
+        }
+        let client_ip = context.client_ip.to_string();
+        match state.client_requests.try_track(&client_ip, max) {
+            Some(in_progress) => {
+                // Dropped along with the request, once it has been answered
+                request.extensions_mut().insert(InProgress { _counted: in_progress });
+                Action::Continue
+            }
+            None => {
+                log::info!("Too many requests in progress from {}", context.client_ip);
+                state.record_rate_limit_violation(context.client_ip).await;
+                let status = http::StatusCode::TOO_MANY_REQUESTS;
+                Action::Respond(state.error_response(status, Some(request), None).await)
+            }
+        }
+    }
+}
+
+/// Answers requests from client IPs that have gone over their quota of bytes for the minute with
+/// 429, so a client can't hog the proxy's bandwidth with a few huge requests or downloads
+pub struct BandwidthLimit {}
+
+#[async_trait]
+impl Middleware for BandwidthLimit {
+    async fn on_request(
+        &self,
+        context: &Context<'_>,
+        request: &mut http::Request<Vec<u8>>,
+    ) -> Action {
+        let state = context.state;
+        let max = state.max_bytes_per_minute.load(Ordering::SeqCst);
+        if state.bandwidth.allows(context.client_ip, max) {
+            return Action::Continue;
+        }
+        log::info!("{} is over its bandwidth quota", context.client_ip);
+        state.record_rate_limit_violation(context.client_ip).await;
+        let status = http::StatusCode::TOO_MANY_REQUESTS;
+        Action::Respond(state.error_response(status, Some(request), None).await)
+    }
+}
@@ -0,0 +1,22 @@
+use async_trait::async_trait;
+use crate::response;
+use super::{Action, Context, Middleware};
+
+/// Answers requests that the redirect rules (forcing HTTPS, the canonical host name and path
+/// redirects) send elsewhere, without contacting an upstream
+pub struct Redirect {}
+
+#[async_trait]
+impl Middleware for Redirect {
+    async fn on_request(
+        &self,
+        context: &Context<'_>,
+        request: &mut http::Request<Vec<u8>>,
+    ) -> Action {
+        let redirects = context.state.redirects.read().await;
+        match redirects.redirect_for(request, context.frontend.https) {
+            Some((status, location)) => Action::Respond(response::make_redirect(status, &location)),
+            None => Action::Continue,
+        }
+    }
+}
@@ -0,0 +1,159 @@
+use std::convert::TryFrom;
+use async_trait::async_trait;
+use rhai::{Dynamic, Engine, EvalAltResult, Map, Scope, AST};
+use super::{Action, Context, Middleware};
+
+/// Function a script has to define, which is called with each request
+const ENTRY_POINT: &str = "on_request";
+
+/// How many operations a script may take on one request before it's stopped, so that a script
+/// stuck in a loop can't hold up the proxy
+const MAX_OPERATIONS: u64 = 100_000;
+
+/// Runs each request past a Rhai script, which decides whether it gets through and may rewrite
+/// it. The script defines `fn on_request(request)`, where `request` is a map with the method,
+/// path, query, headers (lowercased names), client_ip and https. It returns:
+///
+/// * nothing or `true` to let the request through untouched
+/// * `false` to turn it away with 403, or a status code to turn it away with that
+/// * a map to do more: `status` (and optionally `body`) answers the request, `path` replaces the
+///   path and query, and `headers` sets each header given, removing those set to `()`
+///
+/// A script that fails or returns anything else answers the request with 500.
+pub struct Script {
+    path: String,
+    engine: Engine,
+    ast: AST,
+}
+
+impl Script {
+    /// Compiles the script at `path`, failing if it can't be read or doesn't parse
+    pub fn load(path: &str) -> Result<Script, Box<EvalAltResult>> {
+        let mut engine = Engine::new();
+        engine.set_max_operations(MAX_OPERATIONS);
+        let ast = engine.compile_file(path.into())?;
+        Ok(Script { path: path.to_string(), engine, ast })
+    }
+
+    /// Calls the script with the request, returning what it decided
+    fn decide(
+        &self,
+        context: &Context<'_>,
+        request: &http::Request<Vec<u8>>,
+    ) -> Result<Dynamic, String> {
+        let mut headers = Map::new();
+        for name in request.headers().keys() {
+            let values: Vec<&str> = request
+                .headers()
+                .get_all(name)
+                .iter()
+                .map(|value| value.to_str().unwrap_or(""))
+                .collect();
+            headers.insert(name.as_str().into(), values.join(", ").into());
+        }
+        let mut fields = Map::new();
+        fields.insert("method".into(), request.method().as_str().into());
+        fields.insert("path".into(), request.uri().path().into());
+        fields.insert("query".into(), request.uri().query().unwrap_or("").into());
+        fields.insert("headers".into(), headers.into());
+        fields.insert("client_ip".into(), context.client_ip.to_string().into());
+        fields.insert("https".into(), context.frontend.https.into());
+        self.engine
+            .call_fn::<Dynamic>(&mut Scope::new(), &self.ast, ENTRY_POINT, (fields,))
+            .map_err(|err| err.to_string())
+    }
+}
+
+#[async_trait]
+impl Middleware for Script {
+    async fn on_request(
+        &self,
+        context: &Context<'_>,
+        request: &mut http::Request<Vec<u8>>,
+    ) -> Action {
+        let state = context.state;
+        let decision = self.decide(context, request).and_then(|decision| apply(decision, request));
+        let (status, body) = match decision {
+            Ok(Decision::Allow) => return Action::Continue,
+            Ok(Decision::Deny(status, body)) => (status, body),
+            Err(err) => {
+                log::warn!("Script {} failed: {}", self.path, err);
+                let status = http::StatusCode::INTERNAL_SERVER_ERROR;
+                return Action::Respond(state.error_response(status, Some(request), None).await);
+            }
+        };
+        log::info!("Script {} turned away {} with {}", self.path, context.client_ip, status);
+        match body {
+            None => Action::Respond(state.error_response(status, Some(request), None).await),
+            Some(body) => {
+                let response = http::Response::builder()
+                    .status(status)
+                    .header("Content-Type", "text/plain")
+                    .header("Content-Length", body.len().to_string())
+                    .version(http::Version::HTTP_11)
+                    .body(body.into_bytes())
+                    .unwrap();
+                Action::Respond(response)
+            }
+        }
+    }
+}
+
+/// What becomes of a request once the script has looked at it
+enum Decision {
+    Allow,
+    Deny(http::StatusCode, Option<String>),
+}
+
+/// Makes sense of what the script returned, rewriting the request if it asked to
+fn apply(decision: Dynamic, request: &mut http::Request<Vec<u8>>) -> Result<Decision, String> {
+    if decision.is_unit() {
+        return Ok(Decision::Allow);
+    }
+    if let Ok(allow) = decision.as_bool() {
+        let forbidden = Decision::Deny(http::StatusCode::FORBIDDEN, None);
+        return Ok(if allow { Decision::Allow } else { forbidden });
+    }
+    if let Ok(status) = decision.as_int() {
+        return Ok(Decision::Deny(status_code(status)?, None));
+    }
+    let mut fields = decision
+        .try_cast::<Map>()
+        .ok_or_else(|| "on_request returned something other than a bool, int or map".to_string())?;
+    if let Some(status) = fields.remove("status") {
+        let status = status.as_int().map_err(|_| "status isn't an int".to_string())?;
+        let body = match fields.remove("body") {
+            Some(body) => Some(body.into_string().map_err(|_| "body isn't a string".to_string())?),
+            None => None,
+        };
+        return Ok(Decision::Deny(status_code(status)?, body));
+    }
+    if let Some(path) = fields.remove("path") {
+        let path = path.into_string().map_err(|_| "path isn't a string".to_string())?;
+        let uri: http::Uri = path.parse().map_err(|_| format!("invalid path {:?}", path))?;
+        *request.uri_mut() = uri;
+    }
+    if let Some(headers) = fields.remove("headers") {
+        let headers = headers.try_cast::<Map>().ok_or_else(|| "headers isn't a map".to_string())?;
+        for (name, value) in headers {
+            let name = http::header::HeaderName::from_bytes(name.as_bytes())
+                .map_err(|_| format!("invalid header name {:?}", name))?;
+            if value.is_unit() {
+                request.headers_mut().remove(&name);
+                continue;
+            }
+            let value = value.to_string();
+            let value = http::HeaderValue::from_str(&value)
+                .map_err(|_| format!("invalid value for header {}", name))?;
+            request.headers_mut().insert(name, value);
+        }
+    }
+    Ok(Decision::Allow)
+}
+
+fn status_code(status: i64) -> Result<http::StatusCode, String> {
+    u16::try_from(status)
+        .ok()
+        .and_then(|status| http::StatusCode::from_u16(status).ok())
+        .ok_or_else(|| format!("invalid status {}", status))
+}
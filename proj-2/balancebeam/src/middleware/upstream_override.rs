@@ -0,0 +1,76 @@
+use std::net::IpAddr;
+use std::sync::atomic::Ordering;
+use async_trait::async_trait;
+use crate::upstream;
+use super::{Action, Context, Middleware};
+
+/// Header that names the upstream a request should be sent to
+const HEADER: &str = "x-balancebeam-upstream";
+
+/// Index of the upstream a request was sent to with the X-Balancebeam-Upstream header. Requests
+/// carrying it go to that upstream and no other.
+#[derive(Clone, Copy, Debug)]
+pub struct ForcedUpstream(pub usize);
+
+/// With --upstream-override, sends requests from trusted clients to the upstream named by their
+/// X-Balancebeam-Upstream header, either by its address (or the `--upstream` entry it was resolved
+/// from) or by its index in the admin API's list of upstreams. The header is removed from every
+/// request, so upstreams never see it, and is ignored from anyone else.
+pub struct UpstreamOverride {}
+
+#[async_trait]
+impl Middleware for UpstreamOverride {
+    async fn on_request(
+        &self,
+        context: &Context<'_>,
+        request: &mut http::Request<Vec<u8>>,
+    ) -> Action {
+        let state = context.state;
+        let wanted = match request.headers_mut().remove(HEADER) {
+            Some(wanted) => wanted,
+            None => return Action::Continue,
+        };
+        if !state.upstream_override.load(Ordering::SeqCst)
+            || !trusted(context.client_ip, &state.upstream_override_cidrs.read().await)
+        {
+            log::debug!("Ignoring {} header from {}", HEADER, context.client_ip);
+            return Action::Continue;
+        }
+        let wanted = wanted.to_str().unwrap_or("").trim();
+        let found = find(&state.upstream_addresses.read().await, wanted);
+        match found {
+            Some(idx) => {
+                log::info!("{} sent its request to upstream {}", context.client_ip, wanted);
+                request.extensions_mut().insert(ForcedUpstream(idx));
+                Action::Continue
+            }
+            None => {
+                log::info!("{} asked for unknown upstream {:?}", context.client_ip, wanted);
+                let status = http::StatusCode::BAD_REQUEST;
+                Action::Respond(state.error_response(status, Some(request), None).await)
+            }
+        }
+    }
+}
+
+/// Returns true if the client may pick upstreams. Without any ranges set up, only clients on this
+/// host may.
+fn trusted(client_ip: IpAddr, cidrs: &[crate::Cidr]) -> bool {
+    if cidrs.is_empty() {
+        client_ip.to_canonical().is_loopback()
+    } else {
+        cidrs.iter().any(|cidr| cidr.contains(client_ip))
+    }
+}
+
+/// Finds the upstream named by index, backend address or `--upstream` entry
+fn find(addresses: &[String], wanted: &str) -> Option<usize> {
+    if let Ok(idx) = wanted.parse::<usize>() {
+        return Some(idx).filter(|&idx| idx < addresses.len());
+    }
+    addresses.iter().position(|address| {
+        address == wanted
+            || upstream::entry_of(address) == wanted
+            || address.rsplit_once('@').map(|(_, resolved)| resolved) == Some(wanted)
+    })
+}
@@ -0,0 +1,112 @@
+use std::io;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::{AsyncWrite, AsyncWriteExt};
+use bytes::{Bytes, BytesMut};
+use tokio::sync::{mpsc, Semaphore};
+use tokio_rustls::TlsConnector;
+use crate::body::{BodyReader, Framing};
+use crate::{chunked, request, response, upstream};
+
+/// Most mirrored requests that may be in flight at once. Past this, requests aren't mirrored, so
+/// a slow shadow backend can't pile up work in the proxy.
+const MAX_IN_FLIGHT: usize = 100;
+/// Number of body pieces that may be waiting to be sent to the shadow backend before it's cut off
+const BODY_BUFFER_PIECES: usize = 64;
+/// Longest a mirrored request may take, from connecting to reading the response headers
+const TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Sends copies of requests to a shadow backend, throwing away its responses. Clients never wait
+/// on the shadow backend, and nothing it does affects them.
+pub struct Mirror {
+    in_flight: Arc<Semaphore>,
+}
+
+impl Mirror {
+    pub fn new() -> Mirror {
+        Mirror { in_flight: Arc::new(Semaphore::new(MAX_IN_FLIGHT)) }
+    }
+
+    /// Starts sending a copy of the request to the shadow backend. The copy's body is tee'd off
+    /// the request's body as it is read.
+    pub fn send(
+        &self,
+        address: &str,
+        connector: &TlsConnector,
+        request: &http::Request<Vec<u8>>,
+        request_body: &mut BodyReader,
+    ) {
+        let permit = match self.in_flight.clone().try_acquire_owned() {
+            Ok(permit) => permit,
+            Err(_) => {
+                log::debug!("Too many mirrored requests in flight, not mirroring this one");
+                return;
+            }
+        };
+        let (tee, body) = mpsc::channel(BODY_BUFFER_PIECES);
+        request_body.set_tee(tee);
+        let framing = request_body.framing();
+        let address = address.to_string();
+        let connector = connector.clone();
+        let request = copy_head(request);
+        tokio::spawn(async move {
+            let mirrored = send_copy(&address, &connector, &request, framing, body);
+            match tokio::time::timeout(TIMEOUT, mirrored).await {
+                Ok(Ok(status)) => log::debug!("Mirror {} answered with {}", address, status),
+                Ok(Err(error)) => log::debug!("Failed to mirror request to {}: {}", address, error),
+                Err(_) => log::debug!("Timed out mirroring request to {}", address),
+            }
+            drop(permit);
+        });
+    }
+}
+
+/// Copies the request line and headers (the body is passed on separately)
+fn copy_head(request: &http::Request<Vec<u8>>) -> http::Request<Vec<u8>> {
+    let mut copy = http::Request::new(Vec::new());
+    *copy.method_mut() = request.method().clone();
+    *copy.uri_mut() = request.uri().clone();
+    *copy.version_mut() = request.version();
+    *copy.headers_mut() = request.headers().clone();
+    copy
+}
+
+/// Sends the request to the shadow backend, and returns the status it answered with
+async fn send_copy(
+    address: &str,
+    connector: &TlsConnector,
+    request: &http::Request<Vec<u8>>,
+    framing: Framing,
+    mut body: mpsc::Receiver<Option<Bytes>>,
+) -> io::Result<http::StatusCode> {
+    let mut stream = upstream::connect(address, connector).await?;
+    request::write_head(request, &mut stream).await?;
+    loop {
+        match body.recv().await {
+            Some(Some(piece)) => write_piece(&mut stream, framing, &piece).await?,
+            Some(None) => break,
+            // The client's body ended early, or we fell too far behind reading it
+            None => return Err(io::Error::other("request body cut off")),
+        }
+    }
+    if framing == Framing::Chunked {
+        chunked::write_end(&mut stream).await?;
+    }
+    stream.flush().await?;
+    match response::read_head(&mut stream, request.method(), BytesMut::new()).await {
+        Ok((response, _)) => Ok(response.status()),
+        Err(error) => Err(io::Error::other(format!("{:?}", error))),
+    }
+}
+
+async fn write_piece<S: AsyncWrite + Unpin>(
+    stream: &mut S,
+    framing: Framing,
+    piece: &[u8],
+) -> io::Result<()> {
+    if framing == Framing::Chunked {
+        chunked::write_chunk(stream, piece).await
+    } else {
+        stream.write_all(piece).await
+    }
+}
@@ -0,0 +1,42 @@
+use std::collections::VecDeque;
+use std::time::{Duration, Instant};
+use dashmap::DashMap;
+
+/// Keeps track of recent request failures for each upstream, so that an upstream that keeps
+/// failing can be marked down without waiting for the next active health check. Failures are
+/// recorded from every connection, so the history is sharded rather than locked as a whole.
+pub struct FailureTracker {
+    /// Times of the consecutive failures seen for each upstream address within the window
+    failures: DashMap<String, VecDeque<Instant>>,
+}
+
+impl FailureTracker {
+    pub fn new() -> FailureTracker {
+        FailureTracker { failures: DashMap::new() }
+    }
+
+    /// Records a failed request to the upstream. Returns true if the upstream has now failed
+    /// `threshold` times in a row within `window`, in which case its history is cleared.
+    pub fn record_failure(&self, address: &str, threshold: usize, window: Duration) -> bool {
+        let now = Instant::now();
+        let mut failures = self.failures.entry(address.to_string()).or_default();
+        failures.push_back(now);
+        while let Some(&oldest) = failures.front() {
+            if now.duration_since(oldest) <= window {
+                break;
+            }
+            failures.pop_front();
+        }
+        if failures.len() >= threshold {
+            failures.clear();
+            true
+        } else {
+            false
+        }
+    }
+
+    /// Records a successful request to the upstream, which breaks any run of failures
+    pub fn record_success(&self, address: &str) {
+        self.failures.remove(address);
+    }
+}
@@ -0,0 +1,128 @@
+use std::net::IpAddr;
+use crate::access_control::Cidr;
+use crate::request;
+
+/// Headers that balancebeam adds to tell upstreams (and clients) that a request went through a
+/// proxy. Each can be turned off with --disable-proxy-header.
+#[derive(clap::ArgEnum, Debug, Clone, Copy, PartialEq, serde::Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum ProxyHeader {
+    XForwardedFor,
+    XForwardedProto,
+    XForwardedPort,
+    XForwardedHost,
+    Via,
+}
+
+/// Header that carries the subject of the client's certificate, when clients have to present one
+const CLIENT_CERT_SUBJECT: &str = "x-client-cert-subject";
+
+/// The listener a client connected to
+#[derive(Clone, Copy, Debug)]
+pub struct Frontend {
+    /// Port we accepted the connection on
+    pub port: u16,
+    /// Whether the client is talking to us over TLS
+    pub https: bool,
+}
+
+/// Value to add to the Via header for a message with the given HTTP version
+fn via_value(version: http::Version) -> &'static str {
+    match version {
+        http::Version::HTTP_10 => "1.0 balancebeam",
+        _ => "1.1 balancebeam",
+    }
+}
+
+fn set_header(headers: &mut http::HeaderMap, name: &'static str, value: &str) {
+    if let Ok(value) = http::HeaderValue::from_str(value) {
+        headers.insert(name, value);
+    }
+}
+
+/// Adds the proxy headers to a request that is about to be forwarded to an upstream
+pub fn add_request_headers(
+    request: &mut http::Request<Vec<u8>>,
+    client_ip: &str,
+    frontend: Frontend,
+    disabled: &[ProxyHeader],
+) {
+    let enabled = |header| !disabled.contains(&header);
+    // Add X-Forwarded-For header so that the upstream server knows the client's IP address.
+    // (We're the ones connecting directly to the upstream server, so without this header, the
+    // upstream server will only know our IP, not the client's.)
+    if enabled(ProxyHeader::XForwardedFor) {
+        request::extend_header_value(request, "x-forwarded-for", client_ip);
+    }
+    if enabled(ProxyHeader::XForwardedProto) {
+        let proto = if frontend.https { "https" } else { "http" };
+        set_header(request.headers_mut(), "x-forwarded-proto", proto);
+    }
+    if enabled(ProxyHeader::XForwardedPort) {
+        set_header(request.headers_mut(), "x-forwarded-port", &frontend.port.to_string());
+    }
+    if enabled(ProxyHeader::XForwardedHost) {
+        if let Some(host) = request.headers().get(http::header::HOST).cloned() {
+            request.headers_mut().insert("x-forwarded-host", host);
+        }
+    }
+    if enabled(ProxyHeader::Via) {
+        let via = via_value(request.version());
+        request::extend_header_value(request, "via", via);
+    }
+}
+
+/// Tells upstreams whose certificate the client presented, if it presented one. Whatever the
+/// client sent in the header itself is dropped, so it can't pass itself off as someone else.
+pub fn set_client_cert_subject(request: &mut http::Request<Vec<u8>>, subject: Option<&str>) {
+    let headers = request.headers_mut();
+    headers.remove(CLIENT_CERT_SUBJECT);
+    if let Some(subject) = subject {
+        set_header(headers, CLIENT_CERT_SUBJECT, subject);
+    }
+}
+
+/// Works out who sent a request that may have come through proxies in front of us. Going from the
+/// right of X-Forwarded-For (the hop nearest us), addresses are believed as long as the one that
+/// added them is a trusted proxy, and the first untrusted one is the client. Anything a client
+/// put in the header itself is to the left of that, so it can't pass itself off as someone else.
+pub fn client_ip(request: &http::Request<Vec<u8>>, peer: IpAddr, trusted: &[Cidr]) -> IpAddr {
+    let is_trusted = |ip: IpAddr| trusted.iter().any(|cidr| cidr.contains(ip));
+    let mut client = peer;
+    if !is_trusted(peer) {
+        return client;
+    }
+    let hops: Vec<&str> = request
+        .headers()
+        .get_all("x-forwarded-for")
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .collect();
+    for hop in hops.into_iter().rev() {
+        // Stop at anything that isn't an address, rather than guess what a proxy meant by it
+        match hop.trim().parse() {
+            Ok(ip) => client = ip,
+            Err(_) => break,
+        }
+        if !is_trusted(client) {
+            break;
+        }
+    }
+    client
+}
+
+/// Adds the proxy headers to a response from an upstream that is about to be sent to the client
+pub fn add_response_headers(response: &mut http::Response<Vec<u8>>, disabled: &[ProxyHeader]) {
+    if disabled.contains(&ProxyHeader::Via) {
+        return;
+    }
+    let via = via_value(response.version());
+    let new_value = match response.headers().get(http::header::VIA) {
+        Some(existing_value) => [existing_value.as_bytes(), b", ", via.as_bytes()].concat(),
+        None => via.as_bytes().to_vec(),
+    };
+    if let Ok(value) = http::HeaderValue::from_bytes(&new_value) {
+        response.headers_mut().insert(http::header::VIA, value);
+    }
+}
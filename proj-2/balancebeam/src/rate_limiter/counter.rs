@@ -1,29 +1,33 @@
-use std::collections::HashMap;
-use std::net::IpAddr;
+use std::hash::Hash;
+use async_trait::async_trait;
+use dashmap::DashMap;
 use super::RateLimiterStrategy;
 
-pub struct Counter {
+/// Counts each client's requests, letting through up to `limit` of them until the counts are
+/// cleared. The counts are sharded, so clients hashed to different shards never wait on each other.
+pub struct Counter<K: Hash + Eq> {
     limit: usize,
-    requests: HashMap<IpAddr, usize>
+    requests: DashMap<K, usize>,
 }
 
-impl Counter {
-    pub fn new(limit: usize) -> Counter {
+impl<K: Hash + Eq> Counter<K> {
+    pub fn new(limit: usize) -> Counter<K> {
         Counter {
             limit,
-            requests: HashMap::new()
+            requests: DashMap::new(),
         }
     }
 }
 
-impl RateLimiterStrategy for Counter {
-    fn register_request(&mut self, addr: IpAddr) -> bool {
-        let count = self.requests.entry(addr).or_insert(0);
+#[async_trait]
+impl<K: Hash + Eq + Send + Sync + 'static> RateLimiterStrategy<K> for Counter<K> {
+    async fn register_request(&self, key: K) -> bool {
+        let mut count = self.requests.entry(key).or_insert(0);
         *count += 1;
         *count <= self.limit
     }
 
-    fn refresh(&mut self) {
+    async fn refresh(&self) {
         self.requests.clear()
     }
-}
\ No newline at end of file
+}
@@ -1,14 +1,97 @@
 use std::net::IpAddr;
+use async_trait::async_trait;
 
 pub mod counter;
+pub mod token_bucket;
+#[cfg(feature = "redis")]
+pub mod redis_counter;
 
-#[derive(clap::ArgEnum, Debug)]
+#[derive(clap::ArgEnum, Debug, Clone, Copy, PartialEq, serde::Deserialize)]
+#[serde(rename_all = "kebab-case")]
 pub enum ArgRateLimiter {
-    Counter
+    Counter,
+    TokenBucket,
+    /// Counts kept in Redis, shared by every balancebeam instance using the same server
+    #[cfg(feature = "redis")]
+    Redis,
 }
 
-pub trait RateLimiterStrategy: Send + Sync {
-    fn register_request(&mut self, addr: IpAddr) -> bool;
+/// How rate limiters are set up, apart from their quota
+#[derive(Debug, Clone, PartialEq)]
+pub struct Settings {
+    pub strategy: ArgRateLimiter,
+    /// Burst size for the token-bucket limiter (0 = same as the quota)
+    pub burst: usize,
+    /// Redis server the Redis limiter keeps its counts in
+    #[cfg(feature = "redis")]
+    pub redis_url: Option<String>,
+}
+
+/// What requests are grouped by when counting them against the rate limit
+#[derive(clap::ArgEnum, Debug, Clone, Copy, PartialEq, serde::Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum RateLimitBy {
+    /// Each client IP address gets its own quota
+    Ip,
+    /// Each API key (the X-Api-Key or Authorization header) gets its own quota, so clients
+    /// sharing an IP (e.g. behind a NAT) don't use up each other's. Requests without a key are
+    /// limited by IP.
+    ApiKey,
+    /// Each JSON Web Token's subject (or the claim given by --jwt-rate-limit-claim) gets its own
+    /// quota. Requests whose token doesn't have the claim are limited by IP.
+    Jwt,
+}
+
+/// Who a request is counted against
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum RateLimitKey {
+    Ip(IpAddr),
+    ApiKey(String),
+    /// Value of a claim in the request's validated JSON Web Token
+    Jwt(String),
+}
+
+impl RateLimitKey {
+    /// Returns the key to count the request against when limiting requests by the given
+    /// `RateLimitBy`
+    pub fn by(
+        limit_by: RateLimitBy,
+        request: &http::Request<Vec<u8>>,
+        client_ip: IpAddr,
+    ) -> RateLimitKey {
+        match limit_by {
+            RateLimitBy::Ip => RateLimitKey::Ip(client_ip),
+            RateLimitBy::ApiKey => RateLimitKey::for_request(request, client_ip),
+            RateLimitBy::Jwt => {
+                let claims = request.extensions().get::<crate::jwt::Claims>();
+                match claims.and_then(|claims| claims.rate_limit_key.clone()) {
+                    Some(key) => RateLimitKey::Jwt(key),
+                    None => RateLimitKey::Ip(client_ip),
+                }
+            }
+        }
+    }
+
+    /// Returns the key to count the request against, if it is limited by API key
+    pub fn for_request(request: &http::Request<Vec<u8>>, client_ip: IpAddr) -> RateLimitKey {
+        let api_key = ["x-api-key", "authorization"]
+            .iter()
+            .filter_map(|name| request.headers().get(*name))
+            .filter_map(|value| value.to_str().ok())
+            .find(|value| !value.is_empty());
+        match api_key {
+            Some(api_key) => RateLimitKey::ApiKey(api_key.to_string()),
+            None => RateLimitKey::Ip(client_ip),
+        }
+    }
+}
+
+/// Decides whether requests are let through. `K` is what requests are grouped by, e.g. the
+/// client's IP address. Limiters keep their counts behind their own locks, so that checking one
+/// client doesn't hold up the others while a limiter is waiting on I/O.
+#[async_trait]
+pub trait RateLimiterStrategy<K: Send + 'static>: Send + Sync {
+    async fn register_request(&self, key: K) -> bool;
 
-    fn refresh(&mut self);
+    async fn refresh(&self);
 }
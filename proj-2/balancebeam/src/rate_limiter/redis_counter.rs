@@ -0,0 +1,90 @@
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use async_trait::async_trait;
+use redis::aio::MultiplexedConnection;
+use tokio::sync::Mutex;
+use super::{RateLimiterStrategy, RateLimitKey};
+
+/// How long to wait for Redis before letting a request through without counting it
+const TIMEOUT: Duration = Duration::from_millis(500);
+
+/// Counts requests in Redis instead of in memory, so that every balancebeam instance using the
+/// same Redis server enforces one shared quota per client. Counts are kept per minute-long window,
+/// and Redis expires old windows by itself.
+///
+/// If Redis can't be reached, requests are let through rather than turning every client away.
+pub struct RedisRateLimiter {
+    client: redis::Client,
+    /// Shared by all requests, which Redis answers in order. Set up when the first request comes
+    /// in, and again after it breaks.
+    connection: Mutex<Option<MultiplexedConnection>>,
+    limit: usize,
+    /// Prefix of the Redis keys, so that limiters with different quotas don't share counts
+    namespace: String,
+}
+
+impl RedisRateLimiter {
+    /// Sets up a limiter using the Redis server at the given URL (e.g. "redis://10.0.0.5:6379").
+    /// `scope` tells apart limiters with different quotas, e.g. the routes with their own.
+    pub fn new(url: &str, limit: usize, scope: &str) -> Result<RedisRateLimiter, String> {
+        let client = redis::Client::open(url).map_err(|err| err.to_string())?;
+        Ok(RedisRateLimiter {
+            client,
+            connection: Mutex::new(None),
+            limit,
+            namespace: format!("balancebeam:rate-limit:{}", scope),
+        })
+    }
+
+    async fn connection(&self) -> redis::RedisResult<MultiplexedConnection> {
+        let mut connection = self.connection.lock().await;
+        if connection.is_none() {
+            *connection = Some(self.client.get_multiplexed_tokio_connection().await?);
+        }
+        Ok(connection.as_ref().unwrap().clone())
+    }
+
+    /// Adds the request to its window's count in Redis, and returns the new count
+    async fn count_request(&self, key: &str) -> redis::RedisResult<usize> {
+        let mut connection = self.connection().await?;
+        let (count,): (usize,) = redis::pipe()
+            .atomic()
+            .incr(key, 1)
+            .expire(key, 60)
+            .ignore()
+            .query_async(&mut connection)
+            .await?;
+        Ok(count)
+    }
+}
+
+#[async_trait]
+impl RateLimiterStrategy<RateLimitKey> for RedisRateLimiter {
+    async fn register_request(&self, key: RateLimitKey) -> bool {
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default();
+        let window = now.as_secs() / 60;
+        let key = match key {
+            RateLimitKey::Ip(ip) => format!("{}:ip:{}:{}", self.namespace, ip, window),
+            RateLimitKey::ApiKey(api_key) => {
+                format!("{}:key:{}:{}", self.namespace, api_key, window)
+            }
+            RateLimitKey::Jwt(claim) => format!("{}:jwt:{}:{}", self.namespace, claim, window),
+        };
+        match tokio::time::timeout(TIMEOUT, self.count_request(&key)).await {
+            Ok(Ok(count)) => count <= self.limit,
+            Ok(Err(err)) => {
+                log::warn!("Could not count request in Redis, letting it through: {}", err);
+                // Connect again next time, in case the connection is what broke
+                *self.connection.lock().await = None;
+                true
+            }
+            Err(_) => {
+                log::warn!("Timed out counting request in Redis, letting it through");
+                true
+            }
+        }
+    }
+
+    async fn refresh(&self) {
+        // Redis expires windows that are over
+    }
+}
@@ -0,0 +1,66 @@
+use std::hash::Hash;
+use std::time::Instant;
+use async_trait::async_trait;
+use dashmap::DashMap;
+use super::RateLimiterStrategy;
+
+struct Bucket {
+    tokens: f64,
+    last_refill: Instant,
+}
+
+/// Gives every client (IP or API key) a bucket of `burst` tokens that refills at a steady rate. Each request takes one
+/// token, so short bursts are allowed as long as the average rate stays under the limit. Buckets
+/// are sharded, so clients hashed to different shards never wait on each other.
+pub struct TokenBucket<K: Hash + Eq> {
+    /// Maximum number of tokens a bucket can hold
+    burst: f64,
+    /// Tokens added to each bucket per second
+    refill_rate: f64,
+    buckets: DashMap<K, Bucket>,
+}
+
+impl<K: Hash + Eq> TokenBucket<K> {
+    pub fn new(requests_per_minute: usize, burst: usize) -> TokenBucket<K> {
+        TokenBucket {
+            burst: burst as f64,
+            refill_rate: requests_per_minute as f64 / 60.0,
+            buckets: DashMap::new(),
+        }
+    }
+
+    fn refill(&self, bucket: &mut Bucket, now: Instant) {
+        let elapsed = now.duration_since(bucket.last_refill).as_secs_f64();
+        bucket.tokens = (bucket.tokens + elapsed * self.refill_rate).min(self.burst);
+        bucket.last_refill = now;
+    }
+}
+
+#[async_trait]
+impl<K: Hash + Eq + Send + Sync + 'static> RateLimiterStrategy<K> for TokenBucket<K> {
+    async fn register_request(&self, key: K) -> bool {
+        let now = Instant::now();
+        let mut bucket = self.buckets.entry(key).or_insert(Bucket {
+            tokens: self.burst,
+            last_refill: now,
+        });
+        self.refill(&mut bucket, now);
+        let allowed = bucket.tokens >= 1.0;
+        if allowed {
+            bucket.tokens -= 1.0;
+        }
+        allowed
+    }
+
+    async fn refresh(&self) {
+        // Buckets that have filled back up behave exactly like new ones, so drop them to keep
+        // memory bounded by the number of recently active clients
+        let now = Instant::now();
+        let burst = self.burst;
+        let refill_rate = self.refill_rate;
+        self.buckets.retain(|_, bucket| {
+            let elapsed = now.duration_since(bucket.last_refill).as_secs_f64();
+            bucket.tokens + elapsed * refill_rate < burst
+        });
+    }
+}
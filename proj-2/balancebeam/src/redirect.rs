@@ -0,0 +1,140 @@
+use serde::Deserialize;
+use crate::routing::matches_prefix;
+
+/// Statuses a redirect rule may answer with
+const REDIRECT_STATUSES: [u16; 5] = [301, 302, 303, 307, 308];
+
+/// Sends requests under a path prefix somewhere else. The rest of the path after the prefix, and
+/// the query string, are kept: with `from = "/docs"` and `to = "/manual"`, "/docs/intro?v=2" is
+/// sent to "/manual/intro?v=2".
+#[derive(Clone, Debug, PartialEq, Deserialize)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+pub struct RedirectRule {
+    pub from: String,
+    /// A path on the same site, or a full URL starting with http:// or https://
+    pub to: String,
+    #[serde(default = "default_status")]
+    pub status: u16,
+}
+
+fn default_status() -> u16 {
+    301
+}
+
+impl RedirectRule {
+    pub fn is_valid(&self) -> bool {
+        REDIRECT_STATUSES.contains(&self.status)
+            && self.from.starts_with('/')
+            && (self.to.starts_with('/') || is_absolute(&self.to))
+    }
+}
+
+/// Parses a --redirect argument, which is formatted like "/old=/new" and redirects with 301
+pub fn parse_redirect(arg: &str) -> Result<RedirectRule, String> {
+    let rule = match arg.split_once('=') {
+        Some((from, to)) => RedirectRule { from: from.into(), to: to.into(), status: 301 },
+        None => return Err(format!("expected <prefix>=<path or URL>, got {:?}", arg)),
+    };
+    if rule.is_valid() {
+        Ok(rule)
+    } else {
+        Err(format!("invalid redirect {:?}: expected a path prefix, and a path or URL", arg))
+    }
+}
+
+fn is_absolute(url: &str) -> bool {
+    url.starts_with("http://") || url.starts_with("https://")
+}
+
+/// Which requests are answered with a redirect instead of being forwarded
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Redirects {
+    /// Send clients that connected over plain HTTP to the same URL over HTTPS
+    pub force_https: bool,
+    /// Send requests for any other host name to this one
+    pub canonical_host: Option<String>,
+    /// Path redirects, longest prefix first
+    pub rules: Vec<RedirectRule>,
+}
+
+impl Redirects {
+    pub fn new(
+        force_https: bool,
+        canonical_host: Option<String>,
+        rules: &[RedirectRule],
+    ) -> Redirects {
+        let mut rules = rules.to_vec();
+        rules.sort_by_key(|rule| std::cmp::Reverse(rule.from.len()));
+        Redirects { force_https, canonical_host, rules }
+    }
+
+    /// Returns the status and location to redirect the request to, if it should be redirected.
+    /// Changes of scheme, host and path are combined into one redirect, so clients aren't sent
+    /// through a chain of them.
+    pub fn redirect_for(
+        &self,
+        request: &http::Request<Vec<u8>>,
+        https: bool,
+    ) -> Option<(http::StatusCode, String)> {
+        let uri = request.uri();
+        let host = uri
+            .host()
+            .map(String::from)
+            .or_else(|| {
+                let host = request.headers().get(http::header::HOST)?;
+                host.to_str().ok().map(String::from)
+            })
+            .unwrap_or_default();
+
+        let mut scheme = if https { "https" } else { "http" };
+        let mut target_host = host.clone();
+        if self.force_https && !https {
+            scheme = "https";
+            // The port the client used was our plain HTTP one
+            target_host = strip_port(&host).to_string();
+        }
+        if let Some(canonical) = &self.canonical_host {
+            if !strip_port(&host).eq_ignore_ascii_case(strip_port(canonical)) {
+                target_host = canonical.clone();
+            }
+        }
+
+        let query = uri.query().map(|query| format!("?{}", query)).unwrap_or_default();
+        let rule = self.rules.iter().find(|rule| matches_prefix(uri.path(), &rule.from));
+        if let Some(rule) = rule {
+            let rest = uri.path()[rule.from.len()..].trim_start_matches('/');
+            let path = if rest.is_empty() {
+                format!("{}{}", rule.to, query)
+            } else {
+                format!("{}/{}{}", rule.to.trim_end_matches('/'), rest, query)
+            };
+            let status = http::StatusCode::from_u16(rule.status).ok()?;
+            if is_absolute(&rule.to) {
+                return Some((status, path));
+            }
+            return Some((status, format!("{}://{}{}", scheme, target_host, path)));
+        }
+
+        let scheme_changed = scheme == "https" && !https;
+        if !scheme_changed && target_host == host {
+            return None;
+        }
+        // 301 lets clients turn a POST into a GET, so other methods get 308, which doesn't
+        let method = request.method();
+        let status = if method == http::Method::GET || method == http::Method::HEAD {
+            http::StatusCode::MOVED_PERMANENTLY
+        } else {
+            http::StatusCode::PERMANENT_REDIRECT
+        };
+        Some((status, format!("{}://{}{}{}", scheme, target_host, uri.path(), query)))
+    }
+}
+
+/// Returns the host name without its port, if it has one
+fn strip_port(host: &str) -> &str {
+    // IPv6 addresses are in brackets, and have colons of their own
+    match host.rfind(':') {
+        Some(idx) if !host[idx..].contains(']') => &host[..idx],
+        _ => host,
+    }
+}
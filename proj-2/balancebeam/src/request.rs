@@ -1,6 +1,8 @@
-use std::cmp::min;
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
-use tokio::net::TcpStream;
+use bytes::{Buf, BufMut, BytesMut};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use crate::body::{self, BodyReader, Framing};
+use crate::buffer_pool::{self, Head};
+use crate::chunked;
 
 const MAX_HEADERS_SIZE: usize = 8000;
 const MAX_BODY_SIZE: usize = 10000000;
@@ -15,14 +17,42 @@ pub enum Error {
     MalformedRequest(httparse::Error),
     /// The Content-Length header is present, but does not contain a valid numeric value
     InvalidContentLength,
+    /// The request has several Content-Length values that disagree
+    ConflictingContentLength,
+    /// The request has both Content-Length and Transfer-Encoding, which could be read as
+    /// different bodies by us and the upstream
+    AmbiguousFraming,
+    /// The request has a Transfer-Encoding whose last coding isn't chunked (or that applies
+    /// chunked more than once), so the body's length can't be told
+    UnsupportedTransferEncoding,
+    /// A header name or value has characters that aren't allowed in it, or a header that may only
+    /// appear once (Host) appears more than once
+    InvalidHeader,
     /// The Content-Length header does not match the size of the request body that was sent
     ContentLengthMismatch,
     /// The request body is bigger than MAX_BODY_SIZE
     RequestBodyTooLarge,
-    /// Encountered an I/O error when reading/writing a TcpStream
+    /// The request uses Transfer-Encoding: chunked, but the body isn't validly chunked
+    InvalidChunkedBody,
+    /// Encountered an I/O error when reading/writing a stream
     ConnectionError(std::io::Error),
 }
 
+impl From<body::Error> for Error {
+    fn from(err: body::Error) -> Error {
+        match err {
+            body::Error::ContentLengthMismatch => Error::ContentLengthMismatch,
+            body::Error::InvalidChunkedBody => Error::InvalidChunkedBody,
+            body::Error::BodyTooLarge => Error::RequestBodyTooLarge,
+            body::Error::TimedOut => Error::ConnectionError(std::io::Error::new(
+                std::io::ErrorKind::TimedOut,
+                "timed out reading the request body",
+            )),
+            body::Error::Io(err) => Error::ConnectionError(err),
+        }
+    }
+}
+
 /// Extracts the Content-Length header value from the provided request. Returns Ok(Some(usize)) if
 /// the Content-Length is present and valid, Ok(None) if Content-Length is not present, or
 /// Err(Error) if Content-Length is present but invalid.
@@ -66,6 +96,59 @@ pub fn extend_header_value(
         .insert(name, http::HeaderValue::from_bytes(&new_value).unwrap());
 }
 
+/// Returns the value of the cookie with the given name, if the request has one
+pub fn get_cookie<'a>(request: &'a http::Request<Vec<u8>>, name: &str) -> Option<&'a str> {
+    request
+        .headers()
+        .get_all(http::header::COOKIE)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(';'))
+        .filter_map(|cookie| cookie.trim().split_once('='))
+        .find(|(cookie_name, _)| *cookie_name == name)
+        .map(|(_, value)| value)
+}
+
+/// Returns true if the Connection header lists the given option (e.g. "close")
+pub fn has_connection_option(headers: &http::HeaderMap, option: &str) -> bool {
+    headers
+        .get_all(http::header::CONNECTION)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .any(|token| token.trim().eq_ignore_ascii_case(option))
+}
+
+/// Returns true if the client is asking to switch this connection over to another protocol (e.g.
+/// WebSocket), i.e. the request has an Upgrade header and "upgrade" in its Connection header.
+pub fn is_upgrade(request: &http::Request<Vec<u8>>) -> bool {
+    has_connection_option(request.headers(), "upgrade")
+        && request.headers().contains_key(http::header::UPGRADE)
+}
+
+/// Returns true if the client wants to send more requests on this connection after this one.
+/// HTTP/1.1 connections stay open unless the client says "Connection: close"; HTTP/1.0 ones
+/// close unless it says "Connection: keep-alive".
+pub fn wants_keep_alive(request: &http::Request<Vec<u8>>) -> bool {
+    if has_connection_option(request.headers(), "close") {
+        return false;
+    }
+    request.version() != http::Version::HTTP_10
+        || has_connection_option(request.headers(), "keep-alive")
+}
+
+/// Returns true if the client is waiting for a "100 Continue" before it sends the request body
+pub fn expects_continue(request: &http::Request<Vec<u8>>) -> bool {
+    request
+        .headers()
+        .get(http::header::EXPECT)
+        .and_then(|value| value.to_str().ok())
+        .is_some_and(|value| value.trim().eq_ignore_ascii_case("100-continue"))
+}
+
+/// A request parsed from a buffer, and the length of its head in the buffer
+type Parsed = (http::Request<Vec<u8>>, usize);
+
 /// Attempts to parse the data in the supplied buffer as an HTTP request. Returns one of the
 /// following:
 ///
@@ -74,143 +157,273 @@ pub fn extend_header_value(
 /// * If there is data in the buffer that is definitely not a valid HTTP request, returns Err(Error)
 ///
 /// You won't need to touch this function.
-fn parse_request(buffer: &[u8]) -> Result<Option<(http::Request<Vec<u8>>, usize)>, Error> {
+fn parse_request(buffer: &[u8]) -> Result<Option<Parsed>, Error> {
     let mut headers = [httparse::EMPTY_HEADER; MAX_NUM_HEADERS];
     let mut req = httparse::Request::new(&mut headers);
-    let res = req.parse(buffer).or_else(|err| Err(Error::MalformedRequest(err)))?;
+    let res = req.parse(buffer).map_err(Error::MalformedRequest)?;
 
     if let httparse::Status::Complete(len) = res {
-        let mut request = http::Request::builder()
-            .method(req.method.unwrap())
-            .uri(req.path.unwrap())
-            .version(http::Version::HTTP_11);
-        for header in req.headers {
-            request = request.header(header.name, header.value);
+        let head = Head::new(buffer, len);
+        let mut request = http::Request::new(Vec::new());
+        *request.method_mut() = http::Method::from_bytes(req.method.unwrap().as_bytes())
+            .map_err(|_| Error::MalformedRequest(httparse::Error::Token))?;
+        *request.uri_mut() = http::Uri::from_maybe_shared(head.part(req.path.unwrap().as_bytes()))
+            .map_err(|_| Error::MalformedRequest(httparse::Error::Token))?;
+        *request.version_mut() = if req.version == Some(0) {
+            http::Version::HTTP_10
+        } else {
+            http::Version::HTTP_11
+        };
+        let headers = request.headers_mut();
+        headers.reserve(req.headers.len());
+        for header in req.headers.iter() {
+            // Bad characters are rejected here, rather than making it into the request.
+            // Surrounding whitespace isn't part of the value.
+            let name = http::header::HeaderName::from_bytes(header.name.as_bytes())
+                .map_err(|_| Error::InvalidHeader)?;
+            let value = head.part(trim_whitespace(header.value));
+            let value = http::HeaderValue::from_maybe_shared(value)
+                .map_err(|_| Error::InvalidHeader)?;
+            headers.append(name, value);
         }
-        let request = request.body(Vec::new()).unwrap();
         Ok(Some((request, len)))
     } else {
         Ok(None)
     }
 }
 
+/// Finds the end of a request's head as its bytes come in, without going back over the ones it
+/// has already looked at. httparse can't pick up where it left off, so rather than parse
+/// everything read so far after every read, the head is parsed in full once, when the blank line
+/// ending it has arrived. Until then, only the request line is checked (once it's all there), so
+/// a client sending garbage is still turned away without waiting for the rest of it.
+#[derive(Default)]
+struct HeadParser {
+    /// Bytes at the start of the buffer already known not to hold the end of the head
+    scanned: usize,
+    /// Whether the request line has arrived and been found valid
+    line_checked: bool,
+}
+
+impl HeadParser {
+    /// Looks at the bytes added to the buffer since the last call. Returns the request and the
+    /// length of its head once the whole head has arrived.
+    fn advance(&mut self, buffer: &[u8]) -> Result<Option<Parsed>, Error> {
+        if !self.line_checked && buffer[self.scanned..].contains(&b'\n') {
+            if let Some(parsed) = parse_request(buffer)? {
+                return Ok(Some(parsed));
+            }
+            self.line_checked = true;
+        }
+        while let Some(end) = find_blank_line(buffer, self.scanned) {
+            self.scanned = end;
+            // Blank lines before the request line don't end anything, and httparse skips them
+            if let Some(parsed) = parse_request(buffer)? {
+                return Ok(Some(parsed));
+            }
+        }
+        // The last couple of bytes may be the start of a blank line whose end hasn't arrived
+        self.scanned = self.scanned.max(buffer.len().saturating_sub(2));
+        Ok(None)
+    }
+}
+
+/// Returns the position just past the first blank line ("\r\n" or a bare "\n", as httparse
+/// allows) that ends after `from`
+fn find_blank_line(buffer: &[u8], from: usize) -> Option<usize> {
+    let mut idx = from;
+    while let Some(offset) = buffer[idx..].iter().position(|&byte| byte == b'\n') {
+        let newline = idx + offset;
+        match &buffer[newline + 1..] {
+            [b'\n', ..] => return Some(newline + 2),
+            [b'\r', b'\n', ..] => return Some(newline + 3),
+            _ => idx = newline + 1,
+        }
+    }
+    None
+}
+
 /// Reads an HTTP request from the provided stream, waiting until a complete set of headers is sent.
-/// This function only reads the request line and headers; the read_body function can subsequently
-/// be called in order to read the request body (for a POST request).
+/// This function only reads the request line and headers; the request body (for a POST request) is
+/// left to be read after it. `already_read` holds bytes that were read off the stream earlier
+/// (e.g. past the end of the previous request on the connection), which are the start of this
+/// request.
 ///
-/// Returns Ok(http::Request) if a valid request is received, or Error if not.
+/// Returns the request and the bytes read past the end of its headers if a valid request is
+/// received, or Error if not.
 ///
 /// You will need to modify this function in Milestone 2.
-async fn read_headers(stream: &mut TcpStream) -> Result<http::Request<Vec<u8>>, Error> {
+async fn read_headers<S: AsyncRead + Unpin>(
+    stream: &mut S,
+    already_read: BytesMut,
+) -> Result<(http::Request<Vec<u8>>, BytesMut), Error> {
     // Try reading the headers from the request. We may not receive all the headers in one shot
     // (e.g. we might receive the first few bytes of a request, and then the rest follows later).
-    // Try parsing repeatedly until we read a valid HTTP request
-    let mut request_buffer = [0_u8; MAX_HEADERS_SIZE];
-    let mut bytes_read = 0;
+    // Keep reading until the parser has seen a complete, valid set of headers
+    let mut request_buffer = already_read;
+    let mut parser = HeadParser::default();
     loop {
-        // Read bytes from the connection into the buffer, starting at position bytes_read
+        // See if we've read a valid request so far
+        if !request_buffer.is_empty() {
+            if let Some((request, headers_len)) = parser.advance(&request_buffer)? {
+                // We've read a complete set of headers. However, if this was a POST request, a
+                // request body might have been included as well, and we might have read part of
+                // the body out of the stream into request_buffer. We need to hand those bytes
+                // back so that we don't lose them. The headers are skipped over rather than the
+                // rest copied out, so the buffer goes on being used for the body.
+                request_buffer.advance(headers_len);
+                return Ok((request, request_buffer));
+            }
+        }
+        if request_buffer.len() >= MAX_HEADERS_SIZE {
+            // The headers are too long for us to accept
+            return Err(Error::IncompleteRequest(request_buffer.len()));
+        }
+
+        // Read bytes from the connection straight onto the end of the buffer
+        let room = MAX_HEADERS_SIZE - request_buffer.len();
+        buffer_pool::reserve(&mut request_buffer, room);
         let new_bytes = stream
-            .read(&mut request_buffer[bytes_read..]).await
-            .or_else(|err| Err(Error::ConnectionError(err)))?;
+            .read_buf(&mut (&mut request_buffer).limit(room)).await
+            .map_err(Error::ConnectionError)?;
         if new_bytes == 0 {
             // We didn't manage to read a complete request
-            return Err(Error::IncompleteRequest(bytes_read));
-        }
-        bytes_read += new_bytes;
-
-        // See if we've read a valid request so far
-        if let Some((mut request, headers_len)) = parse_request(&request_buffer[..bytes_read])? {
-            // We've read a complete set of headers. However, if this was a POST request, a request
-            // body might have been included as well, and we might have read part of the body out of
-            // the stream into header_buffer. We need to add those bytes to the Request body so that
-            // we don't lose them
-            request
-                .body_mut()
-                .extend_from_slice(&request_buffer[headers_len..bytes_read]);
-            return Ok(request);
+            return Err(Error::IncompleteRequest(request_buffer.len()));
         }
     }
 }
 
-/// This function reads the body for a request from the stream. The client only sends a body if the
-/// Content-Length header is present; this function reads that number of bytes from the stream. It
-/// returns Ok(()) if successful, or Err(Error) if Content-Length bytes couldn't be read.
-///
-/// You will need to modify this function in Milestone 2.
-async fn read_body(
-    stream: &mut TcpStream,
-    request: &mut http::Request<Vec<u8>>,
-    content_length: usize,
-) -> Result<(), Error> {
-    // Keep reading data until we read the full body length, or until we hit an error.
-    while request.body().len() < content_length {
-        // Read up to 512 bytes at a time. (If the client only sent a small body, then only allocate
-        // space to read that body.)
-        let mut buffer = vec![0_u8; min(512, content_length)];
-        let bytes_read = stream.read(&mut buffer).await.or_else(|err| Err(Error::ConnectionError(err)))?;
-
-        // Make sure the client is still sending us bytes
-        if bytes_read == 0 {
-            log::debug!(
-                "Client hung up after sending a body of length {}, even though it said the content \
-                length is {}",
-                request.body().len(),
-                content_length
-            );
-            return Err(Error::ContentLengthMismatch);
-        }
+fn trim_whitespace(value: &[u8]) -> &[u8] {
+    let is_whitespace = |byte: &u8| *byte == b' ' || *byte == b'\t';
+    let start = value.iter().position(|byte| !is_whitespace(byte)).unwrap_or(value.len());
+    let end = value.iter().rposition(|byte| !is_whitespace(byte)).map_or(start, |idx| idx + 1);
+    &value[start..end]
+}
 
-        // Make sure the client didn't send us *too many* bytes
-        if request.body().len() + bytes_read > content_length {
-            log::debug!(
-                "Client sent more bytes than we expected based on the given content length!"
-            );
-            return Err(Error::ContentLengthMismatch);
+/// Guards against request smuggling, where a request is written so that we and the upstream
+/// disagree about where it ends, and the rest of it is taken by the upstream as another request
+/// that never went through us. Requests whose framing could be read more than one way are
+/// rejected: ones with both Content-Length and Transfer-Encoding, with Content-Lengths that
+/// disagree or aren't plain numbers, with a Transfer-Encoding that doesn't end in chunked, or with
+/// more than one Host. Repeated Content-Lengths that agree are merged into one, so the upstream
+/// sees the request the same way we do.
+fn sanitize_headers(request: &mut http::Request<Vec<u8>>) -> Result<(), Error> {
+    let headers = request.headers_mut();
+    if headers.get_all(http::header::HOST).iter().count() > 1 {
+        return Err(Error::InvalidHeader);
+    }
+    let mut content_length = None;
+    let mut lengths = 0;
+    for value in headers.get_all(http::header::CONTENT_LENGTH) {
+        let value = value.to_str().map_err(|_| Error::InvalidContentLength)?;
+        for length in value.split(',').map(str::trim) {
+            // Only digits: parsing would also take a leading "+", which upstreams may not
+            if length.is_empty() || !length.bytes().all(|byte| byte.is_ascii_digit()) {
+                return Err(Error::InvalidContentLength);
+            }
+            let length = length.parse::<usize>().map_err(|_| Error::InvalidContentLength)?;
+            lengths += 1;
+            if content_length.replace(length).is_some_and(|previous| previous != length) {
+                return Err(Error::ConflictingContentLength);
+            }
         }
-
-        // Store the received bytes in the request body
-        request.body_mut().extend_from_slice(&buffer[..bytes_read]);
+    }
+    if headers.contains_key(http::header::TRANSFER_ENCODING) {
+        if content_length.is_some() {
+            return Err(Error::AmbiguousFraming);
+        }
+        let mut codings = Vec::new();
+        for value in headers.get_all(http::header::TRANSFER_ENCODING) {
+            let value = value.to_str().map_err(|_| Error::UnsupportedTransferEncoding)?;
+            let listed = value.split(',').map(|coding| coding.trim().to_ascii_lowercase());
+            codings.extend(listed.filter(|coding| !coding.is_empty()));
+        }
+        let chunked_count = codings.iter().filter(|coding| *coding == "chunked").count();
+        if codings.last().map(String::as_str) != Some("chunked") || chunked_count > 1 {
+            return Err(Error::UnsupportedTransferEncoding);
+        }
+    }
+    // A single length is left as it was sent
+    if let Some(length) = content_length.filter(|_| lengths > 1) {
+        headers.insert(http::header::CONTENT_LENGTH, http::HeaderValue::from(length));
     }
     Ok(())
 }
 
+/// Works out how the request's body is delimited. The client only sends a body if it uses
+/// Transfer-Encoding: chunked or the Content-Length header is present (never both, which
+/// sanitize_headers has already turned away).
+fn body_framing(request: &http::Request<Vec<u8>>) -> Result<Framing, Error> {
+    if chunked::is_chunked(request.headers()) {
+        // The body is sent as a series of chunks
+        Ok(Framing::Chunked)
+    } else {
+        match get_content_length(request)? {
+            Some(content_length) if content_length > 0 => Ok(Framing::Length(content_length)),
+            _ => Ok(Framing::Empty),
+        }
+    }
+}
+
+/// Reads the head (request line and headers) of an HTTP request from a stream, returning an
+/// Error if the client closes the connection prematurely or sends an invalid request. The body is
+/// left on the stream, to be read with the returned BodyReader, so that it can be passed on a
+/// piece at a time rather than held in memory. `already_read` holds bytes that were read off the
+/// stream earlier, which are the start of this request, and the rest is read into it.
+pub async fn read_head<S: AsyncRead + Unpin>(
+    stream: &mut S,
+    already_read: BytesMut,
+) -> Result<(http::Request<Vec<u8>>, BodyReader), Error> {
+    let (mut request, body_start) = read_headers(stream, already_read).await?;
+    sanitize_headers(&mut request)?;
+    let framing = body_framing(&request)?;
+    Ok((request, BodyReader::new(framing, body_start)))
+}
+
 /// This function reads and returns an HTTP request from a stream, returning an Error if the client
-/// closes the connection prematurely or sends an invalid request.
+/// closes the connection prematurely or sends an invalid request. The whole body is read into
+/// memory, so this is only for small requests (such as those to the admin API).
 ///
 /// You will need to modify this function in Milestone 2.
-pub async fn read_from_stream(stream: &mut TcpStream) -> Result<http::Request<Vec<u8>>, Error> {
-    // Read headers
-    let mut request = read_headers(stream).await?;
-    // Read body if the client supplied the Content-Length header (which it does for POST requests)
-    if let Some(content_length) = get_content_length(&request)? {
-        if content_length > MAX_BODY_SIZE {
-            return Err(Error::RequestBodyTooLarge);
-        } else {
-            read_body(stream, &mut request, content_length).await?;
-        }
-    }
+pub async fn read_from_stream<S: AsyncRead + Unpin>(stream: &mut S) -> Result<http::Request<Vec<u8>>, Error> {
+    let (mut request, body) = read_head(stream, BytesMut::new()).await?;
+    *request.body_mut() = body.read_to_end(stream, MAX_BODY_SIZE).await?;
     Ok(request)
 }
 
+/// Writes the request line and headers to the provided stream. The body has to be written after
+/// them, in the framing the headers announce. Requests are always sent as HTTP/1.1, whatever
+/// version the client spoke, since that's the version we speak.
+pub async fn write_head<S: AsyncWrite + Unpin>(
+    request: &http::Request<Vec<u8>>,
+    stream: &mut S,
+) -> Result<(), std::io::Error> {
+    let request_line = format!("{} {} HTTP/1.1", request.method(), request.uri());
+    stream.write_all(request_line.as_bytes()).await?;
+    stream.write_all(b"\r\n").await?; // \r\n
+    for (header_name, header_value) in request.headers() {
+        stream.write_all(format!("{}: ", header_name).as_bytes()).await?;
+        stream.write_all(header_value.as_bytes()).await?;
+        stream.write_all(b"\r\n").await?; // \r\n
+    }
+    stream.write_all(b"\r\n").await
+}
+
 /// This function serializes a request to bytes and writes those bytes to the provided stream.
 ///
 /// You will need to modify this function in Milestone 2.
-pub async fn write_to_stream(
+pub async fn write_to_stream<S: AsyncWrite + Unpin>(
     request: &http::Request<Vec<u8>>,
-    stream: &mut TcpStream,
+    stream: &mut S,
 ) -> Result<(), std::io::Error> {
-    stream.write(&format_request_line(request).into_bytes()).await?;
-    stream.write(&['\r' as u8, '\n' as u8]).await?; // \r\n
-    for (header_name, header_value) in request.headers() {
-        stream.write(&format!("{}: ", header_name).as_bytes()).await?;
-        stream.write(header_value.as_bytes()).await?;
-        stream.write(&['\r' as u8, '\n' as u8]).await?; // \r\n
+    write_head(request, stream).await?;
+    if chunked::is_chunked(request.headers()) {
+        chunked::write_body(stream, request.body()).await?;
+    } else if !request.body().is_empty() {
+        stream.write_all(request.body()).await?;
     }
-    stream.write(&['\r' as u8, '\n' as u8]).await?;
-    if request.body().len() > 0 {
-        stream.write(request.body()).await?;
-    }
-    Ok(())
+    // Streams that buffer writes (e.g. TLS) don't send anything until they're flushed
+    stream.flush().await
 }
 
 pub fn format_request_line(request: &http::Request<Vec<u8>>) -> String {
@@ -0,0 +1,102 @@
+use std::sync::atomic::{AtomicUsize, Ordering};
+use tokio::time::{timeout_at, Duration, Instant};
+
+use crate::active_connections::ActiveConnection;
+use crate::state::ProxyState;
+
+/// Why a request was turned away rather than waiting for an upstream to have room for it
+#[derive(Debug)]
+pub enum Rejected {
+    /// As many requests as may wait were already waiting
+    QueueFull,
+    /// No upstream had room for the request before the queue timeout ran out
+    TimedOut,
+}
+
+/// Counts a request as waiting until it is dropped
+struct Queued<'a>(&'a AtomicUsize);
+
+impl Drop for Queued<'_> {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+/// Returns the upstreams in the pool that are already serving as many requests as they may at once
+pub async fn saturated(state: &ProxyState, pool: &[usize]) -> Vec<usize> {
+    let max = state.max_upstream_concurrency.load(Ordering::SeqCst);
+    if max == 0 {
+        return Vec::new();
+    }
+    let addresses = state.upstream_addresses.read().await;
+    pool.iter()
+        .copied()
+        .filter(|&idx| {
+            addresses
+                .get(idx)
+                .is_some_and(|address| state.upstream_requests.get(address) >= max)
+        })
+        .collect()
+}
+
+/// Returns true if one of the live upstreams in the pool (other than the excluded ones) has room
+/// for another request. If none of them are alive, there's no point waiting, so this returns true
+/// as well and leaves it to the caller to fail to connect.
+async fn has_room(state: &ProxyState, pool: &[usize], excluded: &[usize]) -> bool {
+    let saturated = saturated(state, pool).await;
+    let upstream_status = state.upstream_status.load();
+    let mut candidates = pool
+        .iter()
+        .filter(|idx| upstream_status.is_alive(**idx) && !excluded.contains(idx))
+        .peekable();
+    candidates.peek().is_none() || candidates.any(|idx| !saturated.contains(idx))
+}
+
+/// Waits until an upstream the request could go to has room for it under
+/// `--max-upstream-concurrency`. Rather than turning a burst of requests away as soon as every
+/// upstream is busy, up to `--request-queue-size` of them wait for up to `--request-queue-timeout`
+/// seconds for another request to finish.
+pub async fn wait_for_room(
+    state: &ProxyState,
+    pool: &[usize],
+    excluded: &[usize],
+) -> Result<(), Rejected> {
+    if state.max_upstream_concurrency.load(Ordering::SeqCst) == 0 {
+        return Ok(());
+    }
+    let queue_timeout = state.request_queue_timeout.load(Ordering::SeqCst);
+    let deadline = Instant::now() + Duration::from_secs(queue_timeout as u64);
+    let mut queued = None;
+    loop {
+        // Start listening for requests finishing before looking, so none can slip by in between
+        let finished = state.upstream_requests.closed();
+        if has_room(state, pool, excluded).await {
+            return Ok(());
+        }
+        if queued.is_none() {
+            let waiting = state.queued_requests.fetch_add(1, Ordering::SeqCst);
+            queued = Some(Queued(&state.queued_requests));
+            if waiting >= state.request_queue_size.load(Ordering::SeqCst) {
+                return Err(Rejected::QueueFull);
+            }
+        }
+        if timeout_at(deadline, finished).await.is_err() {
+            return Err(Rejected::TimedOut);
+        }
+    }
+}
+
+/// Counts a request as in flight to the upstream until the returned handle is dropped. Returns
+/// None if the upstream is already serving as many requests as it may (which it can be, even
+/// after `wait_for_room` found it had room, if other requests got there first).
+pub fn claim(state: &ProxyState, address: &str) -> Option<ActiveConnection> {
+    match state.max_upstream_concurrency.load(Ordering::SeqCst) {
+        0 => Some(state.upstream_requests.track(address)),
+        max => state.upstream_requests.try_track(address, max),
+    }
+}
+
+/// Returns how many seconds a client turned away by the queue should wait before trying again
+pub fn retry_after(state: &ProxyState) -> usize {
+    state.request_queue_timeout.load(Ordering::SeqCst).max(1)
+}
@@ -1,5 +1,9 @@
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
-use tokio::net::TcpStream;
+use bytes::{Buf, BufMut, BytesMut};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use crate::body::{self, BodyReader, Framing};
+use crate::buffer_pool::{self, Head};
+use crate::chunked;
+use crate::request;
 
 const MAX_HEADERS_SIZE: usize = 8000;
 const MAX_BODY_SIZE: usize = 10000000;
@@ -17,10 +21,31 @@ pub enum Error {
     ContentLengthMismatch,
     /// The request body is bigger than MAX_BODY_SIZE
     ResponseBodyTooLarge,
-    /// Encountered an I/O error when reading/writing a TcpStream
+    /// The response uses Transfer-Encoding: chunked, but the body isn't validly chunked
+    InvalidChunkedBody,
+    /// Encountered an I/O error when reading/writing a stream
     ConnectionError(std::io::Error),
 }
 
+impl From<body::Error> for Error {
+    fn from(err: body::Error) -> Error {
+        match err {
+            body::Error::ContentLengthMismatch => Error::ContentLengthMismatch,
+            body::Error::InvalidChunkedBody => Error::InvalidChunkedBody,
+            body::Error::BodyTooLarge => Error::ResponseBodyTooLarge,
+            body::Error::TimedOut => Error::ConnectionError(std::io::Error::new(
+                std::io::ErrorKind::TimedOut,
+                "timed out reading the response body",
+            )),
+            body::Error::Io(err) => Error::ConnectionError(err),
+        }
+    }
+}
+
+/// Marks a response that was read without a body (e.g. a response to a HEAD request), so that we
+/// don't write a chunked body for it even if it has a Transfer-Encoding: chunked header
+struct NoBody;
+
 /// Extracts the Content-Length header value from the provided response. Returns Ok(Some(usize)) if
 /// the Content-Length is present and valid, Ok(None) if Content-Length is not present, or
 /// Err(Error) if Content-Length is present but invalid.
@@ -57,16 +82,27 @@ fn parse_response(buffer: &[u8]) -> Result<Option<(http::Response<Vec<u8>>, usiz
     let mut resp = httparse::Response::new(&mut headers);
     let res = resp
         .parse(buffer)
-        .or_else(|err| Err(Error::MalformedResponse(err)))?;
+        .map_err(Error::MalformedResponse)?;
 
     if let httparse::Status::Complete(len) = res {
-        let mut response = http::Response::builder()
-            .status(resp.code.unwrap())
-            .version(http::Version::HTTP_11);
-        for header in resp.headers {
-            response = response.header(header.name, header.value);
+        let head = Head::new(buffer, len);
+        let mut response = http::Response::new(Vec::new());
+        *response.status_mut() = http::StatusCode::from_u16(resp.code.unwrap())
+            .map_err(|_| Error::MalformedResponse(httparse::Error::Status))?;
+        *response.version_mut() = if resp.version == Some(0) {
+            http::Version::HTTP_10
+        } else {
+            http::Version::HTTP_11
+        };
+        let headers = response.headers_mut();
+        headers.reserve(resp.headers.len());
+        for header in resp.headers.iter() {
+            let name = http::header::HeaderName::from_bytes(header.name.as_bytes())
+                .map_err(|_| Error::MalformedResponse(httparse::Error::HeaderName))?;
+            let value = http::HeaderValue::from_maybe_shared(head.part(header.value))
+                .map_err(|_| Error::MalformedResponse(httparse::Error::HeaderValue))?;
+            headers.append(name, value);
         }
-        let response = response.body(Vec::new()).unwrap();
         Ok(Some((response, len)))
     } else {
         Ok(None)
@@ -74,126 +110,172 @@ fn parse_response(buffer: &[u8]) -> Result<Option<(http::Response<Vec<u8>>, usiz
 }
 
 /// Reads an HTTP response from the provided stream, waiting until a complete set of headers is
-/// sent. This function only reads the response line and headers; the read_body function can
-/// subsequently be called in order to read the response body.
+/// sent. This function only reads the response line and headers; the response body is left to be
+/// read after it. `already_read` holds bytes that were read off the stream earlier (e.g. past the
+/// end of an interim response), which are the start of this response.
 ///
-/// Returns Ok(http::Response) if a valid response is received, or Error if not.
+/// Returns the response and the bytes read past the end of its headers if a valid response is
+/// received, or Error if not.
 ///
 /// You will need to modify this function in Milestone 2.
-async fn read_headers(stream: &mut TcpStream) -> Result<http::Response<Vec<u8>>, Error> {
+async fn read_headers<S: AsyncRead + Unpin>(
+    stream: &mut S,
+    already_read: BytesMut,
+) -> Result<(http::Response<Vec<u8>>, BytesMut), Error> {
     // Try reading the headers from the response. We may not receive all the headers in one shot
     // (e.g. we might receive the first few bytes of a response, and then the rest follows later).
     // Try parsing repeatedly until we read a valid HTTP response
-    let mut response_buffer = [0_u8; MAX_HEADERS_SIZE];
-    let mut bytes_read = 0;
+    let mut response_buffer = already_read;
     loop {
-        // Read bytes from the connection into the buffer, starting at position bytes_read
+        // See if we've read a valid response so far
+        if !response_buffer.is_empty() {
+            if let Some((response, headers_len)) = parse_response(&response_buffer)? {
+                // We've read a complete set of headers. We may have also read the first part of
+                // the response body; hand whatever is left over in the response buffer back as
+                // the start of the response body, reusing the buffer for it.
+                response_buffer.advance(headers_len);
+                return Ok((response, response_buffer));
+            }
+        }
+        if response_buffer.len() >= MAX_HEADERS_SIZE {
+            // The headers are too long for us to accept
+            return Err(Error::IncompleteResponse);
+        }
+
+        // Read bytes from the connection straight onto the end of the buffer
+        let room = MAX_HEADERS_SIZE - response_buffer.len();
+        buffer_pool::reserve(&mut response_buffer, room);
         let new_bytes = stream
-            .read(&mut response_buffer[bytes_read..]).await
-            .or_else(|err| Err(Error::ConnectionError(err)))?;
+            .read_buf(&mut (&mut response_buffer).limit(room)).await
+            .map_err(Error::ConnectionError)?;
         if new_bytes == 0 {
             // We didn't manage to read a complete response
             return Err(Error::IncompleteResponse);
         }
-        bytes_read += new_bytes;
+    }
+}
 
-        // See if we've read a valid response so far
-        if let Some((mut response, headers_len)) = parse_response(&response_buffer[..bytes_read])? {
-            // We've read a complete set of headers. We may have also read the first part of the
-            // response body; take whatever is left over in the response buffer and save that as
-            // the start of the response body.
-            response
-                .body_mut()
-                .extend_from_slice(&response_buffer[headers_len..bytes_read]);
-            return Ok(response);
+/// Works out how the response's body is delimited. If the Content-Length header is present, the
+/// body is that many bytes long; otherwise, it lasts until the connection is closed. Responses to
+/// other methods (OPTIONS and TRACE included) follow the same rules as GET's.
+fn body_framing(
+    response: &mut http::Response<Vec<u8>>,
+    request_method: &http::Method,
+) -> Result<Framing, Error> {
+    let status = response.status();
+    // 1xx and 204 responses never have a body, so any framing headers on them are wrong, and
+    // would leave the client waiting for a body that isn't coming. They're dropped.
+    if status.is_informational() || status == http::StatusCode::NO_CONTENT {
+        response.headers_mut().remove(http::header::CONTENT_LENGTH);
+        response.headers_mut().remove(http::header::TRANSFER_ENCODING);
+    }
+    // A response may have a body as long as it is not responding to a HEAD request (or
+    // successfully to a CONNECT, after which the connection is a tunnel) and as long as the
+    // response status code is not 1xx, 204 (no content), or 304 (not modified). The
+    // Content-Length of a HEAD or 304 response is left alone, since it gives the length of the
+    // body a GET would have got.
+    if request_method == http::Method::HEAD
+        || (request_method == http::Method::CONNECT && status.is_success())
+        || status.is_informational()
+        || status == http::StatusCode::NO_CONTENT
+        || status == http::StatusCode::NOT_MODIFIED
+    {
+        response.extensions_mut().insert(NoBody);
+        Ok(Framing::Empty)
+    } else if chunked::is_chunked(response.headers()) {
+        // Transfer-Encoding takes precedence over Content-Length, so drop any Content-Length
+        // rather than forwarding a conflicting one
+        response.headers_mut().remove(http::header::CONTENT_LENGTH);
+        Ok(Framing::Chunked)
+    } else {
+        match get_content_length(response)? {
+            Some(0) => Ok(Framing::Empty),
+            Some(content_length) => Ok(Framing::Length(content_length)),
+            None => Ok(Framing::UntilClose),
         }
     }
 }
 
-/// This function reads the body for a response from the stream. If the Content-Length header is
-/// present, it reads that many bytes; otherwise, it reads bytes until the connection is closed.
+/// Reads the head (status line and headers) of an HTTP response from a stream, returning an Error
+/// if the server closes the connection prematurely or sends an invalid response. The body is left
+/// on the stream, to be read with the returned BodyReader, so that it can be passed on a piece at
+/// a time rather than held in memory.
 ///
-/// You will need to modify this function in Milestone 2.
-async fn read_body(stream: &mut TcpStream, response: &mut http::Response<Vec<u8>>) -> Result<(), Error> {
-    // The response may or may not supply a Content-Length header. If it provides the header, then
-    // we want to read that number of bytes; if it does not, we want to keep reading bytes until
-    // the connection is closed.
-    let content_length = get_content_length(response)?;
-
-    while content_length.is_none() || response.body().len() < content_length.unwrap() {
-        let mut buffer = [0_u8; 512];
-        let bytes_read = stream
-            .read(&mut buffer).await
-            .or_else(|err| Err(Error::ConnectionError(err)))?;
-        if bytes_read == 0 {
-            // The server has hung up!
-            if content_length.is_none() {
-                // We've reached the end of the response
-                break;
-            } else {
-                // Content-Length was set, but the server hung up before we managed to read that
-                // number of bytes
-                return Err(Error::ContentLengthMismatch);
-            }
-        }
-
-        // Make sure the server doesn't send more bytes than it promised to send
-        if content_length.is_some() && response.body().len() + bytes_read > content_length.unwrap()
-        {
-            return Err(Error::ContentLengthMismatch);
-        }
-
-        // Make sure server doesn't send more bytes than we allow
-        if response.body().len() + bytes_read > MAX_BODY_SIZE {
-            return Err(Error::ResponseBodyTooLarge);
+/// Interim responses (100 Continue, 103 Early Hints and the like) that come ahead of the final
+/// one are skipped. Taking one for the answer would leave the real answer on the connection, to
+/// be mistaken for the answer to the next request sent on it.
+///
+/// The response is read into `buffer`, whose contents are thrown away first. A connection can
+/// pass in what the BodyReader for its last response left behind, so that its memory is reused.
+pub async fn read_head<S: AsyncRead + Unpin>(
+    stream: &mut S,
+    request_method: &http::Method,
+    mut buffer: BytesMut,
+) -> Result<(http::Response<Vec<u8>>, BodyReader), Error> {
+    buffer.clear();
+    let mut already_read = buffer;
+    loop {
+        let (mut response, body_start) = read_headers(stream, already_read).await?;
+        let status = response.status();
+        if status.is_informational() && status != http::StatusCode::SWITCHING_PROTOCOLS {
+            log::debug!("Skipping interim {} response", status.as_u16());
+            already_read = body_start;
+            continue;
         }
-
-        // Append received bytes to the response body
-        response.body_mut().extend_from_slice(&buffer[..bytes_read]);
+        let framing = body_framing(&mut response, request_method)?;
+        return Ok((response, BodyReader::new(framing, body_start)));
     }
-    Ok(())
 }
 
 /// This function reads and returns an HTTP response from a stream, returning an Error if the server
-/// closes the connection prematurely or sends an invalid response.
+/// closes the connection prematurely or sends an invalid response. The whole body is read into
+/// memory, so this is only for small responses (such as those to health checks).
 ///
 /// You will need to modify this function in Milestone 2.
-pub async fn read_from_stream(
-    stream: &mut TcpStream,
+pub async fn read_from_stream<S: AsyncRead + Unpin>(
+    stream: &mut S,
     request_method: &http::Method,
 ) -> Result<http::Response<Vec<u8>>, Error> {
-    let mut response = read_headers(stream).await?;
-    // A response may have a body as long as it is not responding to a HEAD request and as long as
-    // the response status code is not 1xx, 204 (no content), or 304 (not modified).
-    if !(request_method == http::Method::HEAD
-        || response.status().as_u16() < 200
-        || response.status() == http::StatusCode::NO_CONTENT
-        || response.status() == http::StatusCode::NOT_MODIFIED)
-    {
-        read_body(stream, &mut response).await?;
-    }
+    let (mut response, body) = read_head(stream, request_method, BytesMut::new()).await?;
+    *response.body_mut() = body.read_to_end(stream, MAX_BODY_SIZE).await?;
     Ok(response)
 }
 
+/// Writes the status line and headers to the provided stream. The body has to be written after
+/// them, in the framing the headers announce. Responses are always sent as HTTP/1.1, whatever
+/// version the upstream spoke, since that's the version we speak.
+pub async fn write_head<S: AsyncWrite + Unpin>(
+    response: &http::Response<Vec<u8>>,
+    stream: &mut S,
+) -> Result<(), std::io::Error> {
+    let status = response.status();
+    let status_line =
+        format!("HTTP/1.1 {} {}", status.as_str(), status.canonical_reason().unwrap_or(""));
+    stream.write_all(status_line.as_bytes()).await?;
+    stream.write_all(b"\r\n").await?; // \r\n
+    for (header_name, header_value) in response.headers() {
+        stream.write_all(format!("{}: ", header_name).as_bytes()).await?;
+        stream.write_all(header_value.as_bytes()).await?;
+        stream.write_all(b"\r\n").await?; // \r\n
+    }
+    stream.write_all(b"\r\n").await
+}
+
 /// This function serializes a response to bytes and writes those bytes to the provided stream.
 ///
 /// You will need to modify this function in Milestone 2.
-pub async fn write_to_stream(
+pub async fn write_to_stream<S: AsyncWrite + Unpin>(
     response: &http::Response<Vec<u8>>,
-    stream: &mut TcpStream,
+    stream: &mut S,
 ) -> Result<(), std::io::Error> {
-    stream.write(&format_response_line(response).into_bytes()).await?;
-    stream.write(&['\r' as u8, '\n' as u8]).await?; // \r\n
-    for (header_name, header_value) in response.headers() {
-        stream.write(&format!("{}: ", header_name).as_bytes()).await?;
-        stream.write(header_value.as_bytes()).await?;
-        stream.write(&['\r' as u8, '\n' as u8]).await?; // \r\n
-    }
-    stream.write(&['\r' as u8, '\n' as u8]).await?;
-    if response.body().len() > 0 {
-        stream.write(response.body()).await?;
+    write_head(response, stream).await?;
+    if chunked::is_chunked(response.headers()) && response.extensions().get::<NoBody>().is_none() {
+        chunked::write_body(stream, response.body()).await?;
+    } else if !response.body().is_empty() {
+        stream.write_all(response.body()).await?;
     }
-    Ok(())
+    // Streams that buffer writes (e.g. TLS) don't send anything until they're flushed
+    stream.flush().await
 }
 
 pub fn format_response_line(response: &http::Response<Vec<u8>>) -> String {
@@ -205,6 +287,34 @@ pub fn format_response_line(response: &http::Response<Vec<u8>>) -> String {
     )
 }
 
+/// Adds a Set-Cookie header to the response, for a session cookie that applies to the whole site
+pub fn add_cookie(response: &mut http::Response<Vec<u8>>, name: &str, value: &str) {
+    let cookie = format!("{}={}; Path=/; HttpOnly", name, value);
+    if let Ok(cookie) = http::HeaderValue::from_str(&cookie) {
+        response.headers_mut().append(http::header::SET_COOKIE, cookie);
+    }
+}
+
+/// Returns true if the upstream will take another request on this connection after this response.
+/// HTTP/1.1 upstreams keep it open unless they say "Connection: close"; HTTP/1.0 ones close it
+/// unless they say "Connection: keep-alive".
+pub fn is_keep_alive(response: &http::Response<Vec<u8>>) -> bool {
+    if request::has_connection_option(response.headers(), "close") {
+        return false;
+    }
+    response.version() != http::Version::HTTP_10
+        || request::has_connection_option(response.headers(), "keep-alive")
+}
+
+/// Tells the client whether the connection stays open after this response. Whatever the upstream
+/// put in the Connection header was about its connection to us, not ours to the client.
+pub fn set_keep_alive(response: &mut http::Response<Vec<u8>>, keep_alive: bool) {
+    let value = if keep_alive { "keep-alive" } else { "close" };
+    response
+        .headers_mut()
+        .insert(http::header::CONNECTION, http::HeaderValue::from_static(value));
+}
+
 /// This is a helper function that creates an http::Response containing an HTTP error that can be
 /// sent to a client.
 pub fn make_http_error(status: http::StatusCode) -> http::Response<Vec<u8>> {
@@ -222,3 +332,16 @@ pub fn make_http_error(status: http::StatusCode) -> http::Response<Vec<u8>> {
         .body(body)
         .unwrap()
 }
+
+/// Creates a response that sends the client to another URL
+pub fn make_redirect(status: http::StatusCode, location: &str) -> http::Response<Vec<u8>> {
+    let body = format!("Redirecting to {}", location).into_bytes();
+    http::Response::builder()
+        .status(status)
+        .header("Location", location)
+        .header("Content-Type", "text/plain")
+        .header("Content-Length", body.len().to_string())
+        .version(http::Version::HTTP_11)
+        .body(body)
+        .unwrap_or_else(|_| make_http_error(http::StatusCode::INTERNAL_SERVER_ERROR))
+}
@@ -0,0 +1,271 @@
+use std::collections::{HashMap, HashSet};
+use std::net::IpAddr;
+use bytes::BytesMut;
+use std::sync::atomic::Ordering;
+use std::sync::{Arc, Mutex};
+use tokio::time::{timeout, Duration, Instant};
+
+use crate::body::Framing;
+use crate::load_balance::RequestContext;
+use crate::connection::connect_to_upstream;
+use crate::state::ProxyState;
+use crate::{request, response};
+
+/// Most responses kept at once. Past this, the oldest one is dropped to make room.
+const MAX_ENTRIES: usize = 1024;
+/// Largest body kept. Only small responses (health checks, static files) are worth holding on to.
+pub const MAX_BODY_SIZE: usize = 1024 * 1024;
+
+/// How long a response may be served from the cache, according to its Cache-Control header
+#[derive(Clone, Copy, Debug)]
+pub struct Freshness {
+    /// How long the response is fresh for (max-age, or s-maxage if given)
+    max_age: Duration,
+    /// How long after going stale it may still be served while it is fetched again in the
+    /// background (stale-while-revalidate)
+    stale_while_revalidate: Duration,
+    /// How long after going stale it may still be served if the upstream fails (stale-if-error)
+    stale_if_error: Duration,
+}
+
+/// A response kept for answering later requests
+struct Entry {
+    status: http::StatusCode,
+    headers: http::HeaderMap,
+    body: Vec<u8>,
+    stored: Instant,
+    freshness: Freshness,
+}
+
+impl Entry {
+    /// Builds a response to a request out of the entry, saying how old it is
+    fn to_response(&self) -> http::Response<Vec<u8>> {
+        let mut response = http::Response::new(self.body.clone());
+        *response.status_mut() = self.status;
+        *response.headers_mut() = self.headers.clone();
+        let age = http::HeaderValue::from(self.stored.elapsed().as_secs());
+        response.headers_mut().insert(http::header::AGE, age);
+        response
+    }
+}
+
+/// What the cache has for a request
+pub enum Lookup {
+    /// A response that is still fresh
+    Fresh(http::Response<Vec<u8>>),
+    /// A response that has gone stale, but may be served while a fresh one is fetched
+    Stale(http::Response<Vec<u8>>),
+    Miss,
+}
+
+/// Responses to GET requests under the `--cache-path` prefixes, kept for as long as their
+/// Cache-Control header allows. A response that has gone stale may still be served for a while
+/// (stale-while-revalidate) while a fresh copy is fetched in the background, or (stale-if-error)
+/// if the upstream fails, so that cached content stays available through an outage.
+pub struct ResponseCache {
+    entries: Mutex<HashMap<String, Entry>>,
+    /// Keys of the entries being fetched again in the background
+    revalidating: Mutex<HashSet<String>>,
+}
+
+impl ResponseCache {
+    pub fn new() -> ResponseCache {
+        ResponseCache { entries: Mutex::new(HashMap::new()), revalidating: Mutex::new(HashSet::new()) }
+    }
+
+    pub fn lookup(&self, key: &str) -> Lookup {
+        let entries = self.entries.lock().unwrap();
+        let entry = match entries.get(key) {
+            Some(entry) => entry,
+            None => return Lookup::Miss,
+        };
+        let age = entry.stored.elapsed();
+        if age < entry.freshness.max_age {
+            Lookup::Fresh(entry.to_response())
+        } else if age < entry.freshness.max_age + entry.freshness.stale_while_revalidate {
+            Lookup::Stale(entry.to_response())
+        } else {
+            Lookup::Miss
+        }
+    }
+
+    /// Returns the response to serve in place of an upstream failure, if there is one that isn't
+    /// too stale for that
+    pub fn lookup_on_error(&self, key: &str) -> Option<http::Response<Vec<u8>>> {
+        let entries = self.entries.lock().unwrap();
+        let entry = entries.get(key)?;
+        let usable_for = entry.freshness.max_age + entry.freshness.stale_if_error;
+        (entry.stored.elapsed() < usable_for).then(|| entry.to_response())
+    }
+
+    /// Keeps the response (whose body has been read into `body`) for later requests
+    pub fn store(
+        &self,
+        key: &str,
+        response: &http::Response<Vec<u8>>,
+        body: &[u8],
+        freshness: Freshness,
+    ) {
+        let mut headers = response.headers().clone();
+        headers.remove(http::header::CONNECTION);
+        headers.remove("keep-alive");
+        let entry = Entry {
+            status: response.status(),
+            headers,
+            body: body.to_vec(),
+            stored: Instant::now(),
+            freshness,
+        };
+        let mut entries = self.entries.lock().unwrap();
+        if entries.len() >= MAX_ENTRIES && !entries.contains_key(key) {
+            let oldest = entries.iter().min_by_key(|(_, entry)| entry.stored);
+            if let Some(oldest) = oldest.map(|(key, _)| key.clone()) {
+                entries.remove(&oldest);
+            }
+        }
+        entries.insert(key.to_string(), entry);
+    }
+
+    /// Forgets every response
+    pub fn clear(&self) {
+        self.entries.lock().unwrap().clear();
+    }
+
+    /// Marks the entry as being fetched again. Returns false if it already is.
+    fn start_revalidating(&self, key: &str) -> bool {
+        self.revalidating.lock().unwrap().insert(key.to_string())
+    }
+
+    fn finish_revalidating(&self, key: &str) {
+        self.revalidating.lock().unwrap().remove(key);
+    }
+}
+
+/// Returns the key a request's response is kept under, or None if it shouldn't be served from (or
+/// kept in) the cache: only GET requests for paths under one of the prefixes are, and not ones
+/// carrying credentials, whose responses may be meant for that client alone. The key doesn't
+/// depend on the encodings the client accepts: the cache keeps the uncompressed response, which is
+/// compressed for each client as it is served.
+pub fn key(request: &http::Request<Vec<u8>>, prefixes: &[String]) -> Option<String> {
+    if request.method() != http::Method::GET
+        || request.headers().contains_key(http::header::AUTHORIZATION)
+        || !prefixes.iter().any(|prefix| request.uri().path().starts_with(prefix.as_str()))
+    {
+        return None;
+    }
+    let host = request
+        .headers()
+        .get(http::header::HOST)
+        .and_then(|host| host.to_str().ok())
+        .unwrap_or("");
+    let path = request.uri().path_and_query().map(|path| path.as_str()).unwrap_or("/");
+    Some(format!("{}{}", host, path))
+}
+
+/// Returns how long the response may be cached for, or None if it may not be. Only complete 200
+/// responses of a known length that say how long they're good for are kept, and not ones that
+/// set cookies, are compressed, or vary with anything but the Accept-Encoding header (which is the
+/// same for every request sent upstream for a cached path).
+pub fn cacheable(response: &http::Response<Vec<u8>>, framing: Framing) -> Option<Freshness> {
+    if response.status() != http::StatusCode::OK {
+        return None;
+    }
+    match framing {
+        Framing::Length(len) if len <= MAX_BODY_SIZE => {}
+        _ => return None,
+    }
+    let headers = response.headers();
+    let varies = headers
+        .get_all(http::header::VARY)
+        .iter()
+        .flat_map(|value| value.to_str().unwrap_or("*").split(','))
+        .any(|name| !name.trim().eq_ignore_ascii_case("accept-encoding"));
+    if headers.contains_key(http::header::SET_COOKIE)
+        || varies
+        || headers.contains_key(http::header::CONTENT_ENCODING)
+    {
+        return None;
+    }
+    let mut max_age = None;
+    let mut shared_max_age = None;
+    let mut stale_while_revalidate = 0;
+    let mut stale_if_error = 0;
+    for value in headers.get_all(http::header::CACHE_CONTROL) {
+        for directive in value.to_str().ok()?.split(',') {
+            let (name, value) = match directive.split_once('=') {
+                Some((name, value)) => (name.trim(), value.trim().trim_matches('"').parse().ok()),
+                None => (directive.trim(), None),
+            };
+            match name.to_ascii_lowercase().as_str() {
+                "no-store" | "no-cache" | "private" => return None,
+                "max-age" => max_age = value,
+                "s-maxage" => shared_max_age = value,
+                "stale-while-revalidate" => stale_while_revalidate = value.unwrap_or(0),
+                "stale-if-error" => stale_if_error = value.unwrap_or(0),
+                _ => {}
+            }
+        }
+    }
+    let max_age = shared_max_age.or(max_age)?;
+    Some(Freshness {
+        max_age: Duration::from_secs(max_age),
+        stale_while_revalidate: Duration::from_secs(stale_while_revalidate),
+        stale_if_error: Duration::from_secs(stale_if_error),
+    })
+}
+
+/// Fetches a fresh copy of a stale response in the background, unless that's already happening
+pub fn revalidate(
+    state: &Arc<ProxyState>,
+    key: String,
+    request: &http::Request<Vec<u8>>,
+    pool: Vec<usize>,
+    client_ip: IpAddr,
+) {
+    if !state.response_cache.start_revalidating(&key) {
+        return;
+    }
+    let mut copy = http::Request::new(Vec::new());
+    *copy.method_mut() = request.method().clone();
+    *copy.uri_mut() = request.uri().clone();
+    *copy.headers_mut() = request.headers().clone();
+    copy.headers_mut()
+        .insert(http::header::CONNECTION, http::HeaderValue::from_static("close"));
+    let state = state.clone();
+    tokio::spawn(async move {
+        let response_timeout = state.upstream_response_timeout.load(Ordering::SeqCst).max(1);
+        let fetched = timeout(
+            Duration::from_secs(response_timeout as u64),
+            fetch(&state, &copy, &pool, client_ip),
+        )
+        .await;
+        match fetched {
+            Ok(Some((response, body, freshness))) => {
+                log::debug!("Revalidated cached response for {}", key);
+                state.response_cache.store(&key, &response, &body, freshness);
+            }
+            Ok(None) => log::debug!("Failed to revalidate cached response for {}", key),
+            Err(_) => log::debug!("Timed out revalidating cached response for {}", key),
+        }
+        state.response_cache.finish_revalidating(&key);
+    });
+}
+
+/// Sends the request to an upstream, returning its response if it can be cached
+async fn fetch(
+    state: &Arc<ProxyState>,
+    request: &http::Request<Vec<u8>>,
+    pool: &[usize],
+    client_ip: IpAddr,
+) -> Option<(http::Response<Vec<u8>>, Vec<u8>, Freshness)> {
+    let context = RequestContext { client_ip, request, pool, excluded: &[] };
+    let mut connection = connect_to_upstream(state, &context).await.ok()?;
+    request::write_to_stream(request, &mut connection.stream).await.ok()?;
+    let (response, body) =
+        response::read_head(&mut connection.stream, request.method(), BytesMut::new())
+            .await
+            .ok()?;
+    let freshness = cacheable(&response, body.framing())?;
+    let body = body.read_to_end(&mut connection.stream, MAX_BODY_SIZE).await.ok()?;
+    Some((response, body, freshness))
+}
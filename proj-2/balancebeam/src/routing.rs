@@ -0,0 +1,187 @@
+use std::collections::HashMap;
+use std::net::IpAddr;
+use crate::access_control::Cidr;
+use crate::blue_green::Color;
+use crate::config::Config;
+use crate::upstream;
+
+/// Parses a `<key>=<value>` command-line argument, such as a route or a pool
+pub fn parse_key_value(arg: &str) -> Result<(String, String), String> {
+    match arg.split_once('=') {
+        Some((key, value)) if !key.is_empty() && !value.is_empty() => {
+            Ok((key.to_string(), value.to_string()))
+        }
+        _ => Err(format!("expected <key>=<value>, got {:?}", arg)),
+    }
+}
+
+/// Returns true if the path falls under the route prefix. "/api" matches "/api" and "/api/users",
+/// but not "/apis".
+pub(crate) fn matches_prefix(path: &str, prefix: &str) -> bool {
+    match path.strip_prefix(prefix) {
+        Some(rest) => prefix.ends_with('/') || rest.is_empty() || rest.starts_with('/'),
+        None => false,
+    }
+}
+
+/// Settings a route overrides for the requests under it. Those that aren't given fall back to the
+/// global settings.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct RoutePolicy {
+    /// How long (in seconds) to wait for an upstream to respond (0 = forever)
+    pub upstream_response_timeout: Option<usize>,
+    /// Number of other upstreams a failed request may be retried on
+    pub max_retries: Option<usize>,
+    /// Maximum number of requests each client can make under the route in a minute (0 = unlimited)
+    pub max_requests_per_minute: Option<usize>,
+}
+
+/// A path prefix, and what happens to requests under it
+pub struct Route {
+    pub prefix: String,
+    /// Upstreams in the pool the route sends requests to
+    members: Vec<usize>,
+    pub policy: RoutePolicy,
+}
+
+/// Decides which upstreams may serve a request, based on where it came from and its path. Upstreams
+/// are referred to by their index in the list of backends (`Config::all_upstreams`, with host names
+/// expanded into the addresses they resolved to).
+pub struct Routes {
+    /// Routes, longest prefix first
+    routes: Vec<Route>,
+    /// Client address ranges and the upstreams requests from them go to, narrowest range first
+    clients: Vec<(Cidr, Vec<usize>)>,
+    /// Upstreams that requests not matching any route are sent to
+    default: Vec<usize>,
+    /// Upstreams in the blue and green pools, which take the place of the default upstreams
+    blue_green: Option<(Vec<usize>, Vec<usize>)>,
+    /// Upstreams of each canary, with the percentage of requests it gets
+    canaries: Vec<(Vec<usize>, usize)>,
+    /// Upstreams in each pool, by the pool's name
+    pools: HashMap<String, Vec<usize>>,
+}
+
+impl Routes {
+    pub fn new(config: &Config, backends: &[String]) -> Routes {
+        // A backend belongs to a pool if the pool lists the upstream it was resolved from
+        let indexes = |addresses: &[String]| -> Vec<usize> {
+            backends
+                .iter()
+                .enumerate()
+                .filter(|(_, backend)| addresses.contains(&upstream::entry_of(backend).to_string()))
+                .map(|(idx, _)| idx)
+                .collect()
+        };
+        let mut routes: Vec<Route> = config
+            .routes
+            .iter()
+            .map(|(prefix, pool)| Route {
+                prefix: prefix.clone(),
+                members: config.pools.get(pool).map(|pool| indexes(pool)).unwrap_or_default(),
+                policy: config.route_policies.get(prefix).cloned().unwrap_or_default(),
+            })
+            .collect();
+        routes.sort_by_key(|route| std::cmp::Reverse(route.prefix.len()));
+        let mut clients: Vec<(Cidr, Vec<usize>)> = config
+            .client_routes
+            .iter()
+            .map(|(range, pool)| {
+                (*range, config.pools.get(pool).map(|pool| indexes(pool)).unwrap_or_default())
+            })
+            .collect();
+        clients.sort_by_key(|(range, _)| std::cmp::Reverse(range.prefix_len()));
+        // Canaries take requests that don't match any route, alongside the --upstream hosts
+        let mut default_upstreams = config.default_upstreams();
+        default_upstreams.extend(config.canaries.keys().cloned());
+        let canaries = config
+            .canaries
+            .iter()
+            .map(|(address, percent)| (indexes(std::slice::from_ref(address)), *percent))
+            .collect();
+        let pools = config.pools.iter().map(|(name, pool)| (name.clone(), indexes(pool))).collect();
+        let pool_members = |name: &String| config.pools.get(name).map(|pool| indexes(pool));
+        let blue_green = match (&config.blue_pool, &config.green_pool) {
+            (Some(blue), Some(green)) => pool_members(blue).zip(pool_members(green)),
+            _ => None,
+        };
+        Routes {
+            routes,
+            clients,
+            default: indexes(&default_upstreams),
+            blue_green,
+            canaries,
+            pools,
+        }
+    }
+
+    /// Returns true if the upstream is a canary
+    pub fn is_canary(&self, idx: usize) -> bool {
+        self.canaries.iter().any(|(members, _)| members.contains(&idx))
+    }
+
+    /// Returns the route a request for the given path falls under, if any
+    pub fn route_for(&self, path: &str) -> Option<&Route> {
+        self.routes.iter().find(|route| matches_prefix(path, &route.prefix))
+    }
+
+    /// Returns the upstreams that may serve a request for the given path, while the given
+    /// blue/green pool is live
+    pub fn pool_for(&self, path: &str, live: Color) -> &[usize] {
+        self.route_for(path)
+            .map(|route| route.members.as_slice())
+            .unwrap_or_else(|| self.default_pool(live))
+    }
+
+    /// Returns the upstreams that requests from the client go to, if it falls in one of the client
+    /// routes' ranges. These take precedence over the path's route.
+    pub fn pool_for_client(&self, ip: IpAddr) -> Option<&[usize]> {
+        self.clients
+            .iter()
+            .find(|(range, _)| range.contains(ip))
+            .map(|(_, members)| members.as_slice())
+    }
+
+    /// Returns the upstreams that requests not matching any route are sent to, while the given
+    /// blue/green pool is live
+    pub fn default_pool(&self, live: Color) -> &[usize] {
+        self.color_pool(live).unwrap_or(&self.default)
+    }
+
+    /// Returns the upstreams in the blue or green pool, if there are blue/green pools
+    pub fn color_pool(&self, color: Color) -> Option<&[usize]> {
+        self.blue_green.as_ref().map(|(blue, green)| match color {
+            Color::Blue => blue.as_slice(),
+            Color::Green => green.as_slice(),
+        })
+    }
+
+    /// Returns the upstreams in the pool with the given name, if there is one
+    pub fn pool_named(&self, name: &str) -> Option<&[usize]> {
+        self.pools.get(name).map(|members| members.as_slice())
+    }
+
+    /// Returns the settings the route a request for the given path falls under overrides
+    pub fn policy_for(&self, path: &str) -> RoutePolicy {
+        self.route_for(path).map(|route| route.policy.clone()).unwrap_or_default()
+    }
+
+    /// Narrows a pool down to the upstreams a request may go to: a canary's, for that canary's
+    /// share of requests, or otherwise the pool's stable upstreams. `roll` is a random number from
+    /// 0 to 100.
+    pub fn split_canary_traffic(&self, pool: &[usize], roll: f64) -> Vec<usize> {
+        let mut threshold = 0.0;
+        for (members, percent) in &self.canaries {
+            let members: Vec<usize> =
+                members.iter().copied().filter(|idx| pool.contains(idx)).collect();
+            if members.is_empty() {
+                continue;
+            }
+            threshold += *percent as f64;
+            if roll < threshold {
+                return members;
+            }
+        }
+        pool.iter().copied().filter(|&idx| !self.is_canary(idx)).collect()
+    }
+}
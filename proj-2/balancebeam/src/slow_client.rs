@@ -0,0 +1,186 @@
+use std::future::Future;
+use std::io;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::time::{sleep, Duration, Instant, Sleep};
+use crate::access_log::Sizes;
+
+/// How long a client may keep us waiting before its throughput is held against it. Each byte it
+/// sends or reads buys it more time on top of this.
+const GRACE_PERIOD: Duration = Duration::from_secs(2);
+
+/// How slow a client may be before we give up on it
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Limits {
+    /// Longest a client may go without reading any of a response (None = forever)
+    pub write_timeout: Option<Duration>,
+    /// Fewest bytes per second a client has to send a request or read a response at, counting
+    /// only the time spent waiting on it (0 = no minimum)
+    pub min_rate: usize,
+}
+
+impl Limits {
+    pub fn new(write_timeout: usize, min_rate: usize) -> Limits {
+        let write_timeout = match write_timeout {
+            0 => None,
+            seconds => Some(Duration::from_secs(seconds as u64)),
+        };
+        Limits { write_timeout, min_rate }
+    }
+}
+
+/// Keeps track of how long we've waited on the client in one direction, and how much it has sent
+/// or read in that time
+struct Meter {
+    /// Bytes moved since the meter was last reset
+    bytes: u64,
+    /// Time spent waiting on the client since then, not counting the current wait
+    waited: Duration,
+    /// When the current wait started, if we're waiting
+    waiting_since: Option<Instant>,
+    /// Goes off when the current wait has gone on for too long
+    deadline: Pin<Box<Sleep>>,
+}
+
+impl Meter {
+    fn new() -> Meter {
+        Meter {
+            bytes: 0,
+            waited: Duration::ZERO,
+            waiting_since: None,
+            deadline: Box::pin(sleep(Duration::ZERO)),
+        }
+    }
+
+    fn reset(&mut self) {
+        self.bytes = 0;
+        self.waited = Duration::ZERO;
+        self.waiting_since = None;
+    }
+
+    /// Returns how long the current wait may last, if there's a limit. Throughput only counts once
+    /// the client has started sending or reading something, so that waiting for its next request
+    /// isn't held against it.
+    fn allowed_wait(&self, stall_timeout: Option<Duration>, min_rate: usize) -> Option<Duration> {
+        let rate_allowance = (min_rate > 0 && self.bytes > 0).then(|| {
+            let earned = Duration::from_secs_f64(self.bytes as f64 / min_rate as f64);
+            GRACE_PERIOD.max(earned).saturating_sub(self.waited)
+        });
+        match (stall_timeout, rate_allowance) {
+            (Some(stall_timeout), Some(allowance)) => Some(stall_timeout.min(allowance)),
+            (stall_timeout, allowance) => stall_timeout.or(allowance),
+        }
+    }
+
+    /// Accounts for the outcome of a read or write on the client connection, turning a wait
+    /// that has gone on too long into a timeout error
+    fn record<T>(
+        &mut self,
+        cx: &mut Context<'_>,
+        limits: (Option<Duration>, usize),
+        outcome: Poll<io::Result<T>>,
+        transferred: impl FnOnce(&T) -> usize,
+    ) -> Poll<io::Result<T>> {
+        match outcome {
+            Poll::Ready(Ok(value)) => {
+                if let Some(since) = self.waiting_since.take() {
+                    self.waited += since.elapsed();
+                }
+                self.bytes += transferred(&value) as u64;
+                Poll::Ready(Ok(value))
+            }
+            Poll::Ready(Err(err)) => Poll::Ready(Err(err)),
+            Poll::Pending => {
+                let allowed = match self.allowed_wait(limits.0, limits.1) {
+                    Some(allowed) => allowed,
+                    None => return Poll::Pending,
+                };
+                let since = *self.waiting_since.get_or_insert_with(Instant::now);
+                self.deadline.as_mut().reset(since + allowed);
+                match self.deadline.as_mut().poll(cx) {
+                    Poll::Ready(()) => Poll::Ready(Err(io::Error::new(
+                        io::ErrorKind::TimedOut,
+                        "client is too slow",
+                    ))),
+                    Poll::Pending => Poll::Pending,
+                }
+            }
+        }
+    }
+}
+
+/// A client connection that fails with a timeout error once the client goes too long without
+/// reading any of a response, or sends a request or reads a response too slowly, so that
+/// slow-loris style clients can't hold on to a connection (and the upstream connection behind it)
+/// for as long as they like.
+pub struct Guarded<S> {
+    inner: S,
+    limits: Limits,
+    read: Meter,
+    write: Meter,
+}
+
+impl<S> Guarded<S> {
+    pub fn new(inner: S, limits: Limits) -> Guarded<S> {
+        Guarded { inner, limits, read: Meter::new(), write: Meter::new() }
+    }
+
+    /// Starts measuring afresh for the next request on the connection, with the given limits
+    pub fn reset(&mut self, limits: Limits) {
+        self.limits = limits;
+        self.read.reset();
+        self.write.reset();
+    }
+
+    /// How much has been read from and written to the client since the last reset, i.e. for the
+    /// current request, given the size of the response body sent
+    pub fn sizes(&self, body: u64) -> Sizes {
+        Sizes { body, received: self.read.bytes, sent: self.write.bytes }
+    }
+
+    /// Stops holding the client to any limits, e.g. once the connection is upgraded to another
+    /// protocol, where long pauses are normal
+    pub fn lift_limits(&mut self) {
+        self.limits = Limits::default();
+    }
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for Guarded<S> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        let filled_before = buf.filled().len();
+        let outcome = Pin::new(&mut this.inner).poll_read(cx, buf);
+        let read = buf.filled().len() - filled_before;
+        // Requests are already given a time limit by the read timeouts
+        this.read.record(cx, (None, this.limits.min_rate), outcome, |_| read)
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for Guarded<S> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        let outcome = Pin::new(&mut this.inner).poll_write(cx, buf);
+        let limits = (this.limits.write_timeout, this.limits.min_rate);
+        this.write.record(cx, limits, outcome, |written| *written)
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        let outcome = Pin::new(&mut this.inner).poll_flush(cx);
+        let limits = (this.limits.write_timeout, this.limits.min_rate);
+        this.write.record(cx, limits, outcome, |_| 0)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_shutdown(cx)
+    }
+}
@@ -0,0 +1,1265 @@
+use crate::access_control::{AccessList, Cidr};
+use crate::access_log::{AccessLog, Sizes, Timing};
+use crate::active_connections::ActiveConnections;
+use crate::active_health::HealthCheck;
+use crate::auth::Authenticator;
+use crate::ban_list::BanList;
+use crate::bandwidth::BandwidthLimiter;
+use crate::blue_green::Deployment;
+use crate::canary::CanaryStats;
+use crate::circuit_breaker::CircuitBreakers;
+use crate::config::Config;
+use crate::connection::UpstreamConnection;
+use crate::connection_limit::ConnectionLimits;
+use crate::dns::ResolvedHosts;
+use crate::error_pages::ErrorPages;
+use crate::header_rules::HeaderRules;
+use crate::health_hooks::HealthHooks;
+use crate::jwt::JwtValidator;
+use crate::latency::Latencies;
+use crate::load_balance::{
+    consistent_hash::ConsistentHash, random::Random, round_robin::RoundRobin,
+};
+use crate::load_balance::{ewma::Ewma, p2c::P2c, sticky::Sticky};
+use crate::load_balance::{ArgLoadBalance, LoadBalanceStrategy};
+use crate::middleware::{ApiKeyRateLimit, Authenticate, Maintenance, Middleware, ProxyHeaders};
+use crate::middleware::{BandwidthLimit, ConcurrentRequestLimit, Cors, JwtAuth, Redirect};
+use crate::middleware::{RouteRateLimit, UpstreamOverride};
+use crate::mirror::Mirror;
+use crate::passive_health::FailureTracker;
+use crate::proxy_headers::ProxyHeader;
+#[cfg(feature = "redis")]
+use crate::rate_limiter::redis_counter::RedisRateLimiter;
+use crate::rate_limiter::{counter::Counter, token_bucket::TokenBucket};
+use crate::rate_limiter::{ArgRateLimiter, RateLimitBy, RateLimitKey, RateLimiterStrategy};
+use crate::redirect::Redirects;
+use crate::response_cache::ResponseCache;
+use crate::routing::Routes;
+use crate::tcp_proxy::ProxyMode;
+use crate::upstream_limit::UpstreamRateLimiter;
+use crate::upstream_stats::UpstreamStats;
+use crate::{compression, cors, dns, error_pages, http2, middleware, rate_limiter, slow_client};
+use crate::{statsd, upstream};
+use arc_swap::ArcSwap;
+use dashmap::DashMap;
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicU8, AtomicUsize, Ordering};
+use std::sync::Arc;
+use tokio::{
+    sync::{Notify, RwLock},
+    time::{sleep, Duration, Instant},
+};
+
+/// Contains information about the state of balancebeam (e.g. what servers we are currently proxying
+/// to, what servers have failed, rate limiting counts, etc.)
+pub struct ProxyState {
+    /// How frequently we check whether upstream servers are alive (Milestone 4)
+    pub(crate) active_health_check_interval: AtomicUsize,
+    /// What request we send when doing active health checks, and what response we expect
+    pub(crate) active_health_check: RwLock<HealthCheck>,
+    /// Where upstreams going down and coming back up are reported
+    pub(crate) health_hooks: RwLock<HealthHooks>,
+    /// Number of failed requests in a row after which we mark an upstream down (0 = never)
+    pub(crate) passive_health_check_failures: AtomicUsize,
+    /// How close together (in seconds) failures need to be to count as being in a row
+    pub(crate) passive_health_check_window: AtomicUsize,
+    /// Recent request failures for each upstream
+    pub(crate) upstream_failures: FailureTracker,
+    /// Number of other upstreams a failed request may be retried on
+    pub(crate) max_retries: AtomicUsize,
+    /// How long (in seconds) we wait for a client to send a request (0 = forever)
+    pub(crate) client_read_timeout: AtomicUsize,
+    /// How long (in seconds) a client connection may sit idle between requests before we close it
+    /// (0 = forever)
+    pub(crate) client_idle_timeout: AtomicUsize,
+    /// How long (in seconds) a client may go without reading any of a response (0 = forever)
+    pub(crate) client_write_timeout: AtomicUsize,
+    /// Bytes per second a client has to send requests and read responses at (0 = no minimum)
+    pub(crate) client_min_rate: AtomicUsize,
+    /// How many requests a client may send on one connection before we close it (0 = no limit)
+    pub(crate) max_requests_per_connection: AtomicUsize,
+    /// How long (in seconds) we wait for a connection to an upstream to be established (0 = forever)
+    pub(crate) upstream_connect_timeout: AtomicUsize,
+    /// How long (in milliseconds) we wait before trying another upstream after failing to connect
+    /// to one, doubling with each attempt (0 = try the next one right away)
+    pub(crate) upstream_connect_backoff: AtomicUsize,
+    /// How long (in seconds) we wait for an upstream to respond to a request (0 = forever)
+    pub(crate) upstream_response_timeout: AtomicUsize,
+    /// How long each upstream has been taking to respond lately
+    pub(crate) upstream_latencies: Latencies,
+    /// Each upstream's request rate, error rate and response times over the last minute
+    pub(crate) upstream_stats: UpstreamStats,
+    /// How often (in seconds) we log a summary of the upstreams' stats (0 = never)
+    pub(crate) stats_log_interval: AtomicUsize,
+    /// How many connections are open to each upstream
+    pub(crate) upstream_connections: ActiveConnections,
+    /// How many requests are in flight to each upstream, so we know when a drained one is done
+    pub(crate) upstream_requests: ActiveConnections,
+    /// Limits on how fast requests are sent to each upstream
+    pub(crate) upstream_limiter: UpstreamRateLimiter,
+    /// Most requests each upstream may be serving at once (0 = unlimited)
+    pub(crate) max_upstream_concurrency: AtomicUsize,
+    /// Most requests that may wait for an upstream to have room for them
+    pub(crate) request_queue_size: AtomicUsize,
+    /// How long (in seconds) a request may wait for an upstream to have room for it
+    pub(crate) request_queue_timeout: AtomicUsize,
+    /// How many requests are waiting for an upstream to have room for them
+    pub(crate) queued_requests: AtomicUsize,
+    /// Circuit breaker for each upstream, which takes it out of rotation while it keeps failing
+    pub(crate) circuit_breakers: CircuitBreakers,
+    /// Maximum number of requests an individual IP can make in a minute (Milestone 5)
+    pub(crate) max_requests_per_minute: AtomicUsize,
+    /// Most requests each client IP may have in progress at once (0 = unlimited)
+    pub(crate) max_concurrent_per_ip: AtomicUsize,
+    /// How many requests each client IP has in progress
+    pub(crate) client_requests: ActiveConnections,
+    /// Most bytes each client IP may send and be sent in a minute (0 = unlimited)
+    pub(crate) max_bytes_per_minute: AtomicU64,
+    /// How many bytes each client IP has sent and been sent this minute
+    pub(crate) bandwidth: BandwidthLimiter,
+    /// How often (in seconds) upstream host names are looked up again (0 = never)
+    pub(crate) dns_refresh_interval: AtomicUsize,
+    /// Addresses the upstreams' host names resolved to
+    pub(crate) resolved_hosts: RwLock<ResolvedHosts>,
+    /// Addresses of servers that we are proxying to, including the ones in pools, with host names
+    /// expanded into the addresses they resolved to
+    pub(crate) upstream_addresses: RwLock<Vec<String>>,
+    /// Which of the upstreams each request may be sent to, depending on its path
+    pub(crate) routes: RwLock<Routes>,
+    /// Which of the blue and green pools is live
+    pub(crate) deployment: Deployment,
+    /// How long (in seconds) connections pinned to the pool switched away from may keep using it
+    pub(crate) blue_green_drain_timeout: AtomicUsize,
+    /// How requests to the canaries are faring compared to the rest
+    pub(crate) canary_stats: CanaryStats,
+    /// Shadow backend to send a copy of each request to, if any
+    pub(crate) mirror_address: RwLock<Option<String>>,
+    /// Sends the copies to the shadow backend
+    pub(crate) mirror: Mirror,
+    /// Path prefixes whose responses are cached
+    pub(crate) cache_paths: RwLock<Vec<String>>,
+    /// Responses kept for answering later requests
+    pub(crate) response_cache: ResponseCache,
+    /// Status of upstream servers. It's swapped out along with upstream_addresses, while holding
+    /// that lock.
+    pub(crate) upstream_status: ArcSwap<UpstreamsStatus>,
+    /// What requests are counted against for rate limiting
+    pub(crate) rate_limit_by: RwLock<RateLimitBy>,
+    /// Strategy of limiter to use. It's swapped out whole when its settings change, so requests
+    /// can check it without taking a lock.
+    pub(crate) limiter: ArcSwap<Box<dyn RateLimiterStrategy<RateLimitKey>>>,
+    /// Clients that kept going over the rate limit, and are turned away when they connect
+    pub(crate) ban_list: BanList,
+    /// Limiters for the routes with their own rate limit, by route prefix. Each is set up when
+    /// the first request under its route comes in.
+    pub(crate) route_limiters: DashMap<String, Arc<dyn RateLimiterStrategy<RateLimitKey>>>,
+    /// Strategy of load balancer to use
+    pub(crate) load_balancer: RwLock<Box<dyn LoadBalanceStrategy>>,
+    /// Name of the session cookie that pins clients to an upstream, if sticky sessions are on
+    pub(crate) sticky_cookie: RwLock<Option<String>>,
+    /// Proxy headers that we shouldn't add to requests and responses
+    pub(crate) disabled_proxy_headers: RwLock<Vec<ProxyHeader>>,
+    /// Which clients may use the proxy
+    pub(crate) access_list: RwLock<AccessList>,
+    /// Whether requests are being answered with the maintenance page
+    pub(crate) maintenance: AtomicBool,
+    /// Client connections currently open, by client address
+    pub(crate) client_connections: ActiveConnections,
+    /// Whether we've stopped accepting connections, and are waiting for the open ones to finish
+    pub(crate) shutting_down: AtomicBool,
+    /// Notified when we start shutting down, so that idle connections can be closed right away
+    pub(crate) shutdown: Notify,
+    /// How long (in seconds) we wait for open connections to finish when shutting down
+    /// (0 = forever)
+    pub(crate) shutdown_timeout: AtomicUsize,
+    /// Content type and contents of the maintenance page (None = a plain 503 message)
+    pub(crate) maintenance_page: RwLock<Option<(&'static str, Vec<u8>)>>,
+    /// Clients that are let through while in maintenance mode
+    pub(crate) maintenance_allow_cidrs: RwLock<Vec<Cidr>>,
+    /// Whether trusted clients may pick the upstream for their requests
+    pub(crate) upstream_override: AtomicBool,
+    /// Clients that may pick the upstream for their requests (empty = only ones on this host)
+    pub(crate) upstream_override_cidrs: RwLock<Vec<Cidr>>,
+    /// Proxies whose X-Forwarded-For is believed about who the client is
+    pub(crate) trusted_proxies: RwLock<Vec<Cidr>>,
+    /// Requests that are answered with a redirect instead of being forwarded
+    pub(crate) redirects: RwLock<Redirects>,
+    /// Checks the credentials of requests, if authentication is set up
+    pub(crate) authenticator: RwLock<Authenticator>,
+    /// Checks requests' JSON Web Tokens, if JWT validation is set up
+    pub(crate) jwt_validator: RwLock<Option<JwtValidator>>,
+    /// Which origins may make cross-origin requests, and with what
+    pub(crate) cors: RwLock<cors::Settings>,
+    /// Changes made to the headers of requests and responses
+    pub(crate) header_rules: RwLock<HeaderRules>,
+    /// Pages that errors we answer requests with ourselves are shown with
+    pub(crate) error_pages: RwLock<ErrorPages>,
+    /// Which responses get compressed for clients that accept it
+    pub(crate) compression: RwLock<compression::Settings>,
+    /// Settings from the command line, which the config file is applied on top of when reloading
+    pub(crate) base_config: Config,
+    /// Settings currently in effect
+    pub(crate) config: RwLock<Config>,
+    /// Config file to reload settings from when we receive SIGHUP
+    pub(crate) config_path: Option<String>,
+    /// TLS client settings for connecting to https:// upstreams
+    pub(crate) upstream_tls: tokio_rustls::TlsConnector,
+    /// Limits on how many client connections can be open at once
+    pub(crate) connection_limits: ConnectionLimits,
+    /// Where to log each request, if anywhere
+    pub(crate) access_log: Option<AccessLog>,
+    /// Hooks each request and response is passed through, in order
+    pub(crate) middlewares: Vec<Arc<dyn Middleware>>,
+    /// Whether connections carry HTTP requests or are proxied as raw TCP
+    pub(crate) mode: ProxyMode,
+    /// Connections to the upstreams for requests from HTTP/2 clients, if we accept those
+    pub(crate) http2: Option<http2::Upstreams>,
+    /// Where to push request and upstream health metrics, if anywhere
+    pub(crate) statsd: Option<statsd::Statsd>,
+}
+
+impl ProxyState {
+    pub(crate) fn new(
+        config: &Config,
+        base_config: Config,
+        config_path: Option<String>,
+        upstream_tls: tokio_rustls::TlsConnector,
+        connection_limits: ConnectionLimits,
+        access_log: Option<AccessLog>,
+        middlewares: Vec<Arc<dyn Middleware>>,
+    ) -> ProxyState {
+        // Maintenance mode and rate limiting come first, so requests they turn away aren't worked
+        // on any further. Tokens are checked just before rate limiting, which may count requests
+        // by one of their claims. Browsers don't send credentials with CORS preflight requests,
+        // so those are answered before any are asked for.
+        let mut chain: Vec<Arc<dyn Middleware>> = vec![
+            Arc::new(Maintenance {}),
+            Arc::new(ConcurrentRequestLimit {}),
+            Arc::new(Cors {}),
+            Arc::new(JwtAuth {}),
+            Arc::new(ApiKeyRateLimit {}),
+            Arc::new(RouteRateLimit {}),
+            Arc::new(BandwidthLimit {}),
+            Arc::new(Redirect {}),
+            Arc::new(Authenticate {}),
+            Arc::new(UpstreamOverride {}),
+            Arc::new(ProxyHeaders {}),
+        ];
+        chain.extend(middlewares);
+        ProxyState {
+            dns_refresh_interval: AtomicUsize::new(config.dns_refresh_interval),
+            resolved_hosts: RwLock::new(ResolvedHosts::new()),
+            upstream_addresses: RwLock::new(config.all_upstreams()),
+            upstream_status: ArcSwap::from_pointee(UpstreamsStatus::new(
+                config.all_upstreams().len(),
+                config.slow_start_window,
+            )),
+            routes: RwLock::new(Routes::new(config, &config.all_upstreams())),
+            deployment: Deployment::new(),
+            blue_green_drain_timeout: AtomicUsize::new(config.blue_green_drain_timeout),
+            canary_stats: CanaryStats::new(),
+            mirror_address: RwLock::new(config.mirror.clone()),
+            cache_paths: RwLock::new(config.cache_paths.clone()),
+            response_cache: ResponseCache::new(),
+            mirror: Mirror::new(),
+            active_health_check_interval: AtomicUsize::new(config.active_health_check_interval),
+            active_health_check: RwLock::new(HealthCheck::from_config(config)),
+            health_hooks: RwLock::new(HealthHooks::from_config(config)),
+            passive_health_check_failures: AtomicUsize::new(config.passive_health_check_failures),
+            passive_health_check_window: AtomicUsize::new(config.passive_health_check_window),
+            upstream_failures: FailureTracker::new(),
+            max_retries: AtomicUsize::new(config.max_retries),
+            client_read_timeout: AtomicUsize::new(config.client_read_timeout),
+            client_idle_timeout: AtomicUsize::new(config.client_idle_timeout),
+            client_write_timeout: AtomicUsize::new(config.client_write_timeout),
+            client_min_rate: AtomicUsize::new(config.client_min_rate),
+            max_requests_per_connection: AtomicUsize::new(config.max_requests_per_connection),
+            upstream_connect_timeout: AtomicUsize::new(config.upstream_connect_timeout),
+            upstream_connect_backoff: AtomicUsize::new(config.upstream_connect_backoff),
+            upstream_response_timeout: AtomicUsize::new(config.upstream_response_timeout),
+            upstream_latencies: Latencies::new(),
+            upstream_stats: UpstreamStats::new(),
+            stats_log_interval: AtomicUsize::new(config.stats_log_interval),
+            upstream_connections: ActiveConnections::new(),
+            upstream_requests: ActiveConnections::new(),
+            upstream_limiter: UpstreamRateLimiter::new(
+                config.max_upstream_rps,
+                Duration::from_secs(config.upstream_queue_timeout as u64),
+            ),
+            max_upstream_concurrency: AtomicUsize::new(config.max_upstream_concurrency),
+            request_queue_size: AtomicUsize::new(config.request_queue_size),
+            request_queue_timeout: AtomicUsize::new(config.request_queue_timeout),
+            queued_requests: AtomicUsize::new(0),
+            circuit_breakers: CircuitBreakers::new(config.circuit_breaker_settings()),
+            max_requests_per_minute: AtomicUsize::new(config.max_requests_per_minute),
+            max_concurrent_per_ip: AtomicUsize::new(config.max_concurrent_per_ip),
+            max_bytes_per_minute: AtomicU64::new(config.max_bytes_per_minute),
+            bandwidth: BandwidthLimiter::default(),
+            client_requests: ActiveConnections::new(),
+            rate_limit_by: RwLock::new(config.rate_limit_by),
+            limiter: ArcSwap::from_pointee(set_up_rate_limiter(
+                &config.rate_limiter_settings(),
+                config.max_requests_per_minute,
+                "global",
+            )),
+            ban_list: BanList::new(config.ban_settings()),
+            route_limiters: DashMap::new(),
+            load_balancer: RwLock::new(set_up_load_balancer(
+                config.load_balancer,
+                &config.all_upstreams(),
+                config.hash_header.clone(),
+                config.sticky_cookie.clone(),
+            )),
+            sticky_cookie: RwLock::new(config.sticky_cookie.clone()),
+            disabled_proxy_headers: RwLock::new(config.disabled_proxy_headers.clone()),
+            access_list: RwLock::new(config.access_list()),
+            maintenance: AtomicBool::new(false),
+            client_connections: ActiveConnections::new(),
+            shutting_down: AtomicBool::new(false),
+            shutdown: Notify::new(),
+            shutdown_timeout: AtomicUsize::new(config.shutdown_timeout),
+            maintenance_page: RwLock::new(middleware::load_maintenance_page(
+                config.maintenance_page.as_deref(),
+            )),
+            maintenance_allow_cidrs: RwLock::new(config.maintenance_allow_cidrs.clone()),
+            upstream_override: AtomicBool::new(config.upstream_override),
+            upstream_override_cidrs: RwLock::new(config.upstream_override_cidrs.clone()),
+            trusted_proxies: RwLock::new(config.trusted_proxies.clone()),
+            redirects: RwLock::new(config.redirects()),
+            authenticator: RwLock::new(config.authenticator()),
+            jwt_validator: RwLock::new(None),
+            error_pages: RwLock::new(ErrorPages::load(&config.error_pages)),
+            cors: RwLock::new(config.cors_settings()),
+            header_rules: RwLock::new(config.header_rules()),
+            compression: RwLock::new(config.compression_settings()),
+            base_config,
+            config: RwLock::new(config.clone()),
+            config_path,
+            upstream_tls,
+            connection_limits,
+            access_log,
+            middlewares: chain,
+            mode: ProxyMode::Http,
+            http2: None,
+            statsd: None,
+        }
+    }
+
+    /// Switches over to a new set of settings. Connections that are already open keep talking to
+    /// the upstream they were assigned; new requests are routed using the new settings.
+    pub(crate) async fn apply_config(&self, config: Config) {
+        let mut current = self.config.write().await;
+        self.switch_config(&mut current, config).await;
+    }
+
+    /// Applies the current settings again, e.g. to pick up upstream host names resolving to new
+    /// addresses
+    pub(crate) async fn reapply_config(&self) {
+        let mut current = self.config.write().await;
+        let config = current.clone();
+        self.switch_config(&mut current, config).await;
+    }
+
+    /// Adds an upstream to the ones that requests not matching any route are proxied to. Returns
+    /// false if it's already in use. (The upstream is forgotten again if the config file is
+    /// reloaded.)
+    pub(crate) async fn add_upstream(&self, address: String) -> bool {
+        let mut current = self.config.write().await;
+        if current.all_upstreams().contains(&address) {
+            return false;
+        }
+        let mut config = current.clone();
+        config.upstreams.push(address);
+        self.switch_config(&mut current, config).await;
+        true
+    }
+
+    /// Sets the admin state of the upstream with the given address, or of all the backends an
+    /// upstream's host name resolved to. Returns false if there is no such upstream.
+    pub(crate) async fn set_admin_state(&self, address: &str, admin_state: AdminState) -> bool {
+        let upstream_addresses = self.upstream_addresses.read().await;
+        let upstream_status = self.upstream_status.load();
+        let mut found = false;
+        for (idx, addr) in upstream_addresses.iter().enumerate() {
+            if addr == address || upstream::entry_of(addr) == address {
+                upstream_status.set_admin_state(idx, admin_state);
+                found = true;
+            }
+        }
+        found
+    }
+
+    /// Drains the upstreams on the new drain list, and puts the ones taken off it back into
+    /// rotation
+    pub(crate) async fn update_drained(&self, old: &[String], new: &[String]) {
+        for address in old.iter().filter(|address| !new.contains(address)) {
+            log::info!("Putting upstream {} back into rotation", address);
+            self.set_admin_state(address, AdminState::Enabled).await;
+        }
+        for address in new {
+            let found = self.set_admin_state(address, AdminState::Draining).await;
+            if !old.contains(address) {
+                match found {
+                    true => log::info!("Draining upstream {}", address),
+                    false => {
+                        log::warn!("Can't drain {}, which isn't one of the upstreams", address)
+                    }
+                }
+            }
+        }
+    }
+
+    /// Returns the upstreams being drained that have no requests left in flight, and so can be
+    /// taken down
+    pub(crate) async fn drained_upstreams(&self) -> Vec<String> {
+        let upstream_addresses = self.upstream_addresses.read().await;
+        let upstream_status = self.upstream_status.load();
+        upstream_addresses
+            .iter()
+            .enumerate()
+            .filter(|(idx, address)| {
+                upstream_status.admin_state(*idx) == Some(AdminState::Draining)
+                    && self.upstream_requests.get(address) == 0
+            })
+            .map(|(_, address)| address.clone())
+            .collect()
+    }
+
+    /// Records that a request to the given upstream failed, and marks the upstream down if it has
+    /// now failed too many times in a row
+    pub(crate) async fn record_upstream_failure(&self, address: &str) {
+        let threshold = self.passive_health_check_failures.load(Ordering::SeqCst);
+        if threshold == 0 {
+            return;
+        }
+        let window =
+            Duration::from_secs(self.passive_health_check_window.load(Ordering::SeqCst) as u64);
+        if !self
+            .upstream_failures
+            .record_failure(address, threshold, window)
+        {
+            return;
+        }
+        let upstream_addresses = self.upstream_addresses.read().await;
+        if let Some(idx) = upstream_addresses.iter().position(|addr| addr == address) {
+            log::warn!(
+                "Marking upstream {} down after {} failed requests in a row",
+                address,
+                threshold
+            );
+            if self.upstream_status.load().set_down(idx) {
+                self.report_health_change(address, false).await;
+            }
+        }
+    }
+
+    /// Reports an upstream going down or coming back up to the health hooks and statsd
+    pub(crate) async fn report_health_change(&self, address: &str, healthy: bool) {
+        match healthy {
+            true => log::info!("Upstream {} is back up", address),
+            false => log::warn!("Upstream {} is down", address),
+        }
+        if let Some(statsd) = &self.statsd {
+            statsd.health_change(address, healthy);
+        }
+        self.health_hooks
+            .read()
+            .await
+            .notify(&self.upstream_tls, address, healthy);
+    }
+
+    pub(crate) async fn record_upstream_success(&self, address: &str) {
+        if self.passive_health_check_failures.load(Ordering::SeqCst) > 0 {
+            self.upstream_failures.record_success(address);
+        }
+    }
+
+    /// Records the outcome of a request for the upstream's circuit breaker, and takes the upstream
+    /// out of rotation if its circuit opens
+    pub(crate) async fn record_circuit_outcome(&self, address: &str, success: bool) {
+        if !self.circuit_breakers.record(address, success) {
+            return;
+        }
+        let upstream_addresses = self.upstream_addresses.read().await;
+        if let Some(idx) = upstream_addresses.iter().position(|addr| addr == address) {
+            log::warn!(
+                "Opening circuit for upstream {} after too many failed requests",
+                address
+            );
+            self.upstream_status.load().set_circuit_open(idx, true);
+        }
+    }
+
+    /// Narrows the pool down to the canary or stable upstreams, picked at random in proportion to
+    /// the canaries' share of requests. Falls back to the whole pool if none of the picked
+    /// upstreams can take requests.
+    pub(crate) async fn split_canary_traffic(&self, pool: Vec<usize>) -> Vec<usize> {
+        let roll = rand::random::<f64>() * 100.0;
+        let group = self.routes.read().await.split_canary_traffic(&pool, roll);
+        let upstream_status = self.upstream_status.load();
+        if group.iter().any(|&idx| upstream_status.is_alive(idx)) {
+            group
+        } else {
+            pool
+        }
+    }
+
+    /// Counts a request sent to the upstream towards the canary or stable group's stats
+    pub(crate) async fn record_canary_outcome(&self, idx: usize, failed: bool) {
+        let canary = self.routes.read().await.is_canary(idx);
+        self.canary_stats.record(canary, failed);
+    }
+
+    /// Counts a new connection against its client IP's rate limit, if requests are limited by IP.
+    /// Returns false if the client is over the limit.
+    pub(crate) async fn register_connection(&self, client_ip: std::net::IpAddr) -> bool {
+        // Limiting by API key happens per request, once the request's headers are in. So does
+        // limiting the clients behind a trusted proxy, since only their requests say who they are.
+        if self.max_requests_per_minute.load(Ordering::SeqCst) == 0
+            || *self.rate_limit_by.read().await != RateLimitBy::Ip
+            || self.is_trusted_proxy(client_ip).await
+        {
+            return true;
+        }
+        let limiter = self.limiter.load_full();
+        let allowed = limiter.register_request(RateLimitKey::Ip(client_ip)).await;
+        if !allowed {
+            self.record_rate_limit_violation(client_ip).await;
+        }
+        allowed
+    }
+
+    /// Whether requests from this address are proxied for clients named in their X-Forwarded-For
+    pub(crate) async fn is_trusted_proxy(&self, ip: std::net::IpAddr) -> bool {
+        self.trusted_proxies
+            .read()
+            .await
+            .iter()
+            .any(|cidr| cidr.contains(ip))
+    }
+
+    /// Counts a client going over a rate limit against it, banning it if it keeps doing so
+    pub(crate) async fn record_rate_limit_violation(&self, client_ip: std::net::IpAddr) {
+        if self.ban_list.record_violation(client_ip) {
+            log::warn!(
+                "Banning {}, which keeps going over the rate limit",
+                client_ip
+            );
+        }
+    }
+
+    /// Waits for the open client connections to finish, for up to the shutdown timeout. Idle
+    /// connections are closed right away, and busy ones once their current request is answered.
+    pub(crate) async fn finish_connections(&self) {
+        self.shutting_down.store(true, Ordering::SeqCst);
+        self.shutdown.notify_waiters();
+        let timeout = self.shutdown_timeout.load(Ordering::SeqCst);
+        let deadline = Instant::now() + Duration::from_secs(timeout as u64);
+        loop {
+            let open = self.client_connections.total();
+            if open == 0 {
+                log::info!("All client connections are finished");
+                return;
+            }
+            if timeout > 0 && Instant::now() >= deadline {
+                log::warn!(
+                    "Giving up on {} client connections that are still open",
+                    open
+                );
+                return;
+            }
+            sleep(Duration::from_millis(100)).await;
+        }
+    }
+
+    /// Makes the response for an error we answer a request with ourselves, with the page set up
+    /// for its status if there is one
+    pub(crate) async fn error_response(
+        &self,
+        status: http::StatusCode,
+        request: Option<&http::Request<Vec<u8>>>,
+        upstream: Option<&str>,
+    ) -> http::Response<Vec<u8>> {
+        let request_id = error_pages::request_id(request);
+        let vars = error_pages::Vars {
+            request_id: &request_id,
+            upstream,
+        };
+        self.error_pages.read().await.render(status, &vars)
+    }
+
+    /// Writes an access log entry for a request, if access logging is on, pushes its metrics to
+    /// statsd, and counts its bytes against the client's bandwidth
+    pub(crate) fn log_access(
+        &self,
+        client_ip: std::net::IpAddr,
+        request: &http::Request<Vec<u8>>,
+        upstream: Option<&UpstreamConnection>,
+        status: http::StatusCode,
+        sizes: Sizes,
+        timing: &Timing,
+    ) {
+        let upstream = upstream.map(|upstream| upstream.address.as_str());
+        self.log_request(client_ip, request, upstream, status, sizes, timing);
+    }
+
+    /// The same as `log_access`, for requests forwarded without an `UpstreamConnection` (i.e.
+    /// over HTTP/2), where only the upstream's address is known
+    pub(crate) fn log_request(
+        &self,
+        client_ip: std::net::IpAddr,
+        request: &http::Request<Vec<u8>>,
+        upstream: Option<&str>,
+        status: http::StatusCode,
+        sizes: Sizes,
+        timing: &Timing,
+    ) {
+        self.bandwidth.record(client_ip, sizes);
+        if let Some(access_log) = &self.access_log {
+            access_log.log(client_ip, request, upstream, status, sizes, timing);
+        }
+        if let Some(statsd) = &self.statsd {
+            statsd.request(upstream, status, timing.elapsed());
+        }
+    }
+
+    /// How slow clients may be in sending requests and reading responses
+    pub(crate) fn slow_client_limits(&self) -> slow_client::Limits {
+        slow_client::Limits::new(
+            self.client_write_timeout.load(Ordering::SeqCst),
+            self.client_min_rate.load(Ordering::SeqCst),
+        )
+    }
+
+    /// Waits for the upstream's turn to take another request. Returns false if it's too busy for
+    /// the request to wait.
+    pub(crate) async fn wait_for_upstream_slot(&self, address: &str) -> bool {
+        let wait = self.upstream_limiter.reserve(address);
+        match wait {
+            Some(wait) => {
+                sleep(wait).await;
+                true
+            }
+            None => false,
+        }
+    }
+
+    pub(crate) async fn switch_config(&self, current: &mut Config, config: Config) {
+        self.dns_refresh_interval
+            .store(config.dns_refresh_interval, Ordering::SeqCst);
+        let backends = {
+            let mut resolved_hosts = self.resolved_hosts.write().await;
+            if config.dns_refresh_interval == 0 {
+                // Host names are looked up on each connection instead
+                resolved_hosts.clear();
+            }
+            dns::expand(&config.all_upstreams(), &resolved_hosts)
+        };
+        let backends_changed;
+        {
+            // The status is swapped while holding the addresses, so that whoever looks an upstream
+            // up by address gets the status that goes with it
+            let mut upstream_addresses = self.upstream_addresses.write().await;
+            let upstream_status = self.upstream_status.load();
+            upstream_status
+                .slow_start_window
+                .store(config.slow_start_window, Ordering::SeqCst);
+            backends_changed = *upstream_addresses != backends;
+            if backends_changed {
+                let new_status = upstream_status.for_new_upstreams(&upstream_addresses, &backends);
+                self.upstream_status.store(Arc::new(new_status));
+                *upstream_addresses = backends.clone();
+            }
+            // Routes refer to upstreams by index, so they have to change along with the addresses
+            *self.routes.write().await = Routes::new(&config, &backends);
+        }
+        self.update_drained(&current.drain, &config.drain).await;
+        // Only start the load balancer and rate limiter over if their settings changed, so that
+        // e.g. round-robin position and rate limit counts survive unrelated changes
+        if backends_changed
+            || current.load_balancer != config.load_balancer
+            || current.hash_header != config.hash_header
+            || current.sticky_cookie != config.sticky_cookie
+        {
+            *self.load_balancer.write().await = set_up_load_balancer(
+                config.load_balancer,
+                &backends,
+                config.hash_header.clone(),
+                config.sticky_cookie.clone(),
+            );
+            *self.sticky_cookie.write().await = config.sticky_cookie.clone();
+        }
+        if current.rate_limiter_settings() != config.rate_limiter_settings()
+            || current.max_requests_per_minute != config.max_requests_per_minute
+            || current.rate_limit_by != config.rate_limit_by
+        {
+            self.limiter.store(Arc::new(set_up_rate_limiter(
+                &config.rate_limiter_settings(),
+                config.max_requests_per_minute,
+                "global",
+            )));
+            *self.rate_limit_by.write().await = config.rate_limit_by;
+        }
+        if current.route_policies != config.route_policies
+            || current.rate_limiter_settings() != config.rate_limiter_settings()
+        {
+            self.route_limiters.clear();
+        }
+        self.ban_list.set_settings(config.ban_settings());
+        self.max_requests_per_minute
+            .store(config.max_requests_per_minute, Ordering::SeqCst);
+        self.max_concurrent_per_ip
+            .store(config.max_concurrent_per_ip, Ordering::SeqCst);
+        self.max_bytes_per_minute
+            .store(config.max_bytes_per_minute, Ordering::SeqCst);
+        self.active_health_check_interval
+            .store(config.active_health_check_interval, Ordering::SeqCst);
+        *self.active_health_check.write().await = HealthCheck::from_config(&config);
+        *self.health_hooks.write().await = HealthHooks::from_config(&config);
+        self.passive_health_check_failures
+            .store(config.passive_health_check_failures, Ordering::SeqCst);
+        self.passive_health_check_window
+            .store(config.passive_health_check_window, Ordering::SeqCst);
+        self.max_retries.store(config.max_retries, Ordering::SeqCst);
+        self.stats_log_interval
+            .store(config.stats_log_interval, Ordering::SeqCst);
+        *self.disabled_proxy_headers.write().await = config.disabled_proxy_headers.clone();
+        *self.access_list.write().await = config.access_list();
+        *self.maintenance_page.write().await =
+            middleware::load_maintenance_page(config.maintenance_page.as_deref());
+        *self.maintenance_allow_cidrs.write().await = config.maintenance_allow_cidrs.clone();
+        self.upstream_override
+            .store(config.upstream_override, Ordering::SeqCst);
+        *self.upstream_override_cidrs.write().await = config.upstream_override_cidrs.clone();
+        *self.trusted_proxies.write().await = config.trusted_proxies.clone();
+        *self.redirects.write().await = config.redirects();
+        *self.authenticator.write().await = config.authenticator();
+        // A key that can't be loaded mustn't leave tokens unchecked, so the old one stays in use
+        match JwtValidator::new(&config.jwt_settings()) {
+            Ok(jwt_validator) => *self.jwt_validator.write().await = jwt_validator,
+            Err(err) => {
+                log::error!(
+                    "Keeping the current JWT key, failed to load the new one: {}",
+                    err
+                )
+            }
+        }
+        *self.error_pages.write().await = ErrorPages::load(&config.error_pages);
+        *self.cors.write().await = config.cors_settings();
+        *self.header_rules.write().await = config.header_rules();
+        *self.compression.write().await = config.compression_settings();
+        *self.mirror_address.write().await = config.mirror.clone();
+        if current.cache_paths != config.cache_paths {
+            *self.cache_paths.write().await = config.cache_paths.clone();
+            self.response_cache.clear();
+        }
+        self.client_read_timeout
+            .store(config.client_read_timeout, Ordering::SeqCst);
+        self.client_idle_timeout
+            .store(config.client_idle_timeout, Ordering::SeqCst);
+        self.client_write_timeout
+            .store(config.client_write_timeout, Ordering::SeqCst);
+        self.client_min_rate
+            .store(config.client_min_rate, Ordering::SeqCst);
+        self.max_requests_per_connection
+            .store(config.max_requests_per_connection, Ordering::SeqCst);
+        self.upstream_connect_timeout
+            .store(config.upstream_connect_timeout, Ordering::SeqCst);
+        self.upstream_connect_backoff
+            .store(config.upstream_connect_backoff, Ordering::SeqCst);
+        self.shutdown_timeout
+            .store(config.shutdown_timeout, Ordering::SeqCst);
+        self.upstream_response_timeout
+            .store(config.upstream_response_timeout, Ordering::SeqCst);
+        self.upstream_limiter.set_limits(
+            config.max_upstream_rps,
+            Duration::from_secs(config.upstream_queue_timeout as u64),
+        );
+        self.max_upstream_concurrency
+            .store(config.max_upstream_concurrency, Ordering::SeqCst);
+        self.request_queue_size
+            .store(config.request_queue_size, Ordering::SeqCst);
+        self.request_queue_timeout
+            .store(config.request_queue_timeout, Ordering::SeqCst);
+        self.blue_green_drain_timeout
+            .store(config.blue_green_drain_timeout, Ordering::SeqCst);
+        if current.circuit_breaker_settings() != config.circuit_breaker_settings() {
+            self.circuit_breakers
+                .set_settings(config.circuit_breaker_settings());
+            if config.circuit_breaker_error_rate == 0 {
+                self.upstream_status.load().close_all_circuits();
+            }
+        }
+        *current = config;
+    }
+}
+
+/// Whether an operator has taken an upstream out of rotation through the admin API
+#[derive(Clone, Copy, Debug, PartialEq, serde::Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub(crate) enum AdminState {
+    /// Taking requests as usual, as long as it's healthy
+    Enabled,
+    /// Not given any new requests, but the ones already in flight to it carry on
+    Draining,
+    /// Forced down. Health checks won't bring it back until it is enabled again
+    Disabled,
+}
+
+impl AdminState {
+    fn from_u8(value: u8) -> AdminState {
+        match value {
+            0 => AdminState::Enabled,
+            1 => AdminState::Draining,
+            _ => AdminState::Disabled,
+        }
+    }
+}
+
+/// Status of each upstream, one by one match upstream_addresses. It's all kept in atomics, so
+/// that picking an upstream never waits on the health checks or the admin API, and a new one is
+/// swapped in whenever the list of upstreams changes.
+pub(crate) struct UpstreamsStatus {
+    /// Whether each upstream is healthy
+    status: Vec<AtomicBool>,
+    /// Admin state of each upstream
+    admin: Vec<AtomicU8>,
+    /// Whether each upstream's circuit breaker is open
+    circuit_open: Vec<AtomicBool>,
+    /// When each upstream last came back up after being down, as milliseconds after `epoch` plus
+    /// one, or 0 if it never has
+    recovered_at: Vec<AtomicU64>,
+    /// What recovered_at is counted from
+    epoch: Instant,
+    /// How many seconds a recovered upstream takes to ramp up to a full share of requests
+    slow_start_window: AtomicUsize,
+}
+
+impl UpstreamsStatus {
+    fn new(counts: usize, slow_start_window: usize) -> UpstreamsStatus {
+        UpstreamsStatus {
+            status: (0..counts).map(|_| AtomicBool::new(true)).collect(),
+            admin: (0..counts)
+                .map(|_| AtomicU8::new(AdminState::Enabled as u8))
+                .collect(),
+            circuit_open: (0..counts).map(|_| AtomicBool::new(false)).collect(),
+            recovered_at: (0..counts).map(|_| AtomicU64::new(0)).collect(),
+            epoch: Instant::now(),
+            slow_start_window: AtomicUsize::new(slow_start_window),
+        }
+    }
+
+    pub(crate) fn len(&self) -> usize {
+        self.status.len()
+    }
+
+    /// Returns true if the upstream is healthy, enabled and its circuit is closed, so it can be
+    /// given new connections
+    pub(crate) fn is_alive(&self, idx: usize) -> bool {
+        // The upstream list may have been swapped out by a config reload while a strategy was
+        // still holding an old index, so treat unknown upstreams as dead rather than panicking
+        self.is_healthy(idx)
+            && self.admin_state(idx) == Some(AdminState::Enabled)
+            && !self.is_circuit_open(idx)
+    }
+
+    pub(crate) fn is_healthy(&self, idx: usize) -> bool {
+        self.status
+            .get(idx)
+            .is_some_and(|status| status.load(Ordering::SeqCst))
+    }
+
+    pub(crate) fn admin_state(&self, idx: usize) -> Option<AdminState> {
+        let admin = self.admin.get(idx)?;
+        Some(AdminState::from_u8(admin.load(Ordering::SeqCst)))
+    }
+
+    pub(crate) fn is_circuit_open(&self, idx: usize) -> bool {
+        self.circuit_open
+            .get(idx)
+            .is_some_and(|open| open.load(Ordering::SeqCst))
+    }
+
+    pub(crate) fn all_dead(&self) -> bool {
+        !(0..self.len()).any(|idx| self.is_alive(idx))
+    }
+
+    fn recovered_at(&self, idx: usize) -> Option<Instant> {
+        match self.recovered_at.get(idx)?.load(Ordering::SeqCst) {
+            0 => None,
+            millis => Some(self.epoch + Duration::from_millis(millis - 1)),
+        }
+    }
+
+    /// Returns the share of requests a recovering upstream should get, from 0 when it has just
+    /// come back up to 1 once it's past the slow-start window
+    fn warm_up(&self, idx: usize) -> f64 {
+        let window = self.slow_start_window.load(Ordering::SeqCst);
+        match self.recovered_at(idx) {
+            Some(recovered_at) if window > 0 => {
+                let elapsed = recovered_at.elapsed().as_secs_f64();
+                (elapsed / window as f64).min(1.0)
+            }
+            _ => 1.0,
+        }
+    }
+
+    /// Returns true if the upstream recovered recently and isn't yet getting its full share of
+    /// requests
+    pub(crate) fn is_warming_up(&self, idx: usize) -> bool {
+        self.warm_up(idx) < 1.0
+    }
+
+    /// Decides whether to let a request through to the upstream while it is warming up, letting
+    /// through only its share of them. `has_warm_alternative` tells whether the request could go
+    /// to another upstream that isn't warming up; when it can't, the upstream always gets it.
+    pub(crate) fn admit_while_warming_up(
+        &self,
+        idx: usize,
+        has_warm_alternative: impl FnOnce() -> bool,
+    ) -> bool {
+        let share = self.warm_up(idx);
+        share >= 1.0 || !has_warm_alternative() || rand::random::<f64>() < share
+    }
+
+    /// Marks the upstream healthy. Returns true if it was down until now.
+    pub(crate) fn set_up(&self, idx: usize) -> bool {
+        if idx < self.len() && !self.is_healthy(idx) {
+            // Start the warm-up before the upstream shows as healthy, so it's never given a full
+            // share of requests right as it comes back
+            let millis = self.epoch.elapsed().as_millis() as u64 + 1;
+            self.recovered_at[idx].store(millis, Ordering::SeqCst);
+            self.status[idx].store(true, Ordering::SeqCst);
+            true
+        } else {
+            false
+        }
+    }
+
+    /// Marks the upstream down. Returns true if it was healthy until now.
+    pub(crate) fn set_down(&self, idx: usize) -> bool {
+        self.status
+            .get(idx)
+            .is_some_and(|status| status.swap(false, Ordering::SeqCst))
+    }
+
+    pub(crate) fn set_admin_state(&self, idx: usize, admin_state: AdminState) {
+        if let Some(admin) = self.admin.get(idx) {
+            admin.store(admin_state as u8, Ordering::SeqCst);
+        }
+    }
+
+    pub(crate) fn set_circuit_open(&self, idx: usize, open: bool) {
+        if let Some(circuit_open) = self.circuit_open.get(idx) {
+            circuit_open.store(open, Ordering::SeqCst);
+        }
+    }
+
+    fn close_all_circuits(&self) {
+        self.circuit_open
+            .iter()
+            .for_each(|open| open.store(false, Ordering::SeqCst));
+    }
+
+    /// Builds the status for a new list of upstreams. Upstreams that were already in the old list
+    /// keep their current status, and new ones start out alive.
+    fn for_new_upstreams(
+        &self,
+        old_addresses: &[String],
+        new_addresses: &[String],
+    ) -> UpstreamsStatus {
+        let old_indexes: Vec<Option<usize>> = new_addresses
+            .iter()
+            .map(|addr| old_addresses.iter().position(|old| old == addr))
+            .collect();
+        UpstreamsStatus {
+            status: old_indexes
+                .iter()
+                .map(|old_idx| AtomicBool::new(old_idx.is_none_or(|idx| self.is_healthy(idx))))
+                .collect(),
+            admin: old_indexes
+                .iter()
+                .map(|old_idx| {
+                    let admin_state = old_idx
+                        .and_then(|idx| self.admin_state(idx))
+                        .unwrap_or(AdminState::Enabled);
+                    AtomicU8::new(admin_state as u8)
+                })
+                .collect(),
+            circuit_open: old_indexes
+                .iter()
+                .map(|old_idx| {
+                    AtomicBool::new(old_idx.is_some_and(|idx| self.is_circuit_open(idx)))
+                })
+                .collect(),
+            recovered_at: old_indexes
+                .iter()
+                .map(|old_idx| {
+                    let recovered_at = old_idx.and_then(|idx| self.recovered_at.get(idx));
+                    AtomicU64::new(recovered_at.map_or(0, |millis| millis.load(Ordering::SeqCst)))
+                })
+                .collect(),
+            epoch: self.epoch,
+            slow_start_window: AtomicUsize::new(self.slow_start_window.load(Ordering::SeqCst)),
+        }
+    }
+}
+
+/// Sets up a rate limiter with the given quota. `scope` tells apart limiters whose counts are
+/// kept outside the process, so limiters with different quotas don't share them.
+#[cfg_attr(not(feature = "redis"), allow(unused_variables))]
+pub(crate) fn set_up_rate_limiter(
+    settings: &rate_limiter::Settings,
+    max_requests_per_minute: usize,
+    scope: &str,
+) -> Box<dyn RateLimiterStrategy<RateLimitKey>> {
+    match settings.strategy {
+        ArgRateLimiter::Counter => Box::new(Counter::new(max_requests_per_minute)),
+        ArgRateLimiter::TokenBucket => {
+            let burst = if settings.burst == 0 {
+                max_requests_per_minute
+            } else {
+                settings.burst
+            };
+            Box::new(TokenBucket::new(max_requests_per_minute, burst))
+        }
+        #[cfg(feature = "redis")]
+        ArgRateLimiter::Redis => {
+            let url = settings.redis_url.as_deref().unwrap_or_default();
+            match RedisRateLimiter::new(url, max_requests_per_minute, scope) {
+                Ok(limiter) => Box::new(limiter),
+                Err(err) => {
+                    // The config was checked, so this shouldn't happen
+                    log::error!(
+                        "Invalid Redis URL {:?}, counting requests locally: {}",
+                        url,
+                        err
+                    );
+                    Box::new(Counter::new(max_requests_per_minute))
+                }
+            }
+        }
+    }
+}
+
+fn set_up_load_balancer(
+    load_balancer: ArgLoadBalance,
+    upstream_addresses: &[String],
+    hash_header: Option<String>,
+    sticky_cookie: Option<String>,
+) -> Box<dyn LoadBalanceStrategy> {
+    let strategy: Box<dyn LoadBalanceStrategy> = match load_balancer {
+        ArgLoadBalance::Random => Box::new(Random::new()),
+        ArgLoadBalance::RoundRobin => Box::new(RoundRobin::new()),
+        ArgLoadBalance::ConsistentHash => {
+            Box::new(ConsistentHash::new(upstream_addresses, hash_header))
+        }
+        ArgLoadBalance::Ewma => Box::new(Ewma::new()),
+        ArgLoadBalance::P2c => Box::new(P2c::new()),
+    };
+    match sticky_cookie {
+        Some(cookie) => Box::new(Sticky::new(cookie, upstream_addresses, strategy)),
+        None => strategy,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::load_balance::RequestContext;
+    use crate::tls;
+    use std::net::{IpAddr, Ipv4Addr};
+
+    const UPSTREAMS: [&str; 3] = ["127.0.0.1:9001", "127.0.0.1:9002", "127.0.0.1:9003"];
+
+    fn client(last: u8) -> IpAddr {
+        IpAddr::V4(Ipv4Addr::new(10, 0, 0, last))
+    }
+
+    fn config() -> Config {
+        Config::new(
+            UPSTREAMS
+                .iter()
+                .map(|upstream| upstream.to_string())
+                .collect(),
+        )
+    }
+
+    fn proxy_state(config: Config) -> Arc<ProxyState> {
+        let upstream_tls = tls::make_connector(None, None).unwrap();
+        let state = ProxyState::new(
+            &config,
+            config.clone(),
+            None,
+            upstream_tls,
+            ConnectionLimits::new(0, 0),
+            None,
+            Vec::new(),
+        );
+        Arc::new(state)
+    }
+
+    /// Asks the load balancer for an upstream from the pool `times` times over, returning the
+    /// upstreams it picked
+    async fn pick(state: &Arc<ProxyState>, pool: &[usize], times: usize) -> Vec<Option<usize>> {
+        let request = http::Request::builder().uri("/").body(Vec::new()).unwrap();
+        let context = RequestContext {
+            client_ip: client(1),
+            request: &request,
+            pool,
+            excluded: &[],
+        };
+        let mut picked = Vec::new();
+        for _ in 0..times {
+            picked.push(
+                state
+                    .load_balancer
+                    .read()
+                    .await
+                    .select_backend(state, &context)
+                    .await,
+            );
+        }
+        picked
+    }
+
+    #[tokio::test]
+    async fn test_selection_skips_unavailable_upstreams() {
+        let state = proxy_state(config());
+        let everyone = [0, 1, 2];
+        let mut picked = pick(&state, &everyone, 6).await;
+        picked.sort();
+        assert_eq!(
+            picked,
+            [Some(0), Some(0), Some(1), Some(1), Some(2), Some(2)]
+        );
+
+        // Only the request's pool is picked from
+        assert!(pick(&state, &[2], 3)
+            .await
+            .iter()
+            .all(|&idx| idx == Some(2)));
+
+        // Neither upstreams that are down nor ones an operator disabled are picked
+        state.upstream_status.load().set_down(0);
+        assert!(
+            state
+                .set_admin_state(UPSTREAMS[1], AdminState::Disabled)
+                .await
+        );
+        assert!(pick(&state, &everyone, 3)
+            .await
+            .iter()
+            .all(|&idx| idx == Some(2)));
+
+        state.upstream_status.load().set_down(2);
+        assert!(pick(&state, &everyone, 1).await.iter().all(Option::is_none));
+    }
+
+    #[tokio::test]
+    async fn test_warming_upstream_serves_a_pool_of_its_own() {
+        let mut config = config();
+        config.slow_start_window = 3600;
+        let state = proxy_state(config);
+        let upstream_status = state.upstream_status.load();
+        upstream_status.set_down(2);
+        assert!(upstream_status.set_up(2));
+        assert!(upstream_status.is_warming_up(2));
+
+        // While others can take the requests, the recovering upstream is held back...
+        assert!(!pick(&state, &[0, 1, 2], 20).await.contains(&Some(2)));
+
+        // ... but a pool with nothing else in it still gets to use it
+        assert!(pick(&state, &[2], 20)
+            .await
+            .iter()
+            .all(|&idx| idx == Some(2)));
+
+        // ... as does one whose other upstreams are warming up too
+        upstream_status.set_down(1);
+        assert!(upstream_status.set_up(1));
+        assert!(pick(&state, &[1, 2], 20).await.iter().all(Option::is_some));
+    }
+
+    #[tokio::test]
+    async fn test_failures_in_a_row_mark_upstream_down() {
+        let mut config = config();
+        config.passive_health_check_failures = 2;
+        let state = proxy_state(config);
+
+        state.record_upstream_failure(UPSTREAMS[0]).await;
+        state.record_upstream_success(UPSTREAMS[0]).await;
+        state.record_upstream_failure(UPSTREAMS[0]).await;
+        assert!(
+            state.upstream_status.load().is_alive(0),
+            "Failures weren't in a row"
+        );
+
+        state.record_upstream_failure(UPSTREAMS[0]).await;
+        assert!(!state.upstream_status.load().is_alive(0));
+        assert!(state.upstream_status.load().is_alive(1));
+    }
+
+    #[tokio::test]
+    async fn test_rate_limit_and_ban() {
+        let mut config = config();
+        config.max_requests_per_minute = 2;
+        config.ban_threshold = 1;
+        let state = proxy_state(config);
+
+        let limiter = state.limiter.load();
+        assert!(limiter.register_request(RateLimitKey::Ip(client(1))).await);
+        assert!(limiter.register_request(RateLimitKey::Ip(client(1))).await);
+        assert!(!limiter.register_request(RateLimitKey::Ip(client(1))).await);
+        // Each client has a limit of its own
+        assert!(limiter.register_request(RateLimitKey::Ip(client(2))).await);
+
+        // Going over the limit more often than the threshold allows gets a client banned
+        state.record_rate_limit_violation(client(1)).await;
+        assert!(!state.ban_list.is_banned(client(1)));
+        state.record_rate_limit_violation(client(1)).await;
+        assert!(state.ban_list.is_banned(client(1)));
+        assert!(!state.ban_list.is_banned(client(2)));
+    }
+
+    #[tokio::test]
+    async fn test_apply_config() {
+        let mut config = config();
+        config.max_requests_per_minute = 1;
+        let state = proxy_state(config.clone());
+        let limiter = || state.limiter.load();
+        assert!(
+            limiter()
+                .register_request(RateLimitKey::Ip(client(1)))
+                .await
+        );
+
+        // Rate limit counts survive changes that have nothing to do with them
+        config.max_retries = 2;
+        state.apply_config(config.clone()).await;
+        assert!(
+            !limiter()
+                .register_request(RateLimitKey::Ip(client(1)))
+                .await
+        );
+        assert_eq!(state.max_retries.load(Ordering::SeqCst), 2);
+
+        // ... but start over when the limit changes
+        config.max_requests_per_minute = 5;
+        state.apply_config(config.clone()).await;
+        assert!(
+            limiter()
+                .register_request(RateLimitKey::Ip(client(1)))
+                .await
+        );
+
+        // New upstreams are picked from once the config naming them is applied
+        config.upstreams = vec!["127.0.0.1:9004".to_string()];
+        state.apply_config(config.clone()).await;
+        assert_eq!(*state.upstream_addresses.read().await, ["127.0.0.1:9004"]);
+        assert_eq!(pick(&state, &[0], 2).await, [Some(0), Some(0)]);
+
+        assert!(!state.add_upstream("127.0.0.1:9004".to_string()).await);
+        assert!(state.add_upstream("127.0.0.1:9005".to_string()).await);
+        assert_eq!(state.upstream_addresses.read().await.len(), 2);
+    }
+}
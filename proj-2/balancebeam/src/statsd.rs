@@ -0,0 +1,114 @@
+use std::io;
+use std::net::{SocketAddr, ToSocketAddrs, UdpSocket};
+use std::time::Duration;
+
+/// Where metrics are pushed to, for setups that don't scrape them
+#[derive(Clone, Debug)]
+pub struct Settings {
+    /// host:port of the statsd server (or Datadog agent) to send metrics to over UDP
+    pub address: String,
+    /// Put in front of the name of every metric
+    pub prefix: String,
+    /// Tag metrics the way DogStatsD does (e.g. `|#status:200`), instead of putting upstreams and
+    /// status classes in the metric names
+    pub tags: bool,
+}
+
+/// Sends metrics to statsd as requests are answered and upstreams go up and down. Packets are
+/// sent without waiting on anything, and ones that can't be sent are dropped, so a missing statsd
+/// server never slows requests down.
+pub struct Statsd {
+    socket: UdpSocket,
+    prefix: String,
+    tags: bool,
+}
+
+impl Statsd {
+    pub fn connect(settings: &Settings) -> io::Result<Statsd> {
+        let address = settings.address.to_socket_addrs()?.next().ok_or_else(|| {
+            io::Error::other(format!("{} has no addresses", settings.address))
+        })?;
+        let local: SocketAddr = match address {
+            SocketAddr::V4(_) => "0.0.0.0:0".parse().unwrap(),
+            SocketAddr::V6(_) => "[::]:0".parse().unwrap(),
+        };
+        let socket = UdpSocket::bind(local)?;
+        socket.connect(address)?;
+        socket.set_nonblocking(true)?;
+        Ok(Statsd { socket, prefix: settings.prefix.clone(), tags: settings.tags })
+    }
+
+    /// Counts a request answered with the given status, and how long it took. `upstream` is the
+    /// upstream it was sent to, if it got that far.
+    pub fn request(&self, upstream: Option<&str>, status: http::StatusCode, latency: Duration) {
+        let class = format!("{}xx", status.as_u16() / 100);
+        let ms = latency.as_secs_f64() * 1000.0;
+        let prefix = &self.prefix;
+        let packet = if self.tags {
+            let tags = format!(
+                "status:{},status_class:{},upstream:{}",
+                status.as_u16(),
+                class,
+                tag_value(upstream.unwrap_or("none"))
+            );
+            format!(
+                "{}.requests:1|c|#{}\n{}.request_time:{:.3}|ms|#{}",
+                prefix, tags, prefix, ms, tags
+            )
+        } else {
+            let mut packet = format!(
+                "{}.requests.{}:1|c\n{}.request_time:{:.3}|ms",
+                prefix, class, prefix, ms
+            );
+            if let Some(upstream) = upstream {
+                let upstream = name_part(upstream);
+                packet += &format!(
+                    "\n{}.upstream.{}.requests.{}:1|c\n{}.upstream.{}.request_time:{:.3}|ms",
+                    prefix, upstream, class, prefix, upstream, ms
+                );
+            }
+            packet
+        };
+        self.send(&packet);
+    }
+
+    /// Counts an upstream going down or coming back up, and sets its health gauge
+    pub fn health_change(&self, upstream: &str, healthy: bool) {
+        let (event, gauge) = if healthy { ("up", 1) } else { ("down", 0) };
+        let prefix = &self.prefix;
+        let packet = if self.tags {
+            let tags = format!("upstream:{}", tag_value(upstream));
+            format!(
+                "{}.upstream.{}:1|c|#{}\n{}.upstream.healthy:{}|g|#{}",
+                prefix, event, tags, prefix, gauge, tags
+            )
+        } else {
+            let upstream = name_part(upstream);
+            format!(
+                "{}.upstream.{}.{}:1|c\n{}.upstream.{}.healthy:{}|g",
+                prefix, upstream, event, prefix, upstream, gauge
+            )
+        };
+        self.send(&packet);
+    }
+
+    fn send(&self, packet: &str) {
+        if let Err(err) = self.socket.send(packet.as_bytes()) {
+            log::debug!("Could not send metrics to statsd: {}", err);
+        }
+    }
+}
+
+/// Makes an upstream's address fit in a metric name, which statsd splits on dots and colons
+fn name_part(address: &str) -> String {
+    address
+        .chars()
+        .map(|c| if c.is_ascii_alphanumeric() || c == '-' { c } else { '_' })
+        .collect()
+}
+
+/// Makes an upstream's address safe to use as a tag value, which DogStatsD ends at a comma or
+/// pipe
+fn tag_value(address: &str) -> String {
+    address.replace([',', '|', '#'], "_")
+}
@@ -0,0 +1,93 @@
+use crate::state::ProxyState;
+use std::sync::Arc;
+use tokio::signal::unix::{signal, SignalKind};
+use tokio::time::{sleep, Duration};
+
+pub(crate) async fn limiter_refresh(state: Arc<ProxyState>, interval: u64) {
+    loop {
+        sleep(Duration::from_secs(interval)).await;
+        let limiter = state.limiter.load_full();
+        limiter.refresh().await;
+        state.bandwidth.refresh();
+        state.ban_list.prune();
+        let route_limiters: Vec<_> = state
+            .route_limiters
+            .iter()
+            .map(|limiter| limiter.value().clone())
+            .collect();
+        for limiter in route_limiters {
+            limiter.refresh().await;
+        }
+    }
+}
+
+/// Lets trial requests through to upstreams whose circuits have finished cooling down
+pub(crate) async fn half_open_circuits(state: Arc<ProxyState>, interval: u64) {
+    loop {
+        sleep(Duration::from_secs(interval)).await;
+        let expired = state.circuit_breakers.half_open_expired();
+        if expired.is_empty() {
+            continue;
+        }
+        let upstream_addresses = state.upstream_addresses.read().await;
+        let upstream_status = state.upstream_status.load();
+        for address in expired {
+            if let Some(idx) = upstream_addresses.iter().position(|addr| *addr == address) {
+                log::info!("Half-opening circuit for upstream {}", address);
+                upstream_status.set_circuit_open(idx, false);
+            }
+        }
+    }
+}
+
+/// Logs each upstream being drained once it has no more requests in flight, so whoever is taking
+/// it down knows it's safe to
+pub(crate) async fn report_drained(state: Arc<ProxyState>) {
+    let mut reported: Vec<String> = Vec::new();
+    loop {
+        sleep(Duration::from_millis(500)).await;
+        let drained = state.drained_upstreams().await;
+        for address in &drained {
+            if !reported.contains(address) {
+                log::info!("Upstream {} is drained, and can be taken down", address);
+            }
+        }
+        reported = drained;
+    }
+}
+
+/// Reloads the config file each time we receive SIGHUP
+pub(crate) async fn reload_on_sighup(state: Arc<ProxyState>) {
+    let mut hangups = match signal(SignalKind::hangup()) {
+        Ok(hangups) => hangups,
+        Err(err) => {
+            log::error!("Could not listen for SIGHUP: {}", err);
+            return;
+        }
+    };
+    while hangups.recv().await.is_some() {
+        let path = match &state.config_path {
+            Some(path) => path,
+            None => {
+                log::warn!("Received SIGHUP, but no config file was given with --config");
+                continue;
+            }
+        };
+        // Instances found through service discovery aren't in the file, so keep them
+        let mut base_config = state.base_config.clone();
+        base_config.discovered_upstreams = state.config.read().await.discovered_upstreams.clone();
+        match base_config.with_file(path) {
+            Ok(config) => {
+                state.apply_config(config).await;
+                log::info!("Reloaded configuration from {}", path);
+            }
+            Err(err) => {
+                log::error!(
+                    "Keeping the current configuration, failed to reload {}: {}",
+                    path,
+                    err
+                );
+            }
+        }
+    }
+}
@@ -0,0 +1,57 @@
+use std::net::SocketAddr;
+use std::sync::Arc;
+use tokio::io::{AsyncRead, AsyncWrite};
+use crate::load_balance::RequestContext;
+use crate::connection::connect_to_upstream;
+use crate::state::ProxyState;
+
+/// What balancebeam proxies
+#[derive(clap::ArgEnum, Debug, Clone, Copy, PartialEq)]
+pub enum ProxyMode {
+    /// HTTP requests, which are parsed so they can be routed, rewritten, rate limited, etc.
+    Http,
+    /// Raw TCP connections (e.g. to Redis or Postgres), which are passed on byte for byte. Each
+    /// connection goes to one of the default upstreams, picked by the load balancer.
+    Tcp,
+}
+
+/// Connects the client to an upstream and copies bytes between them in both directions until
+/// either side closes its connection
+pub async fn serve<S: AsyncRead + AsyncWrite + Unpin>(
+    mut client_conn: S,
+    client_addr: SocketAddr,
+    state: Arc<ProxyState>,
+) {
+    let live = state.deployment.live();
+    let pool = state.routes.read().await.default_pool(live).to_vec();
+    let pool = state.split_canary_traffic(pool).await;
+    // There's no request to go by, so load balancers that look at one (e.g. to hash a header) get
+    // an empty one and fall back on the client's IP address
+    let request = http::Request::new(Vec::new());
+    let context = RequestContext {
+        client_ip: client_addr.ip(),
+        request: &request,
+        pool: &pool,
+        excluded: &[],
+    };
+    let mut upstream = match connect_to_upstream(&state, &context).await {
+        Ok(upstream) => upstream,
+        Err(error) => {
+            log::error!("Failed to connect to an upstream for {}: {}", client_addr, error);
+            return;
+        }
+    };
+    log::info!("{} -> {}: opened connection", client_addr, upstream.ip);
+    // The whole connection is one request, as far as draining the upstream goes
+    let _in_flight = state.upstream_requests.track(&upstream.address);
+    match tokio::io::copy_bidirectional(&mut client_conn, &mut upstream.stream).await {
+        Ok((sent, received)) => log::info!(
+            "{} <- {}: closed connection after sending {} bytes and receiving {}",
+            client_addr,
+            upstream.ip,
+            sent,
+            received
+        ),
+        Err(err) => log::info!("{} <- {}: connection failed: {}", client_addr, upstream.ip, err),
+    }
+}
@@ -0,0 +1,42 @@
+use opentelemetry::trace::TracerProvider as _;
+use opentelemetry_otlp::WithExportConfig;
+use opentelemetry_sdk::trace::SdkTracerProvider;
+use opentelemetry_sdk::Resource;
+use tracing_subscriber::layer::SubscriberExt;
+
+/// Sends the spans balancebeam records for each request (connecting to the upstream, forwarding
+/// the request, sending the response back) to an OTLP collector, such as Jaeger or Tempo. Spans
+/// are exported in batches in the background; call `shutdown` before exiting so that the last
+/// ones aren't lost.
+pub struct Telemetry {
+    provider: SdkTracerProvider,
+}
+
+impl Telemetry {
+    /// Starts exporting spans over OTLP/HTTP to the given endpoint (e.g.
+    /// "http://localhost:4318/v1/traces"), reported as coming from the given service
+    pub fn install(endpoint: &str, service_name: &str) -> Result<Telemetry, String> {
+        let exporter = opentelemetry_otlp::SpanExporter::builder()
+            .with_http()
+            .with_endpoint(endpoint)
+            .build()
+            .map_err(|err| err.to_string())?;
+        let resource = Resource::builder().with_service_name(service_name.to_string()).build();
+        let provider = SdkTracerProvider::builder()
+            .with_batch_exporter(exporter)
+            .with_resource(resource)
+            .build();
+        let tracer = provider.tracer("balancebeam");
+        let subscriber = tracing_subscriber::registry()
+            .with(tracing_opentelemetry::layer().with_tracer(tracer));
+        tracing::subscriber::set_global_default(subscriber).map_err(|err| err.to_string())?;
+        Ok(Telemetry { provider })
+    }
+
+    /// Exports the spans that haven't been sent yet, and stops exporting
+    pub fn shutdown(self) {
+        if let Err(err) = self.provider.shutdown() {
+            log::warn!("Failed to export the last traces: {}", err);
+        }
+    }
+}
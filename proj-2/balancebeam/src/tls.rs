@@ -0,0 +1,263 @@
+use std::{fmt, fs, io, sync::Arc};
+use simple_asn1::{ASN1Block, OID};
+use tokio::io::{AsyncRead, AsyncReadExt};
+use tokio_rustls::rustls::{self, pki_types::{CertificateDer, PrivateKeyDer}};
+use tokio_rustls::rustls::server::{VerifierBuilderError, WebPkiClientVerifier};
+use tokio_rustls::{TlsAcceptor, TlsConnector};
+use crate::with_timeout;
+
+/// Type of the TLS record a client opens a connection with, which carries its ClientHello. No
+/// HTTP request (or HTTP/2 preface) starts with this byte.
+const HANDSHAKE_RECORD: u8 = 0x16;
+
+#[derive(Debug)]
+pub enum Error {
+    /// A certificate or key file couldn't be read
+    Unreadable(String, io::Error),
+    /// The certificate file doesn't contain any PEM-encoded certificates
+    NoCertificates(String),
+    /// The key file doesn't contain a PEM-encoded private key
+    NoPrivateKey(String),
+    /// rustls rejected the certificate/key pair (e.g. the key doesn't match the certificate)
+    InvalidConfig(rustls::Error),
+    /// Client certificates can't be verified against the given CA certificates
+    InvalidClientCa(String, VerifierBuilderError),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Unreadable(path, err) => write!(f, "could not read {}: {}", path, err),
+            Error::NoCertificates(path) => write!(f, "no certificates found in {}", path),
+            Error::NoPrivateKey(path) => write!(f, "no private key found in {}", path),
+            Error::InvalidConfig(err) => write!(f, "invalid certificate or key: {}", err),
+            Error::InvalidClientCa(path, err) => {
+                write!(f, "invalid client CA certificates in {}: {}", path, err)
+            }
+        }
+    }
+}
+
+fn crypto_provider() -> Arc<rustls::crypto::CryptoProvider> {
+    Arc::new(rustls::crypto::ring::default_provider())
+}
+
+fn load_certs(path: &str) -> Result<Vec<CertificateDer<'static>>, Error> {
+    let contents = fs::read(path).map_err(|err| Error::Unreadable(path.to_string(), err))?;
+    let certs = rustls_pemfile::certs(&mut contents.as_slice())
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|err| Error::Unreadable(path.to_string(), err))?;
+    if certs.is_empty() {
+        return Err(Error::NoCertificates(path.to_string()));
+    }
+    Ok(certs)
+}
+
+fn load_private_key(path: &str) -> Result<PrivateKeyDer<'static>, Error> {
+    let contents = fs::read(path).map_err(|err| Error::Unreadable(path.to_string(), err))?;
+    rustls_pemfile::private_key(&mut contents.as_slice())
+        .map_err(|err| Error::Unreadable(path.to_string(), err))?
+        .ok_or_else(|| Error::NoPrivateKey(path.to_string()))
+}
+
+/// Builds an acceptor that terminates TLS on incoming connections, using the PEM-encoded
+/// certificate chain and private key in the given files. If `client_ca_path` is given, clients
+/// must present a certificate signed by one of the CA certificates in it, and the handshake fails
+/// for those that don't. If `http2` is true, clients are offered HTTP/2 through ALPN.
+pub fn make_acceptor(
+    cert_path: &str,
+    key_path: &str,
+    client_ca_path: Option<&str>,
+    http2: bool,
+) -> Result<TlsAcceptor, Error> {
+    let certs = load_certs(cert_path)?;
+    let key = load_private_key(key_path)?;
+    let builder = rustls::ServerConfig::builder_with_provider(crypto_provider())
+        .with_safe_default_protocol_versions()
+        .map_err(Error::InvalidConfig)?;
+    let builder = match client_ca_path {
+        Some(path) => {
+            let mut roots = rustls::RootCertStore::empty();
+            for cert in load_certs(path)? {
+                roots.add(cert).map_err(Error::InvalidConfig)?;
+            }
+            let verifier =
+                WebPkiClientVerifier::builder_with_provider(Arc::new(roots), crypto_provider())
+                    .build()
+                    .map_err(|err| Error::InvalidClientCa(path.to_string(), err))?;
+            builder.with_client_cert_verifier(verifier)
+        }
+        None => builder.with_no_client_auth(),
+    };
+    let mut config = builder.with_single_cert(certs, key).map_err(Error::InvalidConfig)?;
+    if http2 {
+        config.alpn_protocols = vec![b"h2".to_vec(), b"http/1.1".to_vec()];
+    }
+    Ok(TlsAcceptor::from(Arc::new(config)))
+}
+
+/// Reads the first byte of a connection to see whether the client is starting a TLS handshake or
+/// talking plaintext. Returns whether it's TLS, along with the byte read (none if the client hung
+/// up), which has to be put back in front of the connection. Fails if reading takes longer than
+/// `timeout` seconds.
+pub async fn sniff<S: AsyncRead + Unpin>(
+    client_conn: &mut S,
+    timeout: usize,
+) -> io::Result<(bool, Vec<u8>)> {
+    let read = async {
+        let mut start = vec![0; 1];
+        let len = client_conn.read(&mut start).await?;
+        start.truncate(len);
+        Ok::<_, io::Error>((start.first() == Some(&HANDSHAKE_RECORD), start))
+    };
+    with_timeout(timeout, read)
+        .await
+        .unwrap_or_else(|| Err(io::Error::new(io::ErrorKind::TimedOut, "timed out")))
+}
+
+/// Returns true if the client and we agreed through ALPN to speak HTTP/2
+pub fn negotiated_http2(connection: &rustls::ServerConnection) -> bool {
+    connection.alpn_protocol() == Some(b"h2")
+}
+
+/// Returns the subject of the certificate a client presented, if it presented one, as a
+/// distinguished name written the way RFC 4514 does (e.g. "CN=alice,O=Example Corp")
+pub fn client_subject(connection: &rustls::ServerConnection) -> Option<String> {
+    certificate_subject(connection.peer_certificates()?.first()?)
+}
+
+/// Reads the subject out of a DER-encoded X.509 certificate
+fn certificate_subject(der: &[u8]) -> Option<String> {
+    let blocks = simple_asn1::from_der(der).ok()?;
+    let tbs_certificate = match blocks.first()? {
+        ASN1Block::Sequence(_, certificate) => match certificate.first()? {
+            ASN1Block::Sequence(_, tbs_certificate) => tbs_certificate,
+            _ => return None,
+        },
+        _ => return None,
+    };
+    // The version comes first, except in version 1 certificates, which leave it out. Then come
+    // the serial number, signature algorithm, issuer and validity period.
+    let version = matches!(tbs_certificate.first()?, ASN1Block::Explicit(..)) as usize;
+    let rdns = match tbs_certificate.get(version + 4)? {
+        ASN1Block::Sequence(_, rdns) => rdns,
+        _ => return None,
+    };
+    // The certificate lists the least specific part (e.g. the country) first, and RFC 4514 the
+    // most specific part
+    let mut parts = Vec::new();
+    for rdn in rdns.iter().rev() {
+        let attributes = match rdn {
+            ASN1Block::Set(_, attributes) => attributes,
+            _ => return None,
+        };
+        let mut part = Vec::new();
+        for attribute in attributes {
+            match attribute {
+                ASN1Block::Sequence(_, pair) => match pair.as_slice() {
+                    [ASN1Block::ObjectIdentifier(_, oid), value] => part.push(format!(
+                        "{}={}",
+                        attribute_name(oid)?,
+                        escape_attribute_value(string_value(value)?)
+                    )),
+                    _ => return None,
+                },
+                _ => return None,
+            }
+        }
+        parts.push(part.join("+"));
+    }
+    Some(parts.join(","))
+}
+
+/// Returns the short name RFC 4514 gives an attribute, or its OID in dotted form
+fn attribute_name(oid: &OID) -> Option<String> {
+    let arcs: Vec<u64> = oid.as_vec().ok()?;
+    let dotted = arcs.iter().map(u64::to_string).collect::<Vec<_>>().join(".");
+    let name = match dotted.as_str() {
+        "2.5.4.3" => "CN",
+        "2.5.4.6" => "C",
+        "2.5.4.7" => "L",
+        "2.5.4.8" => "ST",
+        "2.5.4.9" => "STREET",
+        "2.5.4.10" => "O",
+        "2.5.4.11" => "OU",
+        "0.9.2342.19200300.100.1.1" => "UID",
+        "0.9.2342.19200300.100.1.25" => "DC",
+        _ => return Some(dotted),
+    };
+    Some(name.to_string())
+}
+
+fn string_value(block: &ASN1Block) -> Option<&str> {
+    match block {
+        ASN1Block::UTF8String(_, value)
+        | ASN1Block::PrintableString(_, value)
+        | ASN1Block::TeletexString(_, value)
+        | ASN1Block::IA5String(_, value)
+        | ASN1Block::UniversalString(_, value)
+        | ASN1Block::BMPString(_, value) => Some(value),
+        _ => None,
+    }
+}
+
+/// Escapes the characters that have a meaning of their own in a distinguished name
+fn escape_attribute_value(value: &str) -> String {
+    let mut escaped = String::with_capacity(value.len());
+    for (i, c) in value.chars().enumerate() {
+        let leading = i == 0 && (c == ' ' || c == '#');
+        let trailing = i == value.chars().count() - 1 && c == ' ';
+        if leading || trailing || matches!(c, ',' | '+' | '"' | '\\' | '<' | '>' | ';' | '=') {
+            escaped.push('\\');
+        }
+        escaped.push(c);
+    }
+    escaped
+}
+
+/// Builds a connector for talking to HTTPS upstreams. Upstream certificates are checked against the
+/// usual web PKI roots, plus any PEM-encoded CA certificates in `ca_cert_path` (e.g. for backends
+/// using an internal CA). If `client_cert` is given, as the paths of a PEM-encoded certificate
+/// chain and private key, the certificate is presented to upstreams that ask for one.
+pub fn make_connector(
+    ca_cert_path: Option<&str>,
+    client_cert: Option<(&str, &str)>,
+) -> Result<TlsConnector, Error> {
+    Ok(TlsConnector::from(Arc::new(client_config(ca_cert_path, client_cert)?)))
+}
+
+/// Builds a connector like `make_connector`'s that asks upstreams for HTTP/2 through ALPN
+pub fn make_http2_connector(
+    ca_cert_path: Option<&str>,
+    client_cert: Option<(&str, &str)>,
+) -> Result<TlsConnector, Error> {
+    let mut config = client_config(ca_cert_path, client_cert)?;
+    config.alpn_protocols = vec![b"h2".to_vec()];
+    Ok(TlsConnector::from(Arc::new(config)))
+}
+
+fn client_config(
+    ca_cert_path: Option<&str>,
+    client_cert: Option<(&str, &str)>,
+) -> Result<rustls::ClientConfig, Error> {
+    let mut roots = rustls::RootCertStore::empty();
+    roots.extend(webpki_roots::TLS_SERVER_ROOTS.iter().cloned());
+    if let Some(path) = ca_cert_path {
+        for cert in load_certs(path)? {
+            roots.add(cert).map_err(Error::InvalidConfig)?;
+        }
+    }
+    let builder = rustls::ClientConfig::builder_with_provider(crypto_provider())
+        .with_safe_default_protocol_versions()
+        .map_err(Error::InvalidConfig)?
+        .with_root_certificates(roots);
+    let config = match client_cert {
+        Some((cert_path, key_path)) => {
+            let certs = load_certs(cert_path)?;
+            let key = load_private_key(key_path)?;
+            builder.with_client_auth_cert(certs, key).map_err(Error::InvalidConfig)?
+        }
+        None => builder.with_no_client_auth(),
+    };
+    Ok(config)
+}
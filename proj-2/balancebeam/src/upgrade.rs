@@ -0,0 +1,120 @@
+use std::collections::HashMap;
+use std::os::unix::io::RawFd;
+use std::process::Command;
+use crate::listener::Listener;
+
+/// Listening sockets a new process inherits, as "address=fd" pairs separated by commas
+const LISTENERS_VAR: &str = "BALANCEBEAM_LISTENERS";
+/// Process ID of the process that started this one to take over from it
+const PARENT_VAR: &str = "BALANCEBEAM_UPGRADE_PARENT";
+
+/// Listening sockets handed down from the process we're taking over from, by the address they
+/// were bound to
+pub struct Inherited {
+    listeners: HashMap<String, RawFd>,
+    parent: Option<libc::pid_t>,
+}
+
+impl Inherited {
+    /// Picks up the sockets handed down to this process, if it was started to take over from
+    /// another one. The environment variables they came in are cleared, so that they aren't passed
+    /// on to other programs.
+    pub fn from_env() -> Inherited {
+        let mut listeners = HashMap::new();
+        if let Ok(value) = std::env::var(LISTENERS_VAR) {
+            for pair in value.split(',').filter(|pair| !pair.is_empty()) {
+                match pair.rsplit_once('=').map(|(address, fd)| (address, fd.parse())) {
+                    Some((address, Ok(fd))) => {
+                        listeners.insert(address.to_string(), fd);
+                    }
+                    _ => log::warn!("Ignoring invalid inherited listener {:?}", pair),
+                }
+            }
+        }
+        let parent = std::env::var(PARENT_VAR).ok().and_then(|pid| pid.parse().ok());
+        std::env::remove_var(LISTENERS_VAR);
+        std::env::remove_var(PARENT_VAR);
+        Inherited { listeners, parent }
+    }
+
+    /// Returns a listener for the address: the inherited socket bound to it if there is one, or
+    /// else a newly bound one
+    pub async fn bind(&mut self, address: &str, v6_only: bool) -> std::io::Result<Listener> {
+        match self.listeners.remove(address) {
+            Some(fd) => {
+                // Safety: the fd was handed down to us for this socket, and nothing else owns it
+                let listener = unsafe { Listener::from_raw_fd(address, fd)? };
+                log::info!("Took over listening socket for {}", address);
+                Ok(listener)
+            }
+            None => Listener::bind(address, v6_only).await,
+        }
+    }
+
+    /// Lets the process we're taking over from know that we're accepting connections, so that it
+    /// can stop, and closes any inherited sockets we had no use for (e.g. because the address to
+    /// listen on was changed)
+    pub fn done(self) {
+        for (address, fd) in self.listeners {
+            log::info!("Closing inherited listening socket for {}, which isn't used", address);
+            // Safety: we own the fd, and nothing else will use it
+            unsafe { libc::close(fd) };
+        }
+        if let Some(parent) = self.parent {
+            log::info!("Telling process {} to finish up, now that we're running", parent);
+            // Safety: kill has no memory safety requirements
+            if unsafe { libc::kill(parent, libc::SIGQUIT) } != 0 {
+                log::warn!(
+                    "Could not signal process {}: {}",
+                    parent,
+                    std::io::Error::last_os_error()
+                );
+            }
+        }
+    }
+}
+
+/// Starts a new copy of this program, with the same arguments, that takes over the given
+/// listening sockets. Returns the new process's ID.
+///
+/// This is how balancebeam is upgraded without downtime: on SIGUSR2, it starts a new copy of
+/// itself (from the binary on disk, which may have been replaced by a newer version) that
+/// inherits its listening sockets. Once the new process is accepting connections on them, it sends
+/// the old one SIGQUIT, and the old one stops accepting connections and exits when the ones it has
+/// open are finished. The sockets stay open the whole time, so no connection is refused.
+pub fn spawn_successor(listeners: &[(String, RawFd)]) -> std::io::Result<u32> {
+    // Duplicates of the sockets are made for the new process to inherit, since sockets Rust opens
+    // are closed when a new program is executed
+    let mut fds = Vec::new();
+    for (address, listener) in listeners {
+        // Safety: the caller keeps the listeners open while we're duplicating them
+        let fd = unsafe { libc::dup(*listener) };
+        if fd < 0 {
+            let error = std::io::Error::last_os_error();
+            close_all(&fds);
+            return Err(error);
+        }
+        fds.push((address.clone(), fd));
+    }
+    let value: Vec<String> = fds.iter().map(|(address, fd)| format!("{}={}", address, fd)).collect();
+
+    // The program is found the way it was when we were started, rather than by our own path, so
+    // that a binary replaced on disk is picked up
+    let mut args = std::env::args_os();
+    let program = args.next().unwrap_or_else(|| "balancebeam".into());
+    let spawned = Command::new(program)
+        .args(args)
+        .env(LISTENERS_VAR, value.join(","))
+        .env(PARENT_VAR, std::process::id().to_string())
+        .spawn();
+    // The new process has its own copies now
+    close_all(&fds);
+    Ok(spawned?.id())
+}
+
+fn close_all(fds: &[(String, RawFd)]) {
+    for (_, fd) in fds {
+        // Safety: these are duplicates we made, and nothing else uses them
+        unsafe { libc::close(*fd) };
+    }
+}
@@ -0,0 +1,175 @@
+use std::convert::TryFrom;
+use std::io;
+use std::net::IpAddr;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::net::{TcpStream, UnixStream};
+use tokio_rustls::client::TlsStream;
+use tokio_rustls::rustls::pki_types::ServerName;
+use tokio_rustls::TlsConnector;
+
+/// Where an `--upstream` entry points. Entries may be given as `host:port`, `http://host:port` or
+/// `https://host:port`; the port defaults to 80 or 443 when a scheme is given. Once the host name
+/// has been looked up, each address it resolved to is a backend of its own, written as the entry
+/// followed by `@ip:port` (e.g. `https://api.example.com@10.0.0.5:443`). An upstream on the same
+/// machine can also be reached over a Unix domain socket, given as `unix:/path/to.sock`.
+pub struct UpstreamAddr<'a> {
+    /// host:port as given in the entry ("localhost" for a Unix domain socket)
+    pub authority: String,
+    /// host:port to open a TCP connection to, or the path of the Unix domain socket
+    connect_to: String,
+    /// Host name (or IP) to verify the upstream's certificate against, if it speaks TLS
+    host: &'a str,
+    tls: bool,
+    unix: bool,
+}
+
+/// Returns the `--upstream` entry that a backend was resolved from (or the backend itself, if it
+/// wasn't resolved)
+pub fn entry_of(backend: &str) -> &str {
+    match backend.rsplit_once('@') {
+        Some((entry, _)) => entry,
+        None => backend,
+    }
+}
+
+impl<'a> UpstreamAddr<'a> {
+    pub fn parse(backend: &'a str) -> UpstreamAddr<'a> {
+        if let Some(path) = crate::listener::unix_path(backend) {
+            return UpstreamAddr {
+                authority: "localhost".to_string(),
+                connect_to: path.to_string(),
+                host: "localhost",
+                tls: false,
+                unix: true,
+            };
+        }
+        let (upstream, resolved) = match backend.rsplit_once('@') {
+            Some((entry, resolved)) => (entry, Some(resolved)),
+            None => (backend, None),
+        };
+        let (rest, tls, default_port) = if let Some(rest) = upstream.strip_prefix("https://") {
+            (rest, true, Some(443))
+        } else if let Some(rest) = upstream.strip_prefix("http://") {
+            (rest, false, Some(80))
+        } else {
+            (upstream, false, None)
+        };
+        let rest = rest.trim_end_matches('/');
+        // Split off the port, taking care not to split inside a bracketed IPv6 address
+        let (host, has_port) = match rest.rfind(':') {
+            Some(idx) if !rest[idx..].contains(']') => (&rest[..idx], true),
+            _ => (rest, false),
+        };
+        let authority = match (has_port, default_port) {
+            (false, Some(port)) => format!("{}:{}", rest, port),
+            _ => rest.to_string(),
+        };
+        UpstreamAddr {
+            connect_to: resolved.map(String::from).unwrap_or_else(|| authority.clone()),
+            authority,
+            host: host.trim_start_matches('[').trim_end_matches(']'),
+            tls,
+            unix: false,
+        }
+    }
+
+    /// Returns true if the entry names its host by IP address, or is a Unix domain socket, so
+    /// there is nothing to look up
+    pub fn is_resolved(&self) -> bool {
+        self.unix || self.host.parse::<IpAddr>().is_ok()
+    }
+
+    /// Returns the URI scheme requests to the upstream are made with
+    pub fn scheme(&self) -> &'static str {
+        if self.tls {
+            "https"
+        } else {
+            "http"
+        }
+    }
+}
+
+/// A connection to an upstream server, which may or may not be encrypted. Both kinds can be used
+/// anywhere a plain stream can, so the request/response forwarding code doesn't need to care.
+pub enum UpstreamStream {
+    Plain(TcpStream),
+    Tls(Box<TlsStream<TcpStream>>),
+    Unix(UnixStream),
+}
+
+impl UpstreamStream {
+    /// Returns the IP and port we're connected to, or the socket path for a Unix domain socket
+    pub fn peer(&self) -> io::Result<String> {
+        match self {
+            UpstreamStream::Plain(stream) => Ok(stream.peer_addr()?.to_string()),
+            UpstreamStream::Tls(stream) => Ok(stream.get_ref().0.peer_addr()?.to_string()),
+            UpstreamStream::Unix(stream) => {
+                let addr = stream.peer_addr()?;
+                Ok(addr.as_pathname().map(|path| path.display().to_string()).unwrap_or_default())
+            }
+        }
+    }
+}
+
+/// Opens a connection to the given backend, performing a TLS handshake if it uses the https://
+/// scheme
+pub async fn connect(backend: &str, connector: &TlsConnector) -> io::Result<UpstreamStream> {
+    let addr = UpstreamAddr::parse(backend);
+    if addr.unix {
+        return Ok(UpstreamStream::Unix(UnixStream::connect(&addr.connect_to).await?));
+    }
+    let stream = TcpStream::connect(&addr.connect_to).await?;
+    if !addr.tls {
+        return Ok(UpstreamStream::Plain(stream));
+    }
+    let server_name = ServerName::try_from(addr.host.to_string())
+        .map_err(|err| io::Error::new(io::ErrorKind::InvalidInput, err))?;
+    let stream = connector.connect(server_name, stream).await?;
+    Ok(UpstreamStream::Tls(Box::new(stream)))
+}
+
+impl AsyncRead for UpstreamStream {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            UpstreamStream::Plain(stream) => Pin::new(stream).poll_read(cx, buf),
+            UpstreamStream::Tls(stream) => Pin::new(stream).poll_read(cx, buf),
+            UpstreamStream::Unix(stream) => Pin::new(stream).poll_read(cx, buf),
+        }
+    }
+}
+
+impl AsyncWrite for UpstreamStream {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        match self.get_mut() {
+            UpstreamStream::Plain(stream) => Pin::new(stream).poll_write(cx, buf),
+            UpstreamStream::Tls(stream) => Pin::new(stream).poll_write(cx, buf),
+            UpstreamStream::Unix(stream) => Pin::new(stream).poll_write(cx, buf),
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            UpstreamStream::Plain(stream) => Pin::new(stream).poll_flush(cx),
+            UpstreamStream::Tls(stream) => Pin::new(stream).poll_flush(cx),
+            UpstreamStream::Unix(stream) => Pin::new(stream).poll_flush(cx),
+        }
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            UpstreamStream::Plain(stream) => Pin::new(stream).poll_shutdown(cx),
+            UpstreamStream::Tls(stream) => Pin::new(stream).poll_shutdown(cx),
+            UpstreamStream::Unix(stream) => Pin::new(stream).poll_shutdown(cx),
+        }
+    }
+}
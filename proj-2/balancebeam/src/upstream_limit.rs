@@ -0,0 +1,60 @@
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
+use dashmap::DashMap;
+
+/// Caps the rate of requests sent to each upstream, so a slow backend can't be pushed into
+/// overload. Each upstream may take a second's worth of requests in a burst; beyond that, requests
+/// are spaced out evenly, waiting their turn for up to the queue timeout. Every request reserves
+/// a slot, so the upstreams are sharded rather than locked as a whole.
+pub struct UpstreamRateLimiter {
+    /// Requests per second each upstream may be sent (0 = unlimited)
+    max_rps: AtomicUsize,
+    /// Longest a request may wait for its turn before it is turned away, in milliseconds
+    queue_timeout: AtomicU64,
+    /// For each upstream, when it would be free again if requests were sent at exactly the
+    /// maximum rate from now on
+    free_at: DashMap<String, Instant>,
+}
+
+impl UpstreamRateLimiter {
+    pub fn new(max_rps: usize, queue_timeout: Duration) -> UpstreamRateLimiter {
+        UpstreamRateLimiter {
+            max_rps: AtomicUsize::new(max_rps),
+            queue_timeout: AtomicU64::new(queue_timeout.as_millis() as u64),
+            free_at: DashMap::new(),
+        }
+    }
+
+    /// Changes the limits, forgetting the requests sent so far if the rate changed
+    pub fn set_limits(&self, max_rps: usize, queue_timeout: Duration) {
+        self.queue_timeout.store(queue_timeout.as_millis() as u64, Ordering::SeqCst);
+        if self.max_rps.swap(max_rps, Ordering::SeqCst) != max_rps {
+            self.free_at.clear();
+        }
+    }
+
+    /// Reserves a slot for a request to the upstream. Returns how long to wait before sending the
+    /// request, or None if it would have to wait longer than the queue timeout (in which case no
+    /// slot is taken).
+    pub fn reserve(&self, address: &str) -> Option<Duration> {
+        let max_rps = self.max_rps.load(Ordering::SeqCst);
+        if max_rps == 0 {
+            return Some(Duration::from_secs(0));
+        }
+        let queue_timeout = Duration::from_millis(self.queue_timeout.load(Ordering::SeqCst));
+        let interval = Duration::from_secs(1) / max_rps as u32;
+        let burst = interval * (max_rps as u32 - 1);
+        let now = Instant::now();
+        // The upstream's entry stays locked until the slot is taken, so that two requests can't
+        // both be given the same one
+        let mut free_at = self.free_at.entry(address.to_string()).or_insert(now);
+        let start = (*free_at).max(now);
+        // A request can go out once the upstream would be free within the burst allowance
+        let wait = start.saturating_duration_since(now + burst);
+        if wait > queue_timeout {
+            return None;
+        }
+        *free_at = start + interval;
+        Some(wait)
+    }
+}
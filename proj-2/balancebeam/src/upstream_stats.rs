@@ -0,0 +1,144 @@
+use std::collections::{HashMap, VecDeque};
+use std::sync::atomic::Ordering;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use serde::Serialize;
+use tokio::time::sleep;
+use crate::state::ProxyState;
+
+/// How far back the stats look
+const WINDOW: Duration = Duration::from_secs(60);
+/// Most requests remembered for each upstream. Past this, the oldest ones are forgotten early,
+/// which keeps memory (and the work of sorting for percentiles) bounded under heavy load.
+const MAX_SAMPLES: usize = 10_000;
+
+/// A request that was sent to an upstream
+struct Sample {
+    at: Instant,
+    latency: Duration,
+    failed: bool,
+}
+
+/// Response time percentiles, in milliseconds
+#[derive(Serialize, Debug)]
+pub struct Percentiles {
+    p50: f64,
+    p95: f64,
+    p99: f64,
+}
+
+/// What the admin API reports about one upstream, over the last minute
+#[derive(Serialize, Debug)]
+pub struct UpstreamReport {
+    address: String,
+    requests: usize,
+    errors: usize,
+    error_rate: f64,
+    requests_per_second: f64,
+    /// None if the upstream hasn't been sent any requests in the last minute
+    latency_ms: Option<Percentiles>,
+}
+
+/// Keeps the requests each upstream was sent over the last minute: how long each took to be
+/// answered and whether it failed, so the upstreams can be compared with each other
+pub struct UpstreamStats {
+    samples: Mutex<HashMap<String, VecDeque<Sample>>>,
+    started: Instant,
+}
+
+impl UpstreamStats {
+    pub fn new() -> UpstreamStats {
+        UpstreamStats { samples: Mutex::new(HashMap::new()), started: Instant::now() }
+    }
+
+    /// Records a request sent to the upstream, how long it took to get the response's headers,
+    /// and whether it failed (with a 5xx status, or no response at all)
+    pub fn record(&self, address: &str, latency: Duration, failed: bool) {
+        let now = Instant::now();
+        let mut samples = self.samples.lock().unwrap();
+        let upstream = samples.entry(address.to_string()).or_default();
+        forget_old(upstream, now);
+        if upstream.len() >= MAX_SAMPLES {
+            upstream.pop_front();
+        }
+        upstream.push_back(Sample { at: now, latency, failed });
+    }
+
+    /// Returns the stats of every upstream that has been sent requests, ordered by address
+    pub fn report(&self) -> Vec<UpstreamReport> {
+        let now = Instant::now();
+        // Until we've been running for a whole window, rates are worked out over the time we have
+        let seconds = now.duration_since(self.started).min(WINDOW).as_secs_f64().max(1.0);
+        let mut samples = self.samples.lock().unwrap();
+        let mut reports: Vec<UpstreamReport> = samples
+            .iter_mut()
+            .map(|(address, upstream)| {
+                forget_old(upstream, now);
+                let requests = upstream.len();
+                let errors = upstream.iter().filter(|sample| sample.failed).count();
+                let mut latencies: Vec<Duration> =
+                    upstream.iter().map(|sample| sample.latency).collect();
+                latencies.sort_unstable();
+                let latency_ms = (!latencies.is_empty()).then(|| Percentiles {
+                    p50: percentile(&latencies, 50.0),
+                    p95: percentile(&latencies, 95.0),
+                    p99: percentile(&latencies, 99.0),
+                });
+                UpstreamReport {
+                    address: address.clone(),
+                    requests,
+                    errors,
+                    error_rate: if requests == 0 { 0.0 } else { errors as f64 / requests as f64 },
+                    requests_per_second: requests as f64 / seconds,
+                    latency_ms,
+                }
+            })
+            .collect();
+        reports.sort_by(|a, b| a.address.cmp(&b.address));
+        reports
+    }
+}
+
+/// Drops the samples that have fallen out of the window
+fn forget_old(samples: &mut VecDeque<Sample>, now: Instant) {
+    while samples.front().is_some_and(|sample| now.duration_since(sample.at) > WINDOW) {
+        samples.pop_front();
+    }
+}
+
+/// Returns the given percentile of the sorted latencies, in milliseconds, using the nearest-rank
+/// method
+fn percentile(sorted: &[Duration], percent: f64) -> f64 {
+    let rank = ((percent / 100.0) * sorted.len() as f64).ceil() as usize;
+    let latency = sorted[rank.clamp(1, sorted.len()) - 1];
+    (latency.as_secs_f64() * 1_000_000.0).round() / 1000.0
+}
+
+/// Logs a line for each upstream that was sent requests in the last minute, every
+/// --stats-log-interval seconds
+pub async fn log_periodically(state: Arc<ProxyState>) {
+    loop {
+        let interval = state.stats_log_interval.load(Ordering::SeqCst) as u64;
+        // When logging is turned off, check back in a while in case it gets turned on
+        sleep(Duration::from_secs(if interval > 0 { interval } else { 10 })).await;
+        if state.stats_log_interval.load(Ordering::SeqCst) == 0 {
+            continue;
+        }
+        for report in state.upstream_stats.report() {
+            let latency = match &report.latency_ms {
+                Some(latency) => latency,
+                None => continue,
+            };
+            log::info!(
+                "Upstream {}: {:.2} requests/s, {:.1}% errors, \
+                 p50 {:.1}ms, p95 {:.1}ms, p99 {:.1}ms",
+                report.address,
+                report.requests_per_second,
+                report.error_rate * 100.0,
+                latency.p50,
+                latency.p95,
+                latency.p99
+            );
+        }
+    }
+}
@@ -0,0 +1,72 @@
+use std::io;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::time::sleep;
+use crate::state::ProxyState;
+
+/// How often the upstreams file is checked for changes
+const POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Reads the upstreams listed in a file, one per line. Blank lines and lines starting with # are
+/// skipped, as are upstreams that are listed twice.
+pub fn read(path: &str) -> io::Result<Vec<String>> {
+    let mut upstreams: Vec<String> = Vec::new();
+    for line in std::fs::read_to_string(path)?.lines() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') || upstreams.iter().any(|u| u == line) {
+            continue;
+        }
+        upstreams.push(line.to_string());
+    }
+    Ok(upstreams)
+}
+
+/// Checks the --upstreams-file every second, and switches over to the upstreams it lists when
+/// they change. The file is read rather than watched for events, since tools that manage it
+/// often replace it (or, in Kubernetes, the symlink to it) rather than writing to it.
+pub async fn watch(state: Arc<ProxyState>) {
+    // Whether reading the file failed last time, and the upstreams it listed if we couldn't use
+    // them, so that the same problem isn't complained about every second
+    let mut failing = false;
+    let mut rejected: Option<Vec<String>> = None;
+    loop {
+        sleep(POLL_INTERVAL).await;
+        let (path, known) = {
+            let config = state.config.read().await;
+            match &config.upstreams_file {
+                Some(path) => (path.clone(), config.file_upstreams.clone()),
+                None => continue,
+            }
+        };
+        let upstreams = match read(&path) {
+            Ok(upstreams) => upstreams,
+            Err(err) => {
+                if !failing {
+                    log::warn!("Keeping the current upstreams, failed to read {}: {}", path, err);
+                }
+                failing = true;
+                continue;
+            }
+        };
+        failing = false;
+        if upstreams == known || rejected.as_ref() == Some(&upstreams) {
+            continue;
+        }
+
+        let mut current = state.config.write().await;
+        if current.upstreams_file.as_deref() != Some(path.as_str()) {
+            // The config was reloaded with another file in the meantime
+            continue;
+        }
+        let mut config = current.clone();
+        config.file_upstreams = upstreams;
+        if let Err(err) = config.validate() {
+            log::error!("Keeping the current upstreams, {} is unusable: {}", path, err);
+            rejected = Some(config.file_upstreams);
+            continue;
+        }
+        rejected = None;
+        log::info!("Upstreams in {} changed: {}", path, config.file_upstreams.join(", "));
+        state.switch_config(&mut current, config).await;
+    }
+}
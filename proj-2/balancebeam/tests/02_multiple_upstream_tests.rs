@@ -1,9 +1,11 @@
 mod common;
 
-use common::{init_logging, BalanceBeam, EchoServer, ErrorServer, Server};
+use common::{
+    free_address, init_logging, BalanceBeam, Behavior, EchoServer, ErrorServer, MockServer, Server,
+};
 
 use std::time::Duration;
-use tokio::time::sleep;
+use tokio::time::{sleep, timeout};
 
 async fn setup_with_params(
     n_upstreams: usize,
@@ -131,6 +133,59 @@ async fn test_passive_health_checks() {
     log::info!("All done :)");
 }
 
+/// Starts an upstream that accepts connections, but hangs up on every request without answering
+async fn start_hang_up_server() -> String {
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0")
+        .await
+        .expect("Could not bind hang-up server");
+    let address = listener.local_addr().unwrap().to_string();
+    tokio::spawn(async move {
+        while let Ok((mut stream, _)) = listener.accept().await {
+            let mut buffer = [0_u8; 1024];
+            let _ = tokio::io::AsyncReadExt::read(&mut stream, &mut buffer).await;
+        }
+    });
+    address
+}
+
+/// Verify that an upstream whose requests keep failing is marked down, even though it still
+/// accepts connections and the active health checks haven't run yet:
+///
+/// * Put an upstream that hangs up on every request alongside a working one
+/// * Send requests until the broken upstream has failed twice in a row
+/// * All the remaining requests should go to the working upstream
+#[tokio::test]
+async fn test_passive_health_checks_on_request_failures() {
+    init_logging();
+    let upstream = EchoServer::new().await;
+    let broken_address = start_hang_up_server().await;
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&broken_address, &upstream.address],
+        &["--passive-health-check-failures", "2", "--active-health-check-interval", "60"],
+    )
+    .await;
+
+    let mut n_failed = 0;
+    for i in 0..10 {
+        let path = format!("/request-{}", i);
+        let response = reqwest::get(format!("http://{}{}", balancebeam.address, path))
+            .await
+            .expect("Error sending request to balancebeam");
+        if response.status() == reqwest::StatusCode::BAD_GATEWAY {
+            n_failed += 1;
+        } else {
+            let response_text = response.text().await.unwrap();
+            assert!(response_text.contains(&format!("GET {} HTTP/1.1", path)));
+        }
+    }
+    assert_eq!(n_failed, 2, "Broken upstream wasn't marked down after 2 failures");
+
+    let num_requests_received = Box::new(upstream).stop().await;
+    assert_eq!(num_requests_received, 8);
+
+    log::info!("All done :)");
+}
+
 /// Verify that the active health checks are monitoring HTTP status, rather than simply depending
 /// on whether connections can be established to determine whether an upstream is up:
 ///
@@ -240,6 +295,229 @@ async fn test_active_health_checks_restore_failed_upstream() {
     log::info!("All done :)");
 }
 
+/// Make sure active health checks send the configured request and check the response against the
+/// configured expectations:
+///
+/// * Start one upstream that echoes requests back, and one that only returns HTTP error 500s
+/// * Health check with a POST, expecting a 2xx status and the request line in the response body
+/// * Once the health checks have run, every request should go to the echo server
+#[tokio::test]
+async fn test_active_health_checks_custom_expectations() {
+    init_logging();
+    let echo_upstream = EchoServer::new().await;
+    let error_upstream = ErrorServer::new().await;
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&error_upstream.address, &echo_upstream.address],
+        &[
+            "--active-health-check-interval",
+            "1",
+            "--active-health-check-method",
+            "POST",
+            "--active-health-check-path",
+            "/probe",
+            "--active-health-check-status",
+            "200-299",
+            "--active-health-check-body",
+            "POST /probe HTTP/1.1",
+        ],
+    )
+    .await;
+
+    log::info!("Waiting for health checks to mark the error server down...");
+    sleep(Duration::from_secs(3)).await;
+
+    for i in 0..6 {
+        let path = format!("/request-{}", i);
+        let response_text = balancebeam
+            .get(&path)
+            .await
+            .expect("Error sending request to balancebeam");
+        assert!(
+            response_text.contains(&format!("GET {} HTTP/1.1", path)),
+            "balancebeam returned unexpected response. Active health checks may not be working."
+        );
+    }
+
+    Box::new(echo_upstream).stop().await;
+    Box::new(error_upstream).stop().await;
+    log::info!("All done :)");
+}
+
+/// Polls the admin API until it reports the upstream as healthy (or not), so tests don't have to
+/// guess how long the health checks will take
+async fn wait_for_health(admin_address: &str, upstream_address: &str, healthy: bool) {
+    timeout(Duration::from_secs(10), async {
+        loop {
+            let upstreams: Vec<serde_json::Value> =
+                reqwest::get(format!("http://{}/upstreams", admin_address))
+                    .await
+                    .expect("Error sending request to admin API")
+                    .json()
+                    .await
+                    .expect("Admin API returned invalid JSON");
+            if upstreams
+                .iter()
+                .any(|info| info["address"] == upstream_address && info["healthy"] == healthy)
+            {
+                return;
+            }
+            sleep(Duration::from_millis(100)).await;
+        }
+    })
+    .await
+    .expect("Health checks never changed the upstream's status");
+}
+
+/// With slow start, an upstream that has just been restored should only get a trickle of requests
+/// at first, rather than its full share:
+///
+/// * Make one of the upstreams fail, and wait for the health checks to mark it down
+/// * Let it recover, and wait for the health checks to mark it up again
+/// * Send a bunch of requests. Nearly all of them should go to the upstream that never went down
+#[tokio::test]
+async fn test_slow_start_after_recovery() {
+    init_logging();
+    let n_requests = 40;
+    let steady_upstream = MockServer::new("steady", Behavior::Healthy).await;
+    let recovering_upstream = MockServer::new("recovering", Behavior::Healthy).await;
+    let admin = free_address();
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&steady_upstream.address, &recovering_upstream.address],
+        &[
+            "--active-health-check-interval",
+            "1",
+            "--slow-start-window",
+            "60",
+            "--admin-bind",
+            &admin,
+        ],
+    )
+    .await;
+
+    log::info!("Making one of the upstreams fail and waiting for it to be marked down...");
+    recovering_upstream.set_behavior(Behavior::Status(500));
+    wait_for_health(&admin, &recovering_upstream.address, false).await;
+
+    log::info!("Letting it recover and waiting for it to be marked up...");
+    recovering_upstream.set_behavior(Behavior::Healthy);
+    wait_for_health(&admin, &recovering_upstream.address, true).await;
+
+    // Count which upstream answered each request, leaving out the health checks they also get
+    let mut recovered_count = 0;
+    let mut steady_count = 0;
+    for i in 0..n_requests {
+        let response_text = balancebeam
+            .get(&format!("/request-{}", i))
+            .await
+            .expect("Error sending request to balancebeam");
+        match response_text.as_str() {
+            "recovering" => recovered_count += 1,
+            "steady" => steady_count += 1,
+            other => panic!("balancebeam returned unexpected response {:?}", other),
+        }
+    }
+    assert!(
+        recovered_count * 3 < steady_count,
+        "The recovered upstream got {} requests to the other's {}; slow start may not be working",
+        recovered_count,
+        steady_count
+    );
+
+    Box::new(recovering_upstream).stop().await;
+    Box::new(steady_upstream).stop().await;
+    log::info!("All done :)");
+}
+
+/// Starts an upstream that accepts connections and reads requests, but never answers them
+async fn start_unresponsive_server() -> String {
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0")
+        .await
+        .expect("Could not bind unresponsive server");
+    let address = listener.local_addr().unwrap().to_string();
+    tokio::spawn(async move {
+        let mut connections = Vec::new();
+        while let Ok((stream, _)) = listener.accept().await {
+            // Hold on to the connection so it stays open
+            connections.push(stream);
+        }
+    });
+    address
+}
+
+/// Upstreams should be health checked at the same time, so that a few unresponsive upstreams
+/// don't hold up noticing that another one is failing:
+///
+/// * Put three upstreams that never answer and one that returns Error 500s alongside a working one
+/// * Health checks time out after 2 seconds. Checking the upstreams one at a time would take at
+///   least 6 seconds to get to the last one, but checking them all at once takes about 2
+/// * After 4 seconds, all the requests should go to the working upstream
+#[tokio::test]
+async fn test_active_health_checks_run_concurrently() {
+    init_logging();
+    let mut addresses = Vec::new();
+    for _ in 0..3 {
+        addresses.push(start_unresponsive_server().await);
+    }
+    let error_upstream = ErrorServer::new().await;
+    let echo_upstream = EchoServer::new().await;
+    addresses.push(error_upstream.address.clone());
+    addresses.push(echo_upstream.address.clone());
+    let upstreams: Vec<&str> = addresses.iter().map(String::as_str).collect();
+    let balancebeam = BalanceBeam::new_with_args(
+        &upstreams,
+        &["--active-health-check-interval", "1", "--active-health-check-timeout", "2"],
+    )
+    .await;
+
+    log::info!("Waiting for health checks to mark the broken upstreams down...");
+    sleep(Duration::from_secs(3)).await;
+
+    for i in 0..6 {
+        let path = format!("/request-{}", i);
+        let response_text = timeout(Duration::from_secs(1), balancebeam.get(&path))
+            .await
+            .expect("Request took too long. Health checks may be holding up requests")
+            .expect("Error sending request to balancebeam");
+        assert!(
+            response_text.contains(&format!("GET {} HTTP/1.1", path)),
+            "balancebeam returned unexpected response. Health checks may not be concurrent."
+        );
+    }
+
+    Box::new(echo_upstream).stop().await;
+    Box::new(error_upstream).stop().await;
+    log::info!("All done :)");
+}
+
+/// Make sure an upstream whose health check response is missing the expected body text is marked
+/// down, even though it responds with 200 OK
+#[tokio::test]
+async fn test_active_health_checks_body_mismatch() {
+    init_logging();
+    let upstream = EchoServer::new().await;
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&upstream.address],
+        &[
+            "--active-health-check-interval",
+            "1",
+            "--active-health-check-body",
+            "not in the response",
+        ],
+    )
+    .await;
+
+    log::info!("Waiting for health checks to mark the upstream down...");
+    sleep(Duration::from_secs(3)).await;
+
+    let response = reqwest::get(format!("http://{}/request", balancebeam.address))
+        .await
+        .expect("Error sending request to balancebeam");
+    assert_eq!(response.status(), reqwest::StatusCode::BAD_GATEWAY);
+
+    Box::new(upstream).stop().await;
+    log::info!("All done :)");
+}
+
 /// Enable rate limiting and ensure that requests fail after sending more than the threshold
 #[tokio::test]
 async fn test_rate_limiting() {
@@ -0,0 +1,185 @@
+mod common;
+
+use common::{init_logging, setup_with_args, stop_all, BalanceBeam, EchoServer, Server};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+
+/// Starts an upstream that takes `delay` to answer each request, and counts the requests it gets
+async fn start_slow_upstream(delay: Duration) -> (String, Arc<AtomicUsize>) {
+    let listener = TcpListener::bind("127.0.0.1:0")
+        .await
+        .expect("Could not bind upstream");
+    let address = listener.local_addr().unwrap().to_string();
+    let requests_received = Arc::new(AtomicUsize::new(0));
+    let counter = requests_received.clone();
+    tokio::spawn(async move {
+        while let Ok((mut stream, _)) = listener.accept().await {
+            let counter = counter.clone();
+            tokio::spawn(async move {
+                let mut buffer = [0_u8; 1024];
+                if !matches!(stream.read(&mut buffer).await, Ok(n) if n > 0) {
+                    return;
+                }
+                counter.fetch_add(1, Ordering::SeqCst);
+                tokio::time::sleep(delay).await;
+                let _ = stream
+                    .write_all(b"HTTP/1.1 200 OK\r\nContent-Length: 4\r\n\r\nslow")
+                    .await;
+            });
+        }
+    });
+    (address, requests_received)
+}
+
+/// With consistent hashing, every request from the same client should land on the same upstream
+#[tokio::test]
+async fn test_consistent_hash_is_sticky() {
+    let n_requests = 20;
+    let (balancebeam, upstreams) =
+        setup_with_args(3, &["--load-balancer", "consistent-hash"]).await;
+
+    for i in 0..n_requests {
+        let path = format!("/request-{}", i);
+        let response_text = balancebeam
+            .get(&path)
+            .await
+            .expect("Error sending request to balancebeam");
+        assert!(response_text.contains(&format!("GET {} HTTP/1.1", path)));
+    }
+
+    let request_counters = stop_all(upstreams).await;
+    assert!(
+        request_counters.contains(&n_requests),
+        "Requests from a single client were spread across several upstreams"
+    );
+
+    log::info!("All done :)");
+}
+
+/// When hashing on a header, clients with different header values should be spread across the
+/// upstreams. (Each request uses a new connection, since balancebeam picks an upstream when the
+/// first request on a connection arrives.)
+#[tokio::test]
+async fn test_consistent_hash_by_header() {
+    let n_keys = 15;
+    let (balancebeam, upstreams) = setup_with_args(
+        3,
+        &["--load-balancer", "consistent-hash", "--hash-header", "x-user-id"],
+    )
+    .await;
+
+    for round in 0..2 {
+        for key in 0..n_keys {
+            let response_text = reqwest::Client::new()
+                .get(format!("http://{}/round-{}", balancebeam.address, round))
+                .header("x-user-id", format!("user-{}", key))
+                .send()
+                .await
+                .expect("Error sending request to balancebeam")
+                .text()
+                .await
+                .expect("Balancebeam replied with a malformed response");
+            assert!(response_text.contains(&format!("x-user-id: user-{}", key)));
+        }
+    }
+
+    let request_counters = stop_all(upstreams).await;
+    assert_eq!(request_counters.iter().sum::<usize>(), n_keys * 2);
+    assert!(
+        request_counters.iter().filter(|&&count| count > 0).count() > 1,
+        "All header values were hashed onto a single upstream"
+    );
+
+    log::info!("All done :)");
+}
+
+/// The least-response-time balancer should send nearly everything to the faster upstream once it
+/// has measured both of them
+#[tokio::test]
+async fn test_ewma_prefers_fast_upstream() {
+    init_logging();
+    let n_requests = 10;
+    let (slow_address, slow_requests) = start_slow_upstream(Duration::from_millis(200)).await;
+    let fast_upstream = EchoServer::new().await;
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&slow_address, &fast_upstream.address],
+        &["--load-balancer", "ewma", "--active-health-check-interval", "60"],
+    )
+    .await;
+
+    for i in 0..n_requests {
+        let status = reqwest::Client::new()
+            .get(format!("http://{}/request-{}", balancebeam.address, i))
+            .send()
+            .await
+            .expect("Error sending request to balancebeam")
+            .status();
+        assert_eq!(status.as_u16(), 200);
+    }
+
+    let fast_requests = Box::new(fast_upstream).stop().await;
+    let slow_requests = slow_requests.load(Ordering::SeqCst);
+    assert_eq!(fast_requests + slow_requests, n_requests);
+    assert!(
+        slow_requests <= 2,
+        "The slow upstream got {} of {} requests",
+        slow_requests,
+        n_requests
+    );
+
+    log::info!("All done :)");
+}
+
+/// Power of two choices should keep sending requests to the upstream that is free, rather than
+/// queueing them up behind one that is stuck on a slow request
+#[tokio::test]
+async fn test_p2c_avoids_busy_upstream() {
+    init_logging();
+    let n_requests = 10;
+    let (slow_address, slow_requests) = start_slow_upstream(Duration::from_secs(2)).await;
+    let fast_upstream = EchoServer::new().await;
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&slow_address, &fast_upstream.address],
+        &["--load-balancer", "p2c", "--active-health-check-interval", "60"],
+    )
+    .await;
+
+    // Each request gets its own connection, which is closed as soon as the response starts coming
+    // back, so that balancebeam lets go of the upstream connection too
+    let mut requests = Vec::new();
+    for i in 0..n_requests {
+        let address = balancebeam.address.clone();
+        requests.push(tokio::spawn(async move {
+            let mut stream = TcpStream::connect(&address)
+                .await
+                .expect("Could not connect to balancebeam");
+            let request = format!("GET /request-{} HTTP/1.1\r\nHost: localhost\r\n\r\n", i);
+            stream
+                .write_all(request.as_bytes())
+                .await
+                .expect("Error sending request to balancebeam");
+            let mut buffer = [0_u8; 12];
+            stream.read_exact(&mut buffer).await.expect("Error reading response");
+            String::from_utf8_lossy(&buffer).into_owned()
+        }));
+        tokio::time::sleep(Duration::from_millis(50)).await;
+    }
+    for request in requests {
+        assert_eq!(request.await.unwrap(), "HTTP/1.1 200");
+    }
+
+    let fast_requests = Box::new(fast_upstream).stop().await;
+    let slow_requests = slow_requests.load(Ordering::SeqCst);
+    assert_eq!(fast_requests + slow_requests, n_requests);
+    assert!(
+        slow_requests <= 2,
+        "The busy upstream got {} of {} requests",
+        slow_requests,
+        n_requests
+    );
+
+    log::info!("All done :)");
+}
@@ -0,0 +1,93 @@
+mod common;
+
+use common::{get_status, setup_with_args, stop_all};
+use std::time::Duration;
+use tokio::time::sleep;
+
+/// The token bucket should allow a burst up to the configured size, reject anything past it, and
+/// let requests through again once tokens have been refilled
+#[tokio::test]
+async fn test_token_bucket_burst() {
+    let burst = 3;
+    let (balancebeam, upstreams) = setup_with_args(
+        1,
+        &[
+            "--max-requests-per-minute",
+            "60",
+            "--rate-limiter",
+            "token-bucket",
+            "--rate-limit-burst",
+            &burst.to_string(),
+        ],
+    )
+    .await;
+
+    log::info!("Sending a burst of requests within the burst size. These should succeed.");
+    for i in 0..burst {
+        assert_eq!(get_status(&balancebeam.address, &format!("/burst-{}", i)).await, 200);
+    }
+
+    log::info!("The bucket is empty now, so the next request should be rejected");
+    assert_eq!(get_status(&balancebeam.address, "/overboard").await, 429);
+
+    log::info!("Waiting for a token to be refilled (1 per second at 60 requests per minute)");
+    sleep(Duration::from_millis(1100)).await;
+    assert_eq!(get_status(&balancebeam.address, "/refilled").await, 200);
+    assert_eq!(get_status(&balancebeam.address, "/overboard-again").await, 429);
+
+    let request_counters = stop_all(upstreams).await;
+    assert_eq!(request_counters.iter().sum::<usize>(), burst + 1);
+
+    log::info!("All done :)");
+}
+
+/// Send a request carrying the given API key on a new connection and return the HTTP status code
+async fn get_status_with_key(address: &str, path: &str, api_key: &str) -> u16 {
+    reqwest::Client::new()
+        .get(format!("http://{}{}", address, path))
+        .header("x-api-key", api_key)
+        .send()
+        .await
+        .expect("Error sending request to balancebeam")
+        .status()
+        .as_u16()
+}
+
+/// When limiting by API key, each key should get its own quota, even though all the requests come
+/// from the same IP
+#[tokio::test]
+async fn test_rate_limit_by_api_key() {
+    let limit = 2;
+    let (balancebeam, upstreams) = setup_with_args(
+        1,
+        &[
+            "--max-requests-per-minute",
+            &limit.to_string(),
+            "--rate-limit-by",
+            "api-key",
+        ],
+    )
+    .await;
+
+    log::info!("Using up the quota for the first key");
+    for i in 0..limit {
+        let path = format!("/first-{}", i);
+        assert_eq!(get_status_with_key(&balancebeam.address, &path, "first").await, 200);
+    }
+    assert_eq!(get_status_with_key(&balancebeam.address, "/first-over", "first").await, 429);
+
+    log::info!("The second key has a quota of its own");
+    for i in 0..limit {
+        let path = format!("/second-{}", i);
+        assert_eq!(get_status_with_key(&balancebeam.address, &path, "second").await, 200);
+    }
+    assert_eq!(get_status_with_key(&balancebeam.address, "/second-over", "second").await, 429);
+
+    log::info!("Requests without a key are limited by IP, which hasn't been used yet");
+    assert_eq!(get_status(&balancebeam.address, "/no-key").await, 200);
+
+    let request_counters = stop_all(upstreams).await;
+    assert_eq!(request_counters.iter().sum::<usize>(), 2 * limit + 1);
+
+    log::info!("All done :)");
+}
@@ -0,0 +1,56 @@
+mod common;
+
+use common::{init_logging, BalanceBeam, EchoServer, Server};
+use rand::Rng;
+use std::time::Duration;
+use tokio::time::sleep;
+
+fn write_config(path: &std::path::Path, upstreams: &[&str]) {
+    let upstreams: Vec<String> = upstreams.iter().map(|addr| format!("\"{}\"", addr)).collect();
+    std::fs::write(path, format!("upstreams = [{}]\n", upstreams.join(", ")))
+        .expect("Could not write config file");
+}
+
+async fn send_requests(balancebeam: &BalanceBeam, prefix: &str, n_requests: usize) {
+    for i in 0..n_requests {
+        let path = format!("/{}-{}", prefix, i);
+        let response_text = balancebeam
+            .get(&path)
+            .await
+            .expect("Error sending request to balancebeam");
+        assert!(response_text.contains(&format!("GET {} HTTP/1.1", path)));
+    }
+}
+
+/// Start balancebeam with a config file pointing at one upstream, then swap in a different
+/// upstream and send SIGHUP. New requests should go to the new upstream without a restart.
+#[tokio::test]
+async fn test_sighup_reloads_upstreams() {
+    init_logging();
+    let n_requests = 4;
+    let first = EchoServer::new().await;
+    let second = EchoServer::new().await;
+
+    let config_path = std::env::temp_dir().join(format!(
+        "balancebeam-test-{}.toml",
+        rand::thread_rng().gen::<u32>()
+    ));
+    write_config(&config_path, &[&first.address]);
+    let balancebeam =
+        BalanceBeam::new_with_args(&[], &["--config", config_path.to_str().unwrap()]).await;
+
+    log::info!("Sending requests with the initial config");
+    send_requests(&balancebeam, "before", n_requests).await;
+
+    log::info!("Switching upstreams in the config file and sending SIGHUP");
+    write_config(&config_path, &[&second.address]);
+    balancebeam.send_sighup();
+    sleep(Duration::from_millis(500)).await;
+    send_requests(&balancebeam, "after", n_requests).await;
+
+    std::fs::remove_file(&config_path).ok();
+    assert_eq!(Box::new(first).stop().await, n_requests);
+    assert_eq!(Box::new(second).stop().await, n_requests);
+
+    log::info!("All done :)");
+}
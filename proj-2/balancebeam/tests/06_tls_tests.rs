@@ -0,0 +1,148 @@
+mod common;
+
+use common::{setup_with_args, stop_all, BalanceBeam};
+
+fn cert_path(file_name: &str) -> String {
+    format!("{}/tests/certs/{}", env!("CARGO_MANIFEST_DIR"), file_name)
+}
+
+fn https_client() -> reqwest::Client {
+    let cert = std::fs::read(cert_path("localhost.crt")).expect("Could not read test certificate");
+    reqwest::Client::builder()
+        .add_root_certificate(
+            reqwest::Certificate::from_pem(&cert).expect("Test certificate is not valid PEM"),
+        )
+        .build()
+        .expect("Could not build HTTPS client")
+}
+
+/// Balancebeam should decrypt HTTPS requests and forward them to the upstream as plain HTTP
+#[tokio::test]
+async fn test_tls_termination() {
+    let cert = cert_path("localhost.crt");
+    let key = cert_path("localhost.key");
+    let (balancebeam, upstreams) =
+        setup_with_args(1, &["--tls-cert", &cert, "--tls-key", &key]).await;
+    let port = balancebeam.address.rsplit(':').next().unwrap();
+    let client = https_client();
+
+    for path in &["/first", "/second"] {
+        let response_text = client
+            .get(format!("https://localhost:{}{}", port, path))
+            .header("x-sent-by", "balancebeam-tests")
+            .send()
+            .await
+            .expect("Error sending HTTPS request to balancebeam")
+            .text()
+            .await
+            .expect("Balancebeam replied with a malformed response");
+        assert!(response_text.contains(&format!("GET {} HTTP/1.1", path)));
+        assert!(response_text.contains("x-sent-by: balancebeam-tests"));
+    }
+
+    let request_counters = stop_all(upstreams).await;
+    assert_eq!(request_counters, vec![2]);
+
+    log::info!("All done :)");
+}
+
+/// Plain HTTP clients shouldn't get through when TLS is enabled
+#[tokio::test]
+async fn test_tls_rejects_plain_http() {
+    let cert = cert_path("localhost.crt");
+    let key = cert_path("localhost.key");
+    let (balancebeam, upstreams) =
+        setup_with_args(1, &["--tls-cert", &cert, "--tls-key", &key]).await;
+
+    assert!(
+        balancebeam.get("/plain").await.is_err(),
+        "Balancebeam answered a plain HTTP request on a TLS listener"
+    );
+
+    let request_counters = stop_all(upstreams).await;
+    assert_eq!(request_counters, vec![0]);
+
+    log::info!("All done :)");
+}
+
+/// Balancebeam should be able to forward requests to an upstream over HTTPS. The HTTPS upstream
+/// here is a second balancebeam terminating TLS in front of an echo server.
+#[tokio::test]
+async fn test_https_upstream() {
+    let cert = cert_path("localhost.crt");
+    let key = cert_path("localhost.key");
+    let (tls_balancebeam, upstreams) =
+        setup_with_args(1, &["--tls-cert", &cert, "--tls-key", &key]).await;
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&format!("https://{}", tls_balancebeam.address)],
+        &["--upstream-ca-cert", &cert],
+    )
+    .await;
+
+    for path in &["/first", "/second"] {
+        let response_text = balancebeam
+            .get(path)
+            .await
+            .expect("Error sending request to balancebeam");
+        assert!(response_text.contains(&format!("GET {} HTTP/1.1", path)));
+    }
+
+    let request_counters = stop_all(upstreams).await;
+    assert_eq!(request_counters, vec![2]);
+
+    log::info!("All done :)");
+}
+
+/// Upstreams whose certificate we don't trust should be treated as unreachable
+#[tokio::test]
+async fn test_https_upstream_untrusted_certificate() {
+    let cert = cert_path("localhost.crt");
+    let key = cert_path("localhost.key");
+    let (tls_balancebeam, upstreams) =
+        setup_with_args(1, &["--tls-cert", &cert, "--tls-key", &key]).await;
+    let balancebeam =
+        BalanceBeam::new_with_args(&[&format!("https://{}", tls_balancebeam.address)], &[]).await;
+
+    let response_text = balancebeam
+        .get("/untrusted")
+        .await
+        .expect("Error sending request to balancebeam");
+    assert!(response_text.contains("502"));
+
+    let request_counters = stop_all(upstreams).await;
+    assert_eq!(request_counters, vec![0]);
+
+    log::info!("All done :)");
+}
+
+/// With TLS auto-detection, the same port should serve HTTPS and plain HTTP clients, and tell
+/// upstreams which one each request came in over
+#[tokio::test]
+async fn test_tls_auto_detect() {
+    let cert = cert_path("localhost.crt");
+    let key = cert_path("localhost.key");
+    let (balancebeam, upstreams) =
+        setup_with_args(1, &["--tls-cert", &cert, "--tls-key", &key, "--tls-auto-detect"]).await;
+    let port = balancebeam.address.rsplit(':').next().unwrap();
+
+    let response_text = https_client()
+        .get(format!("https://localhost:{}/secure", port))
+        .send()
+        .await
+        .expect("Error sending HTTPS request to balancebeam")
+        .text()
+        .await
+        .expect("Balancebeam replied with a malformed response");
+    assert!(response_text.contains("GET /secure HTTP/1.1"));
+    assert!(response_text.contains("x-forwarded-proto: https"));
+
+    let response_text =
+        balancebeam.get("/plain").await.expect("Error sending plain HTTP request to balancebeam");
+    assert!(response_text.contains("GET /plain HTTP/1.1"));
+    assert!(response_text.contains("x-forwarded-proto: http\n"));
+
+    let request_counters = stop_all(upstreams).await;
+    assert_eq!(request_counters, vec![2]);
+
+    log::info!("All done :)");
+}
@@ -0,0 +1,86 @@
+mod common;
+
+use common::{init_logging, BalanceBeam, Server, UpgradeServer};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+
+/// Reads from the stream until the end of the response headers, returning the headers
+async fn read_response_headers(stream: &mut TcpStream) -> String {
+    let mut response = Vec::new();
+    let mut byte = [0_u8; 1];
+    while !response.ends_with(b"\r\n\r\n") {
+        let bytes_read = stream
+            .read(&mut byte)
+            .await
+            .expect("Error reading response from balancebeam");
+        assert_ne!(bytes_read, 0, "Balancebeam hung up before sending a complete response");
+        response.push(byte[0]);
+    }
+    String::from_utf8(response).expect("Response headers are not valid UTF-8")
+}
+
+/// Once the upstream switches protocols, balancebeam should relay raw bytes in both directions
+/// instead of trying to parse them as HTTP
+#[tokio::test]
+async fn test_upgrade_pass_through() {
+    init_logging();
+    let upstream = UpgradeServer::new().await;
+    let balancebeam = BalanceBeam::new_with_args(&[&upstream.address], &[]).await;
+
+    let mut stream = TcpStream::connect(&balancebeam.address)
+        .await
+        .expect("Could not connect to balancebeam");
+    stream
+        .write_all(
+            b"GET /chat HTTP/1.1\r\nHost: localhost\r\nConnection: Upgrade\r\nUpgrade: echo\r\n\r\n",
+        )
+        .await
+        .expect("Error sending upgrade request to balancebeam");
+    let headers = read_response_headers(&mut stream).await;
+    assert!(headers.starts_with("HTTP/1.1 101"), "Unexpected response: {}", headers);
+
+    // Neither of these messages is valid HTTP, so they'd be rejected if balancebeam were still
+    // parsing the connection
+    for message in &[&b"hello\x00world"[..], &b"\x81\x05frame"[..]] {
+        stream
+            .write_all(message)
+            .await
+            .expect("Error writing to upgraded connection");
+        let mut echoed = vec![0_u8; message.len()];
+        stream
+            .read_exact(&mut echoed)
+            .await
+            .expect("Error reading from upgraded connection");
+        assert_eq!(&echoed, message);
+    }
+    drop(stream);
+
+    let num_requests_received = Box::new(upstream).stop().await;
+    assert_eq!(num_requests_received, 1);
+
+    log::info!("All done :)");
+}
+
+/// Requests without a Connection: Upgrade header are proxied as plain HTTP
+#[tokio::test]
+async fn test_upgrade_requires_connection_header() {
+    init_logging();
+    let upstream = UpgradeServer::new().await;
+    let balancebeam = BalanceBeam::new_with_args(&[&upstream.address], &[]).await;
+
+    let mut stream = TcpStream::connect(&balancebeam.address)
+        .await
+        .expect("Could not connect to balancebeam");
+    stream
+        .write_all(b"GET /plain HTTP/1.1\r\nHost: localhost\r\n\r\n")
+        .await
+        .expect("Error sending request to balancebeam");
+    let headers = read_response_headers(&mut stream).await;
+    assert!(headers.starts_with("HTTP/1.1 426"), "Unexpected response: {}", headers);
+    drop(stream);
+
+    let num_requests_received = Box::new(upstream).stop().await;
+    assert_eq!(num_requests_received, 1);
+
+    log::info!("All done :)");
+}
@@ -0,0 +1,201 @@
+mod common;
+
+use common::{init_logging, BalanceBeam, EchoServer, Server};
+use std::time::Duration;
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::time::sleep;
+
+/// Reads one response with a Content-Length body off the stream, returning (headers, body)
+async fn read_response(stream: &mut BufReader<TcpStream>) -> (String, String) {
+    let mut headers = String::new();
+    let mut content_length = 0;
+    loop {
+        let mut line = String::new();
+        stream
+            .read_line(&mut line)
+            .await
+            .expect("Error reading response from balancebeam");
+        if line == "\r\n" || line.is_empty() {
+            break;
+        }
+        if let Some(value) = line.to_lowercase().strip_prefix("content-length:") {
+            content_length = value.trim().parse().expect("Invalid Content-Length");
+        }
+        headers += &line;
+    }
+    let mut body = vec![0_u8; content_length];
+    stream
+        .read_exact(&mut body)
+        .await
+        .expect("Error reading response body from balancebeam");
+    (headers, String::from_utf8(body).expect("Response body is not valid UTF-8"))
+}
+
+/// Balancebeam should decode a chunked request body, and re-encode it when forwarding it
+#[tokio::test]
+async fn test_chunked_request() {
+    init_logging();
+    let upstream = EchoServer::new().await;
+    let balancebeam = BalanceBeam::new_with_args(&[&upstream.address], &[]).await;
+
+    let stream = TcpStream::connect(&balancebeam.address)
+        .await
+        .expect("Could not connect to balancebeam");
+    let mut stream = BufReader::new(stream);
+    stream
+        .write_all(
+            b"POST /upload HTTP/1.1\r\nHost: localhost\r\nTransfer-Encoding: chunked\r\n\r\n\
+              6;ext=value\r\nhello \r\n",
+        )
+        .await
+        .expect("Error sending request to balancebeam");
+    // Send the rest of the body separately, so it arrives after the headers have been parsed
+    sleep(Duration::from_millis(100)).await;
+    stream
+        .write_all(b"5\r\nworld\r\n0\r\nX-Trailer: ignored\r\n\r\n")
+        .await
+        .expect("Error sending request to balancebeam");
+    let (headers, body) = read_response(&mut stream).await;
+    assert!(headers.starts_with("HTTP/1.1 200"), "Unexpected response: {}", headers);
+    assert!(body.starts_with("POST /upload HTTP/1.1"));
+    assert!(body.contains("transfer-encoding: chunked"));
+    assert!(body.ends_with("\n\nhello world"), "Body was not decoded: {:?}", body);
+
+    // The connection should still be usable, since the whole chunked body was consumed
+    stream
+        .write_all(b"GET /after HTTP/1.1\r\nHost: localhost\r\n\r\n")
+        .await
+        .expect("Error sending request to balancebeam");
+    let (_, body) = read_response(&mut stream).await;
+    assert!(body.starts_with("GET /after HTTP/1.1"));
+    drop(stream);
+
+    let num_requests_received = Box::new(upstream).stop().await;
+    assert_eq!(num_requests_received, 2);
+
+    log::info!("All done :)");
+}
+
+/// Invalid chunked bodies should be rejected rather than forwarded
+#[tokio::test]
+async fn test_invalid_chunked_request() {
+    init_logging();
+    let upstream = EchoServer::new().await;
+    let balancebeam = BalanceBeam::new_with_args(&[&upstream.address], &[]).await;
+
+    let stream = TcpStream::connect(&balancebeam.address)
+        .await
+        .expect("Could not connect to balancebeam");
+    let mut stream = BufReader::new(stream);
+    stream
+        .write_all(
+            b"POST /upload HTTP/1.1\r\nHost: localhost\r\nTransfer-Encoding: chunked\r\n\r\n\
+              zz\r\nhello\r\n0\r\n\r\n",
+        )
+        .await
+        .expect("Error sending request to balancebeam");
+    let (headers, _) = read_response(&mut stream).await;
+    assert!(headers.starts_with("HTTP/1.1 400"), "Unexpected response: {}", headers);
+    drop(stream);
+
+    let num_requests_received = Box::new(upstream).stop().await;
+    assert_eq!(num_requests_received, 0);
+
+    log::info!("All done :)");
+}
+
+/// Starts an upstream that streams its responses back in several chunks, trickled out over time
+async fn start_chunked_upstream() -> String {
+    let listener = TcpListener::bind("127.0.0.1:0")
+        .await
+        .expect("Could not bind chunked upstream");
+    let address = listener.local_addr().unwrap().to_string();
+    tokio::spawn(async move {
+        while let Ok((stream, _)) = listener.accept().await {
+            tokio::spawn(async move {
+                let mut stream = BufReader::new(stream);
+                loop {
+                    // Read the request headers (the requests we send don't have bodies)
+                    let mut request_line = String::new();
+                    match stream.read_line(&mut request_line).await {
+                        Ok(0) | Err(_) => return,
+                        Ok(_) => {}
+                    }
+                    let mut line = String::new();
+                    while line != "\r\n" {
+                        line.clear();
+                        match stream.read_line(&mut line).await {
+                            Ok(0) | Err(_) => return,
+                            Ok(_) => {}
+                        }
+                    }
+                    let pieces: [&[u8]; 4] = [
+                        b"HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\n",
+                        b"8\r\nstream",
+                        b"ed\r\n9\r\n response\r\n",
+                        b"0\r\n\r\n",
+                    ];
+                    // Responses to HEAD requests only get the headers
+                    let num_pieces = if request_line.starts_with("HEAD") { 1 } else { pieces.len() };
+                    for piece in &pieces[..num_pieces] {
+                        if stream.write_all(piece).await.is_err() {
+                            return;
+                        }
+                        sleep(Duration::from_millis(50)).await;
+                    }
+                }
+            });
+        }
+    });
+    address
+}
+
+/// Chunked responses from the upstream should make it to the client intact
+#[tokio::test]
+async fn test_chunked_response() {
+    init_logging();
+    let upstream_address = start_chunked_upstream().await;
+    let balancebeam = BalanceBeam::new_with_args(&[&upstream_address], &[]).await;
+
+    let client = reqwest::Client::new();
+    for path in &["/first", "/second"] {
+        let response = client
+            .get(format!("http://{}{}", balancebeam.address, path))
+            .send()
+            .await
+            .expect("Error sending request to balancebeam");
+        assert_eq!(
+            response
+                .headers()
+                .get("transfer-encoding")
+                .map(|value| value.to_str().unwrap()),
+            Some("chunked")
+        );
+        let body = response
+            .text()
+            .await
+            .expect("Balancebeam replied with a malformed response");
+        assert_eq!(body, "streamed response");
+    }
+
+    // A HEAD response has the chunked header, but no body, and must not be given one. (If it were,
+    // the next response on the connection would be garbled.)
+    let response = client
+        .head(format!("http://{}/head", balancebeam.address))
+        .send()
+        .await
+        .expect("Error sending request to balancebeam");
+    assert_eq!(response.status(), http::StatusCode::OK);
+    let body = client
+        .get(format!("http://{}/after-head", balancebeam.address))
+        .send()
+        .await
+        .expect("Error sending request to balancebeam")
+        .text()
+        .await
+        .expect("Balancebeam replied with a malformed response");
+    assert_eq!(body, "streamed response");
+
+    log::info!("All done :)");
+}
@@ -0,0 +1,226 @@
+mod common;
+
+use common::{free_address, init_logging, setup_with_args, stop_all};
+use common::{BalanceBeam, EchoServer, Server};
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpListener;
+use tokio::time::sleep;
+
+fn admin_address() -> String {
+    free_address()
+}
+
+/// Starts an upstream that takes `delay` to answer each request, with an empty 200 response
+async fn start_slow_server(delay: Duration) -> String {
+    let listener = TcpListener::bind("127.0.0.1:0").await.expect("Could not bind slow server");
+    let address = listener.local_addr().unwrap().to_string();
+    tokio::spawn(async move {
+        while let Ok((mut stream, _)) = listener.accept().await {
+            tokio::spawn(async move {
+                let mut buffer = [0_u8; 1024];
+                if stream.read(&mut buffer).await.unwrap_or(0) == 0 {
+                    return;
+                }
+                sleep(delay).await;
+                let _ = stream.write_all(b"HTTP/1.1 200 OK\r\nContent-Length: 0\r\n\r\n").await;
+            });
+        }
+    });
+    address
+}
+
+async fn admin_post(admin_address: &str, path: &str, upstream: &str) -> reqwest::StatusCode {
+    reqwest::Client::new()
+        .post(format!("http://{}{}", admin_address, path))
+        .body(upstream.to_string())
+        .send()
+        .await
+        .expect("Error sending request to admin API")
+        .status()
+}
+
+async fn list_upstreams(admin_address: &str) -> Vec<serde_json::Value> {
+    reqwest::get(format!("http://{}/upstreams", admin_address))
+        .await
+        .expect("Error sending request to admin API")
+        .json()
+        .await
+        .expect("Admin API returned invalid JSON")
+}
+
+/// Upstreams added through the admin API should start receiving requests
+#[tokio::test]
+async fn test_admin_add_upstream() {
+    let admin = admin_address();
+    let (balancebeam, mut upstreams) = setup_with_args(2, &["--admin-bind", &admin]).await;
+
+    let listed = list_upstreams(&admin).await;
+    assert_eq!(listed.len(), 2);
+    for (upstream, info) in upstreams.iter().zip(&listed) {
+        assert_eq!(info["address"], upstream.address());
+        assert_eq!(info["healthy"], true);
+        assert_eq!(info["state"], "enabled");
+    }
+
+    let new_upstream = EchoServer::new().await;
+    assert_eq!(
+        admin_post(&admin, "/upstreams", &new_upstream.address).await,
+        reqwest::StatusCode::CREATED
+    );
+    assert_eq!(
+        admin_post(&admin, "/upstreams", &new_upstream.address).await,
+        reqwest::StatusCode::CONFLICT
+    );
+    assert_eq!(list_upstreams(&admin).await.len(), 3);
+    upstreams.push(Box::new(new_upstream));
+
+    for i in 0..9 {
+        let path = format!("/request-{}", i);
+        let response_text = balancebeam
+            .get(&path)
+            .await
+            .expect("Error sending request to balancebeam");
+        assert!(response_text.contains(&format!("GET {} HTTP/1.1", path)));
+    }
+
+    let request_counters = stop_all(upstreams).await;
+    assert_eq!(request_counters, vec![3, 3, 3]);
+
+    log::info!("All done :)");
+}
+
+/// Upstreams forced down through the admin API shouldn't get any requests until they're brought
+/// back up
+#[tokio::test]
+async fn test_admin_force_down() {
+    let admin = admin_address();
+    let (balancebeam, upstreams) = setup_with_args(2, &["--admin-bind", &admin]).await;
+    let down_address = upstreams[0].address();
+
+    assert_eq!(
+        admin_post(&admin, "/upstreams/down", &down_address).await,
+        reqwest::StatusCode::OK
+    );
+    assert_eq!(
+        admin_post(&admin, "/upstreams/down", "127.0.0.1:1").await,
+        reqwest::StatusCode::NOT_FOUND
+    );
+    assert_eq!(list_upstreams(&admin).await[0]["state"], "disabled");
+    for _ in 0..4 {
+        balancebeam
+            .get("/while-down")
+            .await
+            .expect("Error sending request to balancebeam");
+    }
+
+    assert_eq!(
+        admin_post(&admin, "/upstreams/up", &down_address).await,
+        reqwest::StatusCode::OK
+    );
+    for _ in 0..4 {
+        balancebeam
+            .get("/after-up")
+            .await
+            .expect("Error sending request to balancebeam");
+    }
+
+    let request_counters = stop_all(upstreams).await;
+    assert_eq!(request_counters, vec![2, 6]);
+
+    log::info!("All done :)");
+}
+
+/// Draining an upstream should stop it getting new requests, even from clients whose
+/// connections were going to it
+#[tokio::test]
+async fn test_admin_drain() {
+    let admin = admin_address();
+    let (balancebeam, upstreams) = setup_with_args(2, &["--admin-bind", &admin]).await;
+    let url = format!("http://{}/drain", balancebeam.address);
+
+    // This client keeps its connection to balancebeam open between requests. Round robin sends
+    // its first request to the second upstream.
+    let client = reqwest::Client::new();
+    let response = client.get(&url).send().await.expect("Error sending request to balancebeam");
+    assert_eq!(response.status(), reqwest::StatusCode::OK);
+    response.text().await.expect("Balancebeam replied with a malformed response");
+
+    assert_eq!(
+        admin_post(&admin, "/upstreams/drain", &upstreams[1].address()).await,
+        reqwest::StatusCode::OK
+    );
+    let listed = list_upstreams(&admin).await;
+    assert_eq!(listed[1]["state"], "draining");
+    assert_eq!(listed[1]["drained"], true);
+    assert_eq!(listed[0]["drained"], false);
+
+    for _ in 0..2 {
+        let response =
+            client.get(&url).send().await.expect("Error sending request to balancebeam");
+        assert_eq!(response.status(), reqwest::StatusCode::OK);
+        response.text().await.expect("Balancebeam replied with a malformed response");
+    }
+    let response = reqwest::get(&url).await.expect("Error sending request to balancebeam");
+    assert_eq!(response.status(), reqwest::StatusCode::OK);
+
+    let request_counters = stop_all(upstreams).await;
+    assert_eq!(request_counters, vec![3, 1]);
+
+    log::info!("All done :)");
+}
+
+/// A drained upstream should only be reported as drained once the requests in flight to it are
+/// done
+#[tokio::test]
+async fn test_admin_drain_waits_for_in_flight() {
+    init_logging();
+    let admin = admin_address();
+    let upstream = start_slow_server(Duration::from_secs(2)).await;
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&upstream],
+        &["--admin-bind", &admin, "--active-health-check-interval", "60"],
+    )
+    .await;
+
+    let url = format!("http://{}/slow", balancebeam.address);
+    let request = tokio::spawn(async move { reqwest::get(&url).await });
+    sleep(Duration::from_millis(500)).await;
+    assert_eq!(admin_post(&admin, "/upstreams/drain", &upstream).await, reqwest::StatusCode::OK);
+    let listed = list_upstreams(&admin).await;
+    assert_eq!(listed[0]["in_flight"], 1);
+    assert_eq!(listed[0]["drained"], false);
+
+    let response = request.await.unwrap().expect("Error sending request to balancebeam");
+    assert_eq!(response.status(), reqwest::StatusCode::OK);
+    let listed = list_upstreams(&admin).await;
+    assert_eq!(listed[0]["in_flight"], 0);
+    assert_eq!(listed[0]["drained"], true);
+
+    log::info!("All done :)");
+}
+
+/// Upstreams given with --drain shouldn't get any requests
+#[tokio::test]
+async fn test_drain_option() {
+    init_logging();
+    let admin = admin_address();
+    let upstreams: Vec<Box<dyn Server>> =
+        vec![Box::new(EchoServer::new().await), Box::new(EchoServer::new().await)];
+    let drained = upstreams[0].address();
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&drained, &upstreams[1].address()],
+        &["--admin-bind", &admin, "--drain", &drained],
+    )
+    .await;
+
+    assert_eq!(list_upstreams(&admin).await[0]["state"], "draining");
+    for _ in 0..4 {
+        balancebeam.get("/drained").await.expect("Error sending request to balancebeam");
+    }
+
+    let request_counters = stop_all(upstreams).await;
+    assert_eq!(request_counters, vec![0, 4]);
+
+    log::info!("All done :)");
+}
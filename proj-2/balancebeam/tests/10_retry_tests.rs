@@ -0,0 +1,70 @@
+mod common;
+
+use common::{init_logging, BalanceBeam, EchoServer, ErrorServer, Server};
+
+/// Idempotent requests that get a 5xx from one upstream should be retried on another one, so the
+/// client never sees the error
+#[tokio::test]
+async fn test_retry_on_server_error() {
+    init_logging();
+    let error_upstream = ErrorServer::new().await;
+    let echo_upstream = EchoServer::new().await;
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&error_upstream.address, &echo_upstream.address],
+        &["--max-retries", "1", "--active-health-check-interval", "60"],
+    )
+    .await;
+
+    for i in 0..6 {
+        let path = format!("/request-{}", i);
+        let response = reqwest::get(format!("http://{}{}", balancebeam.address, path))
+            .await
+            .expect("Error sending request to balancebeam");
+        assert_eq!(response.status(), reqwest::StatusCode::OK);
+        let response_text = response.text().await.unwrap();
+        assert!(response_text.contains(&format!("GET {} HTTP/1.1", path)));
+    }
+
+    let num_errors_returned = Box::new(error_upstream).stop().await;
+    assert!(num_errors_returned > 0, "The erroring upstream never got any requests");
+    let num_requests_received = Box::new(echo_upstream).stop().await;
+    assert_eq!(num_requests_received, 6);
+
+    log::info!("All done :)");
+}
+
+/// Non-idempotent requests must not be retried, since the upstream that failed may already have
+/// acted on them
+#[tokio::test]
+async fn test_no_retry_for_post() {
+    init_logging();
+    let error_upstream = ErrorServer::new().await;
+    let echo_upstream = EchoServer::new().await;
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&error_upstream.address, &echo_upstream.address],
+        &["--max-retries", "1", "--active-health-check-interval", "60"],
+    )
+    .await;
+
+    let mut num_errors = 0;
+    for i in 0..4 {
+        // Use a new connection each time, so the requests are spread across both upstreams
+        let response = reqwest::Client::new()
+            .post(format!("http://{}/post-{}", balancebeam.address, i))
+            .body("some data")
+            .send()
+            .await
+            .expect("Error sending request to balancebeam");
+        if response.status() == reqwest::StatusCode::INTERNAL_SERVER_ERROR {
+            num_errors += 1;
+        }
+    }
+    assert_eq!(num_errors, 2, "POST requests should go round-robin without being retried");
+
+    let num_errors_returned = Box::new(error_upstream).stop().await;
+    assert_eq!(num_errors_returned, 2);
+    let num_requests_received = Box::new(echo_upstream).stop().await;
+    assert_eq!(num_requests_received, 2);
+
+    log::info!("All done :)");
+}
@@ -0,0 +1,81 @@
+mod common;
+
+use common::{init_logging, BalanceBeam};
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::time::timeout;
+
+/// Starts an upstream that accepts connections and reads requests, but never answers them
+async fn start_stuck_server() -> String {
+    let listener = TcpListener::bind("127.0.0.1:0")
+        .await
+        .expect("Could not bind stuck server");
+    let address = listener.local_addr().unwrap().to_string();
+    tokio::spawn(async move {
+        while let Ok((mut stream, _)) = listener.accept().await {
+            tokio::spawn(async move {
+                let mut buffer = [0_u8; 1024];
+                while let Ok(n) = stream.read(&mut buffer).await {
+                    if n == 0 {
+                        break;
+                    }
+                }
+            });
+        }
+    });
+    address
+}
+
+/// An upstream that never responds should get the client a 504, rather than hanging forever
+#[tokio::test]
+async fn test_upstream_response_timeout() {
+    init_logging();
+    let upstream_address = start_stuck_server().await;
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&upstream_address],
+        &["--upstream-response-timeout", "1", "--active-health-check-interval", "60"],
+    )
+    .await;
+
+    let start = Instant::now();
+    let response = reqwest::get(format!("http://{}/stuck", balancebeam.address))
+        .await
+        .expect("Error sending request to balancebeam");
+    assert_eq!(response.status(), reqwest::StatusCode::GATEWAY_TIMEOUT);
+    assert!(
+        start.elapsed() < Duration::from_secs(5),
+        "balancebeam took {:?} to give up on the upstream",
+        start.elapsed()
+    );
+
+    log::info!("All done :)");
+}
+
+/// A client that never finishes sending its request should have its connection closed
+#[tokio::test]
+async fn test_client_read_timeout() {
+    init_logging();
+    let upstream_address = start_stuck_server().await;
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&upstream_address],
+        &["--client-read-timeout", "1", "--active-health-check-interval", "60"],
+    )
+    .await;
+
+    let mut stream = TcpStream::connect(&balancebeam.address)
+        .await
+        .expect("Could not connect to balancebeam");
+    stream
+        .write_all(b"GET /slow HTTP/1.1\r\nHost: localhost\r\n")
+        .await
+        .expect("Error sending request to balancebeam");
+    let mut buffer = [0_u8; 1024];
+    let n = timeout(Duration::from_secs(5), stream.read(&mut buffer))
+        .await
+        .expect("balancebeam didn't close the connection to a slow client")
+        .expect("Error reading from balancebeam");
+    assert_eq!(n, 0, "Expected balancebeam to hang up without a response");
+
+    log::info!("All done :)");
+}
@@ -0,0 +1,66 @@
+mod common;
+
+use common::{init_logging, BalanceBeam, EchoServer, ErrorServer, Server};
+use std::time::Duration;
+use tokio::time::sleep;
+
+/// Sends requests to balancebeam over new connections, so that each one goes through the load
+/// balancer. Returns the number of requests that failed.
+async fn count_errors(balancebeam: &BalanceBeam, n_requests: usize) -> usize {
+    let mut n_errors = 0;
+    for i in 0..n_requests {
+        let response = reqwest::get(format!("http://{}/request-{}", balancebeam.address, i))
+            .await
+            .expect("Error sending request to balancebeam");
+        if response.status().is_server_error() {
+            n_errors += 1;
+        }
+    }
+    n_errors
+}
+
+/// An upstream whose requests keep failing should have its circuit opened, and be given trial
+/// requests again once the cooldown is over:
+///
+/// * Put an upstream that only returns HTTP error 500s alongside a working one
+/// * Once two requests to it have failed, it shouldn't get any more
+/// * Replace it with a working server, and wait for the cooldown
+/// * The trial requests succeed, so it should be back in rotation
+#[tokio::test]
+async fn test_circuit_breaker() {
+    init_logging();
+    let echo_upstream = EchoServer::new().await;
+    let error_upstream = ErrorServer::new().await;
+    let error_address = error_upstream.address.clone();
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&error_address, &echo_upstream.address],
+        &[
+            "--circuit-breaker-error-rate",
+            "50",
+            "--circuit-breaker-window",
+            "2",
+            "--circuit-breaker-cooldown",
+            "2",
+            "--circuit-breaker-trial-requests",
+            "1",
+            "--active-health-check-interval",
+            "60",
+        ],
+    )
+    .await;
+
+    assert_eq!(count_errors(&balancebeam, 8).await, 2, "Circuit didn't open after 2 errors");
+    assert_eq!(Box::new(error_upstream).stop().await, 2);
+
+    log::info!("Replacing the failing upstream with a working one...");
+    let restored_upstream = EchoServer::new_at_address(error_address).await;
+    log::info!("Waiting for the circuit to half-open...");
+    sleep(Duration::from_secs(4)).await;
+
+    assert_eq!(count_errors(&balancebeam, 6).await, 0);
+    let num_requests_received = Box::new(restored_upstream).stop().await;
+    assert_eq!(num_requests_received, 3, "Upstream wasn't put back into rotation");
+
+    Box::new(echo_upstream).stop().await;
+    log::info!("All done :)");
+}
@@ -0,0 +1,78 @@
+mod common;
+
+use common::{init_logging, BalanceBeam, EchoServer, Server};
+
+/// Sends a request over a new connection with the given cookie, returning the value of the sticky
+/// cookie that balancebeam sets in the response, if any
+async fn get_with_cookie(balancebeam: &BalanceBeam, cookie: Option<&str>) -> Option<String> {
+    let mut request = reqwest::Client::new().get(format!("http://{}/sticky", balancebeam.address));
+    if let Some(cookie) = cookie {
+        request = request.header("Cookie", format!("other=1; lb={}", cookie));
+    }
+    let response = request.send().await.expect("Error sending request to balancebeam");
+    assert_eq!(response.status(), reqwest::StatusCode::OK);
+    response
+        .headers()
+        .get("set-cookie")
+        .map(|value| value.to_str().unwrap().to_string())
+        .and_then(|value| {
+            value
+                .split(';')
+                .next()
+                .and_then(|cookie| cookie.strip_prefix("lb="))
+                .map(|value| value.to_string())
+        })
+}
+
+/// Requests carrying the session cookie should keep going to the same upstream, and fall back to
+/// another upstream (with a new cookie) once that one goes down
+#[tokio::test]
+async fn test_sticky_sessions() {
+    init_logging();
+    let mut upstreams: Vec<Box<dyn Server>> =
+        vec![Box::new(EchoServer::new().await), Box::new(EchoServer::new().await)];
+    let upstream_addresses: Vec<String> = upstreams.iter().map(|u| u.address()).collect();
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&upstream_addresses[0], &upstream_addresses[1]],
+        &["--sticky-cookie", "lb", "--active-health-check-interval", "60"],
+    )
+    .await;
+
+    let cookie = get_with_cookie(&balancebeam, None)
+        .await
+        .expect("balancebeam didn't set the sticky cookie");
+    for _ in 0..5 {
+        assert_eq!(
+            get_with_cookie(&balancebeam, Some(&cookie)).await,
+            None,
+            "balancebeam replaced a cookie that was still valid"
+        );
+    }
+
+    // Find out which upstream the cookie pins us to, and take it down
+    let mut pinned = None;
+    for (idx, upstream) in upstreams.drain(..).enumerate().collect::<Vec<_>>() {
+        let num_requests_received = upstream.stop().await;
+        if num_requests_received > 0 {
+            assert_eq!(num_requests_received, 6, "Requests weren't all sent to one upstream");
+            pinned = Some(idx);
+        } else {
+            upstreams.push(Box::new(
+                EchoServer::new_at_address(upstream_addresses[idx].clone()).await,
+            ));
+        }
+    }
+    let pinned = pinned.expect("No upstream received any requests");
+    log::info!("Requests were pinned to upstream {}", upstream_addresses[pinned]);
+
+    let new_cookie = get_with_cookie(&balancebeam, Some(&cookie))
+        .await
+        .expect("balancebeam didn't move the client to a new upstream");
+    assert_ne!(new_cookie, cookie);
+    assert_eq!(get_with_cookie(&balancebeam, Some(&new_cookie)).await, None);
+
+    let num_requests_received = upstreams.pop().unwrap().stop().await;
+    assert_eq!(num_requests_received, 2);
+
+    log::info!("All done :)");
+}
@@ -0,0 +1,105 @@
+mod common;
+
+use common::{init_logging, write_config, BalanceBeam, Behavior, EchoServer, MockServer, Server};
+
+/// Requests should be sent to the pool their path's route points to, and everything else to the
+/// default upstreams. This should hold even when one client connection makes requests for
+/// several routes.
+#[tokio::test]
+async fn test_path_prefix_routing() {
+    init_logging();
+    let default_upstream = EchoServer::new().await;
+    let api_upstream = EchoServer::new().await;
+    let static_upstream = EchoServer::new().await;
+    let api_pool = format!("api={}", api_upstream.address);
+    let static_pool = format!("static={}", static_upstream.address);
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&default_upstream.address],
+        &[
+            "--pool",
+            &api_pool,
+            "--pool",
+            &static_pool,
+            "--route",
+            "/api=api",
+            "--route",
+            "/static/=static",
+            "--active-health-check-interval",
+            "60",
+        ],
+    )
+    .await;
+
+    // This client keeps its connection to balancebeam open between requests
+    let client = reqwest::Client::new();
+    for path in &["/api", "/api/users", "/static/logo.png", "/apis", "/", "/api/again"] {
+        let response_text = client
+            .get(format!("http://{}{}", balancebeam.address, path))
+            .send()
+            .await
+            .expect("Error sending request to balancebeam")
+            .text()
+            .await
+            .expect("Balancebeam replied with a malformed response");
+        assert!(response_text.contains(&format!("GET {} HTTP/1.1", path)));
+    }
+
+    assert_eq!(Box::new(api_upstream).stop().await, 3);
+    assert_eq!(Box::new(static_upstream).stop().await, 1);
+    assert_eq!(Box::new(default_upstream).stop().await, 2);
+
+    log::info!("All done :)");
+}
+
+/// Requests from a client in a client route's range should go to that route's pool, whatever
+/// their path, with the narrowest matching range winning
+#[tokio::test]
+async fn test_client_routes() {
+    init_logging();
+    let default_upstream = MockServer::new("default", Behavior::Healthy).await;
+    let api_upstream = MockServer::new("api", Behavior::Healthy).await;
+    let staging_upstream = MockServer::new("staging", Behavior::Healthy).await;
+    let local_upstream = MockServer::new("local", Behavior::Healthy).await;
+    let pools = format!(
+        "upstreams = [\"{}\"]\n\
+         [pools]\n\
+         api = [\"{}\"]\n\
+         staging = [\"{}\"]\n\
+         local = [\"{}\"]\n\
+         [routes]\n\
+         \"/api\" = \"api\"\n",
+        default_upstream.address,
+        api_upstream.address,
+        staging_upstream.address,
+        local_upstream.address
+    );
+    let get = |balancebeam: &BalanceBeam, path: &str| {
+        let url = format!("http://{}{}", balancebeam.address, path);
+        async move { reqwest::get(url).await.unwrap().text().await.unwrap() }
+    };
+
+    // Clients outside the ranges are routed by path as usual
+    let config_path = write_config(&format!(
+        "{}[client-routes]\n\"10.0.0.0/8\" = \"staging\"\n",
+        pools
+    ));
+    let args = ["--config", config_path.to_str().unwrap(), "--active-health-check-interval", "60"];
+    let balancebeam = BalanceBeam::new_with_args(&[], &args).await;
+    assert_eq!(get(&balancebeam, "/").await, "default");
+    assert_eq!(get(&balancebeam, "/api/users").await, "api");
+    drop(balancebeam);
+    let _ = std::fs::remove_file(&config_path);
+
+    // The tests connect from 127.0.0.1
+    let config_path = write_config(&format!(
+        "{}[client-routes]\n\"127.0.0.0/8\" = \"staging\"\n\"127.0.0.1/32\" = \"local\"\n",
+        pools
+    ));
+    let args = ["--config", config_path.to_str().unwrap(), "--active-health-check-interval", "60"];
+    let balancebeam = BalanceBeam::new_with_args(&[], &args).await;
+    assert_eq!(get(&balancebeam, "/").await, "local");
+    assert_eq!(get(&balancebeam, "/api/users").await, "local");
+    let _ = std::fs::remove_file(&config_path);
+
+    log::info!("All done :)");
+}
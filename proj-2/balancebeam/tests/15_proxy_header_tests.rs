@@ -0,0 +1,64 @@
+mod common;
+
+use common::{init_logging, BalanceBeam, EchoServer, Server};
+
+/// Sends a request through balancebeam, returning the Via header of the response and the request
+/// as the upstream saw it
+async fn get_through_proxy(balancebeam: &BalanceBeam) -> (Option<String>, String) {
+    let response = reqwest::get(format!("http://{}/headers", balancebeam.address))
+        .await
+        .expect("Error sending request to balancebeam");
+    assert_eq!(response.status(), reqwest::StatusCode::OK);
+    let via = response
+        .headers()
+        .get("via")
+        .map(|value| value.to_str().unwrap().to_string());
+    let request_text = response
+        .text()
+        .await
+        .expect("Balancebeam replied with a malformed response");
+    (via, request_text)
+}
+
+/// Forwarded requests should say how the client reached balancebeam, and both requests and
+/// responses should say they went through a proxy
+#[tokio::test]
+async fn test_proxy_headers() {
+    init_logging();
+    let upstream = EchoServer::new().await;
+    let balancebeam = BalanceBeam::new_with_args(&[&upstream.address], &[]).await;
+    let port = balancebeam.address.rsplit(':').next().unwrap();
+
+    let (via, request_text) = get_through_proxy(&balancebeam).await;
+    assert_eq!(via.as_deref(), Some("1.1 balancebeam"));
+    assert!(request_text.contains("x-forwarded-for: 127.0.0.1"));
+    assert!(request_text.contains("x-forwarded-proto: http\n"));
+    assert!(request_text.contains(&format!("x-forwarded-port: {}\n", port)));
+    assert!(request_text.contains(&format!("x-forwarded-host: {}\n", balancebeam.address)));
+    assert!(request_text.contains("via: 1.1 balancebeam\n"));
+
+    Box::new(upstream).stop().await;
+    log::info!("All done :)");
+}
+
+/// Headers turned off with --disable-proxy-header shouldn't be added
+#[tokio::test]
+async fn test_disable_proxy_headers() {
+    init_logging();
+    let upstream = EchoServer::new().await;
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&upstream.address],
+        &["--disable-proxy-header", "via", "--disable-proxy-header", "x-forwarded-host"],
+    )
+    .await;
+
+    let (via, request_text) = get_through_proxy(&balancebeam).await;
+    assert_eq!(via, None);
+    assert!(!request_text.contains("via:"));
+    assert!(!request_text.contains("x-forwarded-host:"));
+    assert!(request_text.contains("x-forwarded-for: 127.0.0.1"));
+    assert!(request_text.contains("x-forwarded-proto: http\n"));
+
+    Box::new(upstream).stop().await;
+    log::info!("All done :)");
+}
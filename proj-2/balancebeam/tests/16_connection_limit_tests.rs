@@ -0,0 +1,136 @@
+mod common;
+
+use common::{init_logging, BalanceBeam, EchoServer, Server};
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::time::sleep;
+
+/// Opens a connection to balancebeam and makes a request on it, leaving the connection open
+async fn open_connection(balancebeam: &BalanceBeam) -> TcpStream {
+    let mut stream = TcpStream::connect(&balancebeam.address)
+        .await
+        .expect("Could not connect to balancebeam");
+    stream
+        .write_all(b"GET /held-open HTTP/1.1\r\nHost: localhost\r\n\r\n")
+        .await
+        .expect("Error sending request to balancebeam");
+    let mut buffer = [0_u8; 1024];
+    let n = stream
+        .read(&mut buffer)
+        .await
+        .expect("Error reading response from balancebeam");
+    assert!(buffer[..n].starts_with(b"HTTP/1.1 200"));
+    stream
+}
+
+/// Starts an upstream that takes a second to answer each request
+async fn start_slow_server() -> String {
+    let listener = TcpListener::bind("127.0.0.1:0").await.expect("Could not bind slow server");
+    let address = listener.local_addr().unwrap().to_string();
+    tokio::spawn(async move {
+        while let Ok((mut stream, _)) = listener.accept().await {
+            tokio::spawn(async move {
+                let mut buffer = [0_u8; 1024];
+                if stream.read(&mut buffer).await.unwrap_or(0) == 0 {
+                    return;
+                }
+                sleep(Duration::from_secs(1)).await;
+                let _ = stream.write_all(b"HTTP/1.1 200 OK\r\nContent-Length: 0\r\n\r\n").await;
+            });
+        }
+    });
+    address
+}
+
+async fn get_status(balancebeam: &BalanceBeam) -> reqwest::StatusCode {
+    reqwest::get(format!("http://{}/request", balancebeam.address))
+        .await
+        .expect("Error sending request to balancebeam")
+        .status()
+}
+
+/// Connections beyond the total limit should be turned away until others close
+#[tokio::test]
+async fn test_max_connections() {
+    init_logging();
+    let upstream = EchoServer::new().await;
+    let balancebeam =
+        BalanceBeam::new_with_args(&[&upstream.address], &["--max-connections", "2"]).await;
+
+    let first = open_connection(&balancebeam).await;
+    let _second = open_connection(&balancebeam).await;
+    assert_eq!(get_status(&balancebeam).await, reqwest::StatusCode::SERVICE_UNAVAILABLE);
+
+    drop(first);
+    sleep(Duration::from_millis(200)).await;
+    assert_eq!(get_status(&balancebeam).await, reqwest::StatusCode::OK);
+
+    Box::new(upstream).stop().await;
+    log::info!("All done :)");
+}
+
+/// A client IP shouldn't be able to hold open more connections than the per-IP limit
+#[tokio::test]
+async fn test_max_connections_per_ip() {
+    init_logging();
+    let upstream = EchoServer::new().await;
+    let balancebeam =
+        BalanceBeam::new_with_args(&[&upstream.address], &["--max-connections-per-ip", "1"])
+            .await;
+
+    let held = open_connection(&balancebeam).await;
+    assert_eq!(get_status(&balancebeam).await, reqwest::StatusCode::SERVICE_UNAVAILABLE);
+
+    drop(held);
+    sleep(Duration::from_millis(200)).await;
+    assert_eq!(get_status(&balancebeam).await, reqwest::StatusCode::OK);
+
+    Box::new(upstream).stop().await;
+    log::info!("All done :)");
+}
+
+/// A client IP shouldn't be able to have more requests in progress at once than the per-IP limit
+#[tokio::test]
+async fn test_max_concurrent_per_ip() {
+    init_logging();
+    let upstream = start_slow_server().await;
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&upstream],
+        &["--max-concurrent-per-ip", "2", "--active-health-check-interval", "60"],
+    )
+    .await;
+
+    let url = format!("http://{}/slow", balancebeam.address);
+    let requests: Vec<_> = (0..3)
+        .map(|_| {
+            let url = url.clone();
+            tokio::spawn(async move { reqwest::get(&url).await.unwrap().status() })
+        })
+        .collect();
+    let mut statuses = Vec::new();
+    for request in requests {
+        statuses.push(request.await.unwrap().as_u16());
+    }
+    statuses.sort();
+    assert_eq!(statuses, vec![200, 200, 429]);
+
+    // Once they're answered, the client may send more
+    assert_eq!(get_status(&balancebeam).await, reqwest::StatusCode::OK);
+    log::info!("All done :)");
+}
+
+/// Connections kept open between requests shouldn't count against the per-IP request limit
+#[tokio::test]
+async fn test_max_concurrent_per_ip_ignores_idle_connections() {
+    init_logging();
+    let upstream = EchoServer::new().await;
+    let balancebeam =
+        BalanceBeam::new_with_args(&[&upstream.address], &["--max-concurrent-per-ip", "1"]).await;
+
+    let _held = open_connection(&balancebeam).await;
+    assert_eq!(get_status(&balancebeam).await, reqwest::StatusCode::OK);
+
+    Box::new(upstream).stop().await;
+    log::info!("All done :)");
+}
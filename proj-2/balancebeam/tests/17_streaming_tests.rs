@@ -0,0 +1,195 @@
+mod common;
+
+use common::{init_logging, setup_with_args, stop_all, BalanceBeam};
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::{mpsc, oneshot};
+use tokio::time::timeout;
+
+/// Starts an upstream that answers one request, sending the first half of its response body right
+/// away and the second half only once `finish` fires
+async fn start_half_done_upstream(finish: oneshot::Receiver<()>) -> String {
+    let listener = TcpListener::bind("127.0.0.1:0")
+        .await
+        .expect("Could not bind upstream");
+    let address = listener.local_addr().unwrap().to_string();
+    tokio::spawn(async move {
+        let (mut stream, _) = listener.accept().await.unwrap();
+        let mut buffer = [0_u8; 1024];
+        let _ = stream.read(&mut buffer).await;
+        let _ = stream
+            .write_all(b"HTTP/1.1 200 OK\r\nContent-Length: 11\r\n\r\nhello")
+            .await;
+        let _ = finish.await;
+        let _ = stream.write_all(b" world").await;
+    });
+    address
+}
+
+/// Starts an upstream that passes along everything it receives, and never answers
+async fn start_recording_upstream(received: mpsc::UnboundedSender<Vec<u8>>) -> String {
+    let listener = TcpListener::bind("127.0.0.1:0")
+        .await
+        .expect("Could not bind upstream");
+    let address = listener.local_addr().unwrap().to_string();
+    tokio::spawn(async move {
+        let (mut stream, _) = listener.accept().await.unwrap();
+        let mut buffer = [0_u8; 1024];
+        while let Ok(n) = stream.read(&mut buffer).await {
+            if n == 0 || received.send(buffer[..n].to_vec()).is_err() {
+                break;
+            }
+        }
+    });
+    address
+}
+
+/// Request bodies bigger than balancebeam would ever buffer should make it to the upstream and
+/// back intact
+#[tokio::test]
+async fn test_large_body() {
+    let (balancebeam, upstreams) = setup_with_args(1, &[]).await;
+
+    let body: Vec<u8> = (0..12_000_000_u32).map(|i| (i % 251) as u8).collect();
+    let response = reqwest::Client::new()
+        .post(format!("http://{}/upload", balancebeam.address))
+        .body(body.clone())
+        .send()
+        .await
+        .expect("Error sending request to balancebeam");
+    assert_eq!(response.status(), reqwest::StatusCode::OK);
+    let echoed = response.bytes().await.expect("Error reading response body");
+    assert!(
+        echoed.ends_with(&body),
+        "The upstream didn't get (or echo back) the whole body"
+    );
+
+    stop_all(upstreams).await;
+    log::info!("All done :)");
+}
+
+/// The client should get the start of a response body before the upstream has sent all of it
+#[tokio::test]
+async fn test_response_body_streamed() {
+    init_logging();
+    let (finish_tx, finish_rx) = oneshot::channel();
+    let upstream_address = start_half_done_upstream(finish_rx).await;
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&upstream_address],
+        &["--active-health-check-interval", "60"],
+    )
+    .await;
+
+    let mut response = reqwest::get(format!("http://{}/stream", balancebeam.address))
+        .await
+        .expect("Error sending request to balancebeam");
+    let first = timeout(Duration::from_secs(3), response.chunk())
+        .await
+        .expect("balancebeam held back the body until the upstream finished it")
+        .expect("Error reading response body");
+    assert_eq!(first.as_deref(), Some(&b"hello"[..]));
+
+    finish_tx.send(()).unwrap();
+    let rest = response.bytes().await.expect("Error reading response body");
+    assert_eq!(&rest[..], b" world");
+
+    log::info!("All done :)");
+}
+
+/// The upstream should get the start of a request body before the client has sent all of it
+#[tokio::test]
+async fn test_request_body_streamed() {
+    init_logging();
+    let (received_tx, mut received_rx) = mpsc::unbounded_channel();
+    let upstream_address = start_recording_upstream(received_tx).await;
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&upstream_address],
+        &["--active-health-check-interval", "60"],
+    )
+    .await;
+
+    let mut stream = TcpStream::connect(&balancebeam.address)
+        .await
+        .expect("Could not connect to balancebeam");
+    stream
+        .write_all(b"POST /upload HTTP/1.1\r\nHost: localhost\r\nContent-Length: 11\r\n\r\nhello")
+        .await
+        .expect("Error sending request to balancebeam");
+
+    let mut received = Vec::new();
+    while !received.ends_with(b"\r\n\r\nhello") {
+        let piece = timeout(Duration::from_secs(3), received_rx.recv())
+            .await
+            .expect("balancebeam held back the body until the client finished it")
+            .expect("Upstream connection closed");
+        received.extend_from_slice(&piece);
+    }
+
+    log::info!("All done :)");
+}
+
+/// A client that sends `Expect: 100-continue` should be told to go ahead with its body, and the
+/// upstream should get the head without waiting for the body
+#[tokio::test]
+async fn test_expect_continue() {
+    init_logging();
+    let (received_tx, mut received_rx) = mpsc::unbounded_channel();
+    let upstream_address = start_recording_upstream(received_tx).await;
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&upstream_address],
+        &["--active-health-check-interval", "60"],
+    )
+    .await;
+
+    let mut stream = TcpStream::connect(&balancebeam.address)
+        .await
+        .expect("Could not connect to balancebeam");
+    stream
+        .write_all(
+            b"POST /upload HTTP/1.1\r\nHost: localhost\r\nTransfer-Encoding: chunked\r\n\
+              Expect: 100-continue\r\n\r\n",
+        )
+        .await
+        .expect("Error sending request to balancebeam");
+
+    let mut response = Vec::new();
+    while !response.ends_with(b"\r\n\r\n") {
+        let mut buffer = [0_u8; 1024];
+        let n = timeout(Duration::from_secs(3), stream.read(&mut buffer))
+            .await
+            .expect("balancebeam never told the client to go ahead")
+            .expect("Error reading from balancebeam");
+        assert!(n > 0, "balancebeam hung up");
+        response.extend_from_slice(&buffer[..n]);
+    }
+    assert!(
+        response.starts_with(b"HTTP/1.1 100 Continue"),
+        "Unexpected response: {}",
+        String::from_utf8_lossy(&response)
+    );
+
+    let mut received = Vec::new();
+    while !received.ends_with(b"\r\n\r\n") {
+        let piece = timeout(Duration::from_secs(3), received_rx.recv())
+            .await
+            .expect("balancebeam held back the head until the body arrived")
+            .expect("Upstream connection closed");
+        received.extend_from_slice(&piece);
+    }
+    assert!(received.starts_with(b"POST /upload"));
+
+    stream
+        .write_all(b"5\r\nhello\r\n0\r\n\r\n")
+        .await
+        .expect("Error sending body to balancebeam");
+    while !received.ends_with(b"hello\r\n0\r\n\r\n") {
+        let piece = timeout(Duration::from_secs(3), received_rx.recv())
+            .await
+            .expect("The body never reached the upstream")
+            .expect("Upstream connection closed");
+        received.extend_from_slice(&piece);
+    }
+
+    log::info!("All done :)");
+}
@@ -0,0 +1,58 @@
+mod common;
+
+use common::{get_status, setup_with_args, stop_all};
+use std::time::{Duration, Instant};
+
+/// Requests over an upstream's rate limit should be answered with 503, without reaching it
+#[tokio::test]
+async fn test_upstream_rate_limit_rejects() {
+    let (balancebeam, upstreams) = setup_with_args(
+        1,
+        &["--max-upstream-rps", "2", "--active-health-check-interval", "60"],
+    )
+    .await;
+
+    let mut statuses = Vec::new();
+    for i in 0..4 {
+        statuses.push(get_status(&balancebeam.address, &format!("/request-{}", i)).await);
+    }
+    assert_eq!(statuses, vec![200, 200, 503, 503]);
+
+    let request_counters = stop_all(upstreams).await;
+    assert_eq!(request_counters, vec![2]);
+
+    log::info!("All done :)");
+}
+
+/// With a queue timeout, requests over the rate limit should wait their turn instead
+#[tokio::test]
+async fn test_upstream_rate_limit_queues() {
+    let (balancebeam, upstreams) = setup_with_args(
+        1,
+        &[
+            "--max-upstream-rps",
+            "2",
+            "--upstream-queue-timeout",
+            "5",
+            "--active-health-check-interval",
+            "60",
+        ],
+    )
+    .await;
+
+    let start = Instant::now();
+    for i in 0..4 {
+        assert_eq!(get_status(&balancebeam.address, &format!("/request-{}", i)).await, 200);
+    }
+    // The first two go out right away, and the other two half a second apart after them
+    assert!(
+        start.elapsed() >= Duration::from_millis(900),
+        "Requests weren't held back: all 4 took {:?}",
+        start.elapsed()
+    );
+
+    let request_counters = stop_all(upstreams).await;
+    assert_eq!(request_counters, vec![4]);
+
+    log::info!("All done :)");
+}
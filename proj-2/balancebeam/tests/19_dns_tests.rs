@@ -0,0 +1,59 @@
+mod common;
+
+use common::{free_address, init_logging, BalanceBeam, EchoServer, Server};
+
+async fn list_upstreams(admin_address: &str) -> Vec<serde_json::Value> {
+    reqwest::get(format!("http://{}/upstreams", admin_address))
+        .await
+        .expect("Error sending request to admin API")
+        .json()
+        .await
+        .expect("Admin API returned invalid JSON")
+}
+
+/// An upstream given by host name should be expanded into a backend for the address it resolves
+/// to, and the admin API should be able to act on all of a host name's backends at once
+#[tokio::test]
+async fn test_upstream_host_name_resolved() {
+    init_logging();
+    let upstream = EchoServer::new().await;
+    let port = upstream.address.rsplit(':').next().unwrap();
+    let host_name_upstream = format!("localhost:{}", port);
+    let admin = free_address();
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&host_name_upstream],
+        &["--dns-refresh-interval", "60", "--admin-bind", &admin],
+    )
+    .await;
+
+    let listed = list_upstreams(&admin).await;
+    let backend = format!("{}@127.0.0.1:{}", host_name_upstream, port);
+    assert!(
+        listed.iter().any(|info| info["address"] == backend.as_str()),
+        "Expected {} among the backends, got {:?}",
+        backend,
+        listed
+    );
+
+    let response = balancebeam
+        .get("/resolved")
+        .await
+        .expect("Error sending request to balancebeam");
+    assert!(response.starts_with("GET /resolved HTTP/1.1"));
+
+    log::info!("Taking down every backend of the host name");
+    let status = reqwest::Client::new()
+        .post(format!("http://{}/upstreams/down", admin))
+        .body(host_name_upstream.clone())
+        .send()
+        .await
+        .expect("Error sending request to admin API")
+        .status();
+    assert_eq!(status, reqwest::StatusCode::OK);
+    for info in list_upstreams(&admin).await {
+        assert_eq!(info["state"], "disabled");
+    }
+
+    Box::new(upstream).stop().await;
+    log::info!("All done :)");
+}
@@ -0,0 +1,81 @@
+mod common;
+
+use common::{init_logging, BalanceBeam, EchoServer, Server};
+
+async fn get_status(balancebeam: &BalanceBeam) -> reqwest::StatusCode {
+    reqwest::get(format!("http://{}/request", balancebeam.address))
+        .await
+        .expect("Error sending request to balancebeam")
+        .status()
+}
+
+/// Clients in a denied range should be answered with 403, without reaching the upstream
+#[tokio::test]
+async fn test_deny_cidr() {
+    init_logging();
+    let upstream = EchoServer::new().await;
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&upstream.address],
+        &["--deny-cidr", "127.0.0.0/8", "--active-health-check-interval", "60"],
+    )
+    .await;
+
+    assert_eq!(get_status(&balancebeam).await, reqwest::StatusCode::FORBIDDEN);
+
+    assert_eq!(Box::new(upstream).stop().await, 0);
+    log::info!("All done :)");
+}
+
+/// Once any ranges are allowed, clients outside all of them should be answered with 403
+#[tokio::test]
+async fn test_allow_cidr() {
+    init_logging();
+    let upstream = EchoServer::new().await;
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&upstream.address],
+        &["--allow-cidr", "10.0.0.0/8", "--active-health-check-interval", "60"],
+    )
+    .await;
+    assert_eq!(get_status(&balancebeam).await, reqwest::StatusCode::FORBIDDEN);
+
+    let allowed = BalanceBeam::new_with_args(
+        &[&upstream.address],
+        &[
+            "--allow-cidr",
+            "10.0.0.0/8",
+            "--allow-cidr",
+            "127.0.0.1",
+            "--active-health-check-interval",
+            "60",
+        ],
+    )
+    .await;
+    assert_eq!(get_status(&allowed).await, reqwest::StatusCode::OK);
+
+    assert_eq!(Box::new(upstream).stop().await, 1);
+    log::info!("All done :)");
+}
+
+/// A deny range takes precedence over an allow range that overlaps it
+#[tokio::test]
+async fn test_deny_overrides_allow() {
+    init_logging();
+    let upstream = EchoServer::new().await;
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&upstream.address],
+        &[
+            "--allow-cidr",
+            "127.0.0.0/8",
+            "--deny-cidr",
+            "127.0.0.1/32",
+            "--active-health-check-interval",
+            "60",
+        ],
+    )
+    .await;
+
+    assert_eq!(get_status(&balancebeam).await, reqwest::StatusCode::FORBIDDEN);
+
+    Box::new(upstream).stop().await;
+    log::info!("All done :)");
+}
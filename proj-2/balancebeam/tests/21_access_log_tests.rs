@@ -0,0 +1,201 @@
+mod common;
+
+use common::{free_address, init_logging, BalanceBeam, EchoServer, Server};
+use std::time::Duration;
+use tokio::time::sleep;
+
+/// Returns a path in the temp directory for a test's access log, removing any left over from an
+/// earlier run
+fn access_log_path(name: &str) -> std::path::PathBuf {
+    let file_name = format!("balancebeam-{}-{}.log", name, std::process::id());
+    let path = std::env::temp_dir().join(file_name);
+    let _ = std::fs::remove_file(&path);
+    path
+}
+
+/// Reads the access log, parsing each line as JSON
+fn read_access_log(path: &std::path::Path) -> Vec<serde_json::Value> {
+    std::fs::read_to_string(path)
+        .expect("Could not read access log")
+        .lines()
+        .map(|line| serde_json::from_str(line).expect("Access log line isn't valid JSON"))
+        .collect()
+}
+
+/// Each request should be logged as a line of JSON describing it
+#[tokio::test]
+async fn test_access_log() {
+    init_logging();
+    let path = access_log_path("access-log");
+    let upstream = EchoServer::new().await;
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&upstream.address],
+        &["--access-log", path.to_str().unwrap(), "--active-health-check-interval", "60"],
+    )
+    .await;
+
+    let response_text = balancebeam
+        .get("/logged")
+        .await
+        .expect("Error sending request to balancebeam");
+    sleep(Duration::from_millis(100)).await;
+
+    let entries = read_access_log(&path);
+    assert_eq!(entries.len(), 1, "Expected one access log entry, got {:?}", entries);
+    let entry = &entries[0];
+    assert_eq!(entry["client_ip"], "127.0.0.1");
+    assert_eq!(entry["method"], "GET");
+    assert_eq!(entry["path"], "/logged");
+    assert_eq!(entry["upstream"], upstream.address.as_str());
+    assert_eq!(entry["status"], 200);
+    assert_eq!(entry["bytes"], response_text.len() as u64);
+    assert!(entry["latency_ms"].is_f64());
+    assert!(entry["timestamp"].is_string());
+
+    Box::new(upstream).stop().await;
+    let _ = std::fs::remove_file(&path);
+    log::info!("All done :)");
+}
+
+/// Requests that balancebeam answers itself, because the upstream couldn't, should be logged too
+#[tokio::test]
+async fn test_access_log_upstream_error() {
+    init_logging();
+    let path = access_log_path("access-log-error");
+    let dead_upstream = free_address();
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&dead_upstream],
+        &["--access-log", path.to_str().unwrap(), "--active-health-check-interval", "60"],
+    )
+    .await;
+
+    let status = reqwest::get(format!("http://{}/unlucky", balancebeam.address))
+        .await
+        .expect("Error sending request to balancebeam")
+        .status();
+    assert_eq!(status, reqwest::StatusCode::BAD_GATEWAY);
+    sleep(Duration::from_millis(100)).await;
+
+    let entries = read_access_log(&path);
+    assert_eq!(entries.len(), 1, "Expected one access log entry, got {:?}", entries);
+    assert_eq!(entries[0]["path"], "/unlucky");
+    assert_eq!(entries[0]["status"], 502);
+    assert!(entries[0]["upstream"].is_null());
+
+    let _ = std::fs::remove_file(&path);
+    log::info!("All done :)");
+}
+
+/// Sends requests one after another, giving balancebeam time to log each of them
+async fn send_requests(balancebeam: &BalanceBeam, count: usize) {
+    for i in 0..count {
+        balancebeam
+            .get(&format!("/request-{}", i))
+            .await
+            .expect("Error sending request to balancebeam");
+    }
+    sleep(Duration::from_millis(200)).await;
+}
+
+/// Returns the paths of the current access log and the rotated files after it, removing any left
+/// over from an earlier run
+fn rotated_paths(path: &std::path::Path, suffix: &str) -> Vec<std::path::PathBuf> {
+    let paths: Vec<_> = (1..=4)
+        .map(|n| std::path::PathBuf::from(format!("{}.{}{}", path.display(), n, suffix)))
+        .collect();
+    for path in &paths {
+        let _ = std::fs::remove_file(path);
+    }
+    paths
+}
+
+/// The access log should be rolled over once it would grow past its maximum size, keeping only as
+/// many rotated files as it's told to
+#[tokio::test]
+async fn test_access_log_size_rotation() {
+    init_logging();
+    let path = access_log_path("access-log-rotation");
+    let rotated = rotated_paths(&path, "");
+    let upstream = EchoServer::new().await;
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&upstream.address],
+        &[
+            "--access-log",
+            path.to_str().unwrap(),
+            "--access-log-max-size",
+            "500",
+            "--access-log-keep",
+            "2",
+            "--active-health-check-interval",
+            "60",
+        ],
+    )
+    .await;
+
+    send_requests(&balancebeam, 12).await;
+
+    // Each file holds whole lines, and never goes over the limit
+    let mut entries = Vec::new();
+    for path in [&rotated[1], &rotated[0], &path] {
+        assert!(std::fs::metadata(path).expect("Missing access log file").len() <= 500);
+        entries.extend(read_access_log(path));
+    }
+    assert!(!rotated[2].exists(), "Kept more rotated files than asked for");
+    // Going from .2 to .1 to the current file, the entries run from oldest to newest, with the
+    // oldest ones gone along with the files they were in
+    let numbers: Vec<usize> = entries
+        .iter()
+        .map(|entry| entry["path"].as_str().unwrap()["/request-".len()..].parse().unwrap())
+        .collect();
+    assert!(numbers.windows(2).all(|pair| pair[0] + 1 == pair[1]), "Got {:?}", numbers);
+    assert_eq!(numbers.last(), Some(&11));
+    assert!(numbers[0] > 0);
+
+    Box::new(upstream).stop().await;
+    for path in std::iter::once(&path).chain(&rotated) {
+        let _ = std::fs::remove_file(path);
+    }
+    log::info!("All done :)");
+}
+
+/// Rotated files should be gzipped when asked for
+#[tokio::test]
+async fn test_access_log_compressed_rotation() {
+    init_logging();
+    let path = access_log_path("access-log-compressed");
+    let rotated = rotated_paths(&path, ".gz");
+    let upstream = EchoServer::new().await;
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&upstream.address],
+        &[
+            "--access-log",
+            path.to_str().unwrap(),
+            "--access-log-max-size",
+            "500",
+            "--access-log-compress",
+            "--active-health-check-interval",
+            "60",
+        ],
+    )
+    .await;
+
+    send_requests(&balancebeam, 6).await;
+
+    let compressed = std::fs::File::open(&rotated[0]).expect("Rotated file wasn't compressed");
+    let mut text = String::new();
+    std::io::Read::read_to_string(&mut flate2::read::GzDecoder::new(compressed), &mut text)
+        .expect("Rotated file isn't valid gzip");
+    assert!(text.lines().count() > 0);
+    for line in text.lines() {
+        let entry: serde_json::Value = serde_json::from_str(line).expect("Line isn't valid JSON");
+        assert_eq!(entry["status"], 200);
+    }
+    let uncompressed = format!("{}.1", path.display());
+    assert!(!std::path::Path::new(&uncompressed).exists(), "Uncompressed copy was left behind");
+
+    Box::new(upstream).stop().await;
+    for path in std::iter::once(&path).chain(&rotated) {
+        let _ = std::fs::remove_file(path);
+    }
+    log::info!("All done :)");
+}
@@ -0,0 +1,48 @@
+mod common;
+
+use balancebeam::{ArgLoadBalance, Proxy};
+use common::{free_address, init_logging, EchoServer, Server};
+use std::time::Duration;
+use tokio::time::sleep;
+
+/// Another program should be able to run the proxy in-process and send requests through it
+#[tokio::test]
+async fn test_embedded_proxy() {
+    init_logging();
+    let upstreams = vec![EchoServer::new().await, EchoServer::new().await];
+    let address = free_address();
+    let mut proxy = Proxy::new(upstreams.iter().map(|upstream| upstream.address.clone()).collect())
+        .bind(&address)
+        .load_balancer(ArgLoadBalance::RoundRobin);
+    proxy.config_mut().active_health_check_interval = 60;
+    let proxy = tokio::spawn(proxy.run());
+    sleep(Duration::from_millis(500)).await;
+
+    for i in 0..4 {
+        let path = format!("/request-{}", i);
+        let response_text = reqwest::get(format!("http://{}{}", address, path))
+            .await
+            .expect("Error sending request to the embedded proxy")
+            .text()
+            .await
+            .expect("The embedded proxy replied with a malformed response");
+        assert!(response_text.contains(&format!("GET {} HTTP/1.1", path)));
+    }
+
+    proxy.abort();
+    for upstream in upstreams {
+        assert_eq!(Box::new(upstream).stop().await, 2);
+    }
+    log::info!("All done :)");
+}
+
+/// Starting the proxy without any upstreams should fail, rather than serve errors
+#[tokio::test]
+async fn test_embedded_proxy_without_upstreams() {
+    init_logging();
+    let result = Proxy::new(Vec::new()).bind(&free_address()).run().await;
+    assert!(matches!(
+        result,
+        Err(balancebeam::Error::Config(balancebeam::ConfigError::NoUpstreams))
+    ));
+}
@@ -0,0 +1,78 @@
+mod common;
+
+use async_trait::async_trait;
+use balancebeam::middleware::{Action, Context};
+use balancebeam::{Middleware, Proxy};
+use common::{free_address, init_logging, EchoServer, Server};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::time::sleep;
+
+/// Answers requests for /blocked itself, and tags everything else on the way in and out
+struct BlockAndTag {}
+
+#[async_trait]
+impl Middleware for BlockAndTag {
+    async fn on_request(
+        &self,
+        _context: &Context<'_>,
+        request: &mut http::Request<Vec<u8>>,
+    ) -> Action {
+        if request.uri().path() == "/blocked" {
+            let response = http::Response::builder()
+                .status(http::StatusCode::IM_A_TEAPOT)
+                .header("content-length", "0")
+                .body(Vec::new())
+                .unwrap();
+            return Action::Respond(response);
+        }
+        request.headers_mut().insert("x-tagged", http::HeaderValue::from_static("request"));
+        Action::Continue
+    }
+
+    async fn on_response(
+        &self,
+        _context: &Context<'_>,
+        _request: &http::Request<Vec<u8>>,
+        response: &mut http::Response<Vec<u8>>,
+    ) {
+        response.headers_mut().insert("x-tagged", http::HeaderValue::from_static("response"));
+    }
+}
+
+/// Custom middlewares should be able to change requests and responses, and answer requests
+/// without them reaching an upstream
+#[tokio::test]
+async fn test_custom_middleware() {
+    init_logging();
+    let upstream = EchoServer::new().await;
+    let address = free_address();
+    let mut proxy = Proxy::new(vec![upstream.address.clone()])
+        .bind(&address)
+        .middleware(Arc::new(BlockAndTag {}));
+    proxy.config_mut().active_health_check_interval = 60;
+    let proxy = tokio::spawn(proxy.run());
+    sleep(Duration::from_millis(500)).await;
+
+    let response = reqwest::get(format!("http://{}/allowed", address))
+        .await
+        .expect("Error sending request to balancebeam");
+    assert_eq!(response.status().as_u16(), 200);
+    assert_eq!(response.headers()["x-tagged"], "response");
+    let response_text = response.text().await.expect("Error reading response body");
+    assert!(
+        response_text.to_lowercase().contains("x-tagged: request"),
+        "The upstream didn't get the header the middleware added"
+    );
+    // The built-in middlewares still run ahead of the custom one
+    assert!(response_text.to_lowercase().contains("x-forwarded-for: 127.0.0.1"));
+
+    let response = reqwest::get(format!("http://{}/blocked", address))
+        .await
+        .expect("Error sending request to balancebeam");
+    assert_eq!(response.status().as_u16(), 418);
+
+    proxy.abort();
+    assert_eq!(Box::new(upstream).stop().await, 1);
+    log::info!("All done :)");
+}
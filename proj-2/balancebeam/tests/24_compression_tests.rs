@@ -0,0 +1,276 @@
+mod common;
+
+use common::{init_logging, BalanceBeam};
+use flate2::read::{GzDecoder, ZlibDecoder};
+use flate2::write::GzEncoder;
+use std::io::{Read, Write};
+use std::sync::{Arc, Mutex};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpListener;
+
+/// Starts an upstream that answers every request with the given headers (other than
+/// Content-Length) and body
+async fn start_static_upstream(headers: &'static str, body: Vec<u8>) -> String {
+    let listener = TcpListener::bind("127.0.0.1:0")
+        .await
+        .expect("Could not bind upstream");
+    let address = listener.local_addr().unwrap().to_string();
+    tokio::spawn(async move {
+        loop {
+            let (mut stream, _) = match listener.accept().await {
+                Ok(connection) => connection,
+                Err(_) => return,
+            };
+            let body = body.clone();
+            tokio::spawn(async move {
+                let mut received = Vec::new();
+                let mut buffer = [0_u8; 1024];
+                loop {
+                    match stream.read(&mut buffer).await {
+                        Ok(0) | Err(_) => return,
+                        Ok(n) => received.extend_from_slice(&buffer[..n]),
+                    }
+                    // Requests through balancebeam here never have a body
+                    while let Some(end) = received.windows(4).position(|w| w == b"\r\n\r\n") {
+                        received.drain(..end + 4);
+                        let head = format!(
+                            "HTTP/1.1 200 OK\r\n{}Content-Length: {}\r\n\r\n",
+                            headers,
+                            body.len()
+                        );
+                        if stream.write_all(head.as_bytes()).await.is_err()
+                            || stream.write_all(&body).await.is_err()
+                        {
+                            return;
+                        }
+                    }
+                }
+            });
+        }
+    });
+    address
+}
+
+/// Starts an upstream that gzips its cacheable JSON response for requests that accept gzip, like
+/// a web server would. Returns its address, and the Accept-Encoding of each request it got.
+async fn start_negotiating_upstream() -> (String, Arc<Mutex<Vec<String>>>) {
+    let listener = TcpListener::bind("127.0.0.1:0").await.expect("Could not bind upstream");
+    let address = listener.local_addr().unwrap().to_string();
+    let seen = Arc::new(Mutex::new(Vec::new()));
+    let seen_by_upstream = seen.clone();
+    tokio::spawn(async move {
+        while let Ok((mut stream, _)) = listener.accept().await {
+            let seen = seen_by_upstream.clone();
+            tokio::spawn(async move {
+                let mut received = Vec::new();
+                let mut buffer = [0_u8; 1024];
+                loop {
+                    match stream.read(&mut buffer).await {
+                        Ok(0) | Err(_) => return,
+                        Ok(n) => received.extend_from_slice(&buffer[..n]),
+                    }
+                    while let Some(end) = received.windows(4).position(|w| w == b"\r\n\r\n") {
+                        let head = String::from_utf8_lossy(&received[..end]).to_lowercase();
+                        received.drain(..end + 4);
+                        let accept_encoding = head
+                            .lines()
+                            .find_map(|line| line.strip_prefix("accept-encoding:"))
+                            .unwrap_or("")
+                            .trim()
+                            .to_string();
+                        let gzip = accept_encoding.contains("gzip");
+                        seen.lock().unwrap().push(accept_encoding);
+                        let mut body = json_body();
+                        let mut headers = "Content-Type: application/json\r\n\
+                                           Cache-Control: max-age=60\r\nVary: Accept-Encoding\r\n"
+                            .to_string();
+                        if gzip {
+                            let mut encoder =
+                                GzEncoder::new(Vec::new(), flate2::Compression::default());
+                            encoder.write_all(&body).unwrap();
+                            body = encoder.finish().unwrap();
+                            headers += "Content-Encoding: gzip\r\n";
+                        }
+                        let head = format!(
+                            "HTTP/1.1 200 OK\r\n{}Content-Length: {}\r\n\r\n",
+                            headers,
+                            body.len()
+                        );
+                        if stream.write_all(head.as_bytes()).await.is_err()
+                            || stream.write_all(&body).await.is_err()
+                        {
+                            return;
+                        }
+                    }
+                }
+            });
+        }
+    });
+    (address, seen)
+}
+
+async fn start_balancebeam(upstream_address: &str) -> BalanceBeam {
+    BalanceBeam::new_with_args(
+        &[upstream_address],
+        &["--active-health-check-interval", "60", "--compression-min-size", "100"],
+    )
+    .await
+}
+
+/// Sends a request with the given Accept-Encoding header on a new connection
+async fn get(balancebeam: &BalanceBeam, accept_encoding: Option<&str>) -> reqwest::Response {
+    let mut request = reqwest::Client::new().get(format!("http://{}/", balancebeam.address));
+    if let Some(accept_encoding) = accept_encoding {
+        request = request.header("Accept-Encoding", accept_encoding);
+    }
+    request.send().await.expect("Error sending request to balancebeam")
+}
+
+fn json_body() -> Vec<u8> {
+    let items: Vec<String> = (0..200).map(|i| format!("{{\"id\": {}}}", i)).collect();
+    format!("[{}]", items.join(", ")).into_bytes()
+}
+
+/// Compressible responses should be gzipped for clients that accept gzip
+#[tokio::test]
+async fn test_gzip_compression() {
+    init_logging();
+    let body = json_body();
+    let upstream = start_static_upstream("Content-Type: application/json\r\n", body.clone()).await;
+    let balancebeam = start_balancebeam(&upstream).await;
+
+    let response = get(&balancebeam, Some("deflate;q=0.5, gzip")).await;
+    assert_eq!(response.headers()["content-encoding"], "gzip");
+    assert_eq!(response.headers()["vary"], "Accept-Encoding");
+    let compressed = response.bytes().await.expect("Error reading response body");
+    assert!(compressed.len() < body.len());
+    let mut decompressed = Vec::new();
+    GzDecoder::new(&compressed[..])
+        .read_to_end(&mut decompressed)
+        .expect("The response body isn't valid gzip");
+    assert_eq!(decompressed, body);
+
+    log::info!("All done :)");
+}
+
+/// Clients that only accept deflate should get the body in deflate's (zlib) format
+#[tokio::test]
+async fn test_deflate_compression() {
+    init_logging();
+    let body = json_body();
+    let upstream = start_static_upstream("Content-Type: application/json\r\n", body.clone()).await;
+    let balancebeam = start_balancebeam(&upstream).await;
+
+    let response = get(&balancebeam, Some("deflate")).await;
+    assert_eq!(response.headers()["content-encoding"], "deflate");
+    let compressed = response.bytes().await.expect("Error reading response body");
+    let mut decompressed = Vec::new();
+    ZlibDecoder::new(&compressed[..])
+        .read_to_end(&mut decompressed)
+        .expect("The response body isn't valid zlib");
+    assert_eq!(decompressed, body);
+
+    log::info!("All done :)");
+}
+
+/// Responses should pass through untouched if the client doesn't ask for compression, or if
+/// they're too small or of a type that isn't compressed
+#[tokio::test]
+async fn test_ineligible_responses_not_compressed() {
+    init_logging();
+    let json = start_static_upstream("Content-Type: application/json\r\n", json_body()).await;
+    let small = start_static_upstream("Content-Type: text/plain\r\n", b"hi".to_vec()).await;
+    let image = start_static_upstream("Content-Type: image/png\r\n", vec![7; 1000]).await;
+
+    let balancebeam = start_balancebeam(&json).await;
+    let response = get(&balancebeam, None).await;
+    assert!(response.headers().get("content-encoding").is_none());
+    assert_eq!(response.bytes().await.unwrap(), json_body());
+
+    for (upstream, body) in [(small, b"hi".to_vec()), (image, vec![7; 1000])] {
+        let balancebeam = start_balancebeam(&upstream).await;
+        let response = get(&balancebeam, Some("gzip")).await;
+        assert!(response.headers().get("content-encoding").is_none());
+        assert_eq!(response.bytes().await.unwrap(), body);
+    }
+
+    log::info!("All done :)");
+}
+
+/// A body the upstream gzipped should be decompressed for clients that don't accept gzip, and
+/// passed on as it is for clients that do
+#[tokio::test]
+async fn test_upstream_compression_undone() {
+    init_logging();
+    let body = json_body();
+    let mut encoder = GzEncoder::new(Vec::new(), flate2::Compression::default());
+    encoder.write_all(&body).unwrap();
+    let compressed = encoder.finish().unwrap();
+    let upstream = start_static_upstream(
+        "Content-Type: application/json\r\nContent-Encoding: gzip\r\n",
+        compressed.clone(),
+    )
+    .await;
+    let balancebeam = start_balancebeam(&upstream).await;
+
+    let response = get(&balancebeam, Some("identity")).await;
+    assert!(response.headers().get("content-encoding").is_none());
+    assert_eq!(response.bytes().await.unwrap(), body);
+
+    let response = get(&balancebeam, Some("gzip")).await;
+    assert_eq!(response.headers()["content-encoding"], "gzip");
+    assert_eq!(response.bytes().await.unwrap(), compressed);
+
+    log::info!("All done :)");
+}
+
+/// Responses under a cache path should be fetched from the upstream uncompressed and kept once,
+/// then compressed to suit each client, rather than kept once per encoding clients ask for
+#[tokio::test]
+async fn test_cached_response_encoded_per_client() {
+    init_logging();
+    let body = json_body();
+    let (upstream, seen) = start_negotiating_upstream().await;
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&upstream],
+        &[
+            "--active-health-check-interval",
+            "60",
+            "--compression-min-size",
+            "100",
+            "--cache-path",
+            "/",
+        ],
+    )
+    .await;
+
+    let response = get(&balancebeam, Some("gzip")).await;
+    assert_eq!(response.headers()["content-encoding"], "gzip");
+    assert_eq!(response.headers().get_all("vary").iter().count(), 1);
+    let compressed = response.bytes().await.unwrap();
+    let mut decompressed = Vec::new();
+    GzDecoder::new(&compressed[..])
+        .read_to_end(&mut decompressed)
+        .expect("The response body isn't valid gzip");
+    assert_eq!(decompressed, body);
+
+    // Served from the cache, in whatever encoding each client accepts
+    let response = get(&balancebeam, Some("deflate")).await;
+    assert!(response.headers().get("age").is_some());
+    assert_eq!(response.headers()["content-encoding"], "deflate");
+    let compressed = response.bytes().await.unwrap();
+    let mut decompressed = Vec::new();
+    ZlibDecoder::new(&compressed[..])
+        .read_to_end(&mut decompressed)
+        .expect("The response body isn't valid zlib");
+    assert_eq!(decompressed, body);
+
+    for accept_encoding in [None, Some("identity")] {
+        let response = get(&balancebeam, accept_encoding).await;
+        assert!(response.headers().get("content-encoding").is_none());
+        assert_eq!(response.bytes().await.unwrap(), body);
+    }
+
+    assert_eq!(*seen.lock().unwrap(), vec!["identity".to_string()]);
+    log::info!("All done :)");
+}
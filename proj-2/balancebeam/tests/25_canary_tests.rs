@@ -0,0 +1,81 @@
+mod common;
+
+use common::{free_address, get_status, init_logging, stop_all};
+use common::{BalanceBeam, EchoServer, ErrorServer, Server};
+
+/// A canary should get about its percentage of requests, with the rest going to the stable
+/// upstreams
+#[tokio::test]
+async fn test_canary_share() {
+    init_logging();
+    let upstreams: Vec<Box<dyn Server>> = vec![
+        Box::new(EchoServer::new().await),
+        Box::new(EchoServer::new().await),
+        Box::new(EchoServer::new().await),
+    ];
+    let canary = format!("{}=20%", upstreams[2].address());
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&upstreams[0].address(), &upstreams[1].address()],
+        &["--canary", &canary, "--active-health-check-interval", "60"],
+    )
+    .await;
+
+    for i in 0..100 {
+        assert_eq!(get_status(&balancebeam.address, &format!("/request-{}", i)).await, 200);
+    }
+
+    let request_counters = stop_all(upstreams).await;
+    assert_eq!(request_counters.iter().sum::<usize>(), 100);
+    assert!(
+        (5..=40).contains(&request_counters[2]),
+        "The canary got {} of 100 requests, instead of about 20",
+        request_counters[2]
+    );
+
+    log::info!("All done :)");
+}
+
+/// The admin API should report the canary's error rate next to the stable upstreams'
+#[tokio::test]
+async fn test_canary_stats() {
+    init_logging();
+    let stable = EchoServer::new().await;
+    let canary = ErrorServer::new().await;
+    let admin = free_address();
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&stable.address],
+        &[
+            "--canary",
+            &format!("{}=50%", canary.address),
+            "--passive-health-check-failures",
+            "0",
+            "--active-health-check-interval",
+            "60",
+            "--admin-bind",
+            &admin,
+        ],
+    )
+    .await;
+
+    let mut failures = 0;
+    for i in 0..20 {
+        if get_status(&balancebeam.address, &format!("/request-{}", i)).await == 500 {
+            failures += 1;
+        }
+    }
+    assert!(failures > 0, "The canary didn't get any requests");
+
+    let report: serde_json::Value = reqwest::get(format!("http://{}/canary", admin))
+        .await
+        .expect("Error sending request to admin API")
+        .json()
+        .await
+        .expect("Admin API returned invalid JSON");
+    assert_eq!(report["canary"]["requests"], failures);
+    assert_eq!(report["canary"]["errors"], failures);
+    assert_eq!(report["canary"]["error_rate"], 1.0);
+    assert_eq!(report["stable"]["requests"], 20 - failures);
+    assert_eq!(report["stable"]["errors"], 0);
+
+    log::info!("All done :)");
+}
@@ -0,0 +1,115 @@
+mod common;
+
+use common::{free_address, init_logging, BalanceBeam, EchoServer, Server};
+use std::time::Duration;
+use tokio::io::AsyncReadExt;
+use tokio::net::TcpListener;
+use tokio::sync::mpsc;
+use tokio::time::{sleep, timeout};
+
+/// Starts a shadow backend that passes along everything it receives, and never answers
+async fn start_recording_mirror(received: mpsc::UnboundedSender<Vec<u8>>) -> String {
+    let listener = TcpListener::bind("127.0.0.1:0")
+        .await
+        .expect("Could not bind mirror");
+    let address = listener.local_addr().unwrap().to_string();
+    tokio::spawn(async move {
+        while let Ok((mut stream, _)) = listener.accept().await {
+            let received = received.clone();
+            tokio::spawn(async move {
+                let mut buffer = [0_u8; 1024];
+                while let Ok(n) = stream.read(&mut buffer).await {
+                    if n == 0 || received.send(buffer[..n].to_vec()).is_err() {
+                        break;
+                    }
+                }
+            });
+        }
+    });
+    address
+}
+
+/// Every request should also be sent to the mirror, while clients get the real upstream's
+/// responses
+#[tokio::test]
+async fn test_requests_mirrored() {
+    init_logging();
+    let upstream = EchoServer::new().await;
+    let mirror = EchoServer::new().await;
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&upstream.address],
+        &["--mirror", &mirror.address, "--active-health-check-interval", "60"],
+    )
+    .await;
+
+    for i in 0..5 {
+        let path = format!("/request-{}", i);
+        let response_text = balancebeam
+            .get(&path)
+            .await
+            .expect("Error sending request to balancebeam");
+        assert!(response_text.contains(&format!("GET {} HTTP/1.1", path)));
+    }
+    // Mirrored requests are sent in the background
+    sleep(Duration::from_millis(500)).await;
+
+    assert_eq!(Box::new(upstream).stop().await, 5);
+    assert_eq!(Box::new(mirror).stop().await, 5);
+    log::info!("All done :)");
+}
+
+/// Request bodies should be copied to the mirror along with the headers
+#[tokio::test]
+async fn test_request_body_mirrored() {
+    init_logging();
+    let upstream = EchoServer::new().await;
+    let (received_tx, mut received_rx) = mpsc::unbounded_channel();
+    let mirror = start_recording_mirror(received_tx).await;
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&upstream.address],
+        &["--mirror", &mirror, "--active-health-check-interval", "60"],
+    )
+    .await;
+
+    let response_text = balancebeam
+        .post("/upload", "hello mirror")
+        .await
+        .expect("Error sending request to balancebeam");
+    assert!(response_text.contains("hello mirror"));
+
+    let mut mirrored = Vec::new();
+    while !mirrored.ends_with(b"\r\n\r\nhello mirror") {
+        let piece = timeout(Duration::from_secs(3), received_rx.recv())
+            .await
+            .expect("The mirror never got the whole request")
+            .expect("Mirror connection closed");
+        mirrored.extend_from_slice(&piece);
+    }
+    assert!(mirrored.starts_with(b"POST /upload HTTP/1.1\r\n"));
+
+    log::info!("All done :)");
+}
+
+/// A mirror that can't be reached shouldn't affect clients
+#[tokio::test]
+async fn test_unreachable_mirror() {
+    init_logging();
+    let upstream = EchoServer::new().await;
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&upstream.address],
+        &["--mirror", &free_address(), "--active-health-check-interval", "60"],
+    )
+    .await;
+
+    for i in 0..3 {
+        let path = format!("/request-{}", i);
+        let response_text = balancebeam
+            .get(&path)
+            .await
+            .expect("Error sending request to balancebeam");
+        assert!(response_text.contains(&format!("GET {} HTTP/1.1", path)));
+    }
+
+    assert_eq!(Box::new(upstream).stop().await, 3);
+    log::info!("All done :)");
+}
@@ -0,0 +1,99 @@
+mod common;
+
+use common::{free_address, init_logging, BalanceBeam, EchoServer, Server};
+use rand::Rng;
+
+async fn admin_post(admin_address: &str, path: &str) {
+    let status = reqwest::Client::new()
+        .post(format!("http://{}{}", admin_address, path))
+        .send()
+        .await
+        .expect("Error sending request to admin API")
+        .status();
+    assert_eq!(status, reqwest::StatusCode::OK);
+}
+
+/// Send a request on a new connection and return the HTTP status code and body
+async fn get(address: &str, path: &str) -> (u16, String) {
+    let response = reqwest::get(format!("http://{}{}", address, path))
+        .await
+        .expect("Error sending request to balancebeam");
+    let status = response.status().as_u16();
+    (status, response.text().await.expect("Error reading response body"))
+}
+
+/// Turning maintenance mode on should answer every request with the maintenance page, until it's
+/// turned off again
+#[tokio::test]
+async fn test_maintenance_mode() {
+    init_logging();
+    let page_path = std::env::temp_dir().join(format!(
+        "balancebeam-test-{}.html",
+        rand::thread_rng().gen::<u32>()
+    ));
+    std::fs::write(&page_path, "<h1>Back soon</h1>").expect("Could not write maintenance page");
+    let upstream = EchoServer::new().await;
+    let admin = free_address();
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&upstream.address],
+        &[
+            "--maintenance-page",
+            page_path.to_str().unwrap(),
+            "--admin-bind",
+            &admin,
+            "--active-health-check-interval",
+            "60",
+        ],
+    )
+    .await;
+
+    assert_eq!(get(&balancebeam.address, "/before").await.0, 200);
+
+    admin_post(&admin, "/maintenance/on").await;
+    let (status, body) = get(&balancebeam.address, "/during").await;
+    assert_eq!(status, 503);
+    assert_eq!(body, "<h1>Back soon</h1>");
+    let maintenance: serde_json::Value = reqwest::get(format!("http://{}/maintenance", admin))
+        .await
+        .expect("Error sending request to admin API")
+        .json()
+        .await
+        .expect("Admin API returned invalid JSON");
+    assert_eq!(maintenance["enabled"], true);
+
+    admin_post(&admin, "/maintenance/off").await;
+    assert_eq!(get(&balancebeam.address, "/after").await.0, 200);
+
+    // The request made during maintenance never reached the upstream
+    assert_eq!(Box::new(upstream).stop().await, 2);
+    let _ = std::fs::remove_file(&page_path);
+    log::info!("All done :)");
+}
+
+/// Clients in an allowed range should still be proxied while in maintenance mode
+#[tokio::test]
+async fn test_maintenance_allow_list() {
+    init_logging();
+    let upstream = EchoServer::new().await;
+    let admin = free_address();
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&upstream.address],
+        &[
+            "--maintenance-allow-cidr",
+            "127.0.0.0/8",
+            "--admin-bind",
+            &admin,
+            "--active-health-check-interval",
+            "60",
+        ],
+    )
+    .await;
+
+    admin_post(&admin, "/maintenance/on").await;
+    let (status, body) = get(&balancebeam.address, "/allowed").await;
+    assert_eq!(status, 200);
+    assert!(body.contains("GET /allowed HTTP/1.1"));
+
+    assert_eq!(Box::new(upstream).stop().await, 1);
+    log::info!("All done :)");
+}
@@ -0,0 +1,127 @@
+mod common;
+
+use common::{get_status, init_logging, write_config, BalanceBeam, EchoServer, ErrorServer};
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpListener;
+use tokio::time::sleep;
+
+/// Starts an upstream that waits for the given delay before answering each request with 200
+async fn start_slow_upstream(delay: Duration) -> String {
+    let listener = TcpListener::bind("127.0.0.1:0")
+        .await
+        .expect("Could not bind upstream");
+    let address = listener.local_addr().unwrap().to_string();
+    tokio::spawn(async move {
+        while let Ok((mut stream, _)) = listener.accept().await {
+            tokio::spawn(async move {
+                let mut buffer = [0_u8; 1024];
+                while let Ok(n) = stream.read(&mut buffer).await {
+                    if n == 0 {
+                        break;
+                    }
+                    sleep(delay).await;
+                    let response = b"HTTP/1.1 200 OK\r\nContent-Length: 0\r\n\r\n";
+                    if stream.write_all(response).await.is_err() {
+                        break;
+                    }
+                }
+            });
+        }
+    });
+    address
+}
+
+/// A route's own response timeout should apply to requests under it, and only to those
+#[tokio::test]
+async fn test_route_timeout() {
+    init_logging();
+    let upstream = start_slow_upstream(Duration::from_secs(2)).await;
+    let config_path = write_config(&format!(
+        "upstreams = [\"{0}\"]\n\
+         upstream-response-timeout = 10\n\
+         [pools]\n\
+         slow = [\"{0}\"]\n\
+         [routes]\n\
+         \"/hurry\" = {{ pool = \"slow\", upstream-response-timeout = 1 }}\n",
+        upstream
+    ));
+    let balancebeam = BalanceBeam::new_with_args(
+        &[],
+        &["--config", config_path.to_str().unwrap(), "--active-health-check-interval", "60"],
+    )
+    .await;
+
+    assert_eq!(get_status(&balancebeam.address, "/hurry/up").await, 504);
+    assert_eq!(get_status(&balancebeam.address, "/take-your-time").await, 200);
+
+    let _ = std::fs::remove_file(&config_path);
+    log::info!("All done :)");
+}
+
+/// A route's own retry count should let its requests be retried when retries are otherwise off
+#[tokio::test]
+async fn test_route_retries() {
+    init_logging();
+    let echo = EchoServer::new().await;
+    let error = ErrorServer::new().await;
+    let config_path = write_config(&format!(
+        "upstreams = [\"{0}\"]\n\
+         max-retries = 0\n\
+         [pools]\n\
+         mixed = [\"{1}\", \"{0}\"]\n\
+         [routes]\n\
+         \"/retried\" = {{ pool = \"mixed\", max-retries = 1 }}\n",
+        echo.address, error.address
+    ));
+    let balancebeam = BalanceBeam::new_with_args(
+        &[],
+        &[
+            "--config",
+            config_path.to_str().unwrap(),
+            "--passive-health-check-failures",
+            "0",
+            "--active-health-check-interval",
+            "60",
+        ],
+    )
+    .await;
+
+    for i in 0..6 {
+        assert_eq!(get_status(&balancebeam.address, &format!("/retried/{}", i)).await, 200);
+    }
+
+    let _ = std::fs::remove_file(&config_path);
+    log::info!("All done :)");
+}
+
+/// A route's own rate limit should turn away requests under it once a client is over it, without
+/// affecting other routes
+#[tokio::test]
+async fn test_route_rate_limit() {
+    init_logging();
+    let upstream = EchoServer::new().await;
+    let config_path = write_config(&format!(
+        "upstreams = [\"{0}\"]\n\
+         [pools]\n\
+         default = [\"{0}\"]\n\
+         [routes]\n\
+         \"/login\" = {{ pool = \"default\", max-requests-per-minute = 2 }}\n",
+        upstream.address
+    ));
+    let balancebeam = BalanceBeam::new_with_args(
+        &[],
+        &["--config", config_path.to_str().unwrap(), "--active-health-check-interval", "60"],
+    )
+    .await;
+
+    let mut statuses = Vec::new();
+    for _ in 0..3 {
+        statuses.push(get_status(&balancebeam.address, "/login").await);
+    }
+    assert_eq!(statuses, vec![200, 200, 429]);
+    assert_eq!(get_status(&balancebeam.address, "/home").await, 200);
+
+    let _ = std::fs::remove_file(&config_path);
+    log::info!("All done :)");
+}
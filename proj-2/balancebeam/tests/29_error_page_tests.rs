@@ -0,0 +1,110 @@
+mod common;
+
+use common::{init_logging, BalanceBeam, EchoServer};
+use rand::Rng;
+use tokio::io::AsyncReadExt;
+use tokio::net::TcpListener;
+
+/// Writes a file with the given contents to a new temporary path with the given extension
+fn write_temp_file(extension: &str, contents: &str) -> std::path::PathBuf {
+    let path = std::env::temp_dir().join(format!(
+        "balancebeam-test-{}.{}",
+        rand::thread_rng().gen::<u32>(),
+        extension
+    ));
+    std::fs::write(&path, contents).expect("Could not write temporary file");
+    path
+}
+
+/// Starts an upstream that hangs up on every request without answering it
+async fn start_hang_up_upstream() -> String {
+    let listener = TcpListener::bind("127.0.0.1:0")
+        .await
+        .expect("Could not bind upstream");
+    let address = listener.local_addr().unwrap().to_string();
+    tokio::spawn(async move {
+        while let Ok((mut stream, _)) = listener.accept().await {
+            let mut buffer = [0_u8; 1024];
+            let _ = stream.read(&mut buffer).await;
+        }
+    });
+    address
+}
+
+/// Sends a request with the given request ID, and returns the status, content type and body
+async fn get_with_id(address: &str, path: &str, request_id: &str) -> (u16, String, String) {
+    let response = reqwest::Client::new()
+        .get(format!("http://{}{}", address, path))
+        .header("x-request-id", request_id)
+        .send()
+        .await
+        .expect("Error sending request to balancebeam");
+    let status = response.status().as_u16();
+    let content_type = response
+        .headers()
+        .get("content-type")
+        .map(|value| value.to_str().unwrap().to_string())
+        .unwrap_or_default();
+    (status, content_type, response.text().await.expect("Error reading response body"))
+}
+
+/// An error page file should be used when the upstream fails the request, with the request ID and
+/// upstream filled in
+#[tokio::test]
+async fn test_error_page_file() {
+    init_logging();
+    let page_path =
+        write_temp_file("html", "<p>{{upstream}} is down ({{status}}, {{request_id}})</p>");
+    let upstream = start_hang_up_upstream().await;
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&upstream],
+        &[
+            "--error-page",
+            &format!("502={}", page_path.to_str().unwrap()),
+            "--active-health-check-interval",
+            "60",
+        ],
+    )
+    .await;
+
+    let (status, content_type, body) = get_with_id(&balancebeam.address, "/", "abc123").await;
+    assert_eq!(status, 502);
+    assert_eq!(content_type, "text/html; charset=utf-8");
+    assert_eq!(body, format!("<p>{} is down (502, abc123)</p>", upstream));
+
+    let _ = std::fs::remove_file(&page_path);
+    log::info!("All done :)");
+}
+
+/// An error page given inline in the config file should be used for requests we turn away, with
+/// its own content type
+#[tokio::test]
+async fn test_inline_error_page() {
+    init_logging();
+    let upstream = EchoServer::new().await;
+    let config_path = write_temp_file(
+        "toml",
+        &format!(
+            "upstreams = [\"{}\"]\n\
+             max-requests-per-minute = 1\n\
+             [error-pages.429]\n\
+             body = '{{\"error\": \"slow down\", \"request\": \"{{{{request_id}}}}\"}}'\n\
+             content-type = \"application/json\"\n",
+            upstream.address
+        ),
+    );
+    let balancebeam = BalanceBeam::new_with_args(
+        &[],
+        &["--config", config_path.to_str().unwrap(), "--active-health-check-interval", "60"],
+    )
+    .await;
+
+    assert_eq!(get_with_id(&balancebeam.address, "/", "first").await.0, 200);
+    let (status, content_type, body) = get_with_id(&balancebeam.address, "/", "second").await;
+    assert_eq!(status, 429);
+    assert_eq!(content_type, "application/json");
+    assert_eq!(body, "{\"error\": \"slow down\", \"request\": \"second\"}");
+
+    let _ = std::fs::remove_file(&config_path);
+    log::info!("All done :)");
+}
@@ -0,0 +1,86 @@
+mod common;
+
+use common::{init_logging, BalanceBeam, EchoServer, Server};
+
+/// Sends a request without following redirects, and returns the status and Location header
+async fn send(address: &str, method: reqwest::Method, path: &str) -> (u16, Option<String>) {
+    let client = reqwest::Client::builder()
+        .redirect(reqwest::redirect::Policy::none())
+        .build()
+        .unwrap();
+    let response = client
+        .request(method, format!("http://{}{}", address, path))
+        .send()
+        .await
+        .expect("Error sending request to balancebeam");
+    let location = response
+        .headers()
+        .get("location")
+        .map(|value| value.to_str().unwrap().to_string());
+    (response.status().as_u16(), location)
+}
+
+/// Requests under a redirected prefix should be answered with a redirect that keeps the rest of
+/// the path and the query, without going to the upstream
+#[tokio::test]
+async fn test_path_redirect() {
+    init_logging();
+    let upstream = EchoServer::new().await;
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&upstream.address],
+        &["--redirect", "/old=/new", "--active-health-check-interval", "60"],
+    )
+    .await;
+
+    let (status, location) =
+        send(&balancebeam.address, reqwest::Method::GET, "/old/page?x=1").await;
+    assert_eq!(status, 301);
+    assert_eq!(location, Some(format!("http://{}/new/page?x=1", balancebeam.address)));
+    let (status, location) = send(&balancebeam.address, reqwest::Method::GET, "/older").await;
+    assert_eq!(status, 200);
+    assert_eq!(location, None);
+
+    assert_eq!(Box::new(upstream).stop().await, 1);
+    log::info!("All done :)");
+}
+
+/// Plain HTTP requests should be sent to HTTPS, with a 308 for methods that 301 would let clients
+/// change
+#[tokio::test]
+async fn test_force_https() {
+    init_logging();
+    let upstream = EchoServer::new().await;
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&upstream.address],
+        &["--force-https", "--active-health-check-interval", "60"],
+    )
+    .await;
+
+    let (status, location) = send(&balancebeam.address, reqwest::Method::GET, "/a?b=c").await;
+    assert_eq!(status, 301);
+    assert_eq!(location.as_deref(), Some("https://127.0.0.1/a?b=c"));
+    let (status, location) = send(&balancebeam.address, reqwest::Method::POST, "/form").await;
+    assert_eq!(status, 308);
+    assert_eq!(location.as_deref(), Some("https://127.0.0.1/form"));
+
+    assert_eq!(Box::new(upstream).stop().await, 0);
+    log::info!("All done :)");
+}
+
+/// Requests for another host name should be sent to the canonical one
+#[tokio::test]
+async fn test_canonical_host() {
+    init_logging();
+    let upstream = EchoServer::new().await;
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&upstream.address],
+        &["--canonical-host", "www.example.com", "--active-health-check-interval", "60"],
+    )
+    .await;
+
+    let (status, location) = send(&balancebeam.address, reqwest::Method::GET, "/page").await;
+    assert_eq!(status, 301);
+    assert_eq!(location.as_deref(), Some("http://www.example.com/page"));
+
+    log::info!("All done :)");
+}
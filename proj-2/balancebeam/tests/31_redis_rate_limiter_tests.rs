@@ -0,0 +1,100 @@
+//! Tests for the Redis rate limiter, which is only built with the "redis" feature. The test that
+//! shares a quota between instances needs a Redis server, given by BALANCEBEAM_TEST_REDIS_URL
+//! (e.g. redis://127.0.0.1:6379), and is skipped without one.
+#![cfg(feature = "redis")]
+
+mod common;
+
+use common::{free_address, init_logging, BalanceBeam, EchoServer, Server};
+use rand::Rng;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::time::sleep;
+
+/// Send a request carrying the given API key on a new connection and return the HTTP status code
+async fn get_status_with_key(address: &str, path: &str, api_key: &str) -> u16 {
+    reqwest::Client::new()
+        .get(format!("http://{}{}", address, path))
+        .header("x-api-key", api_key)
+        .send()
+        .await
+        .expect("Error sending request to balancebeam")
+        .status()
+        .as_u16()
+}
+
+/// Two instances using the same Redis server should enforce one quota between them
+#[tokio::test]
+async fn test_shared_quota() {
+    init_logging();
+    let redis_url = match std::env::var("BALANCEBEAM_TEST_REDIS_URL") {
+        Ok(url) => url,
+        Err(_) => {
+            log::info!("BALANCEBEAM_TEST_REDIS_URL isn't set, skipping");
+            return;
+        }
+    };
+    let upstream = EchoServer::new().await;
+    let args = [
+        "--max-requests-per-minute",
+        "3",
+        "--rate-limiter",
+        "redis",
+        "--rate-limit-redis-url",
+        &redis_url,
+        "--rate-limit-by",
+        "api-key",
+        "--active-health-check-interval",
+        "60",
+    ];
+    let first = BalanceBeam::new_with_args(&[&upstream.address], &args).await;
+    let second = BalanceBeam::new_with_args(&[&upstream.address], &args).await;
+
+    // Counts are kept per minute, so don't start right before the next minute begins
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap();
+    if now.as_secs() % 60 > 50 {
+        sleep(Duration::from_secs(60 - now.as_secs() % 60)).await;
+    }
+    // A key of its own keeps counts from earlier runs out of the way
+    let api_key = format!("test-{}", rand::thread_rng().gen::<u64>());
+    assert_eq!(get_status_with_key(&first.address, "/1", &api_key).await, 200);
+    assert_eq!(get_status_with_key(&second.address, "/2", &api_key).await, 200);
+    assert_eq!(get_status_with_key(&first.address, "/3", &api_key).await, 200);
+    assert_eq!(get_status_with_key(&second.address, "/4", &api_key).await, 429);
+    assert_eq!(get_status_with_key(&first.address, "/5", &api_key).await, 429);
+
+    assert_eq!(Box::new(upstream).stop().await, 3);
+    log::info!("All done :)");
+}
+
+/// Requests should be let through if Redis can't be reached, rather than turning everyone away
+#[tokio::test]
+async fn test_redis_unreachable() {
+    init_logging();
+    let upstream = EchoServer::new().await;
+    let redis_url = format!("redis://{}", free_address());
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&upstream.address],
+        &[
+            "--max-requests-per-minute",
+            "1",
+            "--rate-limiter",
+            "redis",
+            "--rate-limit-redis-url",
+            &redis_url,
+            "--active-health-check-interval",
+            "60",
+        ],
+    )
+    .await;
+
+    for i in 0..3 {
+        let response_text = balancebeam
+            .get(&format!("/request-{}", i))
+            .await
+            .expect("Error sending request to balancebeam");
+        assert!(response_text.contains(&format!("GET /request-{} HTTP/1.1", i)));
+    }
+
+    assert_eq!(Box::new(upstream).stop().await, 3);
+    log::info!("All done :)");
+}
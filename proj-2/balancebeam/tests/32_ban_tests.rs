@@ -0,0 +1,125 @@
+mod common;
+
+use common::{free_address, get_status, init_logging, BalanceBeam, EchoServer, Server};
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+use tokio::time::timeout;
+
+/// A client that keeps going over the rate limit should be banned, show up in the admin API, and
+/// be let back in once it's unbanned
+#[tokio::test]
+async fn test_ban_and_unban() {
+    init_logging();
+    let upstream = EchoServer::new().await;
+    let admin = free_address();
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&upstream.address],
+        &[
+            "--max-requests-per-minute",
+            "1",
+            "--ban-threshold",
+            "2",
+            "--admin-bind",
+            &admin,
+            "--active-health-check-interval",
+            "60",
+        ],
+    )
+    .await;
+
+    assert_eq!(get_status(&balancebeam.address, "/ok").await, 200);
+    // The third time over the limit is one too many
+    for _ in 0..3 {
+        assert_eq!(get_status(&balancebeam.address, "/over").await, 429);
+    }
+    assert_eq!(get_status(&balancebeam.address, "/banned").await, 403);
+
+    let bans: serde_json::Value = reqwest::get(format!("http://{}/bans", admin))
+        .await
+        .expect("Error sending request to admin API")
+        .json()
+        .await
+        .expect("Admin API returned invalid JSON");
+    assert_eq!(bans[0]["ip"], "127.0.0.1");
+    assert!(bans[0]["remaining"].as_u64().unwrap() > 3500);
+
+    let status = reqwest::Client::new()
+        .post(format!("http://{}/bans/unban", admin))
+        .body("127.0.0.1")
+        .send()
+        .await
+        .expect("Error sending request to admin API")
+        .status();
+    assert_eq!(status, reqwest::StatusCode::OK);
+    // Still over the rate limit, but no longer banned
+    assert_eq!(get_status(&balancebeam.address, "/unbanned").await, 429);
+
+    assert_eq!(Box::new(upstream).stop().await, 1);
+    log::info!("All done :)");
+}
+
+/// Going over the rate limit shouldn't get anyone banned unless banning is turned on
+#[tokio::test]
+async fn test_no_ban_by_default() {
+    init_logging();
+    let upstream = EchoServer::new().await;
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&upstream.address],
+        &["--max-requests-per-minute", "1", "--active-health-check-interval", "60"],
+    )
+    .await;
+
+    assert_eq!(get_status(&balancebeam.address, "/ok").await, 200);
+    for _ in 0..5 {
+        assert_eq!(get_status(&balancebeam.address, "/over").await, 429);
+    }
+
+    log::info!("All done :)");
+}
+
+/// A banned client should be answered as soon as it has sent its request's head, without
+/// balancebeam waiting for (or reading) the body
+#[tokio::test]
+async fn test_banned_client_answered_without_body() {
+    init_logging();
+    let upstream = EchoServer::new().await;
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&upstream.address],
+        &[
+            "--max-requests-per-minute",
+            "1",
+            "--ban-threshold",
+            "1",
+            "--active-health-check-interval",
+            "60",
+        ],
+    )
+    .await;
+
+    assert_eq!(get_status(&balancebeam.address, "/ok").await, 200);
+    for _ in 0..2 {
+        assert_eq!(get_status(&balancebeam.address, "/over").await, 429);
+    }
+
+    let mut stream = TcpStream::connect(&balancebeam.address)
+        .await
+        .expect("Could not connect to balancebeam");
+    stream
+        .write_all(b"POST /banned HTTP/1.1\r\nHost: localhost\r\nContent-Length: 1000000\r\n\r\n")
+        .await
+        .expect("Error sending request to balancebeam");
+    let mut response = Vec::new();
+    timeout(Duration::from_secs(3), stream.read_to_end(&mut response))
+        .await
+        .expect("balancebeam waited for the banned client's body")
+        .expect("Error reading response from balancebeam");
+    assert!(
+        response.starts_with(b"HTTP/1.1 403"),
+        "Unexpected response: {}",
+        String::from_utf8_lossy(&response)
+    );
+
+    assert_eq!(Box::new(upstream).stop().await, 1);
+    log::info!("All done :)");
+}
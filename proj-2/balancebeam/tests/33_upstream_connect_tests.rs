@@ -0,0 +1,51 @@
+mod common;
+
+use common::{free_address, get_status, init_logging, BalanceBeam, EchoServer, Server};
+use std::time::{Duration, Instant};
+
+/// Upstreams that refuse connections should be skipped over until one that works is found
+#[tokio::test]
+async fn test_skip_dead_upstreams() {
+    init_logging();
+    let upstream = EchoServer::new().await;
+    let dead = [free_address(), free_address(), free_address()];
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&dead[0], &dead[1], &upstream.address, &dead[2]],
+        &["--active-health-check-interval", "60"],
+    )
+    .await;
+
+    for i in 0..4 {
+        assert_eq!(get_status(&balancebeam.address, &format!("/request-{}", i)).await, 200);
+    }
+
+    assert_eq!(Box::new(upstream).stop().await, 4);
+    log::info!("All done :)");
+}
+
+/// When no upstream can be reached, each one should be tried once, backing off between attempts,
+/// and then the client should get a 502
+#[tokio::test]
+async fn test_all_upstreams_dead() {
+    init_logging();
+    let dead = [free_address(), free_address(), free_address()];
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&dead[0], &dead[1], &dead[2]],
+        &["--upstream-connect-backoff", "100", "--active-health-check-interval", "60"],
+    )
+    .await;
+
+    let start = Instant::now();
+    assert_eq!(get_status(&balancebeam.address, "/first").await, 502);
+    // Waits of 100ms and then 200ms between the three attempts
+    let elapsed = start.elapsed();
+    assert!(elapsed >= Duration::from_millis(300), "Gave up too soon: {:?}", elapsed);
+    assert!(elapsed < Duration::from_secs(5), "Took too long to give up: {:?}", elapsed);
+
+    // They're all marked down now, so there's nothing left to try
+    let start = Instant::now();
+    assert_eq!(get_status(&balancebeam.address, "/second").await, 502);
+    assert!(start.elapsed() < Duration::from_millis(300));
+
+    log::info!("All done :)");
+}
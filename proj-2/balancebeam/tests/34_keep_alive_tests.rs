@@ -0,0 +1,118 @@
+mod common;
+
+use common::{init_logging, BalanceBeam, EchoServer, Server};
+use std::time::Duration;
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+use tokio::net::TcpStream;
+use tokio::time::{sleep, timeout};
+
+/// Reads one response with a Content-Length body off the stream, returning its headers
+async fn read_response(stream: &mut BufReader<TcpStream>) -> String {
+    let mut headers = String::new();
+    let mut content_length = 0;
+    loop {
+        let mut line = String::new();
+        stream
+            .read_line(&mut line)
+            .await
+            .expect("Error reading response from balancebeam");
+        if line == "\r\n" || line.is_empty() {
+            break;
+        }
+        if let Some(value) = line.to_lowercase().strip_prefix("content-length:") {
+            content_length = value.trim().parse().expect("Invalid Content-Length");
+        }
+        headers += &line.to_lowercase();
+    }
+    let mut body = vec![0_u8; content_length];
+    stream
+        .read_exact(&mut body)
+        .await
+        .expect("Error reading response body from balancebeam");
+    headers
+}
+
+/// Sends a request on the stream and returns the response's headers
+async fn send(stream: &mut BufReader<TcpStream>, request: &[u8]) -> String {
+    stream.write_all(request).await.expect("Error sending request to balancebeam");
+    read_response(stream).await
+}
+
+/// Returns true if balancebeam closes the connection within a couple of seconds
+async fn is_closed(stream: &mut BufReader<TcpStream>) -> bool {
+    let mut buf = [0_u8; 1];
+    matches!(timeout(Duration::from_secs(2), stream.read(&mut buf)).await, Ok(Ok(0)) | Ok(Err(_)))
+}
+
+async fn connect(address: &str) -> BufReader<TcpStream> {
+    let stream = TcpStream::connect(address).await.expect("Could not connect to balancebeam");
+    BufReader::new(stream)
+}
+
+/// A client that says "Connection: close" should get that back, and then be hung up on
+#[tokio::test]
+async fn test_connection_close() {
+    init_logging();
+    let upstream = EchoServer::new().await;
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&upstream.address],
+        &["--active-health-check-interval", "60"],
+    )
+    .await;
+
+    let mut stream = connect(&balancebeam.address).await;
+    let headers = send(&mut stream, b"GET /a HTTP/1.1\r\nHost: localhost\r\n\r\n").await;
+    assert!(headers.contains("connection: keep-alive"), "Unexpected response: {}", headers);
+    let headers =
+        send(&mut stream, b"GET /b HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n")
+            .await;
+    assert!(headers.contains("connection: close"), "Unexpected response: {}", headers);
+    assert!(is_closed(&mut stream).await);
+
+    assert_eq!(Box::new(upstream).stop().await, 2);
+    log::info!("All done :)");
+}
+
+/// Connections should be closed once they've carried the maximum number of requests
+#[tokio::test]
+async fn test_max_requests_per_connection() {
+    init_logging();
+    let upstream = EchoServer::new().await;
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&upstream.address],
+        &["--max-requests-per-connection", "2", "--active-health-check-interval", "60"],
+    )
+    .await;
+
+    let mut stream = connect(&balancebeam.address).await;
+    let headers = send(&mut stream, b"GET /1 HTTP/1.1\r\nHost: localhost\r\n\r\n").await;
+    assert!(headers.contains("connection: keep-alive"), "Unexpected response: {}", headers);
+    let headers = send(&mut stream, b"GET /2 HTTP/1.1\r\nHost: localhost\r\n\r\n").await;
+    assert!(headers.contains("connection: close"), "Unexpected response: {}", headers);
+    assert!(is_closed(&mut stream).await);
+
+    assert_eq!(Box::new(upstream).stop().await, 2);
+    log::info!("All done :)");
+}
+
+/// A connection left idle after a request should be closed once the idle timeout runs out
+#[tokio::test]
+async fn test_idle_timeout() {
+    init_logging();
+    let upstream = EchoServer::new().await;
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&upstream.address],
+        &["--client-idle-timeout", "1", "--active-health-check-interval", "60"],
+    )
+    .await;
+
+    let mut stream = connect(&balancebeam.address).await;
+    send(&mut stream, b"GET /first HTTP/1.1\r\nHost: localhost\r\n\r\n").await;
+    // Staying under the timeout keeps the connection open
+    sleep(Duration::from_millis(500)).await;
+    send(&mut stream, b"GET /second HTTP/1.1\r\nHost: localhost\r\n\r\n").await;
+    assert!(is_closed(&mut stream).await);
+
+    assert_eq!(Box::new(upstream).stop().await, 2);
+    log::info!("All done :)");
+}
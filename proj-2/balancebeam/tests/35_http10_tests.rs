@@ -0,0 +1,146 @@
+mod common;
+
+use common::{init_logging, BalanceBeam, EchoServer, Server};
+use std::time::Duration;
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::time::timeout;
+
+/// Sends a request on a new connection and reads everything balancebeam sends back until it
+/// hangs up
+async fn send_until_close(address: &str, request: &[u8]) -> String {
+    let mut stream = TcpStream::connect(address).await.expect("Could not connect to balancebeam");
+    stream.write_all(request).await.expect("Error sending request to balancebeam");
+    let mut response = Vec::new();
+    timeout(Duration::from_secs(5), stream.read_to_end(&mut response))
+        .await
+        .expect("Balancebeam didn't close the connection")
+        .expect("Error reading response from balancebeam");
+    String::from_utf8(response).expect("Response is not valid UTF-8")
+}
+
+/// Reads one response with a Content-Length body off the stream, returning (headers, body)
+async fn read_response(stream: &mut BufReader<TcpStream>) -> (String, String) {
+    let mut headers = String::new();
+    let mut content_length = 0;
+    loop {
+        let mut line = String::new();
+        stream
+            .read_line(&mut line)
+            .await
+            .expect("Error reading response from balancebeam");
+        if line == "\r\n" || line.is_empty() {
+            break;
+        }
+        if let Some(value) = line.to_lowercase().strip_prefix("content-length:") {
+            content_length = value.trim().parse().expect("Invalid Content-Length");
+        }
+        headers += &line.to_lowercase();
+    }
+    let mut body = vec![0_u8; content_length];
+    stream
+        .read_exact(&mut body)
+        .await
+        .expect("Error reading response body from balancebeam");
+    (headers, String::from_utf8(body).expect("Response body is not valid UTF-8"))
+}
+
+/// Starts an upstream that answers every request with a chunked body
+async fn start_chunked_upstream() -> String {
+    let listener = TcpListener::bind("127.0.0.1:0")
+        .await
+        .expect("Could not bind chunked upstream");
+    let address = listener.local_addr().unwrap().to_string();
+    tokio::spawn(async move {
+        while let Ok((stream, _)) = listener.accept().await {
+            tokio::spawn(async move {
+                let mut stream = BufReader::new(stream);
+                let mut line = String::new();
+                while line != "\r\n" {
+                    line.clear();
+                    match stream.read_line(&mut line).await {
+                        Ok(0) | Err(_) => return,
+                        Ok(_) => {}
+                    }
+                }
+                let _ = stream
+                    .write_all(
+                        b"HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\n\
+                          8\r\nstreamed\r\n9\r\n response\r\n0\r\n\r\n",
+                    )
+                    .await;
+            });
+        }
+    });
+    address
+}
+
+/// HTTP/1.0 connections should be closed after one request, unless the client asks to keep it
+#[tokio::test]
+async fn test_no_implicit_keep_alive() {
+    init_logging();
+    let upstream = EchoServer::new().await;
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&upstream.address],
+        &["--active-health-check-interval", "60"],
+    )
+    .await;
+
+    let response = send_until_close(&balancebeam.address, b"GET /once HTTP/1.0\r\n\r\n").await;
+    assert!(response.starts_with("HTTP/1.1 200"), "Unexpected response: {}", response);
+    assert!(response.to_lowercase().contains("connection: close"));
+    // The upstream is spoken to in HTTP/1.1
+    assert!(response.contains("GET /once HTTP/1.1"));
+
+    let stream = TcpStream::connect(&balancebeam.address)
+        .await
+        .expect("Could not connect to balancebeam");
+    let mut stream = BufReader::new(stream);
+    for path in &["/first", "/second"] {
+        let request = format!("GET {} HTTP/1.0\r\nConnection: keep-alive\r\n\r\n", path);
+        stream
+            .write_all(request.as_bytes())
+            .await
+            .expect("Error sending request to balancebeam");
+        let (headers, body) = read_response(&mut stream).await;
+        assert!(headers.contains("connection: keep-alive"), "Unexpected response: {}", headers);
+        assert!(body.starts_with(&format!("GET {} HTTP/1.1", path)));
+    }
+
+    assert_eq!(Box::new(upstream).stop().await, 3);
+    log::info!("All done :)");
+}
+
+/// HTTP/1.0 clients can't read chunked bodies, so they should get the body as plain bytes, ended
+/// by the connection closing
+#[tokio::test]
+async fn test_chunked_response_to_http10() {
+    init_logging();
+    let upstream_address = start_chunked_upstream().await;
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&upstream_address],
+        &["--active-health-check-interval", "60"],
+    )
+    .await;
+
+    let response = send_until_close(
+        &balancebeam.address,
+        b"GET /stream HTTP/1.0\r\nConnection: keep-alive\r\n\r\n",
+    )
+    .await;
+    let (headers, body) = response.split_once("\r\n\r\n").expect("Response has no body");
+    let headers = headers.to_lowercase();
+    assert!(!headers.contains("transfer-encoding"), "Unexpected response: {}", headers);
+    assert!(headers.contains("connection: close"), "Unexpected response: {}", headers);
+    assert_eq!(body, "streamed response");
+
+    // HTTP/1.1 clients still get it chunked
+    let response = send_until_close(
+        &balancebeam.address,
+        b"GET /stream HTTP/1.1\r\nConnection: close\r\n\r\n",
+    )
+    .await;
+    assert!(response.to_lowercase().contains("transfer-encoding: chunked"));
+
+    log::info!("All done :)");
+}
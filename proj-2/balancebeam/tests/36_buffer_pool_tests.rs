@@ -0,0 +1,57 @@
+mod common;
+
+use common::{free_address, init_logging, BalanceBeam, EchoServer, Server};
+
+/// Fetches the buffer reuse stats from the admin API
+async fn buffer_stats(admin: &str) -> serde_json::Value {
+    reqwest::get(format!("http://{}/buffers", admin))
+        .await
+        .expect("Error sending request to admin API")
+        .json()
+        .await
+        .expect("Admin API returned invalid JSON")
+}
+
+/// Under a steady stream of requests with bodies, each connection's buffer should reuse its memory
+/// once the pieces split off it have been passed on, rather than allocating for every request and
+/// every piece of every body
+#[tokio::test]
+async fn test_buffers_reused() {
+    init_logging();
+    let upstream = EchoServer::new().await;
+    let admin = free_address();
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&upstream.address],
+        &["--admin-bind", &admin, "--active-health-check-interval", "60"],
+    )
+    .await;
+
+    const NUM_REQUESTS: usize = 50;
+    let client = reqwest::Client::new();
+    // A few pieces' worth of body, each way
+    let body = "x".repeat(32 * 1024);
+    for i in 0..NUM_REQUESTS {
+        let response = client
+            .post(format!("http://{}/upload-{}", balancebeam.address, i))
+            .body(body.clone())
+            .send()
+            .await
+            .expect("Error sending request to balancebeam")
+            .text()
+            .await
+            .expect("Balancebeam replied with a malformed response");
+        assert!(response.ends_with(&body));
+    }
+
+    let stats = buffer_stats(&admin).await;
+    let allocated = stats["allocated"].as_u64().unwrap();
+    let reused = stats["reused"].as_u64().unwrap();
+    log::info!("{} buffers allocated, {} reused", allocated, reused);
+    // Without reuse, every request would allocate a buffer for its headers and one for every
+    // piece of its body and its response's
+    assert!(reused >= 4 * NUM_REQUESTS as u64, "Only {} buffers were reused", reused);
+    assert!(allocated < NUM_REQUESTS as u64 / 4, "{} buffers were allocated", allocated);
+
+    assert_eq!(Box::new(upstream).stop().await, NUM_REQUESTS);
+    log::info!("All done :)");
+}
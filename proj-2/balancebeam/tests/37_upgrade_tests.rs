@@ -0,0 +1,134 @@
+mod common;
+
+use common::{free_address, init_logging, BalanceBeam, EchoServer, Server};
+use nix::sys::signal::{kill, Signal};
+use nix::unistd::Pid;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+use tokio::time::sleep;
+
+/// Finds the balancebeam process, other than `old_pid`, that was started with the given bind
+/// address
+fn find_successor(address: &str, old_pid: u32) -> Option<i32> {
+    for entry in std::fs::read_dir("/proc").ok()?.flatten() {
+        let pid: i32 = match entry.file_name().to_str().and_then(|name| name.parse().ok()) {
+            Some(pid) => pid,
+            None => continue,
+        };
+        if pid as u32 == old_pid {
+            continue;
+        }
+        let cmdline = match std::fs::read(entry.path().join("cmdline")) {
+            Ok(cmdline) => cmdline,
+            Err(_) => continue,
+        };
+        let args: Vec<&[u8]> = cmdline.split(|&byte| byte == 0).collect();
+        let is_balancebeam = args.first().is_some_and(|program| program.ends_with(b"balancebeam"));
+        if is_balancebeam && args.windows(2).any(|pair| pair == [b"--bind", address.as_bytes()]) {
+            return Some(pid);
+        }
+    }
+    None
+}
+
+/// On SIGUSR2, a new process should take over the listening sockets and the old one should exit,
+/// without any connection being refused along the way
+#[tokio::test]
+async fn test_upgrade_without_downtime() {
+    init_logging();
+    let upstream = EchoServer::new().await;
+    let admin = free_address();
+    let mut balancebeam = BalanceBeam::new_with_args(
+        &[&upstream.address],
+        &["--admin-bind", &admin, "--active-health-check-interval", "60"],
+    )
+    .await;
+    let old_pid = balancebeam.pid();
+
+    // Keep sending requests, each on a new connection, all through the upgrade
+    let done = Arc::new(AtomicBool::new(false));
+    let succeeded = Arc::new(AtomicUsize::new(0));
+    let load = {
+        let address = balancebeam.address.clone();
+        let done = done.clone();
+        let succeeded = succeeded.clone();
+        tokio::spawn(async move {
+            let client = reqwest::Client::builder().pool_max_idle_per_host(0).build().unwrap();
+            let mut i = 0;
+            while !done.load(Ordering::SeqCst) {
+                let response = client
+                    .get(format!("http://{}/request-{}", address, i))
+                    .send()
+                    .await
+                    .expect("Request failed during the upgrade");
+                assert_eq!(response.status().as_u16(), 200);
+                succeeded.fetch_add(1, Ordering::SeqCst);
+                i += 1;
+            }
+        })
+    };
+
+    sleep(Duration::from_millis(300)).await;
+    balancebeam.send_signal(Signal::SIGUSR2);
+    assert!(
+        balancebeam.wait_for_exit(Duration::from_secs(10)).await,
+        "The old process didn't exit after the new one took over"
+    );
+    let successor = find_successor(&balancebeam.address, old_pid)
+        .expect("Couldn't find the process that took over");
+    sleep(Duration::from_millis(300)).await;
+    done.store(true, Ordering::SeqCst);
+    load.await.expect("Requests failed during the upgrade");
+    assert!(succeeded.load(Ordering::SeqCst) > 0);
+
+    // The new process has the admin API's socket, too
+    let status = reqwest::get(format!("http://{}/upstreams", admin))
+        .await
+        .expect("Error sending request to admin API")
+        .status();
+    assert_eq!(status.as_u16(), 200);
+
+    kill(Pid::from_raw(successor), Signal::SIGKILL).expect("Couldn't stop the new process");
+    log::info!("All done :)");
+}
+
+/// On SIGQUIT, a request that's in progress should be finished before balancebeam exits
+#[tokio::test]
+async fn test_quit_finishes_requests() {
+    init_logging();
+    let upstream = EchoServer::new().await;
+    let mut balancebeam = BalanceBeam::new_with_args(
+        &[&upstream.address],
+        &["--active-health-check-interval", "60"],
+    )
+    .await;
+
+    // Start a request whose body is still on its way when SIGQUIT arrives
+    let mut stream = TcpStream::connect(&balancebeam.address)
+        .await
+        .expect("Could not connect to balancebeam");
+    stream
+        .write_all(b"POST /slow HTTP/1.1\r\nHost: localhost\r\nContent-Length: 23\r\n\r\nfirst half, ")
+        .await
+        .expect("Error sending request to balancebeam");
+    sleep(Duration::from_millis(300)).await;
+    balancebeam.send_signal(Signal::SIGQUIT);
+    sleep(Duration::from_millis(300)).await;
+    stream.write_all(b"second half").await.expect("Error sending request to balancebeam");
+
+    // The connection is closed once the response is sent, rather than kept open for another
+    let mut response = String::new();
+    stream
+        .read_to_string(&mut response)
+        .await
+        .expect("Error reading response from balancebeam");
+    assert!(response.starts_with("HTTP/1.1 200"), "Unexpected response: {}", response);
+    assert!(response.ends_with("first half, second half"), "Unexpected response: {}", response);
+    assert!(balancebeam.wait_for_exit(Duration::from_secs(5)).await);
+
+    assert_eq!(Box::new(upstream).stop().await, 1);
+    log::info!("All done :)");
+}
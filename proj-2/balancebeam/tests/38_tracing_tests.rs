@@ -0,0 +1,73 @@
+#![cfg(feature = "otel")]
+
+mod common;
+
+use common::{init_logging, BalanceBeam, EchoServer, Server};
+use nix::sys::signal::Signal;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpListener;
+
+/// Stands in for an OTLP collector, keeping the request line of every request it gets
+async fn start_collector() -> (String, Arc<Mutex<Vec<String>>>) {
+    let listener = TcpListener::bind("127.0.0.1:0").await.expect("Could not bind collector");
+    let address = listener.local_addr().unwrap().to_string();
+    let received = Arc::new(Mutex::new(Vec::new()));
+    let collected = received.clone();
+    tokio::spawn(async move {
+        loop {
+            let (mut stream, _) = match listener.accept().await {
+                Ok(accepted) => accepted,
+                Err(_) => return,
+            };
+            let received = collected.clone();
+            tokio::spawn(async move {
+                // The exporter's requests are small, so one read gets the request line
+                let mut buf = vec![0; 64 * 1024];
+                let len = stream.read(&mut buf).await.unwrap_or(0);
+                let request = String::from_utf8_lossy(&buf[..len]).to_string();
+                if let Some(line) = request.lines().next() {
+                    received.lock().unwrap().push(line.to_string());
+                }
+                let _ = stream.write_all(b"HTTP/1.1 200 OK\r\nContent-Length: 0\r\n\r\n").await;
+            });
+        }
+    });
+    (address, received)
+}
+
+/// With an OTLP endpoint given, the spans for a proxied request should be exported there, at the
+/// latest when balancebeam shuts down
+#[tokio::test]
+async fn test_traces_exported() {
+    init_logging();
+    let (collector, received) = start_collector().await;
+    let upstream = EchoServer::new().await;
+    let endpoint = format!("http://{}/v1/traces", collector);
+    let mut balancebeam = BalanceBeam::new_with_args(
+        &[&upstream.address],
+        &["--otlp-endpoint", &endpoint, "--active-health-check-interval", "60"],
+    )
+    .await;
+
+    let response = reqwest::get(format!("http://{}/traced", balancebeam.address))
+        .await
+        .expect("Error sending request to balancebeam")
+        .text()
+        .await
+        .expect("Balancebeam replied with a malformed response");
+    assert!(response.starts_with("GET /traced HTTP/1.1"));
+
+    balancebeam.send_signal(Signal::SIGQUIT);
+    assert!(balancebeam.wait_for_exit(Duration::from_secs(10)).await);
+    let received = received.lock().unwrap().clone();
+    assert!(
+        received.iter().any(|line| line.starts_with("POST /v1/traces")),
+        "No traces were exported: {:?}",
+        received
+    );
+
+    assert_eq!(Box::new(upstream).stop().await, 1);
+    log::info!("All done :)");
+}
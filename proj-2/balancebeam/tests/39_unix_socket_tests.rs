@@ -0,0 +1,84 @@
+mod common;
+
+use common::{init_logging, BalanceBeam, EchoServer, Server};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{UnixListener, UnixStream};
+
+/// Returns a path in the temp directory for a socket that doesn't exist yet
+fn socket_path(name: &str) -> String {
+    let path = std::env::temp_dir()
+        .join(format!("balancebeam-{}-{}.sock", std::process::id(), name));
+    let _ = std::fs::remove_file(&path);
+    path.to_str().unwrap().to_string()
+}
+
+/// Balancebeam should accept clients on a Unix domain socket and proxy their requests as usual
+#[tokio::test]
+async fn test_unix_listener() {
+    init_logging();
+    let upstream = EchoServer::new().await;
+    let path = socket_path("listener");
+    let _balancebeam = BalanceBeam::new_at_address(
+        format!("unix:{}", path),
+        &[&upstream.address],
+        &["--active-health-check-interval", "60"],
+    )
+    .await;
+
+    let mut stream = UnixStream::connect(&path).await.expect("Could not connect to balancebeam");
+    stream
+        .write_all(b"GET /over-unix HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n")
+        .await
+        .expect("Error sending request to balancebeam");
+    let mut response = String::new();
+    stream
+        .read_to_string(&mut response)
+        .await
+        .expect("Error reading response from balancebeam");
+    assert!(response.starts_with("HTTP/1.1 200"), "Unexpected response: {}", response);
+    assert!(response.contains("GET /over-unix HTTP/1.1"), "Unexpected response: {}", response);
+
+    assert_eq!(Box::new(upstream).stop().await, 1);
+    let _ = std::fs::remove_file(&path);
+    log::info!("All done :)");
+}
+
+/// An upstream given as unix:<path> should be connected to over its Unix domain socket
+#[tokio::test]
+async fn test_unix_upstream() {
+    init_logging();
+    let path = socket_path("upstream");
+    let listener = UnixListener::bind(&path).expect("Could not bind upstream socket");
+    let upstream = tokio::spawn(async move {
+        let (mut stream, _) = listener.accept().await.expect("Upstream failed to accept");
+        let mut request = Vec::new();
+        let mut buf = [0; 1024];
+        while !request.ends_with(b"\r\n\r\n") {
+            let len = stream.read(&mut buf).await.expect("Upstream failed to read request");
+            assert!(len > 0, "Connection closed before the request was complete");
+            request.extend_from_slice(&buf[..len]);
+        }
+        stream
+            .write_all(b"HTTP/1.1 200 OK\r\nContent-Length: 15\r\n\r\nhello from unix")
+            .await
+            .expect("Upstream failed to write response");
+        String::from_utf8(request).unwrap()
+    });
+
+    let upstream_address = format!("unix:{}", path);
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&upstream_address],
+        &["--active-health-check-interval", "60"],
+    )
+    .await;
+    let response = balancebeam
+        .get("/to-unix")
+        .await
+        .expect("Error sending request to balancebeam");
+    assert_eq!(response, "hello from unix");
+    let request = upstream.await.expect("Upstream task failed");
+    assert!(request.starts_with("GET /to-unix HTTP/1.1"), "Unexpected request: {}", request);
+
+    let _ = std::fs::remove_file(&path);
+    log::info!("All done :)");
+}
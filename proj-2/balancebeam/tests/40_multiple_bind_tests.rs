@@ -0,0 +1,90 @@
+mod common;
+
+use common::{free_address, init_logging, BalanceBeam, EchoServer, Server};
+
+/// Sends a request to balancebeam at the given address, returning the request the upstream saw
+async fn get_via(address: &str, path: &str) -> String {
+    reqwest::get(format!("http://{}{}", address, path))
+        .await
+        .expect("Error sending request to balancebeam")
+        .text()
+        .await
+        .expect("Balancebeam replied with a malformed response")
+}
+
+/// Returns the port of an address like the ones `free_address` gives out
+fn port_of(address: &str) -> &str {
+    address.rsplit_once(':').unwrap().1
+}
+
+/// Every address given with --bind should be listened on, all serving the same upstreams
+#[tokio::test]
+async fn test_multiple_binds() {
+    init_logging();
+    let upstream = EchoServer::new().await;
+    let second = free_address();
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&upstream.address],
+        &["--bind", &second, "--active-health-check-interval", "60"],
+    )
+    .await;
+
+    let first_response = get_via(&balancebeam.address, "/first").await;
+    assert!(first_response.starts_with("GET /first HTTP/1.1"));
+    let second_response = get_via(&second, "/second").await;
+    assert!(second_response.starts_with("GET /second HTTP/1.1"));
+    // Each listener reports its own port to the upstream
+    assert!(second_response.contains(&format!("x-forwarded-port: {}\n", port_of(&second))));
+
+    assert_eq!(Box::new(upstream).stop().await, 2);
+    log::info!("All done :)");
+}
+
+/// Listening on [::] should take IPv4 connections too, with IPv4 clients seen by their IPv4
+/// address rather than an IPv4-mapped IPv6 one
+#[tokio::test]
+async fn test_dual_stack() {
+    init_logging();
+    let upstream = EchoServer::new().await;
+    let port = port_of(&free_address()).to_string();
+    let _balancebeam = BalanceBeam::new_at_address(
+        format!("[::]:{}", port),
+        &[&upstream.address],
+        &["--active-health-check-interval", "60"],
+    )
+    .await;
+
+    let response = get_via(&format!("127.0.0.1:{}", port), "/over-ipv4").await;
+    assert!(response.starts_with("GET /over-ipv4 HTTP/1.1"));
+    assert!(response.contains("x-forwarded-for: 127.0.0.1\n"), "Unexpected request: {}", response);
+    let response = get_via(&format!("[::1]:{}", port), "/over-ipv6").await;
+    assert!(response.starts_with("GET /over-ipv6 HTTP/1.1"));
+    assert!(response.contains("x-forwarded-for: ::1\n"), "Unexpected request: {}", response);
+
+    assert_eq!(Box::new(upstream).stop().await, 2);
+    log::info!("All done :)");
+}
+
+/// Listening on both 0.0.0.0 and [::] with the same port should work, each taking its own kind of
+/// connections
+#[tokio::test]
+async fn test_separate_ipv4_and_ipv6_binds() {
+    init_logging();
+    let upstream = EchoServer::new().await;
+    let port = port_of(&free_address()).to_string();
+    let ipv6_bind = format!("[::]:{}", port);
+    let _balancebeam = BalanceBeam::new_at_address(
+        format!("0.0.0.0:{}", port),
+        &[&upstream.address],
+        &["--bind", &ipv6_bind, "--active-health-check-interval", "60"],
+    )
+    .await;
+
+    let response = get_via(&format!("127.0.0.1:{}", port), "/over-ipv4").await;
+    assert!(response.starts_with("GET /over-ipv4 HTTP/1.1"));
+    let response = get_via(&format!("[::1]:{}", port), "/over-ipv6").await;
+    assert!(response.starts_with("GET /over-ipv6 HTTP/1.1"));
+
+    assert_eq!(Box::new(upstream).stop().await, 2);
+    log::info!("All done :)");
+}
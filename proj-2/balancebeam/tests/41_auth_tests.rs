@@ -0,0 +1,122 @@
+mod common;
+
+use common::{init_logging, BalanceBeam, EchoServer, Server};
+
+/// Writes an htpasswd file with a user for each kind of hash we understand, and one whose password
+/// isn't hashed at all, returning its path
+fn write_htpasswd() -> String {
+    let path = std::env::temp_dir().join(format!("balancebeam-{}.htpasswd", std::process::id()));
+    let alice = bcrypt::hash("wonderland", 4).unwrap();
+    let contents = format!(
+        "# Made by hand\nalice:{}\nbob:$apr1$abcdefgh$h9FWgUz3n9YxylKLlR5SQ/\ncarol:{{SHA}}87u9ZqY9S/F0eUBXjsPQEDUw4h0=\n\ndave:plaintext\n",
+        alice
+    );
+    std::fs::write(&path, contents).unwrap();
+    path.to_str().unwrap().to_string()
+}
+
+/// Requests need the right user name and password when an htpasswd file is given, whichever kind
+/// of hash the password is stored as
+#[tokio::test]
+async fn test_basic_auth() {
+    init_logging();
+    let upstream = EchoServer::new().await;
+    let htpasswd = write_htpasswd();
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&upstream.address],
+        &["--auth-htpasswd", &htpasswd, "--active-health-check-interval", "60"],
+    )
+    .await;
+    let url = format!("http://{}/private", balancebeam.address);
+    let client = reqwest::Client::new();
+
+    let response = client.get(&url).send().await.expect("Error sending request to balancebeam");
+    assert_eq!(response.status().as_u16(), 401);
+    assert_eq!(
+        response.headers()["www-authenticate"],
+        "Basic realm=\"balancebeam\", charset=\"UTF-8\""
+    );
+    // Passwords stored as themselves aren't accepted
+    let wrong = [("alice", "wrong"), ("nobody", "wonderland"), ("bob", ""), ("dave", "plaintext")];
+    for (user, password) in &wrong {
+        let response = client
+            .get(&url)
+            .basic_auth(user, Some(password))
+            .send()
+            .await
+            .expect("Error sending request to balancebeam");
+        assert_eq!(response.status().as_u16(), 401, "{} got in with {:?}", user, password);
+    }
+
+    let users = [
+        ("alice", "wonderland"),
+        ("bob", "secret"),
+        ("carol", "hunter2"),
+    ];
+    for (user, password) in &users {
+        // The second time around, the credentials have already been checked
+        for _ in 0..2 {
+            let response = client
+                .get(&url)
+                .basic_auth(user, Some(password))
+                .send()
+                .await
+                .expect("Error sending request to balancebeam");
+            assert_eq!(response.status().as_u16(), 200, "{} couldn't log in", user);
+        }
+    }
+
+    assert_eq!(Box::new(upstream).stop().await, 2 * users.len());
+    let _ = std::fs::remove_file(&htpasswd);
+    log::info!("All done :)");
+}
+
+/// Requests need the right bearer token when one is given, and are told if theirs was wrong
+#[tokio::test]
+async fn test_bearer_token() {
+    init_logging();
+    let upstream = EchoServer::new().await;
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&upstream.address],
+        &[
+            "--auth-bearer-token",
+            "s3cret-token",
+            "--auth-realm",
+            "api",
+            "--active-health-check-interval",
+            "60",
+        ],
+    )
+    .await;
+    let url = format!("http://{}/private", balancebeam.address);
+    let client = reqwest::Client::new();
+
+    let response = client.get(&url).send().await.expect("Error sending request to balancebeam");
+    assert_eq!(response.status().as_u16(), 401);
+    assert_eq!(response.headers()["www-authenticate"], "Bearer realm=\"api\"");
+
+    let response = client
+        .get(&url)
+        .bearer_auth("guess")
+        .send()
+        .await
+        .expect("Error sending request to balancebeam");
+    assert_eq!(response.status().as_u16(), 401);
+    assert_eq!(
+        response.headers()["www-authenticate"],
+        "Bearer realm=\"api\", error=\"invalid_token\""
+    );
+
+    let response = client
+        .get(&url)
+        .bearer_auth("s3cret-token")
+        .send()
+        .await
+        .expect("Error sending request to balancebeam");
+    assert_eq!(response.status().as_u16(), 200);
+    let text = response.text().await.unwrap();
+    assert!(text.starts_with("GET /private HTTP/1.1"));
+
+    assert_eq!(Box::new(upstream).stop().await, 1);
+    log::info!("All done :)");
+}
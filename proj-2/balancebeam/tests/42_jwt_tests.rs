@@ -0,0 +1,198 @@
+mod common;
+
+use common::{init_logging, BalanceBeam, EchoServer, Server};
+use jsonwebtoken::{Algorithm, EncodingKey, Header};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+const SECRET: &str = "not-so-secret";
+
+/// Seconds since the epoch, give or take the given offset
+fn timestamp(offset: i64) -> i64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs() as i64 + offset
+}
+
+/// Makes an HS256 token with the given claims, valid for an hour
+fn hs256_token(claims: serde_json::Value) -> String {
+    let mut claims = claims;
+    if claims.get("exp").is_none() {
+        claims["exp"] = timestamp(3600).into();
+    }
+    let key = EncodingKey::from_secret(SECRET.as_bytes());
+    jsonwebtoken::encode(&Header::new(Algorithm::HS256), &claims, &key).unwrap()
+}
+
+/// Sends a GET request with the given bearer token, returning the response's status and its
+/// WWW-Authenticate header
+async fn get_with_token(url: &str, token: Option<&str>) -> (u16, Option<String>) {
+    let mut request = reqwest::Client::new().get(url);
+    if let Some(token) = token {
+        request = request.bearer_auth(token);
+    }
+    let response = request.send().await.expect("Error sending request to balancebeam");
+    let challenge = response
+        .headers()
+        .get("www-authenticate")
+        .map(|value| value.to_str().unwrap().to_string());
+    (response.status().as_u16(), challenge)
+}
+
+/// Only requests with a valid, unexpired token signed with the secret should get through
+#[tokio::test]
+async fn test_hs256_tokens() {
+    init_logging();
+    let upstream = EchoServer::new().await;
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&upstream.address],
+        &["--jwt-hs256-secret", SECRET, "--active-health-check-interval", "60"],
+    )
+    .await;
+    let url = format!("http://{}/api", balancebeam.address);
+
+    let (status, challenge) = get_with_token(&url, None).await;
+    assert_eq!(status, 401);
+    assert_eq!(challenge.as_deref(), Some("Bearer realm=\"balancebeam\""));
+
+    let expired = hs256_token(serde_json::json!({ "sub": "alice", "exp": timestamp(-3600) }));
+    let wrong_key = jsonwebtoken::encode(
+        &Header::new(Algorithm::HS256),
+        &serde_json::json!({ "sub": "alice", "exp": timestamp(3600) }),
+        &EncodingKey::from_secret(b"some other secret"),
+    )
+    .unwrap();
+    for token in &["garbage", expired.as_str(), wrong_key.as_str()] {
+        let (status, challenge) = get_with_token(&url, Some(token)).await;
+        assert_eq!(status, 401, "Token {} was accepted", token);
+        assert_eq!(
+            challenge.as_deref(),
+            Some("Bearer realm=\"balancebeam\", error=\"invalid_token\"")
+        );
+    }
+
+    let token = hs256_token(serde_json::json!({ "sub": "alice" }));
+    let (status, _) = get_with_token(&url, Some(&token)).await;
+    assert_eq!(status, 200);
+
+    assert_eq!(Box::new(upstream).stop().await, 1);
+    log::info!("All done :)");
+}
+
+/// RS256 tokens should be checked with the public key, and tokens from other issuers or signed
+/// with algorithms we have no key for turned away
+#[tokio::test]
+async fn test_rs256_tokens() {
+    init_logging();
+    let upstream = EchoServer::new().await;
+    let public_key = format!("{}/tests/certs/jwt.pub", env!("CARGO_MANIFEST_DIR"));
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&upstream.address],
+        &[
+            "--jwt-rs256-public-key",
+            &public_key,
+            "--jwt-issuer",
+            "https://auth.example.com",
+            "--active-health-check-interval",
+            "60",
+        ],
+    )
+    .await;
+    let url = format!("http://{}/api", balancebeam.address);
+    let private_key = std::fs::read(format!("{}/tests/certs/jwt.key", env!("CARGO_MANIFEST_DIR")))
+        .expect("Could not read test private key");
+    let key = EncodingKey::from_rsa_pem(&private_key).unwrap();
+    let rs256_token = |issuer: &str| {
+        let claims = serde_json::json!({ "sub": "alice", "iss": issuer, "exp": timestamp(3600) });
+        jsonwebtoken::encode(&Header::new(Algorithm::RS256), &claims, &key).unwrap()
+    };
+
+    let (status, _) = get_with_token(&url, Some(&rs256_token("https://auth.example.com"))).await;
+    assert_eq!(status, 200);
+    let (status, _) = get_with_token(&url, Some(&rs256_token("https://evil.example.com"))).await;
+    assert_eq!(status, 401);
+    let hs256 =
+        hs256_token(serde_json::json!({ "sub": "alice", "iss": "https://auth.example.com" }));
+    let (status, _) = get_with_token(&url, Some(&hs256)).await;
+    assert_eq!(status, 401);
+
+    assert_eq!(Box::new(upstream).stop().await, 1);
+    log::info!("All done :)");
+}
+
+/// With --jwt-route-claim, requests should go to the pool their token names
+#[tokio::test]
+async fn test_route_by_claim() {
+    init_logging();
+    let default_upstream = EchoServer::new().await;
+    let tenant_a = EchoServer::new().await;
+    let tenant_b = EchoServer::new().await;
+    let pool_a = format!("tenant-a={}", tenant_a.address);
+    let pool_b = format!("tenant-b={}", tenant_b.address);
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&default_upstream.address],
+        &[
+            "--pool",
+            &pool_a,
+            "--pool",
+            &pool_b,
+            "--jwt-hs256-secret",
+            SECRET,
+            "--jwt-route-claim",
+            "tenant",
+            "--active-health-check-interval",
+            "60",
+        ],
+    )
+    .await;
+    let url = format!("http://{}/api", balancebeam.address);
+
+    let tokens = [
+        hs256_token(serde_json::json!({ "sub": "alice", "tenant": "tenant-a" })),
+        hs256_token(serde_json::json!({ "sub": "bob", "tenant": "tenant-b" })),
+        hs256_token(serde_json::json!({ "sub": "carol", "tenant": "tenant-b" })),
+        // A tenant without a pool of its own, and a token without a tenant, go to the default pool
+        hs256_token(serde_json::json!({ "sub": "dave", "tenant": "tenant-c" })),
+        hs256_token(serde_json::json!({ "sub": "erin" })),
+    ];
+    for token in &tokens {
+        let (status, _) = get_with_token(&url, Some(token)).await;
+        assert_eq!(status, 200);
+    }
+
+    assert_eq!(Box::new(tenant_a).stop().await, 1);
+    assert_eq!(Box::new(tenant_b).stop().await, 2);
+    assert_eq!(Box::new(default_upstream).stop().await, 2);
+    log::info!("All done :)");
+}
+
+/// With --rate-limit-by jwt, each token subject should get its own quota
+#[tokio::test]
+async fn test_rate_limit_by_claim() {
+    init_logging();
+    let upstream = EchoServer::new().await;
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&upstream.address],
+        &[
+            "--jwt-hs256-secret",
+            SECRET,
+            "--rate-limit-by",
+            "jwt",
+            "--max-requests-per-minute",
+            "2",
+            "--active-health-check-interval",
+            "60",
+        ],
+    )
+    .await;
+    let url = format!("http://{}/api", balancebeam.address);
+
+    let alice = hs256_token(serde_json::json!({ "sub": "alice" }));
+    let bob = hs256_token(serde_json::json!({ "sub": "bob" }));
+    for _ in 0..2 {
+        assert_eq!(get_with_token(&url, Some(&alice)).await.0, 200);
+    }
+    assert_eq!(get_with_token(&url, Some(&alice)).await.0, 429);
+    // Bob's requests come from the same IP, but are counted separately
+    assert_eq!(get_with_token(&url, Some(&bob)).await.0, 200);
+
+    assert_eq!(Box::new(upstream).stop().await, 3);
+    log::info!("All done :)");
+}
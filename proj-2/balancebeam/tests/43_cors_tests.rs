@@ -0,0 +1,159 @@
+mod common;
+
+use common::{init_logging, BalanceBeam, EchoServer, Server};
+
+const ORIGIN: &str = "https://app.example.com";
+
+/// Returns the value of a response header, if the response has it
+fn header(response: &reqwest::Response, name: &str) -> Option<String> {
+    response.headers().get(name).map(|value| value.to_str().unwrap().to_string())
+}
+
+/// Preflight requests from allowed origins should be answered by balancebeam itself, even when
+/// requests need a token, which browsers don't send with them
+#[tokio::test]
+async fn test_preflight() {
+    init_logging();
+    let upstream = EchoServer::new().await;
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&upstream.address],
+        &[
+            "--cors-allow-origin",
+            ORIGIN,
+            "--cors-allow-method",
+            "GET",
+            "--cors-allow-method",
+            "PUT",
+            "--cors-max-age",
+            "600",
+            "--jwt-hs256-secret",
+            "not-so-secret",
+            "--active-health-check-interval",
+            "60",
+        ],
+    )
+    .await;
+    let client = reqwest::Client::new();
+    let url = format!("http://{}/api/items/1", balancebeam.address);
+    let preflight = |origin: &'static str| {
+        client
+            .request(reqwest::Method::OPTIONS, &url)
+            .header("Origin", origin)
+            .header("Access-Control-Request-Method", "PUT")
+            .header("Access-Control-Request-Headers", "authorization, content-type")
+            .send()
+    };
+
+    let response = preflight(ORIGIN).await.expect("Error sending request to balancebeam");
+    assert_eq!(response.status().as_u16(), 204);
+    assert_eq!(header(&response, "access-control-allow-origin").as_deref(), Some(ORIGIN));
+    assert_eq!(header(&response, "access-control-allow-methods").as_deref(), Some("GET, PUT"));
+    assert_eq!(
+        header(&response, "access-control-allow-headers").as_deref(),
+        Some("authorization, content-type")
+    );
+    assert_eq!(header(&response, "access-control-max-age").as_deref(), Some("600"));
+
+    // Other origins get an answer that doesn't allow anything
+    let response = preflight("https://evil.example.com")
+        .await
+        .expect("Error sending request to balancebeam");
+    assert_eq!(response.status().as_u16(), 204);
+    assert_eq!(header(&response, "access-control-allow-origin"), None);
+    assert_eq!(header(&response, "access-control-allow-methods"), None);
+
+    assert_eq!(Box::new(upstream).stop().await, 0);
+    log::info!("All done :)");
+}
+
+/// Responses to requests from allowed origins should get the headers that let scripts read them
+#[tokio::test]
+async fn test_response_headers() {
+    init_logging();
+    let upstream = EchoServer::new().await;
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&upstream.address],
+        &[
+            "--cors-allow-origin",
+            ORIGIN,
+            "--cors-expose-header",
+            "X-Request-Id",
+            "--cors-allow-credentials",
+            "--active-health-check-interval",
+            "60",
+        ],
+    )
+    .await;
+    let client = reqwest::Client::new();
+    let url = format!("http://{}/api", balancebeam.address);
+
+    let response = client.get(&url).header("Origin", ORIGIN).send().await.unwrap();
+    assert_eq!(response.status().as_u16(), 200);
+    assert_eq!(header(&response, "access-control-allow-origin").as_deref(), Some(ORIGIN));
+    assert_eq!(header(&response, "access-control-allow-credentials").as_deref(), Some("true"));
+    assert_eq!(
+        header(&response, "access-control-expose-headers").as_deref(),
+        Some("X-Request-Id")
+    );
+    assert_eq!(header(&response, "vary").as_deref(), Some("Origin"));
+
+    // Requests from other origins, or not from a browser at all, are forwarded as usual, but
+    // their responses don't say anyone may read them
+    for origin in &[Some("https://evil.example.com"), None] {
+        let mut request = client.get(&url);
+        if let Some(origin) = origin {
+            request = request.header("Origin", *origin);
+        }
+        let response = request.send().await.unwrap();
+        assert_eq!(response.status().as_u16(), 200);
+        assert_eq!(header(&response, "access-control-allow-origin"), None);
+        assert_eq!(header(&response, "access-control-allow-credentials"), None);
+    }
+
+    assert_eq!(Box::new(upstream).stop().await, 3);
+    log::info!("All done :)");
+}
+
+/// With any origin allowed, responses should say "*", unless credentials are allowed, in which
+/// case browsers need the origin named
+#[tokio::test]
+async fn test_any_origin() {
+    init_logging();
+    let upstream = EchoServer::new().await;
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&upstream.address],
+        &["--cors-allow-origin", "*", "--active-health-check-interval", "60"],
+    )
+    .await;
+    let response = reqwest::Client::new()
+        .get(format!("http://{}/api", balancebeam.address))
+        .header("Origin", ORIGIN)
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(header(&response, "access-control-allow-origin").as_deref(), Some("*"));
+    assert_eq!(header(&response, "vary"), None);
+    drop(balancebeam);
+
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&upstream.address],
+        &[
+            "--cors-allow-origin",
+            "*",
+            "--cors-allow-credentials",
+            "--active-health-check-interval",
+            "60",
+        ],
+    )
+    .await;
+    let response = reqwest::Client::new()
+        .get(format!("http://{}/api", balancebeam.address))
+        .header("Origin", ORIGIN)
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(header(&response, "access-control-allow-origin").as_deref(), Some(ORIGIN));
+
+    assert_eq!(Box::new(upstream).stop().await, 2);
+    log::info!("All done :)");
+}
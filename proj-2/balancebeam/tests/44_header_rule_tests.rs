@@ -0,0 +1,88 @@
+mod common;
+
+use common::{free_address, init_logging, write_config, BalanceBeam, EchoServer, Server};
+
+const HSTS: &str = "max-age=31536000";
+
+/// Request headers should be changed before the request is forwarded, and response headers before
+/// the response is sent back, including the ones balancebeam adds itself
+#[tokio::test]
+async fn test_header_rules() {
+    init_logging();
+    let upstream = EchoServer::new().await;
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&upstream.address],
+        &[
+            "--request-header",
+            "set:Host=internal.example.com",
+            "--request-header",
+            "remove:X-Debug",
+            "--request-header",
+            "add:X-Env=prod",
+            "--response-header",
+            "remove:Via",
+            "--response-header",
+            &format!("set:Strict-Transport-Security={}", HSTS),
+            "--active-health-check-interval",
+            "60",
+        ],
+    )
+    .await;
+
+    let response = reqwest::Client::new()
+        .get(format!("http://{}/", balancebeam.address))
+        .header("X-Debug", "1")
+        .header("X-Env", "dev")
+        .send()
+        .await
+        .expect("Error sending request to balancebeam");
+    assert_eq!(response.status().as_u16(), 200);
+    assert!(response.headers().get("via").is_none());
+    assert_eq!(response.headers().get("strict-transport-security").unwrap(), HSTS);
+    let body = response.text().await.unwrap();
+    assert!(body.contains("host: internal.example.com\n"), "Unexpected request: {}", body);
+    assert!(!body.contains("x-debug"), "Unexpected request: {}", body);
+    // Added values go alongside the ones the client sent
+    assert!(body.contains("x-env: dev\n"), "Unexpected request: {}", body);
+    assert!(body.contains("x-env: prod\n"), "Unexpected request: {}", body);
+
+    assert_eq!(Box::new(upstream).stop().await, 1);
+    log::info!("All done :)");
+}
+
+/// Rules from a config file should apply to the errors balancebeam answers with, too
+#[tokio::test]
+async fn test_header_rules_from_config_file() {
+    init_logging();
+    let config = write_config(&format!(
+        r#"
+[[response-headers]]
+action = "set"
+name = "Strict-Transport-Security"
+value = "{}"
+
+[[response-headers]]
+action = "add"
+name = "X-Served-By"
+value = "balancebeam"
+"#,
+        HSTS
+    ));
+    // Nothing is listening at the upstream's address, so requests fail
+    let upstream = free_address();
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&upstream],
+        &["--config", config.to_str().unwrap(), "--active-health-check-interval", "60"],
+    )
+    .await;
+
+    let response = reqwest::get(format!("http://{}/", balancebeam.address))
+        .await
+        .expect("Error sending request to balancebeam");
+    assert!(response.status().is_server_error());
+    assert_eq!(response.headers().get("strict-transport-security").unwrap(), HSTS);
+    assert_eq!(response.headers().get("x-served-by").unwrap(), "balancebeam");
+
+    std::fs::remove_file(config).unwrap();
+    log::info!("All done :)");
+}
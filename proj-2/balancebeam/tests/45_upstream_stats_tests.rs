@@ -0,0 +1,69 @@
+mod common;
+
+use common::{free_address, init_logging, BalanceBeam, EchoServer, ErrorServer, Server};
+
+/// The admin API should report each upstream's request count, error rate and response times
+#[tokio::test]
+async fn test_upstream_stats() {
+    init_logging();
+    let healthy = EchoServer::new().await;
+    let failing = ErrorServer::new().await;
+    let admin = free_address();
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&healthy.address, &failing.address],
+        &[
+            "--passive-health-check-failures",
+            "0",
+            "--active-health-check-interval",
+            "60",
+            "--admin-bind",
+            &admin,
+        ],
+    )
+    .await;
+
+    // Each request gets a connection of its own, so they're spread over both upstreams
+    for i in 0..20 {
+        reqwest::Client::new()
+            .get(format!("http://{}/request-{}", balancebeam.address, i))
+            .send()
+            .await
+            .expect("Error sending request to balancebeam");
+    }
+
+    let stats: serde_json::Value = reqwest::get(format!("http://{}/stats", admin))
+        .await
+        .expect("Error sending request to admin API")
+        .json()
+        .await
+        .expect("Admin API didn't return JSON");
+    let stats = stats.as_array().expect("Expected a list of upstreams");
+    assert_eq!(stats.len(), 2, "Unexpected stats: {:?}", stats);
+    let find = |address: &str| {
+        stats
+            .iter()
+            .find(|upstream| upstream["address"] == address)
+            .unwrap_or_else(|| panic!("No stats for {}: {:?}", address, stats))
+            .clone()
+    };
+    let healthy_stats = find(&healthy.address);
+    let failing_stats = find(&failing.address);
+
+    let requests = |stats: &serde_json::Value| stats["requests"].as_u64().unwrap();
+    assert_eq!(requests(&healthy_stats) + requests(&failing_stats), 20);
+    assert_eq!(healthy_stats["errors"], 0);
+    assert_eq!(healthy_stats["error_rate"], 0.0);
+    assert_eq!(failing_stats["errors"], failing_stats["requests"]);
+    assert_eq!(failing_stats["error_rate"], 1.0);
+    for stats in &[healthy_stats, failing_stats] {
+        assert!(stats["requests_per_second"].as_f64().unwrap() > 0.0);
+        let latency = &stats["latency_ms"];
+        let p50 = latency["p50"].as_f64().unwrap();
+        let p95 = latency["p95"].as_f64().unwrap();
+        let p99 = latency["p99"].as_f64().unwrap();
+        assert!(0.0 < p50 && p50 <= p95 && p95 <= p99, "Unexpected latencies: {}", latency);
+    }
+
+    assert_eq!(Box::new(healthy).stop().await + Box::new(failing).stop().await, 20);
+    log::info!("All done :)");
+}
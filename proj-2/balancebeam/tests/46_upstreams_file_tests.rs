@@ -0,0 +1,65 @@
+mod common;
+
+use common::{init_logging, BalanceBeam, EchoServer, Server};
+use rand::Rng;
+use std::path::Path;
+use std::time::Duration;
+use tokio::time::sleep;
+
+/// Replaces the file's contents all at once, the way tools that manage such files do
+fn replace_file(path: &Path, contents: &str) {
+    let temp = path.with_extension("new");
+    std::fs::write(&temp, contents).expect("Could not write temporary file");
+    std::fs::rename(&temp, path).expect("Could not replace file");
+}
+
+/// Sends requests, each on a new connection, so the load balancer gets to pick an upstream for
+/// each one
+async fn send_requests(address: &str, count: usize) {
+    for i in 0..count {
+        let response = reqwest::Client::new()
+            .get(format!("http://{}/request-{}", address, i))
+            .send()
+            .await
+            .expect("Error sending request to balancebeam");
+        assert_eq!(response.status().as_u16(), 200);
+    }
+}
+
+/// Upstreams should be added and removed as the upstreams file changes
+#[tokio::test]
+async fn test_upstreams_file_changes() {
+    init_logging();
+    let first = EchoServer::new().await;
+    let second = EchoServer::new().await;
+    let path = std::env::temp_dir()
+        .join(format!("balancebeam-test-{}.upstreams", rand::thread_rng().gen::<u32>()));
+    std::fs::write(&path, format!("# Managed by a deploy tool\n{}\n", first.address)).unwrap();
+    let balancebeam = BalanceBeam::new_with_args(
+        &[],
+        &["--upstreams-file", path.to_str().unwrap(), "--active-health-check-interval", "60"],
+    )
+    .await;
+
+    send_requests(&balancebeam.address, 4).await;
+
+    // Add an upstream
+    replace_file(&path, &format!("{}\n\n{}\n", first.address, second.address));
+    sleep(Duration::from_secs(3)).await;
+    send_requests(&balancebeam.address, 4).await;
+
+    // Take the first one out again
+    replace_file(&path, &format!("{}\n", second.address));
+    sleep(Duration::from_secs(3)).await;
+    send_requests(&balancebeam.address, 4).await;
+
+    // An empty file would leave no upstreams at all, so it's ignored
+    replace_file(&path, "");
+    sleep(Duration::from_secs(3)).await;
+    send_requests(&balancebeam.address, 2).await;
+
+    std::fs::remove_file(&path).unwrap();
+    assert_eq!(Box::new(first).stop().await, 6);
+    assert_eq!(Box::new(second).stop().await, 8);
+    log::info!("All done :)");
+}
@@ -0,0 +1,441 @@
+#![cfg(feature = "discovery")]
+
+mod common;
+
+use base64::Engine;
+use common::{init_logging, BalanceBeam, EchoServer, Server};
+use hyper::service::{make_service_fn, service_fn};
+use hyper::{Body, Request, Response, StatusCode};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::sync::broadcast;
+use tokio::time::sleep;
+
+/// What the fake registry answers with: a status, and a JSON body
+type Answer = Arc<Mutex<(StatusCode, String)>>;
+
+/// Stands in for Consul or etcd, answering requests to the given path with whatever the test has
+/// put in the returned answer
+async fn start_registry(path: &'static str) -> (String, Answer) {
+    let answer: Answer = Arc::new(Mutex::new((StatusCode::OK, String::new())));
+    let served = answer.clone();
+    let service = make_service_fn(move |_| {
+        let served = served.clone();
+        async move {
+            Ok::<_, hyper::Error>(service_fn(move |req: Request<Body>| {
+                let served = served.clone();
+                async move {
+                    let (status, body) = if req.uri().path() == path {
+                        served.lock().unwrap().clone()
+                    } else {
+                        (StatusCode::NOT_FOUND, String::new())
+                    };
+                    let response = Response::builder().status(status).body(Body::from(body));
+                    Ok::<_, hyper::Error>(response.unwrap())
+                }
+            }))
+        }
+    });
+    let server = hyper::Server::bind(&"127.0.0.1:0".parse().unwrap()).serve(service);
+    let address = server.local_addr().to_string();
+    tokio::spawn(server);
+    (address, answer)
+}
+
+/// Builds a response from Consul's health API listing instances at the given addresses. Instances
+/// without an address of their own are at their node's address.
+fn consul_instances(addresses: &[&str]) -> String {
+    let entries: Vec<serde_json::Value> = addresses
+        .iter()
+        .enumerate()
+        .map(|(i, address)| {
+            let (host, port) = address.rsplit_once(':').unwrap();
+            let port: u16 = port.parse().unwrap();
+            // Leave every other instance's address to be taken from its node
+            let service_address = if i % 2 == 0 { host } else { "" };
+            serde_json::json!({
+                "Node": { "Node": format!("node-{}", i), "Address": host },
+                "Service": { "ID": format!("web-{}", i), "Address": service_address, "Port": port },
+            })
+        })
+        .collect();
+    serde_json::Value::Array(entries).to_string()
+}
+
+/// Builds a response from etcd's range API with a key for each of the given addresses
+fn etcd_instances(addresses: &[&str]) -> String {
+    let encode = |value: &str| base64::engine::general_purpose::STANDARD.encode(value);
+    let kvs: Vec<serde_json::Value> = addresses
+        .iter()
+        .enumerate()
+        .map(|(i, address)| {
+            serde_json::json!({
+                "key": encode(&format!("/services/web/{}", i)),
+                "value": encode(address),
+            })
+        })
+        .collect();
+    serde_json::json!({ "header": {}, "kvs": kvs, "count": kvs.len() }).to_string()
+}
+
+/// Builds the EndpointSlice for the `i`th pod (pods that serve on different ports go in
+/// different slices), given its address and whether it's ready
+fn endpoint_slice(i: usize, address: &str, ready: bool) -> serde_json::Value {
+    let (ip, port) = address.rsplit_once(':').unwrap();
+    let port: u16 = port.parse().unwrap();
+    serde_json::json!({
+        "metadata": { "name": format!("web-{}", i) },
+        "addressType": "IPv4",
+        "endpoints": [{ "addresses": [ip], "conditions": { "ready": ready } }],
+        "ports": [
+            { "name": "metrics", "port": 1, "protocol": "TCP" },
+            { "name": "http", "port": port, "protocol": "TCP" },
+        ],
+    })
+}
+
+/// The pods behind the fake Kubernetes service: each one's address, and whether it's ready
+type Pods = Vec<(String, bool)>;
+
+/// Stands in for the Kubernetes API server. Lists of the service's EndpointSlices are answered
+/// with the pods last set, and watches with a MODIFIED event for each slice whenever the pods are
+/// set again.
+struct FakeK8s {
+    address: String,
+    pods: Arc<Mutex<Pods>>,
+    /// Events to send to each watch, one JSON object per line. An empty string ends the watches.
+    events: broadcast::Sender<String>,
+    lists: Arc<AtomicUsize>,
+    /// Whether to answer the next watch with a 410 Gone error, the way the API server answers a
+    /// watch from a resourceVersion it no longer has
+    expire_next_watch: Arc<AtomicBool>,
+}
+
+impl FakeK8s {
+    async fn new(pods: &[(&str, bool)]) -> FakeK8s {
+        let pods: Pods =
+            pods.iter().map(|(address, ready)| (address.to_string(), *ready)).collect();
+        let pods = Arc::new(Mutex::new(pods));
+        let (events, _) = broadcast::channel::<String>(16);
+        let lists = Arc::new(AtomicUsize::new(0));
+        let expire_next_watch = Arc::new(AtomicBool::new(false));
+        let (served_pods, served_events) = (pods.clone(), events.clone());
+        let (served_lists, served_expire) = (lists.clone(), expire_next_watch.clone());
+        let service = make_service_fn(move |_| {
+            let (pods, events) = (served_pods.clone(), served_events.clone());
+            let (lists, expire) = (served_lists.clone(), served_expire.clone());
+            async move {
+                Ok::<_, hyper::Error>(service_fn(move |req: Request<Body>| {
+                    let (pods, events) = (pods.clone(), events.clone());
+                    let (lists, expire) = (lists.clone(), expire.clone());
+                    async move {
+                        let path = "/apis/discovery.k8s.io/v1/namespaces/default/endpointslices";
+                        let watch = req.uri().query().unwrap_or_default().contains("watch=true");
+                        let body = if req.uri().path() != path {
+                            let response = Response::builder().status(StatusCode::NOT_FOUND);
+                            return Ok::<_, hyper::Error>(response.body(Body::empty()).unwrap());
+                        } else if !watch {
+                            lists.fetch_add(1, Ordering::SeqCst);
+                            let items: Vec<serde_json::Value> = pods
+                                .lock()
+                                .unwrap()
+                                .iter()
+                                .enumerate()
+                                .map(|(i, (address, ready))| endpoint_slice(i, address, *ready))
+                                .collect();
+                            let list = serde_json::json!({
+                                "kind": "EndpointSliceList",
+                                "metadata": { "resourceVersion": "1" },
+                                "items": items,
+                            });
+                            Body::from(list.to_string())
+                        } else if expire.swap(false, Ordering::SeqCst) {
+                            let error = serde_json::json!({
+                                "type": "ERROR",
+                                "object": { "kind": "Status", "code": 410, "reason": "Expired" },
+                            });
+                            Body::from(format!("{}\n", error))
+                        } else {
+                            let (mut sender, body) = Body::channel();
+                            let mut events = events.subscribe();
+                            tokio::spawn(async move {
+                                while let Ok(event) = events.recv().await {
+                                    if event.is_empty() {
+                                        break;
+                                    }
+                                    if sender.send_data(event.into()).await.is_err() {
+                                        break;
+                                    }
+                                }
+                            });
+                            body
+                        };
+                        Ok::<_, hyper::Error>(Response::new(body))
+                    }
+                }))
+            }
+        });
+        let server = hyper::Server::bind(&"127.0.0.1:0".parse().unwrap()).serve(service);
+        let address = server.local_addr().to_string();
+        tokio::spawn(server);
+        FakeK8s { address, pods, events, lists, expire_next_watch }
+    }
+
+    /// Waits for balancebeam to start watching, so that it gets the events for what's set next
+    async fn wait_for_watch(&self) {
+        for _ in 0..50 {
+            if self.events.receiver_count() > 0 {
+                return;
+            }
+            sleep(Duration::from_millis(100)).await;
+        }
+        panic!("balancebeam never started watching the EndpointSlices");
+    }
+
+    /// Changes the pods, sending an event for each slice to the watches
+    fn set_pods(&self, pods: &[(&str, bool)]) {
+        let mut current = self.pods.lock().unwrap();
+        *current = pods.iter().map(|(address, ready)| (address.to_string(), *ready)).collect();
+        for (i, (address, ready)) in pods.iter().enumerate() {
+            let event = serde_json::json!({
+                "type": "MODIFIED",
+                "object": endpoint_slice(i, address, *ready),
+            });
+            let _ = self.events.send(format!("{}\n", event));
+        }
+    }
+
+    /// Changes the pods without telling the watches, then ends them and answers the next watch
+    /// with 410 Gone, so that the pods have to be listed again to be found
+    fn expire_watches_with(&self, pods: &[(&str, bool)]) {
+        *self.pods.lock().unwrap() =
+            pods.iter().map(|(address, ready)| (address.to_string(), *ready)).collect();
+        self.expire_next_watch.store(true, Ordering::SeqCst);
+        let _ = self.events.send(String::new());
+    }
+
+    fn lists(&self) -> usize {
+        self.lists.load(Ordering::SeqCst)
+    }
+}
+
+/// Sends requests, each on a new connection, so the load balancer gets to pick an upstream for
+/// each one
+async fn send_requests(address: &str, count: usize) {
+    for i in 0..count {
+        let response = reqwest::Client::new()
+            .get(format!("http://{}/request-{}", address, i))
+            .send()
+            .await
+            .expect("Error sending request to balancebeam");
+        assert_eq!(response.status().as_u16(), 200);
+    }
+}
+
+/// Instances registered in Consul should be added to the upstreams, and removed once they're gone
+#[tokio::test]
+async fn test_consul_discovery() {
+    init_logging();
+    let first = EchoServer::new().await;
+    let second = EchoServer::new().await;
+    let (registry, answer) = start_registry("/v1/health/service/web").await;
+    *answer.lock().unwrap() = (StatusCode::OK, consul_instances(&[&first.address]));
+    let balancebeam = BalanceBeam::new_with_args(
+        &[],
+        &[
+            "--discovery",
+            "consul",
+            "--discovery-address",
+            &registry,
+            "--discovery-service",
+            "web",
+            "--discovery-interval",
+            "1",
+            "--active-health-check-interval",
+            "60",
+        ],
+    )
+    .await;
+
+    send_requests(&balancebeam.address, 4).await;
+
+    // A second instance registers
+    *answer.lock().unwrap() =
+        (StatusCode::OK, consul_instances(&[&first.address, &second.address]));
+    sleep(Duration::from_secs(3)).await;
+    send_requests(&balancebeam.address, 4).await;
+
+    // The first one goes away
+    *answer.lock().unwrap() = (StatusCode::OK, consul_instances(&[&second.address]));
+    sleep(Duration::from_secs(3)).await;
+    send_requests(&balancebeam.address, 4).await;
+
+    assert_eq!(Box::new(first).stop().await, 6);
+    assert_eq!(Box::new(second).stop().await, 6);
+    log::info!("All done :)");
+}
+
+/// Addresses stored under the etcd key prefix should be used as upstreams, and kept if etcd fails
+#[tokio::test]
+async fn test_etcd_discovery() {
+    init_logging();
+    let first = EchoServer::new().await;
+    let second = EchoServer::new().await;
+    let (registry, answer) = start_registry("/v3/kv/range").await;
+    *answer.lock().unwrap() =
+        (StatusCode::OK, etcd_instances(&[&first.address, &second.address]));
+    let balancebeam = BalanceBeam::new_with_args(
+        &[],
+        &[
+            "--discovery",
+            "etcd",
+            "--discovery-address",
+            &registry,
+            "--discovery-service",
+            "/services/web/",
+            "--discovery-interval",
+            "1",
+            "--active-health-check-interval",
+            "60",
+        ],
+    )
+    .await;
+
+    send_requests(&balancebeam.address, 4).await;
+
+    // The second instance goes away
+    *answer.lock().unwrap() = (StatusCode::OK, etcd_instances(&[&first.address]));
+    sleep(Duration::from_secs(3)).await;
+    send_requests(&balancebeam.address, 4).await;
+
+    // etcd failing shouldn't take the instances away
+    *answer.lock().unwrap() = (StatusCode::SERVICE_UNAVAILABLE, String::new());
+    sleep(Duration::from_secs(3)).await;
+    send_requests(&balancebeam.address, 2).await;
+
+    assert_eq!(Box::new(first).stop().await, 8);
+    assert_eq!(Box::new(second).stop().await, 2);
+    log::info!("All done :)");
+}
+
+/// Ready pods behind a Kubernetes service should be the upstreams, using the port asked for.
+/// Changes to them should be picked up from a watch as they happen, without listing them again.
+#[tokio::test]
+async fn test_k8s_discovery() {
+    init_logging();
+    let first = EchoServer::new().await;
+    let second = EchoServer::new().await;
+    let k8s = FakeK8s::new(&[(&first.address, true), (&second.address, false)]).await;
+    let balancebeam = BalanceBeam::new_with_args(
+        &[],
+        &[
+            "--discover",
+            "k8s:default/web:http",
+            "--discovery-address",
+            &k8s.address,
+            "--discovery-interval",
+            "60",
+            "--active-health-check-interval",
+            "60",
+        ],
+    )
+    .await;
+
+    send_requests(&balancebeam.address, 4).await;
+    k8s.wait_for_watch().await;
+    let lists = k8s.lists();
+
+    // The second pod becomes ready
+    k8s.set_pods(&[(&first.address, true), (&second.address, true)]);
+    sleep(Duration::from_secs(1)).await;
+    send_requests(&balancebeam.address, 4).await;
+
+    // The first one is shutting down
+    k8s.set_pods(&[(&first.address, false), (&second.address, true)]);
+    sleep(Duration::from_secs(1)).await;
+    send_requests(&balancebeam.address, 4).await;
+
+    assert_eq!(k8s.lists(), lists, "The pods were listed again instead of watched");
+    assert_eq!(Box::new(first).stop().await, 6);
+    assert_eq!(Box::new(second).stop().await, 6);
+    log::info!("All done :)");
+}
+
+/// When a watch can't be resumed because its resourceVersion has expired, the pods should be
+/// listed again straight away, rather than after the interval
+#[tokio::test]
+async fn test_k8s_watch_expired() {
+    init_logging();
+    let first = EchoServer::new().await;
+    let second = EchoServer::new().await;
+    let k8s = FakeK8s::new(&[(&first.address, true)]).await;
+    let balancebeam = BalanceBeam::new_with_args(
+        &[],
+        &[
+            "--discover",
+            "k8s:default/web:http",
+            "--discovery-address",
+            &k8s.address,
+            "--discovery-interval",
+            "60",
+            "--active-health-check-interval",
+            "60",
+        ],
+    )
+    .await;
+
+    send_requests(&balancebeam.address, 2).await;
+    k8s.wait_for_watch().await;
+    let lists = k8s.lists();
+
+    // The second pod comes up while the watch is being restarted, and the restarted watch is
+    // turned away
+    k8s.expire_watches_with(&[(&first.address, true), (&second.address, true)]);
+    sleep(Duration::from_secs(1)).await;
+    send_requests(&balancebeam.address, 4).await;
+
+    assert_eq!(k8s.lists(), lists + 1);
+    assert_eq!(Box::new(first).stop().await, 4);
+    assert_eq!(Box::new(second).stop().await, 2);
+    log::info!("All done :)");
+}
+
+/// On clusters without EndpointSlices, the service's Endpoints should be used instead
+#[tokio::test]
+async fn test_k8s_endpoints_fallback() {
+    init_logging();
+    let upstream = EchoServer::new().await;
+    let (registry, answer) = start_registry("/api/v1/namespaces/default/endpoints").await;
+    let (ip, port) = upstream.address.rsplit_once(':').unwrap();
+    let endpoints = serde_json::json!({
+        "kind": "EndpointsList",
+        "metadata": { "resourceVersion": "1" },
+        "items": [{
+            "metadata": { "name": "web" },
+            "subsets": [{
+                "addresses": [{ "ip": ip }],
+                "notReadyAddresses": [{ "ip": "127.0.0.2" }],
+                "ports": [{ "name": "http", "port": port.parse::<u16>().unwrap() }],
+            }],
+        }],
+    });
+    *answer.lock().unwrap() = (StatusCode::OK, endpoints.to_string());
+    let balancebeam = BalanceBeam::new_with_args(
+        &[],
+        &[
+            "--discover",
+            "k8s:default/web",
+            "--discovery-address",
+            &registry,
+            "--active-health-check-interval",
+            "60",
+        ],
+    )
+    .await;
+
+    send_requests(&balancebeam.address, 3).await;
+
+    assert_eq!(Box::new(upstream).stop().await, 3);
+    log::info!("All done :)");
+}
@@ -0,0 +1,121 @@
+mod common;
+
+use common::{free_address, init_logging, BalanceBeam};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+use tokio::net::{TcpListener, TcpStream};
+
+/// A line-based server that isn't HTTP: it greets each client with its name, then echoes each
+/// line back. Returns its address and the number of connections it has taken.
+async fn start_line_server(name: &'static str) -> (String, Arc<AtomicUsize>) {
+    let listener = TcpListener::bind("127.0.0.1:0").await.expect("Could not bind line server");
+    let address = listener.local_addr().unwrap().to_string();
+    let connections = Arc::new(AtomicUsize::new(0));
+    let counted = connections.clone();
+    tokio::spawn(async move {
+        loop {
+            let (stream, _) = match listener.accept().await {
+                Ok(accepted) => accepted,
+                Err(_) => return,
+            };
+            counted.fetch_add(1, Ordering::SeqCst);
+            tokio::spawn(async move {
+                let (reader, mut writer) = stream.into_split();
+                if writer.write_all(format!("+{}\r\n", name).as_bytes()).await.is_err() {
+                    return;
+                }
+                let mut lines = BufReader::new(reader).lines();
+                while let Ok(Some(line)) = lines.next_line().await {
+                    if writer.write_all(format!("{}\r\n", line).as_bytes()).await.is_err() {
+                        return;
+                    }
+                }
+            });
+        }
+    });
+    (address, connections)
+}
+
+/// Opens a connection through balancebeam, checks that lines are echoed back, and returns the
+/// name of the server that answered
+async fn talk(address: &str) -> String {
+    let stream = TcpStream::connect(address).await.expect("Could not connect to balancebeam");
+    let (reader, mut writer) = stream.into_split();
+    let mut lines = BufReader::new(reader).lines();
+    let greeting = lines.next_line().await.unwrap().expect("Connection closed without a greeting");
+    for command in &["PING", "GET /not/http"] {
+        writer.write_all(format!("{}\r\n", command).as_bytes()).await.unwrap();
+        assert_eq!(lines.next_line().await.unwrap().as_deref(), Some(*command));
+    }
+    greeting.trim_start_matches('+').to_string()
+}
+
+/// Connections should be passed through byte for byte, and spread over the upstreams
+#[tokio::test]
+async fn test_tcp_round_robin() {
+    init_logging();
+    let (first, first_connections) = start_line_server("first").await;
+    let (second, second_connections) = start_line_server("second").await;
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&first, &second],
+        &["--mode", "tcp", "--active-health-check-interval", "60"],
+    )
+    .await;
+
+    let mut names = Vec::new();
+    for _ in 0..4 {
+        names.push(talk(&balancebeam.address).await);
+    }
+    names.sort();
+    assert_eq!(names, vec!["first", "first", "second", "second"]);
+    assert_eq!(first_connections.load(Ordering::SeqCst), 2);
+    assert_eq!(second_connections.load(Ordering::SeqCst), 2);
+    log::info!("All done :)");
+}
+
+/// An upstream that doesn't accept connections should be skipped, whether the health checks or a
+/// client's connection found out first
+#[tokio::test]
+async fn test_tcp_dead_upstream() {
+    init_logging();
+    let (live, live_connections) = start_line_server("live").await;
+    let dead = free_address();
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&live, &dead],
+        &["--mode", "tcp", "--active-health-check-interval", "1"],
+    )
+    .await;
+
+    for _ in 0..4 {
+        assert_eq!(talk(&balancebeam.address).await, "live");
+    }
+    // The health checks only connect, so they count as connections too
+    assert!(live_connections.load(Ordering::SeqCst) >= 4);
+    log::info!("All done :)");
+}
+
+/// Clients over the connection limit should have their connections closed, without being sent
+/// an HTTP response they wouldn't understand
+#[tokio::test]
+async fn test_tcp_connection_limit() {
+    init_logging();
+    let (upstream, _) = start_line_server("upstream").await;
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&upstream],
+        &["--mode", "tcp", "--max-connections", "1", "--active-health-check-interval", "60"],
+    )
+    .await;
+
+    let held = TcpStream::connect(&balancebeam.address).await.unwrap();
+    let mut held = BufReader::new(held);
+    let mut greeting = String::new();
+    held.read_line(&mut greeting).await.unwrap();
+    assert_eq!(greeting, "+upstream\r\n");
+
+    let mut turned_away = TcpStream::connect(&balancebeam.address).await.unwrap();
+    let mut received = Vec::new();
+    turned_away.read_to_end(&mut received).await.unwrap();
+    assert!(received.is_empty(), "Got {:?}", String::from_utf8_lossy(&received));
+    log::info!("All done :)");
+}
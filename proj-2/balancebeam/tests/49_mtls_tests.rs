@@ -0,0 +1,171 @@
+mod common;
+
+use common::{setup_with_args, stop_all, BalanceBeam};
+
+fn cert_path(file_name: &str) -> String {
+    format!("{}/tests/certs/{}", env!("CARGO_MANIFEST_DIR"), file_name)
+}
+
+/// Builds an HTTPS client that trusts balancebeam's certificate, and presents the given client
+/// certificate (e.g. "client" for client.crt and client.key) if one is given
+fn https_client(client_cert: Option<&str>) -> reqwest::Client {
+    let cert = std::fs::read(cert_path("localhost.crt")).expect("Could not read test certificate");
+    let mut builder = reqwest::Client::builder().add_root_certificate(
+        reqwest::Certificate::from_pem(&cert).expect("Test certificate is not valid PEM"),
+    );
+    if let Some(name) = client_cert {
+        let cert = std::fs::read(cert_path(&format!("{}.crt", name))).unwrap();
+        let key = std::fs::read(cert_path(&format!("{}.key", name))).unwrap();
+        let identity = reqwest::Identity::from_pkcs8_pem(&cert, &key)
+            .expect("Test client certificate is not valid PEM");
+        builder = builder.identity(identity);
+    }
+    builder.build().expect("Could not build HTTPS client")
+}
+
+/// Clients with a certificate from the CA should get through, and their certificate's subject
+/// should be passed on, replacing anything they claimed themselves
+#[tokio::test]
+async fn test_client_cert_accepted() {
+    let (balancebeam, upstreams) = setup_with_args(
+        1,
+        &[
+            "--tls-cert",
+            &cert_path("localhost.crt"),
+            "--tls-key",
+            &cert_path("localhost.key"),
+            "--tls-client-ca",
+            &cert_path("client-ca.crt"),
+        ],
+    )
+    .await;
+    let port = balancebeam.address.rsplit(':').next().unwrap();
+
+    let response_text = https_client(Some("client"))
+        .get(format!("https://localhost:{}/mtls", port))
+        .header("x-client-cert-subject", "CN=admin")
+        .send()
+        .await
+        .expect("Error sending HTTPS request to balancebeam")
+        .text()
+        .await
+        .expect("Balancebeam replied with a malformed response");
+    assert!(response_text.contains("GET /mtls HTTP/1.1"));
+    assert!(
+        response_text.contains("x-client-cert-subject: CN=test-client,O=Balancebeam Tests\n"),
+        "Subject missing from request: {}",
+        response_text
+    );
+    assert!(!response_text.contains("CN=admin"));
+
+    let request_counters = stop_all(upstreams).await;
+    assert_eq!(request_counters, vec![1]);
+    log::info!("All done :)");
+}
+
+/// Clients without a certificate, or with one from another CA, should be turned away
+#[tokio::test]
+async fn test_client_cert_required() {
+    let (balancebeam, upstreams) = setup_with_args(
+        1,
+        &[
+            "--tls-cert",
+            &cert_path("localhost.crt"),
+            "--tls-key",
+            &cert_path("localhost.key"),
+            "--tls-client-ca",
+            &cert_path("client-ca.crt"),
+        ],
+    )
+    .await;
+    let port = balancebeam.address.rsplit(':').next().unwrap();
+
+    for client_cert in &[None, Some("untrusted-client")] {
+        let result =
+            https_client(*client_cert).get(format!("https://localhost:{}/", port)).send().await;
+        assert!(result.is_err(), "Request with client certificate {:?} got through", client_cert);
+    }
+
+    let request_counters = stop_all(upstreams).await;
+    assert_eq!(request_counters, vec![0]);
+    log::info!("All done :)");
+}
+
+/// Without client certificates, a subject the client sends itself shouldn't be passed on
+#[tokio::test]
+async fn test_client_cert_subject_not_spoofable() {
+    let (balancebeam, upstreams) = setup_with_args(
+        1,
+        &["--tls-cert", &cert_path("localhost.crt"), "--tls-key", &cert_path("localhost.key")],
+    )
+    .await;
+    let port = balancebeam.address.rsplit(':').next().unwrap();
+
+    let response_text = https_client(None)
+        .get(format!("https://localhost:{}/", port))
+        .header("x-client-cert-subject", "CN=admin")
+        .send()
+        .await
+        .expect("Error sending HTTPS request to balancebeam")
+        .text()
+        .await
+        .expect("Balancebeam replied with a malformed response");
+    assert!(!response_text.contains("x-client-cert-subject"));
+
+    let request_counters = stop_all(upstreams).await;
+    assert_eq!(request_counters, vec![1]);
+    log::info!("All done :)");
+}
+
+/// Balancebeam should present its client certificate to upstreams that require one. The upstream
+/// here is a second balancebeam requiring client certificates in front of an echo server.
+#[tokio::test]
+async fn test_upstream_client_cert() {
+    let cert = cert_path("localhost.crt");
+    let (tls_balancebeam, upstreams) = setup_with_args(
+        1,
+        &[
+            "--tls-cert",
+            &cert,
+            "--tls-key",
+            &cert_path("localhost.key"),
+            "--tls-client-ca",
+            &cert_path("client-ca.crt"),
+        ],
+    )
+    .await;
+    let upstream = format!("https://{}", tls_balancebeam.address);
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&upstream],
+        &[
+            "--upstream-ca-cert",
+            &cert,
+            "--upstream-client-cert",
+            &cert_path("client.crt"),
+            "--upstream-client-key",
+            &cert_path("client.key"),
+            "--active-health-check-interval",
+            "60",
+        ],
+    )
+    .await;
+    let response_text = balancebeam.get("/through-mtls").await.expect("Error sending request");
+    assert!(response_text.contains("GET /through-mtls HTTP/1.1"));
+    assert!(response_text.contains("x-client-cert-subject: CN=test-client,O=Balancebeam Tests\n"));
+
+    // Without the certificate, the upstream turns us away
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&upstream],
+        &["--upstream-ca-cert", &cert, "--active-health-check-interval", "60"],
+    )
+    .await;
+    let status = reqwest::get(format!("http://{}/no-cert", balancebeam.address))
+        .await
+        .expect("Error sending request")
+        .status();
+    assert_eq!(status.as_u16(), 502);
+
+    let request_counters = stop_all(upstreams).await;
+    assert_eq!(request_counters, vec![1]);
+    log::info!("All done :)");
+}
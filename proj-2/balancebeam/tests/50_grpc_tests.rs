@@ -0,0 +1,151 @@
+mod common;
+
+use common::{free_address, init_logging, BalanceBeam};
+use hyper::body::HttpBody;
+use hyper::service::{make_service_fn, service_fn};
+use hyper::{Body, Request, Response};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+
+/// An HTTP/2-only server that answers each call the way a gRPC server would: with the request's
+/// body echoed back, followed by a grpc-status trailer. Returns its address and the number of
+/// calls it has taken.
+async fn start_grpc_server(name: &'static str) -> (String, Arc<AtomicUsize>) {
+    let address = free_address();
+    let calls = Arc::new(AtomicUsize::new(0));
+    let counted = calls.clone();
+    let bind_addr = address.parse().unwrap();
+    let service = make_service_fn(move |_| {
+        let counted = counted.clone();
+        async move {
+            Ok::<_, hyper::Error>(service_fn(move |request: Request<Body>| {
+                counted.fetch_add(1, Ordering::SeqCst);
+                async move {
+                    let message = hyper::body::to_bytes(request.into_body()).await?;
+                    let (mut sender, body) = Body::channel();
+                    tokio::spawn(async move {
+                        sender.send_data(message).await.ok();
+                        let mut trailers = hyper::HeaderMap::new();
+                        trailers.insert("grpc-status", "0".parse().unwrap());
+                        trailers.insert("x-served-by", name.parse().unwrap());
+                        sender.send_trailers(trailers).await.ok();
+                    });
+                    let response = Response::builder()
+                        .header("content-type", "application/grpc")
+                        .body(body)
+                        .unwrap();
+                    Ok::<_, hyper::Error>(response)
+                }
+            }))
+        }
+    });
+    let server = hyper::Server::bind(&bind_addr).http2_only(true).serve(service);
+    tokio::spawn(async move {
+        if let Err(err) = server.await {
+            log::error!("Error in gRPC server: {}", err);
+        }
+    });
+    (address, calls)
+}
+
+/// Makes a gRPC-style call, returning the response along with its body and trailers
+async fn call(
+    client: &hyper::Client<hyper::client::HttpConnector>,
+    address: &str,
+    message: &'static str,
+) -> (Response<Body>, Vec<u8>, Option<hyper::HeaderMap>) {
+    let request = Request::post(format!("http://{}/echo.Echo/Say", address))
+        .header("content-type", "application/grpc")
+        .header("te", "trailers")
+        .body(Body::from(message))
+        .unwrap();
+    let mut response = client.request(request).await.expect("Error sending call");
+    let mut body = Vec::new();
+    while let Some(chunk) = response.body_mut().data().await {
+        body.extend(chunk.expect("Error reading response body"));
+    }
+    let trailers = response.body_mut().trailers().await.expect("Error reading trailers");
+    (response, body, trailers)
+}
+
+fn http2_client() -> hyper::Client<hyper::client::HttpConnector> {
+    hyper::Client::builder().http2_only(true).build_http()
+}
+
+/// Calls should make it through over HTTP/2, with the upstream's trailers passed on, while
+/// HTTP/1 clients are still served as before
+#[tokio::test]
+async fn test_grpc_trailers_forwarded() {
+    init_logging();
+    let (upstream, calls) = start_grpc_server("only").await;
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&upstream],
+        &["--http2", "--active-health-check-interval", "60"],
+    )
+    .await;
+
+    let (response, body, trailers) = call(&http2_client(), &balancebeam.address, "hello").await;
+    assert_eq!(response.status(), 200);
+    assert_eq!(response.version(), hyper::Version::HTTP_2);
+    assert_eq!(response.headers()["content-type"], "application/grpc");
+    assert_eq!(body, b"hello");
+    let trailers = trailers.expect("Trailers were not forwarded");
+    assert_eq!(trailers["grpc-status"], "0");
+    assert_eq!(trailers["x-served-by"], "only");
+
+    // The upstream only speaks HTTP/2, so an HTTP/1 request reaches it as HTTP/1 and fails, but
+    // balancebeam should still be reading HTTP/1 requests
+    let status = reqwest::get(format!("http://{}/", balancebeam.address))
+        .await
+        .expect("Error sending HTTP/1 request")
+        .status();
+    assert_eq!(status.as_u16(), 502);
+    assert_eq!(calls.load(Ordering::SeqCst), 1);
+    log::info!("All done :)");
+}
+
+/// Calls made over a single client connection should each be load balanced on their own, rather
+/// than all going wherever the connection went
+#[tokio::test]
+async fn test_grpc_per_call_load_balancing() {
+    init_logging();
+    let (first, first_calls) = start_grpc_server("first").await;
+    let (second, second_calls) = start_grpc_server("second").await;
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&first, &second],
+        &["--http2", "--load-balancer", "round-robin", "--active-health-check-interval", "60"],
+    )
+    .await;
+
+    let client = http2_client();
+    let mut served_by = Vec::new();
+    for _ in 0..4 {
+        let (_, body, trailers) = call(&client, &balancebeam.address, "ping").await;
+        assert_eq!(body, b"ping");
+        let trailers = trailers.expect("Trailers were not forwarded");
+        served_by.push(trailers["x-served-by"].to_str().unwrap().to_string());
+    }
+    served_by.sort();
+    assert_eq!(served_by, vec!["first", "first", "second", "second"]);
+    assert_eq!(first_calls.load(Ordering::SeqCst), 2);
+    assert_eq!(second_calls.load(Ordering::SeqCst), 2);
+    log::info!("All done :)");
+}
+
+/// When no upstream can take a call, the client should get a gRPC status it understands
+#[tokio::test]
+async fn test_grpc_unavailable_status() {
+    init_logging();
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&free_address()],
+        &["--http2", "--active-health-check-interval", "60"],
+    )
+    .await;
+
+    let (response, body, _) = call(&http2_client(), &balancebeam.address, "anyone?").await;
+    assert_eq!(response.status(), 200);
+    assert_eq!(response.headers()["content-type"], "application/grpc");
+    assert_eq!(response.headers()["grpc-status"], "14");
+    assert!(body.is_empty());
+    log::info!("All done :)");
+}
@@ -0,0 +1,150 @@
+mod common;
+
+use common::{init_logging, BalanceBeam};
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpListener;
+use tokio::time::sleep;
+
+/// Starts an upstream that takes `delay` to answer each request
+async fn start_slow_server(delay: Duration) -> String {
+    let listener = TcpListener::bind("127.0.0.1:0").await.expect("Could not bind slow server");
+    let address = listener.local_addr().unwrap().to_string();
+    tokio::spawn(async move {
+        while let Ok((mut stream, _)) = listener.accept().await {
+            tokio::spawn(async move {
+                let mut buffer = [0_u8; 1024];
+                if stream.read(&mut buffer).await.unwrap_or(0) == 0 {
+                    return;
+                }
+                sleep(delay).await;
+                let _ = stream.write_all(b"HTTP/1.1 200 OK\r\nContent-Length: 0\r\n\r\n").await;
+            });
+        }
+    });
+    address
+}
+
+/// Sends a request on a new connection in the background
+fn spawn_get(address: &str) -> tokio::task::JoinHandle<reqwest::Response> {
+    let url = format!("http://{}/", address);
+    tokio::spawn(async move { reqwest::get(&url).await.expect("Error sending request") })
+}
+
+/// Requests over an upstream's concurrency limit should wait for it to finish the ones it has,
+/// rather than being turned away
+#[tokio::test]
+async fn test_request_queue_waits() {
+    init_logging();
+    let upstream = start_slow_server(Duration::from_millis(500)).await;
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&upstream],
+        &["--max-upstream-concurrency", "1", "--active-health-check-interval", "60"],
+    )
+    .await;
+
+    let start = Instant::now();
+    let requests: Vec<_> = (0..3).map(|_| spawn_get(&balancebeam.address)).collect();
+    for request in requests {
+        assert_eq!(request.await.unwrap().status().as_u16(), 200);
+    }
+    // The upstream only ever had one request at a time
+    assert!(
+        start.elapsed() >= Duration::from_millis(1400),
+        "Requests weren't held back: all 3 took {:?}",
+        start.elapsed()
+    );
+    log::info!("All done :)");
+}
+
+/// A request that waits longer than the queue timeout should get a 503 telling the client when to
+/// try again
+#[tokio::test]
+async fn test_request_queue_timeout() {
+    init_logging();
+    let upstream = start_slow_server(Duration::from_secs(3)).await;
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&upstream],
+        &[
+            "--max-upstream-concurrency",
+            "1",
+            "--request-queue-timeout",
+            "1",
+            "--active-health-check-interval",
+            "60",
+        ],
+    )
+    .await;
+
+    let first = spawn_get(&balancebeam.address);
+    sleep(Duration::from_millis(300)).await;
+    let start = Instant::now();
+    let response = spawn_get(&balancebeam.address).await.unwrap();
+    assert_eq!(response.status().as_u16(), 503);
+    assert_eq!(response.headers()["retry-after"], "1");
+    assert!(start.elapsed() >= Duration::from_millis(900), "Request didn't wait its turn");
+    assert_eq!(first.await.unwrap().status().as_u16(), 200);
+    log::info!("All done :)");
+}
+
+/// Once the queue is full, further requests should be turned away right away
+#[tokio::test]
+async fn test_request_queue_full() {
+    init_logging();
+    let upstream = start_slow_server(Duration::from_secs(2)).await;
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&upstream],
+        &[
+            "--max-upstream-concurrency",
+            "1",
+            "--request-queue-size",
+            "0",
+            "--active-health-check-interval",
+            "60",
+        ],
+    )
+    .await;
+
+    let first = spawn_get(&balancebeam.address);
+    sleep(Duration::from_millis(300)).await;
+    let start = Instant::now();
+    let response = spawn_get(&balancebeam.address).await.unwrap();
+    assert_eq!(response.status().as_u16(), 503);
+    assert_eq!(response.headers()["retry-after"], "5");
+    assert!(start.elapsed() < Duration::from_secs(1), "Request waited despite a full queue");
+    assert_eq!(first.await.unwrap().status().as_u16(), 200);
+    log::info!("All done :)");
+}
+
+/// Requests should go to an upstream that has room before queueing behind a busy one, even when
+/// the load balancer would rather send them all to the same one
+#[tokio::test]
+async fn test_busy_upstreams_skipped() {
+    init_logging();
+    let first = start_slow_server(Duration::from_secs(1)).await;
+    let second = start_slow_server(Duration::from_secs(1)).await;
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&first, &second],
+        &[
+            "--max-upstream-concurrency",
+            "1",
+            "--load-balancer",
+            "consistent-hash",
+            "--active-health-check-interval",
+            "60",
+        ],
+    )
+    .await;
+
+    let start = Instant::now();
+    let requests: Vec<_> = (0..2).map(|_| spawn_get(&balancebeam.address)).collect();
+    for request in requests {
+        assert_eq!(request.await.unwrap().status().as_u16(), 200);
+    }
+    assert!(
+        start.elapsed() < Duration::from_millis(1800),
+        "Requests queued behind each other: both took {:?}",
+        start.elapsed()
+    );
+    log::info!("All done :)");
+}
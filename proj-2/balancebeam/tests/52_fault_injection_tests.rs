@@ -0,0 +1,157 @@
+mod common;
+
+use common::{init_logging, BalanceBeam, Behavior, MockServer, Server};
+use std::time::Duration;
+use tokio::time::sleep;
+
+/// Sends a request on a new connection, returning the status and body
+async fn get(balancebeam: &BalanceBeam, path: &str) -> (u16, String) {
+    let response = reqwest::get(format!("http://{}{}", balancebeam.address, path))
+        .await
+        .expect("Error sending request to balancebeam");
+    let status = response.status().as_u16();
+    (status, response.text().await.unwrap())
+}
+
+/// Starts balancebeam in front of the given mock upstreams, retrying failed requests once. Active
+/// health checks run every second if `health_checks` is set, and are otherwise left for later.
+async fn start_balancebeam(upstreams: &[&MockServer], health_checks: bool) -> BalanceBeam {
+    let addresses: Vec<&str> = upstreams.iter().map(|upstream| upstream.address.as_str()).collect();
+    let interval = if health_checks { "1" } else { "60" };
+    BalanceBeam::new_with_args(
+        &addresses,
+        &["--max-retries", "1", "--active-health-check-interval", interval],
+    )
+    .await
+}
+
+/// Requests to an upstream that hangs up on them should fail over to one that doesn't
+#[tokio::test]
+async fn test_hang_up_fails_over() {
+    init_logging();
+    let broken = MockServer::new("broken", Behavior::HangUp).await;
+    let healthy = MockServer::new("healthy", Behavior::Healthy).await;
+    let balancebeam = start_balancebeam(&[&broken, &healthy], false).await;
+
+    for i in 0..6 {
+        assert_eq!(get(&balancebeam, &format!("/request-{}", i)).await, (200, "healthy".into()));
+    }
+    assert_eq!(Box::new(healthy).stop().await, 6);
+    assert!(Box::new(broken).stop().await > 0, "The broken upstream never got any requests");
+    log::info!("All done :)");
+}
+
+/// A response that isn't HTTP should be treated as a failure: retried if possible, and otherwise
+/// answered with 502
+#[tokio::test]
+async fn test_malformed_response() {
+    init_logging();
+    let malformed = MockServer::new("malformed", Behavior::Malformed).await;
+    let healthy = MockServer::new("healthy", Behavior::Healthy).await;
+    let balancebeam = start_balancebeam(&[&malformed, &healthy], false).await;
+    for i in 0..4 {
+        assert_eq!(get(&balancebeam, &format!("/request-{}", i)).await, (200, "healthy".into()));
+    }
+
+    let alone = BalanceBeam::new_with_args(
+        &[&malformed.address],
+        &["--passive-health-check-failures", "0", "--active-health-check-interval", "60"],
+    )
+    .await;
+    assert_eq!(get(&alone, "/").await.0, 502);
+    log::info!("All done :)");
+}
+
+/// An upstream that takes too long should time out, with the request retried elsewhere if there
+/// is somewhere else to go, and answered with 504 if not
+#[tokio::test]
+async fn test_slow_upstream() {
+    init_logging();
+    let slow = MockServer::new("slow", Behavior::Delay(Duration::from_secs(3))).await;
+    let healthy = MockServer::new("healthy", Behavior::Healthy).await;
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&slow.address, &healthy.address],
+        &[
+            "--max-retries",
+            "1",
+            "--upstream-response-timeout",
+            "1",
+            "--active-health-check-interval",
+            "60",
+        ],
+    )
+    .await;
+    for i in 0..3 {
+        assert_eq!(get(&balancebeam, &format!("/request-{}", i)).await, (200, "healthy".into()));
+    }
+
+    let alone = BalanceBeam::new_with_args(
+        &[&slow.address],
+        &["--upstream-response-timeout", "1", "--active-health-check-interval", "60"],
+    )
+    .await;
+    assert_eq!(get(&alone, "/").await.0, 504);
+    log::info!("All done :)");
+}
+
+/// Server errors should be retried on another upstream
+#[tokio::test]
+async fn test_server_error_retried() {
+    init_logging();
+    let failing = MockServer::new("failing", Behavior::Status(503)).await;
+    let healthy = MockServer::new("healthy", Behavior::Healthy).await;
+    let balancebeam = start_balancebeam(&[&failing, &healthy], false).await;
+
+    for i in 0..6 {
+        assert_eq!(get(&balancebeam, &format!("/request-{}", i)).await, (200, "healthy".into()));
+    }
+    assert!(failing.requests_received() > 0, "The failing upstream never got any requests");
+    log::info!("All done :)");
+}
+
+/// An upstream that keeps going up and down shouldn't cost clients any requests, as long as
+/// there's a steady one to fall back on
+#[tokio::test]
+async fn test_flapping_upstream() {
+    init_logging();
+    let flapping = MockServer::new("flapping", Behavior::Flap(Duration::from_millis(700))).await;
+    let steady = MockServer::new("steady", Behavior::Healthy).await;
+    let balancebeam = start_balancebeam(&[&flapping, &steady], true).await;
+
+    for i in 0..20 {
+        let (status, _) = get(&balancebeam, &format!("/request-{}", i)).await;
+        assert_eq!(status, 200, "Request {} failed", i);
+        sleep(Duration::from_millis(150)).await;
+    }
+    log::info!("All done :)");
+}
+
+/// An upstream that breaks partway through should be taken out of rotation, and put back once it
+/// recovers
+#[tokio::test]
+async fn test_fault_injected_while_running() {
+    init_logging();
+    let flaky = MockServer::new("flaky", Behavior::Healthy).await;
+    let steady = MockServer::new("steady", Behavior::Healthy).await;
+    let balancebeam = start_balancebeam(&[&flaky, &steady], true).await;
+
+    flaky.set_behavior(Behavior::HangUp);
+    for i in 0..6 {
+        assert_eq!(get(&balancebeam, &format!("/broken-{}", i)).await, (200, "steady".into()));
+    }
+
+    flaky.set_behavior(Behavior::Healthy);
+    log::info!("Waiting for the active health check to notice the upstream is back...");
+    sleep(Duration::from_secs(3)).await;
+    let mut served_by = Vec::new();
+    for i in 0..10 {
+        let (status, body) = get(&balancebeam, &format!("/recovered-{}", i)).await;
+        assert_eq!(status, 200);
+        served_by.push(body);
+    }
+    assert!(
+        served_by.iter().any(|name| name == "flaky"),
+        "The upstream recovered, but never got any more requests"
+    );
+    log::info!("All done :)");
+}
@@ -0,0 +1,108 @@
+mod common;
+
+use common::{
+    free_address, init_logging, setup_with_args, stop_all, BalanceBeam, Behavior, MockServer,
+};
+use std::time::Duration;
+use tokio::process::Command;
+
+/// Runs `balancebeam bench` with the given arguments, returning what it printed
+async fn bench(args: &[&str]) -> String {
+    let mut path = std::env::current_exe().expect("Could not get current test executable path");
+    path.pop();
+    path.pop();
+    path.push("balancebeam");
+    let output = Command::new(path)
+        .arg("bench")
+        .args(args)
+        .output()
+        .await
+        .expect("Could not run balancebeam bench");
+    assert!(output.status.success(), "balancebeam bench failed: {:?}", output);
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    log::info!("Bench output:\n{}", stdout);
+    stdout
+}
+
+/// The benchmark should send exactly as many requests as asked, spread over its connections, and
+/// report how they went
+#[tokio::test]
+async fn test_bench_requests() {
+    let (balancebeam, upstreams) =
+        setup_with_args(2, &["--active-health-check-interval", "60"]).await;
+
+    let url = format!("http://{}/bench", balancebeam.address);
+    let report = bench(&[&url, "--concurrency", "4", "--requests", "200"]).await;
+    assert!(report.contains("Sent 200 requests"));
+    assert!(report.contains("Responses: 200 x 200"));
+    assert!(report.contains("Errors: 0"));
+    assert!(report.contains("Requests/sec: "));
+    assert!(report.contains("p99"));
+
+    let request_counters = stop_all(upstreams).await;
+    assert_eq!(request_counters.iter().sum::<usize>(), 200);
+    log::info!("All done :)");
+}
+
+/// With a body size and the admin API's address, the benchmark should send POSTs with bodies and
+/// report how many buffer allocations they cost the proxy. Each connection reads into a buffer of
+/// its own and reuses it, so that should come to far fewer than one per request.
+#[tokio::test]
+async fn test_bench_reports_buffer_allocations() {
+    let admin = free_address();
+    let (balancebeam, upstreams) = setup_with_args(
+        1,
+        &["--admin-bind", &admin, "--active-health-check-interval", "60"],
+    )
+    .await;
+
+    let url = format!("http://{}/upload", balancebeam.address);
+    let report = bench(&[
+        &url,
+        "--concurrency",
+        "2",
+        "--requests",
+        "100",
+        "--body-size",
+        "32768",
+        "--admin",
+        &admin,
+    ])
+    .await;
+    assert!(report.contains("Responses: 200 x 100"));
+    let allocations: usize = report
+        .lines()
+        .find_map(|line| line.strip_prefix("Buffer allocations: "))
+        .and_then(|line| line.split(' ').next())
+        .expect("Report doesn't give the number of buffer allocations")
+        .parse()
+        .unwrap();
+    assert!(allocations < 25, "{} buffer allocations for 100 requests", allocations);
+
+    stop_all(upstreams).await;
+    log::info!("All done :)");
+}
+
+/// Failed requests and error statuses should be reported, rather than stopping the benchmark
+#[tokio::test]
+async fn test_bench_reports_errors() {
+    init_logging();
+    let upstream = MockServer::new("failing", Behavior::Status(503)).await;
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&upstream.address],
+        &["--passive-health-check-failures", "0", "--active-health-check-interval", "60"],
+    )
+    .await;
+
+    let url = format!("http://{}/", balancebeam.address);
+    let report = bench(&[&url, "-c", "2", "-n", "10", "--new-connections"]).await;
+    assert!(report.contains("Responses: 503 x 10"));
+
+    // Nothing is listening here, so every request fails outright
+    drop(balancebeam);
+    tokio::time::sleep(Duration::from_millis(200)).await;
+    let report = bench(&[&url, "-c", "2", "-n", "10"]).await;
+    assert!(report.contains("Responses: none"));
+    assert!(report.contains("Errors: 10"));
+    log::info!("All done :)");
+}
@@ -0,0 +1,127 @@
+mod common;
+
+use common::{init_logging, BalanceBeam, Behavior, MockServer};
+use std::time::Duration;
+use tokio::time::sleep;
+
+/// Sends a request on a new connection, returning the status, Age header (if any) and body
+async fn get(balancebeam: &BalanceBeam, path: &str) -> (u16, Option<String>, String) {
+    let response = reqwest::get(format!("http://{}{}", balancebeam.address, path))
+        .await
+        .expect("Error sending request to balancebeam");
+    let status = response.status().as_u16();
+    let age = response.headers().get("age").map(|age| age.to_str().unwrap().to_string());
+    (status, age, response.text().await.unwrap())
+}
+
+/// Starts balancebeam in front of the upstream, caching responses under /static
+async fn start_balancebeam(upstream: &MockServer) -> BalanceBeam {
+    BalanceBeam::new_with_args(
+        &[&upstream.address],
+        &[
+            "--cache-path",
+            "/static",
+            "--passive-health-check-failures",
+            "0",
+            "--active-health-check-interval",
+            "60",
+        ],
+    )
+    .await
+}
+
+/// Fresh responses under a cache path should be answered without asking the upstream again, while
+/// everything else still goes to the upstream
+#[tokio::test]
+async fn test_fresh_responses_cached() {
+    init_logging();
+    let upstream = MockServer::new("cached", Behavior::Healthy).await;
+    upstream.set_header("Cache-Control", "public, max-age=60");
+    let balancebeam = start_balancebeam(&upstream).await;
+
+    let (status, age, body) = get(&balancebeam, "/static/app.js").await;
+    assert_eq!((status, age, body.as_str()), (200, None, "cached"));
+    let sent = upstream.requests_received();
+    for _ in 0..5 {
+        let (status, age, body) = get(&balancebeam, "/static/app.js").await;
+        assert_eq!((status, body.as_str()), (200, "cached"));
+        assert!(age.is_some(), "Cached responses should say how old they are");
+    }
+    assert_eq!(upstream.requests_received(), sent, "Cached requests reached the upstream");
+
+    // Different query strings and paths outside the cache paths are requests of their own
+    get(&balancebeam, "/static/app.js?v=2").await;
+    get(&balancebeam, "/api/users").await;
+    get(&balancebeam, "/api/users").await;
+    assert_eq!(upstream.requests_received(), sent + 3);
+    log::info!("All done :)");
+}
+
+/// Responses that say they mustn't be kept should always go to the upstream
+#[tokio::test]
+async fn test_uncacheable_responses() {
+    init_logging();
+    let upstream = MockServer::new("private", Behavior::Healthy).await;
+    upstream.set_header("Cache-Control", "no-store, max-age=60");
+    let balancebeam = start_balancebeam(&upstream).await;
+
+    let sent = upstream.requests_received();
+    for _ in 0..3 {
+        assert_eq!(get(&balancebeam, "/static/secret").await.0, 200);
+    }
+    assert_eq!(upstream.requests_received(), sent + 3);
+    log::info!("All done :)");
+}
+
+/// Once a response goes stale, it should still be served within its stale-while-revalidate window,
+/// while a fresh copy is fetched in the background
+#[tokio::test]
+async fn test_stale_while_revalidate() {
+    init_logging();
+    let upstream = MockServer::new("revalidated", Behavior::Healthy).await;
+    upstream.set_header("Cache-Control", "max-age=1, stale-while-revalidate=30");
+    let balancebeam = start_balancebeam(&upstream).await;
+
+    assert_eq!(get(&balancebeam, "/static/health").await.0, 200);
+    let sent = upstream.requests_received();
+    log::info!("Waiting for the cached response to go stale...");
+    sleep(Duration::from_millis(1500)).await;
+
+    // The stale response is served straight away, and triggers one background refresh
+    let (status, age, body) = get(&balancebeam, "/static/health").await;
+    assert_eq!((status, age.as_deref(), body.as_str()), (200, Some("1"), "revalidated"));
+    sleep(Duration::from_millis(500)).await;
+    assert_eq!(upstream.requests_received(), sent + 1, "The response wasn't revalidated");
+
+    // The refreshed copy is fresh again
+    let (status, age, _) = get(&balancebeam, "/static/health").await;
+    assert_eq!((status, age.as_deref()), (200, Some("0")));
+    assert_eq!(upstream.requests_received(), sent + 1);
+    log::info!("All done :)");
+}
+
+/// When the upstream fails, a stale response within its stale-if-error window should be served in
+/// place of the error
+#[tokio::test]
+async fn test_stale_if_error() {
+    init_logging();
+    let upstream = MockServer::new("outage", Behavior::Healthy).await;
+    upstream.set_header("Cache-Control", "max-age=1, stale-if-error=60");
+    let balancebeam = start_balancebeam(&upstream).await;
+
+    assert_eq!(get(&balancebeam, "/static/index.html").await.0, 200);
+    log::info!("Waiting for the cached response to go stale...");
+    sleep(Duration::from_millis(1500)).await;
+
+    upstream.set_behavior(Behavior::Status(500));
+    let (status, age, body) = get(&balancebeam, "/static/index.html").await;
+    assert_eq!((status, body.as_str()), (200, "outage"));
+    assert!(age.is_some());
+
+    upstream.set_behavior(Behavior::HangUp);
+    assert_eq!(get(&balancebeam, "/static/index.html").await.0, 200);
+
+    // Nothing was cached for this path, so the failure gets through
+    assert_eq!(get(&balancebeam, "/static/other.html").await.0, 502);
+    log::info!("All done :)");
+}
@@ -0,0 +1,157 @@
+mod common;
+
+use common::{free_address, init_logging, BalanceBeam, Behavior, MockServer};
+use std::time::Duration;
+use tokio::time::sleep;
+
+async fn admin_post(admin_address: &str, path: &str) -> String {
+    let response = reqwest::Client::new()
+        .post(format!("http://{}{}", admin_address, path))
+        .send()
+        .await
+        .expect("Error sending request to admin API");
+    assert_eq!(response.status(), reqwest::StatusCode::OK);
+    response.text().await.unwrap()
+}
+
+async fn deployment(admin_address: &str) -> serde_json::Value {
+    reqwest::get(format!("http://{}/deployment", admin_address))
+        .await
+        .expect("Error sending request to admin API")
+        .json()
+        .await
+        .expect("Admin API returned invalid JSON")
+}
+
+/// Sends a request with the given client, returning the name of the upstream that answered it
+async fn served_by(client: &reqwest::Client, balancebeam: &BalanceBeam, path: &str) -> String {
+    client
+        .get(format!("http://{}{}", balancebeam.address, path))
+        .send()
+        .await
+        .expect("Error sending request to balancebeam")
+        .text()
+        .await
+        .unwrap()
+}
+
+/// Starts balancebeam with blue, green and api pools, the last one routed to under /api
+async fn start_balancebeam(
+    blue: &MockServer,
+    green: &MockServer,
+    api: &MockServer,
+    admin: &str,
+    drain_timeout: &str,
+) -> BalanceBeam {
+    let blue_pool = format!("blue={}", blue.address);
+    let green_pool = format!("green={}", green.address);
+    let api_pool = format!("api={}", api.address);
+    BalanceBeam::new_with_args(
+        &[],
+        &[
+            "--pool",
+            &blue_pool,
+            "--pool",
+            &green_pool,
+            "--pool",
+            &api_pool,
+            "--route",
+            "/api=api",
+            "--blue-pool",
+            "blue",
+            "--green-pool",
+            "green",
+            "--blue-green-drain-timeout",
+            drain_timeout,
+            "--admin-bind",
+            admin,
+            "--active-health-check-interval",
+            "60",
+        ],
+    )
+    .await
+}
+
+/// Requests not matching any route should go to the live pool, and switch over to the other one
+/// when the admin API says so
+#[tokio::test]
+async fn test_blue_green_switch() {
+    init_logging();
+    let blue = MockServer::new("blue", Behavior::Healthy).await;
+    let green = MockServer::new("green", Behavior::Healthy).await;
+    let api = MockServer::new("api", Behavior::Healthy).await;
+    let admin = free_address();
+    let balancebeam = start_balancebeam(&blue, &green, &api, &admin, "30").await;
+
+    assert_eq!(deployment(&admin).await["live"], "blue");
+    for i in 0..3 {
+        let client = reqwest::Client::new();
+        assert_eq!(served_by(&client, &balancebeam, &format!("/before-{}", i)).await, "blue");
+    }
+
+    assert_eq!(admin_post(&admin, "/deployment/green").await, "OK\n");
+    assert_eq!(admin_post(&admin, "/deployment/green").await, "Already live\n");
+    let report = deployment(&admin).await;
+    assert_eq!(report["live"], "green");
+    assert_eq!(report["draining"], "blue");
+    for i in 0..3 {
+        let client = reqwest::Client::new();
+        assert_eq!(served_by(&client, &balancebeam, &format!("/after-{}", i)).await, "green");
+        // Routes still send requests to their own pools
+        assert_eq!(served_by(&client, &balancebeam, "/api/users").await, "api");
+    }
+
+    admin_post(&admin, "/deployment/blue").await;
+    let client = reqwest::Client::new();
+    assert_eq!(served_by(&client, &balancebeam, "/rolled-back").await, "blue");
+    log::info!("All done :)");
+}
+
+/// Connections pinned to the old pool should keep using it until the drain timeout runs out,
+/// while new connections go to the new pool straight away
+#[tokio::test]
+async fn test_blue_green_drain() {
+    init_logging();
+    let blue = MockServer::new("blue", Behavior::Healthy).await;
+    let green = MockServer::new("green", Behavior::Healthy).await;
+    let api = MockServer::new("api", Behavior::Healthy).await;
+    let admin = free_address();
+    let balancebeam = start_balancebeam(&blue, &green, &api, &admin, "2").await;
+
+    // This client keeps its connection to balancebeam open between requests
+    let pinned = reqwest::Client::new();
+    assert_eq!(served_by(&pinned, &balancebeam, "/session").await, "blue");
+
+    admin_post(&admin, "/deployment/green").await;
+    assert_eq!(served_by(&pinned, &balancebeam, "/session").await, "blue");
+    let fresh = reqwest::Client::new();
+    assert_eq!(served_by(&fresh, &balancebeam, "/session").await, "green");
+
+    log::info!("Waiting for the drain timeout to run out...");
+    sleep(Duration::from_millis(2500)).await;
+    assert!(deployment(&admin).await["draining"].is_null());
+    assert_eq!(served_by(&pinned, &balancebeam, "/session").await, "green");
+    log::info!("All done :)");
+}
+
+/// Switching should be refused when there are no blue/green pools
+#[tokio::test]
+async fn test_blue_green_not_configured() {
+    init_logging();
+    let upstream = MockServer::new("upstream", Behavior::Healthy).await;
+    let admin = free_address();
+    let _balancebeam = BalanceBeam::new_with_args(
+        &[&upstream.address],
+        &["--admin-bind", &admin, "--active-health-check-interval", "60"],
+    )
+    .await;
+
+    let status = reqwest::Client::new()
+        .post(format!("http://{}/deployment/green", admin))
+        .send()
+        .await
+        .expect("Error sending request to admin API")
+        .status();
+    assert_eq!(status, reqwest::StatusCode::NOT_FOUND);
+    log::info!("All done :)");
+}
@@ -0,0 +1,103 @@
+mod common;
+
+use common::{init_logging, BalanceBeam, EchoServer, Server};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+
+/// Sends raw bytes to balancebeam on a new connection, returning everything it sent back before
+/// closing the connection
+async fn send_raw(balancebeam: &BalanceBeam, request: &[u8]) -> String {
+    let mut stream = TcpStream::connect(&balancebeam.address)
+        .await
+        .expect("Could not connect to balancebeam");
+    stream.write_all(request).await.expect("Error sending request to balancebeam");
+    let mut response = Vec::new();
+    let read = tokio::time::timeout(
+        std::time::Duration::from_secs(5),
+        stream.read_to_end(&mut response),
+    )
+    .await;
+    assert!(read.is_ok(), "Balancebeam kept the connection open");
+    String::from_utf8_lossy(&response).to_string()
+}
+
+async fn start_balancebeam(upstream: &EchoServer) -> BalanceBeam {
+    BalanceBeam::new_with_args(&[&upstream.address], &["--active-health-check-interval", "60"])
+        .await
+}
+
+/// Requests whose length could be read more than one way should be rejected, and the connection
+/// closed, so that nothing after them can be taken for another request
+#[tokio::test]
+async fn test_ambiguous_framing_rejected() {
+    init_logging();
+    let upstream = EchoServer::new().await;
+    let balancebeam = start_balancebeam(&upstream).await;
+
+    let requests: [&[u8]; 6] = [
+        // Content-Length and Transfer-Encoding, hiding a second request in the body
+        b"POST / HTTP/1.1\r\nHost: localhost\r\nContent-Length: 5\r\n\
+          Transfer-Encoding: chunked\r\n\r\n0\r\n\r\n\
+          GET /smuggled HTTP/1.1\r\nHost: localhost\r\n\r\n",
+        // Content-Lengths that disagree
+        b"POST / HTTP/1.1\r\nHost: localhost\r\nContent-Length: 5\r\n\
+          Content-Length: 6\r\n\r\nhello!",
+        b"POST / HTTP/1.1\r\nHost: localhost\r\nContent-Length: 5, 6\r\n\r\nhello!",
+        // A Content-Length that isn't just digits
+        b"POST / HTTP/1.1\r\nHost: localhost\r\nContent-Length: +5\r\n\r\nhello",
+        // Transfer-Encodings that don't end in chunked
+        b"POST / HTTP/1.1\r\nHost: localhost\r\nTransfer-Encoding: chunked, gzip\r\n\r\n0\r\n\r\n",
+        b"POST / HTTP/1.1\r\nHost: localhost\r\nTransfer-Encoding: chunked\r\n\
+          Transfer-Encoding: chunked\r\n\r\n0\r\n\r\n",
+    ];
+    for request in requests.iter() {
+        let response = send_raw(&balancebeam, request).await;
+        assert!(response.starts_with("HTTP/1.1 400"), "Unexpected response: {:?}", response);
+        assert_eq!(response.matches("HTTP/1.1").count(), 1, "Got more than one response");
+    }
+
+    assert_eq!(Box::new(upstream).stop().await, 0);
+    log::info!("All done :)");
+}
+
+/// Headers that are invalid, or that may only appear once but appear twice, should be rejected
+#[tokio::test]
+async fn test_invalid_headers_rejected() {
+    init_logging();
+    let upstream = EchoServer::new().await;
+    let balancebeam = start_balancebeam(&upstream).await;
+
+    let requests: [&[u8]; 2] = [
+        b"GET / HTTP/1.1\r\nHost: localhost\r\nHost: evil.example.com\r\nConnection: close\r\n\r\n",
+        b"GET / HTTP/1.1\r\nHost: localhost\r\nX-Bad: a\x01b\r\nConnection: close\r\n\r\n",
+    ];
+    for request in requests.iter() {
+        let response = send_raw(&balancebeam, request).await;
+        assert!(response.starts_with("HTTP/1.1 400"), "Unexpected response: {:?}", response);
+    }
+
+    assert_eq!(Box::new(upstream).stop().await, 0);
+    log::info!("All done :)");
+}
+
+/// Content-Lengths that agree should be merged into one before the request is forwarded
+#[tokio::test]
+async fn test_repeated_content_length_normalized() {
+    init_logging();
+    let upstream = EchoServer::new().await;
+    let balancebeam = start_balancebeam(&upstream).await;
+
+    let response = send_raw(
+        &balancebeam,
+        b"POST /upload HTTP/1.1\r\nHost: localhost\r\nContent-Length: 5\r\n\
+          Content-Length: 5, 5\r\nX-Padded:   value  \r\nConnection: close\r\n\r\nhello",
+    )
+    .await;
+    assert!(response.starts_with("HTTP/1.1 200"), "Unexpected response: {:?}", response);
+    assert_eq!(response.matches("content-length: 5\n").count(), 1, "Got: {:?}", response);
+    assert!(response.contains("x-padded: value\n"));
+    assert!(response.ends_with("\n\nhello"));
+
+    assert_eq!(Box::new(upstream).stop().await, 1);
+    log::info!("All done :)");
+}
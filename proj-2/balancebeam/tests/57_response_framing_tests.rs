@@ -0,0 +1,178 @@
+mod common;
+
+use common::{init_logging, BalanceBeam};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::time::{timeout, Duration};
+
+/// Starts an upstream that answers each request according to its path, keeping the connection
+/// open between requests. Returns its address, and the number of requests it has answered.
+async fn start_upstream() -> (String, Arc<AtomicUsize>) {
+    let listener = TcpListener::bind("127.0.0.1:0").await.expect("Could not bind upstream");
+    let address = listener.local_addr().unwrap().to_string();
+    let answered = Arc::new(AtomicUsize::new(0));
+    let counter = answered.clone();
+    tokio::spawn(async move {
+        while let Ok((stream, _)) = listener.accept().await {
+            let counter = counter.clone();
+            tokio::spawn(async move {
+                let mut stream = BufReader::new(stream);
+                loop {
+                    let mut request_line = String::new();
+                    match stream.read_line(&mut request_line).await {
+                        Ok(0) | Err(_) => return,
+                        Ok(_) => {}
+                    }
+                    // Skip the rest of the head; none of the requests have bodies
+                    loop {
+                        let mut line = String::new();
+                        match stream.read_line(&mut line).await {
+                            Ok(0) | Err(_) => return,
+                            Ok(_) if line == "\r\n" => break,
+                            Ok(_) => {}
+                        }
+                    }
+                    let mut parts = request_line.split(' ');
+                    let method = parts.next().unwrap_or("");
+                    let path = parts.next().unwrap_or("");
+                    let response: &[u8] = match (method, path) {
+                        // The lengths of a HEAD response describe the body a GET would get
+                        ("HEAD", _) => b"HTTP/1.1 200 OK\r\nContent-Length: 1000\r\n\r\n",
+                        // Framing headers on a 204 are wrong, but mustn't leave anyone waiting
+                        (_, "/no-content") => {
+                            b"HTTP/1.1 204 No Content\r\nTransfer-Encoding: chunked\r\n\r\n"
+                        }
+                        (_, "/not-modified") => b"HTTP/1.1 304 Not Modified\r\n\
+                                                  Content-Length: 42\r\nETag: \"v1\"\r\n\r\n",
+                        (_, "/early-hints") => {
+                            b"HTTP/1.1 103 Early Hints\r\nLink: </style.css>; rel=preload\r\n\r\n\
+                              HTTP/1.1 102 Processing\r\n\r\n\
+                              HTTP/1.1 200 OK\r\nContent-Length: 5\r\n\r\nhints"
+                        }
+                        ("OPTIONS", _) => b"HTTP/1.1 200 OK\r\nAllow: GET, HEAD, OPTIONS\r\n\
+                                           Content-Length: 0\r\n\r\n",
+                        _ => b"HTTP/1.1 200 OK\r\nContent-Length: 2\r\n\r\nok",
+                    };
+                    counter.fetch_add(1, Ordering::SeqCst);
+                    if stream.get_mut().write_all(response).await.is_err() {
+                        return;
+                    }
+                }
+            });
+        }
+    });
+    (address, answered)
+}
+
+/// Reads a response head off the stream, then as many body bytes as `body_len` says there are.
+/// Returns the head (lowercased) and the body.
+async fn read_response(stream: &mut BufReader<TcpStream>, body_len: usize) -> (String, String) {
+    let read = async {
+        let mut head = String::new();
+        loop {
+            let mut line = String::new();
+            stream.read_line(&mut line).await.expect("Error reading response");
+            if line.is_empty() {
+                panic!("Balancebeam closed the connection partway through a response");
+            }
+            if line == "\r\n" {
+                break;
+            }
+            head += &line;
+        }
+        let mut body = vec![0_u8; body_len];
+        stream.read_exact(&mut body).await.expect("Error reading response body");
+        (head.to_lowercase(), String::from_utf8(body).unwrap())
+    };
+    timeout(Duration::from_secs(5), read).await.expect("Timed out waiting for a response")
+}
+
+/// Sends requests over one connection, checking each response, so that a response read with the
+/// wrong length would throw the following ones off
+async fn check_responses(requests: &[(&str, &str, &str, usize)]) {
+    init_logging();
+    let (upstream, answered) = start_upstream().await;
+    let balancebeam =
+        BalanceBeam::new_with_args(&[&upstream], &["--active-health-check-interval", "60"]).await;
+    let stream = TcpStream::connect(&balancebeam.address)
+        .await
+        .expect("Could not connect to balancebeam");
+    let mut stream = BufReader::new(stream);
+    for (method, path, status, body_len) in requests {
+        let request = format!("{} {} HTTP/1.1\r\nHost: localhost\r\n\r\n", method, path);
+        stream.get_mut().write_all(request.as_bytes()).await.unwrap();
+        let (head, body) = read_response(&mut stream, *body_len).await;
+        assert!(
+            head.starts_with(&format!("http/1.1 {}", status)),
+            "Unexpected response to {} {}: {}",
+            method,
+            path,
+            head
+        );
+        // Whatever comes after the response on the connection has to be the next response
+        stream.get_mut().write_all(b"GET /next HTTP/1.1\r\nHost: localhost\r\n\r\n").await.unwrap();
+        let (next_head, next_body) = read_response(&mut stream, 2).await;
+        assert!(next_head.starts_with("http/1.1 200"), "Out of step after {} {}", method, path);
+        assert_eq!(next_body, "ok");
+        log::info!("{} {} -> {:?}", method, path, body);
+    }
+    assert_eq!(answered.load(Ordering::SeqCst), requests.len() * 2);
+    log::info!("All done :)");
+}
+
+/// Responses to HEAD requests have no body, whatever their Content-Length says
+#[tokio::test]
+async fn test_head_response() {
+    check_responses(&[("HEAD", "/", "200", 0), ("HEAD", "/big-file", "200", 0)]).await;
+}
+
+/// 204 and 304 responses have no body
+#[tokio::test]
+async fn test_no_content_and_not_modified() {
+    check_responses(&[("GET", "/no-content", "204", 0), ("GET", "/not-modified", "304", 0)])
+        .await;
+}
+
+/// Interim responses should be passed over for the final response that follows them
+#[tokio::test]
+async fn test_interim_responses_skipped() {
+    check_responses(&[("GET", "/early-hints", "200", 5)]).await;
+}
+
+/// OPTIONS responses are framed like any other
+#[tokio::test]
+async fn test_options_response() {
+    check_responses(&[("OPTIONS", "/", "200", 0), ("OPTIONS", "/users", "200", 0)]).await;
+}
+
+/// Framing headers that a 204 mustn't have should be dropped, while the Content-Length of HEAD
+/// and 304 responses is passed on
+#[tokio::test]
+async fn test_framing_headers() {
+    init_logging();
+    let (upstream, _) = start_upstream().await;
+    let balancebeam =
+        BalanceBeam::new_with_args(&[&upstream], &["--active-health-check-interval", "60"]).await;
+    let client = reqwest::Client::new();
+    let url = |path: &str| format!("http://{}{}", balancebeam.address, path);
+
+    let response = client.get(url("/no-content")).send().await.unwrap();
+    assert_eq!(response.status().as_u16(), 204);
+    assert!(response.headers().get("transfer-encoding").is_none());
+    assert!(response.headers().get("content-length").is_none());
+
+    let response = client.head(url("/big-file")).send().await.unwrap();
+    assert_eq!(response.headers()["content-length"], "1000");
+
+    let response = client.get(url("/not-modified")).send().await.unwrap();
+    assert_eq!(response.status().as_u16(), 304);
+    assert_eq!(response.headers()["content-length"], "42");
+    assert_eq!(response.headers()["etag"], "\"v1\"");
+
+    let response = client.get(url("/early-hints")).send().await.unwrap();
+    assert!(response.headers().get("link").is_none());
+    assert_eq!(response.text().await.unwrap(), "hints");
+    log::info!("All done :)");
+}
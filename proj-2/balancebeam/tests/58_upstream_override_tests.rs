@@ -0,0 +1,80 @@
+mod common;
+
+use common::{init_logging, BalanceBeam, Behavior, EchoServer, MockServer, Server};
+
+/// Sends a request on a new connection, with the X-Balancebeam-Upstream header if one is given.
+/// Returns the status and body of the response.
+async fn get(balancebeam: &BalanceBeam, upstream: Option<&str>) -> (u16, String) {
+    let mut request = reqwest::Client::new().get(format!("http://{}/debug", balancebeam.address));
+    if let Some(upstream) = upstream {
+        request = request.header("X-Balancebeam-Upstream", upstream);
+    }
+    let response = request.send().await.expect("Error sending request to balancebeam");
+    (response.status().as_u16(), response.text().await.unwrap())
+}
+
+async fn start_balancebeam(upstreams: &[&str], extra_args: &[&str]) -> BalanceBeam {
+    let mut args = extra_args.to_vec();
+    args.extend(&["--active-health-check-interval", "60"]);
+    BalanceBeam::new_with_args(upstreams, &args).await
+}
+
+/// Trusted clients should be able to send requests to an upstream of their choosing, by index or
+/// by address
+#[tokio::test]
+async fn test_upstream_override() {
+    init_logging();
+    let first = MockServer::new("first", Behavior::Healthy).await;
+    let second = MockServer::new("second", Behavior::Healthy).await;
+    let upstreams = [first.address.as_str(), second.address.as_str()];
+    let balancebeam = start_balancebeam(&upstreams, &["--upstream-override"]).await;
+
+    for _ in 0..4 {
+        assert_eq!(get(&balancebeam, Some("1")).await, (200, "second".to_string()));
+        assert_eq!(get(&balancebeam, Some(&first.address)).await, (200, "first".to_string()));
+    }
+    assert_eq!(get(&balancebeam, Some("2")).await.0, 400);
+    assert_eq!(get(&balancebeam, Some("127.0.0.1:1")).await.0, 400);
+    log::info!("All done :)");
+}
+
+/// The header should be ignored unless overriding is turned on and the client is trusted
+#[tokio::test]
+async fn test_upstream_override_untrusted() {
+    init_logging();
+    let first = MockServer::new("first", Behavior::Healthy).await;
+    let second = MockServer::new("second", Behavior::Healthy).await;
+    let upstreams = [first.address.as_str(), second.address.as_str()];
+    let disabled = start_balancebeam(&upstreams, &[]).await;
+    let untrusted = start_balancebeam(
+        &upstreams,
+        &["--upstream-override", "--upstream-override-cidr", "10.0.0.0/8"],
+    )
+    .await;
+
+    for balancebeam in [&disabled, &untrusted] {
+        let mut served_by = Vec::new();
+        for _ in 0..4 {
+            let (status, body) = get(balancebeam, Some("0")).await;
+            assert_eq!(status, 200);
+            served_by.push(body);
+        }
+        assert!(served_by.contains(&"second".to_string()), "The header wasn't ignored");
+    }
+    log::info!("All done :)");
+}
+
+/// Upstreams should never see the header
+#[tokio::test]
+async fn test_upstream_override_header_removed() {
+    init_logging();
+    let upstream = EchoServer::new().await;
+    for args in [&["--upstream-override"][..], &[]] {
+        let balancebeam = start_balancebeam(&[&upstream.address], args).await;
+        let (status, body) = get(&balancebeam, Some(&upstream.address)).await;
+        assert_eq!(status, 200);
+        assert!(!body.contains("x-balancebeam-upstream"), "Got: {:?}", body);
+    }
+    assert_eq!(Box::new(upstream).stop().await, 2);
+    log::info!("All done :)");
+}
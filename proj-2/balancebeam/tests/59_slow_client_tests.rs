@@ -0,0 +1,156 @@
+mod common;
+
+use common::{init_logging, BalanceBeam, EchoServer, Server};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::time::{sleep, timeout, Duration, Instant};
+
+/// Size of the response the upstream sends: far more than the socket buffers between it and the
+/// client can hold
+const BODY_SIZE: usize = 256 * 1024 * 1024;
+
+/// Starts an upstream that answers each request with a huge body, streamed as fast as it's taken.
+/// Returns its address, and a flag that is set once a connection to it is closed before the whole
+/// body was sent.
+async fn start_big_upstream() -> (String, Arc<AtomicBool>) {
+    let listener = TcpListener::bind("127.0.0.1:0").await.expect("Could not bind upstream");
+    let address = listener.local_addr().unwrap().to_string();
+    let cut_off = Arc::new(AtomicBool::new(false));
+    let flag = cut_off.clone();
+    tokio::spawn(async move {
+        while let Ok((mut stream, _)) = listener.accept().await {
+            let flag = flag.clone();
+            tokio::spawn(async move {
+                let mut received = Vec::new();
+                let mut buffer = [0_u8; 1024];
+                while !received.windows(4).any(|w| w == b"\r\n\r\n") {
+                    match stream.read(&mut buffer).await {
+                        Ok(0) | Err(_) => return,
+                        Ok(n) => received.extend_from_slice(&buffer[..n]),
+                    }
+                }
+                let head = format!("HTTP/1.1 200 OK\r\nContent-Length: {}\r\n\r\n", BODY_SIZE);
+                if stream.write_all(head.as_bytes()).await.is_err() {
+                    return;
+                }
+                let piece = vec![b'x'; 64 * 1024];
+                for _ in 0..BODY_SIZE / piece.len() {
+                    if stream.write_all(&piece).await.is_err() {
+                        flag.store(true, Ordering::SeqCst);
+                        return;
+                    }
+                }
+            });
+        }
+    });
+    (address, cut_off)
+}
+
+/// Connects to balancebeam and sends a request for the huge body
+async fn request_big_body(balancebeam: &BalanceBeam) -> TcpStream {
+    let mut stream = TcpStream::connect(&balancebeam.address)
+        .await
+        .expect("Could not connect to balancebeam");
+    stream.write_all(b"GET /big HTTP/1.1\r\nHost: localhost\r\n\r\n").await.unwrap();
+    stream
+}
+
+/// Waits for the upstream connection to be closed on the slow client's behalf
+async fn wait_for_cut_off(cut_off: &AtomicBool) {
+    let started = Instant::now();
+    while !cut_off.load(Ordering::SeqCst) {
+        assert!(started.elapsed() < Duration::from_secs(10), "The upstream was never let go");
+        sleep(Duration::from_millis(100)).await;
+    }
+}
+
+/// A client that stops reading a response should be disconnected once the write timeout runs
+/// out, letting go of the upstream connection too
+#[tokio::test]
+async fn test_client_write_timeout() {
+    init_logging();
+    let (upstream, cut_off) = start_big_upstream().await;
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&upstream],
+        &["--client-write-timeout", "1", "--active-health-check-interval", "60"],
+    )
+    .await;
+
+    let mut stream = request_big_body(&balancebeam).await;
+    wait_for_cut_off(&cut_off).await;
+    // Whatever was sent before balancebeam gave up is followed by the connection closing
+    let mut received = Vec::new();
+    let read = timeout(Duration::from_secs(10), stream.read_to_end(&mut received)).await;
+    assert!(read.is_ok(), "Balancebeam kept the connection open");
+    assert!(received.len() < BODY_SIZE);
+    log::info!("All done :)");
+}
+
+/// A client that keeps reading, but too slowly, should be disconnected too
+#[tokio::test]
+async fn test_client_min_rate_response() {
+    init_logging();
+    let (upstream, cut_off) = start_big_upstream().await;
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&upstream],
+        &["--client-min-rate", "10000000", "--active-health-check-interval", "60"],
+    )
+    .await;
+
+    // Read a little at a time until balancebeam gives up. (What it sent before then may still be
+    // waiting in the socket buffers.)
+    let mut stream = request_big_body(&balancebeam).await;
+    let started = Instant::now();
+    let mut buffer = vec![0_u8; 16 * 1024];
+    while !cut_off.load(Ordering::SeqCst) {
+        let read = timeout(Duration::from_secs(5), stream.read(&mut buffer)).await;
+        assert!(matches!(read, Ok(Ok(n)) if n > 0), "Balancebeam stopped sending");
+        assert!(started.elapsed() < Duration::from_secs(10), "The slow client wasn't cut off");
+        sleep(Duration::from_millis(100)).await;
+    }
+    log::info!("All done :)");
+}
+
+/// A client that sends a request a byte at a time should be disconnected, while other clients
+/// are served as usual
+#[tokio::test]
+async fn test_client_min_rate_request() {
+    init_logging();
+    let upstream = EchoServer::new().await;
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&upstream.address],
+        &["--client-min-rate", "100", "--active-health-check-interval", "60"],
+    )
+    .await;
+
+    let mut slow = TcpStream::connect(&balancebeam.address)
+        .await
+        .expect("Could not connect to balancebeam");
+    slow.write_all(b"GET / HTTP/1.1\r\n").await.unwrap();
+    let started = Instant::now();
+    let mut closed = false;
+    for byte in b"X-Slow: ".iter().cycle().take(100) {
+        if slow.write_all(&[*byte]).await.is_err() {
+            closed = true;
+            break;
+        }
+        let mut buffer = [0_u8; 1024];
+        if let Ok(read) = timeout(Duration::from_millis(250), slow.read(&mut buffer)).await {
+            assert!(matches!(read, Ok(0) | Err(_)), "Unexpected response to a partial request");
+            closed = true;
+            break;
+        }
+    }
+    assert!(closed, "The slow client wasn't cut off");
+    assert!(started.elapsed() < Duration::from_secs(10));
+
+    let response = reqwest::get(format!("http://{}/fast", balancebeam.address))
+        .await
+        .expect("Error sending request to balancebeam");
+    assert_eq!(response.status().as_u16(), 200);
+
+    assert_eq!(Box::new(upstream).stop().await, 1);
+    log::info!("All done :)");
+}
@@ -0,0 +1,154 @@
+mod common;
+
+use common::{init_logging, BalanceBeam, EchoServer, Server};
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+use tokio::net::TcpListener;
+use tokio::sync::mpsc;
+use tokio::time::{sleep, timeout, Duration, Instant};
+
+/// The path and JSON body of each webhook received
+type Events = mpsc::UnboundedReceiver<(String, serde_json::Value)>;
+
+/// Starts a server that takes webhook POSTs, answering each with 200. Returns its address, and the
+/// webhooks it gets.
+async fn start_webhook_receiver() -> (String, Events) {
+    let listener = TcpListener::bind("127.0.0.1:0").await.expect("Could not bind receiver");
+    let address = listener.local_addr().unwrap().to_string();
+    let (sender, receiver) = mpsc::unbounded_channel();
+    tokio::spawn(async move {
+        while let Ok((stream, _)) = listener.accept().await {
+            let sender = sender.clone();
+            tokio::spawn(async move {
+                let mut stream = BufReader::new(stream);
+                let mut request_line = String::new();
+                stream.read_line(&mut request_line).await.unwrap();
+                let mut content_length = 0;
+                loop {
+                    let mut line = String::new();
+                    stream.read_line(&mut line).await.unwrap();
+                    if line == "\r\n" {
+                        break;
+                    }
+                    if let Some((name, value)) = line.split_once(':') {
+                        if name.eq_ignore_ascii_case("content-length") {
+                            content_length = value.trim().parse().unwrap();
+                        }
+                    }
+                }
+                let mut body = vec![0_u8; content_length];
+                stream.read_exact(&mut body).await.unwrap();
+                let path = request_line.split(' ').nth(1).unwrap_or("").to_string();
+                sender.send((path, serde_json::from_slice(&body).unwrap())).unwrap();
+                let response = b"HTTP/1.1 200 OK\r\nContent-Length: 0\r\nConnection: close\r\n\r\n";
+                let _ = stream.get_mut().write_all(response).await;
+            });
+        }
+    });
+    (address, receiver)
+}
+
+/// Waits for the next webhook, failing the test if none comes
+async fn next_event(receiver: &mut Events) -> (String, serde_json::Value) {
+    timeout(Duration::from_secs(10), receiver.recv())
+        .await
+        .expect("Timed out waiting for a webhook")
+        .unwrap()
+}
+
+/// The webhook should be told when an upstream goes down, and again when it comes back up
+#[tokio::test]
+async fn test_health_webhook() {
+    init_logging();
+    let (receiver_address, mut events) = start_webhook_receiver().await;
+    let steady_upstream = EchoServer::new().await;
+    let flaky_upstream = EchoServer::new().await;
+    let flaky_address = flaky_upstream.address.clone();
+    let webhook = format!("http://{}/hooks/health", receiver_address);
+    let _balancebeam = BalanceBeam::new_with_args(
+        &[&steady_upstream.address, &flaky_address],
+        &["--active-health-check-interval", "1", "--health-webhook", &webhook],
+    )
+    .await;
+
+    log::info!("Killing an upstream and waiting to hear it went down...");
+    Box::new(flaky_upstream).stop().await;
+    let (path, event) = next_event(&mut events).await;
+    assert_eq!(path, "/hooks/health");
+    assert_eq!(event["upstream"], flaky_address.as_str());
+    assert_eq!(event["state"], "down");
+    assert!(event["timestamp"].is_string());
+
+    log::info!("Bringing it back and waiting to hear it came back up...");
+    let flaky_upstream = EchoServer::new_at_address(flaky_address.clone()).await;
+    let (_, event) = next_event(&mut events).await;
+    assert_eq!(event["upstream"], flaky_address.as_str());
+    assert_eq!(event["state"], "up");
+
+    // Upstreams that stay healthy aren't reported
+    sleep(Duration::from_secs(2)).await;
+    assert!(events.try_recv().is_err(), "Got a webhook for an upstream that didn't change");
+
+    Box::new(flaky_upstream).stop().await;
+    Box::new(steady_upstream).stop().await;
+    log::info!("All done :)");
+}
+
+/// The command should be run with the details of the change in its environment
+#[tokio::test]
+async fn test_health_command() {
+    init_logging();
+    let output = std::env::temp_dir().join(format!("balancebeam-hooks-{}.log", std::process::id()));
+    let _ = std::fs::remove_file(&output);
+    let upstream = EchoServer::new().await;
+    let address = upstream.address.clone();
+    let command = format!(
+        "echo \"$BALANCEBEAM_UPSTREAM $BALANCEBEAM_STATE $BALANCEBEAM_TIMESTAMP\" >> {}",
+        output.display()
+    );
+    let _balancebeam = BalanceBeam::new_with_args(
+        &[&address],
+        &["--active-health-check-interval", "1", "--health-command", &command],
+    )
+    .await;
+
+    Box::new(upstream).stop().await;
+    let started = Instant::now();
+    let line = loop {
+        if let Ok(contents) = std::fs::read_to_string(&output) {
+            if let Some(line) = contents.lines().next() {
+                break line.to_string();
+            }
+        }
+        assert!(started.elapsed() < Duration::from_secs(10), "The command was never run");
+        sleep(Duration::from_millis(100)).await;
+    };
+    let fields: Vec<&str> = line.split(' ').collect();
+    assert_eq!(fields.len(), 3, "Unexpected output {:?}", line);
+    assert_eq!(fields[0], address);
+    assert_eq!(fields[1], "down");
+    assert!(fields[2].ends_with('Z'), "Unexpected timestamp {:?}", fields[2]);
+
+    let _ = std::fs::remove_file(&output);
+    log::info!("All done :)");
+}
+
+/// Webhook URLs that can't be sent to should be rejected at startup
+#[tokio::test]
+async fn test_invalid_health_webhook() {
+    init_logging();
+    let upstream = EchoServer::new().await;
+    let mut path = std::env::current_exe().expect("Could not get current test executable path");
+    path.pop();
+    path.pop();
+    path.push("balancebeam");
+    let status = tokio::process::Command::new(path)
+        .args(["--upstream", &upstream.address, "--health-webhook", "ftp://example.com/hook"])
+        .stdout(std::process::Stdio::null())
+        .stderr(std::process::Stdio::null())
+        .status()
+        .await
+        .expect("Could not run balancebeam");
+    assert!(!status.success());
+    Box::new(upstream).stop().await;
+    log::info!("All done :)");
+}
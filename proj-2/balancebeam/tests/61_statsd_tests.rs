@@ -0,0 +1,120 @@
+mod common;
+
+use common::{init_logging, BalanceBeam, EchoServer, Server};
+use tokio::net::UdpSocket;
+use tokio::time::{timeout, Duration, Instant};
+
+/// Collects the metrics balancebeam pushes to statsd
+struct StatsdReceiver {
+    socket: UdpSocket,
+    address: String,
+    /// Metrics received but not yet looked for
+    received: Vec<String>,
+}
+
+impl StatsdReceiver {
+    async fn new() -> StatsdReceiver {
+        let socket = UdpSocket::bind("127.0.0.1:0").await.expect("Could not bind statsd receiver");
+        let address = socket.local_addr().unwrap().to_string();
+        StatsdReceiver { socket, address, received: Vec::new() }
+    }
+
+    /// Waits for a metric that matches, returning it. Fails the test if none comes.
+    async fn wait_for(&mut self, matches: impl Fn(&str) -> bool) -> String {
+        let deadline = Instant::now() + Duration::from_secs(10);
+        let mut buf = vec![0_u8; 65536];
+        loop {
+            if let Some(idx) = self.received.iter().position(|metric| matches(metric)) {
+                return self.received.remove(idx);
+            }
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            let len = timeout(remaining, self.socket.recv(&mut buf))
+                .await
+                .expect("Timed out waiting for a metric")
+                .unwrap();
+            let packet = std::str::from_utf8(&buf[..len]).expect("Packet isn't UTF-8");
+            self.received.extend(packet.lines().map(String::from));
+        }
+    }
+}
+
+/// Requests should be counted and timed, under the status class and the upstream they went to
+#[tokio::test]
+async fn test_statsd_request_metrics() {
+    init_logging();
+    let mut statsd = StatsdReceiver::new().await;
+    let upstream = EchoServer::new().await;
+    let name = upstream.address.replace(['.', ':'], "_");
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&upstream.address],
+        &["--active-health-check-interval", "60", "--statsd-addr", &statsd.address],
+    )
+    .await;
+
+    balancebeam.get("/counted").await.expect("Error sending request to balancebeam");
+    statsd.wait_for(|metric| metric == "balancebeam.requests.2xx:1|c").await;
+    let timing =
+        statsd.wait_for(|metric| metric.starts_with("balancebeam.request_time:")).await;
+    assert!(timing.ends_with("|ms"), "Unexpected timing {:?}", timing);
+    let upstream_count = format!("balancebeam.upstream.{}.requests.2xx:1|c", name);
+    statsd.wait_for(|metric| metric == upstream_count).await;
+
+    Box::new(upstream).stop().await;
+    log::info!("All done :)");
+}
+
+/// With tags on, details should go in DogStatsD tags instead of the names, under the given prefix
+#[tokio::test]
+async fn test_statsd_tags() {
+    init_logging();
+    let mut statsd = StatsdReceiver::new().await;
+    let upstream = EchoServer::new().await;
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&upstream.address],
+        &[
+            "--active-health-check-interval",
+            "60",
+            "--statsd-addr",
+            &statsd.address,
+            "--statsd-prefix",
+            "lb",
+            "--statsd-tags",
+        ],
+    )
+    .await;
+
+    balancebeam.get("/tagged").await.expect("Error sending request to balancebeam");
+    let count = statsd.wait_for(|metric| metric.starts_with("lb.requests:")).await;
+    assert_eq!(
+        count,
+        format!("lb.requests:1|c|#status:200,status_class:2xx,upstream:{}", upstream.address)
+    );
+
+    Box::new(upstream).stop().await;
+    log::info!("All done :)");
+}
+
+/// An upstream going down should be counted, and its health gauge set
+#[tokio::test]
+async fn test_statsd_health_transitions() {
+    init_logging();
+    let mut statsd = StatsdReceiver::new().await;
+    let steady_upstream = EchoServer::new().await;
+    let flaky_upstream = EchoServer::new().await;
+    let name = flaky_upstream.address.replace(['.', ':'], "_");
+    let _balancebeam = BalanceBeam::new_with_args(
+        &[&steady_upstream.address, &flaky_upstream.address],
+        &["--active-health-check-interval", "1", "--statsd-addr", &statsd.address],
+    )
+    .await;
+
+    log::info!("Killing an upstream and waiting for it to be reported...");
+    Box::new(flaky_upstream).stop().await;
+    let down = format!("balancebeam.upstream.{}.down:1|c", name);
+    statsd.wait_for(|metric| metric == down).await;
+    let gauge = format!("balancebeam.upstream.{}.healthy:0|g", name);
+    statsd.wait_for(|metric| metric == gauge).await;
+
+    Box::new(steady_upstream).stop().await;
+    log::info!("All done :)");
+}
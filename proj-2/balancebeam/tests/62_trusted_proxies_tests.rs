@@ -0,0 +1,116 @@
+mod common;
+
+use common::{init_logging, read_access_log_entry, BalanceBeam, EchoServer, Server};
+
+/// Sends a request as if it came through proxies that added the given X-Forwarded-For. Returns
+/// the status code and body.
+async fn get_forwarded(address: &str, path: &str, forwarded_for: &str) -> (u16, String) {
+    let response = reqwest::Client::new()
+        .get(format!("http://{}{}", address, path))
+        .header("x-forwarded-for", forwarded_for)
+        .send()
+        .await
+        .expect("Error sending request to balancebeam");
+    let status = response.status().as_u16();
+    (status, response.text().await.unwrap())
+}
+
+/// Behind a trusted proxy, each client should get its own rate limit, and upstreams should still
+/// see the proxy's address added to X-Forwarded-For
+#[tokio::test]
+async fn test_trusted_proxy_rate_limit() {
+    init_logging();
+    let upstream = EchoServer::new().await;
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&upstream.address],
+        &[
+            "--active-health-check-interval",
+            "60",
+            "--max-requests-per-minute",
+            "2",
+            "--trusted-proxies",
+            "127.0.0.1,10.0.0.0/8",
+        ],
+    )
+    .await;
+
+    log::info!("Sending requests from one client through two proxies");
+    let first_client = "198.51.100.1, 10.0.0.5";
+    for _ in 0..2 {
+        let (status, _) = get_forwarded(&balancebeam.address, "/first", first_client).await;
+        assert_eq!(status, 200);
+    }
+    let (status, _) = get_forwarded(&balancebeam.address, "/first", first_client).await;
+    assert_eq!(status, 429);
+
+    log::info!("Another client behind the same proxies should have its own limit");
+    let (status, body) =
+        get_forwarded(&balancebeam.address, "/second", "198.51.100.2, 10.0.0.5").await;
+    assert_eq!(status, 200);
+    assert!(body.contains("x-forwarded-for: 198.51.100.2, 10.0.0.5, 127.0.0.1"), "{}", body);
+
+    Box::new(upstream).stop().await;
+    log::info!("All done :)");
+}
+
+/// Clients that aren't trusted proxies can't dodge the rate limit by sending X-Forwarded-For
+#[tokio::test]
+async fn test_untrusted_forwarded_for_ignored() {
+    init_logging();
+    let upstream = EchoServer::new().await;
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&upstream.address],
+        &[
+            "--active-health-check-interval",
+            "60",
+            "--max-requests-per-minute",
+            "2",
+            "--trusted-proxies",
+            "10.0.0.0/8",
+        ],
+    )
+    .await;
+
+    for (i, forwarded_for) in ["198.51.100.1", "198.51.100.2"].iter().enumerate() {
+        let path = format!("/spoofed-{}", i);
+        let (status, _) = get_forwarded(&balancebeam.address, &path, forwarded_for).await;
+        assert_eq!(status, 200);
+    }
+    let (status, _) = get_forwarded(&balancebeam.address, "/spoofed", "198.51.100.3").await;
+    assert_eq!(status, 429);
+
+    Box::new(upstream).stop().await;
+    log::info!("All done :)");
+}
+
+/// The access log should record the rightmost untrusted address, not whatever the client put at
+/// the start of X-Forwarded-For
+#[tokio::test]
+async fn test_trusted_proxy_access_log() {
+    init_logging();
+    let path = std::env::temp_dir()
+        .join(format!("balancebeam-trusted-proxies-{}.log", std::process::id()));
+    let _ = std::fs::remove_file(&path);
+    let upstream = EchoServer::new().await;
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&upstream.address],
+        &[
+            "--active-health-check-interval",
+            "60",
+            "--trusted-proxies",
+            "127.0.0.1",
+            "--access-log",
+            path.to_str().unwrap(),
+        ],
+    )
+    .await;
+
+    let (status, _) = get_forwarded(&balancebeam.address, "/logged", "1.2.3.4, 203.0.113.7").await;
+    assert_eq!(status, 200);
+    let entry = read_access_log_entry(&path).await;
+    assert_eq!(entry["client_ip"], "203.0.113.7");
+
+    Box::new(upstream).stop().await;
+    let _ = std::fs::remove_file(&path);
+    log::info!("All done :)");
+}
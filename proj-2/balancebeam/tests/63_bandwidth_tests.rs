@@ -0,0 +1,88 @@
+mod common;
+
+use common::{free_address, init_logging, read_access_log_entry, BalanceBeam, EchoServer, Server};
+
+/// Posts a body on a new connection, returning the status code
+async fn post_status(address: &str, path: &str, body: &str) -> u16 {
+    reqwest::Client::new()
+        .post(format!("http://{}{}", address, path))
+        .body(body.to_string())
+        .send()
+        .await
+        .expect("Error sending request to balancebeam")
+        .status()
+        .as_u16()
+}
+
+/// The access log should record how many bytes each request moved in each direction, headers
+/// included
+#[tokio::test]
+async fn test_access_log_sizes() {
+    init_logging();
+    let path =
+        std::env::temp_dir().join(format!("balancebeam-bandwidth-{}.log", std::process::id()));
+    let _ = std::fs::remove_file(&path);
+    let upstream = EchoServer::new().await;
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&upstream.address],
+        &["--active-health-check-interval", "60", "--access-log", path.to_str().unwrap()],
+    )
+    .await;
+
+    let body = "x".repeat(1000);
+    assert_eq!(post_status(&balancebeam.address, "/sized", &body).await, 200);
+    let entry = read_access_log_entry(&path).await;
+    let bytes = entry["bytes"].as_u64().unwrap();
+    let received = entry["bytes_received"].as_u64().unwrap();
+    let sent = entry["bytes_sent"].as_u64().unwrap();
+    assert!(received > 1000, "Only {} bytes received for a 1000 byte body", received);
+    assert!(sent > bytes, "Only {} bytes sent for a {} byte body", sent, bytes);
+
+    Box::new(upstream).stop().await;
+    let _ = std::fs::remove_file(&path);
+    log::info!("All done :)");
+}
+
+/// Once a client has gone over its quota of bytes, its requests should be answered with 429, and
+/// the admin API should report what it used
+#[tokio::test]
+async fn test_bandwidth_limit() {
+    init_logging();
+    let admin = free_address();
+    let upstream = EchoServer::new().await;
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&upstream.address],
+        &[
+            "--active-health-check-interval",
+            "60",
+            "--max-bytes-per-minute",
+            "3000",
+            "--admin-bind",
+            &admin,
+        ],
+    )
+    .await;
+
+    log::info!("Sending small requests, which should stay under the quota");
+    assert_eq!(post_status(&balancebeam.address, "/small", "hello").await, 200);
+    assert_eq!(post_status(&balancebeam.address, "/small", "hello").await, 200);
+
+    log::info!("A big request takes the client over, but is let through since it's already begun");
+    let big = "x".repeat(2000);
+    assert_eq!(post_status(&balancebeam.address, "/big", &big).await, 200);
+    assert_eq!(post_status(&balancebeam.address, "/over", "hello").await, 429);
+
+    let usage: Vec<serde_json::Value> = reqwest::get(format!("http://{}/bandwidth", admin))
+        .await
+        .expect("Error sending request to admin API")
+        .json()
+        .await
+        .expect("Admin API returned invalid JSON");
+    assert_eq!(usage.len(), 1);
+    assert_eq!(usage[0]["ip"], "127.0.0.1");
+    assert!(usage[0]["bytes_received"].as_u64().unwrap() > 2000);
+    assert!(usage[0]["bytes_sent"].as_u64().unwrap() > 2000);
+
+    Box::new(upstream).stop().await;
+    log::info!("All done :)");
+}
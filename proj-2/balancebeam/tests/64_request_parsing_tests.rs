@@ -0,0 +1,100 @@
+mod common;
+
+use common::{init_logging, BalanceBeam, EchoServer, Server};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+use tokio::time::{timeout, Duration};
+
+/// Sends a request to balancebeam in pieces of `piece_size` bytes, each written on its own, and
+/// returns everything it answers with before hanging up
+async fn send_in_pieces(address: &str, request: &[u8], piece_size: usize) -> String {
+    let mut stream = TcpStream::connect(address).await.expect("Could not connect to balancebeam");
+    stream.set_nodelay(true).unwrap();
+    for piece in request.chunks(piece_size) {
+        stream.write_all(piece).await.unwrap();
+        stream.flush().await.unwrap();
+        tokio::task::yield_now().await;
+    }
+    let mut response = Vec::new();
+    timeout(Duration::from_secs(10), stream.read_to_end(&mut response))
+        .await
+        .expect("Timed out waiting for a response")
+        .unwrap();
+    String::from_utf8_lossy(&response).into_owned()
+}
+
+/// A request whose head trickles in a byte at a time, with lots of headers, should come out the
+/// same as one sent all at once
+#[tokio::test]
+async fn test_request_sent_byte_by_byte() {
+    init_logging();
+    let upstream = EchoServer::new().await;
+    let balancebeam =
+        BalanceBeam::new_with_args(&[&upstream.address], &["--active-health-check-interval", "60"])
+            .await;
+
+    let mut request = String::from("GET /trickle HTTP/1.1\r\nHost: localhost\r\n");
+    for i in 0..25 {
+        request += &format!("X-Header-{}: value {}\r\n", i, i);
+    }
+    request += "Connection: close\r\n\r\n";
+    let response = send_in_pieces(&balancebeam.address, request.as_bytes(), 1).await;
+    assert!(response.starts_with("HTTP/1.1 200"), "Unexpected response {:?}", response);
+    assert!(response.contains("GET /trickle HTTP/1.1"));
+    for i in 0..25 {
+        assert!(response.contains(&format!("x-header-{}: value {}", i, i)));
+    }
+
+    Box::new(upstream).stop().await;
+    log::info!("All done :)");
+}
+
+/// Heads ended with bare newlines, or with blank lines before them, should still be found, even
+/// when the blank line ending the head is split across reads
+#[tokio::test]
+async fn test_request_line_endings() {
+    init_logging();
+    let upstream = EchoServer::new().await;
+    let balancebeam =
+        BalanceBeam::new_with_args(&[&upstream.address], &["--active-health-check-interval", "60"])
+            .await;
+
+    let bare = b"GET /bare HTTP/1.1\nHost: localhost\nConnection: close\n\n";
+    let response = send_in_pieces(&balancebeam.address, bare, 2).await;
+    assert!(response.starts_with("HTTP/1.1 200"), "Unexpected response {:?}", response);
+    assert!(response.contains("GET /bare HTTP/1.1"));
+
+    let leading = b"\r\n\r\nGET /leading HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n";
+    let response = send_in_pieces(&balancebeam.address, leading, 3).await;
+    assert!(response.starts_with("HTTP/1.1 200"), "Unexpected response {:?}", response);
+    assert!(response.contains("GET /leading HTTP/1.1"));
+
+    Box::new(upstream).stop().await;
+    log::info!("All done :)");
+}
+
+/// A client sending garbage should be turned away as soon as its first line is in, without
+/// waiting for a head that may never end
+#[tokio::test]
+async fn test_garbage_rejected_early() {
+    init_logging();
+    let upstream = EchoServer::new().await;
+    let balancebeam =
+        BalanceBeam::new_with_args(&[&upstream.address], &["--active-health-check-interval", "60"])
+            .await;
+
+    let mut stream = TcpStream::connect(&balancebeam.address)
+        .await
+        .expect("Could not connect to balancebeam");
+    stream.write_all(b"THIS IS NOT HTTP\r\nX-More: on the way").await.unwrap();
+    let mut response = vec![0_u8; 1024];
+    let read = timeout(Duration::from_secs(2), stream.read(&mut response))
+        .await
+        .expect("Garbage wasn't rejected until the head ended")
+        .unwrap();
+    let response = String::from_utf8_lossy(&response[..read]);
+    assert!(response.starts_with("HTTP/1.1 400"), "Unexpected response {:?}", response);
+
+    Box::new(upstream).stop().await;
+    log::info!("All done :)");
+}
@@ -0,0 +1,138 @@
+#![cfg(feature = "scripting")]
+
+mod common;
+
+use common::{init_logging, BalanceBeam, EchoServer, Server};
+
+/// Writes a script to a temporary file, returning its path
+fn write_script(name: &str, source: &str) -> String {
+    let path = std::env::temp_dir()
+        .join(format!("balancebeam-{}-{}.rhai", name, std::process::id()));
+    std::fs::write(&path, source).expect("Could not write script");
+    path.to_str().unwrap().to_string()
+}
+
+/// Sends a request with the given headers, returning the status code and body
+async fn get(address: &str, path: &str, headers: &[(&str, &str)]) -> (u16, String) {
+    let mut request = reqwest::Client::new().get(format!("http://{}{}", address, path));
+    for (name, value) in headers {
+        request = request.header(*name, *value);
+    }
+    let response = request.send().await.expect("Error sending request to balancebeam");
+    let status = response.status().as_u16();
+    (status, response.text().await.unwrap())
+}
+
+/// A script should be able to let requests through, turn them away, and answer them itself
+#[tokio::test]
+async fn test_script_allow_deny() {
+    init_logging();
+    let script = write_script(
+        "allow-deny",
+        r#"
+            fn on_request(request) {
+                if request.path.starts_with("/admin") {
+                    return false;
+                }
+                if request.headers["x-api-version"] == "1" {
+                    return #{ status: 410, body: "API v1 is gone" };
+                }
+                if request.method == "DELETE" {
+                    return 405;
+                }
+            }
+        "#,
+    );
+    let upstream = EchoServer::new().await;
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&upstream.address],
+        &["--active-health-check-interval", "60", "--script", &script],
+    )
+    .await;
+
+    let (status, body) = get(&balancebeam.address, "/public", &[]).await;
+    assert_eq!(status, 200);
+    assert!(body.contains("GET /public HTTP/1.1"));
+    let (status, _) = get(&balancebeam.address, "/admin/users", &[]).await;
+    assert_eq!(status, 403);
+    let (status, body) = get(&balancebeam.address, "/old", &[("x-api-version", "1")]).await;
+    assert_eq!(status, 410);
+    assert_eq!(body, "API v1 is gone");
+    let status = reqwest::Client::new()
+        .delete(format!("http://{}/thing", balancebeam.address))
+        .send()
+        .await
+        .expect("Error sending request to balancebeam")
+        .status();
+    assert_eq!(status.as_u16(), 405);
+
+    assert_eq!(Box::new(upstream).stop().await, 1);
+    let _ = std::fs::remove_file(&script);
+    log::info!("All done :)");
+}
+
+/// A script should be able to rewrite the path and headers a request is forwarded with
+#[tokio::test]
+async fn test_script_rewrite() {
+    init_logging();
+    let script = write_script(
+        "rewrite",
+        r#"
+            fn on_request(request) {
+                #{
+                    path: "/v2" + request.path + "?from=" + request.client_ip,
+                    headers: #{ "x-tenant": "acme", "x-remove-me": () },
+                }
+            }
+        "#,
+    );
+    let upstream = EchoServer::new().await;
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&upstream.address],
+        &["--active-health-check-interval", "60", "--script", &script],
+    )
+    .await;
+
+    let (status, body) = get(&balancebeam.address, "/items", &[("x-remove-me", "yes")]).await;
+    assert_eq!(status, 200);
+    assert!(body.contains("GET /v2/items?from=127.0.0.1 HTTP/1.1"), "{}", body);
+    assert!(body.contains("x-tenant: acme"));
+    assert!(!body.contains("x-remove-me"));
+
+    Box::new(upstream).stop().await;
+    let _ = std::fs::remove_file(&script);
+    log::info!("All done :)");
+}
+
+/// A script that fails, or runs for too long, should have its requests answered with 500 rather
+/// than let through
+#[tokio::test]
+async fn test_script_errors() {
+    init_logging();
+    let script = write_script(
+        "errors",
+        r#"
+            fn on_request(request) {
+                if request.path == "/loop" {
+                    loop {}
+                }
+                request.no_such_field.len()
+            }
+        "#,
+    );
+    let upstream = EchoServer::new().await;
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&upstream.address],
+        &["--active-health-check-interval", "60", "--script", &script],
+    )
+    .await;
+
+    let (status, _) = get(&balancebeam.address, "/broken", &[]).await;
+    assert_eq!(status, 500);
+    let (status, _) = get(&balancebeam.address, "/loop", &[]).await;
+    assert_eq!(status, 500);
+
+    assert_eq!(Box::new(upstream).stop().await, 0);
+    let _ = std::fs::remove_file(&script);
+    log::info!("All done :)");
+}
@@ -0,0 +1,189 @@
+use crate::common::server::Server;
+use async_trait::async_trait;
+use std::sync::{atomic, Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::watch;
+
+/// How a MockServer treats the requests it gets. It can be changed while the server is running,
+/// to inject a fault partway through a test.
+#[allow(dead_code)]
+#[derive(Clone, Debug)]
+pub enum Behavior {
+    /// Answer with a 200 whose body is the server's name
+    Healthy,
+    /// Answer like a healthy server, but only after waiting this long
+    Delay(Duration),
+    /// Hang up without answering
+    HangUp,
+    /// Answer with bytes that aren't an HTTP response
+    Malformed,
+    /// Answer with this status, and the server's name as the body
+    Status(u16),
+    /// Take turns being healthy and hanging up, switching every this long (starting out healthy)
+    Flap(Duration),
+}
+
+struct ServerState {
+    name: &'static str,
+    behavior: Mutex<Behavior>,
+    /// Extra headers to send with each response
+    headers: Mutex<Vec<(String, String)>>,
+    started: Instant,
+    requests_received: atomic::AtomicUsize,
+}
+
+/// An upstream that can be told to misbehave in various ways, so tests can check how balancebeam
+/// copes. It speaks just enough HTTP/1.1 to read requests (with Content-Length bodies) and keeps
+/// connections open between them.
+pub struct MockServer {
+    shutdown_signal_sender: watch::Sender<bool>,
+    server_task: tokio::task::JoinHandle<()>,
+    pub address: String,
+    state: Arc<ServerState>,
+}
+
+#[allow(dead_code)]
+impl MockServer {
+    /// Starts a server that answers with its name, and behaves as told
+    pub async fn new(name: &'static str, behavior: Behavior) -> MockServer {
+        let listener = TcpListener::bind("127.0.0.1:0").await.expect("Could not bind mock server");
+        let address = listener.local_addr().unwrap().to_string();
+        // Tells the server, and every connection it has open, to shut down
+        let (shutdown_tx, mut shutdown_rx) = watch::channel(false);
+
+        let server_state = Arc::new(ServerState {
+            name,
+            behavior: Mutex::new(behavior),
+            headers: Mutex::new(Vec::new()),
+            started: Instant::now(),
+            requests_received: atomic::AtomicUsize::new(0),
+        });
+        let server_task_state = server_state.clone();
+        let server_task = tokio::spawn(async move {
+            loop {
+                let stream = tokio::select! {
+                    accepted = listener.accept() => match accepted {
+                        Ok((stream, _)) => stream,
+                        Err(e) => {
+                            log::error!("Error in MockServer: {}", e);
+                            return;
+                        }
+                    },
+                    _ = shutdown_rx.changed() => return,
+                };
+                let state = server_task_state.clone();
+                let mut shutdown_rx = shutdown_rx.clone();
+                tokio::spawn(async move {
+                    tokio::select! {
+                        _ = serve_connection(stream, &state) => {}
+                        _ = shutdown_rx.changed() => {}
+                    }
+                });
+            }
+        });
+
+        MockServer { shutdown_signal_sender: shutdown_tx, server_task, address, state: server_state }
+    }
+
+    /// Changes how the server treats requests from now on
+    pub fn set_behavior(&self, behavior: Behavior) {
+        *self.state.behavior.lock().unwrap() = behavior;
+    }
+
+    /// Sends the header with every response from now on
+    pub fn set_header(&self, name: &str, value: &str) {
+        self.state.headers.lock().unwrap().push((name.to_string(), value.to_string()));
+    }
+
+    /// Returns the number of requests the server has received so far
+    pub fn requests_received(&self) -> usize {
+        self.state.requests_received.load(atomic::Ordering::SeqCst)
+    }
+}
+
+/// Reads a request off the connection, returning false if the client hung up
+async fn read_request(stream: &mut TcpStream, buffer: &mut Vec<u8>) -> bool {
+    let mut chunk = [0_u8; 1024];
+    let head_end = loop {
+        if let Some(idx) = buffer.windows(4).position(|window| window == b"\r\n\r\n") {
+            break idx + 4;
+        }
+        match stream.read(&mut chunk).await {
+            Ok(0) | Err(_) => return false,
+            Ok(n) => buffer.extend_from_slice(&chunk[..n]),
+        }
+    };
+    let head = String::from_utf8_lossy(&buffer[..head_end]).to_lowercase();
+    let body_len = head
+        .lines()
+        .find_map(|line| line.strip_prefix("content-length:"))
+        .and_then(|len| len.trim().parse::<usize>().ok())
+        .unwrap_or(0);
+    while buffer.len() < head_end + body_len {
+        match stream.read(&mut chunk).await {
+            Ok(0) | Err(_) => return false,
+            Ok(n) => buffer.extend_from_slice(&chunk[..n]),
+        }
+    }
+    buffer.drain(..head_end + body_len);
+    true
+}
+
+async fn respond(stream: &mut TcpStream, state: &ServerState, status: u16) -> std::io::Result<()> {
+    let mut response =
+        format!("HTTP/1.1 {} Mock\r\nContent-Length: {}\r\n", status, state.name.len());
+    for (name, value) in state.headers.lock().unwrap().iter() {
+        response += &format!("{}: {}\r\n", name, value);
+    }
+    response += &format!("\r\n{}", state.name);
+    stream.write_all(response.as_bytes()).await
+}
+
+async fn serve_connection(mut stream: TcpStream, state: &ServerState) {
+    let mut buffer = Vec::new();
+    while read_request(&mut stream, &mut buffer).await {
+        state.requests_received.fetch_add(1, atomic::Ordering::SeqCst);
+        let behavior = state.behavior.lock().unwrap().clone();
+        let answered = match behavior {
+            Behavior::Healthy => respond(&mut stream, state, 200).await,
+            Behavior::Delay(delay) => {
+                tokio::time::sleep(delay).await;
+                respond(&mut stream, state, 200).await
+            }
+            Behavior::HangUp => return,
+            Behavior::Malformed => {
+                let _ = stream.write_all(b"this is not HTTP\r\n\r\n").await;
+                return;
+            }
+            Behavior::Status(status) => respond(&mut stream, state, status).await,
+            Behavior::Flap(period) => {
+                let turn = state.started.elapsed().as_millis() / period.as_millis().max(1);
+                if turn % 2 == 1 {
+                    return;
+                }
+                respond(&mut stream, state, 200).await
+            }
+        };
+        if answered.is_err() {
+            return;
+        }
+    }
+}
+
+#[async_trait]
+impl Server for MockServer {
+    async fn stop(self: Box<Self>) -> usize {
+        // Tell the server and its connections to stop
+        let _ = self.shutdown_signal_sender.send(true);
+        // Wait for it to stop
+        self.server_task.await.expect("MockServer server task panicked");
+
+        self.state.requests_received.load(atomic::Ordering::SeqCst)
+    }
+
+    fn address(&self) -> String {
+        self.address.clone()
+    }
+}
@@ -1,17 +1,73 @@
 mod balancebeam;
 mod echo_server;
 mod error_server;
+mod mock_server;
 mod server;
+mod upgrade_server;
 
+use rand::Rng;
 use std::sync;
 
 pub use balancebeam::BalanceBeam;
 pub use echo_server::EchoServer;
+#[allow(unused_imports)]
 pub use error_server::ErrorServer;
+#[allow(unused_imports)]
+pub use mock_server::{Behavior, MockServer};
 pub use server::Server;
+#[allow(unused_imports)]
+pub use upgrade_server::UpgradeServer;
 
 static INIT_TESTS: sync::Once = sync::Once::new();
 
+/// Returns a local address with a port that was free a moment ago. Picking ports at random can
+/// collide with other servers (or outgoing connections) on the machine, making tests flaky.
+#[allow(dead_code)]
+pub fn free_address() -> String {
+    let listener = std::net::TcpListener::bind("127.0.0.1:0").expect("No free port available");
+    listener.local_addr().unwrap().to_string()
+}
+
+/// Writes a config file with the given contents to a new temporary path
+#[allow(dead_code)]
+pub fn write_config(contents: &str) -> std::path::PathBuf {
+    let path = std::env::temp_dir().join(format!(
+        "balancebeam-test-{}.toml",
+        rand::thread_rng().gen::<u32>()
+    ));
+    std::fs::write(&path, contents).expect("Could not write config file");
+    path
+}
+
+/// Send a request on a new connection and return the HTTP status code
+#[allow(dead_code)]
+pub async fn get_status(address: &str, path: &str) -> u16 {
+    reqwest::Client::new()
+        .get(format!("http://{}{}", address, path))
+        .header("x-sent-by", "balancebeam-tests")
+        .send()
+        .await
+        .expect("Error sending request to balancebeam")
+        .status()
+        .as_u16()
+}
+
+/// Waits for the first line of an access log to be written, and returns it parsed. Requests are
+/// logged only after their response has been sent, so the client can have the response before
+/// the line is there.
+#[allow(dead_code)]
+pub async fn read_access_log_entry(path: &std::path::Path) -> serde_json::Value {
+    let deadline = tokio::time::Instant::now() + std::time::Duration::from_secs(5);
+    loop {
+        let log = std::fs::read_to_string(path).unwrap_or_default();
+        if let Some(end) = log.find('\n') {
+            return serde_json::from_str(&log[..end]).expect("Access log entry isn't JSON");
+        }
+        assert!(tokio::time::Instant::now() < deadline, "Nothing was logged");
+        tokio::time::sleep(std::time::Duration::from_millis(20)).await;
+    }
+}
+
 pub fn init_logging() {
     INIT_TESTS.call_once(|| {
         pretty_env_logger::formatted_builder()
@@ -20,3 +76,41 @@ pub fn init_logging() {
             .init();
     });
 }
+
+/// Starts `n_upstreams` echo servers and a balancebeam instance in front of them, passing any
+/// extra command-line arguments through to balancebeam
+#[allow(dead_code)]
+pub async fn setup_with_args(
+    n_upstreams: usize,
+    extra_args: &[&str],
+) -> (BalanceBeam, Vec<Box<dyn Server>>) {
+    init_logging();
+    let mut upstreams: Vec<Box<dyn Server>> = Vec::new();
+    for _ in 0..n_upstreams {
+        upstreams.push(Box::new(EchoServer::new().await));
+    }
+    let upstream_addresses: Vec<String> = upstreams
+        .iter()
+        .map(|upstream| upstream.address())
+        .collect();
+    let upstream_addresses: Vec<&str> = upstream_addresses
+        .iter()
+        .map(|addr| addr.as_str())
+        .collect();
+    let balancebeam = BalanceBeam::new_with_args(&upstream_addresses, extra_args).await;
+    (balancebeam, upstreams)
+}
+
+/// Stops all the given upstreams, returning the number of requests each one received
+#[allow(dead_code)]
+pub async fn stop_all(mut upstreams: Vec<Box<dyn Server>>) -> Vec<usize> {
+    let mut request_counters = Vec::new();
+    while let Some(upstream) = upstreams.pop() {
+        request_counters.insert(0, upstream.stop().await);
+    }
+    log::info!(
+        "Number of requests received by each upstream: {:?}",
+        request_counters
+    );
+    request_counters
+}
@@ -0,0 +1,117 @@
+use crate::common::free_address;
+use crate::common::server::Server;
+use async_trait::async_trait;
+use hyper::service::{make_service_fn, service_fn};
+use hyper::{Body, Request, Response};
+use std::sync::{atomic, Arc};
+use tokio::sync::oneshot;
+
+#[derive(Debug)]
+struct ServerState {
+    pub requests_received: atomic::AtomicUsize,
+}
+
+/// Accepts any `Upgrade` request and then echoes back every byte it receives on the upgraded
+/// connection, like a bare-bones WebSocket echo server
+async fn upgrade_and_echo(mut req: Request<Body>) -> Result<Response<Body>, hyper::Error> {
+    let protocol = match req.headers().get(http::header::UPGRADE) {
+        Some(protocol) => protocol.clone(),
+        None => {
+            return Ok(Response::builder()
+                .status(http::StatusCode::UPGRADE_REQUIRED)
+                .body(Body::empty())
+                .unwrap())
+        }
+    };
+    tokio::spawn(async move {
+        match hyper::upgrade::on(&mut req).await {
+            Ok(upgraded) => {
+                let (mut reader, mut writer) = tokio::io::split(upgraded);
+                if let Err(e) = tokio::io::copy(&mut reader, &mut writer).await {
+                    log::info!("UpgradeServer connection closed: {}", e);
+                }
+            }
+            Err(e) => log::error!("UpgradeServer failed to upgrade connection: {}", e),
+        }
+    });
+    Ok(Response::builder()
+        .status(http::StatusCode::SWITCHING_PROTOCOLS)
+        .header(http::header::CONNECTION, "upgrade")
+        .header(http::header::UPGRADE, protocol)
+        .body(Body::empty())
+        .unwrap())
+}
+
+pub struct UpgradeServer {
+    shutdown_signal_sender: oneshot::Sender<()>,
+    server_task: tokio::task::JoinHandle<()>,
+    pub address: String,
+    state: Arc<ServerState>,
+}
+
+impl UpgradeServer {
+    #[allow(dead_code)]
+    pub async fn new() -> UpgradeServer {
+        UpgradeServer::new_at_address(free_address()).await
+    }
+
+    #[allow(dead_code)]
+    pub async fn new_at_address(bind_addr_string: String) -> UpgradeServer {
+        let bind_addr = bind_addr_string.parse().unwrap();
+        // Create a one-shot channel that can be used to tell the server to shut down
+        let (shutdown_tx, shutdown_rx) = oneshot::channel::<()>();
+
+        // Start a separate server task
+        let server_state = Arc::new(ServerState {
+            requests_received: atomic::AtomicUsize::new(0),
+        });
+        let server_task_state = server_state.clone();
+        let server_task = tokio::spawn(async move {
+            let service = make_service_fn(|_| {
+                let server_task_state = server_task_state.clone();
+                async move {
+                    Ok::<_, hyper::Error>(service_fn(move |req| {
+                        server_task_state
+                            .requests_received
+                            .fetch_add(1, atomic::Ordering::SeqCst);
+                        upgrade_and_echo(req)
+                    }))
+                }
+            });
+            let server = hyper::Server::bind(&bind_addr)
+                .serve(service)
+                .with_graceful_shutdown(async {
+                    shutdown_rx.await.ok();
+                });
+            // Start serving and wait for the server to exit
+            if let Err(e) = server.await {
+                log::error!("Error in UpgradeServer: {}", e);
+            }
+        });
+
+        UpgradeServer {
+            shutdown_signal_sender: shutdown_tx,
+            server_task,
+            state: server_state,
+            address: bind_addr_string,
+        }
+    }
+}
+
+#[async_trait]
+impl Server for UpgradeServer {
+    async fn stop(self: Box<Self>) -> usize {
+        // Tell the hyper server to stop
+        let _ = self.shutdown_signal_sender.send(());
+        // Wait for it to stop
+        self.server_task
+            .await
+            .expect("UpgradeServer server task panicked");
+
+        self.state.requests_received.load(atomic::Ordering::SeqCst)
+    }
+
+    fn address(&self) -> String {
+        self.address.clone()
+    }
+}
@@ -0,0 +1,77 @@
+// Terminal rendering for hangman. The whole board is redrawn after every guess using ANSI escape
+// codes, so the screen always shows the current state instead of a scrolling log.
+use game::Game;
+use std::io;
+use std::io::Write;
+
+const CLEAR_SCREEN: &str = "\x1b[2J\x1b[1;1H";
+const GREEN: &str = "\x1b[32m";
+const RED: &str = "\x1b[31m";
+const BOLD: &str = "\x1b[1m";
+const RESET: &str = "\x1b[0m";
+
+// The classic hangman figure, one entry per body part added.
+const GALLOWS: [&str; 7] = [
+    "  +---+\n  |   |\n      |\n      |\n      |\n      |\n=========",
+    "  +---+\n  |   |\n  O   |\n      |\n      |\n      |\n=========",
+    "  +---+\n  |   |\n  O   |\n  |   |\n      |\n      |\n=========",
+    "  +---+\n  |   |\n  O   |\n /|   |\n      |\n      |\n=========",
+    "  +---+\n  |   |\n  O   |\n /|\\  |\n      |\n      |\n=========",
+    "  +---+\n  |   |\n  O   |\n /|\\  |\n /    |\n      |\n=========",
+    "  +---+\n  |   |\n  O   |\n /|\\  |\n / \\  |\n      |\n=========",
+];
+
+// Picks the gallows stage for the given number of wrong guesses, spreading the drawing evenly
+// over however many guesses the player is allowed so the figure is complete on the last one.
+fn gallows_stage(wrong_guesses: u32, num_incorrect_guesses: u32) -> &'static str {
+    let last_stage = GALLOWS.len() - 1;
+    let stage = wrong_guesses as usize * last_stage / num_incorrect_guesses as usize;
+    GALLOWS[stage.min(last_stage)]
+}
+
+fn colored_guesses(game: &Game) -> String {
+    let colored: Vec<String> = game
+        .guessed_letters()
+        .iter()
+        .map(|&letter| {
+            let color = if game.is_in_word(letter) { GREEN } else { RED };
+            format!("{}{}{}", color, letter, RESET)
+        })
+        .collect();
+    colored.join(" ")
+}
+
+fn spaced(word: &str) -> String {
+    let letters: Vec<String> = word.chars().map(|c| c.to_string()).collect();
+    letters.join(" ")
+}
+
+// Running totals for the current session, shown above the board.
+pub struct Session {
+    pub round: u32,
+    pub score: u32,
+}
+
+pub fn draw_board(game: &Game, session: &Session, reveal: bool, message: &str) {
+    print!("{}", CLEAR_SCREEN);
+    println!("{}Welcome to CS110L Hangman!{}", BOLD, RESET);
+    println!("Round {} | Score: {}", session.round, session.score);
+    if reveal {
+        println!("random word: {}", game.secret_word());
+    }
+    println!();
+    println!("{}", gallows_stage(game.wrong_guesses(), game.num_incorrect_guesses()));
+    println!();
+    println!("The word so far: {}{}{}", BOLD, spaced(&game.word_so_far()), RESET);
+    println!("You have guessed the following letters: {}", colored_guesses(game));
+    println!("You have {} guesses left", game.guesses_left());
+    println!();
+    if !message.is_empty() {
+        println!("{}", message);
+    }
+}
+
+pub fn prompt(text: &str) {
+    print!("{}", text);
+    io::stdout().flush().expect("Error flushing stdout.");
+}
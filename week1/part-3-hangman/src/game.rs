@@ -0,0 +1,173 @@
+// State of a single game of hangman: the secret word, what the player has uncovered so far and
+// how many wrong guesses they have made.
+use rand::Rng;
+
+pub enum Guess {
+    Letter(char),
+    Word(String),
+    Hint,
+}
+
+pub enum Outcome {
+    Correct,
+    Wrong,
+    AlreadyGuessed,
+}
+
+// Turns a line of user input into a guess. Input is case-insensitive; anything that isn't made
+// up entirely of letters is rejected so that typos don't cost the player a life. The word "hint"
+// asks for a hint rather than being treated as a guess.
+pub fn parse_guess(input: &str) -> Result<Guess, &'static str> {
+    let guess = input.trim().to_lowercase();
+    if guess.is_empty() {
+        return Err("Please enter a letter or a word.");
+    }
+    if !guess.chars().all(|c| c.is_alphabetic()) {
+        return Err("Guesses may only contain letters.");
+    }
+    if guess == "hint" {
+        return Ok(Guess::Hint);
+    }
+
+    let mut chars = guess.chars();
+    match (chars.next(), chars.next()) {
+        (Some(letter), None) => Ok(Guess::Letter(letter)),
+        _ => Ok(Guess::Word(guess)),
+    }
+}
+
+pub struct Game {
+    secret_word: String,
+    // Note: given what you know about Rust so far, it's easier to pull characters out of a
+    // vector than it is to pull them out of a string. You can get the ith character of
+    // secret_word by doing secret_word_chars[i].
+    secret_word_chars: Vec<char>,
+    user_word_chars: Vec<String>,
+    guessed_letters: Vec<char>,
+    wrong_counter: u32,
+    num_incorrect_guesses: u32,
+    solved: bool,
+}
+
+impl Game {
+    pub fn new(secret_word: &str, num_incorrect_guesses: u32) -> Game {
+        let secret_word = secret_word.to_lowercase();
+        let secret_word_chars: Vec<char> = secret_word.chars().collect();
+        let user_word_chars = vec!["-".to_string(); secret_word_chars.len()];
+        Game {
+            secret_word,
+            secret_word_chars,
+            user_word_chars,
+            guessed_letters: vec![],
+            wrong_counter: 0,
+            num_incorrect_guesses,
+            solved: false,
+        }
+    }
+
+    pub fn secret_word(&self) -> &str {
+        &self.secret_word
+    }
+
+    pub fn word_so_far(&self) -> String {
+        self.user_word_chars.join("")
+    }
+
+    pub fn guessed_letters(&self) -> &[char] {
+        &self.guessed_letters
+    }
+
+    pub fn is_in_word(&self, letter: char) -> bool {
+        self.secret_word_chars.contains(&letter)
+    }
+
+    pub fn wrong_guesses(&self) -> u32 {
+        self.wrong_counter
+    }
+
+    pub fn num_incorrect_guesses(&self) -> u32 {
+        self.num_incorrect_guesses
+    }
+
+    pub fn guesses_left(&self) -> u32 {
+        self.num_incorrect_guesses - self.wrong_counter
+    }
+
+    pub fn is_won(&self) -> bool {
+        self.solved || self.word_so_far() == self.secret_word
+    }
+
+    pub fn is_lost(&self) -> bool {
+        self.wrong_counter >= self.num_incorrect_guesses
+    }
+
+    // Points for winning this game: longer words and fewer mistakes are worth more.
+    pub fn score(&self) -> u32 {
+        if !self.is_won() {
+            return 0;
+        }
+        self.secret_word_chars.len() as u32 * (self.guesses_left() + 1)
+    }
+
+    pub fn is_over(&self) -> bool {
+        self.is_won() || self.is_lost()
+    }
+
+    pub fn guess_letter(&mut self, letter: char) -> Outcome {
+        if self.guessed_letters.contains(&letter) {
+            return Outcome::AlreadyGuessed;
+        }
+        self.guessed_letters.push(letter);
+
+        if self.is_in_word(letter) {
+            find_all(&self.secret_word_chars, &mut self.user_word_chars, &letter);
+            Outcome::Correct
+        } else {
+            self.wrong_counter += 1;
+            Outcome::Wrong
+        }
+    }
+
+    // Reveals a random letter the player hasn't found yet, at the cost of one guess. Returns None
+    // if taking the hint would use up the player's last guess.
+    pub fn hint(&mut self) -> Option<char> {
+        if self.guesses_left() <= 1 {
+            return None;
+        }
+        let mut hidden: Vec<char> = self
+            .secret_word_chars
+            .iter()
+            .cloned()
+            .filter(|c| !self.guessed_letters.contains(c))
+            .collect();
+        hidden.sort();
+        hidden.dedup();
+        if hidden.is_empty() {
+            return None;
+        }
+        let letter = hidden[rand::thread_rng().gen_range(0, hidden.len())];
+        self.guessed_letters.push(letter);
+        find_all(&self.secret_word_chars, &mut self.user_word_chars, &letter);
+        self.wrong_counter += 1;
+        Some(letter)
+    }
+
+    pub fn guess_word(&mut self, word: &str) -> Outcome {
+        if word == self.secret_word {
+            self.solved = true;
+            self.user_word_chars = self.secret_word_chars.iter().map(|c| c.to_string()).collect();
+            Outcome::Correct
+        } else {
+            self.wrong_counter += 1;
+            Outcome::Wrong
+        }
+    }
+}
+
+fn find_all(a: &[char], b: &mut [String], matched: &char) {
+    for (idx, val) in a.iter().enumerate() {
+        if val == matched {
+            b[idx] = val.to_string();
+        }
+    }
+}
@@ -1,6 +1,6 @@
 // Simple Hangman Program
-// User gets five incorrect guesses
-// Word chosen randomly from words.txt
+// User gets five incorrect guesses by default (see --guesses)
+// Word chosen randomly from words.txt (see --words)
 // Inspiration from: https://doc.rust-lang.org/book/ch02-00-guessing-game-tutorial.html
 // This assignment will introduce you to some fundamental syntax in Rust:
 // - variable declaration
@@ -13,81 +13,273 @@
 // We've tried to limit/hide Rust's quirks since we'll discuss those details
 // more in depth in the coming lectures.
 extern crate rand;
+#[macro_use]
+extern crate serde_derive;
+extern crate serde_json;
+
+mod display;
+mod game;
+mod stats;
+
+use game::{Game, Guess, Outcome};
+use stats::Stats;
 use rand::Rng;
+use std::env;
 use std::fs;
 use std::io;
-use std::io::Write;
+use std::process;
+
+const DEFAULT_NUM_INCORRECT_GUESSES: u32 = 5;
+const DEFAULT_WORDS_PATH: &str = "words.txt";
+
+#[derive(Clone, Copy)]
+enum Difficulty {
+    Easy,
+    Medium,
+    Hard,
+}
+
+impl Difficulty {
+    fn from_name(name: &str) -> Option<Difficulty> {
+        match name {
+            "easy" => Some(Difficulty::Easy),
+            "medium" => Some(Difficulty::Medium),
+            "hard" => Some(Difficulty::Hard),
+            _ => None,
+        }
+    }
+
+    // Each difficulty has its own word list, grouped by how common the words are, along with a
+    // range of word lengths to pick from.
+    fn words_path(self) -> &'static str {
+        match self {
+            Difficulty::Easy => "words/easy.txt",
+            Difficulty::Medium => "words/medium.txt",
+            Difficulty::Hard => "words/hard.txt",
+        }
+    }
 
-const NUM_INCORRECT_GUESSES: u32 = 5;
-const WORDS_PATH: &str = "words.txt";
+    fn word_lengths(self) -> (usize, usize) {
+        match self {
+            Difficulty::Easy => (3, 6),
+            Difficulty::Medium => (5, 9),
+            Difficulty::Hard => (4, usize::MAX),
+        }
+    }
+}
 
-fn pick_a_random_word() -> String {
-    let file_string = fs::read_to_string(WORDS_PATH).expect("Unable to read file.");
-    let words: Vec<&str> = file_string.split('\n').collect();
-    String::from(words[rand::thread_rng().gen_range(0, words.len())].trim())
+struct Options {
+    words_path: String,
+    num_incorrect_guesses: u32,
+    min_word_length: usize,
+    max_word_length: usize,
+    reveal: bool,
+}
+
+fn print_usage(program: &str) {
+    println!("Usage: {} [options]", program);
+    println!("  --words <path>        word list to pick from (default: {})", DEFAULT_WORDS_PATH);
+    println!("  --guesses <n>         number of incorrect guesses allowed (default: {})", DEFAULT_NUM_INCORRECT_GUESSES);
+    println!("  --min-length <n>      only pick words with at least n letters");
+    println!("  --max-length <n>      only pick words with at most n letters");
+    println!("  --difficulty <level>  easy, medium or hard; picks the word list and word lengths");
+    println!("  --no-reveal           don't print the secret word at startup");
+    println!();
+    println!("While playing, type \"hint\" to reveal a letter at the cost of one guess.");
+}
+
+fn parse_number<T: std::str::FromStr>(flag: &str, value: Option<&String>) -> Result<T, String> {
+    let value = value.ok_or(format!("{} requires a value", flag))?;
+    value
+        .parse::<T>()
+        .or(Err(format!("Invalid value for {}: {}", flag, value)))
+}
+
+fn parse_options(args: &[String]) -> Result<Options, String> {
+    let mut words_path = None;
+    let mut min_word_length = None;
+    let mut max_word_length = None;
+    let mut difficulty = None;
+    let mut options = Options {
+        words_path: DEFAULT_WORDS_PATH.to_string(),
+        num_incorrect_guesses: DEFAULT_NUM_INCORRECT_GUESSES,
+        min_word_length: 1,
+        max_word_length: usize::MAX,
+        reveal: true,
+    };
+
+    let mut iter = args.iter();
+    while let Some(arg) = iter.next() {
+        match arg.as_str() {
+            "--words" => {
+                words_path = Some(iter.next().ok_or("--words requires a value")?.clone());
+            }
+            "--guesses" => options.num_incorrect_guesses = parse_number(arg, iter.next())?,
+            "--min-length" => min_word_length = Some(parse_number(arg, iter.next())?),
+            "--max-length" => max_word_length = Some(parse_number(arg, iter.next())?),
+            "--difficulty" => {
+                let level = iter.next().ok_or("--difficulty requires a value")?;
+                difficulty = Some(
+                    Difficulty::from_name(level)
+                        .ok_or(format!("Unknown difficulty: {}", level))?,
+                );
+            }
+            "--no-reveal" => options.reveal = false,
+            _ => return Err(format!("Unknown option: {}", arg)),
+        }
+    }
+
+    // Explicit options always win over the defaults picked by the difficulty level
+    if let Some(difficulty) = difficulty {
+        let (min, max) = difficulty.word_lengths();
+        options.words_path = difficulty.words_path().to_string();
+        options.min_word_length = min;
+        options.max_word_length = max;
+    }
+    if let Some(path) = words_path {
+        options.words_path = path;
+    }
+    if let Some(min) = min_word_length {
+        options.min_word_length = min;
+    }
+    if let Some(max) = max_word_length {
+        options.max_word_length = max;
+    }
+
+    if options.num_incorrect_guesses == 0 {
+        return Err("--guesses must be at least 1".to_string());
+    }
+    if options.min_word_length > options.max_word_length {
+        return Err("--min-length can't be greater than --max-length".to_string());
+    }
+    Ok(options)
+}
+
+fn pick_a_random_word(options: &Options) -> Result<String, String> {
+    let file_string = fs::read_to_string(&options.words_path)
+        .or(Err(format!("Unable to read file {}.", options.words_path)))?;
+    let words: Vec<&str> = file_string
+        .split('\n')
+        .map(|word| word.trim())
+        .filter(|word| {
+            let len = word.chars().count();
+            len > 0 && len >= options.min_word_length && len <= options.max_word_length
+        })
+        .collect();
+    if words.is_empty() {
+        return Err(format!(
+            "No words in {} match the requested length.",
+            options.words_path
+        ));
+    }
+    Ok(String::from(words[rand::thread_rng().gen_range(0, words.len())]))
 }
 
 fn main() {
-    let secret_word = pick_a_random_word();
-    // Note: given what you know about Rust so far, it's easier to pull characters out of a
-    // vector than it is to pull them out of a string. You can get the ith character of
-    // secret_word by doing secret_word_chars[i].
-    let secret_word_chars: Vec<char> = secret_word.chars().collect();
-    let chars_len = secret_word_chars.len();
-    let mut user_word_chars: Vec<String> = vec!["-".to_string(); chars_len];
-    let mut guessed_strings: Vec<String> = vec![];
-    // Uncomment for debugging:
-    println!("random word: {}", secret_word);
-
-    // Your code here! :)
-
-    println!("Welcome to CS110L Hangman!");
-
-    let mut wrong_counter = 0;
-
-    while wrong_counter <= NUM_INCORRECT_GUESSES {
-        print!("Please guess a letter:");
-        io::stdout()
-            .flush()
-            .expect("Error flushing stdout.");
-        let mut guess = String::new();
-        io::stdin()
-            .read_line(&mut guess)
-            .expect("Error reading line.");
-        
-        guessed_strings.push(guess.clone().replace("\n", ""));
-        
-        let guess_char = guess.chars().next().unwrap();
-
-        if secret_word_chars.iter().any(|&x| x == guess_char) {
-            user_word_chars = find_all(&secret_word_chars, &mut user_word_chars, &guess_char);
-        } else {
-            wrong_counter += 1;
-            println!("Sorry, that letter is not in the word");
+    let args: Vec<String> = env::args().collect();
+    let options = match parse_options(&args[1..]) {
+        Ok(options) => options,
+        Err(err) => {
+            println!("{}", err);
+            print_usage(&args[0]);
+            process::exit(1);
         }
-        let joined = user_word_chars.join("");
-        println!("The word so far: {}", joined);
+    };
+
+    let stats_path = Stats::path();
+    let mut stats = match stats_path {
+        Some(ref path) => Stats::load(path),
+        None => Stats::default(),
+    };
+
+    let mut round = 1;
+    let mut score = 0;
+    let mut message = stats.to_string();
+    loop {
+        let secret_word = match pick_a_random_word(&options) {
+            Ok(word) => word,
+            Err(err) => {
+                println!("{}", err);
+                process::exit(1);
+            }
+        };
+        let mut game = Game::new(&secret_word, options.num_incorrect_guesses);
+        let session = display::Session { round, score };
+
+        // Your code here! :)
 
-        println!("You have guessed the following letters: {}", guessed_strings.join(""));
+        while !game.is_over() {
+            display::draw_board(&game, &session, options.reveal, &message);
+            display::prompt("Please guess a letter, the whole word, or type \"hint\": ");
+            let input = match read_input() {
+                Some(input) => input,
+                None => {
+                    // stdin was closed, so there's nobody left to play with
+                    println!();
+                    println!("The word was: {}", game.secret_word());
+                    return;
+                }
+            };
 
-        println!("You have guessed {} guesses left", NUM_INCORRECT_GUESSES - wrong_counter);
+            message = match game::parse_guess(&input) {
+                Err(err) => err.to_string(),
+                Ok(Guess::Letter(letter)) => match game.guess_letter(letter) {
+                    Outcome::Correct => format!("Nice, {} is in the word!", letter),
+                    Outcome::Wrong => format!("Sorry, {} is not in the word", letter),
+                    Outcome::AlreadyGuessed => format!("You have already guessed the letter {}", letter),
+                },
+                Ok(Guess::Hint) => match game.hint() {
+                    Some(letter) => format!("Hint: the word contains {}", letter),
+                    None => "You don't have enough guesses left to take a hint.".to_string(),
+                },
+                Ok(Guess::Word(word)) => match game.guess_word(&word) {
+                    Outcome::Wrong => format!("Sorry, {} is not the word", word),
+                    _ => String::new(),
+                },
+            };
+        }
 
-        if joined == secret_word {
-            println!("Congratulations you guessed the secret word: {}", secret_word);
-            break;
+        if game.is_won() {
+            score += game.score();
+            message = format!(
+                "Congratulations you guessed the secret word: {} (+{} points)",
+                game.secret_word(),
+                game.score()
+            );
+        } else {
+            message = format!("Sorry, you ran out of guesses! The word was: {}", game.secret_word());
         }
-        if NUM_INCORRECT_GUESSES - wrong_counter <= 0 {
-            println!("Sorry, you ran out of guesses!");
-            break;
+        stats.record(game.is_won());
+        if let Some(ref path) = stats_path {
+            if let Err(err) = stats.save(path) {
+                message = format!("{}\nWarning: failed to save stats to {}: {}", message, path, err);
+            }
+        }
+        let session = display::Session { round, score };
+        display::draw_board(&game, &session, options.reveal, &message);
+        println!("{}", stats);
+
+        display::prompt("Play another round? [y/N] ");
+        match read_input() {
+            Some(ref answer) if answer.trim().eq_ignore_ascii_case("y") => {
+                round += 1;
+                message = String::new();
+            }
+            _ => break,
         }
     }
+    println!("Thanks for playing! Final score: {} after {} round(s)", score, round);
 }
 
-fn find_all(a :&Vec<char>, b: &mut Vec<String>, matched: &char) -> Vec<String> {
-    for (idx, val) in a.iter().enumerate() {
-        if val == matched {
-            b[idx] = val.to_string();
-        }
+// Reads one line from stdin, returning None once stdin has been closed.
+fn read_input() -> Option<String> {
+    let mut input = String::new();
+    let bytes_read = io::stdin()
+        .read_line(&mut input)
+        .expect("Error reading line.");
+    if bytes_read == 0 {
+        None
+    } else {
+        Some(input)
     }
-    b.to_vec()
 }
@@ -0,0 +1,67 @@
+// Win/loss statistics that persist between sessions. They're stored as JSON in the user's home
+// directory and loaded again the next time hangman starts.
+use std::env;
+use std::fmt;
+use std::fs;
+
+const STATS_FILE_NAME: &str = ".hangman_stats.json";
+
+#[derive(Serialize, Deserialize, Default)]
+pub struct Stats {
+    pub games_played: u32,
+    pub wins: u32,
+    pub losses: u32,
+    pub current_streak: u32,
+    pub best_streak: u32,
+}
+
+impl Stats {
+    pub fn path() -> Option<String> {
+        let home = env::var("HOME").ok()?;
+        Some(format!("{}/{}", home, STATS_FILE_NAME))
+    }
+
+    // Loads the stats saved by a previous session. A missing or unreadable file just means we
+    // start counting from scratch.
+    pub fn load(path: &str) -> Stats {
+        fs::read_to_string(path)
+            .ok()
+            .and_then(|contents| serde_json::from_str(&contents).ok())
+            .unwrap_or_default()
+    }
+
+    pub fn save(&self, path: &str) -> Result<(), String> {
+        let contents = serde_json::to_string_pretty(self).map_err(|err| err.to_string())?;
+        fs::write(path, contents).map_err(|err| err.to_string())
+    }
+
+    pub fn record(&mut self, won: bool) {
+        self.games_played += 1;
+        if won {
+            self.wins += 1;
+            self.current_streak += 1;
+            self.best_streak = self.best_streak.max(self.current_streak);
+        } else {
+            self.losses += 1;
+            self.current_streak = 0;
+        }
+    }
+}
+
+impl fmt::Display for Stats {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.games_played == 0 {
+            return write!(f, "No games played yet. Good luck!");
+        }
+        write!(
+            f,
+            "Games played: {}, won: {} ({}%), lost: {}, current streak: {}, best streak: {}",
+            self.games_played,
+            self.wins,
+            self.wins * 100 / self.games_played,
+            self.losses,
+            self.current_streak,
+            self.best_streak
+        )
+    }
+}